@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use hyper::Request;
+use lurk::{
+    internals::get_host_addr,
+    prelude::{HandshakeRequest, LurkRequest, LurkResponse, RelayRequest, RelayResponse},
+};
+use std::hint::black_box;
+use tokio::runtime::Runtime;
+
+fn socks5_codecs(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("socks5");
+
+    // Version, number of methods and methods: "no auth" and "password".
+    let handshake: &[u8] = &[0x05, 0x02, 0x00, 0x02];
+    group.bench_function("parse_handshake_request", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut input = black_box(handshake);
+            HandshakeRequest::read_from(&mut input).await.unwrap()
+        })
+    });
+
+    // CONNECT to 127.0.0.1:80.
+    let relay_ipv4: &[u8] = &[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50];
+    group.bench_function("parse_relay_request_ipv4", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut input = black_box(relay_ipv4);
+            RelayRequest::read_from(&mut input).await.unwrap()
+        })
+    });
+
+    // CONNECT to example.com:443.
+    let relay_domain: &[u8] = &[
+        0x05, 0x01, 0x00, 0x03, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm', 0x01, 0xbb,
+    ];
+    group.bench_function("parse_relay_request_domain", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut input = black_box(relay_domain);
+            RelayRequest::read_from(&mut input).await.unwrap()
+        })
+    });
+
+    let response = RelayResponse::builder()
+        .with_success()
+        .with_bound_address("127.0.0.1:1080".parse().unwrap())
+        .build();
+    group.bench_function("serialize_relay_response", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut output = Vec::with_capacity(32);
+            black_box(&response).write_to(&mut output).await.unwrap();
+            output
+        })
+    });
+
+    group.finish();
+}
+
+fn http_host_extraction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("http");
+
+    group.bench_function("host_from_authority", |b| {
+        b.iter_batched_ref(
+            || Request::get("http://example.com:8080/index.html").body(()).unwrap(),
+            |request| get_host_addr(request).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("host_from_header", |b| {
+        b.iter_batched_ref(
+            || Request::get("/index.html").header("Host", "example.com:8080").body(()).unwrap(),
+            |request| get_host_addr(request).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, socks5_codecs, http_host_extraction);
+criterion_main!(benches);
@@ -0,0 +1,122 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lurk::server::LurkServer;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{copy, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    time::sleep,
+};
+
+/// Amount of data sent through the proxy (and echoed back) per iteration.
+const PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+
+/// Sizes of the chunks the client writes and reads.
+const BUFFER_SIZES: [usize; 4] = [1024, 8 * 1024, 64 * 1024, 256 * 1024];
+
+/// Spawns TCP server echoing everything back to the client.
+async fn spawn_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                copy(&mut reader, &mut writer).await.ok();
+            });
+        }
+    });
+
+    addr
+}
+
+/// Spawns proxy listening on the ephemeral port and waits until it's bound.
+async fn spawn_proxy() -> SocketAddr {
+    let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+    let stats = server.get_stats();
+
+    tokio::spawn(async move { server.run().await.unwrap() });
+
+    loop {
+        if let Some(listener) = stats.get_bound_listeners().first() {
+            return listener.addr;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Opens SOCKS5 tunnel through the proxy to the IPv4 destination.
+async fn connect_through_proxy(proxy: SocketAddr, destination: SocketAddr) -> TcpStream {
+    let SocketAddr::V4(destination) = destination else {
+        unreachable!("echo server is bound to IPv4 address")
+    };
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+
+    let mut response = [0u8; 10];
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    stream.read_exact(&mut response[..2]).await.unwrap();
+    assert_eq!([0x05, 0x00], response[..2]);
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&destination.ip().octets());
+    request.extend_from_slice(&destination.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!([0x05, 0x00], response[..2]);
+
+    stream
+}
+
+/// Writes the payload by chunks and reads it back concurrently.
+async fn relay_payload(stream: TcpStream, buffer_size: usize) {
+    let (mut reader, mut writer) = stream.into_split();
+
+    let write = async move {
+        let chunk = vec![0xAB; buffer_size];
+        for _ in 0..PAYLOAD_SIZE / buffer_size {
+            writer.write_all(&chunk).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+    };
+
+    let read = async move {
+        let mut chunk = vec![0u8; buffer_size];
+        let mut received = 0;
+        while received < PAYLOAD_SIZE {
+            match reader.read(&mut chunk).await.unwrap() {
+                0 => break,
+                n => received += n,
+            }
+        }
+        assert_eq!(PAYLOAD_SIZE, received);
+    };
+
+    tokio::join!(write, read);
+}
+
+fn loopback_relay(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (proxy, destination) = rt.block_on(async { (spawn_proxy().await, spawn_echo_server().await) });
+
+    let mut group = c.benchmark_group("loopback_relay");
+    group.throughput(Throughput::Bytes(2 * PAYLOAD_SIZE as u64));
+    group.sample_size(20);
+
+    for buffer_size in BUFFER_SIZES {
+        group.bench_with_input(BenchmarkId::from_parameter(buffer_size), &buffer_size, |b, &buffer_size| {
+            b.to_async(&rt).iter(|| async move {
+                let stream = connect_through_proxy(proxy, destination).await;
+                relay_payload(stream, buffer_size).await
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, loopback_relay);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+use std::{env, process::Command};
+
+/// Export build details used to identify the running node.
+fn main() {
+    // Hash may be supplied explicitly, e.g. when sources are built outside of the git tree.
+    let git_hash = env::var("LURK_GIT_HASH")
+        .ok()
+        .or_else(git_hash)
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=LURK_GIT_HASH={git_hash}");
+    println!("cargo:rerun-if-env-changed=LURK_GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    // Cargo exposes every enabled feature as CARGO_FEATURE_<NAME> variable.
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=LURK_FEATURES={}", features.join(","));
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok().map(|hash| hash.trim().to_owned())
+}
@@ -0,0 +1,64 @@
+/*
+ * C API embedding Lurk proxy engine into non-Rust applications.
+ *
+ * Engine is configured with the same options as the lurk binary and serves clients
+ * on its own thread. Logging isn't initialized by the engine, as well as the HTTP
+ * endpoint and tenants aren't served by it. Ctrl+C (SIGINT) received by the process
+ * stops the running engine.
+ *
+ * Functions returning int return 0 on success and -1 on failure, the message is
+ * returned by lurk_last_error() then.
+ */
+
+#ifndef LURK_H
+#define LURK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct LurkEngine LurkEngine;
+
+/*
+ * Receives stats events as JSON objects, e.g.
+ * {"event":"connection_opened","peer":"127.0.0.1:50412","label":"SOCKS5"}.
+ * Events are delivered from the engine threads, so user_data has to be safe to use
+ * from any thread and the callback should be cheap and never block.
+ */
+typedef void (*lurk_event_callback)(const char *event, void *user_data);
+
+/* Create engine with the default options. Free it with lurk_engine_free(). */
+LurkEngine *lurk_engine_new(void);
+
+/*
+ * Replace options of the stopped engine with the command line ones, without the
+ * program name, e.g. {"--proxy-port", "1080", "--users-file", "users.json"}.
+ */
+int lurk_engine_configure(LurkEngine *engine, int argc, const char *const *argv);
+
+/* Register the callback (NULL to unregister). Takes effect on the next start. */
+int lurk_engine_set_event_callback(LurkEngine *engine, lurk_event_callback callback, void *user_data);
+
+/* Start serving clients. Returns once the proxy is listening. */
+int lurk_engine_start(LurkEngine *engine);
+
+/* Shut the proxy down gracefully. Returns once it's stopped. */
+int lurk_engine_stop(LurkEngine *engine);
+
+/*
+ * Port the proxy of the running engine is listening on, or -1. It's the one picked
+ * by the OS if the engine has been configured with {"--proxy-port", "0"}.
+ */
+int lurk_engine_proxy_port(LurkEngine *engine);
+
+/* Stop the engine if it's running and free it. */
+void lurk_engine_free(LurkEngine *engine);
+
+/* Message of the last error occurred on the calling thread, or NULL. */
+const char *lurk_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LURK_H */
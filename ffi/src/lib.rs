@@ -0,0 +1,387 @@
+//! C API embedding the proxy engine into non-Rust applications (e.g. GUI wrappers).
+//!
+//! Engine is configured with the same options as the binary, runs on its own thread
+//! and reports stats events to the callback as JSON. See ```include/lurk.h```.
+
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::Parser;
+use lurk::{
+    config::LurkConfig,
+    server::{
+        stats::{
+            node::{LurkListenerKind, LurkNodeState},
+            sink::{LurkStatsEvent, LurkStatsSink},
+        },
+        LurkServer,
+    },
+};
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    net::SocketAddr,
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Callback receiving stats events as JSON, along with the pointer passed on its registration.
+pub type LurkEventCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+pub struct LurkEngine {
+    config: LurkConfig,
+    sink: Option<Arc<LurkCallbackSink>>,
+    running: Option<LurkRunningEngine>,
+}
+
+struct LurkRunningEngine {
+    server: Arc<LurkServer>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl LurkEngine {
+    /// How often the engine being started is checked whether it's up.
+    const START_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    fn new() -> LurkEngine {
+        LurkEngine {
+            config: LurkConfig::parse_from(["lurk"]),
+            sink: None,
+            running: None,
+        }
+    }
+
+    fn configure(&mut self, args: Vec<String>) -> Result<()> {
+        ensure!(self.running.is_none(), "engine can't be configured while it's running");
+        let config = LurkConfig::try_parse_from(std::iter::once("lurk".to_owned()).chain(args))?;
+        ensure!(config.command().is_none(), "commands can't be run by the engine");
+
+        self.config = config;
+        Ok(())
+    }
+
+    /// Build the server and run it on the dedicated thread. Returns once it's listening.
+    fn start(&mut self) -> Result<()> {
+        ensure!(self.running.is_none(), "engine is already running");
+
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        let server = {
+            let _guard = runtime.enter();
+            let mut server_builder = self.config.server_builder()?;
+            if let Some(sink) = &self.sink {
+                server_builder.with_stats_sink(Arc::clone(sink) as Arc<dyn LurkStatsSink>);
+            }
+            Arc::new(server_builder.build())
+        };
+        if let Some(profiles) = server.get_profiles() {
+            server.switch_profile(&profiles.active())?;
+        }
+
+        let thread = thread::Builder::new().name("lurk-engine".to_owned()).spawn({
+            let server = Arc::clone(&server);
+            move || runtime.block_on(server.run())
+        })?;
+
+        while server.get_state() == LurkNodeState::Down {
+            if thread.is_finished() {
+                return join(thread).context("engine failed to start");
+            }
+            thread::sleep(LurkEngine::START_POLL_INTERVAL);
+        }
+
+        self.running = Some(LurkRunningEngine { server, thread });
+        Ok(())
+    }
+
+    /// Address the proxy of the running engine is listening on, e.g. once its port has been picked by the OS.
+    fn proxy_addr(&self) -> Result<SocketAddr> {
+        let running = self.running.as_ref().ok_or_else(|| anyhow!("engine is not running"))?;
+        running
+            .server
+            .get_stats()
+            .get_bound_listeners()
+            .into_iter()
+            .find(|listener| listener.kind == LurkListenerKind::Proxy)
+            .map(|listener| listener.addr)
+            .ok_or_else(|| anyhow!("proxy is not listening"))
+    }
+
+    /// Shut the server down gracefully and wait until it's stopped.
+    fn stop(&mut self) -> Result<()> {
+        let running = self.running.take().ok_or_else(|| anyhow!("engine is not running"))?;
+        running.server.shutdown();
+        join(running.thread)
+    }
+}
+
+fn join(thread: JoinHandle<Result<()>>) -> Result<()> {
+    thread.join().map_err(|_| anyhow!("engine thread has panicked"))?
+}
+
+/// Stats sink passing events to the callback of the embedding application.
+struct LurkCallbackSink {
+    callback: LurkEventCallback,
+    user_data: *mut c_void,
+}
+
+// The application guarantees that user data could be used from any thread (see lurk.h).
+unsafe impl Send for LurkCallbackSink {}
+unsafe impl Sync for LurkCallbackSink {}
+
+impl LurkStatsSink for LurkCallbackSink {
+    fn on_event(&self, event: &LurkStatsEvent<'_>) {
+        let Some(event) = event_to_json(event) else {
+            return;
+        };
+        if let Ok(event) = CString::new(event.to_string()) {
+            (self.callback)(event.as_ptr(), self.user_data);
+        }
+    }
+}
+
+fn event_to_json(event: &LurkStatsEvent<'_>) -> Option<serde_json::Value> {
+    let event = match event {
+        LurkStatsEvent::ConnectionOpened { peer_addr, label } => json!({
+            "event": "connection_opened",
+            "peer": peer_addr.to_string(),
+            "label": label.to_string(),
+        }),
+        LurkStatsEvent::ConnectionClosed {
+            peer_addr,
+            label,
+            l2r_bytes,
+            r2l_bytes,
+        } => json!({
+            "event": "connection_closed",
+            "peer": peer_addr.to_string(),
+            "label": label.to_string(),
+            "l2r_bytes": l2r_bytes,
+            "r2l_bytes": r2l_bytes,
+        }),
+        LurkStatsEvent::ListenerRecovered { addr } => json!({
+            "event": "listener_recovered",
+            "addr": addr.to_string(),
+        }),
+        LurkStatsEvent::AuthResult {
+            peer_addr,
+            user,
+            succeeded,
+        } => json!({
+            "event": "auth_result",
+            "peer": peer_addr.to_string(),
+            "user": user,
+            "succeeded": succeeded,
+        }),
+        LurkStatsEvent::ConfigChanged {
+            changed_by,
+            setting,
+            value,
+        } => json!({
+            "event": "config_changed",
+            "changed_by": changed_by.to_string(),
+            "setting": setting,
+            "value": value,
+        }),
+        _ => return None,
+    };
+
+    Some(event)
+}
+
+/// Run the call, turning errors and panics into -1 along with the last error message.
+fn ffi_call(call: impl FnOnce() -> Result<()>) -> c_int {
+    let err = match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => return 0,
+        Ok(Err(err)) => format!("{:#}", err),
+        Err(_) => "engine call has panicked".to_owned(),
+    };
+
+    let err = CString::new(err).unwrap_or_else(|_| CString::from(c"error message contains NUL byte"));
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(err));
+    -1
+}
+
+/// # Safety
+/// ```engine``` is null or the pointer returned by ```lurk_engine_new```, which hasn't been freed.
+unsafe fn engine_mut<'a>(engine: *mut LurkEngine) -> Result<&'a mut LurkEngine> {
+    unsafe { engine.as_mut() }.ok_or_else(|| anyhow!("engine pointer is null"))
+}
+
+/// Create engine with the default options. It's freed with ```lurk_engine_free```.
+#[no_mangle]
+pub extern "C" fn lurk_engine_new() -> *mut LurkEngine {
+    Box::into_raw(Box::new(LurkEngine::new()))
+}
+
+/// Replace options of the stopped engine with the command line ones (without the program name).
+///
+/// # Safety
+/// ```argv``` points to ```argc``` NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn lurk_engine_configure(engine: *mut LurkEngine, argc: c_int, argv: *const *const c_char) -> c_int {
+    ffi_call(|| {
+        let engine = unsafe { engine_mut(engine) }?;
+        let mut args = Vec::new();
+        for i in 0..usize::try_from(argc)? {
+            let arg = unsafe { *argv.add(i) };
+            ensure!(!arg.is_null(), "argument {} is null", i);
+            args.push(unsafe { CStr::from_ptr(arg) }.to_str()?.to_owned());
+        }
+        engine.configure(args)
+    })
+}
+
+/// Register the callback receiving stats events, replacing the previous one.
+/// Takes effect once the engine is started next time. Passing null callback unregisters it.
+///
+/// # Safety
+/// ```engine``` is null or the pointer returned by ```lurk_engine_new```, which hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn lurk_engine_set_event_callback(
+    engine: *mut LurkEngine,
+    callback: Option<LurkEventCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let engine = unsafe { engine_mut(engine) }?;
+        engine.sink = callback.map(|callback| Arc::new(LurkCallbackSink { callback, user_data }));
+        Ok(())
+    })
+}
+
+/// Start serving clients on the background thread. Returns once the proxy is listening.
+///
+/// # Safety
+/// ```engine``` is null or the pointer returned by ```lurk_engine_new```, which hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn lurk_engine_start(engine: *mut LurkEngine) -> c_int {
+    ffi_call(|| unsafe { engine_mut(engine) }?.start())
+}
+
+/// Shut the proxy down gracefully. Returns once it's stopped.
+///
+/// # Safety
+/// ```engine``` is null or the pointer returned by ```lurk_engine_new```, which hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn lurk_engine_stop(engine: *mut LurkEngine) -> c_int {
+    ffi_call(|| unsafe { engine_mut(engine) }?.stop())
+}
+
+/// Port the proxy of the running engine is listening on, or -1. It's the one picked by the OS if the configured port is 0.
+///
+/// # Safety
+/// ```engine``` is null or the pointer returned by ```lurk_engine_new```, which hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn lurk_engine_proxy_port(engine: *mut LurkEngine) -> c_int {
+    let mut port = -1;
+    match ffi_call(|| {
+        port = c_int::from(unsafe { engine_mut(engine) }?.proxy_addr()?.port());
+        Ok(())
+    }) {
+        0 => port,
+        err => err,
+    }
+}
+
+/// Stop the engine if it's running and free it.
+///
+/// # Safety
+/// ```engine``` is null or the pointer returned by ```lurk_engine_new```, which isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lurk_engine_free(engine: *mut LurkEngine) {
+    if engine.is_null() {
+        return;
+    }
+    let mut engine = unsafe { Box::from_raw(engine) };
+    if engine.running.is_some() {
+        let _ = ffi_call(|| engine.stop());
+    }
+}
+
+/// Message of the last error occurred on the calling thread, or null. Valid until the next failed call.
+#[no_mangle]
+pub extern "C" fn lurk_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(ptr::null(), |err| err.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        net::TcpStream,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+
+    extern "C" fn count_opened_connections(event: *const c_char, user_data: *mut c_void) {
+        let event: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(event) }.to_str().unwrap()).unwrap();
+        if event["event"] == "connection_opened" {
+            assert_eq!("SOCKS5", event["label"]);
+            unsafe { &*(user_data as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(lurk_last_error()) }.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn start_and_stop_engine() {
+        let opened = Box::new(AtomicUsize::new(0));
+        let engine = lurk_engine_new();
+
+        // Port is picked by the OS, so the test doesn't depend on any port being free.
+        let args = [c"--proxy-ipv4", c"127.0.0.1", c"--proxy-port", c"0"].map(|arg| arg.as_ptr());
+        assert_eq!(0, unsafe { lurk_engine_configure(engine, args.len() as c_int, args.as_ptr()) });
+        let user_data = &*opened as *const AtomicUsize as *mut c_void;
+        assert_eq!(0, unsafe {
+            lurk_engine_set_event_callback(engine, Some(count_opened_connections), user_data)
+        });
+
+        assert_eq!(-1, unsafe { lurk_engine_proxy_port(engine) });
+        assert_eq!(0, unsafe { lurk_engine_start(engine) });
+        let port = u16::try_from(unsafe { lurk_engine_proxy_port(engine) }).expect("proxy should be listening");
+        assert_ne!(0, port);
+        assert_eq!(-1, unsafe { lurk_engine_start(engine) });
+        assert_eq!("engine is already running", last_error());
+        assert_eq!(-1, unsafe { lurk_engine_configure(engine, 0, ptr::null()) });
+
+        // Connection labeled as SOCKS5 by the first byte is reported to the callback.
+        TcpStream::connect(("127.0.0.1", port)).unwrap().write_all(&[0x05]).unwrap();
+        let started = Instant::now();
+        while opened.load(Ordering::SeqCst) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "connection should be reported");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(0, unsafe { lurk_engine_stop(engine) });
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        assert_eq!(-1, unsafe { lurk_engine_stop(engine) });
+        assert_eq!("engine is not running", last_error());
+
+        unsafe { lurk_engine_free(engine) };
+    }
+
+    #[test]
+    fn invalid_options() {
+        let engine = lurk_engine_new();
+
+        let args = [c"--proxy-port", c"not-a-port"].map(|arg| arg.as_ptr());
+        assert_eq!(-1, unsafe { lurk_engine_configure(engine, args.len() as c_int, args.as_ptr()) });
+        assert!(last_error().contains("not-a-port"));
+
+        let args = [c"ping"].map(|arg| arg.as_ptr());
+        assert_eq!(-1, unsafe { lurk_engine_configure(engine, args.len() as c_int, args.as_ptr()) });
+        assert_eq!("commands can't be run by the engine", last_error());
+
+        assert_eq!(-1, unsafe { lurk_engine_start(ptr::null_mut()) });
+        unsafe { lurk_engine_free(engine) };
+    }
+}
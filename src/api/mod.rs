@@ -1,23 +1,54 @@
-use crate::server::LurkServer;
+use crate::{
+    auth::{users::LurkUserStore, LurkAuthMethod},
+    common::secret::constant_time_eq,
+    net::{
+        bans::LurkClientBans,
+        tcp::{
+            connection::LurkTcpConnectionLabel,
+            listener::{self, LurkTcpListenerOptions},
+        },
+    },
+    server::{
+        recordings::LurkRecordings,
+        registry::{LurkConnectionEntry, LurkConnectionId},
+        stats::{
+            destinations::LurkDestinationCounters,
+            node::{LurkBoundListener, LurkBuildInfo, LurkListenerKind, LurkNodeState},
+            rates::LurkRates,
+            sink::LurkStatsEvent,
+            LurkCumulativeCounters, LurkServerStats,
+        },
+        LurkServer,
+    },
+};
 use anyhow::Result;
 use bytes::Bytes;
 use chrono::{DateTime, TimeDelta, Utc};
 use http_body_util::Full;
-use hyper::{
-    body::{self},
-    server::conn::http1,
-    service::Service,
-    Request, Response, StatusCode,
-};
+use hyper::{header, http::uri::Authority, server::conn::http1, Method, Request, Response, StatusCode};
 use hyper_util::rt::{TokioIo, TokioTimer};
-use log::{debug, error, info, log_enabled, trace};
+use log::{debug, error, info, log_enabled, trace, warn};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationSeconds};
-use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
-use tokio::net::TcpListener;
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    future::{ready, Ready},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+mod pac;
+mod prometheus;
+pub mod pushgateway;
+pub mod tcp_check;
 
 pub struct LurkHttpEndpoint {
     addr: SocketAddr,
+    listener_options: LurkTcpListenerOptions,
     service: LurkHttpService,
 }
 
@@ -27,19 +58,49 @@ impl LurkHttpEndpoint {
     pub fn new(addr: SocketAddr, node: Arc<LurkServer>) -> LurkHttpEndpoint {
         LurkHttpEndpoint {
             addr,
-            service: LurkHttpService { node },
+            listener_options: LurkTcpListenerOptions::default(),
+            service: LurkHttpService::new(node),
         }
     }
 
+    /// Require the token passed as "Authorization: Bearer <token>" header on all routes except healthcheck.
+    pub fn with_token(mut self, token: impl Into<String>) -> LurkHttpEndpoint {
+        self.service = self.service.with_token(token);
+        self
+    }
+
+    /// Serve status and stats of the tenants, which are run along with the node.
+    pub fn with_tenants(mut self, tenants: impl IntoIterator<Item = (String, Arc<LurkServer>)>) -> LurkHttpEndpoint {
+        self.service = self.service.with_tenants(tenants);
+        self
+    }
+
+    /// Let clients configured by the proxy auto-config file connect to these domains directly.
+    pub fn with_pac_direct_domains(mut self, domains: impl IntoIterator<Item = String>) -> LurkHttpEndpoint {
+        self.service = self.service.with_pac_direct_domains(domains);
+        self
+    }
+
+    /// Tune the socket the endpoint is listening on (backlog, SO_REUSEADDR, TCP_DEFER_ACCEPT).
+    pub fn with_listener_options(mut self, listener_options: LurkTcpListenerOptions) -> LurkHttpEndpoint {
+        self.listener_options = listener_options;
+        self
+    }
+
     /// Asynchronously serve incoming HTTP requests.
     pub async fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = listener::bind_tcp_listener(self.addr, &self.listener_options)?;
         info!("HTTP endpoint is listening on {}", self.addr);
+        self.service
+            .node
+            .get_stats()
+            .on_listener_bound(LurkListenerKind::HttpEndpoint, listener.local_addr()?);
 
         loop {
             let (tcp_stream, client_addr) = listener.accept().await?;
             let io = TokioIo::new(tcp_stream);
-            let service = self.service.clone();
+            let mut service = self.service.clone();
+            service.client_addr = Some(client_addr);
 
             debug!("Incoming HTTP request from {}", client_addr);
 
@@ -59,40 +120,824 @@ impl LurkHttpEndpoint {
     }
 }
 
+/// Management API of the node as a service, both ```hyper``` and ```tower``` one. It's served by
+/// [`LurkHttpEndpoint`], or could be mounted into the embedding application's server (e.g. with
+/// ```axum::Router::nest_service```) along with its own middleware.
+///
+/// Only the head of the request is used, so the body could be of any type. Address of the client
+/// changing the settings is audited if it's passed as ```SocketAddr``` request extension.
 #[derive(Clone)]
-struct LurkHttpService {
+pub struct LurkHttpService {
     node: Arc<LurkServer>,
+    tenants: Arc<BTreeMap<String, Arc<LurkServer>>>,
+    token: Option<Arc<str>>,
+    pac_direct_domains: Arc<[String]>,
+    client_addr: Option<SocketAddr>,
 }
 
-impl Service<Request<body::Incoming>> for LurkHttpService {
-    type Error = anyhow::Error;
-    type Response = Response<Full<Bytes>>;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+impl LurkHttpService {
+    pub fn new(node: Arc<LurkServer>) -> LurkHttpService {
+        LurkHttpService {
+            node,
+            tenants: Arc::new(BTreeMap::new()),
+            token: None,
+            pac_direct_domains: Arc::from([]),
+            client_addr: None,
+        }
+    }
 
-    fn call(&self, request: Request<body::Incoming>) -> Self::Future {
-        let uri_path = request.uri().path();
+    /// Require the token passed as "Authorization: Bearer <token>" header on all routes except healthcheck.
+    pub fn with_token(mut self, token: impl Into<String>) -> LurkHttpService {
+        self.token = Some(Arc::from(token.into()));
+        self
+    }
 
+    /// Serve status and stats of the tenants, which are run along with the node.
+    pub fn with_tenants(mut self, tenants: impl IntoIterator<Item = (String, Arc<LurkServer>)>) -> LurkHttpService {
+        self.tenants = Arc::new(tenants.into_iter().collect());
+        self
+    }
+
+    /// Let clients configured by the proxy auto-config file connect to these domains directly.
+    pub fn with_pac_direct_domains(mut self, domains: impl IntoIterator<Item = String>) -> LurkHttpService {
+        self.pac_direct_domains = domains.into_iter().collect();
+        self
+    }
+
+    /// Serve the request. Problems are described by the response as well.
+    pub fn serve<B>(&self, request: &Request<B>) -> Response<Full<Bytes>> {
         // Dump full request data if trace is enabled
         if log_enabled!(log::Level::Trace) {
-            trace!("{:?}", request);
+            trace!(
+                "{:?} {} '{}' {:?}",
+                request.version(),
+                request.method(),
+                request.uri(),
+                request.headers()
+            );
         } else {
-            info!("{:?} {} '{}'", request.version(), request.method(), uri_path);
+            info!("{:?} {} '{}'", request.version(), request.method(), request.uri().path());
+        }
+
+        let mut service = self.clone();
+        if let Some(client_addr) = request.extensions().get::<SocketAddr>() {
+            service.client_addr = Some(*client_addr);
+        }
+
+        match service.route(request) {
+            Ok(response) => response,
+            Err(problem) => {
+                debug!("Responding to {} '{}' with {:?}", request.method(), request.uri().path(), problem);
+                problem.with_instance(request.uri().path()).into_response()
+            }
+        }
+    }
+
+    /// Route request to the handler. Returns problem description if request can't be served.
+    fn route<B>(&self, request: &Request<B>) -> Result<Response<Full<Bytes>>, LurkApiProblem> {
+        let uri_path = request.uri().path();
+
+        // Healthcheck is left open for load balancers and service registries,
+        // proxy auto-config file is for browsers and OSes, which can't pass the token.
+        if uri_path != "/healthcheck" && uri_path != "/proxy.pac" {
+            self.authorize(request)?;
         }
 
-        let response = match uri_path {
+        match uri_path {
             "/healthcheck" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
                 let node_status = LurkNodeStatus::build(&self.node);
                 trace!("Response to '{uri_path}': {node_status:?}");
-                Response::builder()
-                    .header("Content-Type", "application/json")
-                    .body(node_status.serialize_as_body_chunk())
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&node_status)?))
+            }
+            "/proxy.pac" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let listeners = self.node.get_stats().get_bound_listeners();
+                let host = request_host(request);
+                // Domain rules of the routing go first, so the domains they don't connect to directly are never bypassed.
+                let mut domains = self.node.get_routing().map(|routing| routing.domain_routes()).unwrap_or_default();
+                domains.extend(self.pac_direct_domains.iter().map(|domain| (domain.clone(), true)));
+                let pac = pac::render(&listeners, host.as_ref().map(Authority::host), &domains);
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, pac::CONTENT_TYPE)
+                    .body(Full::new(Bytes::from(pac)))
+                    .expect("HTTP response was not built"))
+            }
+            "/stats" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let node_stats = self.node.get_stats();
+                let snapshot = LurkStatsSnapshot::build(&node_stats, node_stats.get_cumulative_counters());
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&snapshot)?))
             }
-            _ => Response::builder()
-                .status(StatusCode::NOT_IMPLEMENTED)
-                .body(Full::new(Bytes::new())),
+            "/metrics" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let metrics = prometheus::render(&self.node.get_stats());
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, prometheus::CONTENT_TYPE)
+                    .body(Full::new(Bytes::from(metrics)))
+                    .expect("HTTP response was not built"))
+            }
+            "/stats/destinations" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let limit = destinations_limit(request)?;
+                let destinations: Vec<LurkDestinationEntry> = self
+                    .node
+                    .get_stats()
+                    .destinations()
+                    .get_top_by_traffic(limit)
+                    .into_iter()
+                    .map(|(host, counters)| LurkDestinationEntry { host, counters })
+                    .collect();
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&destinations)?))
+            }
+            "/connections" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let mut connections: Vec<LurkConnectionStatus> = self
+                    .node
+                    .get_registry()
+                    .snapshot()
+                    .iter()
+                    .map(|entry| LurkConnectionStatus::build(entry))
+                    .collect();
+                connections.sort_by_key(|connection| connection.id);
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&connections)?))
+            }
+            _ if uri_path.starts_with("/connections/") => {
+                LurkApiProblem::ensure_method(request, &[Method::DELETE])?;
+                let id = connection_id(uri_path)?;
+                let entry = self.node.get_registry().get(id).ok_or_else(|| {
+                    LurkApiProblem::new(LurkApiProblemKind::ConnectionNotFound)
+                        .with_detail(format!("Connection {id} is not served by the node"))
+                        .with_connection_id(id)
+                })?;
+                entry.close();
+                info!("Connection {} from {} has been closed on request", id, entry.peer_addr());
+                Ok(json_response(
+                    StatusCode::OK,
+                    serialize_as_body_chunk(&LurkConnectionStatus::build(&entry))?,
+                ))
+            }
+            "/drain" => {
+                LurkApiProblem::ensure_method(request, &[Method::PUT, Method::DELETE])?;
+                self.node.set_draining(request.method() == Method::PUT);
+                let node_status = LurkNodeStatus::build(&self.node);
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&node_status)?))
+            }
+            "/tenants" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let tenants: Vec<LurkTenantStatus> = self
+                    .tenants
+                    .iter()
+                    .map(|(name, tenant)| LurkTenantStatus {
+                        name: name.clone(),
+                        node: LurkNodeStatus::build(tenant),
+                    })
+                    .collect();
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&tenants)?))
+            }
+            _ if uri_path.starts_with("/tenants/") => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let tenant = self.tenant(uri_path)?;
+                let tenant_stats = tenant.get_stats();
+                let snapshot = LurkStatsSnapshot::build(&tenant_stats, tenant_stats.get_cumulative_counters());
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&snapshot)?))
+            }
+            "/config" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET, Method::PUT])?;
+                if request.method() == Method::PUT {
+                    match query_param(request, "profile") {
+                        Some(name) => {
+                            let previous = self.switch_profile(name)?;
+                            self.audit("profile", &previous, name);
+                        }
+                        None => {
+                            let offered_auth_methods = self.node.get_offered_auth_methods();
+                            let methods = offered_auth_methods_query(request)?;
+                            let previous = offered_auth_methods
+                                .set(methods)
+                                .map_err(|err| LurkApiProblem::new(LurkApiProblemKind::BadRequest).with_detail(format!("{err:#}")))?;
+                            self.audit(
+                                "auth_methods",
+                                &join_auth_methods(&previous),
+                                &join_auth_methods(&offered_auth_methods.get()),
+                            );
+                        }
+                    }
+                }
+                Ok(json_response(
+                    StatusCode::OK,
+                    serialize_as_body_chunk(&LurkConfigStatus::build(&self.node))?,
+                ))
+            }
+            "/users" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let users: Vec<LurkUserStatus> = match self.node.get_users() {
+                    Some(users) => users.names().into_iter().map(|name| LurkUserStatus::build(&users, name)).collect(),
+                    None => Vec::new(),
+                };
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&users)?))
+            }
+            _ if uri_path.starts_with("/users/") => {
+                LurkApiProblem::ensure_method(request, &[Method::POST])?;
+                let name = kicked_user(uri_path)?;
+                if self.node.get_users().is_none_or(|users| users.get_user(name).is_none()) {
+                    return Err(LurkApiProblem::new(LurkApiProblemKind::UserNotFound)
+                        .with_detail(format!("User '{name}' is not known to the node")));
+                }
+
+                let mut kicked: Vec<LurkConnectionStatus> = Vec::new();
+                for entry in self.node.get_registry().snapshot() {
+                    if entry.session().user() == Some(name) {
+                        entry.close();
+                        kicked.push(LurkConnectionStatus::build(&entry));
+                    }
+                }
+                kicked.sort_by_key(|connection| connection.id);
+                info!("{} connection(s) of user '{}' have been closed on request", kicked.len(), name);
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&kicked)?))
+            }
+            "/recordings" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                let recordings = self.recordings()?;
+                Ok(json_response(
+                    StatusCode::OK,
+                    serialize_as_body_chunk(&LurkRecordingsStatus::build(&recordings))?,
+                ))
+            }
+            _ if uri_path.starts_with("/recordings/") => {
+                LurkApiProblem::ensure_method(request, &[Method::PUT, Method::DELETE])?;
+                let recordings = self.recordings()?;
+                let client = path_client(uri_path, "/recordings/")?;
+                if request.method() == Method::PUT {
+                    recordings.enable(client);
+                } else {
+                    recordings.disable(client);
+                }
+                Ok(json_response(
+                    StatusCode::OK,
+                    serialize_as_body_chunk(&LurkRecordingsStatus::build(&recordings))?,
+                ))
+            }
+            "/bans" => {
+                LurkApiProblem::ensure_method(request, &[Method::GET])?;
+                Ok(json_response(
+                    StatusCode::OK,
+                    serialize_as_body_chunk(&LurkBanStatus::build_all(&self.node.get_bans()))?,
+                ))
+            }
+            _ if uri_path.starts_with("/bans/") => {
+                LurkApiProblem::ensure_method(request, &[Method::PUT, Method::DELETE])?;
+                let client = path_client(uri_path, "/bans/")?;
+                let bans = self.node.get_bans();
+                if request.method() == Method::PUT {
+                    bans.ban(client, ban_duration(request)?);
+                } else {
+                    bans.unban(client);
+                }
+                Ok(json_response(
+                    StatusCode::OK,
+                    serialize_as_body_chunk(&LurkBanStatus::build_all(&bans))?,
+                ))
+            }
+            "/stats/reset" => {
+                LurkApiProblem::ensure_method(request, &[Method::POST])?;
+                let node_stats = self.node.get_stats();
+                let snapshot = LurkStatsSnapshot::build(&node_stats, node_stats.take_cumulative_counters());
+                info!("Stats counters have been reset");
+                Ok(json_response(StatusCode::OK, serialize_as_body_chunk(&snapshot)?))
+            }
+            _ => Err(LurkApiProblem::new(LurkApiProblemKind::RouteNotFound)
+                .with_detail(format!("Route '{uri_path}' is not served by the endpoint"))),
+        }
+    }
+}
+
+impl LurkHttpService {
+    /// Fails with "recordings disabled" problem if the node doesn't record tunnels.
+    fn recordings(&self) -> Result<Arc<LurkRecordings>, LurkApiProblem> {
+        self.node.get_recordings().ok_or_else(|| {
+            LurkApiProblem::new(LurkApiProblemKind::RecordingsDisabled).with_detail("Node is started without recordings directory")
+        })
+    }
+
+    /// Find the tenant by "/tenants/{name}/stats" path.
+    fn tenant(&self, uri_path: &str) -> Result<&LurkServer, LurkApiProblem> {
+        let Some(name) = uri_path.trim_start_matches("/tenants/").strip_suffix("/stats") else {
+            return Err(LurkApiProblem::new(LurkApiProblemKind::RouteNotFound)
+                .with_detail(format!("Route '{uri_path}' is not served by the endpoint")));
         };
 
-        Box::pin(async { Ok(response.unwrap()) })
+        self.tenants.get(name).map(Arc::as_ref).ok_or_else(|| {
+            LurkApiProblem::new(LurkApiProblemKind::TenantNotFound).with_detail(format!("Tenant '{name}' is not served by the node"))
+        })
+    }
+
+    /// Switch the node to the profile with given name. Returns the name of the previous profile.
+    fn switch_profile(&self, name: &str) -> Result<String, LurkApiProblem> {
+        if self.node.get_profiles().is_none_or(|profiles| profiles.get(name).is_none()) {
+            return Err(LurkApiProblem::new(LurkApiProblemKind::ProfileNotFound)
+                .with_detail(format!("Profile '{name}' is not defined on the node")));
+        }
+
+        self.node.switch_profile(name).map_err(|err| {
+            LurkApiProblem::new(LurkApiProblemKind::InternalError).with_detail(format!("Profile '{name}' can't be applied: {err:#}"))
+        })
+    }
+
+    /// Record the change of the node setting: it's logged and passed to the stats sinks.
+    fn audit(&self, setting: &str, previous: &str, value: &str) {
+        let changed_by = self
+            .client_addr
+            .map_or_else(|| "unknown client".to_owned(), |addr| addr.to_string());
+        warn!(
+            "Setting '{}' has been changed from '{}' to '{}' by {}",
+            setting, previous, value, changed_by
+        );
+        if let Some(changed_by) = self.client_addr {
+            self.node.get_stats().emit(LurkStatsEvent::ConfigChanged {
+                changed_by,
+                setting,
+                value,
+            });
+        }
+    }
+
+    /// Fails with "unauthorized" problem if the token is required, but request doesn't carry it.
+    fn authorize<B>(&self, request: &Request<B>) -> Result<(), LurkApiProblem> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+
+        let passed = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match passed {
+            Some(passed) if constant_time_eq(passed.as_bytes(), token.as_bytes()) => Ok(()),
+            Some(_) => Err(LurkApiProblem::new(LurkApiProblemKind::Unauthorized).with_detail("Token is invalid")),
+            None => Err(LurkApiProblem::new(LurkApiProblemKind::Unauthorized).with_detail("Bearer token is required")),
+        }
+    }
+}
+
+impl<B> hyper::service::Service<Request<B>> for LurkHttpService {
+    type Error = Infallible;
+    type Response = Response<Full<Bytes>>;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn call(&self, request: Request<B>) -> Self::Future {
+        ready(Ok(self.serve(&request)))
+    }
+}
+
+impl<B> tower_service::Service<Request<B>> for LurkHttpService {
+    type Error = Infallible;
+    type Response = Response<Full<Bytes>>;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        ready(Ok(self.serve(&request)))
+    }
+}
+
+fn json_response(status: StatusCode, body: Full<Bytes>) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .expect("HTTP response was not built")
+}
+
+/// Parse `limit` query parameter of the destinations request.
+fn destinations_limit<B>(request: &Request<B>) -> Result<usize, LurkApiProblem> {
+    const DEFAULT_LIMIT: usize = 100;
+
+    let limit = request
+        .uri()
+        .query()
+        .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("limit=")));
+
+    match limit {
+        None => Ok(DEFAULT_LIMIT),
+        Some(value) => value.parse::<usize>().map_err(|_| {
+            LurkApiProblem::new(LurkApiProblemKind::BadRequest)
+                .with_detail(format!("Query parameter 'limit' should be a non-negative integer, got '{value}'"))
+        }),
+    }
+}
+
+/// Value of the query parameter, if it's passed.
+/// Authority the client has reached the endpoint by.
+fn request_host<B>(request: &Request<B>) -> Option<Authority> {
+    match request.uri().authority() {
+        Some(authority) => Some(authority.clone()),
+        None => request.headers().get(header::HOST)?.to_str().ok()?.parse().ok(),
+    }
+}
+
+fn query_param<'a, B>(request: &'a Request<B>, name: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Parse `duration_secs` query parameter of the ban request.
+fn ban_duration<B>(request: &Request<B>) -> Result<Duration, LurkApiProblem> {
+    const DEFAULT_DURATION: Duration = Duration::from_secs(3600);
+
+    match query_param(request, "duration_secs") {
+        None => Ok(DEFAULT_DURATION),
+        Some(value) => value.parse::<u64>().map(Duration::from_secs).map_err(|_| {
+            LurkApiProblem::new(LurkApiProblemKind::BadRequest).with_detail(format!(
+                "Query parameter 'duration_secs' should be a non-negative integer, got '{value}'"
+            ))
+        }),
+    }
+}
+
+/// Parse `auth_methods` query parameter of the config request, e.g. "none,password".
+fn offered_auth_methods_query<B>(request: &Request<B>) -> Result<Vec<LurkAuthMethod>, LurkApiProblem> {
+    let value = query_param(request, "auth_methods").ok_or_else(|| {
+        LurkApiProblem::new(LurkApiProblemKind::BadRequest).with_detail("Query parameter 'auth_methods' or 'profile' is required")
+    })?;
+
+    value
+        .split(',')
+        .map(|method| method.parse::<LurkAuthMethod>())
+        .collect::<Result<_>>()
+        .map_err(|err| LurkApiProblem::new(LurkApiProblemKind::BadRequest).with_detail(format!("{err:#}")))
+}
+
+fn join_auth_methods(methods: &[LurkAuthMethod]) -> String {
+    methods.iter().map(LurkAuthMethod::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// Parse identifier of the connection from "/connections/{id}" path.
+fn connection_id(uri_path: &str) -> Result<LurkConnectionId, LurkApiProblem> {
+    let value = uri_path.trim_start_matches("/connections/");
+    value.parse::<LurkConnectionId>().map_err(|_| {
+        LurkApiProblem::new(LurkApiProblemKind::BadRequest)
+            .with_detail(format!("Connection identifier should be a non-negative integer, got '{value}'"))
+    })
+}
+
+/// Parse name of the user from "/users/{name}/kick" path.
+fn kicked_user(uri_path: &str) -> Result<&str, LurkApiProblem> {
+    match uri_path.trim_start_matches("/users/").strip_suffix("/kick") {
+        Some(name) if !name.is_empty() && !name.contains('/') => Ok(name),
+        _ => {
+            Err(LurkApiProblem::new(LurkApiProblemKind::RouteNotFound)
+                .with_detail(format!("Route '{uri_path}' is not served by the endpoint")))
+        }
+    }
+}
+
+/// Parse IP address of the client from "/recordings/{ip}" or "/bans/{ip}" path.
+fn path_client(uri_path: &str, prefix: &str) -> Result<IpAddr, LurkApiProblem> {
+    let value = uri_path.trim_start_matches(prefix);
+    value.parse::<IpAddr>().map_err(|_| {
+        LurkApiProblem::new(LurkApiProblemKind::BadRequest).with_detail(format!("Client should be an IP address, got '{value}'"))
+    })
+}
+
+/// Try to serialize input data. Returns serialized bytes on success.
+fn serialize_as_body_chunk<T: Serialize>(value: &T) -> Result<Full<Bytes>, LurkApiProblem> {
+    match serde_json::to_string(value) {
+        Ok(bytes) => Ok(Full::new(Bytes::from(bytes))),
+        Err(err) => {
+            error!("Error occured during body serialization: {err:?}");
+            Err(LurkApiProblem::new(LurkApiProblemKind::InternalError).with_detail("Unable to serialize response body"))
+        }
+    }
+}
+
+/// Kinds of problems reported by the endpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LurkApiProblemKind {
+    BadRequest,
+    Unauthorized,
+    RouteNotFound,
+    ConnectionNotFound,
+    UserNotFound,
+    TenantNotFound,
+    ProfileNotFound,
+    RecordingsDisabled,
+    MethodNotAllowed,
+    InternalError,
+}
+
+impl LurkApiProblemKind {
+    #[rustfmt::skip]
+    fn details(self) -> (&'static str, &'static str, StatusCode) {
+        match self {
+            LurkApiProblemKind::BadRequest         => ("urn:lurk:problem:bad-request",          "Bad request",          StatusCode::BAD_REQUEST),
+            LurkApiProblemKind::Unauthorized       => ("urn:lurk:problem:unauthorized",         "Unauthorized",         StatusCode::UNAUTHORIZED),
+            LurkApiProblemKind::RouteNotFound      => ("urn:lurk:problem:route-not-found",      "Route not found",      StatusCode::NOT_FOUND),
+            LurkApiProblemKind::ConnectionNotFound => ("urn:lurk:problem:connection-not-found", "Connection not found", StatusCode::NOT_FOUND),
+            LurkApiProblemKind::UserNotFound       => ("urn:lurk:problem:user-not-found",       "User not found",       StatusCode::NOT_FOUND),
+            LurkApiProblemKind::TenantNotFound     => ("urn:lurk:problem:tenant-not-found",     "Tenant not found",     StatusCode::NOT_FOUND),
+            LurkApiProblemKind::ProfileNotFound    => ("urn:lurk:problem:profile-not-found",    "Profile not found",    StatusCode::NOT_FOUND),
+            LurkApiProblemKind::RecordingsDisabled => ("urn:lurk:problem:recordings-disabled",  "Recordings disabled",  StatusCode::CONFLICT),
+            LurkApiProblemKind::MethodNotAllowed   => ("urn:lurk:problem:method-not-allowed",   "Method not allowed",   StatusCode::METHOD_NOT_ALLOWED),
+            LurkApiProblemKind::InternalError      => ("urn:lurk:problem:internal-error",       "Internal error",       StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// Error description sent as `application/problem+json` body (RFC 9457).
+#[derive(Debug)]
+struct LurkApiProblem {
+    kind: LurkApiProblemKind,
+    detail: Option<String>,
+    instance: Option<String>,
+    connection_id: Option<LurkConnectionId>,
+    allowed_methods: &'static [Method],
+}
+
+/// Serialized representation of the problem.
+#[derive(Serialize)]
+struct LurkApiProblemBody<'a> {
+    /// URI reference identifying the problem type.
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+
+    /// Short, human-readable summary of the problem type.
+    title: &'static str,
+
+    /// HTTP status code of the response.
+    status: u16,
+
+    /// Explanation specific to this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+
+    /// Request path the problem has occurred on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<&'a str>,
+
+    /// Identifier of the proxied connection the problem relates to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_id: Option<LurkConnectionId>,
+}
+
+impl LurkApiProblem {
+    const CONTENT_TYPE: &'static str = "application/problem+json";
+
+    fn new(kind: LurkApiProblemKind) -> LurkApiProblem {
+        LurkApiProblem {
+            kind,
+            detail: None,
+            instance: None,
+            connection_id: None,
+            allowed_methods: &[],
+        }
+    }
+
+    /// Fails with "method not allowed" problem if request method isn't among allowed ones.
+    fn ensure_method<B>(request: &Request<B>, allowed: &'static [Method]) -> Result<(), LurkApiProblem> {
+        if allowed.contains(request.method()) {
+            return Ok(());
+        }
+
+        let mut problem = LurkApiProblem::new(LurkApiProblemKind::MethodNotAllowed).with_detail(format!(
+            "Method {} is not allowed on '{}'",
+            request.method(),
+            request.uri().path()
+        ));
+        problem.allowed_methods = allowed;
+
+        Err(problem)
+    }
+
+    fn with_detail(mut self, detail: impl Into<String>) -> LurkApiProblem {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    fn with_connection_id(mut self, connection_id: LurkConnectionId) -> LurkApiProblem {
+        self.connection_id = Some(connection_id);
+        self
+    }
+
+    fn with_instance(mut self, instance: impl Into<String>) -> LurkApiProblem {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    fn into_response(self) -> Response<Full<Bytes>> {
+        let (problem_type, title, status) = self.kind.details();
+        let body = LurkApiProblemBody {
+            problem_type,
+            title,
+            status: status.as_u16(),
+            detail: self.detail.as_deref(),
+            instance: self.instance.as_deref(),
+            connection_id: self.connection_id,
+        };
+
+        let mut builder = Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, LurkApiProblem::CONTENT_TYPE);
+
+        if self.kind == LurkApiProblemKind::Unauthorized {
+            builder = builder.header(header::WWW_AUTHENTICATE, "Bearer");
+        }
+
+        if !self.allowed_methods.is_empty() {
+            let allowed = self.allowed_methods.iter().map(Method::as_str).collect::<Vec<&str>>().join(", ");
+            builder = builder.header(header::ALLOW, allowed);
+        }
+
+        let body = serde_json::to_string(&body).map(Bytes::from).unwrap_or_default();
+        builder.body(Full::new(body)).expect("HTTP response was not built")
+    }
+}
+
+/// Full snapshot of the node stats.
+#[derive(Serialize, Debug)]
+struct LurkStatsSnapshot {
+    /// UTC timestamp made when snapshot was taken.
+    snapshot_utc_ts: DateTime<Utc>,
+
+    /// UTC timestamp made when node started to accept connections.
+    started_utc_ts: Option<DateTime<Utc>>,
+
+    /// Number of connections which are being served right now.
+    active_connections: u64,
+
+    /// Counters accumulated since the start or the last reset.
+    counters: LurkCumulativeCounters,
+
+    /// Rates over rolling windows.
+    rates: LurkRates,
+}
+
+impl LurkStatsSnapshot {
+    fn build(node_stats: &LurkServerStats, counters: LurkCumulativeCounters) -> LurkStatsSnapshot {
+        LurkStatsSnapshot {
+            snapshot_utc_ts: Utc::now(),
+            started_utc_ts: node_stats.is_server_started().then(|| node_stats.get_started_utc_timestamp()),
+            active_connections: node_stats.get_active_connections(),
+            counters,
+            rates: node_stats.get_rates(),
+        }
+    }
+}
+
+/// Aggregated stats of the single destination host.
+#[derive(Serialize, Debug)]
+struct LurkDestinationEntry {
+    /// Destination domain name or IP address.
+    host: String,
+
+    #[serde(flatten)]
+    counters: LurkDestinationCounters,
+}
+
+/// Connection which is being served by the node.
+#[derive(Serialize, Debug)]
+struct LurkConnectionStatus {
+    /// Identifier the connection could be closed by.
+    id: LurkConnectionId,
+
+    /// Address of the client.
+    peer_addr: SocketAddr,
+
+    /// Protocol the client speaks.
+    label: String,
+
+    /// Name of the authenticated user.
+    user: Option<String>,
+
+    /// Destination the client has requested to connect to.
+    destination: Option<String>,
+
+    /// UTC timestamp made when connection was accepted.
+    established_utc_ts: DateTime<Utc>,
+
+    /// Bytes relayed so far from client to destination.
+    l2r_bytes: u64,
+
+    /// Bytes relayed so far from destination to client.
+    r2l_bytes: u64,
+}
+
+impl LurkConnectionStatus {
+    fn build(entry: &LurkConnectionEntry) -> LurkConnectionStatus {
+        LurkConnectionStatus {
+            id: entry.id(),
+            peer_addr: entry.peer_addr(),
+            label: entry.label().to_string(),
+            user: entry.session().user().map(str::to_owned),
+            destination: entry.session().destination().map(str::to_owned),
+            established_utc_ts: entry.established_ts(),
+            l2r_bytes: entry.activity().l2r_bytes(),
+            r2l_bytes: entry.activity().r2l_bytes(),
+        }
+    }
+}
+
+/// Sessions of the user allowed to access the proxy.
+#[derive(Serialize, Debug)]
+struct LurkUserStatus {
+    /// Name of the user.
+    name: String,
+
+    /// Number of sessions the user has right now.
+    active_sessions: usize,
+
+    /// Limit of simultaneous sessions of the user.
+    max_sessions: Option<usize>,
+}
+
+impl LurkUserStatus {
+    fn build(users: &LurkUserStore, name: &str) -> LurkUserStatus {
+        LurkUserStatus {
+            name: name.to_owned(),
+            active_sessions: users.active_sessions(name),
+            max_sessions: users.max_sessions(name),
+        }
+    }
+}
+
+/// Settings of the node, which could be changed at runtime.
+#[derive(Serialize, Debug)]
+struct LurkConfigStatus {
+    /// Built-in SOCKS5 authentication methods offered to new clients, in the order of preference.
+    auth_methods: Vec<String>,
+
+    /// Active profile, if profiles are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+
+    /// Profiles the node could be switched to.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    profiles: Vec<String>,
+}
+
+impl LurkConfigStatus {
+    fn build(node: &LurkServer) -> LurkConfigStatus {
+        let profiles = node.get_profiles();
+        LurkConfigStatus {
+            auth_methods: node
+                .get_offered_auth_methods()
+                .get()
+                .iter()
+                .map(LurkAuthMethod::to_string)
+                .collect(),
+            profile: profiles.as_ref().map(|profiles| profiles.active()),
+            profiles: profiles
+                .as_ref()
+                .map(|profiles| profiles.names().into_iter().map(str::to_owned).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Clients whose tunnels are recorded.
+#[derive(Serialize, Debug)]
+struct LurkRecordingsStatus {
+    /// Directory recordings are written to.
+    dir: PathBuf,
+
+    /// Clients new tunnels of which are recorded (besides the ones selected by the policy).
+    clients: Vec<IpAddr>,
+}
+
+impl LurkRecordingsStatus {
+    fn build(recordings: &LurkRecordings) -> LurkRecordingsStatus {
+        LurkRecordingsStatus {
+            dir: recordings.dir().to_owned(),
+            clients: recordings.clients(),
+        }
+    }
+}
+
+/// Client banned from connecting to the node (and the rest of the cluster).
+#[derive(Serialize, Debug)]
+struct LurkBanStatus {
+    client: IpAddr,
+
+    /// Moment the ban expires.
+    expires_utc_ts: DateTime<Utc>,
+}
+
+impl LurkBanStatus {
+    fn build_all(bans: &LurkClientBans) -> Vec<LurkBanStatus> {
+        bans.list()
+            .into_iter()
+            .map(|(client, expires_utc_ts)| LurkBanStatus { client, expires_utc_ts })
+            .collect()
     }
 }
 
@@ -100,12 +945,82 @@ impl Service<Request<body::Incoming>> for LurkHttpService {
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 struct LurkNodeStatus {
+    /// Readiness of the node to take new clients.
+    state: LurkNodeState,
+
     /// Timespan between "started" and "current" timestamps.
     #[serde_as(as = "Option<DurationSeconds<i64>>")]
     uptime_secs: Option<TimeDelta>,
 
     /// UTC timestamp made when node started to accept connections.
     started_utc_ts: Option<DateTime<Utc>>,
+
+    /// Number of protocol responses which peers haven't accepted in time.
+    response_write_timeouts: u64,
+
+    /// Number of tunnels closed as the clients were reading slower than allowed.
+    slow_read_closures: u64,
+
+    /// Number of tunnels closed as they have reached their maximum lifetime.
+    lifetime_closures: u64,
+
+    /// Number of connections with the upstream proxies dropped as their TLS certificates haven't been verified.
+    upstream_tls_verification_failures: u64,
+
+    /// Counters of connections handled by the node.
+    connections: LurkNodeConnectionsStatus,
+
+    /// Amount of data relayed by closed connections.
+    traffic: LurkNodeTrafficStatus,
+
+    /// Connection and traffic rates over rolling windows.
+    rates: LurkRates,
+
+    /// Version, git hash and features of the running binary.
+    build: LurkBuildInfo,
+
+    /// Addresses the node is listening on.
+    listeners: Vec<LurkBoundListener>,
+}
+
+/// Health status of the tenant served on its own listener.
+#[derive(Serialize, Debug)]
+struct LurkTenantStatus {
+    /// Name of the tenant.
+    name: String,
+
+    #[serde(flatten)]
+    node: LurkNodeStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LurkNodeConnectionsStatus {
+    /// Total number of accepted connections.
+    accepted: u64,
+    /// Number of connections which are being served right now.
+    active: u64,
+    /// Number of failures happened while accepting connections.
+    accept_errors: u64,
+    /// Number of connections dropped as the clients aren't allowed to use the proxy.
+    denied_clients: u64,
+    /// Number of connections dropped as their clients open connections too fast.
+    throttled_connections: u64,
+    /// Number of times broken listener has been bound again.
+    listener_recoveries: u64,
+    /// Total number of accepted connections per traffic label.
+    socks5: u64,
+    socks4: u64,
+    http: u64,
+    transparent: u64,
+    unknown: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LurkNodeTrafficStatus {
+    /// Bytes sent from clients to endpoints.
+    l2r_bytes: u64,
+    /// Bytes sent from endpoints to clients.
+    r2l_bytes: u64,
 }
 
 impl LurkNodeStatus {
@@ -121,26 +1036,139 @@ impl LurkNodeStatus {
             started_utc_ts = Some(node_stats.get_started_utc_timestamp());
         }
 
+        let (l2r_bytes, r2l_bytes) = node_stats.get_relayed_bytes();
+
         LurkNodeStatus {
+            state: node.get_state(),
             uptime_secs,
             started_utc_ts,
+            response_write_timeouts: node_stats.get_response_write_timeouts(),
+            slow_read_closures: node_stats.get_slow_read_closures(),
+            lifetime_closures: node_stats.get_lifetime_closures(),
+            upstream_tls_verification_failures: node_stats.get_upstream_tls_verification_failures(),
+            connections: LurkNodeConnectionsStatus {
+                accepted: node_stats.get_accepted_connections(),
+                active: node_stats.get_active_connections(),
+                accept_errors: node_stats.get_accept_errors(),
+                denied_clients: node_stats.get_denied_clients(),
+                throttled_connections: node_stats.get_throttled_connections(),
+                listener_recoveries: node_stats.get_listener_recoveries(),
+                socks5: node_stats.get_connections_with_label(LurkTcpConnectionLabel::Socks5),
+                socks4: node_stats.get_connections_with_label(LurkTcpConnectionLabel::Socks4),
+                http: node_stats.get_connections_with_label(LurkTcpConnectionLabel::Http),
+                transparent: node_stats.get_connections_with_label(LurkTcpConnectionLabel::Transparent),
+                unknown: node_stats.get_connections_with_label(LurkTcpConnectionLabel::Unknown(0)),
+            },
+            traffic: LurkNodeTrafficStatus { l2r_bytes, r2l_bytes },
+            rates: node_stats.get_rates(),
+            build: node_stats.get_build_info().clone(),
+            listeners: node_stats.get_bound_listeners(),
         }
     }
+}
 
-    /// Try to serialize input data. Returns serialized bytes on succes.
-    /// On failure, empty bytes is returned.
-    fn serialize_as_body_chunk(&self) -> Full<Bytes> {
-        let bytes = match serde_json::to_string(&self) {
-            Ok(bytes) => Bytes::from(bytes),
-            Err(err) => {
-                error!(
-                    "Error occured during body serialization: {err:?}.
-                    Empty body has been returned."
-                );
-                Bytes::new()
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::routing::LurkRouting;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    fn node() -> Arc<LurkServer> {
+        Arc::new(LurkServer::new("127.0.0.1:0".parse().unwrap()))
+    }
+
+    fn get(uri: &str) -> Request<Full<Bytes>> {
+        Request::get(uri).body(Full::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_as_tower_service() {
+        let service = LurkHttpService::new(node()).with_token("secret");
+
+        let response = service.clone().oneshot(get("/healthcheck")).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = service.clone().oneshot(get("/stats")).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let response = service.clone().oneshot(get("/unknown")).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[tokio::test]
+    async fn serve_proxy_auto_config() {
+        let routing = LurkRouting::parse(r#"{"rules": [{"id": "ads", "action": "block", "domain_suffix": "ads.internal"}]}"#).unwrap();
+        let node = Arc::new(LurkServer::builder("127.0.0.1:0".parse().unwrap()).with_routing(routing).build());
+        node.get_stats()
+            .on_listener_bound(LurkListenerKind::Https, "0.0.0.0:8443".parse().unwrap());
+        let service = LurkHttpService::new(node)
+            .with_token("secret")
+            .with_pac_direct_domains(["internal".to_owned()]);
+
+        let request = Request::get("/proxy.pac")
+            .header(header::HOST, "proxy.lan:8080")
+            .body(Full::<Bytes>::default())
+            .unwrap();
+        let response = service.clone().oneshot(request).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(pac::CONTENT_TYPE, response.headers()[header::CONTENT_TYPE]);
+
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("dnsDomainIs(host, \".internal\")) return \"DIRECT\";"));
+        assert!(body.contains("dnsDomainIs(host, \".ads.internal\")) return \"HTTPS proxy.lan:8443\";"));
+        assert!(body.find(".ads.internal") < body.find("\".internal"));
+        assert!(body.contains("return \"HTTPS proxy.lan:8443\";"));
+    }
+
+    #[tokio::test]
+    async fn ban_clients() {
+        let node = node();
+        let service = LurkHttpService::new(Arc::clone(&node));
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+        let request = |method: Method, uri: &str| Request::builder().method(method).uri(uri).body(Full::<Bytes>::default()).unwrap();
+        let bans = |response: Response<Full<Bytes>>| async {
+            let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
         };
 
-        Full::new(bytes)
+        let response = service
+            .clone()
+            .oneshot(request(Method::PUT, "/bans/203.0.113.1?duration_secs=60"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("203.0.113.1", bans(response).await[0]["client"]);
+        assert!(node.get_bans().is_banned(client));
+
+        let response = service.clone().oneshot(get("/bans")).await.unwrap();
+        assert_eq!(1, bans(response).await.as_array().unwrap().len());
+
+        let response = service.clone().oneshot(request(Method::DELETE, "/bans/203.0.113.1")).await.unwrap();
+        assert_eq!(serde_json::json!([]), bans(response).await);
+        assert!(!node.get_bans().is_banned(client));
+
+        for uri in ["/bans/client", "/bans/203.0.113.1?duration_secs=hour"] {
+            let response = service.clone().oneshot(request(Method::PUT, uri)).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_behind_embedder_middleware() {
+        // Authorization is done by the embedding application.
+        let service = ServiceBuilder::new()
+            .map_request(|mut request: Request<Full<Bytes>>| {
+                request.extensions_mut().insert(SocketAddr::from(([10, 0, 0, 1], 50412)));
+                request
+            })
+            .service(LurkHttpService::new(node()));
+
+        let response = service.clone().oneshot(get("/stats")).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = service.clone().oneshot(get("/unknown")).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!("application/problem+json", response.headers()[header::CONTENT_TYPE]);
     }
 }
@@ -0,0 +1,114 @@
+use crate::server::stats::node::{LurkBoundListener, LurkListenerKind};
+use std::{fmt::Write, net::SocketAddr};
+
+/// Content type of the proxy auto-config file.
+pub const CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Render proxy auto-config file pointing clients to the proxy listeners of the node.
+///
+/// Listeners bound to the unspecified address are advertised with ```host``` the client
+/// has reached the HTTP endpoint by. ```domains``` (and their subdomains) paired with whether
+/// they are connected to directly are checked in order, the first matching one decides.
+pub fn render(listeners: &[LurkBoundListener], host: Option<&str>, domains: &[(String, bool)]) -> String {
+    let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+
+    let mut proxies = Vec::new();
+    for listener in listeners.iter().filter(|listener| listener.kind == LurkListenerKind::Https) {
+        proxies.push(format!("HTTPS {}", advertised_addr(listener.addr, host)));
+    }
+    for listener in listeners.iter().filter(|listener| listener.kind == LurkListenerKind::Proxy) {
+        let addr = advertised_addr(listener.addr, host);
+        if cfg!(feature = "http") {
+            proxies.push(format!("PROXY {addr}"));
+        }
+        if cfg!(feature = "socks5") {
+            proxies.push(format!("SOCKS5 {addr}"));
+        }
+        if cfg!(feature = "socks4") {
+            proxies.push(format!("SOCKS {addr}"));
+        }
+    }
+
+    // Clients aren't let to bypass the proxy silently, unless it isn't listening at all.
+    let result = if proxies.is_empty() {
+        "DIRECT".to_owned()
+    } else {
+        proxies.join("; ")
+    };
+
+    for (domain, direct) in domains {
+        let domain = domain.trim_start_matches('.');
+        let _ = writeln!(
+            pac,
+            "    if (host == {exact} || dnsDomainIs(host, {suffix})) return {result};",
+            exact = js_string(domain),
+            suffix = js_string(&format!(".{domain}")),
+            result = js_string(if *direct { "DIRECT" } else { &result }),
+        );
+    }
+
+    let _ = writeln!(pac, "    return {};", js_string(&result));
+    pac.push_str("}\n");
+
+    pac
+}
+
+/// Address of the listener reachable by the clients.
+fn advertised_addr(addr: SocketAddr, host: Option<&str>) -> String {
+    match host {
+        Some(host) if addr.ip().is_unspecified() => format!("{}:{}", host, addr.port()),
+        _ => addr.to_string(),
+    }
+}
+
+/// JavaScript string literal with the value escaped.
+fn js_string(value: &str) -> String {
+    serde_json::to_string(value).expect("string should be serialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(kind: LurkListenerKind, addr: &str) -> LurkBoundListener {
+        LurkBoundListener {
+            kind,
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "http", feature = "socks5", feature = "socks4"))]
+    fn render_proxies_and_direct_domains() {
+        let listeners = [
+            listener(LurkListenerKind::Proxy, "0.0.0.0:1080"),
+            listener(LurkListenerKind::HttpEndpoint, "0.0.0.0:8080"),
+            listener(LurkListenerKind::Https, "10.0.0.1:8443"),
+        ];
+        let domains = [
+            ("internal".to_owned(), true),
+            ("ads.corp".to_owned(), false),
+            (".corp".to_owned(), true),
+        ];
+        let pac = render(&listeners, Some("proxy.lan"), &domains);
+
+        assert_eq!(
+            "function FindProxyForURL(url, host) {\n\
+             \x20   if (host == \"internal\" || dnsDomainIs(host, \".internal\")) return \"DIRECT\";\n\
+             \x20   if (host == \"ads.corp\" || dnsDomainIs(host, \".ads.corp\")) \
+             return \"HTTPS 10.0.0.1:8443; PROXY proxy.lan:1080; SOCKS5 proxy.lan:1080; SOCKS proxy.lan:1080\";\n\
+             \x20   if (host == \"corp\" || dnsDomainIs(host, \".corp\")) return \"DIRECT\";\n\
+             \x20   return \"HTTPS 10.0.0.1:8443; PROXY proxy.lan:1080; SOCKS5 proxy.lan:1080; SOCKS proxy.lan:1080\";\n\
+             }\n",
+            pac
+        );
+    }
+
+    #[test]
+    fn render_without_host() {
+        let listeners = [listener(LurkListenerKind::Https, "0.0.0.0:8443")];
+        assert!(render(&listeners, None, &[]).contains("return \"HTTPS 0.0.0.0:8443\";"));
+        assert!(render(&[], None, &[("\"quoted\"".to_owned(), true)]).contains("host == \"\\\"quoted\\\"\""));
+        assert!(render(&[], None, &[]).contains("return \"DIRECT\";"));
+    }
+}
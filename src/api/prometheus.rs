@@ -0,0 +1,247 @@
+use crate::{
+    net::tcp::connection::LurkTcpConnectionLabel,
+    server::stats::{histogram::LurkHistogramSnapshot, rates::LurkRateWindows, LurkServerStats},
+};
+use std::fmt::Write;
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Render node stats in the Prometheus text exposition format.
+pub fn render(stats: &LurkServerStats) -> String {
+    let mut writer = LurkPrometheusWriter::default();
+    let (l2r_bytes, r2l_bytes) = stats.get_relayed_bytes();
+    let build_info = stats.get_build_info();
+
+    writer.info(
+        "lurk_build_info",
+        "Version and git hash of the running build",
+        &[("version", &build_info.version), ("git_hash", &build_info.git_hash)],
+    );
+
+    writer.counter(
+        "lurk_connections_accepted_total",
+        "Total number of accepted connections",
+        "label",
+        &[
+            ("socks5", stats.get_connections_with_label(LurkTcpConnectionLabel::Socks5)),
+            ("socks4", stats.get_connections_with_label(LurkTcpConnectionLabel::Socks4)),
+            ("http", stats.get_connections_with_label(LurkTcpConnectionLabel::Http)),
+            ("transparent", stats.get_connections_with_label(LurkTcpConnectionLabel::Transparent)),
+            ("unknown", stats.get_connections_with_label(LurkTcpConnectionLabel::Unknown(0))),
+        ],
+    );
+    writer.gauge(
+        "lurk_connections_active",
+        "Number of connections which are being served right now",
+        stats.get_active_connections() as f64,
+    );
+    writer.simple_counter(
+        "lurk_accept_errors_total",
+        "Number of failures happened while accepting connections",
+        stats.get_accept_errors(),
+    );
+    writer.simple_counter(
+        "lurk_denied_clients_total",
+        "Number of connections dropped as the clients aren't allowed to use the proxy",
+        stats.get_denied_clients(),
+    );
+    writer.simple_counter(
+        "lurk_throttled_connections_total",
+        "Number of connections dropped as their clients open connections too fast",
+        stats.get_throttled_connections(),
+    );
+    writer.simple_counter(
+        "lurk_listener_recoveries_total",
+        "Number of times broken proxy listener has been bound again",
+        stats.get_listener_recoveries(),
+    );
+    writer.simple_counter(
+        "lurk_response_write_timeouts_total",
+        "Number of protocol responses which peers haven't accepted in time",
+        stats.get_response_write_timeouts(),
+    );
+    writer.simple_counter(
+        "lurk_slow_read_closures_total",
+        "Number of tunnels closed as the clients were reading slower than allowed",
+        stats.get_slow_read_closures(),
+    );
+    writer.simple_counter(
+        "lurk_lifetime_closures_total",
+        "Number of tunnels closed as they have reached their maximum lifetime",
+        stats.get_lifetime_closures(),
+    );
+    writer.simple_counter(
+        "lurk_upstream_tls_verification_failures_total",
+        "Number of connections with the upstream proxies dropped as their TLS certificates haven't been verified",
+        stats.get_upstream_tls_verification_failures(),
+    );
+    writer.simple_counter(
+        "lurk_l2r_bytes_total",
+        "Bytes relayed from clients to destinations by closed connections",
+        l2r_bytes,
+    );
+    writer.simple_counter(
+        "lurk_r2l_bytes_total",
+        "Bytes relayed from destinations to clients by closed connections",
+        r2l_bytes,
+    );
+
+    let (auth_successes, auth_failures) = stats.get_auth_results();
+    writer.counter(
+        "lurk_auth_attempts_total",
+        "Number of user authentication attempts",
+        "result",
+        &[("success", auth_successes), ("failure", auth_failures)],
+    );
+
+    writer.header(
+        "lurk_destination_denials_total",
+        "Number of connections refused by policy rules, blocklists and DNSBL zones",
+        "counter",
+    );
+    for (source, rule, count) in stats.get_destination_denials() {
+        writer.sample(
+            "lurk_destination_denials_total",
+            &[("source", &source.to_string()), ("rule", &rule)],
+            count,
+        );
+    }
+
+    let rates = stats.get_rates();
+    writer.rate(
+        "lurk_connections_per_second",
+        "Accepted connections per second over rolling window",
+        &rates.connections_per_sec,
+    );
+    writer.rate(
+        "lurk_bytes_per_second",
+        "Relayed bytes per second over rolling window",
+        &rates.bytes_per_sec,
+    );
+
+    writer.histogram(
+        "lurk_connect_latency_seconds",
+        "Time taken to establish TCP connection with the destination",
+        &stats.connect_latency().snapshot(),
+    );
+    writer.histogram(
+        "lurk_handshake_duration_seconds",
+        "Time from the first client byte till the tunnel is ready",
+        &stats.handshake_duration().snapshot(),
+    );
+    writer.histogram(
+        "lurk_tunnel_lifetime_seconds",
+        "Time the tunnel has been relaying data",
+        &stats.tunnel_lifetime().snapshot(),
+    );
+
+    writer.buffer
+}
+
+#[derive(Default)]
+struct LurkPrometheusWriter {
+    buffer: String,
+}
+
+impl LurkPrometheusWriter {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.buffer, "# HELP {name} {help}");
+        let _ = writeln!(self.buffer, "# TYPE {name} {kind}");
+    }
+
+    fn simple_counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.buffer, "{name} {value}");
+    }
+
+    /// Counter split by values of the ```label_name``` label.
+    fn counter(&mut self, name: &str, help: &str, label_name: &str, values: &[(&str, u64)]) {
+        self.header(name, help, "counter");
+        for (label, value) in values {
+            let _ = writeln!(self.buffer, "{name}{{{label_name}=\"{label}\"}} {value}");
+        }
+    }
+
+    /// Single sample of the metric with arbitrary labels. Header is written by the caller.
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape_label_value(value)))
+            .collect();
+        let _ = writeln!(self.buffer, "{name}{{{}}} {value}", labels.join(","));
+    }
+
+    /// Constant gauge carrying the information in labels.
+    fn info(&mut self, name: &str, help: &str, labels: &[(&str, &str)]) {
+        self.header(name, help, "gauge");
+        let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{key}=\"{value}\"")).collect();
+        let _ = writeln!(self.buffer, "{name}{{{}}} 1", labels.join(","));
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.buffer, "{name} {value}");
+    }
+
+    fn rate(&mut self, name: &str, help: &str, windows: &LurkRateWindows) {
+        self.header(name, help, "gauge");
+        for (window, value) in [("1m", windows.m1), ("5m", windows.m5), ("15m", windows.m15)] {
+            let _ = writeln!(self.buffer, "{name}{{window=\"{window}\"}} {value}");
+        }
+    }
+
+    fn histogram(&mut self, name: &str, help: &str, snapshot: &LurkHistogramSnapshot) {
+        self.header(name, help, "histogram");
+        for (bound, count) in &snapshot.buckets {
+            let _ = writeln!(self.buffer, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(self.buffer, "{name}_bucket{{le=\"+Inf\"}} {}", snapshot.count);
+        let _ = writeln!(self.buffer, "{name}_sum {}", snapshot.sum_secs);
+        let _ = writeln!(self.buffer, "{name}_count {}", snapshot.count);
+    }
+}
+
+/// Label values may come from the configuration (e.g. rule ids), so they are escaped.
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::error::{LurkDenyReason, LurkDenySource};
+    use std::time::Duration;
+
+    #[test]
+    fn render_histogram() {
+        let stats = LurkServerStats::new();
+        stats.connect_latency().observe(Duration::from_millis(20));
+
+        let output = render(&stats);
+        assert!(output.contains("# TYPE lurk_connect_latency_seconds histogram\n"));
+        assert!(output.contains("lurk_connect_latency_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(output.contains("lurk_connect_latency_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(output.contains("lurk_connect_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(output.contains("lurk_connect_latency_seconds_sum 0.02\n"));
+        assert!(output.contains("lurk_connections_accepted_total{label=\"socks5\"} 0\n"));
+        assert!(output.contains("lurk_auth_attempts_total{result=\"failure\"} 0\n"));
+        assert!(output.contains(&format!("lurk_build_info{{version=\"{}\",", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[test]
+    fn render_destination_denials() {
+        let stats = LurkServerStats::new();
+        let reason = LurkDenyReason {
+            source: LurkDenySource::Policy,
+            rule: "no \"ads\"".to_owned(),
+            pattern: "ads.example.com".to_owned(),
+        };
+        stats.on_destination_denied(&reason);
+        stats.on_destination_denied(&reason);
+
+        let output = render(&stats);
+        assert!(output.contains("# TYPE lurk_destination_denials_total counter\n"));
+        assert!(output.contains("lurk_destination_denials_total{source=\"policy\",rule=\"no \\\"ads\\\"\"} 2\n"));
+    }
+}
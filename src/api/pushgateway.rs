@@ -0,0 +1,172 @@
+use super::prometheus;
+use crate::server::LurkServer;
+use anyhow::{ensure, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{client::conn::http1, header, Method, Request};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpStream, time::interval};
+
+/// Settings of periodic metrics pushes to Prometheus Pushgateway.
+///
+/// **Fields**:
+/// * ```endpoint``` - address ("host:port") of the Pushgateway
+/// * ```job``` - value of the "job" grouping label
+/// * ```instance``` - value of the "instance" grouping label, metrics are grouped by job only if not set
+/// * ```interval``` - period between two pushes
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkPushgatewayOptions {
+    endpoint: String,
+    job: String,
+    instance: Option<String>,
+    interval: Duration,
+}
+
+impl LurkPushgatewayOptions {
+    pub fn new(endpoint: impl Into<String>, job: impl Into<String>, interval: Duration) -> LurkPushgatewayOptions {
+        LurkPushgatewayOptions {
+            endpoint: endpoint.into(),
+            job: job.into(),
+            instance: None,
+            interval,
+        }
+    }
+
+    pub fn set_instance(&mut self, instance: impl Into<String>) -> &mut LurkPushgatewayOptions {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// Path of the metrics group, e.g. "/metrics/job/lurk/instance/edge-1".
+    fn group_path(&self) -> String {
+        let mut path = String::from("/metrics");
+        path.push_str(&grouping_label("job", &self.job));
+        if let Some(instance) = &self.instance {
+            path.push_str(&grouping_label("instance", instance));
+        }
+        path
+    }
+}
+
+/// Periodically pushes node metrics to the Pushgateway, so nodes that
+/// can't be scraped (e.g. behind NAT) are still monitored.
+pub struct LurkMetricsPusher {
+    options: LurkPushgatewayOptions,
+    node: Arc<LurkServer>,
+}
+
+impl LurkMetricsPusher {
+    pub fn new(options: LurkPushgatewayOptions, node: Arc<LurkServer>) -> LurkMetricsPusher {
+        LurkMetricsPusher { options, node }
+    }
+
+    /// Asynchronously push metrics "forever".
+    pub async fn run(&self) {
+        info!(
+            "Pushing metrics to {}{} every {:?}",
+            self.options.endpoint,
+            self.options.group_path(),
+            self.options.interval
+        );
+
+        let mut ticker = interval(self.options.interval);
+        loop {
+            ticker.tick().await;
+            // Pushgateway may be temporarily unavailable, just try again the next time.
+            if let Err(err) = self.push().await {
+                warn!("Failed to push metrics to {}: {}", self.options.endpoint, err);
+            }
+        }
+    }
+
+    /// Replace the metrics of the group with the current ones.
+    pub async fn push(&self) -> Result<()> {
+        let path = self.options.group_path();
+        let body = prometheus::render(&self.node.get_stats());
+
+        let stream = TcpStream::connect(self.options.endpoint.as_str()).await?;
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(path.as_str())
+            .header(header::HOST, self.options.endpoint.as_str())
+            .header(header::CONTENT_TYPE, prometheus::CONTENT_TYPE)
+            .body(Full::new(Bytes::from(body)))?;
+
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        ensure!(
+            status.is_success(),
+            "{} has responded with {}: {}",
+            path,
+            status,
+            String::from_utf8_lossy(&body)
+        );
+
+        debug!("Metrics are pushed to {}{}", self.options.endpoint, path);
+        Ok(())
+    }
+}
+
+/// Path segments of the grouping label. Values which can't be put into the
+/// path as is are base64-encoded, as Pushgateway requires ("=" stands for the empty one).
+fn grouping_label(name: &str, value: &str) -> String {
+    match value {
+        "" => format!("/{}@base64/=", name),
+        value if value.contains('/') => format!("/{}@base64/{}", name, BASE64_URL.encode(value)),
+        value => format!("/{}/{}", name, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{
+        all_of,
+        matchers::{contains, matches, request},
+        responders::status_code,
+        Expectation, Server,
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn group_path() {
+        let mut options = LurkPushgatewayOptions::new("127.0.0.1:9091", "lurk", Duration::from_secs(15));
+        assert_eq!("/metrics/job/lurk", options.group_path());
+
+        options.set_instance("edge-1");
+        assert_eq!("/metrics/job/lurk/instance/edge-1", options.group_path());
+
+        options.set_instance("edge/1");
+        assert_eq!("/metrics/job/lurk/instance@base64/ZWRnZS8x", options.group_path());
+    }
+
+    #[tokio::test]
+    async fn push_metrics() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/metrics/job/lurk/instance/edge-1"),
+                request::headers(contains(("content-type", prometheus::CONTENT_TYPE))),
+                request::body(matches("lurk_connections_active 0")),
+            ])
+            .respond_with(status_code(200)),
+        );
+
+        let mut options = LurkPushgatewayOptions::new(server.addr().to_string(), "lurk", Duration::from_secs(15));
+        options.set_instance("edge-1");
+        let node = Arc::new(LurkServer::new("127.0.0.1:0".parse::<SocketAddr>().unwrap()));
+
+        LurkMetricsPusher::new(options, node)
+            .push()
+            .await
+            .expect("Metrics should be pushed");
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    net::tcp::listener::{self, LurkTcpListenerOptions},
+    server::{
+        stats::node::{LurkListenerKind, LurkNodeState},
+        LurkServer,
+    },
+};
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+
+/// Minimalistic TCP health check responder.
+///
+/// Writes the state of the node in HAProxy agent-check format to every accepted
+/// connection and closes it, so that the node can be checked either by
+/// ```agent-check``` or by ```option tcp-check``` with ```tcp-check expect string up```.
+pub struct LurkTcpCheckResponder {
+    addr: SocketAddr,
+    node: Arc<LurkServer>,
+}
+
+impl LurkTcpCheckResponder {
+    const REPLY_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new(addr: SocketAddr, node: Arc<LurkServer>) -> LurkTcpCheckResponder {
+        LurkTcpCheckResponder { addr, node }
+    }
+
+    /// Asynchronously respond to incoming health checks.
+    pub async fn run(&self) -> Result<()> {
+        let listener = listener::bind_tcp_listener(self.addr, &LurkTcpListenerOptions::default())?;
+        info!("TCP check responder is listening on {}", self.addr);
+        self.node
+            .get_stats()
+            .on_listener_bound(LurkListenerKind::TcpCheck, listener.local_addr()?);
+
+        loop {
+            let (tcp_stream, peer_addr) = listener.accept().await?;
+            let reply = agent_reply(self.node.get_state());
+
+            debug!("Incoming TCP check from {}, replying '{}'", peer_addr, reply.trim_end());
+
+            tokio::spawn(async move {
+                if let Err(err) = timeout(Self::REPLY_WRITE_TIMEOUT, reply_and_close(tcp_stream, reply)).await {
+                    warn!("TCP check reply to {} timed out: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+}
+
+async fn reply_and_close(mut tcp_stream: TcpStream, reply: &str) {
+    // Checker may close the connection without reading the reply, nothing to report then.
+    if tcp_stream.write_all(reply.as_bytes()).await.is_ok() {
+        let _ = tcp_stream.shutdown().await;
+    }
+}
+
+/// Reply understood by HAProxy agent-check: "up" and "ready" bring the server
+/// back, "drain" moves it to drain mode and "down" marks it as failed.
+fn agent_reply(state: LurkNodeState) -> &'static str {
+    match state {
+        LurkNodeState::Up => "up ready\n",
+        LurkNodeState::Drain => "drain\n",
+        LurkNodeState::Down => "down\n",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::{io::AsyncReadExt, task::yield_now};
+
+    async fn check(addr: SocketAddr) -> String {
+        let mut reply = String::new();
+        let mut tcp_stream = TcpStream::connect(addr).await.unwrap();
+        tcp_stream.read_to_string(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn reply_with_node_state() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let node = Arc::new(LurkServer::new(SocketAddr::new(localhost, 0)));
+        let responder = LurkTcpCheckResponder::new(SocketAddr::new(localhost, 0), Arc::clone(&node));
+        let handle = tokio::spawn(async move { responder.run().await });
+
+        let addr = loop {
+            if let Some(listener) = node.get_stats().get_bound_listeners().first() {
+                break listener.addr;
+            }
+            yield_now().await;
+        };
+
+        // Node is not running yet.
+        assert_eq!("down\n", check(addr).await);
+
+        node.get_stats().on_server_started();
+        assert_eq!("up ready\n", check(addr).await);
+
+        node.set_draining(true);
+        assert_eq!("drain\n", check(addr).await);
+
+        node.set_draining(false);
+        assert_eq!("up ready\n", check(addr).await);
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,287 @@
+use crate::{common::error::LurkError, net::tcp::connection::LurkTcpConnection};
+use anyhow::{bail, ensure, Result};
+use private::LurkPrivateAuthMethod;
+use std::{
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+use users::LurkUserStore;
+
+pub mod private;
+pub mod quota;
+pub mod users;
+
+#[rustfmt::skip]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum LurkAuthMethod {
+    None,
+    GssAPI,
+    Password,
+    // Method from the range reserved for private methods (0x80-0xFE)
+    Private(u8),
+}
+
+impl fmt::Display for LurkAuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LurkAuthMethod::None => write!(f, "none"),
+            LurkAuthMethod::GssAPI => write!(f, "gssapi"),
+            LurkAuthMethod::Password => write!(f, "password"),
+            LurkAuthMethod::Private(code) => write!(f, "private({:#04x})", code),
+        }
+    }
+}
+
+impl FromStr for LurkAuthMethod {
+    type Err = anyhow::Error;
+
+    /// Parse built-in method the proxy is able to offer.
+    fn from_str(value: &str) -> Result<LurkAuthMethod> {
+        match value {
+            "none" => Ok(LurkAuthMethod::None),
+            "password" => Ok(LurkAuthMethod::Password),
+            _ => bail!("unknown authentication method '{}', expected 'none' or 'password'", value),
+        }
+    }
+}
+
+/// Built-in methods offered to SOCKS5 clients, in the order of preference.
+///
+/// Methods could be switched while the proxy is running (e.g. open access is
+/// enabled temporarily), which affects new handshakes only.
+pub struct LurkOfferedAuthMethods {
+    methods: RwLock<Vec<LurkAuthMethod>>,
+    users_configured: bool,
+}
+
+impl LurkOfferedAuthMethods {
+    /// Offer password authentication if users are configured, otherwise no authentication.
+    pub fn new(users_configured: bool) -> LurkOfferedAuthMethods {
+        let method = if users_configured {
+            LurkAuthMethod::Password
+        } else {
+            LurkAuthMethod::None
+        };
+
+        LurkOfferedAuthMethods {
+            methods: RwLock::new(vec![method]),
+            users_configured,
+        }
+    }
+
+    /// Methods offered until they are switched.
+    pub fn initial(&self) -> Vec<LurkAuthMethod> {
+        LurkOfferedAuthMethods::new(self.users_configured).get()
+    }
+
+    pub fn get(&self) -> Vec<LurkAuthMethod> {
+        self.methods.read().expect("Auth methods lock is poisoned").clone()
+    }
+
+    /// Offer another set of methods. Returns the previous one.
+    pub fn set(&self, methods: Vec<LurkAuthMethod>) -> Result<Vec<LurkAuthMethod>> {
+        self.validate(&methods)?;
+
+        let mut deduplicated = Vec::with_capacity(methods.len());
+        for method in methods {
+            if !deduplicated.contains(&method) {
+                deduplicated.push(method);
+            }
+        }
+
+        let mut current = self.methods.write().expect("Auth methods lock is poisoned");
+        Ok(std::mem::replace(&mut *current, deduplicated))
+    }
+
+    /// Check whether the methods could be offered, without offering them.
+    pub fn validate(&self, methods: &[LurkAuthMethod]) -> Result<()> {
+        ensure!(!methods.is_empty(), "at least one authentication method should be offered");
+        for method in methods {
+            match method {
+                LurkAuthMethod::None => {}
+                LurkAuthMethod::Password => ensure!(self.users_configured, "password authentication requires users"),
+                _ => bail!("authentication method '{}' can't be offered", method),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct LurkAuthenticator<'a> {
+    users: Option<&'a LurkUserStore>,
+    private_methods: &'a [Arc<dyn LurkPrivateAuthMethod>],
+    available_methods: Vec<LurkAuthMethod>,
+    selected_method: Option<LurkAuthMethod>,
+}
+
+impl<'a> LurkAuthenticator<'a> {
+    /// Create authenticator. Once users are configured, only password
+    /// authentication is allowed. Otherwise, authentication is disabled.
+    pub fn new(users: Option<&'a LurkUserStore>) -> LurkAuthenticator<'a> {
+        let available_method = match users {
+            Some(_) => LurkAuthMethod::Password,
+            None => LurkAuthMethod::None,
+        };
+
+        LurkAuthenticator {
+            users,
+            private_methods: &[],
+            selected_method: None,
+            available_methods: vec![available_method],
+        }
+    }
+
+    /// Offer these built-in methods instead of the default one, in the order of preference.
+    pub fn with_offered_methods(mut self, methods: Vec<LurkAuthMethod>) -> LurkAuthenticator<'a> {
+        self.available_methods = methods;
+        self
+    }
+
+    /// Offer private methods as well. They are preferred over the built-in ones,
+    /// in the order they are passed.
+    pub fn with_private_methods(mut self, private_methods: &'a [Arc<dyn LurkPrivateAuthMethod>]) -> LurkAuthenticator<'a> {
+        self.private_methods = private_methods;
+        self
+    }
+
+    pub fn authenticate_connection(&self, conn: &LurkTcpConnection) -> Result<()> {
+        match self.current_method() {
+            Some(method) => match method {
+                LurkAuthMethod::None => Ok(()),
+                _ => bail!(LurkError::UnsupportedAuthMethod(method)),
+            },
+            None => {
+                bail!("Tried to authenticate {}, but method has not been selected", conn.peer_addr());
+            }
+        }
+    }
+
+    /// Authenticate the user with username and password. Users which have
+    /// exceeded their transfer quota are rejected as well.
+    pub fn authenticate_user(&self, username: &str, password: &str) -> Result<()> {
+        match (self.current_method(), self.users) {
+            (Some(LurkAuthMethod::Password), Some(users)) => {
+                if !users.verify_credentials(username, password) {
+                    bail!(LurkError::InvalidCredentials(username.to_owned()));
+                }
+                users.check_quota(username)
+            }
+            (Some(method), _) => bail!(LurkError::UnsupportedAuthMethod(method)),
+            (None, _) => bail!("Tried to authenticate user '{}', but method has not been selected", username),
+        }
+    }
+
+    /// Find any common authentication method between available
+    /// auth methods on server and supported methods by client.
+    pub fn select_auth_method(&mut self, peer_methods: &HashSet<LurkAuthMethod>) -> Option<LurkAuthMethod> {
+        let private_method = self
+            .private_methods
+            .iter()
+            .map(|method| LurkAuthMethod::Private(method.code()))
+            .find(|method| peer_methods.contains(method));
+
+        let common_method = self.available_methods.iter().find(|method| peer_methods.contains(method));
+
+        self.selected_method = private_method.or(common_method.copied());
+        self.selected_method
+    }
+
+    /// Implementation of the private method with given code, if it's registered.
+    pub fn private_method(&self, code: u8) -> Option<&dyn LurkPrivateAuthMethod> {
+        self.private_methods.iter().find(|method| method.code() == code).map(Arc::as_ref)
+    }
+
+    pub fn current_method(&self) -> Option<LurkAuthMethod> {
+        self.selected_method
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_auth_method() {
+        let peer_methods = HashSet::from([LurkAuthMethod::GssAPI, LurkAuthMethod::Password, LurkAuthMethod::None]);
+        {
+            let mut authenticator = LurkAuthenticator::new(None);
+            authenticator.select_auth_method(&peer_methods);
+            assert_eq!(Some(LurkAuthMethod::None), authenticator.current_method());
+        }
+        {
+            let users = LurkUserStore::new([], false);
+            let mut authenticator = LurkAuthenticator::new(Some(&users));
+            authenticator.select_auth_method(&peer_methods);
+            assert_eq!(Some(LurkAuthMethod::Password), authenticator.current_method());
+
+            // Client without password support is rejected.
+            assert_eq!(None, authenticator.select_auth_method(&HashSet::from([LurkAuthMethod::None])));
+        }
+        {
+            let private_methods: [Arc<dyn LurkPrivateAuthMethod>; 2] = [Arc::new(LurkNoopAuth(0x81)), Arc::new(LurkNoopAuth(0x80))];
+            let mut authenticator = LurkAuthenticator::new(None).with_private_methods(&private_methods);
+
+            // Private methods are preferred in the order they are registered.
+            let peer_methods = HashSet::from([LurkAuthMethod::None, LurkAuthMethod::Private(0x80), LurkAuthMethod::Private(0x81)]);
+            assert_eq!(Some(LurkAuthMethod::Private(0x81)), authenticator.select_auth_method(&peer_methods));
+            assert_eq!(Some(0x81), authenticator.private_method(0x81).map(|method| method.code()));
+
+            // Unknown private method isn't selected.
+            let peer_methods = HashSet::from([LurkAuthMethod::None, LurkAuthMethod::Private(0x90)]);
+            assert_eq!(Some(LurkAuthMethod::None), authenticator.select_auth_method(&peer_methods));
+            assert!(authenticator.private_method(0x90).is_none());
+        }
+    }
+
+    #[test]
+    fn switch_offered_methods() {
+        let offered = LurkOfferedAuthMethods::new(true);
+        assert_eq!(vec![LurkAuthMethod::Password], offered.get());
+
+        // Open access is enabled, clients with credentials are still authenticated.
+        let previous = offered
+            .set(vec![LurkAuthMethod::Password, LurkAuthMethod::None, LurkAuthMethod::Password])
+            .unwrap();
+        assert_eq!(vec![LurkAuthMethod::Password], previous);
+        assert_eq!(vec![LurkAuthMethod::Password, LurkAuthMethod::None], offered.get());
+
+        let users = LurkUserStore::new([], false);
+        let mut authenticator = LurkAuthenticator::new(Some(&users)).with_offered_methods(offered.get());
+        let peer_methods = HashSet::from([LurkAuthMethod::None, LurkAuthMethod::Password]);
+        assert_eq!(Some(LurkAuthMethod::Password), authenticator.select_auth_method(&peer_methods));
+        let peer_methods = HashSet::from([LurkAuthMethod::None]);
+        assert_eq!(Some(LurkAuthMethod::None), authenticator.select_auth_method(&peer_methods));
+
+        assert!(offered.set(vec![]).is_err());
+        assert!(offered.set(vec![LurkAuthMethod::GssAPI]).is_err());
+        assert!(LurkOfferedAuthMethods::new(false).set(vec![LurkAuthMethod::Password]).is_err());
+        assert_eq!(vec![LurkAuthMethod::Password, LurkAuthMethod::None], offered.get());
+
+        assert_eq!(LurkAuthMethod::None, "none".parse().unwrap());
+        assert!("gssapi".parse::<LurkAuthMethod>().is_err());
+    }
+
+    #[test]
+    fn parse_private_auth_method() {
+        assert_eq!(LurkAuthMethod::Private(0x80), LurkAuthMethod::from_socks5_const(0x80).unwrap());
+        assert_eq!(LurkAuthMethod::Private(0xfe), LurkAuthMethod::from_socks5_const(0xfe).unwrap());
+        assert_eq!(0xfe, LurkAuthMethod::Private(0xfe).as_socks5_const());
+        assert!(LurkAuthMethod::from_socks5_const(0x7f).is_err());
+        assert!(LurkAuthMethod::from_socks5_const(0xff).is_err());
+    }
+
+    struct LurkNoopAuth(u8);
+
+    #[async_trait::async_trait]
+    impl LurkPrivateAuthMethod for LurkNoopAuth {
+        fn code(&self) -> u8 {
+            self.0
+        }
+
+        async fn authenticate(&self, _stream: &mut tokio::net::TcpStream, _peer_addr: std::net::SocketAddr) -> Result<Option<String>> {
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// SOCKS5 authentication method from the range reserved for private methods (0x80-0xFE, RFC 1928),
+/// e.g. custom token or HMAC schemes. Implementations are registered on the server and offered to
+/// clients during the handshake along with the built-in methods.
+#[async_trait]
+pub trait LurkPrivateAuthMethod: Send + Sync {
+    /// Code of the method negotiated in the handshake. Has to be within 0x80-0xFE.
+    fn code(&self) -> u8;
+
+    /// Method-dependent sub-negotiation, run once the method has been selected.
+    /// Returns name of the authenticated user if the method identifies users. Connection is closed on error.
+    async fn authenticate(&self, stream: &mut TcpStream, peer_addr: SocketAddr) -> Result<Option<String>>;
+}
@@ -0,0 +1,120 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Transfer limits assigned to the user. Unset limit means unlimited transfer.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LurkQuota {
+    /// Bytes (in both directions) allowed per UTC calendar day.
+    pub daily_bytes: Option<u64>,
+    /// Bytes (in both directions) allowed per UTC calendar month.
+    pub monthly_bytes: Option<u64>,
+}
+
+impl LurkQuota {
+    pub fn is_unlimited(&self) -> bool {
+        self.daily_bytes.is_none() && self.monthly_bytes.is_none()
+    }
+}
+
+/// Bytes transferred by the user within current daily and monthly windows.
+///
+/// Windows are aligned to UTC calendar days and months and reset
+/// lazily, once usage is touched after the window is over.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LurkQuotaUsage {
+    day: NaiveDate,
+    daily_bytes: u64,
+    month: NaiveDate,
+    monthly_bytes: u64,
+}
+
+impl LurkQuotaUsage {
+    pub fn new(now: DateTime<Utc>) -> LurkQuotaUsage {
+        LurkQuotaUsage {
+            day: now.date_naive(),
+            daily_bytes: 0,
+            month: LurkQuotaUsage::month_of(now),
+            monthly_bytes: 0,
+        }
+    }
+
+    pub fn charge(&mut self, bytes: u64, now: DateTime<Utc>) {
+        self.roll(now);
+        self.daily_bytes = self.daily_bytes.saturating_add(bytes);
+        self.monthly_bytes = self.monthly_bytes.saturating_add(bytes);
+    }
+
+    pub fn is_exceeded(&mut self, quota: &LurkQuota, now: DateTime<Utc>) -> bool {
+        self.roll(now);
+        quota.daily_bytes.is_some_and(|limit| self.daily_bytes >= limit)
+            || quota.monthly_bytes.is_some_and(|limit| self.monthly_bytes >= limit)
+    }
+
+    /// Replace counters of the current windows, e.g. with the ones shared by the cluster.
+    pub fn reset(&mut self, daily_bytes: u64, monthly_bytes: u64, now: DateTime<Utc>) {
+        self.roll(now);
+        self.daily_bytes = daily_bytes;
+        self.monthly_bytes = monthly_bytes;
+    }
+
+    pub fn daily_bytes(&self) -> u64 {
+        self.daily_bytes
+    }
+
+    pub fn monthly_bytes(&self) -> u64 {
+        self.monthly_bytes
+    }
+
+    /// Reset counters of windows which are over.
+    fn roll(&mut self, now: DateTime<Utc>) {
+        if self.day != now.date_naive() {
+            self.day = now.date_naive();
+            self.daily_bytes = 0;
+        }
+        if self.month != LurkQuotaUsage::month_of(now) {
+            self.month = LurkQuotaUsage::month_of(now);
+            self.monthly_bytes = 0;
+        }
+    }
+
+    fn month_of(now: DateTime<Utc>) -> NaiveDate {
+        now.date_naive().with_day(1).expect("first day of month should exist")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn windows_reset() {
+        let quota = LurkQuota {
+            daily_bytes: Some(100),
+            monthly_bytes: Some(150),
+        };
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 30, 23, 0, 0).unwrap();
+        let mut usage = LurkQuotaUsage::new(now);
+
+        usage.charge(99, now);
+        assert!(!usage.is_exceeded(&quota, now));
+        usage.charge(1, now);
+        assert!(usage.is_exceeded(&quota, now));
+
+        // Next day, but the same month.
+        let now = Utc.with_ymd_and_hms(2024, 5, 31, 0, 0, 0).unwrap();
+        usage.charge(10, now);
+        assert!(!usage.is_exceeded(&quota, now));
+        assert_eq!((10, 110), (usage.daily_bytes(), usage.monthly_bytes()));
+
+        usage.charge(40, now);
+        assert!(usage.is_exceeded(&quota, now));
+
+        // Next month resets both windows.
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        assert!(!usage.is_exceeded(&quota, now));
+        assert_eq!((0, 0), (usage.daily_bytes(), usage.monthly_bytes()));
+    }
+}
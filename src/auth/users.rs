@@ -0,0 +1,509 @@
+use super::quota::{LurkQuota, LurkQuotaUsage};
+use crate::common::{error::LurkError, secret::constant_time_eq};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// User allowed to access the proxy.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LurkUser {
+    name: String,
+    password: String,
+    #[serde(default)]
+    quota: LurkQuota,
+    #[serde(default)]
+    egress_ip: Option<IpAddr>,
+    #[serde(default)]
+    max_sessions: Option<usize>,
+    #[serde(default)]
+    class: Option<String>,
+    #[serde(default)]
+    rate_limit: Option<u64>,
+}
+
+impl LurkUser {
+    pub fn new(name: impl Into<String>, password: impl Into<String>, quota: LurkQuota) -> LurkUser {
+        LurkUser {
+            name: name.into(),
+            password: password.into(),
+            quota,
+            egress_ip: None,
+            max_sessions: None,
+            class: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Apply limits of the class (e.g. "trial") to the user.
+    pub fn with_class(mut self, class: impl Into<String>) -> LurkUser {
+        self.class = Some(class.into());
+        self
+    }
+
+    /// Establish outbound connections of the user from the given local address.
+    pub fn with_egress_ip(mut self, egress_ip: IpAddr) -> LurkUser {
+        self.egress_ip = Some(egress_ip);
+        self
+    }
+
+    /// Limit number of simultaneous sessions of the user, overriding the default one of the store.
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> LurkUser {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// Relay at most ```bytes_per_sec``` in each direction of every tunnel of the user, overriding the default limit.
+    pub fn with_rate_limit(mut self, bytes_per_sec: u64) -> LurkUser {
+        self.rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn quota(&self) -> &LurkQuota {
+        &self.quota
+    }
+
+    /// Local address outbound connections of the user are established from.
+    pub fn egress_ip(&self) -> Option<IpAddr> {
+        self.egress_ip
+    }
+}
+
+/// Limits shared by users of the same class, e.g. trial users.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LurkUserClass {
+    /// Seconds a single session of the user may stay open for.
+    pub max_session_secs: Option<u64>,
+}
+
+/// Content of the users file.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LurkUsersFile {
+    #[serde(default)]
+    classes: HashMap<String, LurkUserClass>,
+    users: Vec<LurkUser>,
+}
+
+/// Credentials of users along with accounting of their transfer quotas and sessions.
+///
+/// **Fields**:
+/// * ```users``` - users allowed to access the proxy, keyed by name
+/// * ```usage``` - bytes transferred by users in current quota windows
+/// * ```close_active``` - whether sessions should be closed once quota is exceeded
+/// * ```max_sessions``` - limit of simultaneous sessions of users without their own one
+/// * ```sessions``` - number of active sessions of users, shared with their guards
+/// * ```unsynced_bytes``` - bytes charged since the usage was last shared with the cluster
+/// * ```remote_sessions``` - number of active sessions of users on the other nodes of the cluster
+/// * ```classes``` - limits of user classes, keyed by name
+///
+pub struct LurkUserStore {
+    users: HashMap<String, LurkUser>,
+    classes: HashMap<String, LurkUserClass>,
+    usage: Mutex<HashMap<String, LurkQuotaUsage>>,
+    close_active: bool,
+    max_sessions: Mutex<Option<usize>>,
+    sessions: Arc<Mutex<HashMap<String, usize>>>,
+    unsynced_bytes: Mutex<HashMap<String, u64>>,
+    remote_sessions: Mutex<HashMap<String, usize>>,
+}
+
+impl LurkUserStore {
+    pub fn new(users: impl IntoIterator<Item = LurkUser>, close_active: bool) -> LurkUserStore {
+        LurkUserStore {
+            users: users.into_iter().map(|user| (user.name.clone(), user)).collect(),
+            classes: HashMap::new(),
+            usage: Mutex::new(HashMap::new()),
+            close_active,
+            max_sessions: Mutex::new(None),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            unsynced_bytes: Mutex::new(HashMap::new()),
+            remote_sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Define classes users could be assigned to.
+    pub fn with_classes(mut self, classes: impl IntoIterator<Item = (String, LurkUserClass)>) -> LurkUserStore {
+        self.classes = classes.into_iter().collect();
+        self
+    }
+
+    /// Limit number of simultaneous sessions of every user, unless user has its own limit.
+    pub fn with_max_sessions(self, max_sessions: usize) -> LurkUserStore {
+        self.set_max_sessions(Some(max_sessions));
+        self
+    }
+
+    /// Change the limit of simultaneous sessions at runtime. Already active sessions are kept.
+    pub fn set_max_sessions(&self, max_sessions: Option<usize>) {
+        *self.max_sessions.lock().expect("user sessions lock is poisoned") = max_sessions;
+    }
+
+    /// Load users from the JSON file.
+    pub fn from_file(path: &Path, close_active: bool) -> Result<LurkUserStore> {
+        let content = std::fs::read(path).with_context(|| format!("unable to read users file {}", path.display()))?;
+        let file: LurkUsersFile =
+            serde_json::from_slice(&content).with_context(|| format!("users file {} is malformed", path.display()))?;
+
+        // Egress IPs are checked upfront, otherwise every session of the user would fail.
+        for user in &file.users {
+            if let Some(class) = &user.class {
+                if !file.classes.contains_key(class) {
+                    bail!(
+                        "class '{}' of user '{}' is not defined in users file {}",
+                        class,
+                        user.name,
+                        path.display()
+                    );
+                }
+            }
+            if user.rate_limit == Some(0) {
+                bail!(
+                    "rate limit of user '{}' should be positive in users file {}",
+                    user.name,
+                    path.display()
+                );
+            }
+            if let Some(egress_ip) = user.egress_ip {
+                UdpSocket::bind(SocketAddr::new(egress_ip, 0))
+                    .with_context(|| format!("egress IP {} of user '{}' is not assigned to this host", egress_ip, user.name))?;
+            }
+        }
+
+        Ok(LurkUserStore::new(file.users, close_active).with_classes(file.classes))
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<&LurkUser> {
+        self.users.get(name)
+    }
+
+    /// Returns true if user exists and password matches.
+    pub fn verify_credentials(&self, name: &str, password: &str) -> bool {
+        match self.users.get(name) {
+            Some(user) => constant_time_eq(user.password.as_bytes(), password.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Fails if user has already used up the quota in any of the windows.
+    pub fn check_quota(&self, name: &str) -> Result<()> {
+        let Some(user) = self.users.get(name) else {
+            bail!(LurkError::InvalidCredentials(name.to_owned()))
+        };
+
+        if user.quota.is_unlimited() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let mut usage = self.usage();
+        let user_usage = usage.entry(name.to_owned()).or_insert_with(|| LurkQuotaUsage::new(now));
+
+        if user_usage.is_exceeded(&user.quota, now) {
+            bail!(LurkError::QuotaExceeded(name.to_owned()))
+        }
+
+        Ok(())
+    }
+
+    /// Add transferred bytes to the user's usage.
+    pub fn charge(&self, name: &str, bytes: u64) {
+        let now = Utc::now();
+        self.usage()
+            .entry(name.to_owned())
+            .or_insert_with(|| LurkQuotaUsage::new(now))
+            .charge(bytes, now);
+        *self.unsynced_bytes().entry(name.to_owned()).or_default() += bytes;
+    }
+
+    /// Take bytes charged since the last call, to share them with the cluster.
+    pub fn take_unsynced_bytes(&self) -> HashMap<String, u64> {
+        std::mem::take(&mut *self.unsynced_bytes())
+    }
+
+    /// Give back bytes which haven't been shared with the cluster, so they are shared next time.
+    pub fn restore_unsynced_bytes(&self, bytes: HashMap<String, u64>) {
+        let mut unsynced_bytes = self.unsynced_bytes();
+        for (name, bytes) in bytes {
+            *unsynced_bytes.entry(name).or_default() += bytes;
+        }
+    }
+
+    /// Replace usage of the user with the one of the whole cluster. Bytes charged
+    /// locally, but not shared yet, are added on top of it.
+    pub fn apply_cluster_usage(&self, name: &str, daily_bytes: u64, monthly_bytes: u64) {
+        let now = Utc::now();
+        let unsynced = self.unsynced_bytes().get(name).copied().unwrap_or_default();
+        self.usage()
+            .entry(name.to_owned())
+            .or_insert_with(|| LurkQuotaUsage::new(now))
+            .reset(daily_bytes.saturating_add(unsynced), monthly_bytes.saturating_add(unsynced), now);
+    }
+
+    /// Returns usage of the user in current quota windows.
+    pub fn get_usage(&self, name: &str) -> Option<LurkQuotaUsage> {
+        self.usage().get(name).copied()
+    }
+
+    /// Usage of every user in current quota windows, e.g. to save it across restarts.
+    pub fn quota_usage(&self) -> HashMap<String, LurkQuotaUsage> {
+        self.usage().clone()
+    }
+
+    /// Restore usage saved before the restart. Usage of users, which no longer exist, is dropped.
+    /// Windows which have been over since then are reset once the usage is touched.
+    pub fn restore_quota_usage(&self, saved: HashMap<String, LurkQuotaUsage>) {
+        let mut usage = self.usage();
+        for (name, user_usage) in saved {
+            if self.users.contains_key(&name) {
+                usage.insert(name, user_usage);
+            }
+        }
+    }
+
+    /// Whether active sessions of the user should be closed once quota is exceeded.
+    pub fn close_active(&self) -> bool {
+        self.close_active
+    }
+
+    /// Names of all users, in ascending order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.users.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Limit of simultaneous sessions of the user, if any.
+    pub fn max_sessions(&self, name: &str) -> Option<usize> {
+        let default = *self.max_sessions.lock().expect("user sessions lock is poisoned");
+        self.users.get(name)?.max_sessions.or(default)
+    }
+
+    /// Time a single session of the user may stay open for, set by the class of the user.
+    pub fn max_session_duration(&self, name: &str) -> Option<Duration> {
+        let class = self.users.get(name)?.class.as_ref()?;
+        self.classes.get(class)?.max_session_secs.map(Duration::from_secs)
+    }
+
+    /// Bytes per second every tunnel of the user may relay in each direction, if the user has its own limit.
+    pub fn rate_limit(&self, name: &str) -> Option<u64> {
+        self.users.get(name)?.rate_limit
+    }
+
+    /// Number of active sessions of the user on this node.
+    pub fn active_sessions(&self, name: &str) -> usize {
+        self.sessions().get(name).copied().unwrap_or_default()
+    }
+
+    /// Number of active sessions of every user on this node, to share them with the cluster.
+    pub fn local_sessions(&self) -> HashMap<String, usize> {
+        self.sessions().clone()
+    }
+
+    /// Replace number of active sessions of users on the other nodes of the cluster.
+    /// They are counted towards the limit of simultaneous sessions.
+    pub fn set_remote_sessions(&self, remote_sessions: HashMap<String, usize>) {
+        *self.remote_sessions.lock().expect("user sessions lock is poisoned") = remote_sessions;
+    }
+
+    /// Names of users limited by the transfer quota.
+    pub fn names_with_quota(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .users
+            .values()
+            .filter(|user| !user.quota.is_unlimited())
+            .map(|user| user.name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Account new session of the user, unless it would exceed the limit of simultaneous sessions.
+    /// Session is accounted until the returned guard is dropped.
+    pub fn open_session(&self, name: &str) -> Result<LurkUserSession> {
+        let max_sessions = self.max_sessions(name);
+        let remote = self
+            .remote_sessions
+            .lock()
+            .expect("user sessions lock is poisoned")
+            .get(name)
+            .copied()
+            .unwrap_or_default();
+        let mut sessions = self.sessions();
+        let active = sessions.entry(name.to_owned()).or_default();
+
+        if let Some(max_sessions) = max_sessions {
+            if *active + remote >= max_sessions {
+                bail!(LurkError::SessionLimitExceeded(name.to_owned(), max_sessions))
+            }
+        }
+
+        *active += 1;
+        Ok(LurkUserSession {
+            sessions: Arc::clone(&self.sessions),
+            name: name.to_owned(),
+        })
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, usize>> {
+        self.sessions.lock().expect("user sessions lock is poisoned")
+    }
+
+    fn usage(&self) -> std::sync::MutexGuard<'_, HashMap<String, LurkQuotaUsage>> {
+        self.usage.lock().expect("quota usage lock is poisoned")
+    }
+
+    fn unsynced_bytes(&self) -> std::sync::MutexGuard<'_, HashMap<String, u64>> {
+        self.unsynced_bytes.lock().expect("quota usage lock is poisoned")
+    }
+}
+
+/// Active session of the user, accounted until it's dropped.
+pub struct LurkUserSession {
+    sessions: Arc<Mutex<HashMap<String, usize>>>,
+    name: String,
+}
+
+impl Drop for LurkUserSession {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock().expect("user sessions lock is poisoned");
+        if let Some(active) = sessions.get_mut(&self.name) {
+            *active -= 1;
+            if *active == 0 {
+                sessions.remove(&self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::assertions::assert_lurk_err;
+
+    #[test]
+    fn credentials_and_quota() {
+        let quota = LurkQuota {
+            daily_bytes: Some(1000),
+            monthly_bytes: None,
+        };
+        let store = LurkUserStore::new(
+            [
+                LurkUser::new("alice", "secret", quota),
+                LurkUser::new("bob", "pass", LurkQuota::default()),
+            ],
+            false,
+        );
+
+        assert!(store.verify_credentials("alice", "secret"));
+        assert!(!store.verify_credentials("alice", "secreT"));
+        assert!(!store.verify_credentials("carol", "secret"));
+
+        store.charge("alice", 999);
+        assert!(store.check_quota("alice").is_ok());
+        store.charge("alice", 1);
+        assert_lurk_err!(
+            LurkError::QuotaExceeded("alice".to_owned()),
+            store.check_quota("alice").expect_err("quota should be exceeded")
+        );
+
+        // Unlimited user is only accounted.
+        store.charge("bob", u64::MAX);
+        assert!(store.check_quota("bob").is_ok());
+        assert_eq!(u64::MAX, store.get_usage("bob").unwrap().daily_bytes());
+    }
+
+    #[test]
+    fn session_limits() {
+        let store = LurkUserStore::new(
+            [
+                LurkUser::new("alice", "secret", LurkQuota::default()),
+                LurkUser::new("bob", "pass", LurkQuota::default()).with_max_sessions(1),
+            ],
+            false,
+        )
+        .with_max_sessions(2);
+
+        let alice_sessions = [store.open_session("alice").unwrap(), store.open_session("alice").unwrap()];
+        assert_lurk_err!(
+            LurkError::SessionLimitExceeded("alice".to_owned(), 2),
+            store.open_session("alice").err().expect("limit should be exceeded")
+        );
+        assert_eq!(2, store.active_sessions("alice"));
+
+        let bob_session = store.open_session("bob").unwrap();
+        assert!(store.open_session("bob").is_err());
+        drop(bob_session);
+        assert_eq!(0, store.active_sessions("bob"));
+        assert!(store.open_session("bob").is_ok());
+
+        drop(alice_sessions);
+        assert_eq!(0, store.active_sessions("alice"));
+    }
+
+    #[test]
+    fn user_classes() {
+        let store = LurkUserStore::new(
+            [
+                LurkUser::new("alice", "secret", LurkQuota::default()).with_class("trial"),
+                LurkUser::new("bob", "pass", LurkQuota::default()).with_class("paid"),
+                LurkUser::new("carol", "pass", LurkQuota::default()),
+            ],
+            false,
+        )
+        .with_classes([
+            (
+                "trial".to_owned(),
+                LurkUserClass {
+                    max_session_secs: Some(1800),
+                },
+            ),
+            ("paid".to_owned(), LurkUserClass::default()),
+        ]);
+
+        assert_eq!(Some(Duration::from_secs(1800)), store.max_session_duration("alice"));
+        assert_eq!(None, store.max_session_duration("bob"));
+        assert_eq!(None, store.max_session_duration("carol"));
+        assert_eq!(None, store.max_session_duration("dave"));
+    }
+
+    #[test]
+    fn parse_users_file() {
+        let file: LurkUsersFile = serde_json::from_str(
+            r#"{"classes": {"trial": {"max_session_secs": 1800}}, "users": [{"name": "alice", "password": "secret", "quota": {"daily_bytes": 1024}}, {"name": "bob", "password": "pass", "egress_ip": "10.0.0.2", "max_sessions": 3, "class": "trial", "rate_limit": 65536}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                LurkUser::new(
+                    "alice",
+                    "secret",
+                    LurkQuota {
+                        daily_bytes: Some(1024),
+                        monthly_bytes: None
+                    }
+                ),
+                LurkUser::new("bob", "pass", LurkQuota::default())
+                    .with_egress_ip("10.0.0.2".parse().unwrap())
+                    .with_max_sessions(3)
+                    .with_class("trial")
+                    .with_rate_limit(65536)
+            ],
+            file.users
+        );
+        assert_eq!(Some(1800), file.classes["trial"].max_session_secs);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{
+    net::{socks5, Address},
+    proto::socks5::ReplyStatus,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use clap::{Subcommand, ValueEnum};
+use http_body_util::Empty;
+use hyper::{client::conn::http1, header, Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, time::timeout};
+
+/// Diagnostic actions performed against any SOCKS5 or HTTP proxy, as its client.
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum LurkClientAction {
+    /// Connect to the target through the proxy and report timings and the reply
+    Connect {
+        /// Target ("host:port") the proxy is asked to connect to
+        target: String,
+
+        /// Address ("host:port") of the proxy instead of the local one
+        #[arg(long)]
+        proxy: Option<String>,
+
+        /// Protocol spoken with the proxy
+        #[arg(long, value_enum, default_value_t = LurkClientProtocol::Socks5)]
+        protocol: LurkClientProtocol,
+
+        /// Name of the user authenticated by the proxy
+        #[arg(long, requires = "password")]
+        user: Option<String>,
+
+        /// Password of the user
+        #[arg(long, requires = "user")]
+        password: Option<String>,
+
+        /// Number of seconds given to the proxy to establish the tunnel
+        #[arg(long, default_value_t = 5)]
+        timeout_secs: u64,
+    },
+}
+
+/// Protocol the client speaks with the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LurkClientProtocol {
+    // SOCKS5 CONNECT, with password authentication if credentials are passed.
+    Socks5,
+    // HTTP CONNECT, with basic proxy authorization if credentials are passed.
+    Http,
+}
+
+/// Reply of the proxy to the CONNECT request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LurkProxyReply {
+    Socks5(ReplyStatus),
+    Http(StatusCode),
+}
+
+impl LurkProxyReply {
+    pub fn is_success(&self) -> bool {
+        match self {
+            LurkProxyReply::Socks5(status) => *status == ReplyStatus::Succeeded,
+            LurkProxyReply::Http(status) => status.is_success(),
+        }
+    }
+}
+
+impl fmt::Display for LurkProxyReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LurkProxyReply::Socks5(status) => write!(f, "SOCKS5 reply {:#04x} ({:?})", status.as_u8(), status),
+            LurkProxyReply::Http(status) => write!(f, "HTTP {}", status),
+        }
+    }
+}
+
+/// Outcome of the diagnostic connection through the proxy.
+#[derive(Debug, PartialEq)]
+pub struct LurkConnectReport {
+    /// Address of the proxy the client has connected to.
+    pub proxy_addr: SocketAddr,
+    /// Target the proxy has been asked to connect to.
+    pub target: String,
+    /// Time taken to establish TCP connection with the proxy.
+    pub connect: Duration,
+    /// Time taken to negotiate authentication method and authenticate (SOCKS5 only).
+    pub handshake: Option<Duration>,
+    /// Time taken by the proxy to reply to the CONNECT request.
+    pub reply_latency: Duration,
+    /// Reply of the proxy.
+    pub reply: LurkProxyReply,
+    /// Address the proxy has connected to the target from (SOCKS5 only).
+    pub bound_addr: Option<Address>,
+}
+
+impl LurkConnectReport {
+    pub fn total(&self) -> Duration {
+        self.connect + self.handshake.unwrap_or_default() + self.reply_latency
+    }
+}
+
+impl fmt::Display for LurkConnectReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Proxy {} replied to CONNECT {} with {}",
+            self.proxy_addr, self.target, self.reply
+        )?;
+        if let Some(bound_addr) = &self.bound_addr {
+            writeln!(f, "Bound address: {}", bound_addr)?;
+        }
+        writeln!(f, "TCP connect: {:?}", self.connect)?;
+        if let Some(handshake) = self.handshake {
+            writeln!(f, "Handshake:   {:?}", handshake)?;
+        }
+        writeln!(f, "Reply:       {:?}", self.reply_latency)?;
+        write!(f, "Total:       {:?}", self.total())
+    }
+}
+
+/// Connect to the ```target``` through the proxy at ```proxy_addr``` and report how it went.
+/// Refusal of the proxy is reported as well, errors are returned only if the proxy misbehaves.
+pub async fn connect(
+    protocol: LurkClientProtocol,
+    proxy_addr: &str,
+    target: &str,
+    credentials: Option<(&str, &str)>,
+    timeout_after: Duration,
+) -> Result<LurkConnectReport> {
+    let connect = async {
+        let started = Instant::now();
+        let stream = TcpStream::connect(proxy_addr).await?;
+        let connected = started.elapsed();
+
+        let report = match protocol {
+            LurkClientProtocol::Socks5 => connect_socks5(stream, connected, target, credentials).await?,
+            LurkClientProtocol::Http => connect_http(stream, connected, target, credentials).await?,
+        };
+        debug!("CONNECT {} through {}: {:?}", target, report.proxy_addr, report);
+
+        Ok(report)
+    };
+
+    timeout(timeout_after, connect)
+        .await
+        .map_err(|_| anyhow!("{} hasn't established the tunnel in {:?}", proxy_addr, timeout_after))?
+}
+
+async fn connect_socks5(
+    mut stream: TcpStream,
+    connect: Duration,
+    target: &str,
+    credentials: Option<(&str, &str)>,
+) -> Result<LurkConnectReport> {
+    let address = parse_target(target)?;
+
+    let handshake_started = Instant::now();
+    socks5::handshake(&mut stream, credentials).await?;
+    let handshake = handshake_started.elapsed();
+
+    let request_started = Instant::now();
+    let response = socks5::request_connect(&mut stream, address).await?;
+
+    Ok(LurkConnectReport {
+        proxy_addr: stream.peer_addr()?,
+        target: target.to_owned(),
+        connect,
+        handshake: Some(handshake),
+        reply_latency: request_started.elapsed(),
+        reply: LurkProxyReply::Socks5(response.status()),
+        bound_addr: Some(response.bound_address().clone()),
+    })
+}
+
+async fn connect_http(stream: TcpStream, connect: Duration, target: &str, credentials: Option<(&str, &str)>) -> Result<LurkConnectReport> {
+    let proxy_addr = stream.peer_addr()?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection.with_upgrades());
+
+    let mut request = Request::builder().method(Method::CONNECT).uri(target).header(header::HOST, target);
+    if let Some((user, password)) = credentials {
+        let token = BASE64.encode(format!("{}:{}", user, password));
+        request = request.header(header::PROXY_AUTHORIZATION, format!("Basic {}", token));
+    }
+
+    let request_started = Instant::now();
+    let response = sender.send_request(request.body(Empty::<Bytes>::new())?).await?;
+
+    Ok(LurkConnectReport {
+        proxy_addr,
+        target: target.to_owned(),
+        connect,
+        handshake: None,
+        reply_latency: request_started.elapsed(),
+        reply: LurkProxyReply::Http(response.status()),
+        bound_addr: None,
+    })
+}
+
+/// Parse "host:port" target, where host is IP address (IPv6 one in brackets) or domain name.
+fn parse_target(target: &str) -> Result<Address> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(Address::SocketAddress(addr));
+    }
+
+    let (host, port) = target
+        .rsplit_once(':')
+        .with_context(|| format!("target '{}' has no port", target))?;
+    let port = port.parse().with_context(|| format!("target '{}' has invalid port", target))?;
+    ensure!(!host.is_empty() && !host.contains(':'), "target '{}' has invalid host", target);
+
+    Ok(Address::DomainName(host.to_owned(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(
+            Address::SocketAddress("127.0.0.1:80".parse().unwrap()),
+            parse_target("127.0.0.1:80").unwrap()
+        );
+        assert_eq!(
+            Address::SocketAddress("[::1]:443".parse().unwrap()),
+            parse_target("[::1]:443").unwrap()
+        );
+        assert_eq!(
+            Address::DomainName("example.com".to_owned(), 443),
+            parse_target("example.com:443").unwrap()
+        );
+
+        assert!(parse_target("example.com").is_err());
+        assert!(parse_target("example.com:http").is_err());
+        assert!(parse_target(":443").is_err());
+        assert!(parse_target("::1:443").is_err());
+    }
+}
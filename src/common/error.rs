@@ -1,4 +1,12 @@
-use crate::{auth::LurkAuthMethod, proto::socks5::Command};
+use crate::{
+    auth::LurkAuthMethod,
+    proto::socks5::{Command, ReplyStatus},
+};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -12,10 +20,93 @@ pub enum LurkError {
     #[error("Unsupported authentication method {0:?}")]
     UnsupportedAuthMethod(LurkAuthMethod),
     #[error("Unable to resolve domain name {0}")]
-    #[allow(dead_code)]
     UnresolvedDomainName(String),
     #[error("Unable to agree on authentication method")]
     NoAcceptableAuthenticationMethod,
+    #[error("Peer hasn't accepted the response within {0:?}")]
+    ResponseWriteTimeout(Duration),
+    #[error("Invalid credentials of user '{0}'")]
+    InvalidCredentials(String),
+    #[error("Transfer quota of user '{0}' is exceeded")]
+    QuotaExceeded(String),
+    #[error("User '{0}' already has {1} active sessions")]
+    SessionLimitExceeded(String, usize),
+    #[error("Session of user '{0}' has reached its maximum duration of {1:?}")]
+    SessionDurationExceeded(String, Duration),
+    #[error("No data has been relayed by the tunnel for {0:?}")]
+    TunnelIdleTimeout(Duration),
+    #[error("Peer has read the relayed data at {0} bytes/sec only, while the endpoint kept sending")]
+    TunnelSlowRead(u64),
+    #[error("Tunnel has reached its maximum lifetime of {0:?}")]
+    TunnelLifetimeExceeded(Duration),
+    #[error("No connection from {0} has been accepted within {1:?}")]
+    BindAcceptTimeout(String, Duration),
+    #[error("Destination {0} is denied by {1}")]
+    DestinationBlocked(String, LurkDenyReason),
+    #[error("Datagrams to {0} can't be relayed, it's routed through upstream proxy")]
+    DatagramsNotRoutable(String),
+    #[error("Upstream proxy has refused to connect to {0}: {1:?}")]
+    UpstreamProxyRefused(String, ReplyStatus),
+    #[error("Certificate of upstream proxy {0} hasn't been verified: {1}")]
+    UpstreamTlsVerificationFailed(SocketAddr, String),
+    #[error("Client {0} isn't allowed to use the proxy")]
+    ClientNotAllowed(IpAddr),
+    #[error("Client {0} already has {1} open connections")]
+    ClientConnectionLimitExceeded(IpAddr, usize),
+    #[error("Client {0} opens connections too fast")]
+    ClientThrottled(IpAddr),
+    #[error("Client {0} is banned")]
+    ClientBanned(IpAddr),
+    #[error("Client {0} has connected to the transparent listener directly instead of being redirected")]
+    ClientNotRedirected(IpAddr),
+    #[error("Load balancer {0} has sent invalid PROXY protocol header: {1}")]
+    InvalidProxyHeader(IpAddr, String),
+    #[error("Client {0} hasn't sent anything within {1:?}")]
+    ProtocolNotDetected(IpAddr, Duration),
+}
+
+/// Mechanism which has denied the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LurkDenySource {
+    Policy,
+    Blocklist,
+    Dnsbl,
+    Routing,
+    Ssrf,
+    GeoIp,
+}
+
+impl fmt::Display for LurkDenySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LurkDenySource::Policy => write!(f, "policy"),
+            LurkDenySource::Blocklist => write!(f, "blocklist"),
+            LurkDenySource::Dnsbl => write!(f, "dnsbl"),
+            LurkDenySource::Routing => write!(f, "routing"),
+            LurkDenySource::Ssrf => write!(f, "ssrf"),
+            LurkDenySource::GeoIp => write!(f, "geoip"),
+        }
+    }
+}
+
+/// Why the destination has been denied.
+///
+/// **Fields**:
+/// * ```source``` - mechanism which has denied the destination
+/// * ```rule``` - id of the policy or routing rule, URL of the blocklist, DNSBL zone, special-purpose range or country
+/// * ```pattern``` - what has matched the destination, e.g. domain suffix or regex of the rule
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkDenyReason {
+    pub source: LurkDenySource,
+    pub rule: String,
+    pub pattern: String,
+}
+
+impl fmt::Display for LurkDenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} '{}' (matched '{}')", self.source, self.rule, self.pattern)
+    }
 }
 
 #[derive(Error, Debug, PartialEq)]
@@ -30,4 +121,6 @@ pub enum InvalidValue {
     AuthMethod(u8),
     #[error("invalid SOCKS command {0:#02x}")]
     SocksCommand(u8),
+    #[error("invalid version of password authentication {0:#02x}")]
+    PasswordAuthVersion(u8),
 }
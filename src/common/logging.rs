@@ -1,5 +1,6 @@
 // Tunnel
 
+#[cfg(any(feature = "socks5", feature = "socks4", feature = "transparent"))]
 macro_rules! log_tunnel_created {
     ($peer:expr, $proxy:expr, $endpoint:expr) => {
         debug!(
@@ -10,6 +11,7 @@ macro_rules! log_tunnel_created {
     };
 }
 
+#[cfg(any(feature = "socks5", feature = "socks4", feature = "transparent"))]
 macro_rules! log_tunnel_closed {
     ($peer:expr, $proxy:expr, $endpoint:expr, $l2r:expr, $r2l:expr) => {
         debug!(
@@ -25,6 +27,7 @@ macro_rules! log_tunnel_closed {
     };
 }
 
+#[cfg(any(feature = "socks5", feature = "socks4", feature = "transparent"))]
 macro_rules! log_tunnel_closed_with_error {
     ($peer:expr, $proxy:expr, $endpoint:expr, $err:expr) => {
         error!(
@@ -36,12 +39,16 @@ macro_rules! log_tunnel_closed_with_error {
     };
 }
 
+#[cfg(any(feature = "socks5", feature = "socks4", feature = "transparent"))]
 pub(crate) use log_tunnel_closed;
+#[cfg(any(feature = "socks5", feature = "socks4", feature = "transparent"))]
 pub(crate) use log_tunnel_closed_with_error;
+#[cfg(any(feature = "socks5", feature = "socks4", feature = "transparent"))]
 pub(crate) use log_tunnel_created;
 
 // 'Request' error handling
 
+#[cfg(any(feature = "socks5", feature = "socks4"))]
 macro_rules! log_request_handling_error {
     ($conn:expr, $err:expr, $req:expr, $resp:expr) => {
         error!(
@@ -117,10 +124,34 @@ macro_rules! log_tcp_acception_error {
     };
 }
 
+macro_rules! log_stuck_conn {
+    ($entry:expr, $age:expr, $idle:expr, $force_close:expr) => {
+        warn!(
+            "\n\n\tTCP {} connection is STUCK: \
+            \n\t\tid: {} \
+            \n\t\tpeer: '{}' \
+            \n\t\tage: {} sec, idle: {} sec \
+            \n\t\ttransmitted: L->R {}, R->L {} \
+            \n\t\taction: {} \
+            \n",
+            $entry.label(),
+            $entry.id(),
+            $entry.peer_addr(),
+            $age.num_seconds(),
+            $idle.as_secs(),
+            human_bytes($entry.activity().l2r_bytes() as f64),
+            human_bytes($entry.activity().r2l_bytes() as f64),
+            if $force_close { "force close" } else { "none" }
+        )
+    };
+}
+
+pub(crate) use log_stuck_conn;
 pub(crate) use log_tcp_acception_error;
+pub(crate) use log_tcp_canceled_conn;
 pub(crate) use log_tcp_closed_conn;
 pub(crate) use log_tcp_closed_conn_with_error;
 pub(crate) use log_tcp_established_conn;
-pub(crate) use log_tcp_canceled_conn;
 
+#[cfg(any(feature = "socks5", feature = "socks4"))]
 pub(crate) use log_request_handling_error;
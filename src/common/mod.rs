@@ -1,5 +1,6 @@
 pub mod error;
 pub mod logging;
+pub mod secret;
 
 #[cfg(test)]
 pub mod assertions;
@@ -1,5 +1,61 @@
-use clap::Parser;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(any(feature = "http3", feature = "https"))]
+use crate::net::tls::{LurkSniCert, LurkTlsPolicy, LurkTlsPreset, LurkTlsResumption, LurkTlsVersion};
+#[cfg(feature = "upstream-tls")]
+use crate::net::upstream_tls::{self, LurkUpstreamTls, LurkUpstreamTlsOptions};
+#[cfg(feature = "acme")]
+use crate::server::acme::{LurkAcmeChallenge, LurkAcmeOptions};
+#[cfg(feature = "compression")]
+use crate::server::compression::LurkCompression;
+#[cfg(feature = "http3")]
+use crate::server::http3::LurkHttp3Options;
+#[cfg(feature = "https")]
+use crate::server::https::LurkHttpsOptions;
+use crate::{
+    api::pushgateway::LurkPushgatewayOptions,
+    auth::{users::LurkUserStore, LurkOfferedAuthMethods},
+    client::LurkClientAction,
+    ctl::LurkCtlAction,
+    doctor::LurkDoctor,
+    net::{
+        geoip::{LurkCountryAccess, LurkGeoIp},
+        socks5::LurkUpstreamProxy,
+        tcp::listener::{LurkClientAccess, LurkTcpListenerOptions, LurkTransparentMode},
+        LurkResolvePolicy,
+    },
+    ping::LurkPingKind,
+    server::{
+        blocklist::LurkBlocklistOptions,
+        checkpoint::LurkStatsCheckpointOptions,
+        cluster::LurkClusterOptions,
+        discovery::{LurkDiscoveryBackend, LurkDiscoveryOptions},
+        dnsbl::{LurkDnsblAction, LurkDnsblOptions},
+        egress::LurkEgressRotation,
+        error_page::LurkErrorPage,
+        policy::LurkPolicy,
+        pool::LurkWarmPoolOptions,
+        profiles::{LurkProfile, LurkProfiles},
+        restart::{LurkRestartOptions, LurkRestartSchedule},
+        routing::LurkRouting,
+        sessions::{LurkSessionRecordFormat, LurkSessionRecordOptions},
+        shards::LurkShardingOptions,
+        ssrf::LurkSsrfGuard,
+        stats::{destinations::LurkDestinationStats, sink::LurkLogStatsSink},
+        tenants::LurkTenant,
+        watchdog::LurkWatchdogOptions,
+        LurkReplyBoundAddress, LurkServer, LurkServerBuilder,
+    },
+    service::LurkServiceKind,
+};
+use anyhow::{ensure, Context, Result};
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 pub const LOG4RS_CONFIG_FILE_PATH: &str = "log4rs.yaml";
 
@@ -11,6 +67,492 @@ pub struct LurkConfig {
 
     #[command(flatten)]
     http_endpoint_config: LurkHttpEndpointConfig,
+
+    #[command(flatten)]
+    watchdog_config: LurkWatchdogConfig,
+
+    #[command(flatten)]
+    stats_config: LurkStatsConfig,
+
+    #[command(flatten)]
+    metrics_push_config: LurkMetricsPushConfig,
+
+    #[command(flatten)]
+    auth_config: LurkAuthConfig,
+
+    #[command(flatten)]
+    session_records_config: LurkSessionRecordsConfig,
+
+    #[command(flatten)]
+    warm_pool_config: LurkWarmPoolConfig,
+
+    #[command(flatten)]
+    access_control_config: LurkAccessControlConfig,
+
+    #[command(flatten)]
+    discovery_config: LurkDiscoveryConfig,
+
+    #[command(flatten)]
+    restart_config: LurkRestartConfig,
+
+    #[command(flatten)]
+    cluster_config: LurkClusterConfig,
+
+    #[cfg(feature = "https")]
+    #[command(flatten)]
+    https_config: LurkHttpsConfig,
+
+    #[cfg(feature = "http3")]
+    #[command(flatten)]
+    http3_config: LurkHttp3Config,
+
+    #[cfg(any(feature = "http3", feature = "https"))]
+    #[command(flatten)]
+    tls_config: LurkTlsConfig,
+
+    #[cfg(feature = "acme")]
+    #[command(flatten)]
+    acme_config: LurkAcmeConfig,
+
+    #[command(subcommand)]
+    command: Option<LurkCommand>,
+}
+
+/// Auxiliary commands. Proxy is started if none of them is passed.
+#[derive(Subcommand, Debug)]
+pub enum LurkCommand {
+    /// Print service file running lurk with the options passed before this command
+    GenService {
+        /// Service manager the file is generated for
+        #[arg(long, value_enum)]
+        kind: LurkServiceKind,
+
+        /// User to run the service by
+        #[arg(long)]
+        user: Option<String>,
+
+        /// Limit of open file descriptors (i.e. of simultaneous connections)
+        #[arg(long, default_value_t = 65536)]
+        limit_nofile: u64,
+    },
+
+    /// Check the running instance (by default, the one started with the options passed before this command)
+    Ping {
+        /// Way the instance is checked
+        #[arg(long, value_enum, default_value_t = LurkPingKind::Socks5)]
+        kind: LurkPingKind,
+
+        /// Address to check instead of the local proxy (or HTTP endpoint) port
+        #[arg(long)]
+        addr: Option<SocketAddr>,
+
+        /// Number of seconds given to the instance to respond
+        #[arg(long, default_value_t = 5)]
+        timeout_secs: u64,
+    },
+
+    /// Administer the running instance through its HTTP endpoint
+    Ctl {
+        /// Address of the HTTP endpoint instead of the local one
+        #[arg(long)]
+        addr: Option<SocketAddr>,
+
+        /// Token required by the HTTP endpoint, if it differs from --http-endpoint-token
+        #[arg(long)]
+        token: Option<String>,
+
+        #[command(subcommand)]
+        action: LurkCtlAction,
+    },
+
+    /// Diagnose any SOCKS5 or HTTP proxy (by default, the local one) acting as its client
+    Client {
+        #[command(subcommand)]
+        action: LurkClientAction,
+    },
+
+    /// Check the environment the proxy is going to run in with the options passed before this command
+    Doctor {
+        /// Domain name resolved to check DNS
+        #[arg(long, default_value = "example.com")]
+        dns_name: String,
+
+        /// Address connected to, to check outbound IPv4 connectivity
+        #[arg(long, default_value = "1.1.1.1:443")]
+        ipv4_probe: SocketAddr,
+
+        /// Address connected to, to check outbound IPv6 connectivity
+        #[arg(long, default_value = "[2606:4700:4700::1111]:443")]
+        ipv6_probe: SocketAddr,
+
+        /// Number of seconds given to every network check
+        #[arg(long, default_value_t = 3)]
+        timeout_secs: u64,
+    },
+
+    /// Replay the client side of the recorded tunnel against the endpoint
+    Replay {
+        /// Recording made by the instance (see --recordings-dir)
+        file: PathBuf,
+
+        /// Endpoint ("host:port") data is replayed against instead of the recorded destination
+        #[arg(long)]
+        target: Option<String>,
+
+        /// Send recorded chunks back-to-back instead of keeping recorded delays between them
+        #[arg(long)]
+        no_delay: bool,
+
+        /// Number of seconds the endpoint is given to respond after the last chunk is sent
+        #[arg(long, default_value_t = 5)]
+        wait_secs: u64,
+    },
+}
+
+impl LurkCommand {
+    pub const GEN_SERVICE: &'static str = "gen-service";
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkRestartConfig {
+    /// Cron-like schedule ("minute hour day-of-month month day-of-week", UTC) of draining and exiting for restart
+    #[arg(long)]
+    restart_schedule: Option<LurkRestartSchedule>,
+
+    /// Maximum number of seconds given to the active connections to finish before the scheduled restart
+    #[arg(long, default_value_t = 300, requires = "restart_schedule")]
+    restart_drain_deadline_secs: u64,
+
+    /// Code the process exits with on scheduled restart, so the supervisor starts it again
+    #[arg(long, default_value_t = LurkRestartOptions::DEFAULT_EXIT_CODE, requires = "restart_schedule")]
+    restart_exit_code: i32,
+}
+
+#[cfg(feature = "https")]
+#[derive(Default, Parser, Debug)]
+struct LurkHttpsConfig {
+    /// Accept HTTP proxy clients connecting over TLS on this TCP port
+    #[arg(long, requires = "https_certificate")]
+    https_port: Option<u16>,
+
+    /// PEM file with the certificate chain presented to HTTPS proxy clients
+    #[arg(long, requires_all = ["https_port", "https_key"], group = "https_certificate")]
+    https_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the HTTPS proxy certificate
+    #[arg(long, requires = "https_port")]
+    https_key: Option<PathBuf>,
+
+    /// Certificate presented instead of --https-cert to the clients asking for the server name by SNI ("server_name:cert_path:key_path"), could be repeated
+    #[arg(long, requires = "https_port")]
+    https_sni_cert: Vec<LurkSniCert>,
+}
+
+#[cfg(feature = "http3")]
+#[derive(Default, Parser, Debug)]
+struct LurkHttp3Config {
+    /// Serve CONNECT requests over HTTP/3 on this UDP port (experimental)
+    #[arg(long, requires = "http3_certificate")]
+    http3_port: Option<u16>,
+
+    /// PEM file with the certificate chain presented to HTTP/3 clients
+    #[arg(long, requires_all = ["http3_port", "http3_key"], group = "http3_certificate")]
+    http3_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the HTTP/3 certificate
+    #[arg(long, requires = "http3_port")]
+    http3_key: Option<PathBuf>,
+
+    /// Certificate presented instead of --http3-cert to the clients asking for the server name by SNI ("server_name:cert_path:key_path"), could be repeated
+    #[arg(long, requires = "http3_port")]
+    http3_sni_cert: Vec<LurkSniCert>,
+}
+
+#[cfg(any(feature = "http3", feature = "https"))]
+#[derive(Default, Parser, Debug)]
+struct LurkTlsConfig {
+    /// TLS policy of the HTTPS and HTTP/3 listeners, which the rest of --tls-* options adjust
+    #[arg(long, value_enum, default_value_t)]
+    tls_preset: LurkTlsPreset,
+
+    /// Oldest TLS version negotiated with the clients (QUIC always negotiates 1.3)
+    #[arg(long, value_enum)]
+    tls_min_version: Option<LurkTlsVersion>,
+
+    /// Comma-separated cipher suites in the order of preference (e.g. TLS13_AES_256_GCM_SHA384), all supported ones by default
+    #[arg(long, value_delimiter = ',')]
+    tls_cipher_suites: Vec<String>,
+
+    /// How the clients resume their TLS sessions
+    #[arg(long, value_enum, default_value_t)]
+    tls_session_resumption: LurkTlsResumption,
+
+    /// Staple OCSP responses fetched from the responders named by the certificates (certificate files should include the issuers)
+    #[arg(long, default_value_t = false)]
+    tls_ocsp_stapling: bool,
+
+    /// Number of seconds between two fetches of the stapled OCSP responses
+    #[arg(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..), requires = "tls_ocsp_stapling")]
+    tls_ocsp_refresh_secs: u64,
+
+    /// Hand TLS sessions of the HTTPS listener over to the kernel (kTLS) after the handshake, where it supports them (Linux only)
+    #[cfg(feature = "https")]
+    #[arg(long, default_value_t = false)]
+    tls_kernel_offload: bool,
+}
+
+#[cfg(feature = "acme")]
+#[derive(Default, Parser, Debug)]
+struct LurkAcmeConfig {
+    /// Domain the certificate of the HTTPS and HTTP/3 listeners is obtained for from the ACME CA instead of passing it, could be repeated
+    #[arg(
+        long,
+        value_delimiter = ',',
+        group = "https_certificate",
+        group = "http3_certificate",
+        conflicts_with_all = ["https_cert", "https_key"]
+    )]
+    #[cfg_attr(feature = "http3", arg(conflicts_with_all = ["http3_cert", "http3_key"]))]
+    acme_domain: Vec<String>,
+
+    /// URL of the ACME directory of the CA
+    #[arg(long, default_value = "https://acme-v02.api.letsencrypt.org/directory", requires = "acme_domain")]
+    acme_directory_url: String,
+
+    /// Email the CA notifies about the certificate, could be repeated
+    #[arg(long, value_delimiter = ',', requires = "acme_domain")]
+    acme_contact: Vec<String>,
+
+    /// Directory the ACME account key, the certificate and its key are kept in
+    #[arg(long, default_value = "acme", requires = "acme_domain")]
+    acme_state_dir: PathBuf,
+
+    /// How the CA validates control over the domains
+    #[arg(long, value_enum, default_value_t, requires = "acme_domain")]
+    acme_challenge: LurkAcmeChallenge,
+
+    /// TCP port HTTP-01 challenge tokens are served on (the CA asks port 80 of the domains)
+    #[arg(long, default_value_t = 80, requires = "acme_domain")]
+    acme_http_port: u16,
+
+    /// PEM file with the roots trusted to serve the ACME directory
+    #[arg(long, default_value = "/etc/ssl/certs/ca-certificates.crt", requires = "acme_domain")]
+    acme_ca_bundle: PathBuf,
+
+    /// Number of days before the expiration the certificate is renewed
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..), requires = "acme_domain")]
+    acme_renew_before_days: u64,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkDiscoveryConfig {
+    /// Register the proxy in the service registry while it's running
+    #[arg(long, value_enum)]
+    discovery_backend: Option<LurkDiscoveryBackend>,
+
+    /// Address of the service registry HTTP API ("host:port"), local agent by default
+    #[arg(long, requires = "discovery_backend")]
+    discovery_endpoint: Option<String>,
+
+    /// Name the proxy is registered with
+    #[arg(long, default_value = "lurk", requires = "discovery_backend")]
+    discovery_service_name: String,
+
+    /// IP address advertised to the clients, registry decides if not set
+    #[arg(long, requires = "discovery_backend")]
+    discovery_advertise_ip: Option<IpAddr>,
+
+    /// Comma-separated tags attached to the registration
+    #[arg(long, value_delimiter = ',', requires = "discovery_backend")]
+    discovery_tags: Vec<String>,
+
+    /// Registration expires unless it's renewed within this number of seconds
+    #[arg(long, default_value_t = 30, requires = "discovery_backend")]
+    discovery_ttl_secs: u64,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkClusterConfig {
+    /// Redis server ("host:port") client bans, users' transfer usage and sessions are shared with the other nodes through
+    #[arg(long)]
+    cluster_redis_addr: Option<String>,
+
+    /// Password of the Redis server
+    #[arg(long, requires = "cluster_redis_addr")]
+    cluster_redis_password: Option<String>,
+
+    /// Name of the node unique within the cluster, generated from PID and start time if not set
+    #[arg(long, requires = "cluster_redis_addr")]
+    cluster_node_id: Option<String>,
+
+    /// Prefix of the keys written to Redis, so several clusters could share one server
+    #[arg(long, default_value = LurkClusterOptions::DEFAULT_KEY_PREFIX, requires = "cluster_redis_addr")]
+    cluster_key_prefix: String,
+
+    /// Number of milliseconds between two exchanges of the state with the cluster
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..), requires = "cluster_redis_addr")]
+    cluster_sync_interval_ms: u64,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkWarmPoolConfig {
+    /// Comma-separated destinations ("host:port") to keep pre-established TCP connections to
+    #[arg(long, value_delimiter = ',')]
+    warm_pool_destinations: Vec<String>,
+
+    /// Number of pre-established connections kept for every destination
+    #[arg(long, default_value_t = 2, requires = "warm_pool_destinations")]
+    warm_pool_size: usize,
+
+    /// Number of seconds after which unused pre-established connection is closed
+    #[arg(long, default_value_t = 30, requires = "warm_pool_destinations")]
+    warm_pool_idle_timeout_secs: u64,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkAccessControlConfig {
+    /// Comma-separated http:// URLs of destination blocklists (hosts-file or domain-list format)
+    #[arg(long, value_delimiter = ',')]
+    blocklist_urls: Vec<String>,
+
+    /// Number of seconds between two fetches of every blocklist (unchanged ones aren't downloaded again)
+    #[arg(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..), requires = "blocklist_urls")]
+    blocklist_refresh_secs: u64,
+
+    /// JSON file with prioritized allow/deny rules for destinations. File is reloaded once it's modified
+    #[arg(long)]
+    policy_file: Option<PathBuf>,
+
+    /// JSON file with named profiles of auth methods, policy and session limits, switched at runtime through HTTP endpoint
+    #[arg(long)]
+    profiles_file: Option<PathBuf>,
+
+    /// Comma-separated DNSBL zones destination IPs are looked up in before connecting, e.g. "zen.spamhaus.org"
+    #[arg(long, value_delimiter = ',')]
+    dnsbl_zones: Vec<String>,
+
+    /// What is done with destination listed in one of the DNSBL zones
+    #[arg(long, value_enum, default_value_t = LurkDnsblAction::Block, requires = "dnsbl_zones")]
+    dnsbl_action: LurkDnsblAction,
+
+    /// Number of seconds DNSBL lookup result of the destination IP is reused for
+    #[arg(long, default_value_t = 300, requires = "dnsbl_zones")]
+    dnsbl_cache_secs: u64,
+
+    /// Refuse destinations resolved into loopback, private, link-local and other special-purpose addresses
+    #[arg(long, default_value_t = false)]
+    ssrf_guard: bool,
+
+    /// Comma-separated networks the SSRF guard lets through, e.g. "10.0.13.0/24"
+    #[arg(long, value_delimiter = ',', requires = "ssrf_guard")]
+    ssrf_guard_exceptions: Vec<IpNet>,
+
+    /// Country database in MaxMind DB format (e.g. GeoLite2-Country.mmdb) client and destination IPs are looked up in
+    #[arg(long)]
+    geoip_database: Option<PathBuf>,
+
+    /// Comma-separated ISO country codes of the clients allowed to use the proxy, e.g. "DE,FR" (any client if not set)
+    #[arg(long, value_delimiter = ',', requires = "geoip_database")]
+    allowed_client_countries: Vec<String>,
+
+    /// Comma-separated ISO country codes of the clients denied to use the proxy, even if they are allowed
+    #[arg(long, value_delimiter = ',', requires = "geoip_database")]
+    denied_client_countries: Vec<String>,
+
+    /// Comma-separated ISO country codes of the destinations allowed to connect to (any destination if not set)
+    #[arg(long, value_delimiter = ',', requires = "geoip_database")]
+    allowed_destination_countries: Vec<String>,
+
+    /// Comma-separated ISO country codes of the destinations denied to connect to, even if they are allowed
+    #[arg(long, value_delimiter = ',', requires = "geoip_database")]
+    denied_destination_countries: Vec<String>,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkSessionRecordsConfig {
+    /// File to append a record about each finished connection to
+    #[arg(long)]
+    session_records_file: Option<PathBuf>,
+
+    /// Format of session records
+    #[arg(long, value_enum, default_value_t = LurkSessionRecordFormat::Jsonl, requires = "session_records_file")]
+    session_records_format: LurkSessionRecordFormat,
+
+    /// Size (in megabytes) after which the session records file is rotated
+    #[arg(long, default_value_t = 100, requires = "session_records_file")]
+    session_records_max_file_size_mb: u64,
+
+    /// Number of rotated session records files to keep
+    #[arg(long, default_value_t = 5, requires = "session_records_file")]
+    session_records_max_files: usize,
+
+    /// Directory tunnels are recorded to, by "record" policy rules or through HTTP endpoint (see "replay")
+    #[arg(long)]
+    recordings_dir: Option<PathBuf>,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkAuthConfig {
+    /// JSON file with users (names, passwords and transfer quotas). Enables SOCKS5 password and HTTP Basic proxy authentication
+    #[arg(long)]
+    users_file: Option<PathBuf>,
+
+    /// Close active sessions of users who have exceeded their transfer quota
+    #[arg(long, requires = "users_file")]
+    quota_close_active: bool,
+
+    /// Limit of simultaneous sessions of every user, unless it's overridden by "max_sessions" of the user
+    #[arg(long, requires = "users_file")]
+    max_sessions_per_user: Option<usize>,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkStatsConfig {
+    /// File to periodically save cumulative stats counters and quota usage of users to. They are restored from it on start
+    #[arg(long)]
+    stats_checkpoint_file: Option<PathBuf>,
+
+    /// Number of seconds between two stats checkpoints
+    #[arg(long, default_value_t = 60, requires = "stats_checkpoint_file")]
+    stats_checkpoint_interval_secs: u64,
+
+    /// Maximum number of destination hosts to keep aggregated stats for (0 disables them)
+    #[arg(long, default_value_t = LurkDestinationStats::DEFAULT_CAPACITY)]
+    stats_destinations_capacity: usize,
+
+    /// Write stats events (connections opened/closed, authentication results) to the log
+    #[arg(long, default_value_t = false)]
+    stats_log_events: bool,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkMetricsPushConfig {
+    /// Address of Prometheus Pushgateway ("host:port") to periodically push metrics to
+    #[arg(long)]
+    metrics_push_endpoint: Option<String>,
+
+    /// Value of the "job" label metrics are pushed with
+    #[arg(long, default_value = "lurk", requires = "metrics_push_endpoint")]
+    metrics_push_job: String,
+
+    /// Value of the "instance" label metrics are pushed with (should be unique for every node)
+    #[arg(long, requires = "metrics_push_endpoint")]
+    metrics_push_instance: Option<String>,
+
+    /// Number of seconds between two metrics pushes
+    #[arg(long, default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..), requires = "metrics_push_endpoint")]
+    metrics_push_interval_secs: u64,
+}
+
+#[derive(Default, Parser, Debug)]
+struct LurkWatchdogConfig {
+    /// Report connections without any data movement for longer than this number of seconds
+    #[arg(long)]
+    watchdog_idle_threshold_secs: Option<u64>,
+
+    /// Close connections reported by watchdog as stuck
+    #[arg(long, default_value_t = false, requires = "watchdog_idle_threshold_secs")]
+    watchdog_force_close: bool,
 }
 
 #[derive(Default, Parser, Debug)]
@@ -22,6 +564,26 @@ struct LurkHttpEndpointConfig {
     /// TCP port to serve HTTP requests
     #[arg(long, default_value_t = 8080)]
     http_endpoint_port: u16,
+
+    /// Maximum number of HTTP endpoint connections waiting to be accepted
+    #[arg(long, default_value_t = LurkTcpListenerOptions::DEFAULT_BACKLOG, value_parser = clap::value_parser!(u32).range(1..))]
+    http_endpoint_listen_backlog: u32,
+
+    /// Set SO_REUSEADDR on the HTTP endpoint listening socket
+    #[arg(long, default_value_t = false)]
+    http_endpoint_reuse_address: bool,
+
+    /// Token required as "Authorization: Bearer <token>" by all HTTP endpoint routes except healthcheck and proxy.pac
+    #[arg(long)]
+    http_endpoint_token: Option<String>,
+
+    /// Comma-separated domains, which clients configured by /proxy.pac of HTTP endpoint connect to directly (besides the ones routed directly by --routing-file)
+    #[arg(long, value_delimiter = ',')]
+    pac_direct_domains: Vec<String>,
+
+    /// TCP port to reply to HAProxy agent / tcp-check health checks on (disabled if not set)
+    #[arg(long)]
+    tcp_check_port: Option<u16>,
 }
 
 #[derive(Default, Parser, Debug)]
@@ -33,14 +595,507 @@ struct LurkProxyServerConfig {
     /// Proxy server IPv4 address to listen on
     #[arg(short = 'i', long, default_value = "0.0.0.0")]
     proxy_ipv4: Option<Ipv4Addr>,
+
+    /// Proxy server IPv6 address to listen on along with the IPv4 one
+    #[arg(long)]
+    proxy_ipv6: Option<Ipv6Addr>,
+
+    /// Accept IPv4 clients on the IPv6 address as well, instead of listening on the IPv4 one
+    #[arg(long, default_value_t = false, requires = "proxy_ipv6")]
+    proxy_dual_stack: bool,
+
+    /// Comma-separated additional addresses (IP:port) the proxy listens on along with the main one
+    #[arg(long, value_delimiter = ',')]
+    listen: Vec<SocketAddr>,
+
+    /// Accept connections redirected by the firewall on this TCP port and tunnel them to their original destinations
+    #[arg(long)]
+    transparent_port: Option<u16>,
+
+    /// How connections are redirected to the transparent port
+    #[arg(long, value_enum, default_value_t = LurkTransparentMode::Redirect, requires = "transparent_port")]
+    transparent_mode: LurkTransparentMode,
+
+    /// Number of seconds given to the client to accept protocol response
+    #[arg(long, default_value_t = 10)]
+    response_write_timeout_secs: u64,
+
+    /// Close tunnels, which clients read slower than this number of bytes per second while destinations keep sending
+    #[arg(long)]
+    min_read_rate: Option<u64>,
+
+    /// Number of seconds the client read rate is measured over
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..), requires = "min_read_rate")]
+    min_read_rate_window_secs: u64,
+
+    /// Relay at most this number of bytes per second in each direction of every tunnel (users and routing rules could override it)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    tunnel_rate_limit: Option<u64>,
+
+    /// Close tunnels, which have been open for longer than this number of seconds, whether they are relaying data or not
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_tunnel_lifetime_secs: Option<u64>,
+
+    /// Maximum number of pending connections accepted at once per listener wakeup
+    #[arg(long, default_value_t = LurkServer::DEFAULT_ACCEPT_BATCH_SIZE as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    accept_batch_size: u64,
+
+    /// Maximum number of proxy connections waiting to be accepted
+    #[arg(long, default_value_t = LurkTcpListenerOptions::DEFAULT_BACKLOG, value_parser = clap::value_parser!(u32).range(1..))]
+    proxy_listen_backlog: u32,
+
+    /// Set SO_REUSEADDR on the proxy listening socket
+    #[arg(long, default_value_t = false)]
+    proxy_reuse_address: bool,
+
+    /// Accept proxy connection only once the client has sent data or the timeout has expired (TCP_DEFER_ACCEPT, Linux only)
+    #[arg(long)]
+    proxy_defer_accept_secs: Option<u64>,
+
+    /// Size (in bytes) of the receive buffer of accepted proxy connections (SO_RCVBUF)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    proxy_recv_buffer_size: Option<u32>,
+
+    /// Size (in bytes) of the send buffer of accepted proxy connections (SO_SNDBUF)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    proxy_send_buffer_size: Option<u32>,
+
+    /// Comma-separated networks (CIDR) of the load balancers, which send PROXY protocol (v1 or v2) header with the client address
+    #[arg(long, value_delimiter = ',')]
+    proxy_protocol_from: Vec<IpNet>,
+
+    /// Comma-separated client networks (CIDR) allowed to use the proxy (any client if not set)
+    #[arg(long, value_delimiter = ',')]
+    allowed_clients: Vec<IpNet>,
+
+    /// Comma-separated client networks (CIDR) denied to use the proxy, even if they are allowed
+    #[arg(long, value_delimiter = ',')]
+    denied_clients: Vec<IpNet>,
+
+    /// Maximum number of connections open by single client IP at once, further ones are refused
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_connections_per_client: Option<u32>,
+
+    /// Average number of new connections per second single client IP may open, faster ones are dropped at accept time
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    connection_rate_per_client: Option<u32>,
+
+    /// Number of new connections single client IP may open at once before it's throttled to its connection rate
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..), requires = "connection_rate_per_client")]
+    connection_burst_per_client: u32,
+
+    /// Relay hop-by-hop headers (Connection, Keep-Alive, TE, etc.) of forwarded HTTP messages verbatim
+    #[arg(long, default_value_t = false)]
+    http_keep_hop_by_hop_headers: bool,
+
+    /// HTML template of the page served with HTTP error responses ({{status}}, {{title}}, {{reason}} and {{contact}} are substituted)
+    #[arg(long)]
+    http_error_page_template: Option<PathBuf>,
+
+    /// Contact info of administrators substituted into the error page
+    #[arg(long, default_value = "", requires = "http_error_page_template")]
+    http_error_page_contact: String,
+
+    /// Compress forwarded HTTP responses with gzip or brotli for the clients accepting them (Accept-Encoding)
+    #[cfg(feature = "compression")]
+    #[arg(long, default_value_t = false)]
+    http_compression: bool,
+
+    /// Forwarded HTTP responses with shorter Content-Length are sent uncompressed
+    #[cfg(feature = "compression")]
+    #[arg(long, default_value_t = LurkCompression::DEFAULT_MIN_SIZE, requires = "http_compression")]
+    http_compression_min_size: u64,
+
+    /// Comma-separated media types of the compressed responses, the ones ending with '/' match all subtypes
+    #[cfg(feature = "compression")]
+    #[arg(long, value_delimiter = ',', default_values = LurkCompression::DEFAULT_CONTENT_TYPES, requires = "http_compression")]
+    http_compression_types: Vec<String>,
+
+    /// Accept proxy connections by this number of loops with their own listeners bound with SO_REUSEPORT
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "reactor_shards")]
+    proxy_acceptors: u32,
+
+    /// Serve clients by this number of single-threaded runtimes with their own listeners (0 means one per CPU core)
+    #[arg(long)]
+    reactor_shards: Option<usize>,
+
+    /// Pin every reactor shard thread to its own CPU core
+    #[arg(long, default_value_t = false, requires = "reactor_shards")]
+    reactor_shards_pin_threads: bool,
+
+    /// Comma-separated egress IPs of the host to spread SOCKS5 clients over (sticky by username or client IP)
+    #[arg(long, value_delimiter = ',')]
+    egress_ips: Vec<IpAddr>,
+
+    /// How clients are rotated over the egress IPs
+    #[arg(long, value_enum, default_value_t = LurkEgressRotation::Sticky, requires = "egress_ips")]
+    egress_rotation: LurkEgressRotation,
+
+    /// Number of seconds clients stick to their egress IPs with the periodic rotation
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..), requires = "egress_ips")]
+    egress_rotation_period_secs: u64,
+
+    /// Address reported to SOCKS clients once the destination is connected
+    #[arg(long, value_enum, default_value_t = LurkReplyBoundAddress::Outbound)]
+    reply_bound_address: LurkReplyBoundAddress,
+
+    /// How domain names of the destinations are resolved
+    #[arg(long, value_enum, default_value_t = LurkResolvePolicy::System)]
+    resolve_policy: LurkResolvePolicy,
+
+    /// JSON file with tenants served on their own ports, each with its own users, policy and stats
+    #[arg(long)]
+    tenants_file: Option<PathBuf>,
+
+    /// Send the first data to destinations along with SYN (TCP Fast Open, Linux only)
+    #[arg(long, default_value_t = false)]
+    outbound_tcp_fast_open: bool,
+
+    /// Connect to destinations with Multipath TCP, falling back to TCP if it's unsupported (Linux only)
+    #[arg(long, default_value_t = false)]
+    outbound_mptcp: bool,
+
+    /// Address of the parent SOCKS5 proxy to connect to destinations through instead of connecting directly
+    #[arg(long)]
+    upstream_proxy: Option<SocketAddr>,
+
+    /// Username to authenticate with on the parent SOCKS5 proxy
+    #[arg(long, requires_all = ["upstream_proxy", "upstream_proxy_password"])]
+    upstream_proxy_user: Option<String>,
+
+    /// Password to authenticate with on the parent SOCKS5 proxy
+    #[arg(long, requires = "upstream_proxy_user")]
+    upstream_proxy_password: Option<String>,
+
+    /// Comma-separated domains (with their subdomains) connected through the parent proxy (all destinations if not set)
+    #[arg(long, value_delimiter = ',', requires = "upstream_proxy")]
+    upstream_proxy_domains: Vec<String>,
+
+    /// Connect to the parent SOCKS5 proxy over TLS, its certificate is verified by the system CAs unless the CA bundle or pin is passed
+    #[cfg(feature = "upstream-tls")]
+    #[arg(long, default_value_t = false, requires = "upstream_proxy")]
+    upstream_proxy_tls: bool,
+
+    /// Server name the certificate of the parent proxy is verified against (its IP address if not set)
+    #[cfg(feature = "upstream-tls")]
+    #[arg(long, requires = "upstream_proxy_tls")]
+    upstream_proxy_tls_server_name: Option<String>,
+
+    /// PEM file with the CAs trusted to issue the certificate of the parent proxy
+    #[cfg(feature = "upstream-tls")]
+    #[arg(long, requires = "upstream_proxy_tls")]
+    upstream_proxy_ca_bundle: Option<PathBuf>,
+
+    /// Base64 SHA-256 digest of the public key (SPKI) the certificate of the parent proxy should have, could be repeated.
+    /// Pinned key alone is trusted unless the CA bundle is passed
+    #[cfg(feature = "upstream-tls")]
+    #[arg(long, value_parser = upstream_tls::parse_pin_sha256, requires = "upstream_proxy_tls")]
+    upstream_proxy_pin_sha256: Vec<[u8; 32]>,
+
+    /// Accept any certificate of the parent proxy without verification (insecure)
+    #[cfg(feature = "upstream-tls")]
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "upstream_proxy_tls",
+        conflicts_with_all = ["upstream_proxy_ca_bundle", "upstream_proxy_pin_sha256"]
+    )]
+    upstream_proxy_insecure_skip_verify: bool,
+
+    /// JSON file with rules routing destinations directly, through named parent SOCKS5 proxies or blocking them
+    #[arg(long)]
+    routing_file: Option<PathBuf>,
 }
 
 impl LurkConfig {
+    pub fn command(&self) -> Option<&LurkCommand> {
+        self.command.as_ref()
+    }
+
+    /// Local address the instance started with current options serves the ping of passed kind on.
+    pub fn ping_addr(&self, kind: LurkPingKind) -> SocketAddr {
+        let port = match kind {
+            LurkPingKind::Socks5 => self.proxy_server_config.proxy_port,
+            LurkPingKind::Healthcheck => self.http_endpoint_config.http_endpoint_port,
+        };
+
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    /// Main address of the proxy: the IPv4 one, unless the dual-stack IPv6 address serves IPv4 clients instead.
     pub fn server_tcp_bind_addr(&self) -> SocketAddr {
-        let port = self.proxy_server_config.proxy_port;
-        let ipv4 = self.proxy_server_config.proxy_ipv4.expect("IPv4 should have correct format");
+        let config = &self.proxy_server_config;
+        match config.proxy_ipv6 {
+            Some(ipv6) if config.proxy_dual_stack => SocketAddr::new(IpAddr::V6(ipv6), config.proxy_port),
+            _ => {
+                let ipv4 = config.proxy_ipv4.expect("IPv4 should have correct format");
+                SocketAddr::new(IpAddr::V4(ipv4), config.proxy_port)
+            }
+        }
+    }
+
+    /// Addresses the proxy listens on along with the main one.
+    pub fn additional_server_tcp_bind_addrs(&self) -> Vec<SocketAddr> {
+        let config = &self.proxy_server_config;
+        let ipv6_addr = match config.proxy_ipv6 {
+            Some(ipv6) if !config.proxy_dual_stack => Some(SocketAddr::new(IpAddr::V6(ipv6), config.proxy_port)),
+            _ => None,
+        };
+
+        ipv6_addr.into_iter().chain(config.listen.iter().copied()).collect()
+    }
+
+    /// Address the connections redirected by the firewall are accepted on, if transparent proxying is enabled.
+    pub fn transparent_bind_addr(&self) -> Option<SocketAddr> {
+        let port = self.proxy_server_config.transparent_port?;
+        Some(SocketAddr::new(self.server_tcp_bind_addr().ip(), port))
+    }
+
+    /// Tenants served on their own ports along with the main listener.
+    pub fn tenants(&self) -> Result<Vec<LurkTenant>> {
+        let Some(path) = &self.proxy_server_config.tenants_file else {
+            return Ok(Vec::new());
+        };
+
+        let tenants = LurkTenant::from_file(path)?;
+        for tenant in &tenants {
+            ensure!(
+                tenant.port != self.proxy_server_config.proxy_port,
+                "port {} of tenant '{}' is taken by the main listener",
+                tenant.port,
+                tenant.name
+            );
+        }
+        Ok(tenants)
+    }
+
+    /// Doctor checking the environment for the instance started with current options: addresses
+    /// it's going to listen on and the number of connections it's allowed to serve at once.
+    pub fn doctor(&self, timeout: Duration) -> Result<LurkDoctor> {
+        let mut doctor = LurkDoctor::new(timeout).with_listener("proxy", self.server_tcp_bind_addr());
+        for addr in self.additional_server_tcp_bind_addrs() {
+            doctor = doctor.with_listener("proxy", addr);
+        }
+        if let Some(addr) = self.transparent_bind_addr() {
+            doctor = doctor.with_listener("transparent proxy", addr);
+        }
+        if let Some(addr) = self.http_endpoint_bind_addr() {
+            doctor = doctor.with_listener("HTTP endpoint", addr);
+        }
+        if let Some(addr) = self.tcp_check_bind_addr() {
+            doctor = doctor.with_listener("TCP check", addr);
+        }
+
+        let mut user_stores = vec![self.user_store()?];
+        for tenant in self.tenants()? {
+            doctor = doctor.with_listener(format!("tenant '{}'", tenant.name), self.tenant_bind_addr(&tenant));
+            user_stores.push(self.tenant_user_store(&tenant)?);
+        }
+
+        // Number of connections is limited only if every user of every listener has limit of sessions.
+        let max_sessions: Option<u64> = user_stores
+            .iter()
+            .map(|users| {
+                let users = users.as_ref()?;
+                users
+                    .names()
+                    .into_iter()
+                    .map(|name| Some(users.max_sessions(name)? as u64))
+                    .sum::<Option<u64>>()
+            })
+            .sum();
+        if let Some(max_sessions) = max_sessions {
+            let config = &self.warm_pool_config;
+            let pooled = (config.warm_pool_destinations.len() * config.warm_pool_size) as u64;
+            doctor = doctor.with_max_connections(max_sessions + pooled);
+        }
+
+        Ok(doctor)
+    }
+
+    pub fn tenant_bind_addr(&self, tenant: &LurkTenant) -> SocketAddr {
+        SocketAddr::new(self.server_tcp_bind_addr().ip(), tenant.port)
+    }
+
+    /// Users of the tenant. Quota and session limits of the main listener apply to them as well.
+    pub fn tenant_user_store(&self, tenant: &LurkTenant) -> Result<Option<LurkUserStore>> {
+        tenant.users_file.as_deref().map(|path| self.load_user_store(path)).transpose()
+    }
+
+    pub fn tenant_policy(&self, tenant: &LurkTenant) -> Result<Option<LurkPolicy>> {
+        tenant.policy_file.as_deref().map(LurkPolicy::from_file).transpose()
+    }
+
+    pub fn response_write_timeout(&self) -> Duration {
+        Duration::from_secs(self.proxy_server_config.response_write_timeout_secs)
+    }
+
+    pub fn min_read_rate(&self) -> Option<(u64, Duration)> {
+        let config = &self.proxy_server_config;
+        config
+            .min_read_rate
+            .map(|bytes_per_sec| (bytes_per_sec, Duration::from_secs(config.min_read_rate_window_secs)))
+    }
+
+    pub fn tunnel_rate_limit(&self) -> Option<u64> {
+        self.proxy_server_config.tunnel_rate_limit
+    }
+
+    pub fn max_tunnel_lifetime(&self) -> Option<Duration> {
+        self.proxy_server_config.max_tunnel_lifetime_secs.map(Duration::from_secs)
+    }
+
+    pub fn egress_ips(&self) -> &[IpAddr] {
+        &self.proxy_server_config.egress_ips
+    }
+
+    pub fn egress_rotation(&self) -> (LurkEgressRotation, Duration) {
+        let config = &self.proxy_server_config;
+        (config.egress_rotation, Duration::from_secs(config.egress_rotation_period_secs))
+    }
+
+    pub fn reply_bound_address(&self) -> LurkReplyBoundAddress {
+        self.proxy_server_config.reply_bound_address
+    }
+
+    pub fn resolve_policy(&self) -> LurkResolvePolicy {
+        self.proxy_server_config.resolve_policy
+    }
+
+    pub fn outbound_tcp_fast_open(&self) -> bool {
+        self.proxy_server_config.outbound_tcp_fast_open
+    }
 
-        SocketAddr::new(IpAddr::V4(ipv4), port)
+    pub fn outbound_mptcp(&self) -> bool {
+        self.proxy_server_config.outbound_mptcp
+    }
+
+    pub fn upstream_proxy(&self) -> Result<Option<LurkUpstreamProxy>> {
+        let config = &self.proxy_server_config;
+        let Some(addr) = config.upstream_proxy else {
+            return Ok(None);
+        };
+        let mut upstream_proxy = LurkUpstreamProxy::new(addr);
+        if let (Some(user), Some(password)) = (&config.upstream_proxy_user, &config.upstream_proxy_password) {
+            upstream_proxy.set_credentials(user, password);
+        }
+        upstream_proxy.set_domains(config.upstream_proxy_domains.iter().cloned());
+
+        #[cfg(feature = "upstream-tls")]
+        if config.upstream_proxy_tls {
+            let mut options = LurkUpstreamTlsOptions::new();
+            if let Some(server_name) = &config.upstream_proxy_tls_server_name {
+                options.set_server_name(server_name);
+            }
+            if let Some(ca_bundle) = &config.upstream_proxy_ca_bundle {
+                options.set_ca_bundle(ca_bundle);
+            }
+            options
+                .set_pins(config.upstream_proxy_pin_sha256.iter().copied())
+                .set_insecure_skip_verify(config.upstream_proxy_insecure_skip_verify);
+            upstream_proxy.set_tls(LurkUpstreamTls::new(options)?);
+        }
+
+        Ok(Some(upstream_proxy))
+    }
+
+    pub fn routing(&self) -> Result<Option<LurkRouting>> {
+        self.proxy_server_config
+            .routing_file
+            .as_deref()
+            .map(LurkRouting::from_file)
+            .transpose()
+    }
+
+    pub fn http_keep_hop_by_hop_headers(&self) -> bool {
+        self.proxy_server_config.http_keep_hop_by_hop_headers
+    }
+
+    pub fn http_error_page(&self) -> Result<Option<LurkErrorPage>> {
+        let config = &self.proxy_server_config;
+        config
+            .http_error_page_template
+            .as_ref()
+            .map(|path| LurkErrorPage::from_file(path, config.http_error_page_contact.as_str()))
+            .transpose()
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn http_compression(&self) -> Option<LurkCompression> {
+        let config = &self.proxy_server_config;
+        if !config.http_compression {
+            return None;
+        }
+
+        let mut compression = LurkCompression::new();
+        compression
+            .set_min_size(config.http_compression_min_size)
+            .set_content_types(config.http_compression_types.iter().cloned());
+        Some(compression)
+    }
+
+    pub fn accept_batch_size(&self) -> usize {
+        self.proxy_server_config.accept_batch_size as usize
+    }
+
+    pub fn proxy_acceptors(&self) -> usize {
+        self.proxy_server_config.proxy_acceptors as usize
+    }
+
+    /// Options of the proxy listener. Client countries are checked, if the GeoIP database is passed.
+    /// Clones of the options share the connection rate limiter, so every client is throttled once.
+    pub fn proxy_listener_options(&self, geoip: Option<&Arc<LurkGeoIp>>) -> LurkTcpListenerOptions {
+        let config = &self.proxy_server_config;
+        let mut client_access = LurkClientAccess::new(config.allowed_clients.clone(), config.denied_clients.clone());
+        if let Some(countries) = self.client_countries(geoip) {
+            client_access.set_countries(countries);
+        }
+        if let Some(rate) = config.connection_rate_per_client {
+            client_access.set_connection_rate(rate, config.connection_burst_per_client);
+        }
+
+        let mut options = LurkTcpListenerOptions::new(config.proxy_listen_backlog);
+        options
+            .set_reuse_address(config.proxy_reuse_address)
+            .set_dual_stack(config.proxy_dual_stack)
+            .set_proxy_protocol(config.proxy_protocol_from.clone())
+            .set_client_access(client_access);
+        if let Some(secs) = config.proxy_defer_accept_secs {
+            options.set_defer_accept(Duration::from_secs(secs));
+        }
+        if let Some(size) = config.proxy_recv_buffer_size {
+            options.set_recv_buffer_size(size as usize);
+        }
+        if let Some(size) = config.proxy_send_buffer_size {
+            options.set_send_buffer_size(size as usize);
+        }
+        options
+    }
+
+    pub fn max_connections_per_client(&self) -> Option<usize> {
+        self.proxy_server_config.max_connections_per_client.map(|max| max as usize)
+    }
+
+    pub fn sharding_options(&self) -> Option<LurkShardingOptions> {
+        let config = &self.proxy_server_config;
+        config
+            .reactor_shards
+            .map(|shards| LurkShardingOptions::new(shards, config.reactor_shards_pin_threads))
+    }
+
+    pub fn http_endpoint_listener_options(&self) -> LurkTcpListenerOptions {
+        let config = &self.http_endpoint_config;
+        let mut options = LurkTcpListenerOptions::new(config.http_endpoint_listen_backlog);
+        options.set_reuse_address(config.http_endpoint_reuse_address);
+        options
+    }
+
+    pub fn http_endpoint_token(&self) -> Option<&str> {
+        self.http_endpoint_config.http_endpoint_token.as_deref()
+    }
+
+    pub fn pac_direct_domains(&self) -> &[String] {
+        &self.http_endpoint_config.pac_direct_domains
     }
 
     pub fn http_endpoint_bind_addr(&self) -> Option<SocketAddr> {
@@ -53,4 +1108,449 @@ impl LurkConfig {
 
         Some(SocketAddr::new(IpAddr::V4(ipv4), port))
     }
+
+    pub fn tcp_check_bind_addr(&self) -> Option<SocketAddr> {
+        self.http_endpoint_config
+            .tcp_check_port
+            .map(|port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port))
+    }
+
+    pub fn watchdog_options(&self) -> Option<LurkWatchdogOptions> {
+        self.watchdog_config
+            .watchdog_idle_threshold_secs
+            .map(|secs| LurkWatchdogOptions::new(Duration::from_secs(secs), self.watchdog_config.watchdog_force_close))
+    }
+
+    pub fn stats_checkpoint_options(&self) -> Option<LurkStatsCheckpointOptions> {
+        let interval = Duration::from_secs(self.stats_config.stats_checkpoint_interval_secs);
+        self.stats_config
+            .stats_checkpoint_file
+            .as_ref()
+            .map(|path| LurkStatsCheckpointOptions::new(path, interval))
+    }
+
+    /// Load users from the configured file (if any).
+    pub fn user_store(&self) -> Result<Option<LurkUserStore>> {
+        self.auth_config
+            .users_file
+            .as_deref()
+            .map(|path| self.load_user_store(path))
+            .transpose()
+    }
+
+    fn load_user_store(&self, path: &Path) -> Result<LurkUserStore> {
+        let users = LurkUserStore::from_file(path, self.auth_config.quota_close_active)?;
+        Ok(match self.auth_config.max_sessions_per_user {
+            Some(max_sessions) => users.with_max_sessions(max_sessions),
+            None => users,
+        })
+    }
+
+    pub fn pushgateway_options(&self) -> Option<LurkPushgatewayOptions> {
+        let config = &self.metrics_push_config;
+        let endpoint = config.metrics_push_endpoint.as_ref()?;
+        let mut options = LurkPushgatewayOptions::new(
+            endpoint,
+            &config.metrics_push_job,
+            Duration::from_secs(config.metrics_push_interval_secs),
+        );
+        if let Some(instance) = &config.metrics_push_instance {
+            options.set_instance(instance);
+        }
+
+        Some(options)
+    }
+
+    pub fn stats_destinations_capacity(&self) -> usize {
+        self.stats_config.stats_destinations_capacity
+    }
+
+    pub fn stats_log_events(&self) -> bool {
+        self.stats_config.stats_log_events
+    }
+
+    pub fn blocklist_options(&self) -> Option<LurkBlocklistOptions> {
+        let config = &self.access_control_config;
+        if config.blocklist_urls.is_empty() {
+            return None;
+        }
+
+        Some(LurkBlocklistOptions::new(
+            config.blocklist_urls.iter().cloned(),
+            Duration::from_secs(config.blocklist_refresh_secs),
+        ))
+    }
+
+    pub fn dnsbl_options(&self) -> Option<LurkDnsblOptions> {
+        let config = &self.access_control_config;
+        if config.dnsbl_zones.is_empty() {
+            return None;
+        }
+
+        Some(LurkDnsblOptions::new(
+            config.dnsbl_zones.iter().cloned(),
+            config.dnsbl_action,
+            Duration::from_secs(config.dnsbl_cache_secs),
+        ))
+    }
+
+    pub fn ssrf_guard(&self) -> Option<LurkSsrfGuard> {
+        let config = &self.access_control_config;
+        config.ssrf_guard.then(|| LurkSsrfGuard::new(config.ssrf_guard_exceptions.clone()))
+    }
+
+    /// Load GeoIP database from the configured file (if any).
+    pub fn geoip(&self) -> Result<Option<Arc<LurkGeoIp>>> {
+        self.access_control_config
+            .geoip_database
+            .as_ref()
+            .map(|path| LurkGeoIp::from_file(path).map(Arc::new))
+            .transpose()
+    }
+
+    fn client_countries(&self, geoip: Option<&Arc<LurkGeoIp>>) -> Option<LurkCountryAccess> {
+        let config = &self.access_control_config;
+        country_access(geoip, &config.allowed_client_countries, &config.denied_client_countries)
+    }
+
+    pub fn destination_countries(&self, geoip: Option<&Arc<LurkGeoIp>>) -> Option<LurkCountryAccess> {
+        let config = &self.access_control_config;
+        country_access(geoip, &config.allowed_destination_countries, &config.denied_destination_countries)
+    }
+
+    /// Load destination policy from the configured file (if any).
+    pub fn policy(&self) -> Result<Option<LurkPolicy>> {
+        self.access_control_config
+            .policy_file
+            .as_ref()
+            .map(|path| LurkPolicy::from_file(path))
+            .transpose()
+    }
+
+    /// Load named profiles from the configured file (if any).
+    /// Settings omitted by the profiles are taken from the command line options.
+    pub fn profiles(&self) -> Result<Option<LurkProfiles>> {
+        let Some(path) = &self.access_control_config.profiles_file else {
+            return Ok(None);
+        };
+
+        let defaults = LurkProfile {
+            auth_methods: None,
+            policy_file: self.access_control_config.policy_file.clone(),
+            max_sessions_per_user: self.auth_config.max_sessions_per_user,
+        };
+        let profiles = LurkProfiles::from_file(path, defaults)?;
+
+        let offered_auth_methods = LurkOfferedAuthMethods::new(self.auth_config.users_file.is_some());
+        for (name, profile) in profiles.iter() {
+            ensure!(
+                profile.policy_file.is_none() || self.access_control_config.policy_file.is_some(),
+                "policy of profile '{}' requires --policy-file",
+                name
+            );
+            ensure!(
+                profile.max_sessions_per_user.is_none() || self.auth_config.users_file.is_some(),
+                "limit of user sessions of profile '{}' requires --users-file",
+                name
+            );
+            if let Some(auth_methods) = &profile.auth_methods {
+                offered_auth_methods
+                    .validate(auth_methods)
+                    .with_context(|| format!("profile '{}' is invalid", name))?;
+            }
+        }
+
+        Ok(Some(profiles))
+    }
+
+    pub fn warm_pool_options(&self) -> Option<LurkWarmPoolOptions> {
+        let config = &self.warm_pool_config;
+        if config.warm_pool_destinations.is_empty() {
+            return None;
+        }
+
+        Some(LurkWarmPoolOptions::new(
+            config.warm_pool_destinations.iter().cloned(),
+            config.warm_pool_size,
+            Duration::from_secs(config.warm_pool_idle_timeout_secs),
+        ))
+    }
+
+    pub fn restart_options(&self) -> Option<LurkRestartOptions> {
+        let config = &self.restart_config;
+        config.restart_schedule.clone().map(|schedule| {
+            LurkRestartOptions::new(
+                schedule,
+                Duration::from_secs(config.restart_drain_deadline_secs),
+                config.restart_exit_code,
+            )
+        })
+    }
+
+    /// HTTPS listener shares the IP address with the plain one.
+    #[cfg(feature = "https")]
+    pub fn https_options(&self) -> Option<LurkHttpsOptions> {
+        let config = &self.https_config;
+        let bind_addr = SocketAddr::new(self.server_tcp_bind_addr().ip(), config.https_port?);
+        let (cert_path, key_path) = self.listener_cert_paths(&config.https_cert, &config.https_key)?;
+        let mut options = LurkHttpsOptions::new(bind_addr, cert_path, key_path);
+        options
+            .set_sni_certs(config.https_sni_cert.clone())
+            .set_tls_policy(self.tls_policy());
+        Some(options)
+    }
+
+    /// HTTP/3 listener shares the IP address with the TCP one.
+    #[cfg(feature = "http3")]
+    pub fn http3_options(&self) -> Option<LurkHttp3Options> {
+        let config = &self.http3_config;
+        let bind_addr = SocketAddr::new(self.server_tcp_bind_addr().ip(), config.http3_port?);
+        let (cert_path, key_path) = self.listener_cert_paths(&config.http3_cert, &config.http3_key)?;
+        let mut options = LurkHttp3Options::new(bind_addr, cert_path, key_path);
+        options
+            .set_sni_certs(config.http3_sni_cert.clone())
+            .set_tls_policy(self.tls_policy());
+        Some(options)
+    }
+
+    /// Certificate and key presented by the TLS listener: the ones obtained by the ACME client, unless they are passed.
+    #[cfg(any(feature = "http3", feature = "https"))]
+    fn listener_cert_paths(&self, cert_path: &Option<PathBuf>, key_path: &Option<PathBuf>) -> Option<(PathBuf, PathBuf)> {
+        #[cfg(feature = "acme")]
+        if let Some(acme_options) = self.acme_options() {
+            return Some((acme_options.cert_path(), acme_options.key_path()));
+        }
+        Some((cert_path.clone()?, key_path.clone()?))
+    }
+
+    /// ACME client shares the IP address with the TCP listener.
+    #[cfg(feature = "acme")]
+    pub fn acme_options(&self) -> Option<LurkAcmeOptions> {
+        let config = &self.acme_config;
+        if config.acme_domain.is_empty() {
+            return None;
+        }
+        let mut options = LurkAcmeOptions::new(&config.acme_directory_url, config.acme_domain.clone(), &config.acme_state_dir);
+        options
+            .set_contacts(config.acme_contact.clone())
+            .set_challenge(config.acme_challenge)
+            .set_http_challenge_addr(SocketAddr::new(self.server_tcp_bind_addr().ip(), config.acme_http_port))
+            .set_ca_bundle(Some(config.acme_ca_bundle.clone()))
+            .set_renew_before(Duration::from_secs(config.acme_renew_before_days * 24 * 60 * 60));
+        Some(options)
+    }
+
+    /// TLS policy shared by the HTTPS and HTTP/3 listeners.
+    #[cfg(any(feature = "http3", feature = "https"))]
+    pub fn tls_policy(&self) -> LurkTlsPolicy {
+        let config = &self.tls_config;
+        let mut policy = LurkTlsPolicy::new(config.tls_preset);
+        if let Some(min_version) = config.tls_min_version {
+            policy.set_min_version(min_version);
+        }
+        policy
+            .set_cipher_suites(config.tls_cipher_suites.clone())
+            .set_resumption(config.tls_session_resumption)
+            .set_ocsp_refresh_interval(config.tls_ocsp_stapling.then(|| Duration::from_secs(config.tls_ocsp_refresh_secs)));
+        #[cfg(feature = "https")]
+        policy.set_kernel_offload(config.tls_kernel_offload);
+        policy
+    }
+
+    pub fn cluster_options(&self) -> Option<LurkClusterOptions> {
+        let config = &self.cluster_config;
+        let redis_addr = config.cluster_redis_addr.as_ref()?;
+        let node_id = config
+            .cluster_node_id
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", std::process::id(), Utc::now().timestamp_millis()));
+
+        let mut options = LurkClusterOptions::new(redis_addr, node_id);
+        options
+            .set_key_prefix(&config.cluster_key_prefix)
+            .set_sync_interval(Duration::from_millis(config.cluster_sync_interval_ms));
+        if let Some(password) = &config.cluster_redis_password {
+            options.set_redis_password(password);
+        }
+
+        Some(options)
+    }
+
+    pub fn discovery_options(&self) -> Option<LurkDiscoveryOptions> {
+        let config = &self.discovery_config;
+        let backend = config.discovery_backend?;
+        let endpoint = config
+            .discovery_endpoint
+            .clone()
+            .unwrap_or_else(|| backend.default_endpoint().to_owned());
+
+        let mut options = LurkDiscoveryOptions::new(
+            backend,
+            endpoint,
+            &config.discovery_service_name,
+            Duration::from_secs(config.discovery_ttl_secs),
+        );
+        options.set_tags(config.discovery_tags.iter().cloned());
+        if let Some(advertise_ip) = config.discovery_advertise_ip {
+            options.set_advertise_ip(advertise_ip);
+        }
+        if let Some(http_endpoint_addr) = self.http_endpoint_bind_addr() {
+            let health_ip = config.discovery_advertise_ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            let health_addr = SocketAddr::new(health_ip, http_endpoint_addr.port());
+            options.set_health_url(format!("http://{}/healthcheck", health_addr));
+        }
+
+        Some(options)
+    }
+
+    pub fn session_record_options(&self) -> Option<LurkSessionRecordOptions> {
+        let config = &self.session_records_config;
+        config.session_records_file.as_ref().map(|path| {
+            LurkSessionRecordOptions::new(
+                path,
+                config.session_records_format,
+                config.session_records_max_file_size_mb * 1024 * 1024,
+                config.session_records_max_files,
+            )
+        })
+    }
+
+    pub fn recordings_dir(&self) -> Option<&Path> {
+        self.session_records_config.recordings_dir.as_deref()
+    }
+
+    /// Builder of the main proxy server with all the options. Tenants, HTTP endpoint
+    /// and the rest of the auxiliary services are set up separately.
+    pub fn server_builder(&self) -> Result<LurkServerBuilder> {
+        let mut server_builder = self.shared_server_builder(self.server_tcp_bind_addr())?;
+        for bind_addr in self.additional_server_tcp_bind_addrs() {
+            server_builder.with_additional_bind_addr(bind_addr);
+        }
+        if let Some(bind_addr) = self.transparent_bind_addr() {
+            server_builder.with_transparent_listener(bind_addr, self.proxy_server_config.transparent_mode);
+        }
+        if let Some(watchdog_options) = self.watchdog_options() {
+            server_builder.with_watchdog(watchdog_options);
+        }
+        if let Some(checkpoint_options) = self.stats_checkpoint_options() {
+            server_builder.with_stats_checkpoint(checkpoint_options);
+        }
+        if let Some(session_record_options) = self.session_record_options() {
+            server_builder.with_session_records(session_record_options);
+        }
+        if let Some(warm_pool_options) = self.warm_pool_options() {
+            server_builder.with_warm_pool(warm_pool_options);
+        }
+        if let Some(policy) = self.policy()? {
+            server_builder.with_policy(policy);
+        }
+        if let Some(profiles) = self.profiles()? {
+            server_builder.with_profiles(profiles);
+        }
+        if let Some(recordings_dir) = self.recordings_dir() {
+            server_builder.with_recordings(recordings_dir.to_owned());
+        }
+        if let Some(discovery_options) = self.discovery_options() {
+            server_builder.with_service_registration(discovery_options);
+        }
+        if let Some(sharding_options) = self.sharding_options() {
+            server_builder.with_sharding(sharding_options);
+        }
+        if let Some(users) = self.user_store()? {
+            server_builder.with_users(Arc::new(users));
+        }
+        if let Some(cluster_options) = self.cluster_options() {
+            server_builder.with_cluster_state(cluster_options);
+        }
+        #[cfg(feature = "https")]
+        if let Some(https_options) = self.https_options() {
+            server_builder.with_https(https_options);
+        }
+        #[cfg(feature = "http3")]
+        if let Some(http3_options) = self.http3_options() {
+            server_builder.with_http3(http3_options);
+        }
+        #[cfg(feature = "acme")]
+        if let Some(acme_options) = self.acme_options() {
+            server_builder.with_acme(acme_options);
+        }
+
+        Ok(server_builder)
+    }
+
+    /// Builder of the server with settings shared by the main listener and the tenants' ones.
+    pub fn shared_server_builder(&self, bind_addr: SocketAddr) -> Result<LurkServerBuilder> {
+        let geoip = self.geoip()?;
+        let mut server_builder = LurkServer::builder(bind_addr);
+        server_builder
+            .with_listener_options(self.proxy_listener_options(geoip.as_ref()))
+            .with_response_write_timeout(self.response_write_timeout())
+            .with_accept_batch_size(self.accept_batch_size())
+            .with_acceptors(self.proxy_acceptors())
+            .with_hop_by_hop_headers_kept(self.http_keep_hop_by_hop_headers())
+            .with_outbound_fast_open(self.outbound_tcp_fast_open())
+            .with_outbound_mptcp(self.outbound_mptcp())
+            .with_reply_bound_address(self.reply_bound_address())
+            .with_resolve_policy(self.resolve_policy())
+            .with_destinations_capacity(self.stats_destinations_capacity());
+        if self.stats_log_events() {
+            server_builder.with_stats_sink(Arc::new(LurkLogStatsSink));
+        }
+        if let Some(blocklist_options) = self.blocklist_options() {
+            server_builder.with_blocklist(blocklist_options);
+        }
+        if let Some(dnsbl_options) = self.dnsbl_options() {
+            server_builder.with_dnsbl(dnsbl_options);
+        }
+        if let Some(ssrf_guard) = self.ssrf_guard() {
+            server_builder.with_ssrf_guard(ssrf_guard);
+        }
+        if let Some(countries) = self.destination_countries(geoip.as_ref()) {
+            server_builder.with_destination_countries(countries);
+        }
+        if let Some(max_per_client) = self.max_connections_per_client() {
+            server_builder.with_max_connections_per_client(max_per_client);
+        }
+        if let Some(restart_options) = self.restart_options() {
+            server_builder.with_scheduled_restart(restart_options);
+        }
+        if let Some((bytes_per_sec, window)) = self.min_read_rate() {
+            server_builder.with_min_read_rate(bytes_per_sec, window);
+        }
+        if let Some(bytes_per_sec) = self.tunnel_rate_limit() {
+            server_builder.with_tunnel_rate_limit(bytes_per_sec);
+        }
+        if let Some(max_lifetime) = self.max_tunnel_lifetime() {
+            server_builder.with_max_tunnel_lifetime(max_lifetime);
+        }
+        if !self.egress_ips().is_empty() {
+            let (rotation, period) = self.egress_rotation();
+            server_builder.with_egress_ips(self.egress_ips().to_vec());
+            server_builder.with_egress_rotation(rotation, period);
+        }
+        if let Some(error_page) = self.http_error_page()? {
+            server_builder.with_error_page(error_page);
+        }
+        #[cfg(feature = "compression")]
+        if let Some(compression) = self.http_compression() {
+            server_builder.with_compression(compression);
+        }
+        if let Some(upstream_proxy) = self.upstream_proxy()? {
+            server_builder.with_upstream_proxy(upstream_proxy);
+        }
+        if let Some(routing) = self.routing()? {
+            server_builder.with_routing(routing);
+        }
+
+        Ok(server_builder)
+    }
+}
+
+/// Countries IPs are allowed and denied from, unless none is listed.
+fn country_access(geoip: Option<&Arc<LurkGeoIp>>, allow: &[String], deny: &[String]) -> Option<LurkCountryAccess> {
+    if allow.is_empty() && deny.is_empty() {
+        return None;
+    }
+    let geoip = geoip?;
+    Some(LurkCountryAccess::new(Arc::clone(geoip), allow.to_vec(), deny.to_vec()))
 }
@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use clap::Subcommand;
+use http_body_util::{BodyExt, Empty};
+use hyper::{client::conn::http1, header, Method, Request};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Administrative actions performed through the HTTP endpoint of the running instance.
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum LurkCtlAction {
+    /// Show health status of the node
+    Status,
+    /// Show stats counters and rates
+    Stats,
+    /// List connections which are being served right now
+    Connections,
+    /// List users with their active sessions
+    Users,
+    /// Close the connection with the given identifier
+    Kill {
+        /// Identifier of the connection (see "connections")
+        id: u64,
+    },
+    /// Close all connections of the user, e.g. once their credentials are revoked
+    Kick {
+        /// Name of the user (see "users")
+        name: String,
+    },
+    /// List tenants served along with the node
+    Tenants,
+    /// Show settings which could be changed at runtime
+    Config,
+    /// Offer these SOCKS5 authentication methods to new clients
+    AuthMethods {
+        /// Comma-separated methods ("none", "password") in the order of preference
+        methods: String,
+    },
+    /// Switch the node to the named profile of settings
+    Profile {
+        /// Name of the profile (see "config")
+        name: String,
+    },
+    /// Ask load balancers to stop sending new clients to the node
+    Drain,
+    /// Take the node out of drain mode
+    Undrain,
+}
+
+impl LurkCtlAction {
+    fn request(&self) -> (Method, String) {
+        match self {
+            LurkCtlAction::Status => (Method::GET, "/healthcheck".to_owned()),
+            LurkCtlAction::Stats => (Method::GET, "/stats".to_owned()),
+            LurkCtlAction::Connections => (Method::GET, "/connections".to_owned()),
+            LurkCtlAction::Users => (Method::GET, "/users".to_owned()),
+            LurkCtlAction::Kill { id } => (Method::DELETE, format!("/connections/{}", id)),
+            LurkCtlAction::Kick { name } => (Method::POST, format!("/users/{}/kick", name)),
+            LurkCtlAction::Tenants => (Method::GET, "/tenants".to_owned()),
+            LurkCtlAction::Config => (Method::GET, "/config".to_owned()),
+            LurkCtlAction::AuthMethods { methods } => (Method::PUT, format!("/config?auth_methods={}", methods)),
+            LurkCtlAction::Profile { name } => (Method::PUT, format!("/config?profile={}", name)),
+            LurkCtlAction::Drain => (Method::PUT, "/drain".to_owned()),
+            LurkCtlAction::Undrain => (Method::DELETE, "/drain".to_owned()),
+        }
+    }
+}
+
+/// Perform the action on the HTTP endpoint at ```addr```. Returns pretty-printed response.
+pub async fn execute(action: &LurkCtlAction, addr: SocketAddr, token: Option<&str>) -> Result<String> {
+    let (method, path) = action.request();
+
+    let stream = TcpStream::connect(addr).await?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path.as_str())
+        .header(header::HOST, addr.to_string());
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+
+    let response = sender.send_request(request.body(Empty::<Bytes>::new())?).await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    debug!("{} has responded to '{}' with {}", addr, path, status);
+
+    let body: Value = serde_json::from_slice(&body)?;
+    if !status.is_success() {
+        // Endpoint describes errors as RFC 9457 problems.
+        let reason = body["detail"].as_str().or(body["title"].as_str()).unwrap_or("unknown error");
+        bail!("{} has responded with {}: {}", addr, status, reason);
+    }
+
+    Ok(serde_json::to_string_pretty(&body)?)
+}
@@ -0,0 +1,310 @@
+use log::debug;
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+
+/// Outcome of the single check. Checks are ordered by severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LurkCheckStatus {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl fmt::Display for LurkCheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            LurkCheckStatus::Ok => "OK",
+            LurkCheckStatus::Warning => "WARN",
+            LurkCheckStatus::Failed => "FAIL",
+        };
+        f.pad(status)
+    }
+}
+
+/// Single check of the environment along with the advice, if it hasn't passed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkCheck {
+    pub name: String,
+    pub status: LurkCheckStatus,
+    pub message: String,
+}
+
+impl LurkCheck {
+    fn new(name: impl Into<String>, status: LurkCheckStatus, message: impl Into<String>) -> LurkCheck {
+        LurkCheck {
+            name: name.into(),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// Results of all checks, in the order they've been performed.
+#[derive(Debug, Default)]
+pub struct LurkDoctorReport {
+    pub checks: Vec<LurkCheck>,
+}
+
+impl LurkDoctorReport {
+    /// The most severe status among the checks.
+    pub fn status(&self) -> LurkCheckStatus {
+        self.checks.iter().map(|check| check.status).max().unwrap_or(LurkCheckStatus::Ok)
+    }
+}
+
+impl fmt::Display for LurkDoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, check) in self.checks.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "[{:<4}] {}: {}", check.status, check.name, check.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Outbound connectivity probe. Failure of the required probe fails the report, otherwise it's a warning.
+struct LurkOutboundProbe {
+    name: String,
+    addr: SocketAddr,
+    required: bool,
+}
+
+/// Checks the environment the proxy is going to run in, before it's started: limit of open file descriptors,
+/// availability of the addresses to listen on, DNS resolution and outbound connectivity.
+pub struct LurkDoctor {
+    timeout: Duration,
+    listeners: Vec<(String, SocketAddr)>,
+    max_connections: Option<u64>,
+    dns_names: Vec<String>,
+    probes: Vec<LurkOutboundProbe>,
+}
+
+impl LurkDoctor {
+    /// Descriptors taken by the process regardless of the connections (listeners, logs, files, etc).
+    const RESERVED_FDS: u64 = 64;
+
+    /// Soft limit enough for the moderate load, if the number of connections isn't limited.
+    const RECOMMENDED_FDS: u64 = 4096;
+
+    /// Every check waiting for the network is given ```timeout``` to complete.
+    pub fn new(timeout: Duration) -> LurkDoctor {
+        LurkDoctor {
+            timeout,
+            listeners: Vec::new(),
+            max_connections: None,
+            dns_names: Vec::new(),
+            probes: Vec::new(),
+        }
+    }
+
+    /// Check that the address is free to listen on.
+    pub fn with_listener(mut self, name: impl Into<String>, addr: SocketAddr) -> LurkDoctor {
+        self.listeners.push((name.into(), addr));
+        self
+    }
+
+    /// Check that the descriptors are enough to serve this number of tunnels at once.
+    pub fn with_max_connections(mut self, max_connections: u64) -> LurkDoctor {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Check that the domain name is resolved.
+    pub fn with_dns_name(mut self, name: impl Into<String>) -> LurkDoctor {
+        self.dns_names.push(name.into());
+        self
+    }
+
+    /// Check that the address is reachable from the host.
+    pub fn with_outbound_probe(mut self, name: impl Into<String>, addr: SocketAddr, required: bool) -> LurkDoctor {
+        self.probes.push(LurkOutboundProbe {
+            name: name.into(),
+            addr,
+            required,
+        });
+        self
+    }
+
+    pub async fn run(&self) -> LurkDoctorReport {
+        let mut report = LurkDoctorReport::default();
+
+        report.checks.push(self.check_fd_limit());
+        for (name, addr) in &self.listeners {
+            report.checks.push(check_bind(name, *addr));
+        }
+        for name in &self.dns_names {
+            report.checks.push(self.check_dns(name).await);
+        }
+        for probe in &self.probes {
+            report.checks.push(self.check_outbound(probe).await);
+        }
+
+        report
+    }
+
+    fn check_fd_limit(&self) -> LurkCheck {
+        const NAME: &str = "file descriptors";
+
+        let Some((soft, hard)) = nofile_limit() else {
+            return LurkCheck::new(NAME, LurkCheckStatus::Warning, "limit can't be checked on this platform");
+        };
+        debug!("Limit of open file descriptors: soft {}, hard {}", soft, hard);
+
+        // Every tunnel takes two descriptors: connections with the client and the destination.
+        let (needed, reason) = match self.max_connections {
+            Some(max_connections) => (
+                max_connections.saturating_mul(2).saturating_add(LurkDoctor::RESERVED_FDS),
+                format!("{} connections allowed by the options", max_connections),
+            ),
+            None => (LurkDoctor::RECOMMENDED_FDS, "moderate load".to_owned()),
+        };
+        let advice = if hard >= needed {
+            format!(
+                "raise it up to the hard limit {} with 'ulimit -n' or LimitNOFILE= of the service",
+                hard
+            )
+        } else {
+            format!("hard limit {} has to be raised as well (see gen-service --limit-nofile)", hard)
+        };
+
+        if soft < needed {
+            let message = format!("limit {} is below {} needed for {}: {}", soft, needed, reason, advice);
+            LurkCheck::new(NAME, LurkCheckStatus::Warning, message)
+        } else {
+            LurkCheck::new(NAME, LurkCheckStatus::Ok, format!("limit {} is enough for {}", soft, reason))
+        }
+    }
+
+    async fn check_dns(&self, name: &str) -> LurkCheck {
+        let check_name = format!("DNS resolution of {}", name);
+        let started = Instant::now();
+
+        match timeout(self.timeout, lookup_host((name, 0))).await {
+            Ok(Ok(addrs)) => {
+                let addrs: Vec<SocketAddr> = addrs.collect();
+                let message = format!("resolved to {} address(es) in {:?}", addrs.len(), started.elapsed());
+                LurkCheck::new(check_name, LurkCheckStatus::Ok, message)
+            }
+            Ok(Err(err)) => {
+                let message = format!("{}: check resolvers in /etc/resolv.conf, domain names can't be proxied", err);
+                LurkCheck::new(check_name, LurkCheckStatus::Failed, message)
+            }
+            Err(_) => {
+                let message = format!("no answer in {:?}: domain names will be proxied slowly, if at all", self.timeout);
+                LurkCheck::new(check_name, LurkCheckStatus::Failed, message)
+            }
+        }
+    }
+
+    async fn check_outbound(&self, probe: &LurkOutboundProbe) -> LurkCheck {
+        let check_name = format!("outbound {} ({})", probe.name, probe.addr);
+        let started = Instant::now();
+
+        let err = match timeout(self.timeout, TcpStream::connect(probe.addr)).await {
+            Ok(Ok(_)) => return LurkCheck::new(check_name, LurkCheckStatus::Ok, format!("connected in {:?}", started.elapsed())),
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("not connected in {:?}", self.timeout),
+        };
+
+        if probe.required {
+            let message = format!("{}: check routes and firewall, destinations can't be connected to", err);
+            LurkCheck::new(check_name, LurkCheckStatus::Failed, message)
+        } else {
+            let message = format!("{}: destinations reachable over {} only will be unavailable", err, probe.name);
+            LurkCheck::new(check_name, LurkCheckStatus::Warning, message)
+        }
+    }
+}
+
+fn check_bind(name: &str, addr: SocketAddr) -> LurkCheck {
+    let check_name = format!("{} address {}", name, addr);
+
+    let message = match TcpListener::bind(addr) {
+        Ok(_) => return LurkCheck::new(check_name, LurkCheckStatus::Ok, "available"),
+        Err(err) if err.kind() == ErrorKind::AddrInUse => "already in use: is another instance running?".to_owned(),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            "permission denied: ports below 1024 need CAP_NET_BIND_SERVICE or root".to_owned()
+        }
+        Err(err) if err.kind() == ErrorKind::AddrNotAvailable => "not assigned to any interface of the host".to_owned(),
+        Err(err) => err.to_string(),
+    };
+
+    LurkCheck::new(check_name, LurkCheckStatus::Failed, message)
+}
+
+/// Soft and hard limits of open file descriptors.
+#[cfg(target_os = "linux")]
+fn nofile_limit() -> Option<(u64, u64)> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: limit is a valid rlimit struct, which is only written by the call.
+    let rc = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+
+    (rc == 0).then_some((limit.rlim_cur, limit.rlim_max))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn nofile_limit() -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_environment() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_addr = taken.local_addr().unwrap();
+        let free_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let reachable = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let report = LurkDoctor::new(Duration::from_secs(1))
+            .with_max_connections(16)
+            .with_listener("proxy", free_addr)
+            .with_listener("HTTP endpoint", taken_addr)
+            .with_outbound_probe("IPv4", reachable.local_addr().unwrap(), true)
+            .with_outbound_probe("IPv6", free_addr, false)
+            .run()
+            .await;
+
+        let statuses: Vec<LurkCheckStatus> = report.checks.iter().map(|check| check.status).collect();
+        assert_eq!(
+            vec![
+                LurkCheckStatus::Ok,
+                LurkCheckStatus::Ok,
+                LurkCheckStatus::Failed,
+                LurkCheckStatus::Ok,
+                LurkCheckStatus::Warning
+            ],
+            statuses
+        );
+        assert_eq!(format!("HTTP endpoint address {}", taken_addr), report.checks[2].name);
+        assert!(report.checks[2].message.contains("already in use"));
+        assert_eq!(LurkCheckStatus::Failed, report.status());
+    }
+
+    #[test]
+    fn warn_on_low_fd_limit() {
+        let Some((soft, _)) = nofile_limit() else {
+            return;
+        };
+
+        let doctor = LurkDoctor::new(Duration::from_secs(1)).with_max_connections(soft);
+        let check = doctor.check_fd_limit();
+        assert_eq!(LurkCheckStatus::Warning, check.status);
+        assert!(check.message.starts_with(&format!("limit {} is below", soft)), "{}", check.message);
+    }
+}
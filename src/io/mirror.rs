@@ -0,0 +1,263 @@
+use super::recording;
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::Utc;
+use log::{debug, warn};
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+/// Where copies of the relayed data are sent to.
+///
+/// * ```Tcp``` - "tcp://host:port", every mirrored tunnel opens its own connection
+/// * ```Directory``` - "file:///path/to/dir", every mirrored tunnel is written to its own file
+///
+/// Each chunk of data is framed with the direction (0 - from the client, 1 - from the destination)
+/// and big-endian 32-bit length of the chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LurkMirrorTarget {
+    Tcp(String),
+    Directory(PathBuf),
+}
+
+impl LurkMirrorTarget {
+    pub fn parse(target: &str) -> Result<LurkMirrorTarget> {
+        if let Some(addr) = target.strip_prefix("tcp://") {
+            if addr
+                .rsplit_once(':')
+                .is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err())
+            {
+                bail!("mirror target '{}' should be 'tcp://host:port'", target);
+            }
+            return Ok(LurkMirrorTarget::Tcp(addr.to_owned()));
+        }
+
+        match target.strip_prefix("file://") {
+            Some(path) if path.starts_with('/') => Ok(LurkMirrorTarget::Directory(PathBuf::from(path))),
+            _ => bail!(
+                "mirror target '{}' should be either 'tcp://host:port' or 'file:///path/to/dir'",
+                target
+            ),
+        }
+    }
+}
+
+impl fmt::Display for LurkMirrorTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LurkMirrorTarget::Tcp(addr) => write!(f, "tcp://{}", addr),
+            LurkMirrorTarget::Directory(path) => write!(f, "file://{}", path.display()),
+        }
+    }
+}
+
+/// Direction of the mirrored chunk of data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LurkMirrorDirection {
+    L2R = 0,
+    R2L = 1,
+}
+
+/// Where the writer of the mirror puts the data.
+enum LurkMirrorSink {
+    Tcp(String),
+    File(PathBuf),
+}
+
+impl LurkMirrorSink {
+    async fn open(&self) -> io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+        Ok(match self {
+            LurkMirrorSink::Tcp(addr) => Box::new(TcpStream::connect(addr.as_str()).await?),
+            LurkMirrorSink::File(path) => Box::new(File::create(path).await?),
+        })
+    }
+}
+
+impl fmt::Display for LurkMirrorSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LurkMirrorSink::Tcp(addr) => write!(f, "tcp://{}", addr),
+            LurkMirrorSink::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// How chunks are encoded by the writer of the mirror.
+enum LurkMirrorFormat {
+    /// Direction and length of every chunk.
+    Frames,
+    /// Replayable recording with timestamps of the chunks, see [recording].
+    Recording { started: Instant, header: Bytes },
+}
+
+type LurkMirrorChunk = (LurkMirrorDirection, Instant, Bytes);
+
+/// Sending side of the tunnel mirror. Chunks are passed to the background writer
+/// through the bounded channel: once the sink can't keep up, chunks are dropped,
+/// so the tunnel itself never waits for the mirror.
+pub struct LurkTunnelMirror {
+    sender: Sender<LurkMirrorChunk>,
+    dropped_bytes: Arc<AtomicU64>,
+}
+
+impl LurkTunnelMirror {
+    /// Number of chunks waiting for the writer, after which new ones are dropped.
+    const CAPACITY: usize = 256;
+
+    /// Start mirroring the tunnel between ```peer_addr``` and ```destination``` to the target.
+    pub fn start(target: LurkMirrorTarget, peer_addr: SocketAddr, destination: &str) -> LurkTunnelMirror {
+        let sink = match target {
+            LurkMirrorTarget::Tcp(addr) => LurkMirrorSink::Tcp(addr),
+            LurkMirrorTarget::Directory(dir) => LurkMirrorSink::File(dir.join(file_name(peer_addr, "mirror"))),
+        };
+        let name = format!("Mirror of {} -> {}", peer_addr, destination);
+        Self::spawn(name, sink, LurkMirrorFormat::Frames)
+    }
+
+    /// Start recording the tunnel to ```destination``` into the new file in the directory.
+    /// Recording could be replayed with "lurk replay" later on.
+    pub fn record(dir: &Path, peer_addr: SocketAddr, destination: &str) -> LurkTunnelMirror {
+        let sink = LurkMirrorSink::File(dir.join(file_name(peer_addr, "rec")));
+        let format = LurkMirrorFormat::Recording {
+            started: Instant::now(),
+            header: recording::encode_header(destination),
+        };
+        let name = format!("Recording of {} -> {}", peer_addr, destination);
+        Self::spawn(name, sink, format)
+    }
+
+    fn spawn(name: String, sink: LurkMirrorSink, format: LurkMirrorFormat) -> LurkTunnelMirror {
+        let (sender, receiver) = mpsc::channel(Self::CAPACITY);
+        let dropped_bytes = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_mirror(name, sink, format, receiver, Arc::clone(&dropped_bytes)));
+
+        LurkTunnelMirror { sender, dropped_bytes }
+    }
+
+    /// Copy the chunk into the mirror, unless it's lagging behind.
+    pub fn send(&self, direction: LurkMirrorDirection, chunk: &[u8]) {
+        // Chunk is copied only once there is room for it.
+        match self.sender.try_reserve() {
+            Ok(permit) => permit.send((direction, Instant::now(), Bytes::copy_from_slice(chunk))),
+            Err(_) => {
+                self.dropped_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Name of the file the tunnel of the peer is written to, e.g. "1718000000000-127.0.0.1_5000.mirror".
+fn file_name(peer_addr: SocketAddr, extension: &str) -> String {
+    format!("{}-{}.{}", Utc::now().timestamp_millis(), peer_addr, extension).replace([':', '[', ']'], "_")
+}
+
+async fn write_mirror(
+    name: String,
+    sink: LurkMirrorSink,
+    format: LurkMirrorFormat,
+    mut receiver: Receiver<LurkMirrorChunk>,
+    dropped_bytes: Arc<AtomicU64>,
+) {
+    let result = match sink.open().await {
+        Ok(writer) => write_chunks(BufWriter::new(writer), format, &mut receiver).await,
+        Err(err) => Err(err.into()),
+    };
+    // Mirror is gone, the rest of the tunnel is not mirrored.
+    receiver.close();
+
+    if let Err(err) = result {
+        warn!("{} to {} has failed: {}", name, sink, err);
+    }
+    match dropped_bytes.load(Ordering::Relaxed) {
+        0 => debug!("{} to {} is finished", name, sink),
+        dropped => warn!("{} to {} is finished, {} bytes were not written", name, sink, dropped),
+    }
+}
+
+async fn write_chunks<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    format: LurkMirrorFormat,
+    receiver: &mut Receiver<LurkMirrorChunk>,
+) -> Result<()> {
+    if let LurkMirrorFormat::Recording { header, .. } = &format {
+        writer.write_all(header).await?;
+    }
+
+    while let Some((direction, ts, chunk)) = receiver.recv().await {
+        let frame = match &format {
+            LurkMirrorFormat::Frames => {
+                let mut frame = BytesMut::with_capacity(5 + chunk.len());
+                frame.put_u8(direction as u8);
+                frame.put_u32(chunk.len() as u32);
+                frame.put_slice(&chunk);
+                frame.freeze()
+            }
+            LurkMirrorFormat::Recording { started, .. } => recording::encode_chunk(direction, ts.duration_since(*started), &chunk),
+        };
+        writer.write_all(&frame).await?;
+
+        // Flush once there is nothing more to write right away.
+        if receiver.is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(
+            LurkMirrorTarget::Tcp("10.0.0.5:9000".to_owned()),
+            LurkMirrorTarget::parse("tcp://10.0.0.5:9000").unwrap()
+        );
+        assert_eq!(
+            LurkMirrorTarget::Directory(PathBuf::from("/var/lib/lurk/mirror")),
+            LurkMirrorTarget::parse("file:///var/lib/lurk/mirror").unwrap()
+        );
+
+        for invalid in [
+            "10.0.0.5:9000",
+            "tcp://10.0.0.5",
+            "tcp://:9000",
+            "file://relative",
+            "udp://10.0.0.5:9000",
+        ] {
+            assert!(LurkMirrorTarget::parse(invalid).is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_chunks_of_lagging_mirror() {
+        // Writer doesn't get a chance to run until the test yields, so chunks pile up in the channel.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = LurkMirrorTarget::Tcp(listener.local_addr().unwrap().to_string());
+        let mirror = LurkTunnelMirror::start(target, "127.0.0.1:5000".parse().unwrap(), "example.com:443");
+
+        for _ in 0..LurkTunnelMirror::CAPACITY + 10 {
+            mirror.send(LurkMirrorDirection::L2R, b"data");
+        }
+
+        assert_eq!(40, mirror.dropped_bytes.load(Ordering::Relaxed));
+    }
+}
@@ -1,14 +1,19 @@
 use anyhow::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+pub mod mirror;
+pub mod recording;
 pub mod tunnel;
 
+// Traits are implemented only within the crate, so auto traits of the futures don't matter.
+#[allow(async_fn_in_trait)]
 pub trait LurkRequest {
     async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Self>
     where
         Self: std::marker::Sized;
 }
 
+#[allow(async_fn_in_trait)]
 pub trait LurkResponse {
     async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()>;
 }
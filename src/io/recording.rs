@@ -0,0 +1,133 @@
+use super::mirror::LurkMirrorDirection;
+use anyhow::{bail, ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{io::ErrorKind, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Recording file starts with the magic followed by the length-prefixed destination
+/// the recorded connection was established to. Then it's a sequence of frames:
+///
+/// ```text
+/// +-----------+--------------+--------+---------+
+/// | direction | offset (µs)  | length | payload |
+/// +-----------+--------------+--------+---------+
+/// |     1     |      8       |   4    | length  |
+/// +-----------+--------------+--------+---------+
+/// ```
+///
+/// Offset is the time passed since the start of the recording. All numbers are big-endian.
+const MAGIC: &[u8; 8] = b"LURKREC1";
+
+/// Chunks are recorded as they were relayed, so anything longer than this is a corrupted recording.
+const MAX_CHUNK_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Chunk of data relayed through the recorded tunnel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkRecordedChunk {
+    pub direction: LurkMirrorDirection,
+    pub offset: Duration,
+    pub payload: Bytes,
+}
+
+pub fn encode_header(destination: &str) -> Bytes {
+    let destination = &destination.as_bytes()[..destination.len().min(u16::MAX as usize)];
+    let mut header = BytesMut::with_capacity(MAGIC.len() + 2 + destination.len());
+    header.put_slice(MAGIC);
+    header.put_u16(destination.len() as u16);
+    header.put_slice(destination);
+    header.freeze()
+}
+
+pub fn encode_chunk(direction: LurkMirrorDirection, offset: Duration, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(13 + payload.len());
+    frame.put_u8(direction as u8);
+    frame.put_u64(offset.as_micros() as u64);
+    frame.put_u32(payload.len() as u32);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+/// Read the header of the recording. Returns the recorded destination.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut magic = [0u8; MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    ensure!(&magic == MAGIC, "not a lurk recording");
+
+    let mut destination = vec![0u8; reader.read_u16().await? as usize];
+    reader.read_exact(&mut destination).await?;
+    Ok(String::from_utf8_lossy(&destination).into_owned())
+}
+
+/// Read the next chunk of the recording, ```None``` once it's over.
+pub async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<LurkRecordedChunk>> {
+    let direction = match reader.read_u8().await {
+        Ok(0) => LurkMirrorDirection::L2R,
+        Ok(1) => LurkMirrorDirection::R2L,
+        Ok(direction) => bail!("unknown direction {} of the recorded chunk", direction),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let offset = Duration::from_micros(reader.read_u64().await?);
+    let length = reader.read_u32().await? as usize;
+    ensure!(
+        length <= MAX_CHUNK_LENGTH,
+        "recorded chunk is {} bytes long, at most {} is expected",
+        length,
+        MAX_CHUNK_LENGTH
+    );
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(LurkRecordedChunk {
+        direction,
+        offset,
+        payload: Bytes::from(payload),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_recording() {
+        let mut recording = BytesMut::new();
+        recording.put(encode_header("example.com:443"));
+        recording.put(encode_chunk(LurkMirrorDirection::L2R, Duration::from_millis(1), b"ping"));
+        recording.put(encode_chunk(LurkMirrorDirection::R2L, Duration::from_millis(25), b"pong"));
+
+        let mut reader = recording.as_ref();
+        assert_eq!("example.com:443", read_header(&mut reader).await.unwrap());
+        assert_eq!(
+            Some(LurkRecordedChunk {
+                direction: LurkMirrorDirection::L2R,
+                offset: Duration::from_millis(1),
+                payload: Bytes::from_static(b"ping")
+            }),
+            read_chunk(&mut reader).await.unwrap()
+        );
+        assert_eq!(
+            Some(LurkRecordedChunk {
+                direction: LurkMirrorDirection::R2L,
+                offset: Duration::from_millis(25),
+                payload: Bytes::from_static(b"pong")
+            }),
+            read_chunk(&mut reader).await.unwrap()
+        );
+        assert_eq!(None, read_chunk(&mut reader).await.unwrap());
+
+        assert!(read_header(&mut b"LURKMIRR\x00\x00".as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuse_too_long_chunk() {
+        let mut recording = BytesMut::new();
+        recording.put_u8(LurkMirrorDirection::L2R as u8);
+        recording.put_u64(0);
+        recording.put_u32(u32::MAX);
+
+        let err = read_chunk(&mut recording.as_ref()).await.unwrap_err();
+        assert!(err.to_string().contains("recorded chunk is 4294967295 bytes long"), "{}", err);
+    }
+}
@@ -1,9 +1,56 @@
-use anyhow::Result;
-use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite};
+use super::mirror::{LurkMirrorDirection, LurkTunnelMirror};
+use crate::common::error::LurkError;
+use anyhow::{bail, Result};
+use chrono::Utc;
+use std::{
+    future::{pending, Future},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, sleep_until, Instant, Sleep},
+};
 
+/// Bidirectional relay of the data between two streams, the engine behind SOCKS5 and HTTP CONNECT tunnels.
+///
+/// Streams are borrowed, so they could be shut down or reused once the tunnel is finished. Tunnel runs
+/// until both sides have reached EOF and returns the number of bytes relayed from the "left" side to
+/// the "right" one and back. Relaying could be observed, throttled and bounded in time and throughput:
+///
+/// ```no_run
+/// use lurk::prelude::*;
+/// use std::{sync::Arc, time::Duration};
+/// use tokio::net::TcpStream;
+///
+/// # async fn bridge(mut client: TcpStream, mut upstream: TcpStream) -> anyhow::Result<()> {
+/// let activity = Arc::new(LurkTunnelActivity::new());
+/// let (l2r, r2l) = LurkTunnel::new(&mut client, &mut upstream)
+///     .with_activity(Arc::clone(&activity))
+///     .with_rate_limit(1024 * 1024)
+///     .with_idle_timeout(Duration::from_secs(300))
+///     .with_min_read_rate(512, Duration::from_secs(30))
+///     .with_max_lifetime(Duration::from_secs(3600))
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
 pub struct LurkTunnel<'a, X, Y> {
     l2r: &'a mut X,
     r2l: &'a mut Y,
+    activity: Option<Arc<LurkTunnelActivity>>,
+    mirrors: Vec<LurkTunnelMirror>,
+    rate_limit: Option<u64>,
+    idle_timeout: Option<Duration>,
+    min_read_rate: Option<(u64, Duration)>,
+    max_lifetime: Option<Duration>,
 }
 
 impl<'a, X, Y> LurkTunnel<'a, X, Y>
@@ -12,10 +59,592 @@ where
     Y: AsyncRead + AsyncWrite + Unpin,
 {
     pub fn new(l2r: &'a mut X, r2l: &'a mut Y) -> LurkTunnel<'a, X, Y> {
-        LurkTunnel { l2r, r2l }
+        LurkTunnel {
+            l2r,
+            r2l,
+            activity: None,
+            mirrors: Vec::new(),
+            rate_limit: None,
+            idle_timeout: None,
+            min_read_rate: None,
+            max_lifetime: None,
+        }
+    }
+
+    /// Report relayed bytes to the passed activity tracker while tunnel is running.
+    pub fn with_activity(mut self, activity: Arc<LurkTunnelActivity>) -> LurkTunnel<'a, X, Y> {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Tee a copy of the relayed bytes to the mirror while tunnel is running.
+    /// Tunnel could have several mirrors, e.g. IDS feed and debug recording.
+    pub fn with_mirror(mut self, mirror: LurkTunnelMirror) -> LurkTunnel<'a, X, Y> {
+        self.mirrors.push(mirror);
+        self
+    }
+
+    /// Relay at most ```bytes_per_sec``` in each direction. Bursts of up to a second worth of data are allowed.
+    pub fn with_rate_limit(mut self, bytes_per_sec: u64) -> LurkTunnel<'a, X, Y> {
+        assert!(bytes_per_sec > 0, "rate limit should be positive");
+        self.rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Fail once no data has been relayed in either direction for the passed period.
+    /// Period is counted from the last activity reported to the tracker.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> LurkTunnel<'a, X, Y> {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Fail once the "left" side has read less than ```bytes_per_sec``` within the ```window```, while
+    /// the data from the "right" side was waiting to be written to it (slow-read clients holding tunnels open).
+    /// Tunnels which just have nothing to relay aren't affected.
+    pub fn with_min_read_rate(mut self, bytes_per_sec: u64, window: Duration) -> LurkTunnel<'a, X, Y> {
+        assert!(!window.is_zero(), "window should be positive");
+        self.min_read_rate = Some((bytes_per_sec, window));
+        self
+    }
+
+    /// Fail once the tunnel has been running for the passed period, whether it's relaying data or not.
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> LurkTunnel<'a, X, Y> {
+        self.max_lifetime = Some(max_lifetime);
+        self
     }
 
     pub async fn run(&mut self) -> Result<(u64, u64)> {
-        copy_bidirectional(self.l2r, self.r2l).await.map_err(anyhow::Error::from)
+        if self.activity.is_none()
+            && self.mirrors.is_empty()
+            && self.rate_limit.is_none()
+            && self.idle_timeout.is_none()
+            && self.min_read_rate.is_none()
+            && self.max_lifetime.is_none()
+        {
+            return copy_bidirectional(self.l2r, self.r2l).await.map_err(anyhow::Error::from);
+        }
+
+        // Idle periods are tracked by the activity, so tunnel has one even if it's not observed outside.
+        let activity = match (&self.activity, self.idle_timeout) {
+            (None, Some(_)) => Some(Arc::new(LurkTunnelActivity::new())),
+            (activity, _) => activity.clone(),
+        };
+
+        let read_meter = self.min_read_rate.map(|_| ReadMeter::default());
+
+        let (activity, mirrors) = (activity.as_deref(), self.mirrors.as_slice());
+        let mut l2r = ObservedStream::new(self.l2r, activity, mirrors, Direction::L2R, self.rate_limit.map(Throttle::new))
+            .with_read_meter(read_meter.as_ref());
+        let mut r2l = ObservedStream::new(self.r2l, activity, mirrors, Direction::R2L, self.rate_limit.map(Throttle::new));
+        let relay = copy_bidirectional(&mut l2r, &mut r2l);
+
+        if self.idle_timeout.is_none() && self.min_read_rate.is_none() && self.max_lifetime.is_none() {
+            return relay.await.map_err(anyhow::Error::from);
+        }
+
+        let idle = async {
+            match (self.idle_timeout, activity) {
+                (Some(idle_timeout), Some(activity)) => idle(activity, idle_timeout).await,
+                _ => pending().await,
+            }
+        };
+        let slow_read = async {
+            match (self.min_read_rate, &read_meter) {
+                (Some((bytes_per_sec, window)), Some(read_meter)) => read_meter.slow_read(bytes_per_sec, window).await,
+                _ => pending().await,
+            }
+        };
+        let expired = async {
+            match self.max_lifetime {
+                Some(max_lifetime) => sleep(max_lifetime).await,
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            res = relay => res.map_err(anyhow::Error::from),
+            _ = idle => bail!(LurkError::TunnelIdleTimeout(self.idle_timeout.unwrap_or_default())),
+            rate = slow_read => bail!(LurkError::TunnelSlowRead(rate)),
+            _ = expired => bail!(LurkError::TunnelLifetimeExceeded(self.max_lifetime.unwrap_or_default())),
+        }
+    }
+}
+
+/// Resolves once there has been no activity for the passed period.
+async fn idle(activity: &LurkTunnelActivity, idle_timeout: Duration) {
+    loop {
+        let idle_millis = Utc::now().timestamp_millis() - activity.last_activity_ts_millis();
+        let idle_for = Duration::from_millis(idle_millis.max(0) as u64);
+        if idle_for >= idle_timeout {
+            return;
+        }
+        sleep(idle_timeout - idle_for).await;
+    }
+}
+
+/// Byte counters and timestamp of the last data movement in the tunnel.
+///
+/// Shared between the running tunnel and anyone who wants to observe it
+/// (e.g. watchdog looking for stuck connections).
+pub struct LurkTunnelActivity {
+    l2r_bytes: AtomicU64,
+    r2l_bytes: AtomicU64,
+    last_activity_ts_millis: AtomicI64,
+}
+
+impl LurkTunnelActivity {
+    pub fn new() -> LurkTunnelActivity {
+        LurkTunnelActivity {
+            l2r_bytes: AtomicU64::new(0),
+            r2l_bytes: AtomicU64::new(0),
+            last_activity_ts_millis: AtomicI64::new(Utc::now().timestamp_millis()),
+        }
+    }
+
+    /// Number of bytes read from the "left" side of the tunnel.
+    pub fn l2r_bytes(&self) -> u64 {
+        self.l2r_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes read from the "right" side of the tunnel.
+    pub fn r2l_bytes(&self) -> u64 {
+        self.r2l_bytes.load(Ordering::Relaxed)
+    }
+
+    /// UTC timestamp (in millis) of the last data movement.
+    /// Initially, it's equal to the creation time of this tracker.
+    pub fn last_activity_ts_millis(&self) -> i64 {
+        self.last_activity_ts_millis.load(Ordering::Relaxed)
+    }
+
+    /// Record bytes from the "left" side relayed outside of the tunnel (e.g. body of the forwarded HTTP request).
+    pub fn on_l2r_relayed(&self, n: usize) {
+        self.on_bytes_read(Direction::L2R, n);
+    }
+
+    /// Record bytes from the "right" side relayed outside of the tunnel (e.g. body of the forwarded HTTP response).
+    pub fn on_r2l_relayed(&self, n: usize) {
+        self.on_bytes_read(Direction::R2L, n);
+    }
+
+    fn on_bytes_read(&self, direction: Direction, n: usize) {
+        let counter = match direction {
+            Direction::L2R => &self.l2r_bytes,
+            Direction::R2L => &self.r2l_bytes,
+        };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        self.last_activity_ts_millis.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+}
+
+impl Default for LurkTunnelActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tracks how fast the "left" side reads the data written to it by the tunnel.
+#[derive(Default)]
+struct ReadMeter {
+    written: AtomicU64,
+    write_pending: AtomicBool,
+    stalled: AtomicBool,
+}
+
+impl ReadMeter {
+    fn on_poll_write(&self, poll: &Poll<io::Result<usize>>) {
+        match poll {
+            Poll::Pending => {
+                self.write_pending.store(true, Ordering::Relaxed);
+                self.stalled.store(true, Ordering::Relaxed);
+            }
+            Poll::Ready(res) => {
+                self.write_pending.store(false, Ordering::Relaxed);
+                if let Ok(n) = res {
+                    self.written.fetch_add(*n as u64, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Resolves with the read rate (bytes/sec) of the first window, in which the side has been stalling
+    /// the tunnel and has read less than passed minimum.
+    async fn slow_read(&self, min_bytes_per_sec: u64, window: Duration) -> u64 {
+        loop {
+            sleep(window).await;
+            let written = self.written.swap(0, Ordering::Relaxed);
+            // Write pending since the previous window hasn't been polled again, but it's still a stall.
+            let stalled = self.stalled.swap(false, Ordering::Relaxed) || self.write_pending.load(Ordering::Relaxed);
+            let rate = u64::try_from(u128::from(written) * 1000 / window.as_millis().max(1)).unwrap_or(u64::MAX);
+            if stalled && rate < min_bytes_per_sec {
+                return rate;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    L2R,
+    R2L,
+}
+
+impl From<Direction> for LurkMirrorDirection {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::L2R => LurkMirrorDirection::L2R,
+            Direction::R2L => LurkMirrorDirection::R2L,
+        }
+    }
+}
+
+/// Token bucket limiting the read rate of the stream. Bucket holds up to a second worth of bytes.
+struct Throttle {
+    bytes_per_sec: u64,
+    available: u64,
+    refilled: Instant,
+    delay: Pin<Box<Sleep>>,
+}
+
+impl Throttle {
+    /// Depleted bucket is refilled at least with this share of the rate, not to wake up on every byte.
+    const MIN_REFILL_DIVISOR: u64 = 20;
+
+    fn new(bytes_per_sec: u64) -> Throttle {
+        let now = Instant::now();
+        Throttle {
+            bytes_per_sec,
+            available: bytes_per_sec,
+            refilled: now,
+            delay: Box::pin(sleep_until(now)),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = (now - self.refilled).as_nanos() * u128::from(self.bytes_per_sec) / 1_000_000_000;
+        let earned = u64::try_from(earned).unwrap_or(u64::MAX);
+        if self.available.saturating_add(earned) >= self.bytes_per_sec {
+            self.available = self.bytes_per_sec;
+            self.refilled = now;
+        } else if earned > 0 {
+            // Fraction of the byte, which is not earned yet, is kept for the next refill.
+            self.available += earned;
+            self.refilled += self.time_to_earn(earned);
+        }
+    }
+
+    fn time_to_earn(&self, bytes: u64) -> Duration {
+        let nanos = u128::from(bytes) * 1_000_000_000 / u128::from(self.bytes_per_sec);
+        Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+    }
+
+    /// Number of bytes which could be read now. Pending until the bucket is refilled, if it's empty.
+    fn poll_available(&mut self, cx: &mut Context<'_>) -> Poll<u64> {
+        loop {
+            self.refill();
+            if self.available > 0 {
+                return Poll::Ready(self.available);
+            }
+            let deadline = self.refilled + self.time_to_earn((self.bytes_per_sec / Throttle::MIN_REFILL_DIVISOR).max(1));
+            self.delay.as_mut().reset(deadline);
+            ready!(self.delay.as_mut().poll(cx));
+        }
+    }
+}
+
+/// Stream wrapper reporting every successful read to the activity tracker and the mirrors,
+/// optionally throttling the reads.
+struct ObservedStream<'a, S> {
+    inner: &'a mut S,
+    activity: Option<&'a LurkTunnelActivity>,
+    mirrors: &'a [LurkTunnelMirror],
+    direction: Direction,
+    throttle: Option<Throttle>,
+    read_meter: Option<&'a ReadMeter>,
+}
+
+impl<'a, S> ObservedStream<'a, S> {
+    fn new(
+        inner: &'a mut S,
+        activity: Option<&'a LurkTunnelActivity>,
+        mirrors: &'a [LurkTunnelMirror],
+        direction: Direction,
+        throttle: Option<Throttle>,
+    ) -> ObservedStream<'a, S> {
+        ObservedStream {
+            inner,
+            activity,
+            mirrors,
+            direction,
+            throttle,
+            read_meter: None,
+        }
+    }
+
+    /// Account data written to the stream by the meter.
+    fn with_read_meter(mut self, read_meter: Option<&'a ReadMeter>) -> ObservedStream<'a, S> {
+        self.read_meter = read_meter;
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> ObservedStream<'_, S> {
+    fn poll_read_throttled(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let Some(throttle) = self.throttle.as_mut() else {
+            return Pin::new(&mut *self.inner).poll_read(cx, buf);
+        };
+
+        let available = ready!(throttle.poll_available(cx));
+        let mut limited = buf.take(available.min(buf.remaining() as u64) as usize);
+        let limited_ptr = limited.filled().as_ptr();
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, &mut limited);
+        assert_eq!(limited_ptr, limited.filled().as_ptr(), "read buffer was swapped");
+
+        let n = limited.filled().len();
+        // SAFETY: bytes were initialized by the read into the limited part of the buffer.
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        throttle.available -= n as u64;
+
+        poll
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObservedStream<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let poll = self.poll_read_throttled(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[filled_before..];
+            if !read.is_empty() {
+                if let Some(activity) = self.activity {
+                    activity.on_bytes_read(self.direction, read.len());
+                }
+                for mirror in self.mirrors {
+                    mirror.send(self.direction.into(), read);
+                }
+            }
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObservedStream<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Some(read_meter) = self.read_meter {
+            read_meter.on_poll_write(&poll);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::mirror::LurkMirrorTarget;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn tunnel_reports_activity() {
+        let (mut client, mut l2r) = duplex(64);
+        let (mut r2l, mut endpoint) = duplex(64);
+        let activity = Arc::new(LurkTunnelActivity::new());
+
+        let tunnel_activity = Arc::clone(&activity);
+        let tunnel_handle = tokio::spawn(async move {
+            LurkTunnel::new(&mut l2r, &mut r2l)
+                .with_activity(tunnel_activity)
+                .run()
+                .await
+                .expect("Tunnel should finish successfully")
+        });
+
+        client.write_all(&[1, 2, 3]).await.unwrap();
+        let mut buf = [0u8; 3];
+        endpoint.read_exact(&mut buf).await.unwrap();
+        endpoint.write_all(&[4, 5]).await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+
+        drop(client);
+        drop(endpoint);
+
+        assert_eq!((3, 2), tunnel_handle.await.unwrap());
+        assert_eq!(3, activity.l2r_bytes());
+        assert_eq!(2, activity.r2l_bytes());
+    }
+
+    #[tokio::test]
+    async fn tunnel_mirrors_data() {
+        let (mut client, mut l2r) = duplex(64);
+        let (mut r2l, mut endpoint) = duplex(64);
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mirror = LurkTunnelMirror::start(
+            LurkMirrorTarget::Tcp(sink.local_addr().unwrap().to_string()),
+            "127.0.0.1:5000".parse().unwrap(),
+            "example.com:443",
+        );
+
+        let tunnel_handle = tokio::spawn(async move { LurkTunnel::new(&mut l2r, &mut r2l).with_mirror(mirror).run().await });
+
+        client.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        endpoint.read_exact(&mut buf).await.unwrap();
+        endpoint.write_all(b"yo").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+
+        drop(client);
+        drop(endpoint);
+        assert_eq!((2, 2), tunnel_handle.await.unwrap().unwrap());
+
+        let (mut sink, _) = sink.accept().await.unwrap();
+        let mut mirrored = Vec::new();
+        sink.read_to_end(&mut mirrored).await.unwrap();
+        assert_eq!(b"\x00\x00\x00\x00\x02hi\x01\x00\x00\x00\x02yo".as_slice(), mirrored);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tunnel_limits_rate() {
+        let (mut client, mut l2r) = duplex(1024);
+        let (mut r2l, mut endpoint) = duplex(1024);
+
+        let tunnel_handle = tokio::spawn(async move { LurkTunnel::new(&mut l2r, &mut r2l).with_rate_limit(100).run().await });
+
+        // Second worth of data is relayed at once, the rest is throttled.
+        let started = Instant::now();
+        client.write_all(&[7; 250]).await.unwrap();
+        let mut buf = [0u8; 250];
+        endpoint.read_exact(&mut buf).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(1500), "elapsed {elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1600), "elapsed {elapsed:?}");
+
+        drop(client);
+        drop(endpoint);
+        assert_eq!((250, 0), tunnel_handle.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn tunnel_closed_once_idle() {
+        let (mut client, mut l2r) = duplex(64);
+        let (mut r2l, mut endpoint) = duplex(64);
+        let idle_timeout = Duration::from_millis(200);
+
+        let tunnel_handle = tokio::spawn(async move { LurkTunnel::new(&mut l2r, &mut r2l).with_idle_timeout(idle_timeout).run().await });
+
+        // Activity postpones the timeout.
+        for _ in 0..3 {
+            sleep(idle_timeout / 2).await;
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            endpoint.read_exact(&mut buf).await.unwrap();
+        }
+        assert!(!tunnel_handle.is_finished());
+
+        let err = tunnel_handle.await.unwrap().expect_err("tunnel should be closed once idle");
+        assert_eq!(Some(&LurkError::TunnelIdleTimeout(idle_timeout)), err.downcast_ref::<LurkError>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tunnel_closed_once_lifetime_exceeded() {
+        let (mut client, mut l2r) = duplex(64);
+        let (mut r2l, mut endpoint) = duplex(64);
+        let max_lifetime = Duration::from_secs(60);
+
+        let tunnel_handle = tokio::spawn(async move { LurkTunnel::new(&mut l2r, &mut r2l).with_max_lifetime(max_lifetime).run().await });
+
+        // Activity doesn't postpone the deadline.
+        let started = Instant::now();
+        let relay = tokio::spawn(async move {
+            let mut buf = [0u8; 4];
+            loop {
+                client.write_all(b"ping").await?;
+                endpoint.read_exact(&mut buf).await?;
+                sleep(Duration::from_secs(1)).await;
+            }
+            #[allow(unreachable_code)]
+            io::Result::Ok(())
+        });
+
+        let err = tunnel_handle
+            .await
+            .unwrap()
+            .expect_err("tunnel should be closed once lifetime is exceeded");
+        assert_eq!(max_lifetime, started.elapsed());
+        assert_eq!(
+            Some(&LurkError::TunnelLifetimeExceeded(max_lifetime)),
+            err.downcast_ref::<LurkError>()
+        );
+        relay.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tunnel_closed_on_slow_read() {
+        let (mut client, mut l2r) = duplex(64);
+        let (mut r2l, mut endpoint) = duplex(64);
+        let window = Duration::from_secs(10);
+
+        let tunnel_handle = tokio::spawn(async move { LurkTunnel::new(&mut l2r, &mut r2l).with_min_read_rate(100, window).run().await });
+
+        // Endpoint keeps sending, while client reads 10 bytes per second.
+        let send = tokio::spawn(async move { while endpoint.write_all(&[7; 64]).await.is_ok() {} });
+        let read = tokio::spawn(async move {
+            let mut buf = [0u8; 10];
+            while client.read_exact(&mut buf).await.is_ok() {
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        let started = Instant::now();
+        let err = tunnel_handle.await.unwrap().expect_err("tunnel should be closed");
+        assert_eq!(window, started.elapsed());
+        let Some(LurkError::TunnelSlowRead(rate)) = err.downcast_ref::<LurkError>() else {
+            panic!("unexpected error {err}");
+        };
+        assert!(*rate < 100, "rate {rate}");
+        send.abort();
+        read.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tunnel_kept_while_read_rate_is_met() {
+        let (mut client, mut l2r) = duplex(64);
+        let (mut r2l, mut endpoint) = duplex(64);
+        let window = Duration::from_secs(10);
+
+        let tunnel_handle = tokio::spawn(async move { LurkTunnel::new(&mut l2r, &mut r2l).with_min_read_rate(100, window).run().await });
+
+        // Client reads 200 bytes per second of the stalled data, then tunnel is idle: neither is closed.
+        let send = tokio::spawn(async move {
+            endpoint.write_all(&[7; 4000]).await.unwrap();
+            endpoint
+        });
+        let mut buf = [0u8; 20];
+        for _ in 0..200 {
+            client.read_exact(&mut buf).await.unwrap();
+            sleep(Duration::from_millis(100)).await;
+        }
+        let endpoint = send.await.unwrap();
+        sleep(3 * window).await;
+        assert!(!tunnel_handle.is_finished());
+
+        drop(client);
+        drop(endpoint);
+        assert_eq!((0, 4000), tunnel_handle.await.unwrap().unwrap());
     }
 }
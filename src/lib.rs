@@ -1,9 +1,63 @@
 pub mod api;
+pub mod auth;
+pub mod client;
 pub mod config;
+pub mod ctl;
+pub mod doctor;
+pub mod ping;
+pub mod replay;
 pub mod server;
+pub mod service;
 
-mod auth;
 mod common;
 mod io;
 mod net;
 mod proto;
+
+/// Stable API for the tools built on top of the proxy: SOCKS5 codecs, relaying engine and extension traits.
+///
+/// Requests are read by the server and written by the client, responses are the other way
+/// around, so both sides of the protocol could be implemented with these types:
+///
+/// ```no_run
+/// use lurk::prelude::*;
+/// use std::collections::HashSet;
+///
+/// # async fn connect(stream: &mut tokio::net::TcpStream) -> anyhow::Result<()> {
+/// HandshakeRequest::new(HashSet::from([LurkAuthMethod::None])).write_to(stream).await?;
+/// assert_eq!(Some(LurkAuthMethod::None), HandshakeResponse::read_from(stream).await?.auth_method());
+///
+/// let endpoint = Address::DomainName("example.com".to_owned(), 443);
+/// RelayRequest::new(Command::TCPConnect, endpoint).write_to(stream).await?;
+/// assert_eq!(ReplyStatus::Succeeded, RelayResponse::read_from(stream).await?.status());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Items are kept compatible within the major version, unlike the rest of the modules.
+pub mod prelude {
+    pub use crate::{
+        auth::{private::LurkPrivateAuthMethod, LurkAuthMethod},
+        io::{
+            tunnel::{LurkTunnel, LurkTunnelActivity},
+            LurkRequest, LurkResponse,
+        },
+        net::Address,
+        proto::socks5::{
+            request::{HandshakeRequest, PasswordAuthRequest, RelayRequest},
+            response::{
+                HandshakeResponse, HandshakeResponseBuilder, PasswordAuthResponse, PasswordAuthResponseBuilder, RelayResponse,
+                RelayResponseBuilder,
+            },
+            Command, ReplyStatus,
+        },
+        server::stats::sink::{LurkStatsEvent, LurkStatsSink},
+    };
+}
+
+/// Internals re-exported for the benchmarks. Not a part of the public API.
+#[doc(hidden)]
+pub mod internals {
+    #[cfg(feature = "http")]
+    pub use crate::server::handlers::http::utils::get_host_addr;
+}
@@ -1,30 +1,74 @@
-use std::sync::Arc;
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
 use clap::Parser;
 use log::error;
 use log4rs::config::Deserializers;
 use lurk::{
-    api::LurkHttpEndpoint,
-    config::{self, LurkConfig},
-    server::LurkServer,
+    api::{pushgateway::LurkMetricsPusher, tcp_check::LurkTcpCheckResponder, LurkHttpEndpoint},
+    client::{self, LurkClientAction},
+    config::{self, LurkCommand, LurkConfig},
+    ctl,
+    doctor::LurkCheckStatus,
+    ping::{self, LurkPingKind},
+    replay,
+    service::LurkServiceSpec,
 };
+use std::{sync::Arc, time::Duration};
+
+// Allocator-heavy workloads (lots of short-lived connections) benefit from the
+// alternative allocators, especially on musl targets with its slow malloc.
+cfg_if::cfg_if! {
+    if #[cfg(feature = "jemalloc")] {
+        #[global_allocator]
+        static GLOBAL_ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+    } else if #[cfg(feature = "mimalloc")] {
+        #[global_allocator]
+        static GLOBAL_ALLOCATOR: mimalloc::MiMalloc = mimalloc::MiMalloc;
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    log4rs::init_file(config::LOG4RS_CONFIG_FILE_PATH, Deserializers::default()).unwrap();
-
     // Parse config
     let lurk_config = LurkConfig::parse();
 
+    // Auxiliary commands don't need logging
+    if let Some(command) = lurk_config.command() {
+        return run_command(&lurk_config, command).await;
+    }
+
+    // Initialize logging
+    log4rs::init_file(config::LOG4RS_CONFIG_FILE_PATH, Deserializers::default()).unwrap();
+
     // Create proxy server instance. It will handle incoming connection in async. fashion.
-    let server = Arc::new(LurkServer::new(lurk_config.server_tcp_bind_addr()));
+    let server = Arc::new(lurk_config.server_builder()?.build());
+    if let Some(profiles) = server.get_profiles() {
+        server.switch_profile(&profiles.active())?;
+    }
+
+    // Tenants are served by their own instances, so their users, policies and stats are isolated.
+    let mut tenants = Vec::new();
+    for tenant in lurk_config.tenants()? {
+        let mut tenant_builder = lurk_config.shared_server_builder(lurk_config.tenant_bind_addr(&tenant))?;
+        if let Some(users) = lurk_config.tenant_user_store(&tenant)? {
+            tenant_builder.with_users(Arc::new(users));
+        }
+        if let Some(policy) = lurk_config.tenant_policy(&tenant)? {
+            tenant_builder.with_policy(policy);
+        }
+        tenants.push((tenant.name, Arc::new(tenant_builder.build())));
+    }
 
     // Spin up HTTP endpoint if enabled
     if let Some(http_endpoint_bind_addr) = lurk_config.http_endpoint_bind_addr() {
         // Create endpoint and pass atomic reference to created server instance. Endpoint will
         // communicate to server through provided interface (e.g. ask some metrics).
-        let http_endpoint = LurkHttpEndpoint::new(http_endpoint_bind_addr, Arc::clone(&server));
+        let mut http_endpoint = LurkHttpEndpoint::new(http_endpoint_bind_addr, Arc::clone(&server))
+            .with_listener_options(lurk_config.http_endpoint_listener_options())
+            .with_tenants(tenants.clone())
+            .with_pac_direct_domains(lurk_config.pac_direct_domains().to_vec());
+        if let Some(token) = lurk_config.http_endpoint_token() {
+            http_endpoint = http_endpoint.with_token(token);
+        }
         tokio::spawn(async move {
             if let Err(err) = http_endpoint.run().await {
                 error!("Error occured while HTTP endpoint was running: {}", err);
@@ -32,8 +76,117 @@ async fn main() -> Result<()> {
         });
     }
 
-    // Bind and serve clients "forever"
+    // Spin up TCP check responder for load balancers if enabled
+    if let Some(tcp_check_bind_addr) = lurk_config.tcp_check_bind_addr() {
+        let tcp_check_responder = LurkTcpCheckResponder::new(tcp_check_bind_addr, Arc::clone(&server));
+        tokio::spawn(async move {
+            if let Err(err) = tcp_check_responder.run().await {
+                error!("Error occured while TCP check responder was running: {}", err);
+            }
+        });
+    }
+
+    // Push metrics to Pushgateway if enabled
+    if let Some(pushgateway_options) = lurk_config.pushgateway_options() {
+        let metrics_pusher = LurkMetricsPusher::new(pushgateway_options, Arc::clone(&server));
+        tokio::spawn(async move { metrics_pusher.run().await });
+    }
+
+    let tenant_tasks: Vec<_> = tenants
+        .into_iter()
+        .map(|(name, tenant)| {
+            tokio::spawn(async move {
+                if let Err(err) = tenant.run().await {
+                    error!("Error occured while tenant '{}' was served: {}", name, err);
+                }
+            })
+        })
+        .collect();
+
+    // Bind and serve clients "forever", or until the scheduled restart
     server.run().await?;
 
+    // Tenants are stopped by the same signal (or restart schedule) as the main listener.
+    for task in tenant_tasks {
+        let _ = task.await;
+    }
+
+    if let Some(exit_code) = server.get_restart_exit_code() {
+        std::process::exit(exit_code);
+    }
+
+    Ok(())
+}
+
+/// Execute auxiliary command instead of running the proxy.
+async fn run_command(lurk_config: &LurkConfig, command: &LurkCommand) -> Result<()> {
+    match command {
+        LurkCommand::GenService { kind, user, limit_nofile } => {
+            let spec = LurkServiceSpec::from_current_process(LurkCommand::GEN_SERVICE, user.clone(), *limit_nofile)?;
+            print!("{}", spec.render(*kind));
+        }
+        LurkCommand::Ping { kind, addr, timeout_secs } => {
+            let addr = addr.unwrap_or_else(|| lurk_config.ping_addr(*kind));
+            ping::ping(*kind, addr, Duration::from_secs(*timeout_secs))
+                .await
+                .with_context(|| format!("{} is unhealthy", addr))?;
+            println!("{} is healthy", addr);
+        }
+        LurkCommand::Ctl { addr, token, action } => {
+            let addr = addr.unwrap_or_else(|| lurk_config.ping_addr(LurkPingKind::Healthcheck));
+            let token = token.as_deref().or(lurk_config.http_endpoint_token());
+            println!("{}", ctl::execute(action, addr, token).await?);
+        }
+        LurkCommand::Client {
+            action:
+                LurkClientAction::Connect {
+                    target,
+                    proxy,
+                    protocol,
+                    user,
+                    password,
+                    timeout_secs,
+                },
+        } => {
+            let proxy = proxy
+                .clone()
+                .unwrap_or_else(|| lurk_config.ping_addr(LurkPingKind::Socks5).to_string());
+            let credentials = user.as_deref().zip(password.as_deref());
+            let report = client::connect(*protocol, &proxy, target, credentials, Duration::from_secs(*timeout_secs))
+                .await
+                .with_context(|| format!("Unable to connect to {} through {}", target, proxy))?;
+            println!("{}", report);
+            ensure!(report.reply.is_success(), "{} has refused to connect to {}", proxy, target);
+        }
+        LurkCommand::Doctor {
+            dns_name,
+            ipv4_probe,
+            ipv6_probe,
+            timeout_secs,
+        } => {
+            let report = lurk_config
+                .doctor(Duration::from_secs(*timeout_secs))?
+                .with_dns_name(dns_name)
+                .with_outbound_probe("IPv4", *ipv4_probe, true)
+                .with_outbound_probe("IPv6", *ipv6_probe, false)
+                .run()
+                .await;
+            println!("{}", report);
+            ensure!(
+                report.status() != LurkCheckStatus::Failed,
+                "environment isn't ready to run the proxy"
+            );
+        }
+        LurkCommand::Replay {
+            file,
+            target,
+            no_delay,
+            wait_secs,
+        } => {
+            let summary = replay::replay(file, target.as_deref(), *no_delay, Duration::from_secs(*wait_secs)).await?;
+            println!("{}", summary);
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,203 @@
+use crate::common::error::LurkError;
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::info;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard, RwLock, RwLockWriteGuard},
+    time::Duration,
+};
+
+/// Change of the bans made on the node, which hasn't been shared with the cluster yet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LurkBanChange {
+    Ban(IpAddr, DateTime<Utc>),
+    Unban(IpAddr),
+}
+
+/// Clients banned for a while, e.g. through the HTTP endpoint. Connections of the banned clients
+/// are dropped right after they are accepted. Nodes of the cluster share their bans, so the banned
+/// client can't just connect to another node behind the same load balancer.
+pub struct LurkClientBans {
+    bans: RwLock<HashMap<IpAddr, DateTime<Utc>>>,
+    unsynced: Mutex<Vec<LurkBanChange>>,
+}
+
+impl LurkClientBans {
+    pub fn new() -> LurkClientBans {
+        LurkClientBans {
+            bans: RwLock::new(HashMap::new()),
+            unsynced: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Ban the client for the passed period. Returns the moment the ban expires.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) -> DateTime<Utc> {
+        let ip = ip.to_canonical();
+        let expires_at = TimeDelta::from_std(duration)
+            .ok()
+            .and_then(|duration| Utc::now().checked_add_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.bans_mut().insert(ip, expires_at);
+        self.unsynced_changes().push(LurkBanChange::Ban(ip, expires_at));
+        info!("Client {} is banned until {}", ip, expires_at);
+        expires_at
+    }
+
+    /// Lift the ban of the client. Returns false if it wasn't banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let unbanned = self.bans_mut().remove(&ip).is_some_and(|expires_at| expires_at > Utc::now());
+        self.unsynced_changes().push(LurkBanChange::Unban(ip));
+        if unbanned {
+            info!("Client {} is unbanned", ip);
+        }
+        unbanned
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let bans = self.bans.read().expect("Bans lock is poisoned");
+        bans.get(&ip.to_canonical()).is_some_and(|expires_at| *expires_at > Utc::now())
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<()> {
+        if self.is_banned(ip) {
+            bail!(LurkError::ClientBanned(ip.to_canonical()))
+        }
+        Ok(())
+    }
+
+    /// Banned clients along with the moments their bans expire, in ascending order of the clients.
+    pub fn list(&self) -> Vec<(IpAddr, DateTime<Utc>)> {
+        let now = Utc::now();
+        let bans = self.bans.read().expect("Bans lock is poisoned");
+        let mut list: Vec<(IpAddr, DateTime<Utc>)> = bans
+            .iter()
+            .filter(|(_, expires_at)| **expires_at > now)
+            .map(|(ip, expires_at)| (*ip, *expires_at))
+            .collect();
+        list.sort();
+        list
+    }
+
+    /// Take changes of the bans made since the last exchange with the cluster.
+    pub fn take_unsynced_changes(&self) -> Vec<LurkBanChange> {
+        std::mem::take(&mut *self.unsynced_changes())
+    }
+
+    /// Put back the changes, which haven't been shared with the cluster, ahead of the ones made meanwhile.
+    pub fn restore_unsynced_changes(&self, mut changes: Vec<LurkBanChange>) {
+        let mut unsynced = self.unsynced_changes();
+        changes.append(&mut unsynced);
+        *unsynced = changes;
+    }
+
+    /// Replace the bans with the ones of the cluster. Changes made on the node meanwhile are kept on top of them.
+    pub fn apply_cluster_bans(&self, cluster_bans: HashMap<IpAddr, DateTime<Utc>>) {
+        let unsynced = self.unsynced_changes();
+        let mut bans = self.bans_mut();
+        *bans = cluster_bans;
+        for change in unsynced.iter() {
+            match change {
+                LurkBanChange::Ban(ip, expires_at) => bans.insert(*ip, *expires_at),
+                LurkBanChange::Unban(ip) => bans.remove(ip),
+            };
+        }
+    }
+
+    fn bans_mut(&self) -> RwLockWriteGuard<'_, HashMap<IpAddr, DateTime<Utc>>> {
+        self.bans.write().expect("Bans lock is poisoned")
+    }
+
+    fn unsynced_changes(&self) -> MutexGuard<'_, Vec<LurkBanChange>> {
+        self.unsynced.lock().expect("Unsynced bans lock is poisoned")
+    }
+}
+
+impl Default for LurkClientBans {
+    fn default() -> Self {
+        LurkClientBans::new()
+    }
+}
+
+/// Bans are the state of the node, so only the same bans are equal.
+impl PartialEq for LurkClientBans {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for LurkClientBans {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LurkClientBans").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_and_unban_clients() {
+        let bans = LurkClientBans::new();
+        let (alice, bob): (IpAddr, IpAddr) = ("203.0.113.1".parse().unwrap(), "203.0.113.2".parse().unwrap());
+
+        let expires_at = bans.ban(alice, Duration::from_secs(60));
+        assert!(bans.is_banned(alice));
+        assert!(
+            bans.is_banned("::ffff:203.0.113.1".parse().unwrap()),
+            "IPv4-mapped address is banned too"
+        );
+        assert_eq!(
+            Some(&LurkError::ClientBanned(alice)),
+            bans.check(alice).unwrap_err().downcast_ref::<LurkError>()
+        );
+        assert!(bans.check(bob).is_ok());
+        assert_eq!(vec![(alice, expires_at)], bans.list());
+
+        // Expired bans aren't listed nor checked.
+        bans.ban(bob, Duration::ZERO);
+        assert!(!bans.is_banned(bob));
+        assert_eq!(vec![(alice, expires_at)], bans.list());
+
+        assert!(bans.unban(alice));
+        assert!(!bans.unban(alice));
+        assert!(!bans.is_banned(alice));
+
+        // Every change is shared with the cluster.
+        let changes = bans.take_unsynced_changes();
+        assert_eq!(4, changes.len());
+        assert_eq!(LurkBanChange::Ban(alice, expires_at), changes[0]);
+        assert!(matches!(changes[1], LurkBanChange::Ban(ip, _) if ip == bob));
+        assert_eq!(LurkBanChange::Unban(alice), changes[3]);
+        assert!(bans.take_unsynced_changes().is_empty());
+    }
+
+    #[test]
+    fn keep_unsynced_changes_over_cluster_bans() {
+        let bans = LurkClientBans::new();
+        let (alice, bob): (IpAddr, IpAddr) = ("203.0.113.1".parse().unwrap(), "203.0.113.2".parse().unwrap());
+        let in_hour = Utc::now() + TimeDelta::hours(1);
+
+        // Ban made on the node before the cluster state is received isn't lost.
+        bans.ban(alice, Duration::from_secs(60));
+        bans.apply_cluster_bans(HashMap::from([(bob, in_hour)]));
+        assert!(bans.is_banned(alice));
+        assert!(bans.is_banned(bob));
+
+        // Once it's shared, the cluster is the source of truth.
+        let changes = bans.take_unsynced_changes();
+        assert_eq!(1, changes.len());
+        bans.apply_cluster_bans(HashMap::from([(bob, in_hour)]));
+        assert!(!bans.is_banned(alice));
+
+        // Changes, which have failed to be shared, are shared next time.
+        bans.restore_unsynced_changes(changes.clone());
+        bans.unban(bob);
+        let mut expected = changes;
+        expected.push(LurkBanChange::Unban(bob));
+        assert_eq!(expected, bans.take_unsynced_changes());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::common::error::LurkError;
+use anyhow::{bail, Result};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Connections currently open by every client IP, limited to keep one misbehaving
+/// client from exhausting the whole instance.
+pub struct LurkClientConnections {
+    max_per_client: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl LurkClientConnections {
+    pub fn new(max_per_client: usize) -> LurkClientConnections {
+        LurkClientConnections {
+            max_per_client,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Account new connection of the client, unless it would exceed the limit. Connection is
+    /// accounted until the returned guard is dropped. IPv4 clients of dual-stack listeners
+    /// are accounted by their IPv4 addresses.
+    pub fn open(self: &Arc<Self>, ip: IpAddr) -> Result<LurkClientConnection> {
+        let ip = ip.to_canonical();
+        let mut counts = self.counts();
+        let count = counts.entry(ip).or_default();
+        if *count >= self.max_per_client {
+            bail!(LurkError::ClientConnectionLimitExceeded(ip, self.max_per_client))
+        }
+
+        *count += 1;
+        Ok(LurkClientConnection {
+            connections: Arc::clone(self),
+            ip,
+        })
+    }
+
+    fn counts(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.counts.lock().expect("client connections lock is poisoned")
+    }
+}
+
+/// Open connections are the state of the node, so only the same accounting is equal.
+impl PartialEq for LurkClientConnections {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for LurkClientConnections {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LurkClientConnections")
+            .field("max_per_client", &self.max_per_client)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Open connection of the client, accounted until it's dropped.
+pub struct LurkClientConnection {
+    connections: Arc<LurkClientConnections>,
+    ip: IpAddr,
+}
+
+impl Drop for LurkClientConnection {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn limit_connections_per_client() {
+        let connections = Arc::new(LurkClientConnections::new(2));
+        let (alice, bob) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let alice_connections = [connections.open(alice).unwrap(), connections.open(alice).unwrap()];
+        assert_eq!(
+            Some(&LurkError::ClientConnectionLimitExceeded(alice, 2)),
+            connections
+                .open("::ffff:10.0.0.1".parse().unwrap())
+                .err()
+                .expect("limit should be exceeded")
+                .downcast_ref::<LurkError>()
+        );
+        let bob_connection = connections.open(bob).unwrap();
+        assert_eq!(HashMap::from([(alice, 2), (bob, 1)]), *connections.counts());
+
+        drop(alice_connections);
+        drop(bob_connection);
+        assert!(connections.counts().is_empty());
+        assert!(connections.open(alice).is_ok());
+    }
+}
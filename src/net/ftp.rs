@@ -0,0 +1,269 @@
+use super::tcp::LurkOutboundStream;
+use anyhow::{anyhow, bail, Result};
+use log::{debug, trace};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf},
+    time::timeout,
+};
+
+/// Reply of the FTP server: three-digit code and the text of the last line.
+#[derive(Debug, PartialEq)]
+pub struct LurkFtpReply {
+    code: u16,
+    text: String,
+}
+
+impl LurkFtpReply {
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// Positive preliminary (1xx), completion (2xx) or intermediate (3xx) reply.
+    fn is_positive(&self) -> bool {
+        (100..400).contains(&self.code)
+    }
+}
+
+/// Failure reported by the FTP server, as opposed to the transport failures.
+#[derive(Debug, thiserror::Error)]
+#[error("FTP server has replied to {command} with {} {}", reply.code, reply.text)]
+pub struct LurkFtpError {
+    command: &'static str,
+    reply: LurkFtpReply,
+}
+
+impl LurkFtpError {
+    pub fn reply(&self) -> &LurkFtpReply {
+        &self.reply
+    }
+}
+
+/// Minimalistic passive mode FTP client retrieving single files (or listings).
+/// Connections are established by the caller, so they are routed the way any other destination is.
+pub struct LurkFtpClient {
+    control: BufReader<LurkOutboundStream>,
+}
+
+impl LurkFtpClient {
+    /// Time given to the server to accept connection or to reply to the command.
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Maximum length of the reply line, including CRLF.
+    const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+    /// Maximum number of lines of the multiline reply.
+    const MAX_REPLY_LINES: usize = 1024;
+
+    /// Log in over the control connection. Anonymous login is used if no user is passed.
+    pub async fn login(control: LurkOutboundStream, user: Option<&str>, password: Option<&str>) -> Result<LurkFtpClient> {
+        let mut client = LurkFtpClient {
+            control: BufReader::new(control),
+        };
+
+        client.expect_reply("greeting").await?;
+
+        let user = user.unwrap_or("anonymous");
+        let reply = client.command("USER", &format!("USER {}", user)).await?;
+        // 331 - user name okay, need password.
+        if reply.code == 331 {
+            client.command("PASS", &format!("PASS {}", password.unwrap_or("lurk@"))).await?;
+        }
+
+        client.command("TYPE", "TYPE I").await?;
+        Ok(client)
+    }
+
+    /// Control connection with the server.
+    pub fn control(&self) -> &LurkOutboundStream {
+        self.control.get_ref()
+    }
+
+    /// Size of the file, if server supports SIZE command.
+    pub async fn size(&mut self, path: &str) -> Result<Option<u64>> {
+        match self.command("SIZE", &format!("SIZE {}", path)).await {
+            Ok(reply) => Ok(reply.text.trim().parse::<u64>().ok()),
+            Err(err) if err.is::<LurkFtpError>() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Start transfer of the file over the data connection established to the passive port.
+    pub async fn retrieve(self, path: &str, data: LurkOutboundStream) -> Result<LurkFtpTransfer> {
+        self.transfer("RETR", &format!("RETR {}", path), data).await
+    }
+
+    /// Start transfer of the directory listing. Empty path stands for the current directory.
+    pub async fn list(self, path: &str, data: LurkOutboundStream) -> Result<LurkFtpTransfer> {
+        match path {
+            "" => self.transfer("LIST", "LIST", data).await,
+            path => self.transfer("LIST", &format!("LIST {}", path), data).await,
+        }
+    }
+
+    async fn transfer(mut self, command: &'static str, line: &str, data: LurkOutboundStream) -> Result<LurkFtpTransfer> {
+        self.command(command, line).await?;
+        debug!("FTP transfer from {:?} has been started", data.peer_addr());
+
+        Ok(LurkFtpTransfer { data, _client: self })
+    }
+
+    /// Enter passive mode. Returns port of the server the data connection should be established to.
+    /// Address announced by the server is ignored, so the data connection can't be redirected
+    /// to another host (FTP bounce).
+    pub async fn passive(&mut self) -> Result<u16> {
+        match self.command("EPSV", "EPSV").await {
+            Ok(reply) => parse_epsv_port(&reply.text),
+            Err(err) if err.is::<LurkFtpError>() => {
+                let reply = self.command("PASV", "PASV").await?;
+                parse_pasv_port(&reply.text)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn command(&mut self, command: &'static str, line: &str) -> Result<LurkFtpReply> {
+        trace!("FTP >> {}", if command == "PASS" { "PASS ***" } else { line });
+        self.control.get_mut().write_all(format!("{}\r\n", line).as_bytes()).await?;
+        self.expect_reply(command).await
+    }
+
+    async fn expect_reply(&mut self, command: &'static str) -> Result<LurkFtpReply> {
+        let reply = Self::with_timeout(command, self.read_reply()).await?;
+        match reply.is_positive() {
+            true => Ok(reply),
+            false => Err(LurkFtpError { command, reply }.into()),
+        }
+    }
+
+    /// Read the reply, skipping intermediate lines of the multiline one.
+    async fn read_reply(&mut self) -> Result<LurkFtpReply> {
+        let mut line = String::new();
+        let mut multiline_code = None;
+        for _ in 0..Self::MAX_REPLY_LINES {
+            line.clear();
+            match (&mut self.control).take(Self::MAX_LINE_LENGTH).read_line(&mut line).await? {
+                0 => bail!("FTP server has closed control connection"),
+                _ if !line.ends_with('\n') => bail!("FTP reply line is longer than {} bytes", Self::MAX_LINE_LENGTH),
+                _ => trace!("FTP << {}", line.trim_end()),
+            }
+
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            let separator = line.as_bytes().get(3).copied();
+            match (code, separator, multiline_code) {
+                (Some(code), Some(b'-'), None) => multiline_code = Some(code),
+                (Some(code), Some(b' ') | Some(b'\r') | Some(b'\n'), None) => return Ok(reply(code, &line)),
+                (Some(code), Some(b' '), Some(expected)) if code == expected => return Ok(reply(code, &line)),
+                (_, _, Some(_)) => continue,
+                _ => bail!("malformed FTP reply '{}'", line.trim_end()),
+            }
+        }
+        bail!("FTP reply is longer than {} lines", Self::MAX_REPLY_LINES)
+    }
+
+    /// Run the step of the exchange with the server, failing it once the server has stalled.
+    pub async fn with_timeout<T, E: Into<anyhow::Error>>(step: &str, future: impl Future<Output = Result<T, E>>) -> Result<T> {
+        match timeout(Self::TIMEOUT, future).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => bail!("FTP server hasn't completed {} within {:?}", step, Self::TIMEOUT),
+        }
+    }
+}
+
+/// Data connection of the running transfer. Control connection is kept open
+/// until the transfer is finished, as some servers abort it otherwise.
+pub struct LurkFtpTransfer {
+    data: LurkOutboundStream,
+    _client: LurkFtpClient,
+}
+
+impl AsyncRead for LurkFtpTransfer {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.data).poll_read(cx, buf)
+    }
+}
+
+fn reply(code: u16, line: &str) -> LurkFtpReply {
+    LurkFtpReply {
+        code,
+        text: line.get(4..).unwrap_or_default().trim_end().to_owned(),
+    }
+}
+
+/// Parse port from "Entering Extended Passive Mode (|||6446|)".
+fn parse_epsv_port(text: &str) -> Result<u16> {
+    let inner = text
+        .split_once('(')
+        .and_then(|(_, rest)| rest.split_once(')'))
+        .map(|(inner, _)| inner)
+        .ok_or_else(|| anyhow!("malformed EPSV reply '{}'", text))?;
+
+    inner
+        .trim_matches(|c: char| !c.is_ascii_digit())
+        .parse::<u16>()
+        .map_err(|_| anyhow!("malformed EPSV reply '{}'", text))
+}
+
+/// Parse port from "Entering Passive Mode (h1,h2,h3,h4,p1,p2)".
+fn parse_pasv_port(text: &str) -> Result<u16> {
+    let start = text
+        .find(|c: char| c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("malformed PASV reply '{}'", text))?;
+    let numbers: Vec<u8> = text[start..]
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|number| !number.is_empty())
+        .take(6)
+        .map(str::parse::<u8>)
+        .collect::<Result<_, _>>()
+        .map_err(|_| anyhow!("malformed PASV reply '{}'", text))?;
+
+    match numbers.as_slice() {
+        [_, _, _, _, p1, p2] => Ok(u16::from(*p1) << 8 | u16::from(*p2)),
+        _ => bail!("malformed PASV reply '{}'", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Log in to the server, which greets the client with ```greeting```.
+    async fn login_greeted_with(greeting: Vec<u8>) -> Result<LurkFtpClient> {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let _ = stream.write_all(&greeting).await;
+        });
+        LurkFtpClient::login(TcpStream::connect(addr).await.unwrap().into(), None, None).await
+    }
+
+    #[tokio::test]
+    async fn refuse_unbounded_replies() {
+        let endless_line = [b"220 ".as_slice(), &[b'x'; 10 * 1024]].concat();
+        let err = login_greeted_with(endless_line).await.err().expect("greeting should be refused");
+        assert!(err.to_string().contains("longer than 8192 bytes"), "{err}");
+
+        let endless_reply = b"220-welcome\r\n".repeat(2 * LurkFtpClient::MAX_REPLY_LINES);
+        let err = login_greeted_with(endless_reply).await.err().expect("greeting should be refused");
+        assert!(err.to_string().contains("longer than 1024 lines"), "{err}");
+    }
+
+    #[test]
+    fn parse_passive_replies() {
+        assert_eq!(6446, parse_epsv_port("Entering Extended Passive Mode (|||6446|)").unwrap());
+        assert_eq!(1234, parse_pasv_port("Entering Passive Mode (192,168,1,2,4,210).").unwrap());
+        assert_eq!(1234, parse_pasv_port("=192,168,1,2,4,210").unwrap());
+
+        assert!(parse_epsv_port("Entering Extended Passive Mode").is_err());
+        assert!(parse_pasv_port("Entering Passive Mode (192,168,1,2)").is_err());
+        assert!(parse_pasv_port("Entering Passive Mode (192,168,1,2,4,300)").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use maxminddb::{geoip2, Reader};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Country database in MaxMind DB format (e.g. GeoLite2 Country or DB-IP country lite).
+pub struct LurkGeoIp {
+    path: PathBuf,
+    reader: Reader<Vec<u8>>,
+}
+
+impl LurkGeoIp {
+    pub fn from_file(path: &Path) -> Result<LurkGeoIp> {
+        let reader = Reader::open_readfile(path).with_context(|| format!("failed to load GeoIP database {}", path.display()))?;
+        Ok(LurkGeoIp {
+            path: path.to_owned(),
+            reader,
+        })
+    }
+
+    /// Load database from the content of the file.
+    pub fn from_bytes(content: Vec<u8>) -> Result<LurkGeoIp> {
+        let reader = Reader::from_source(content).context("failed to load GeoIP database")?;
+        Ok(LurkGeoIp {
+            path: PathBuf::new(),
+            reader,
+        })
+    }
+
+    /// ISO 3166-1 alpha-2 code of the country the IP is located in, if the database knows it.
+    /// IPv4 addresses mapped into IPv6 ones are looked up as IPv4 ones.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        // IPv6 addresses would be walked through the IPv4-only tree as if they were IPv4 ones.
+        if ip.is_ipv6() && self.reader.metadata.ip_version == 4 {
+            return None;
+        }
+
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        let iso_code = record.country?.iso_code?;
+        Some(iso_code.to_ascii_uppercase())
+    }
+}
+
+impl fmt::Debug for LurkGeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LurkGeoIp").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+/// Countries IPs are allowed and denied from.
+///
+/// **Fields**:
+/// * ```geoip``` - database the countries of IPs are looked up in
+/// * ```allow``` - countries IPs are allowed from, any country is if empty
+/// * ```deny``` - countries IPs are denied from, even if they are allowed
+///
+/// IPs missing in the database (e.g. private ones) are from the unknown country, which is
+/// allowed unless the allow list is set.
+#[derive(Debug, Clone)]
+pub struct LurkCountryAccess {
+    geoip: Arc<LurkGeoIp>,
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+}
+
+impl LurkCountryAccess {
+    /// Country reported for IPs missing in the database.
+    pub const UNKNOWN_COUNTRY: &'static str = "unknown";
+
+    pub fn new(
+        geoip: Arc<LurkGeoIp>,
+        allow: impl IntoIterator<Item = String>,
+        deny: impl IntoIterator<Item = String>,
+    ) -> LurkCountryAccess {
+        let normalize = |country: String| country.trim().to_ascii_uppercase();
+        LurkCountryAccess {
+            geoip,
+            allow: allow.into_iter().map(normalize).collect(),
+            deny: deny.into_iter().map(normalize).collect(),
+        }
+    }
+
+    /// Country of the IP, if it's denied.
+    pub fn denied_country(&self, ip: IpAddr) -> Option<String> {
+        match self.geoip.country(ip) {
+            Some(country) if !self.allow.is_empty() && !self.allow.contains(&country) => Some(country),
+            Some(country) if self.deny.contains(&country) => Some(country),
+            Some(_) => None,
+            None if !self.allow.is_empty() => Some(Self::UNKNOWN_COUNTRY.to_owned()),
+            None => None,
+        }
+    }
+}
+
+impl PartialEq for LurkCountryAccess {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.geoip, &other.geoip) && self.allow == other.allow && self.deny == other.deny
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ipnet::Ipv4Net;
+    use pretty_assertions::assert_eq;
+
+    /// Write string in MaxMind DB data section format.
+    fn write_string(buf: &mut Vec<u8>, value: &str) {
+        assert!(value.len() < 29);
+        buf.push(0x40 | value.len() as u8);
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    /// Build IPv4 country database with 24-bit records, mapping every network to its country.
+    pub(crate) fn country_database(networks: &[(&str, &str)]) -> Vec<u8> {
+        #[derive(Clone, Copy)]
+        enum Record {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+
+        // Data section: {"country": {"iso_code": <country>}} for every network.
+        let mut data = Vec::new();
+        let mut nodes = vec![[Record::Empty; 2]];
+        for (network, country) in networks {
+            let offset = data.len();
+            data.push(0xe1);
+            write_string(&mut data, "country");
+            data.push(0xe1);
+            write_string(&mut data, "iso_code");
+            write_string(&mut data, country);
+
+            let network: Ipv4Net = network.parse().unwrap();
+            let bits = network.network().to_bits();
+            let mut node = 0;
+            for i in 0..network.prefix_len() as u32 {
+                let bit = ((bits >> (31 - i)) & 1) as usize;
+                if i + 1 == network.prefix_len() as u32 {
+                    nodes[node][bit] = Record::Data(offset);
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    _ => {
+                        nodes.push([Record::Empty; 2]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+        }
+
+        let node_count = nodes.len();
+        let mut db = Vec::new();
+        for record in nodes.iter().flatten() {
+            let value = match *record {
+                Record::Empty => node_count,
+                Record::Node(next) => next,
+                Record::Data(offset) => node_count + 16 + offset,
+            };
+            db.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+        }
+        db.extend_from_slice(&[0; 16]);
+        db.extend_from_slice(&data);
+
+        db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        db.push(0xe9);
+        write_string(&mut db, "binary_format_major_version");
+        db.extend_from_slice(&[0xa1, 2]);
+        write_string(&mut db, "binary_format_minor_version");
+        db.push(0xa0);
+        write_string(&mut db, "build_epoch");
+        db.extend_from_slice(&[0x00, 0x02]);
+        write_string(&mut db, "database_type");
+        write_string(&mut db, "Lurk-Test-Country");
+        write_string(&mut db, "description");
+        db.push(0xe0);
+        write_string(&mut db, "ip_version");
+        db.extend_from_slice(&[0xa1, 4]);
+        write_string(&mut db, "languages");
+        db.extend_from_slice(&[0x00, 0x04]);
+        write_string(&mut db, "node_count");
+        db.push(0xc4);
+        db.extend_from_slice(&(node_count as u32).to_be_bytes());
+        write_string(&mut db, "record_size");
+        db.extend_from_slice(&[0xa1, 24]);
+
+        db
+    }
+
+    pub(crate) fn test_geoip() -> Arc<LurkGeoIp> {
+        let db = country_database(&[("81.2.69.0/24", "GB"), ("89.160.20.0/24", "SE"), ("175.16.199.0/24", "CN")]);
+        Arc::new(LurkGeoIp::from_bytes(db).unwrap())
+    }
+
+    #[test]
+    fn lookup_countries() {
+        let geoip = test_geoip();
+        let country = |ip: &str| geoip.country(ip.parse().unwrap());
+        assert_eq!(Some("GB"), country("81.2.69.142").as_deref());
+        assert_eq!(Some("SE"), country("::ffff:89.160.20.1").as_deref());
+        assert_eq!(Some("CN"), country("175.16.199.255").as_deref());
+        assert_eq!(None, country("81.2.70.1"));
+        assert_eq!(None, country("10.0.0.1"));
+        assert_eq!(None, country("2001:db8::1"));
+
+        assert!(LurkGeoIp::from_bytes(b"not a database".to_vec()).is_err());
+    }
+
+    #[test]
+    fn allow_and_deny_countries() {
+        let geoip = test_geoip();
+        let gb = "81.2.69.142".parse().unwrap();
+        let cn = "175.16.199.1".parse().unwrap();
+        let private = "10.0.0.1".parse().unwrap();
+
+        let access = LurkCountryAccess::new(Arc::clone(&geoip), [], ["cn".to_owned()]);
+        assert_eq!(None, access.denied_country(gb));
+        assert_eq!(Some("CN"), access.denied_country(cn).as_deref());
+        assert_eq!(None, access.denied_country(private));
+
+        let access = LurkCountryAccess::new(Arc::clone(&geoip), ["GB".to_owned(), "SE".to_owned()], []);
+        assert_eq!(None, access.denied_country(gb));
+        assert_eq!(Some("CN"), access.denied_country(cn).as_deref());
+        assert_eq!(Some(LurkCountryAccess::UNKNOWN_COUNTRY), access.denied_country(private).as_deref());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::server::TlsStream;
+
+/// Length of the TLS record header: content type, legacy version and length of the fragment.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// TCP stream the HTTPS listener runs TLS sessions over.
+///
+/// Sessions handed over to the kernel (kTLS) are handshaken record by record, and the stream stops
+/// reading once the record completing the handshake is passed to rustls. Records coming after it
+/// are left in the socket for the kernel to decrypt, while reading them ahead would lose them.
+pub struct LurkKtlsStream {
+    tcp_stream: TcpStream,
+    /// Whether the reads are aligned to the records, otherwise the stream just passes them through.
+    aligned: bool,
+    header: [u8; RECORD_HEADER_LEN],
+    header_len: usize,
+    /// Bytes of the current record (along with its header) passed to rustls.
+    served: usize,
+    /// Whether the record has been passed since the last time rustls was given a chance to complete the handshake.
+    yielded: bool,
+}
+
+impl LurkKtlsStream {
+    pub fn new(tcp_stream: TcpStream, aligned: bool) -> LurkKtlsStream {
+        LurkKtlsStream {
+            tcp_stream,
+            aligned,
+            header: [0; RECORD_HEADER_LEN],
+            header_len: 0,
+            served: 0,
+            yielded: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.tcp_stream
+    }
+
+    /// Pass the records through, once the session stays in userspace.
+    pub fn passthrough(&mut self) {
+        self.aligned = false;
+    }
+
+    fn record_len(&self) -> usize {
+        RECORD_HEADER_LEN + usize::from(u16::from_be_bytes([self.header[3], self.header[4]]))
+    }
+
+    fn poll_read_aligned(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.header_len < RECORD_HEADER_LEN {
+            // Handshake reads until the socket has nothing more, so every record is followed by the pending read.
+            // Once the handshake is complete, the read isn't polled again.
+            if self.header_len == 0 && self.yielded {
+                self.yielded = false;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let mut header = ReadBuf::new(&mut self.header[self.header_len..]);
+            ready!(Pin::new(&mut self.tcp_stream).poll_read(cx, &mut header))?;
+            match header.filled().len() {
+                0 => return Poll::Ready(Ok(())),
+                n => self.header_len += n,
+            }
+            self.served = 0;
+        }
+
+        if self.served < RECORD_HEADER_LEN {
+            let header = &self.header[self.served..];
+            let n = header.len().min(buf.remaining());
+            buf.put_slice(&header[..n]);
+            self.served += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut fragment = ReadBuf::new(buf.initialize_unfilled_to((self.record_len() - self.served).min(buf.remaining())));
+        ready!(Pin::new(&mut self.tcp_stream).poll_read(cx, &mut fragment))?;
+        let n = fragment.filled().len();
+        buf.advance(n);
+        self.served += n;
+        if self.served == self.record_len() {
+            self.header_len = 0;
+            self.yielded = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for LurkKtlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.aligned {
+            true => this.poll_read_aligned(cx, buf),
+            false => Pin::new(&mut this.tcp_stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LurkKtlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().tcp_stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().tcp_stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().tcp_stream).poll_shutdown(cx)
+    }
+}
+
+/// Attach TLS upper layer protocol to the socket, unless the session has data buffered by rustls
+/// (the kernel couldn't take it over then). Fails if the kernel doesn't support kTLS.
+#[cfg(target_os = "linux")]
+pub fn attach(tls_stream: &mut TlsStream<LurkKtlsStream>) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (stream, session) = tls_stream.get_mut();
+    let io_state = session.process_new_packets().map_err(io::Error::other)?;
+    if io_state.plaintext_bytes_to_read() != 0 || io_state.tls_bytes_to_write() != 0 {
+        return Err(io::Error::other("session has buffered data"));
+    }
+
+    setsockopt(stream.tcp_stream.as_raw_fd(), libc::SOL_TCP, libc::TCP_ULP, b"tls")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn attach(_tls_stream: &mut TlsStream<LurkKtlsStream>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "kTLS is supported only on Linux"))
+}
+
+/// Hand the session over to the kernel, which encrypts and decrypts the records of the returned stream from now on.
+/// The socket should have TLS attached. Records other than application data (e.g. alerts) fail the reads.
+#[cfg(target_os = "linux")]
+pub fn offload(tls_stream: TlsStream<LurkKtlsStream>) -> io::Result<TcpStream> {
+    use rustls::{ConnectionTrafficSecrets, ProtocolVersion};
+    use std::os::fd::AsRawFd;
+
+    let (stream, session) = tls_stream.into_inner();
+    let version = match session.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => libc::TLS_1_2_VERSION,
+        Some(ProtocolVersion::TLSv1_3) => libc::TLS_1_3_VERSION,
+        version => return Err(io::Error::other(format!("{:?} can't be handed over to the kernel", version))),
+    };
+    let secrets = session.dangerous_extract_secrets().map_err(io::Error::other)?;
+
+    let fd = stream.tcp_stream.as_raw_fd();
+    for (direction, (seq, secrets)) in [(libc::TLS_TX, secrets.tx), (libc::TLS_RX, secrets.rx)] {
+        let rec_seq = seq.to_be_bytes();
+        // Nonce is the salt followed by the IV, ChaCha20-Poly1305 has no salt.
+        match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+                let (salt, iv) = iv.as_ref().split_at(libc::TLS_CIPHER_AES_GCM_128_SALT_SIZE);
+                let crypto_info = libc::tls12_crypto_info_aes_gcm_128 {
+                    info: libc::tls_crypto_info {
+                        version,
+                        cipher_type: libc::TLS_CIPHER_AES_GCM_128,
+                    },
+                    iv: iv.try_into().map_err(io::Error::other)?,
+                    key: key.as_ref().try_into().map_err(io::Error::other)?,
+                    salt: salt.try_into().map_err(io::Error::other)?,
+                    rec_seq,
+                };
+                setsockopt(fd, libc::SOL_TLS, direction, &crypto_info)?;
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+                let (salt, iv) = iv.as_ref().split_at(libc::TLS_CIPHER_AES_GCM_256_SALT_SIZE);
+                let crypto_info = libc::tls12_crypto_info_aes_gcm_256 {
+                    info: libc::tls_crypto_info {
+                        version,
+                        cipher_type: libc::TLS_CIPHER_AES_GCM_256,
+                    },
+                    iv: iv.try_into().map_err(io::Error::other)?,
+                    key: key.as_ref().try_into().map_err(io::Error::other)?,
+                    salt: salt.try_into().map_err(io::Error::other)?,
+                    rec_seq,
+                };
+                setsockopt(fd, libc::SOL_TLS, direction, &crypto_info)?;
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+                let crypto_info = libc::tls12_crypto_info_chacha20_poly1305 {
+                    info: libc::tls_crypto_info {
+                        version,
+                        cipher_type: libc::TLS_CIPHER_CHACHA20_POLY1305,
+                    },
+                    iv: iv.as_ref().try_into().map_err(io::Error::other)?,
+                    key: key.as_ref().try_into().map_err(io::Error::other)?,
+                    salt: [],
+                    rec_seq,
+                };
+                setsockopt(fd, libc::SOL_TLS, direction, &crypto_info)?;
+            }
+            _ => return Err(io::Error::other("cipher suite can't be handed over to the kernel")),
+        }
+    }
+
+    Ok(stream.tcp_stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn offload(_tls_stream: TlsStream<LurkKtlsStream>) -> io::Result<TcpStream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "kTLS is supported only on Linux"))
+}
+
+/// Whether the kernel supports kTLS, which is missing unless the "tls" module is available.
+#[cfg(all(test, target_os = "linux"))]
+pub fn is_supported() -> bool {
+    use std::os::fd::AsRawFd;
+
+    // Upper layer protocol is attached to the connected sockets only.
+    let probe = || -> io::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let tcp_stream = std::net::TcpStream::connect(listener.local_addr()?)?;
+        setsockopt(tcp_stream.as_raw_fd(), libc::SOL_TCP, libc::TCP_ULP, b"tls")
+    };
+    probe().is_ok()
+}
+
+/// Upper layer protocol attached to the socket, which is "tls" once the session is handed over to the kernel.
+#[cfg(all(test, target_os = "linux"))]
+pub fn upper_layer_protocol(tcp_stream: &TcpStream) -> io::Result<String> {
+    use std::os::fd::AsRawFd;
+
+    let mut name = [0u8; 16];
+    let mut len = name.len() as libc::socklen_t;
+    // SAFETY: descriptor is owned by the alive stream and the kernel writes at most ```len``` bytes of the name.
+    let ret = unsafe {
+        libc::getsockopt(
+            tcp_stream.as_raw_fd(),
+            libc::SOL_TCP,
+            libc::TCP_ULP,
+            name.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(String::from_utf8_lossy(&name[..len as usize]).trim_end_matches('\0').to_owned()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt<T: ?Sized>(fd: std::os::fd::RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: descriptor is owned by the alive stream and the option value is the structure the kernel expects for the name.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of_val(value) as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn read_record_by_record() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (tcp_stream, _) = listener.accept().await.unwrap();
+
+        // Both records arrive at once, though they are read one after another.
+        let records = [&[22, 3, 3, 0, 2, 1, 2][..], &[23, 3, 3, 0, 3, 3, 4, 5][..]];
+        client.write_all(&records.concat()).await.unwrap();
+
+        let mut stream = LurkKtlsStream::new(tcp_stream, true);
+        let mut buff = [0u8; 64];
+        for record in records {
+            let (header, fragment) = record.split_at(RECORD_HEADER_LEN);
+            assert_eq!(header.len(), stream.read(&mut buff).await.unwrap());
+            assert_eq!(header, &buff[..header.len()]);
+            assert_eq!(fragment.len(), stream.read(&mut buff).await.unwrap());
+            assert_eq!(fragment, &buff[..fragment.len()]);
+        }
+
+        // Records are passed through as they come otherwise.
+        stream.passthrough();
+        client.write_all(&records.concat()).await.unwrap();
+        let mut read = Vec::new();
+        while read.len() < records.concat().len() {
+            let n = stream.read(&mut buff).await.unwrap();
+            read.extend_from_slice(&buff[..n]);
+        }
+        assert_eq!(records.concat(), read);
+    }
+}
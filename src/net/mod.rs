@@ -1,15 +1,13 @@
 use crate::common::error::LurkError;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
 use bytes::BufMut;
+use clap::ValueEnum;
 use std::{
     fmt::Display,
     io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-};
-use tokio::{
-    io::AsyncReadExt,
-    net::{lookup_host, ToSocketAddrs},
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
 };
+use tokio::net::{lookup_host, ToSocketAddrs};
 
 macro_rules! ipv4_socket_address {
     ($ipv4:expr, $port:expr) => {
@@ -23,7 +21,26 @@ macro_rules! ipv6_socket_address {
     };
 }
 
+pub mod bans;
+pub mod client_limits;
+#[cfg(feature = "http")]
+pub mod ftp;
+pub mod geoip;
+#[cfg(feature = "https")]
+pub mod ktls;
+#[cfg(any(feature = "http3", feature = "https"))]
+pub mod ocsp;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod socks5;
 pub mod tcp;
+#[cfg(any(feature = "http3", feature = "https"))]
+pub mod tls;
+#[cfg(feature = "upstream-tls")]
+pub mod upstream_tls;
+
+#[cfg(test)]
+pub mod sim;
 
 pub(crate) use ipv4_socket_address;
 pub(crate) use ipv6_socket_address;
@@ -32,6 +49,35 @@ pub async fn resolve_sockaddr(addr: impl ToSocketAddrs) -> Result<SocketAddr> {
     lookup_host(addr).await?.next().ok_or(anyhow!(io::ErrorKind::AddrNotAvailable))
 }
 
+/// How domain names of the destinations are resolved into IP addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkResolvePolicy {
+    /// The first address returned by the system resolver
+    #[default]
+    System,
+    /// IPv4 address if there is any, IPv6 one otherwise
+    PreferIpv4,
+    /// IPv6 address if there is any, IPv4 one otherwise
+    PreferIpv6,
+    /// IPv4 address only, domain names resolved into IPv6 addresses only are unreachable
+    Ipv4Only,
+}
+
+impl LurkResolvePolicy {
+    /// Pick one of the resolved addresses, in the order returned by the resolver.
+    pub fn select(&self, addrs: impl IntoIterator<Item = SocketAddr>) -> Option<SocketAddr> {
+        let mut addrs = addrs.into_iter();
+        let prefer = |addrs: Vec<SocketAddr>, ipv4: bool| addrs.iter().find(|addr| addr.is_ipv4() == ipv4).or(addrs.first()).copied();
+
+        match self {
+            LurkResolvePolicy::System => addrs.next(),
+            LurkResolvePolicy::PreferIpv4 => prefer(addrs.collect(), true),
+            LurkResolvePolicy::PreferIpv6 => prefer(addrs.collect(), false),
+            LurkResolvePolicy::Ipv4Only => addrs.find(SocketAddr::is_ipv4),
+        }
+    }
+}
+
 #[repr(u8)]
 #[rustfmt::skip]
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -41,35 +87,25 @@ pub enum Address {
 }
 
 impl Address {
-    pub async fn to_socket_addr(&self) -> Result<SocketAddr> {
+    /// Resolve the address. Domain name is resolved into one of its IP addresses picked by the ```policy```.
+    pub async fn to_socket_addr(&self, policy: LurkResolvePolicy) -> Result<SocketAddr> {
         match self {
             Address::SocketAddress(sock_addr) => Ok(*sock_addr),
-            Address::DomainName(hostname, port) => resolve_sockaddr(format!("{hostname:}:{port:}")).await,
+            Address::DomainName(hostname, port) => {
+                let addrs = lookup_host(format!("{hostname:}:{port:}")).await?;
+                policy
+                    .select(addrs)
+                    .ok_or_else(|| anyhow!(LurkError::UnresolvedDomainName(hostname.clone())))
+            }
         }
     }
 
-    pub async fn read_ipv4<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Address> {
-        let ipv4 = Ipv4Addr::from(stream.read_u32().await?);
-        let port = stream.read_u16().await?;
-
-        Ok(ipv4_socket_address!(ipv4, port))
-    }
-
-    pub async fn read_ipv6<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Address> {
-        let ipv6 = Ipv6Addr::from(stream.read_u128().await?);
-        let port = stream.read_u16().await?;
-
-        Ok(ipv6_socket_address!(ipv6, port))
-    }
-
-    pub async fn read_domain_name<T: AsyncReadExt + Unpin>(stream: &mut T, len: u8) -> Result<Address> {
-        let mut buf = vec![0u8; len as usize];
-        stream.read_exact(&mut buf).await?;
-
-        let name = String::from_utf8(buf).map_err(LurkError::DomainNameDecodingFailed)?;
-        let port = stream.read_u16().await?;
-
-        Ok(Address::DomainName(name, port))
+    /// Returns host part of the address, i.e. IP address or domain name.
+    pub fn host(&self) -> String {
+        match self {
+            Address::SocketAddress(sock_addr) => sock_addr.ip().to_string(),
+            Address::DomainName(hostname, _) => hostname.clone(),
+        }
     }
 
     pub fn write_ipv4<T: BufMut>(bytes: &mut T, ipv4_addr: &SocketAddrV4) {
@@ -82,9 +118,13 @@ impl Address {
         bytes.put_u16(ipv6_addr.port());
     }
 
-    #[allow(unused_variables)]
-    pub fn write_domain_name<T: BufMut>(bytes: &mut T, name: &str, port: &u16) {
-        todo!("Writing of domain names is not implemented")
+    /// Domain name is prefixed with its length, so names longer than 255 bytes can't be written.
+    pub fn write_domain_name<T: BufMut>(bytes: &mut T, name: &str, port: &u16) -> Result<()> {
+        ensure!(name.len() <= u8::MAX as usize, "domain name should be 255 bytes long at most");
+        bytes.put_u8(name.len() as u8);
+        bytes.put_slice(name.as_bytes());
+        bytes.put_u16(*port);
+        Ok(())
     }
 }
 
@@ -100,45 +140,40 @@ impl Display for Address {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_assertions::assert_eq;
-    use std::net::Ipv4Addr;
     use tokio_test::{assert_err, assert_ok};
 
     #[tokio::test]
     async fn domain_to_socket_addr() {
         let resolved = Address::DomainName("www.example.com".to_owned(), 80);
-        assert_ok!(resolved.to_socket_addr().await);
+        assert_ok!(resolved.to_socket_addr(LurkResolvePolicy::System).await);
 
         let unresolved = Address::DomainName("unresolved123".to_owned(), 666);
-        assert_err!(unresolved.to_socket_addr().await);
+        assert_err!(unresolved.to_socket_addr(LurkResolvePolicy::System).await);
     }
 
     #[tokio::test]
-    async fn read_address_from_stream() {
-        let domain_name = "www.example.com".to_string();
-        let domain_name_len = domain_name.len() as u8;
-        let mut mock = tokio_test::io::Builder::new()
-            .read(&[127, 0, 0, 1, 10, 10]) // ipv4
-            .read(&[0, 0, 0, 0, 0, 0xff, 0xff, 0xc0, 0x0a, 0x02, 0xff, 0xca, 0x1, 0x0, 0x11, 0xff, 10, 10]) // ipv6
-            .read([domain_name.as_bytes(), &[10, 10]].concat().as_slice()) // domain
-            .build();
-
-        // IPv4
-        assert_eq!(
-            ipv4_socket_address!(Ipv4Addr::new(127, 0, 0, 1,), 2570),
-            Address::read_ipv4(&mut mock).await.unwrap()
-        );
-
-        // IPv6
-        assert_eq!(
-            ipv6_socket_address!(Ipv6Addr::new(0, 0, 0xff, 0xffc0, 0xa02, 0xffca, 0x100, 0x11ff), 2570),
-            Address::read_ipv6(&mut mock).await.unwrap()
-        );
-
-        // Domain name
-        assert_eq!(
-            Address::DomainName(domain_name, 2570),
-            Address::read_domain_name(&mut mock, domain_name_len).await.unwrap()
-        )
+    async fn localhost_to_ipv4_socket_addr() {
+        let localhost = Address::DomainName("localhost".to_owned(), 80);
+        let resolved = localhost.to_socket_addr(LurkResolvePolicy::Ipv4Only).await.unwrap();
+        assert_eq!("127.0.0.1:80".parse::<SocketAddr>().unwrap(), resolved);
+    }
+
+    #[test]
+    fn select_resolved_address() {
+        let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+
+        for (policy, addrs, expected) in [
+            (LurkResolvePolicy::System, vec![v6, v4], Some(v6)),
+            (LurkResolvePolicy::PreferIpv4, vec![v6, v4], Some(v4)),
+            (LurkResolvePolicy::PreferIpv4, vec![v6], Some(v6)),
+            (LurkResolvePolicy::PreferIpv6, vec![v4, v6], Some(v6)),
+            (LurkResolvePolicy::PreferIpv6, vec![v4], Some(v4)),
+            (LurkResolvePolicy::Ipv4Only, vec![v6, v4], Some(v4)),
+            (LurkResolvePolicy::Ipv4Only, vec![v6], None),
+            (LurkResolvePolicy::System, vec![], None),
+        ] {
+            assert_eq!(expected, policy.select(addrs), "{:?}", policy);
+        }
     }
 }
@@ -0,0 +1,312 @@
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{client::conn::http1, header, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use rustls::pki_types::CertificateDer;
+use std::time::Duration;
+use tokio::{net::TcpStream, time::timeout};
+use yasna::{models::ObjectIdentifier, ASN1Result, BERReader, Tag};
+
+/// Authority Information Access extension of the certificate.
+const OID_AUTHORITY_INFO_ACCESS: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 1];
+
+/// Access method of the OCSP responder in the Authority Information Access extension.
+const OID_OCSP: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1];
+
+/// Hash algorithm identifying the certificate in the request. SHA-1 is the one every responder supports.
+const OID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+
+/// Time given to the responder to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fields of the certificate its status is asked by.
+///
+/// **Fields**:
+/// * ```serial``` - DER-encoded serial number
+/// * ```issuer``` - DER-encoded name of the issuer
+/// * ```subject``` - DER-encoded name of the subject
+/// * ```public_key``` - subject public key, without the algorithm
+/// * ```responder_url``` - URL of the OCSP responder of the issuer, if the certificate names it
+///
+#[derive(Debug, PartialEq)]
+struct LurkOcspCertInfo {
+    serial: Vec<u8>,
+    issuer: Vec<u8>,
+    subject: Vec<u8>,
+    public_key: Vec<u8>,
+    responder_url: Option<String>,
+}
+
+/// Fetch OCSP response for the end-entity certificate of the chain from the responder named by the certificate.
+/// The chain should include the issuer of the certificate.
+///
+/// Response is returned as it has come from the responder, so clients verify its signature themselves.
+pub async fn fetch_response(cert_chain: &[CertificateDer<'_>]) -> Result<Vec<u8>> {
+    let [cert, issuer, ..] = cert_chain else {
+        bail!("certificate chain doesn't include the issuer");
+    };
+    let cert = parse_cert(cert).context("failed to parse certificate")?;
+    let issuer = parse_cert(issuer).context("failed to parse issuer certificate")?;
+    ensure!(
+        cert.issuer == issuer.subject,
+        "certificate isn't followed by its issuer in the chain"
+    );
+    let Some(url) = &cert.responder_url else {
+        bail!("certificate doesn't name OCSP responder");
+    };
+
+    let response = timeout(FETCH_TIMEOUT, post(url, request(&cert, &issuer)))
+        .await
+        .with_context(|| format!("OCSP responder {} hasn't answered in time", url))?
+        .with_context(|| format!("failed to fetch OCSP response from {}", url))?;
+    check_response(&response)?;
+    Ok(response)
+}
+
+/// Fields of the DER-encoded certificate.
+fn parse_cert(der: &[u8]) -> Result<LurkOcspCertInfo> {
+    let info = yasna::parse_der(der, |reader| {
+        reader.read_sequence(|cert| {
+            let info = cert.next().read_sequence(|tbs| {
+                tbs.read_optional(|reader| reader.read_tagged(Tag::context(0), |reader| reader.read_i64()))?;
+                let serial = tbs.next().read_der()?;
+                tbs.next().read_der()?; // signature algorithm
+                let issuer = tbs.next().read_der()?;
+                tbs.next().read_der()?; // validity
+                let subject = tbs.next().read_der()?;
+                let (public_key, _) = tbs.next().read_sequence(|spki| {
+                    spki.next().read_der()?; // algorithm
+                    spki.next().read_bitvec_bytes()
+                })?;
+
+                // Unique IDs and extensions, which follow, are all optional.
+                let mut responder_url = None;
+                while let Some(field) = tbs.read_optional(|reader| reader.read_tagged_der())? {
+                    if field.tag() == Tag::context(3) {
+                        responder_url = yasna::parse_der(field.value(), read_responder_url)?;
+                    }
+                }
+
+                Ok(LurkOcspCertInfo {
+                    serial,
+                    issuer,
+                    subject,
+                    public_key,
+                    responder_url,
+                })
+            })?;
+            cert.next().read_der()?; // signature algorithm
+            cert.next().read_der()?; // signature
+            Ok(info)
+        })
+    })?;
+
+    Ok(info)
+}
+
+/// URL of the OCSP responder from the Authority Information Access extension, if any.
+fn read_responder_url(reader: BERReader) -> ASN1Result<Option<String>> {
+    let mut responder_url = None;
+    reader.read_sequence_of(|reader| {
+        reader.read_sequence(|extension| {
+            let oid = extension.next().read_oid()?;
+            extension.read_optional(|reader| reader.read_bool())?; // critical
+            let value = extension.next().read_bytes()?;
+            if oid != ObjectIdentifier::from_slice(OID_AUTHORITY_INFO_ACCESS) {
+                return Ok(());
+            }
+
+            yasna::parse_der(&value, |reader| {
+                reader.read_sequence_of(|reader| {
+                    reader.read_sequence(|description| {
+                        let method = description.next().read_oid()?;
+                        let location = description.next().read_tagged_der()?;
+                        // Location is uniformResourceIdentifier [6] of the GeneralName.
+                        if responder_url.is_none() && method == ObjectIdentifier::from_slice(OID_OCSP) && location.tag() == Tag::context(6)
+                        {
+                            responder_url = String::from_utf8(location.value().to_vec()).ok();
+                        }
+                        Ok(())
+                    })
+                })
+            })
+        })
+    })?;
+
+    Ok(responder_url)
+}
+
+/// DER-encoded unsigned OCSP request asking status of the certificate.
+fn request(cert: &LurkOcspCertInfo, issuer: &LurkOcspCertInfo) -> Vec<u8> {
+    yasna::construct_der(|writer| {
+        // OCSPRequest holds TBSRequest with the only Request.
+        writer.write_sequence(|ocsp_request| {
+            ocsp_request.next().write_sequence(|tbs_request| {
+                tbs_request.next().write_sequence(|request_list| {
+                    request_list.next().write_sequence(|request| {
+                        request.next().write_sequence(|cert_id| {
+                            cert_id.next().write_sequence(|algorithm| {
+                                algorithm.next().write_oid(&ObjectIdentifier::from_slice(OID_SHA1));
+                                algorithm.next().write_null();
+                            });
+                            cert_id.next().write_bytes(digest(&SHA1_FOR_LEGACY_USE_ONLY, &cert.issuer).as_ref());
+                            cert_id
+                                .next()
+                                .write_bytes(digest(&SHA1_FOR_LEGACY_USE_ONLY, &issuer.public_key).as_ref());
+                            cert_id.next().write_der(&cert.serial);
+                        })
+                    })
+                })
+            })
+        })
+    })
+}
+
+/// Check the responder has answered the request. Responses, which tell it can't, aren't worth stapling.
+fn check_response(response: &[u8]) -> Result<()> {
+    let status = yasna::parse_der(response, |reader| {
+        reader.read_sequence(|response| {
+            let status = response.next().read_enum()?;
+            response.read_optional(|reader| reader.read_der())?; // response bytes
+            Ok(status)
+        })
+    })
+    .context("OCSP response is malformed")?;
+
+    ensure!(status == 0, "OCSP responder has refused the request with status {}", status);
+    Ok(())
+}
+
+/// Send the request to the http:// URL of the responder. Returns body of the response.
+async fn post(url: &str, body: Vec<u8>) -> Result<Vec<u8>> {
+    let uri: Uri = url.parse().with_context(|| format!("'{}' is not a valid URL", url))?;
+    ensure!(uri.scheme_str() == Some("http"), "only http:// URLs are supported");
+    let Some(authority) = uri.authority() else {
+        bail!("URL has no host");
+    };
+
+    // IPv6 address comes in brackets.
+    let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((host, authority.port_u16().unwrap_or(80))).await?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let request = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(header::HOST, authority.as_str())
+        .header(header::CONTENT_TYPE, "application/ocsp-request")
+        .body(Full::new(Bytes::from(body)))?;
+    let response = sender.send_request(request).await?;
+    match response.status() {
+        StatusCode::OK => Ok(response.into_body().collect().await?.to_bytes().to_vec()),
+        status => bail!("server has responded with {}", status),
+    }
+}
+
+/// DER-encoded OCSP response with the status and without the response bytes.
+#[cfg(test)]
+pub fn unsuccessful_response(status: i64) -> Vec<u8> {
+    yasna::construct_der(|writer| writer.write_sequence(|response| response.next().write_enum(status)))
+}
+
+/// DER-encoded successful OCSP response carrying the bytes of the basic response.
+#[cfg(test)]
+pub fn successful_response(basic_response: &[u8]) -> Vec<u8> {
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|response| {
+            response.next().write_enum(0);
+            response.next().write_tagged(Tag::context(0), |writer| {
+                writer.write_sequence(|response_bytes| {
+                    response_bytes
+                        .next()
+                        .write_oid(&ObjectIdentifier::from_slice(&[1, 3, 6, 1, 5, 5, 7, 48, 1, 1]));
+                    response_bytes.next().write_bytes(basic_response);
+                })
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::tls;
+    use httptest::{
+        all_of,
+        matchers::{contains, request},
+        responders::status_code,
+        Expectation, Server,
+    };
+    use rustls::pki_types::pem::PemObject;
+
+    #[test]
+    fn build_request() {
+        let dir = std::env::temp_dir().join(format!("lurk-ocsp-request-{}", std::process::id()));
+        let (cert_path, _, _) = tls::write_ca_signed_cert(&dir, "localhost", "http://127.0.0.1:8080/ocsp");
+        let chain = CertificateDer::pem_file_iter(&cert_path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let (cert, issuer) = (parse_cert(&chain[0]).unwrap(), parse_cert(&chain[1]).unwrap());
+        assert_eq!(Some("http://127.0.0.1:8080/ocsp"), cert.responder_url.as_deref());
+        assert_eq!(None, issuer.responder_url);
+        assert_eq!(cert.issuer, issuer.subject);
+
+        // CertID of the request identifies the certificate by its issuer and serial number.
+        let (name_hash, key_hash, serial) = yasna::parse_der(&request(&cert, &issuer), |reader| {
+            reader.read_sequence(|ocsp_request| {
+                ocsp_request.next().read_sequence(|tbs_request| {
+                    tbs_request.next().read_sequence(|request_list| {
+                        request_list.next().read_sequence(|request| {
+                            request.next().read_sequence(|cert_id| {
+                                cert_id.next().read_der()?;
+                                Ok((
+                                    cert_id.next().read_bytes()?,
+                                    cert_id.next().read_bytes()?,
+                                    cert_id.next().read_der()?,
+                                ))
+                            })
+                        })
+                    })
+                })
+            })
+        })
+        .unwrap();
+        assert_eq!(digest(&SHA1_FOR_LEGACY_USE_ONLY, &cert.issuer).as_ref(), name_hash);
+        assert_eq!(digest(&SHA1_FOR_LEGACY_USE_ONLY, &issuer.public_key).as_ref(), key_hash);
+        assert_eq!(cert.serial, serial);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_from_responder() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/good"),
+                request::headers(contains(("content-type", "application/ocsp-request"))),
+            ])
+            .respond_with(status_code(200).body(successful_response(b"basic"))),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/busy")).respond_with(status_code(200).body(unsuccessful_response(3))),
+        );
+
+        let dir = std::env::temp_dir().join(format!("lurk-ocsp-fetch-{}", std::process::id()));
+        for (path, expected) in [("/good", Some(successful_response(b"basic"))), ("/busy", None)] {
+            let (cert_path, _, _) = tls::write_ca_signed_cert(&dir, "localhost", &server.url_str(path));
+            let chain = CertificateDer::pem_file_iter(&cert_path)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            assert_eq!(expected, fetch_response(&chain).await.ok());
+            // Issuer is needed to identify the certificate.
+            assert!(fetch_response(&chain[..1]).await.is_err());
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
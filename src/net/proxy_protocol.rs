@@ -0,0 +1,181 @@
+use anyhow::{bail, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature the binary (v2) header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Prefix of the text (v1) header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Maximum length of the text header, including CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read PROXY protocol header (v1 or v2), which the load balancer sends before anything else,
+/// and return the address of the client it has accepted the connection from. Nothing is returned
+/// when the balancer doesn't convey it, e.g. for its own health checks (LOCAL command or UNKNOWN protocol).
+///
+/// Header is read exactly, so the client data following it is left in the stream.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // The shortest header possible is "PROXY UNKNOWN\r\n", so the signature is there anyway.
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(fixed[0], fixed[1], &addresses)
+    } else if header.starts_with(V1_PREFIX) {
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LENGTH {
+                bail!("PROXY protocol v1 header is longer than {} bytes", V1_MAX_LENGTH)
+            }
+            header.push(stream.read_u8().await?);
+        }
+        parse_v1(&header[V1_PREFIX.len()..header.len() - 2])
+    } else {
+        bail!("connection doesn't start with PROXY protocol header")
+    }
+}
+
+/// Parse the line "<TCP4|TCP6|UNKNOWN> <source IP> <destination IP> <source port> <destination port>".
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let Ok(line) = str::from_utf8(line) else {
+        bail!("PROXY protocol v1 header isn't ASCII")
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let source_ip = match fields.as_slice() {
+        ["UNKNOWN", ..] => return Ok(None),
+        ["TCP4", source_ip, _, _, _] => source_ip.parse::<Ipv4Addr>().map(IpAddr::V4),
+        ["TCP6", source_ip, _, _, _] => source_ip.parse::<Ipv6Addr>().map(IpAddr::V6),
+        _ => bail!("malformed PROXY protocol v1 header '{}'", line),
+    };
+    match (source_ip, fields[3].parse::<u16>()) {
+        (Ok(ip), Ok(port)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => bail!("malformed PROXY protocol v1 header '{}'", line),
+    }
+}
+
+/// Parse the addresses block of the binary header. TLVs following the addresses are ignored.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        bail!("unsupported PROXY protocol version {}", version_command >> 4)
+    }
+    match version_command & 0x0F {
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => bail!("unsupported PROXY protocol v2 command {:#x}", command),
+    }
+
+    // Source and destination IPs are followed by their ports.
+    let (source_ip, ports): (IpAddr, &[u8]) = match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into()?;
+            (Ipv4Addr::from(ip).into(), &addresses[8..12])
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into()?;
+            (Ipv6Addr::from(ip).into(), &addresses[32..36])
+        }
+        0x1 | 0x2 => bail!("PROXY protocol v2 header is too short for its addresses"),
+        // Unspecified and UNIX socket addresses don't identify the client.
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(source_ip, u16::from_be_bytes([ports[0], ports[1]]))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    async fn read_from(header: &[u8], payload: &[u8]) -> Result<Option<SocketAddr>> {
+        let data = [header, payload].concat();
+        let mut stream = data.as_slice();
+        let source = read_header(&mut stream).await?;
+
+        // Client data isn't consumed along with the header.
+        assert_eq!(payload, stream);
+        Ok(source)
+    }
+
+    fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn read_v1_header() {
+        assert_eq!(
+            Some("192.168.0.1:56324".parse().unwrap()),
+            read_from(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1080\r\n", b"\x05\x01\x00")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some("[2001:db8::1]:56324".parse().unwrap()),
+            read_from(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 1080\r\n", b"GET")
+                .await
+                .unwrap()
+        );
+        assert_eq!(None, read_from(b"PROXY UNKNOWN\r\n", b"").await.unwrap());
+
+        for malformed in [
+            &b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 1080\r\n"[..],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 65536 1080\r\n",
+            b"PROXY TCP4 192.168.0.1\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(read_from(malformed, b"").await.is_err(), "{:?} should be refused", malformed);
+        }
+
+        let endless = [b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat();
+        assert!(read_from(&endless, b"").await.is_err(), "header should be too long");
+    }
+
+    #[tokio::test]
+    async fn read_v2_header() {
+        let ipv4 = [[192, 168, 0, 1], [192, 168, 0, 11]].concat();
+        let ports = [56324u16.to_be_bytes(), 1080u16.to_be_bytes()].concat();
+        let tlv = [0x04, 0x00, 0x01, 0xFF];
+        assert_eq!(
+            Some("192.168.0.1:56324".parse().unwrap()),
+            read_from(&v2_header(0x21, 0x11, &[ipv4.as_slice(), &ports, &tlv].concat()), b"\x05\x01\x00")
+                .await
+                .unwrap()
+        );
+
+        let ipv6 = [
+            "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets(),
+            "2001:db8::2".parse::<Ipv6Addr>().unwrap().octets(),
+        ]
+        .concat();
+        assert_eq!(
+            Some("[2001:db8::1]:56324".parse().unwrap()),
+            read_from(&v2_header(0x21, 0x21, &[ipv6.as_slice(), &ports].concat()), b"GET")
+                .await
+                .unwrap()
+        );
+
+        // Health checks of the balancer itself and clients connected over UNIX sockets.
+        assert_eq!(None, read_from(&v2_header(0x20, 0x00, &[]), b"").await.unwrap());
+        assert_eq!(None, read_from(&v2_header(0x21, 0x31, &[0u8; 216]), b"").await.unwrap());
+
+        for malformed in [
+            v2_header(0x11, 0x11, &[ipv4.as_slice(), &ports].concat()),
+            v2_header(0x22, 0x11, &[ipv4.as_slice(), &ports].concat()),
+            v2_header(0x21, 0x21, &[ipv4.as_slice(), &ports].concat()),
+        ] {
+            assert!(read_from(&malformed, b"").await.is_err(), "{:?} should be refused", malformed);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::common::error::LurkError;
+use anyhow::{bail, Result};
+use std::{collections::HashMap, fmt, net::IpAddr, sync::Mutex, time::Instant};
+
+/// Token bucket of the client: tokens left and the moment they have been refilled last time.
+struct LurkTokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Rate of new connections every client IP is allowed to open. Every client has a bucket of
+/// ```burst``` tokens, which is refilled at ```rate``` tokens per second, and every connection
+/// takes one token. Connections are throttled while the bucket of their client is empty.
+pub struct LurkConnectionRateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, LurkTokenBucket>>,
+}
+
+impl LurkConnectionRateLimiter {
+    /// Number of tracked clients, after which the ones with refilled buckets are forgotten.
+    const PRUNE_THRESHOLD: usize = 4096;
+
+    pub fn new(rate: u32, burst: u32) -> LurkConnectionRateLimiter {
+        debug_assert!(rate > 0 && burst > 0, "rate and burst should be positive");
+        LurkConnectionRateLimiter {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take token for the new connection of the client, unless its bucket is empty.
+    pub fn check(&self, ip: IpAddr) -> Result<()> {
+        let ip = ip.to_canonical();
+        if !self.acquire(ip, Instant::now()) {
+            bail!(LurkError::ClientThrottled(ip))
+        }
+        Ok(())
+    }
+
+    fn acquire(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().expect("connection rate buckets lock is poisoned");
+        if buckets.len() >= Self::PRUNE_THRESHOLD {
+            // Forgotten client gets the full bucket back, the same as the one it would have had by now.
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * self.rate < self.burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(LurkTokenBucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl fmt::Debug for LurkConnectionRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LurkConnectionRateLimiter")
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for LurkConnectionRateLimiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.rate == other.rate && self.burst == other.burst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn throttle_connection_bursts() {
+        let limiter = LurkConnectionRateLimiter::new(2, 3);
+        let (alice, bob) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let started = Instant::now();
+        let at = |millis: u64| started + Duration::from_millis(millis);
+
+        assert_eq!(
+            vec![true, true, true, false],
+            (0..4).map(|_| limiter.acquire(alice, at(0))).collect::<Vec<_>>()
+        );
+        assert!(limiter.acquire(bob, at(0)));
+
+        // Half a second refills single token.
+        assert!(!limiter.acquire(alice, at(400)));
+        assert!(limiter.acquire(alice, at(500)));
+        assert!(!limiter.acquire(alice, at(500)));
+
+        // Bucket isn't refilled above the burst.
+        assert_eq!(
+            vec![true, true, true, false],
+            (0..4).map(|_| limiter.acquire(alice, at(60_000))).collect::<Vec<_>>()
+        );
+
+        let limiter = LurkConnectionRateLimiter::new(1, 1);
+        assert!(limiter.check(alice).is_ok());
+        assert_eq!(
+            Some(&LurkError::ClientThrottled(alice)),
+            limiter
+                .check("::ffff:10.0.0.1".parse().unwrap())
+                .expect_err("client should be throttled")
+                .downcast_ref::<LurkError>()
+        );
+    }
+}
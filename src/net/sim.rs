@@ -0,0 +1,202 @@
+//! Simulated network link for deterministic tests of the protocol and tunnel paths.
+//!
+//! Both ends of the link are in-memory streams, while bytes between them are carried
+//! by the "wire" tasks, which delay, lose (retransmit later) and hold them back during
+//! partitions. All delays are driven by tokio timers, so tests running with the paused
+//! clock (```#[tokio::test(start_paused = true)]```) finish instantly and observe the
+//! same virtual timings every run.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    sync::watch,
+    time::sleep,
+};
+
+/// Faults of the link, same in both directions.
+///
+/// **Fields**:
+/// * ```latency``` - one-way delay of every chunk
+/// * ```loss_rate``` - share of chunks which are lost and delivered after ```retransmit_timeout```
+/// * ```window``` - bytes in flight a sender could have before its writes are blocked
+/// * ```seed``` - seed of the losses, so they are the same every run
+///
+#[derive(Debug, Clone, Copy)]
+pub struct LurkSimLinkOptions {
+    pub latency: Duration,
+    pub loss_rate: f64,
+    pub retransmit_timeout: Duration,
+    pub window: usize,
+    pub seed: u64,
+}
+
+impl Default for LurkSimLinkOptions {
+    fn default() -> LurkSimLinkOptions {
+        LurkSimLinkOptions {
+            latency: Duration::ZERO,
+            loss_rate: 0.0,
+            retransmit_timeout: Duration::from_millis(200),
+            window: 64 * 1024,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LurkSimLinkState {
+    Up,
+    Partitioned,
+    Reset,
+}
+
+/// Switch of the link state, shared by the test and the wire tasks.
+/// Link keeps working once the switch is dropped.
+pub struct LurkSimLinkControl {
+    state: Arc<watch::Sender<LurkSimLinkState>>,
+}
+
+impl LurkSimLinkControl {
+    /// Hold bytes back in both directions until the link is healed.
+    pub fn partition(&self) {
+        self.state.send_replace(LurkSimLinkState::Partitioned);
+    }
+
+    pub fn heal(&self) {
+        self.state.send_replace(LurkSimLinkState::Up);
+    }
+
+    /// Break the link, both ends observe the closed connection.
+    pub fn reset(&self) {
+        self.state.send_replace(LurkSimLinkState::Reset);
+    }
+}
+
+/// Create the link. Returns both of its ends and the switch of its state.
+pub fn link(options: LurkSimLinkOptions) -> (DuplexStream, DuplexStream, LurkSimLinkControl) {
+    let (local, local_wire) = duplex(options.window);
+    let (remote, remote_wire) = duplex(options.window);
+    let state = Arc::new(watch::channel(LurkSimLinkState::Up).0);
+
+    let (local_reader, local_writer) = split(local_wire);
+    let (remote_reader, remote_writer) = split(remote_wire);
+    tokio::spawn(carry(local_reader, remote_writer, options, Arc::clone(&state), options.seed));
+    tokio::spawn(carry(
+        remote_reader,
+        local_writer,
+        options,
+        Arc::clone(&state),
+        options.seed.wrapping_add(1),
+    ));
+
+    (local, remote, LurkSimLinkControl { state })
+}
+
+/// Carry bytes in one direction until either the sender closes its end or the link is reset.
+async fn carry(
+    mut from: ReadHalf<DuplexStream>,
+    mut to: WriteHalf<DuplexStream>,
+    options: LurkSimLinkOptions,
+    switch: Arc<watch::Sender<LurkSimLinkState>>,
+    seed: u64,
+) {
+    let mut state = switch.subscribe();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut buf = vec![0u8; options.window];
+
+    loop {
+        // Sender is blocked once the window is full, since nothing is read during the partition.
+        if !wait_until_up(&mut state).await {
+            return;
+        }
+        let n = tokio::select! {
+            read = from.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            },
+            _ = state.wait_for(|state| *state == LurkSimLinkState::Reset) => return,
+        };
+
+        let mut delay = options.latency;
+        if rng.gen_bool(options.loss_rate) {
+            delay += options.retransmit_timeout;
+        }
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = state.wait_for(|state| *state == LurkSimLinkState::Reset) => return,
+        }
+
+        if !wait_until_up(&mut state).await || to.write_all(&buf[..n]).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = to.shutdown().await;
+}
+
+/// Wait while the link is partitioned. Returns false once it's reset.
+async fn wait_until_up(state: &mut watch::Receiver<LurkSimLinkState>) -> bool {
+    match state.wait_for(|state| *state != LurkSimLinkState::Partitioned).await {
+        Ok(state) => *state == LurkSimLinkState::Up,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_and_partition() {
+        let options = LurkSimLinkOptions {
+            latency: Duration::from_millis(30),
+            ..LurkSimLinkOptions::default()
+        };
+        let (mut local, mut remote, control) = link(options);
+
+        let started = Instant::now();
+        local.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf);
+        assert_eq!(Duration::from_millis(30), started.elapsed());
+
+        // Nothing is delivered until the partition is healed.
+        control.partition();
+        local.write_all(b"pong").await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(10), remote.read_exact(&mut buf))
+            .await
+            .is_err());
+        control.heal();
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"pong", &buf);
+
+        control.reset();
+        assert_eq!(0, remote.read(&mut buf).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn losses_are_reproducible() {
+        let options = LurkSimLinkOptions {
+            latency: Duration::from_millis(10),
+            loss_rate: 0.3,
+            seed: 7,
+            ..LurkSimLinkOptions::default()
+        };
+
+        let mut elapsed = Vec::new();
+        for _ in 0..2 {
+            let (mut local, mut remote, _control) = link(options);
+            let started = Instant::now();
+            for _ in 0..20 {
+                local.write_all(b"x").await.unwrap();
+                remote.read_exact(&mut [0u8; 1]).await.unwrap();
+            }
+            elapsed.push(started.elapsed());
+        }
+
+        assert_eq!(elapsed[0], elapsed[1]);
+        assert!(elapsed[0] > Duration::from_millis(200), "Some chunks should be retransmitted");
+    }
+}
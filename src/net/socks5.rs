@@ -0,0 +1,289 @@
+#[cfg(feature = "upstream-tls")]
+use super::upstream_tls::LurkUpstreamTls;
+use super::{
+    tcp::{self, LurkOutboundStream, TcpConnectionOptions},
+    Address,
+};
+use crate::{
+    auth::LurkAuthMethod,
+    common::error::LurkError,
+    proto::socks5::{
+        request::{HandshakeRequest, PasswordAuthRequest, RelayRequest},
+        response::{HandshakeResponse, PasswordAuthResponse, RelayResponse},
+        Command, ReplyStatus,
+    },
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::timeout,
+};
+
+/// Negotiate authentication method with SOCKS5 proxy and authenticate,
+/// with password if ```credentials``` are passed.
+pub async fn handshake<T>(stream: &mut T, credentials: Option<(&str, &str)>) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let auth_method = match credentials {
+        Some(_) => LurkAuthMethod::Password,
+        None => LurkAuthMethod::None,
+    };
+    HandshakeRequest::new(HashSet::from([auth_method])).write_to(stream).await?;
+    match HandshakeResponse::read_from(stream).await?.auth_method() {
+        Some(selected) => ensure!(selected == auth_method, "proxy has selected unexpected method {:?}", selected),
+        None => bail!("proxy hasn't accepted {:?} authentication", auth_method),
+    }
+
+    if let Some((user, password)) = credentials {
+        PasswordAuthRequest::new(user, password).write_to(stream).await?;
+        ensure!(
+            PasswordAuthResponse::read_from(stream).await?.succeeded(),
+            "proxy has rejected credentials of '{}'",
+            user
+        );
+    }
+
+    Ok(())
+}
+
+/// Ask authenticated SOCKS5 proxy to connect to the ```address```. Refusal is returned as the reply.
+pub async fn request_connect<T>(stream: &mut T, address: Address) -> Result<RelayResponse>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    RelayRequest::new(Command::TCPConnect, address).write_to(stream).await?;
+    RelayResponse::read_from(stream).await
+}
+
+/// Parent SOCKS5 proxy the destinations are connected through instead of connecting to them directly.
+///
+/// **Fields**:
+/// * ```addr``` - address of the proxy
+/// * ```credentials``` - username and password, if the proxy requires authentication
+/// * ```domains``` - domains (along with their subdomains) connected through the proxy, all destinations if empty
+/// * ```tls``` - TLS client the proxy is connected with, plain TCP is used if unset
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkUpstreamProxy {
+    addr: SocketAddr,
+    credentials: Option<(String, String)>,
+    domains: Vec<String>,
+    #[cfg(feature = "upstream-tls")]
+    tls: Option<LurkUpstreamTls>,
+}
+
+impl LurkUpstreamProxy {
+    /// Time given to the proxy to authenticate and connect to the destination.
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(addr: SocketAddr) -> LurkUpstreamProxy {
+        LurkUpstreamProxy {
+            addr,
+            credentials: None,
+            domains: Vec::new(),
+            #[cfg(feature = "upstream-tls")]
+            tls: None,
+        }
+    }
+
+    pub fn set_credentials(&mut self, user: impl Into<String>, password: impl Into<String>) -> &mut LurkUpstreamProxy {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    pub fn set_domains(&mut self, domains: impl IntoIterator<Item = String>) -> &mut LurkUpstreamProxy {
+        self.domains = domains
+            .into_iter()
+            .map(|domain| domain.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    #[cfg(feature = "upstream-tls")]
+    pub fn set_tls(&mut self, tls: LurkUpstreamTls) -> &mut LurkUpstreamProxy {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Returns true if the destination is connected through the proxy. Destinations
+    /// given by IP address are connected through it only if all of them are.
+    pub fn routes(&self, address: &Address) -> bool {
+        if self.domains.is_empty() {
+            return true;
+        }
+        let Address::DomainName(name, _) = address else {
+            return false;
+        };
+
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.iter().any(|domain| {
+            name.strip_suffix(domain.as_str())
+                .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+        })
+    }
+
+    /// Establish connection with the destination through the proxy. Domain names are resolved by the proxy.
+    /// TLS handshake with the proxy, if it's connected over TLS, is given the same time as the SOCKS5 one.
+    pub async fn connect(&self, address: &Address, tcp_options: &TcpConnectionOptions) -> Result<LurkOutboundStream> {
+        let tcp_stream = tcp::establish_tcp_connection_with_opts(self.addr, tcp_options).await?;
+        let credentials = self.credentials.as_ref().map(|(user, password)| (user.as_str(), password.as_str()));
+
+        let (stream, response) = timeout(Self::HANDSHAKE_TIMEOUT, async {
+            #[cfg(feature = "upstream-tls")]
+            let mut stream = match &self.tls {
+                Some(tls) => LurkOutboundStream::Tls(Box::new(tls.connect(self.addr, tcp_stream).await?)),
+                None => LurkOutboundStream::from(tcp_stream),
+            };
+            #[cfg(not(feature = "upstream-tls"))]
+            let mut stream = LurkOutboundStream::from(tcp_stream);
+
+            handshake(&mut stream, credentials).await?;
+            let response = request_connect(&mut stream, address.clone()).await?;
+            Ok::<_, anyhow::Error>((stream, response))
+        })
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "upstream proxy {} hasn't connected to {} in {:?}",
+                self.addr,
+                address,
+                Self::HANDSHAKE_TIMEOUT
+            )
+        })??;
+
+        match response.status() {
+            ReplyStatus::Succeeded => Ok(stream),
+            status => bail!(LurkError::UpstreamProxyRefused(address.to_string(), status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{LurkRequest, LurkResponse};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn route_selected_domains() {
+        let mut upstream = LurkUpstreamProxy::new("127.0.0.1:1080".parse().unwrap());
+        let domain = |name: &str| Address::DomainName(name.to_owned(), 443);
+        let ip = Address::SocketAddress("10.0.0.1:443".parse().unwrap());
+        assert!(upstream.routes(&domain("example.com")));
+        assert!(upstream.routes(&ip));
+
+        upstream.set_domains([".Example.com".to_owned()]);
+        assert!(upstream.routes(&domain("example.com")));
+        assert!(upstream.routes(&domain("www.EXAMPLE.com.")));
+        assert!(!upstream.routes(&domain("badexample.com")));
+        assert!(!upstream.routes(&domain("example.org")));
+        assert!(!upstream.routes(&ip));
+    }
+
+    #[tokio::test]
+    async fn connect_through_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut upstream = LurkUpstreamProxy::new(listener.local_addr().unwrap());
+        upstream.set_credentials("alice", "secret");
+        let address = Address::DomainName("example.com".to_owned(), 443);
+
+        let server_address = address.clone();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let handshake = HandshakeRequest::read_from(&mut stream).await.unwrap();
+            assert!(handshake.auth_methods().contains(&LurkAuthMethod::Password));
+            HandshakeResponse::builder()
+                .with_auth_method(LurkAuthMethod::Password)
+                .build()
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+
+            let auth = PasswordAuthRequest::read_from(&mut stream).await.unwrap();
+            assert_eq!(("alice", "secret"), (auth.username(), auth.password()));
+            PasswordAuthResponse::builder()
+                .with_success()
+                .build()
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+
+            let request = RelayRequest::read_from(&mut stream).await.unwrap();
+            assert_eq!(&server_address, request.endpoint_address());
+            RelayResponse::builder()
+                .with_status(ReplyStatus::HostUnreachable)
+                .with_bound_address("0.0.0.0:0".parse().unwrap())
+                .build()
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+        });
+
+        let err = upstream.connect(&address, &tcp::default_tcp_options()).await.unwrap_err();
+        assert_eq!(
+            Some(&LurkError::UpstreamProxyRefused(address.to_string(), ReplyStatus::HostUnreachable)),
+            err.downcast_ref::<LurkError>()
+        );
+        server.await.unwrap();
+    }
+
+    #[cfg(feature = "upstream-tls")]
+    #[tokio::test]
+    async fn connect_through_tls_upstream() {
+        use crate::net::upstream_tls::{self, LurkUpstreamTlsOptions};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (acceptor, _, pin) = upstream_tls::self_signed_acceptor("proxy.lurk.test");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        let address = Address::DomainName("example.com".to_owned(), 443);
+
+        let server_address = address.clone();
+        let server = tokio::spawn(async move {
+            // The first client doesn't trust the certificate and drops the connection.
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            assert!(acceptor.accept(tcp_stream).await.is_err());
+
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(tcp_stream).await.unwrap();
+            HandshakeRequest::read_from(&mut stream).await.unwrap();
+            HandshakeResponse::builder()
+                .with_auth_method(LurkAuthMethod::None)
+                .build()
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+
+            let request = RelayRequest::read_from(&mut stream).await.unwrap();
+            assert_eq!(&server_address, request.endpoint_address());
+            RelayResponse::builder()
+                .with_status(ReplyStatus::Succeeded)
+                .with_bound_address("0.0.0.0:0".parse().unwrap())
+                .build()
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut options = LurkUpstreamTlsOptions::new();
+        let mut upstream = LurkUpstreamProxy::new(upstream_addr);
+        upstream.set_tls(LurkUpstreamTls::new(options.set_pins([[0u8; 32]]).clone()).unwrap());
+        let err = upstream.connect(&address, &tcp::default_tcp_options()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LurkError>(),
+            Some(LurkError::UpstreamTlsVerificationFailed(..))
+        ));
+
+        upstream.set_tls(LurkUpstreamTls::new(options.set_pins([pin]).clone()).unwrap());
+        let mut stream = upstream.connect(&address, &tcp::default_tcp_options()).await.unwrap();
+        assert!(matches!(stream, LurkOutboundStream::Tls(_)));
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(b"pong", reply.as_slice());
+        server.await.unwrap();
+    }
+}
@@ -1,21 +1,42 @@
-use anyhow::Result;
-use socket2::{SockRef, TcpKeepalive};
-use std::time::Duration;
-use tokio::net::{TcpStream, ToSocketAddrs};
+use anyhow::{bail, Result};
+use log::debug;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs},
+};
 
 /// Different TCP connection options.
 ///
 /// **Fields**:
 /// * ```keep_alive``` - setting for TCP keepalive procedure
+/// * ```local_ip``` - address of the local interface the connection is established from
+/// * ```fast_open``` - send data along with SYN if the endpoint has issued TFO cookie before (TCP_FASTOPEN_CONNECT, Linux only)
+/// * ```mptcp``` - establish Multipath TCP connection, falling back to plain TCP if the kernel doesn't support it (Linux only)
 ///
-///
+#[derive(Clone)]
 pub struct TcpConnectionOptions {
     keep_alive: Option<TcpKeepalive>,
+    local_ip: Option<IpAddr>,
+    fast_open: bool,
+    mptcp: bool,
 }
 
 impl TcpConnectionOptions {
     pub fn new() -> TcpConnectionOptions {
-        TcpConnectionOptions { keep_alive: None }
+        TcpConnectionOptions {
+            keep_alive: None,
+            local_ip: None,
+            fast_open: false,
+            mptcp: false,
+        }
     }
 
     pub fn set_keepalive(&mut self, keep_alive: TcpKeepalive) -> &mut TcpConnectionOptions {
@@ -24,6 +45,22 @@ impl TcpConnectionOptions {
         self
     }
 
+    pub fn set_local_ip(&mut self, local_ip: IpAddr) -> &mut TcpConnectionOptions {
+        debug_assert!(self.local_ip.is_none(), "should be unset");
+        self.local_ip = Some(local_ip);
+        self
+    }
+
+    pub fn set_fast_open(&mut self, fast_open: bool) -> &mut TcpConnectionOptions {
+        self.fast_open = fast_open;
+        self
+    }
+
+    pub fn set_mptcp(&mut self, mptcp: bool) -> &mut TcpConnectionOptions {
+        self.mptcp = mptcp;
+        self
+    }
+
     pub fn apply_to(&self, tcp_stream: &mut TcpStream) -> Result<()> {
         let tcp_sock_ref = SockRef::from(&tcp_stream);
 
@@ -33,6 +70,73 @@ impl TcpConnectionOptions {
 
         Ok(())
     }
+
+    /// Whether the socket has to be set up before connecting, instead of being just connected.
+    fn is_socket_tuned(&self) -> bool {
+        self.local_ip.is_some() || self.fast_open || self.mptcp
+    }
+
+    /// Create socket connecting to ```remote_addr``` with options which have to be set before connecting.
+    /// Options unsupported by the kernel are skipped, so the connection is established anyway.
+    fn create_socket(&self, remote_addr: SocketAddr) -> Result<TcpSocket> {
+        let domain = Domain::for_address(remote_addr);
+        let socket = match self.mptcp {
+            true => create_mptcp_socket(domain).or_else(|err| {
+                debug!("MPTCP socket isn't created ({}), falling back to TCP", err);
+                Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+            })?,
+            false => Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?,
+        };
+        socket.set_nonblocking(true)?;
+
+        if self.fast_open {
+            if let Err(err) = set_tcp_fastopen_connect(&socket) {
+                debug!("TCP Fast Open isn't enabled for connection to {}: {}", remote_addr, err);
+            }
+        }
+        if let Some(local_ip) = self.local_ip {
+            socket.bind(&SocketAddr::new(local_ip, 0).into())?;
+        }
+
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_mptcp_socket(domain: Domain) -> io::Result<Socket> {
+    Socket::new(domain, Type::STREAM, Some(Protocol::MPTCP))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_mptcp_socket(_domain: Domain) -> io::Result<Socket> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_fastopen_connect(socket: &Socket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let enabled: libc::c_int = 1;
+    // SAFETY: descriptor is owned by the alive socket and the option value is c_int, as the kernel expects.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tcp_fastopen_connect(_socket: &Socket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
 }
 
 /// Establish TCP connection with passed ```endpoint```.
@@ -40,7 +144,10 @@ impl TcpConnectionOptions {
 /// Input ```tcp_opts``` are applied to created TCP socket right after stream creation.
 pub async fn establish_tcp_connection_with_opts(addr: impl ToSocketAddrs, tcp_opts: &TcpConnectionOptions) -> Result<TcpStream> {
     // Establish TCP connection with the endpoint.
-    let mut tcp_stream = TcpStream::connect(addr).await.map_err(anyhow::Error::from)?;
+    let mut tcp_stream = match tcp_opts.is_socket_tuned() {
+        true => connect_tuned(addr, tcp_opts).await?,
+        false => TcpStream::connect(addr).await.map_err(anyhow::Error::from)?,
+    };
 
     // Apply passed options to created TCP stream.
     tcp_opts.apply_to(&mut tcp_stream)?;
@@ -50,7 +157,12 @@ pub async fn establish_tcp_connection_with_opts(addr: impl ToSocketAddrs, tcp_op
 
 /// Establish TCP connection with passed ```endpoint``` with default options.
 pub async fn establish_tcp_connection(addr: impl ToSocketAddrs) -> Result<TcpStream> {
-    // Create TCP options.
+    // Establish TCP connection with the target endpoint.
+    establish_tcp_connection_with_opts(addr, &default_tcp_options()).await
+}
+
+/// Options of the connections established with the endpoints by default.
+pub fn default_tcp_options() -> TcpConnectionOptions {
     let mut tcp_opts = TcpConnectionOptions::new();
     tcp_opts.set_keepalive(
         TcpKeepalive::new()
@@ -58,62 +170,650 @@ pub async fn establish_tcp_connection(addr: impl ToSocketAddrs) -> Result<TcpStr
             .with_interval(Duration::from_secs(30)) // 30 sec
             .with_retries(5),
     );
+    tcp_opts
+}
 
-    // Establish TCP connection with the target endpoint.
-    establish_tcp_connection_with_opts(addr, &tcp_opts).await
+/// Stream the destination is connected with: directly or through the upstream proxy, over TLS if the proxy is connected so.
+#[derive(Debug)]
+pub enum LurkOutboundStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "upstream-tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+impl LurkOutboundStream {
+    /// TCP stream the data are sent over.
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            LurkOutboundStream::Tcp(stream) => stream,
+            #[cfg(feature = "upstream-tls")]
+            LurkOutboundStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_stream().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_stream().peer_addr()
+    }
+}
+
+impl From<TcpStream> for LurkOutboundStream {
+    fn from(stream: TcpStream) -> Self {
+        LurkOutboundStream::Tcp(stream)
+    }
+}
+
+impl AsyncRead for LurkOutboundStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LurkOutboundStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "upstream-tls")]
+            LurkOutboundStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LurkOutboundStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LurkOutboundStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "upstream-tls")]
+            LurkOutboundStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LurkOutboundStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "upstream-tls")]
+            LurkOutboundStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LurkOutboundStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "upstream-tls")]
+            LurkOutboundStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connect to the first reachable address of the endpoint with the socket set up by ```tcp_opts```.
+/// If the local IP is set, only addresses of the same family are tried.
+async fn connect_tuned(addr: impl ToSocketAddrs, tcp_opts: &TcpConnectionOptions) -> Result<TcpStream> {
+    let mut last_err = None;
+    for remote_addr in lookup_host(addr)
+        .await?
+        .filter(|remote_addr| tcp_opts.local_ip.is_none_or(|local_ip| remote_addr.is_ipv4() == local_ip.is_ipv4()))
+    {
+        let socket = tcp_opts.create_socket(remote_addr)?;
+        match socket.connect(remote_addr).await {
+            Ok(tcp_stream) => return Ok(tcp_stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    match (last_err, tcp_opts.local_ip) {
+        (Some(err), _) => Err(err.into()),
+        (None, Some(local_ip)) => bail!("endpoint has no addresses reachable from {}", local_ip),
+        (None, None) => bail!("endpoint has no addresses"),
+    }
 }
 
 pub mod listener {
 
     use super::connection::{LurkTcpConnection, LurkTcpConnectionFactory, LurkTcpConnectionLabel};
-    use crate::net::resolve_sockaddr;
-    use anyhow::Result;
+    use crate::{
+        common::error::LurkError,
+        net::{
+            bans::LurkClientBans,
+            client_limits::{LurkClientConnection, LurkClientConnections},
+            geoip::LurkCountryAccess,
+            proxy_protocol,
+            rate_limit::LurkConnectionRateLimiter,
+            resolve_sockaddr,
+        },
+    };
+    use anyhow::{bail, Result};
+    use clap::ValueEnum;
+    use ipnet::IpNet;
     use socket2::{Domain, Socket, Type};
-    use std::net::SocketAddr;
-    use tokio::net::{TcpListener, ToSocketAddrs};
+    use std::{
+        future::poll_fn,
+        io,
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+        task::Poll,
+        time::Duration,
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream, ToSocketAddrs},
+        time::timeout,
+    };
+
+    /// How redirected connections reach the transparent listener.
+    #[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+    pub enum LurkTransparentMode {
+        /// Destination is translated to the listener (iptables REDIRECT), the original one is looked up in the conntrack (SO_ORIGINAL_DST)
+        #[default]
+        Redirect,
+        /// Connections to foreign addresses are delivered intact (iptables TPROXY), which requires CAP_NET_ADMIN (IP_TRANSPARENT)
+        Tproxy,
+    }
+
+    /// Settings of the listening TCP socket.
+    ///
+    /// **Fields**:
+    /// * ```backlog``` - maximum length of the queue of connections waiting to be accepted
+    /// * ```reuse_address``` - allow to bind the address while old connections to it are in TIME_WAIT (SO_REUSEADDR)
+    /// * ```reuse_port``` - allow several listeners to bind the same address, so the kernel balances connections between them (SO_REUSEPORT)
+    /// * ```defer_accept``` - wake up the listener only once the client has sent data or the timeout has expired (TCP_DEFER_ACCEPT, Linux only)
+    /// * ```recv_buffer_size``` - size of the receive buffer inherited by accepted connections (SO_RCVBUF)
+    /// * ```send_buffer_size``` - size of the send buffer inherited by accepted connections (SO_SNDBUF)
+    /// * ```dual_stack``` - accept IPv4 clients on the IPv6 socket as IPv4-mapped ones, otherwise it's IPv6-only (IPV6_V6ONLY)
+    /// * ```transparent``` - how redirected connections reach the listener, if it's the transparent one
+    /// * ```proxy_protocol``` - networks of the load balancers, which send PROXY protocol header before the client data
+    /// * ```client_access``` - client IPs connections are accepted from
+    ///
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LurkTcpListenerOptions {
+        backlog: u32,
+        reuse_address: bool,
+        reuse_port: bool,
+        defer_accept: Option<Duration>,
+        recv_buffer_size: Option<usize>,
+        send_buffer_size: Option<usize>,
+        dual_stack: bool,
+        transparent: Option<LurkTransparentMode>,
+        proxy_protocol: Vec<IpNet>,
+        client_access: LurkClientAccess,
+    }
+
+    impl LurkTcpListenerOptions {
+        pub const DEFAULT_BACKLOG: u32 = 1024;
+
+        pub fn new(backlog: u32) -> LurkTcpListenerOptions {
+            LurkTcpListenerOptions {
+                backlog,
+                reuse_address: false,
+                reuse_port: false,
+                defer_accept: None,
+                recv_buffer_size: None,
+                send_buffer_size: None,
+                dual_stack: false,
+                transparent: None,
+                proxy_protocol: Vec::new(),
+                client_access: LurkClientAccess::default(),
+            }
+        }
+
+        pub fn set_reuse_address(&mut self, reuse_address: bool) -> &mut LurkTcpListenerOptions {
+            self.reuse_address = reuse_address;
+            self
+        }
+
+        pub fn set_reuse_port(&mut self, reuse_port: bool) -> &mut LurkTcpListenerOptions {
+            self.reuse_port = reuse_port;
+            self
+        }
+
+        pub fn set_defer_accept(&mut self, timeout: Duration) -> &mut LurkTcpListenerOptions {
+            debug_assert!(self.defer_accept.is_none(), "should be unset");
+            self.defer_accept = Some(timeout);
+            self
+        }
+
+        /// Buffer is set before listening, so the TCP window scale negotiated with the clients accounts for it.
+        pub fn set_recv_buffer_size(&mut self, size: usize) -> &mut LurkTcpListenerOptions {
+            debug_assert!(self.recv_buffer_size.is_none(), "should be unset");
+            self.recv_buffer_size = Some(size);
+            self
+        }
+
+        pub fn set_send_buffer_size(&mut self, size: usize) -> &mut LurkTcpListenerOptions {
+            debug_assert!(self.send_buffer_size.is_none(), "should be unset");
+            self.send_buffer_size = Some(size);
+            self
+        }
+
+        /// IPv6-only sockets don't conflict with the IPv4 ones bound to the same port, whatever
+        /// the system default (net.ipv6.bindv6only on Linux) is. Ignored by IPv4 sockets.
+        pub fn set_dual_stack(&mut self, dual_stack: bool) -> &mut LurkTcpListenerOptions {
+            self.dual_stack = dual_stack;
+            self
+        }
 
-    const TCP_LISTEN_BACKLOG: i32 = 1024;
+        /// Connections accepted by the transparent listener are tunneled to their original destinations
+        /// as is, without any proxy protocol handshake.
+        pub fn set_transparent(&mut self, mode: LurkTransparentMode) -> &mut LurkTcpListenerOptions {
+            debug_assert!(self.transparent.is_none(), "should be unset");
+            self.transparent = Some(mode);
+            self
+        }
+
+        /// Connections from the load balancers are served on behalf of the clients conveyed by them, which are
+        /// checked against client access instead. Connections from anyone else are never expected to have the header.
+        pub fn set_proxy_protocol(&mut self, load_balancers: Vec<IpNet>) -> &mut LurkTcpListenerOptions {
+            self.proxy_protocol = load_balancers;
+            self
+        }
+
+        pub fn set_client_access(&mut self, client_access: LurkClientAccess) -> &mut LurkTcpListenerOptions {
+            self.client_access = client_access;
+            self
+        }
+
+        pub fn client_access(&self) -> &LurkClientAccess {
+            &self.client_access
+        }
+
+        /// Apply options which have to be set before the socket is bound.
+        fn apply_to(&self, socket: &Socket, domain: Domain) -> io::Result<()> {
+            socket.set_reuse_address(self.reuse_address)?;
+            socket.set_reuse_port(self.reuse_port)?;
+
+            if domain == Domain::IPV6 {
+                socket.set_only_v6(!self.dual_stack)?;
+            }
+            if self.transparent == Some(LurkTransparentMode::Tproxy) {
+                set_ip_transparent(socket, domain)?;
+            }
+
+            // Clients of the transparent listener may wait for the destination to speak first.
+            if let Some(timeout) = self.defer_accept.filter(|_| self.transparent.is_none()) {
+                set_tcp_defer_accept(socket, timeout)?;
+            }
+            if let Some(size) = self.recv_buffer_size {
+                socket.set_recv_buffer_size(size)?;
+            }
+            if let Some(size) = self.send_buffer_size {
+                socket.set_send_buffer_size(size)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Default for LurkTcpListenerOptions {
+        fn default() -> Self {
+            LurkTcpListenerOptions::new(LurkTcpListenerOptions::DEFAULT_BACKLOG)
+        }
+    }
+
+    /// Networks clients are allowed and denied to connect from.
+    ///
+    /// **Fields**:
+    /// * ```allow``` - networks clients are allowed from, any client is if empty
+    /// * ```deny``` - networks clients are denied from, even if they are allowed
+    /// * ```countries``` - countries clients are allowed and denied from, checked once networks allow the client
+    /// * ```connection_rate``` - rate of new connections every allowed client may open, shared by the clones
+    /// * ```bans``` - clients banned for a while, checked once they are allowed, shared by the clones
+    /// * ```connections``` - connections open by every allowed client, limited per client IP and shared by the clones
+    ///
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct LurkClientAccess {
+        allow: Vec<IpNet>,
+        deny: Vec<IpNet>,
+        countries: Option<LurkCountryAccess>,
+        connection_rate: Option<Arc<LurkConnectionRateLimiter>>,
+        bans: Option<Arc<LurkClientBans>>,
+        connections: Option<Arc<LurkClientConnections>>,
+    }
+
+    impl LurkClientAccess {
+        pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> LurkClientAccess {
+            LurkClientAccess {
+                allow,
+                deny,
+                countries: None,
+                connection_rate: None,
+                bans: None,
+                connections: None,
+            }
+        }
+
+        pub fn set_countries(&mut self, countries: LurkCountryAccess) -> &mut LurkClientAccess {
+            self.countries = Some(countries);
+            self
+        }
+
+        /// Throttle new connections of every client to ```rate``` per second on average, with bursts of up to ```burst``` connections.
+        pub fn set_connection_rate(&mut self, rate: u32, burst: u32) -> &mut LurkClientAccess {
+            debug_assert!(self.connection_rate.is_none(), "should be unset");
+            self.connection_rate = Some(Arc::new(LurkConnectionRateLimiter::new(rate, burst)));
+            self
+        }
+
+        /// Drop connections of the clients, while they are banned.
+        pub fn set_bans(&mut self, bans: Arc<LurkClientBans>) -> &mut LurkClientAccess {
+            debug_assert!(self.bans.is_none(), "should be unset");
+            self.bans = Some(bans);
+            self
+        }
+
+        /// Limit number of connections every client IP has open at once to ```max_per_client```.
+        pub fn set_max_connections(&mut self, max_per_client: usize) -> &mut LurkClientAccess {
+            debug_assert!(self.connections.is_none(), "should be unset");
+            self.connections = Some(Arc::new(LurkClientConnections::new(max_per_client)));
+            self
+        }
+
+        /// IPv4 clients of dual-stack listeners are matched by their IPv4 addresses.
+        pub fn is_allowed(&self, ip: IpAddr) -> bool {
+            let ip = ip.to_canonical();
+            let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip));
+            allowed
+                && !self.deny.iter().any(|net| net.contains(&ip))
+                && self
+                    .countries
+                    .as_ref()
+                    .is_none_or(|countries| countries.denied_country(ip).is_none())
+        }
+
+        pub fn check(&self, ip: IpAddr) -> Result<()> {
+            if !self.is_allowed(ip) {
+                bail!(LurkError::ClientNotAllowed(ip))
+            }
+            if let Some(bans) = &self.bans {
+                bans.check(ip)?;
+            }
+            match &self.connection_rate {
+                Some(limiter) => limiter.check(ip),
+                None => Ok(()),
+            }
+        }
+
+        /// Account connection of the allowed client, unless it would exceed the limit of connections per client IP.
+        /// Connection is accounted until the returned guard is dropped. Nothing is accounted without the limit.
+        pub fn open_connection(&self, ip: IpAddr) -> Result<Option<LurkClientConnection>> {
+            self.connections.as_ref().map(|connections| connections.open(ip)).transpose()
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_tcp_defer_accept(socket: &Socket, timeout: Duration) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let secs = libc::c_int::try_from(timeout.as_secs()).unwrap_or(libc::c_int::MAX);
+        // SAFETY: descriptor is owned by the alive socket and the option value is c_int, as the kernel expects.
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_DEFER_ACCEPT,
+                &secs as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_tcp_defer_accept(_socket: &Socket, _timeout: Duration) -> io::Result<()> {
+        log::warn!("TCP_DEFER_ACCEPT is supported only on Linux, option is ignored");
+        Ok(())
+    }
+
+    /// Allow the socket to accept connections to foreign addresses (IP_TRANSPARENT), requires CAP_NET_ADMIN.
+    #[cfg(target_os = "linux")]
+    fn set_ip_transparent(socket: &Socket, domain: Domain) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let (level, name) = match domain {
+            Domain::IPV6 => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+            _ => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        };
+        let enabled: libc::c_int = 1;
+        // SAFETY: descriptor is owned by the alive socket and the option value is c_int, as the kernel expects.
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_ip_transparent(_socket: &Socket, _domain: Domain) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "TPROXY is supported only on Linux"))
+    }
+
+    /// Destination the client has connected to before its connection has been redirected to the listener.
+    /// Connections intercepted by TPROXY aren't translated, so they are accepted on the original destination itself.
+    #[cfg(target_os = "linux")]
+    fn original_destination(tcp_stream: &TcpStream) -> io::Result<SocketAddr> {
+        use std::os::fd::AsRawFd;
+
+        let local_addr = tcp_stream.local_addr()?;
+        let (level, name) = match local_addr {
+            SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+            SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+        };
+        // SAFETY: descriptor is owned by the alive stream, the kernel writes at most ```len``` bytes of the address.
+        let res = unsafe {
+            socket2::SockAddr::try_init(
+                |storage, len| match libc::getsockopt(tcp_stream.as_raw_fd(), level, name, storage.cast(), len) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                },
+            )
+        };
+
+        match res {
+            Ok((_, addr)) => addr
+                .as_socket()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "original destination isn't IP address")),
+            // Connection isn't tracked by the NAT (e.g. intercepted by TPROXY).
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ENOPROTOOPT)) => Ok(local_addr),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn original_destination(tcp_stream: &TcpStream) -> io::Result<SocketAddr> {
+        tcp_stream.local_addr()
+    }
+
+    /// Create non-blocking TCP listener bound to passed `bind_addr`.
+    pub fn bind_tcp_listener(bind_addr: SocketAddr, opts: &LurkTcpListenerOptions) -> Result<TcpListener> {
+        // Create TCP socket and set options
+        let domain = Domain::for_address(bind_addr);
+        let socket = Socket::new(domain, Type::STREAM, None)?;
+        opts.apply_to(&socket, domain)?;
+
+        // Bind TCP socket and mark it ready to accept incoming connections
+        socket.bind(&bind_addr.into())?;
+        socket.listen(i32::try_from(opts.backlog).unwrap_or(i32::MAX))?;
+        socket.set_nonblocking(true)?;
+
+        // Create tokio TCP listener from TCP socket
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
+    /// Returns true if acception has failed because the listening socket itself is unusable
+    /// (e.g. it's been shut down or closed), i.e. no more connections could be accepted from it.
+    pub fn is_listener_broken(err: &io::Error) -> bool {
+        #[cfg(target_os = "linux")]
+        if matches!(err.raw_os_error(), Some(libc::EBADF | libc::ENOTSOCK)) {
+            return true;
+        }
+
+        // Socket isn't listening anymore (EINVAL).
+        err.kind() == io::ErrorKind::InvalidInput
+    }
+
+    /// Sets the accepted TCP streams up as the connections dispatched to the handlers.
+    ///
+    /// It's shared by the tasks the accepted streams are set up in, so the clients, which are
+    /// slow to send their first bytes, don't hold up the listener accepting the others.
+    pub struct LurkTcpConnectionSetup {
+        local_addr: SocketAddr,
+        client_access: LurkClientAccess,
+        transparent: bool,
+        proxy_protocol: Vec<IpNet>,
+    }
+
+    impl LurkTcpConnectionSetup {
+        /// Time given to the load balancer to send PROXY protocol header.
+        const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+        /// Time given to the client to send its first bytes, which the protocol is detected by.
+        const PROTOCOL_DETECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+        /// Connections of the clients, which aren't allowed, are dropped before their protocol is detected.
+        /// Transparent listener doesn't wait for the client to send anything, since it could be the destination
+        /// who speaks first (e.g. SMTP or SSH).
+        ///
+        /// Connection is accounted to its client as soon as it's allowed, so the clients, which don't send anything,
+        /// count towards their limit too. Connection over the limit is still dispatched to the handler, which refuses
+        /// it the way the protocol allows.
+        pub async fn create_connection(&self, mut tcp_stream: TcpStream, peer_addr: SocketAddr) -> Result<LurkTcpConnection> {
+            let peer_addr = self.read_proxy_header(&mut tcp_stream, peer_addr).await?;
+            self.client_access.check(peer_addr.ip())?;
+            let client_connection = self.client_access.open_connection(peer_addr.ip());
+            let conn = if self.transparent {
+                let destination = original_destination(&tcp_stream)?;
+                if self.is_own_address(destination) {
+                    bail!(LurkError::ClientNotRedirected(peer_addr.ip()))
+                }
+                LurkTcpConnectionFactory::create_transparent_connection(tcp_stream, destination)?
+            } else {
+                let tcp_label = match timeout(
+                    Self::PROTOCOL_DETECTION_TIMEOUT,
+                    LurkTcpConnectionLabel::from_tcp_stream(&tcp_stream),
+                )
+                .await
+                {
+                    Ok(tcp_label) => tcp_label?,
+                    Err(_) => bail!(LurkError::ProtocolNotDetected(peer_addr.ip(), Self::PROTOCOL_DETECTION_TIMEOUT)),
+                };
+                LurkTcpConnectionFactory::create_connection(tcp_stream, tcp_label)?
+            };
+
+            Ok(conn.with_peer_addr(peer_addr).with_client_connection(client_connection))
+        }
+
+        /// Address of the client the connection is accepted on behalf of. It's conveyed by the PROXY protocol header,
+        /// if the peer is the load balancer, unless the balancer has opened the connection for itself (e.g. health check).
+        async fn read_proxy_header(&self, tcp_stream: &mut TcpStream, peer_addr: SocketAddr) -> Result<SocketAddr> {
+            let peer_ip = peer_addr.ip().to_canonical();
+            if !self.proxy_protocol.iter().any(|net| net.contains(&peer_ip)) {
+                return Ok(peer_addr);
+            }
+
+            match timeout(Self::PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(tcp_stream)).await {
+                Ok(Ok(client_addr)) => Ok(client_addr.unwrap_or(peer_addr)),
+                Ok(Err(err)) => bail!(LurkError::InvalidProxyHeader(peer_addr.ip(), err.to_string())),
+                Err(_) => bail!(LurkError::InvalidProxyHeader(
+                    peer_addr.ip(),
+                    format!("header hasn't been received within {:?}", Self::PROXY_HEADER_TIMEOUT)
+                )),
+            }
+        }
+
+        /// Whether the client has connected to the listener directly. Tunneling such connection
+        /// to its "original" destination would make the proxy connect to itself over and over.
+        fn is_own_address(&self, destination: SocketAddr) -> bool {
+            destination.port() == self.local_addr.port()
+                && (self.local_addr.ip().is_unspecified() || destination.ip().to_canonical() == self.local_addr.ip().to_canonical())
+        }
+    }
 
     /// Custom implementation of TCP listener.
-    #[allow(dead_code)]
     pub struct LurkTcpListener {
         inner: TcpListener,
+        setup: Arc<LurkTcpConnectionSetup>,
     }
 
     impl LurkTcpListener {
-        /// Binds TCP listener to passed `addr`.
+        /// Binds TCP listener to passed `addr` with default options.
         ///
+        #[cfg(test)]
         pub async fn bind(addr: impl ToSocketAddrs) -> Result<LurkTcpListener> {
+            LurkTcpListener::bind_with_opts(addr, &LurkTcpListenerOptions::default()).await
+        }
+
+        /// Binds TCP listener to passed `addr`. Input `opts` are applied to the listening socket.
+        ///
+        pub async fn bind_with_opts(addr: impl ToSocketAddrs, opts: &LurkTcpListenerOptions) -> Result<LurkTcpListener> {
             let bind_addr = resolve_sockaddr(addr).await?;
+            let inner = bind_tcp_listener(bind_addr, opts)?;
+            let setup = LurkTcpConnectionSetup {
+                local_addr: inner.local_addr()?,
+                client_access: opts.client_access.clone(),
+                transparent: opts.transparent.is_some(),
+                proxy_protocol: opts.proxy_protocol.clone(),
+            };
 
-            // Create TCP socket
-            let socket = Socket::new(Domain::for_address(bind_addr), Type::STREAM, None)?;
+            Ok(LurkTcpListener {
+                inner,
+                setup: Arc::new(setup),
+            })
+        }
+
+        /// Accept incoming TCP connection and set it up in place.
+        #[allow(dead_code)]
+        pub async fn accept(&mut self) -> Result<LurkTcpConnection> {
+            let (tcp_stream, peer_addr) = self.inner.accept().await?;
+            self.setup.create_connection(tcp_stream, peer_addr).await
+        }
 
-            // Bind TCP socket and mark it ready to accept incoming connections
-            socket.bind(&bind_addr.into())?;
-            socket.listen(TCP_LISTEN_BACKLOG)?;
+        /// Wait for incoming TCP connection and accept up to ```max_batch_size``` connections
+        /// in total: the rest are taken from the accept queue only if they are pending already.
+        /// Batch ends on the first failure, as the next attempts would most likely fail as well.
+        /// Returns result of each acception, accepted streams are meant to be set up by the caller.
+        pub async fn accept_batch(&mut self, max_batch_size: usize) -> Vec<io::Result<(TcpStream, SocketAddr)>> {
+            let mut accepted = vec![self.inner.accept().await];
 
-            // Set TCP options
-            socket.set_nonblocking(true)?;
+            while accepted.len() < max_batch_size && accepted.last().is_some_and(Result::is_ok) {
+                // Poll listener once without waiting for the next connection.
+                let pending = poll_fn(|cx| match self.inner.poll_accept(cx) {
+                    Poll::Ready(res) => Poll::Ready(Some(res)),
+                    Poll::Pending => Poll::Ready(None),
+                })
+                .await;
 
-            // Create tokio TCP listener from TCP socket
-            let inner: TcpListener = TcpListener::from_std(socket.into())?;
+                match pending {
+                    Some(res) => accepted.push(res),
+                    None => break,
+                }
+            }
 
-            Ok(LurkTcpListener { inner })
+            accepted
         }
 
-        /// Accept incoming TCP connection.
-        pub async fn accept(&mut self) -> Result<LurkTcpConnection> {
-            let (tcp_stream, _) = self.inner.accept().await?;
-            let tcp_label = LurkTcpConnectionLabel::from_tcp_stream(&tcp_stream).await?;
-
-            LurkTcpConnectionFactory::create_connection(tcp_stream, tcp_label)
+        /// Setup of the connections accepted by this listener.
+        pub fn connection_setup(&self) -> Arc<LurkTcpConnectionSetup> {
+            Arc::clone(&self.setup)
         }
 
         /// Returns local address that this listener is binded to.
-        #[allow(dead_code)]
         pub fn local_addr(&self) -> SocketAddr {
-            self.inner.local_addr().expect("listener doesn't have local address")
+            self.setup.local_addr
+        }
+
+        /// Stop listening, as the OS would do it on the interface failure.
+        #[cfg(test)]
+        pub fn shutdown(&self) {
+            socket2::SockRef::from(&self.inner)
+                .shutdown(std::net::Shutdown::Read)
+                .expect("listener should be shut down");
         }
     }
 
@@ -121,6 +821,7 @@ pub mod listener {
     mod tests {
 
         use super::*;
+        use crate::net::geoip;
         use futures::{stream::FuturesUnordered, StreamExt, TryFutureExt};
         use std::time::Duration;
         use tokio::{
@@ -132,6 +833,176 @@ pub mod listener {
         // :0 tells the OS to pick an open port.
         const TEST_BIND_IPV4: &str = "127.0.0.1:0";
 
+        #[tokio::test]
+        async fn accept_pending_connections_in_batch() {
+            let mut listener = LurkTcpListener::bind(TEST_BIND_IPV4).await.expect("Expect binded listener");
+            let listener_addr = listener.local_addr();
+
+            let mut clients = Vec::new();
+            for _ in 0..3 {
+                let mut client = TcpStream::connect(listener_addr).await.unwrap();
+                client.write_all(&[0x05]).await.unwrap();
+                clients.push(client);
+            }
+
+            // Let all connections land in the accept queue.
+            sleep(Duration::from_millis(100)).await;
+
+            let first_batch = listener.accept_batch(2).await;
+            assert_eq!(2, first_batch.len());
+            assert!(first_batch.iter().all(|accepted| accepted.is_ok()));
+
+            // Only one connection is left pending.
+            let second_batch = listener.accept_batch(10).await;
+            assert_eq!(1, second_batch.len());
+        }
+
+        #[tokio::test]
+        async fn bind_with_options() {
+            let mut opts = LurkTcpListenerOptions::new(16);
+            opts.set_reuse_address(true)
+                .set_defer_accept(Duration::from_secs(1))
+                .set_recv_buffer_size(256 * 1024)
+                .set_send_buffer_size(128 * 1024);
+
+            let listener = LurkTcpListener::bind_with_opts(TEST_BIND_IPV4, &opts)
+                .await
+                .expect("Expect binded listener");
+            let socket = socket2::SockRef::from(&listener.inner);
+            assert!(socket.reuse_address().unwrap());
+
+            // Kernel may round the buffers up (e.g. Linux doubles them for bookkeeping), but not down.
+            let client = TcpStream::connect(listener.local_addr()).await.unwrap();
+            let (accepted, _) = listener.inner.accept().await.unwrap();
+            let accepted = socket2::SockRef::from(&accepted);
+            assert!(accepted.recv_buffer_size().unwrap() >= 256 * 1024);
+            assert!(accepted.send_buffer_size().unwrap() >= 128 * 1024);
+            drop(client);
+        }
+
+        #[tokio::test]
+        async fn bind_ipv6_only_and_dual_stack() {
+            let ipv6_only = LurkTcpListener::bind_with_opts("[::]:0", &LurkTcpListenerOptions::default())
+                .await
+                .expect("Expect binded listener");
+            assert!(socket2::SockRef::from(&ipv6_only.inner).only_v6().unwrap());
+
+            // IPv6-only listener leaves the port free for the IPv4 one.
+            let port = ipv6_only.local_addr().port();
+            LurkTcpListener::bind_with_opts(("0.0.0.0", port), &LurkTcpListenerOptions::default())
+                .await
+                .expect("IPv4 listener should share the port");
+
+            let mut opts = LurkTcpListenerOptions::default();
+            opts.set_dual_stack(true);
+            let mut dual_stack = LurkTcpListener::bind_with_opts("[::]:0", &opts)
+                .await
+                .expect("Expect binded listener");
+            assert!(!socket2::SockRef::from(&dual_stack.inner).only_v6().unwrap());
+
+            // IPv4 clients are accepted as IPv4-mapped ones.
+            let client = TcpStream::connect(("127.0.0.1", dual_stack.local_addr().port())).await.unwrap();
+            client.try_write(&[0x05]).unwrap();
+            let conn = dual_stack.accept().await.expect("Expect accepted connection");
+            assert_eq!("::ffff:127.0.0.1".parse::<IpAddr>().unwrap(), conn.peer_addr().ip());
+        }
+
+        #[tokio::test]
+        async fn drop_connections_not_redirected_to_transparent_listener() {
+            let mut opts = LurkTcpListenerOptions::default();
+            opts.set_transparent(LurkTransparentMode::Redirect);
+            let mut listener = LurkTcpListener::bind_with_opts(TEST_BIND_IPV4, &opts)
+                .await
+                .expect("Expect binded listener");
+
+            // Client connected directly doesn't have to send anything to be refused.
+            let _client = TcpStream::connect(listener.local_addr()).await.unwrap();
+            let res = timeout(Duration::from_secs(5), listener.accept())
+                .await
+                .expect("Connection should be handled without waiting for data");
+            assert_eq!(
+                Some(&LurkError::ClientNotRedirected("127.0.0.1".parse().unwrap())),
+                res.err().as_ref().and_then(|err| err.downcast_ref::<LurkError>())
+            );
+        }
+
+        #[tokio::test]
+        async fn accept_clients_behind_load_balancer() {
+            let mut opts = LurkTcpListenerOptions::default();
+            opts.set_proxy_protocol(vec!["127.0.0.0/8".parse().unwrap()])
+                .set_client_access(LurkClientAccess::new(Vec::new(), vec!["203.0.113.128/25".parse().unwrap()]));
+            let mut listener = LurkTcpListener::bind_with_opts(TEST_BIND_IPV4, &opts)
+                .await
+                .expect("Expect binded listener");
+
+            // Client is identified by the header, and its data following the header is detected as usual.
+            let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+            client
+                .write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 56324 1080\r\n\x05\x01\x00")
+                .await
+                .unwrap();
+            let conn = listener.accept().await.expect("Connection should be accepted");
+            assert_eq!("203.0.113.7:56324".parse::<SocketAddr>().unwrap(), conn.peer_addr());
+            assert_eq!(LurkTcpConnectionLabel::Socks5, conn.label());
+
+            // Client access lists are applied to the conveyed client.
+            let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+            client
+                .write_all(b"PROXY TCP4 203.0.113.200 127.0.0.1 56324 1080\r\n\x05\x01\x00")
+                .await
+                .unwrap();
+            let res = listener.accept().await;
+            assert_eq!(
+                Some(&LurkError::ClientNotAllowed("203.0.113.200".parse().unwrap())),
+                res.err().as_ref().and_then(|err| err.downcast_ref::<LurkError>())
+            );
+
+            // Load balancer has to send the header.
+            let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+            client.write_all(b"\x05\x01\x00 and more data").await.unwrap();
+            let res = listener.accept().await;
+            assert!(matches!(
+                res.err().as_ref().and_then(|err| err.downcast_ref::<LurkError>()),
+                Some(LurkError::InvalidProxyHeader(..))
+            ));
+        }
+
+        #[test]
+        fn allow_and_deny_clients() {
+            let net = |net: &str| net.parse::<IpNet>().unwrap();
+            let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+            let access = LurkClientAccess::new(vec![net("10.0.0.0/8"), net("2001:db8::/32")], vec![net("10.0.13.0/24")]);
+            assert!(access.is_allowed(ip("10.1.2.3")));
+            assert!(access.is_allowed(ip("::ffff:10.1.2.3")));
+            assert!(access.is_allowed(ip("2001:db8::1")));
+            assert!(!access.is_allowed(ip("10.0.13.7")));
+            assert!(!access.is_allowed(ip("192.0.2.1")));
+
+            let access = LurkClientAccess::new(Vec::new(), vec![net("192.0.2.0/24")]);
+            assert!(access.is_allowed(ip("10.1.2.3")));
+            assert!(!access.is_allowed(ip("192.0.2.1")));
+            assert!(LurkClientAccess::default().is_allowed(ip("192.0.2.1")));
+
+            let mut access = LurkClientAccess::new(Vec::new(), vec![net("81.2.69.128/25")]);
+            access.set_countries(LurkCountryAccess::new(geoip::tests::test_geoip(), ["GB".to_owned()], []));
+            assert!(access.is_allowed(ip("81.2.69.1")));
+            assert!(!access.is_allowed(ip("81.2.69.142")));
+            assert!(!access.is_allowed(ip("89.160.20.1")));
+            assert!(!access.is_allowed(ip("10.1.2.3")));
+        }
+
+        #[cfg(target_os = "linux")]
+        #[tokio::test]
+        async fn detect_broken_listener() {
+            let listener = LurkTcpListener::bind(TEST_BIND_IPV4).await.expect("Expect binded listener");
+            listener.shutdown();
+
+            let err = listener.inner.accept().await.expect_err("Shut down listener shouldn't accept");
+            assert!(is_listener_broken(&err));
+            assert!(!is_listener_broken(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        }
+
         /// This tests backpressure limit set on listener.
         /// Number of connections intentionally exceeds the limit. Thus listener
         /// should put on hold some of them and handle only allowed number of
@@ -185,11 +1056,22 @@ pub mod listener {
 
 pub mod connection {
 
+    use crate::{io::tunnel::LurkTunnelActivity, net::client_limits::LurkClientConnection};
     use anyhow::{bail, Result};
     use async_trait::async_trait;
     use hyper_util::rt::TokioIo;
-    use std::{fmt::Display, io, net::SocketAddr};
-    use tokio::net::TcpStream;
+    use std::{
+        fmt::Display,
+        io,
+        net::SocketAddr,
+        pin::Pin,
+        sync::{Arc, OnceLock},
+        task::{Context, Poll},
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        net::TcpStream,
+    };
 
     /// Label that describes the TCP connection.
     ///
@@ -202,9 +1084,15 @@ pub mod connection {
         /// Traffic of TCP connection belongs to proxy SOCKS5 protocol
         Socks5,
 
+        /// Traffic of TCP connection belongs to proxy SOCKS4 or SOCKS4a protocol
+        Socks4,
+
         /// Traffic of TCP connection belongs to HTTP(S) protocol
         Http,
 
+        /// TCP connection has been redirected to the transparent listener and is relayed to its original destination as is
+        Transparent,
+
         /// Unknown traffic
         Unknown(u8),
     }
@@ -223,6 +1111,7 @@ pub mod connection {
                 let label = match buff[0] {
                     b if Self::is_http_label(b) => LurkTcpConnectionLabel::Http,
                     b if Self::is_socks5_label(b) => LurkTcpConnectionLabel::Socks5,
+                    b if Self::is_socks4_label(b) => LurkTcpConnectionLabel::Socks4,
                     v => LurkTcpConnectionLabel::Unknown(v),
                 };
 
@@ -243,6 +1132,10 @@ pub mod connection {
         fn is_socks5_label(byte: u8) -> bool {
             matches!(byte, 0x05)
         }
+
+        fn is_socks4_label(byte: u8) -> bool {
+            matches!(byte, 0x04)
+        }
     }
 
     impl Display for LurkTcpConnectionLabel {
@@ -250,28 +1143,192 @@ pub mod connection {
             match self {
                 LurkTcpConnectionLabel::Http => write!(f, "HTTP(S)"),
                 LurkTcpConnectionLabel::Socks5 => write!(f, "SOCKS5"),
+                LurkTcpConnectionLabel::Socks4 => write!(f, "SOCKS4"),
+                LurkTcpConnectionLabel::Transparent => write!(f, "transparent"),
                 LurkTcpConnectionLabel::Unknown(l) => write!(f, "unknown {l:#04x}"),
             }
         }
     }
 
+    /// Session attributes discovered by the protocol handler while serving the connection.
+    ///
+    /// Each attribute is set at most once, subsequent attempts are ignored.
+    #[derive(Debug, Default)]
+    pub struct LurkSessionInfo {
+        user: OnceLock<String>,
+        route_hint: OnceLock<String>,
+        destination: OnceLock<String>,
+        deny_reason: OnceLock<String>,
+    }
+
+    impl LurkSessionInfo {
+        /// Name of the authenticated user.
+        pub fn user(&self) -> Option<&str> {
+            self.user.get().map(String::as_str)
+        }
+
+        pub fn set_user(&self, user: &str) {
+            let _ = self.user.set(user.to_owned());
+        }
+
+        /// Parent proxy the user has picked by the hint in the username.
+        pub fn route_hint(&self) -> Option<&str> {
+            self.route_hint.get().map(String::as_str)
+        }
+
+        pub fn set_route_hint(&self, route_hint: &str) {
+            let _ = self.route_hint.set(route_hint.to_owned());
+        }
+
+        /// Destination the client has requested to connect to.
+        pub fn destination(&self) -> Option<&str> {
+            self.destination.get().map(String::as_str)
+        }
+
+        pub fn set_destination(&self, destination: impl Display) {
+            let _ = self.destination.set(destination.to_string());
+        }
+
+        /// Why the client has been refused to connect to the destination.
+        pub fn deny_reason(&self) -> Option<&str> {
+            self.deny_reason.get().map(String::as_str)
+        }
+
+        pub fn set_deny_reason(&self, reason: impl Display) {
+            let _ = self.deny_reason.set(reason.to_string());
+        }
+    }
+
+    /// Stream carrying the client connection. Accepted connections are backed by TCP (with TLS
+    /// terminated by the HTTPS listener), handlers are unit-tested over in-memory ones.
+    pub enum LurkConnectionStream {
+        Tcp(TcpStream),
+        #[cfg(feature = "https")]
+        Tls(Box<tokio_rustls::server::TlsStream<crate::net::ktls::LurkKtlsStream>>),
+        #[cfg(test)]
+        Memory(tokio::io::DuplexStream),
+    }
+
+    impl AsyncRead for LurkConnectionStream {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                LurkConnectionStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+                #[cfg(feature = "https")]
+                LurkConnectionStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+                #[cfg(test)]
+                LurkConnectionStream::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl AsyncWrite for LurkConnectionStream {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            match self.get_mut() {
+                LurkConnectionStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+                #[cfg(feature = "https")]
+                LurkConnectionStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+                #[cfg(test)]
+                LurkConnectionStream::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                LurkConnectionStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+                #[cfg(feature = "https")]
+                LurkConnectionStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+                #[cfg(test)]
+                LurkConnectionStream::Memory(stream) => Pin::new(stream).poll_flush(cx),
+            }
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                LurkConnectionStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+                #[cfg(feature = "https")]
+                LurkConnectionStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+                #[cfg(test)]
+                LurkConnectionStream::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+            }
+        }
+    }
+
     /// Factory that produces new TCP connection instances.
     pub struct LurkTcpConnectionFactory {}
 
     impl LurkTcpConnectionFactory {
+        /// Size of the buffer of in-memory connections in each direction.
+        #[cfg(all(test, any(feature = "http", feature = "socks5", feature = "socks4", feature = "transparent")))]
+        const IN_MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
         pub fn create_connection(tcp_stream: TcpStream, label: LurkTcpConnectionLabel) -> Result<LurkTcpConnection> {
             LurkTcpConnection::new(tcp_stream, label)
         }
+
+        /// Create connection redirected to the transparent listener from the ```original_destination```.
+        pub fn create_transparent_connection(tcp_stream: TcpStream, original_destination: SocketAddr) -> Result<LurkTcpConnection> {
+            let mut conn = LurkTcpConnection::new(tcp_stream, LurkTcpConnectionLabel::Transparent)?;
+            conn.original_destination = Some(original_destination);
+            Ok(conn)
+        }
+
+        /// Create HTTP connection served over the TLS session established with the client.
+        #[cfg(feature = "https")]
+        pub fn create_tls_connection(
+            tls_stream: tokio_rustls::server::TlsStream<crate::net::ktls::LurkKtlsStream>,
+        ) -> Result<LurkTcpConnection> {
+            let tcp_stream = tls_stream.get_ref().0.get_ref();
+            Ok(LurkTcpConnection {
+                peer_addr: tcp_stream.peer_addr()?,
+                local_addr: tcp_stream.local_addr()?,
+                activity: Arc::new(LurkTunnelActivity::new()),
+                session: Arc::new(LurkSessionInfo::default()),
+                stream: LurkConnectionStream::Tls(Box::new(tls_stream)),
+                label: LurkTcpConnectionLabel::Http,
+                original_destination: None,
+                client_connection: Ok(None),
+            })
+        }
+
+        /// Create connection served over in-memory pipe instead of the socket, so handlers could be
+        /// tested without binding ports. Returns the client side of the pipe along with the connection.
+        #[cfg(all(test, any(feature = "http", feature = "socks5", feature = "socks4", feature = "transparent")))]
+        pub fn create_in_memory_connection(
+            label: LurkTcpConnectionLabel,
+            peer_addr: SocketAddr,
+            local_addr: SocketAddr,
+        ) -> (LurkTcpConnection, tokio::io::DuplexStream) {
+            let (client, server) = tokio::io::duplex(Self::IN_MEMORY_BUFFER_SIZE);
+            let conn = LurkTcpConnection {
+                stream: LurkConnectionStream::Memory(server),
+                label,
+                peer_addr,
+                local_addr,
+                activity: Arc::new(LurkTunnelActivity::new()),
+                session: Arc::new(LurkSessionInfo::default()),
+                original_destination: None,
+                client_connection: Ok(None),
+            };
+            (conn, client)
+        }
     }
 
     pub struct LurkTcpConnection {
-        stream: TcpStream,
+        stream: LurkConnectionStream,
         /// Label describing traffic in this TCP connection
         label: LurkTcpConnectionLabel,
         /// Remote address that this connection is connected to
         peer_addr: SocketAddr,
         /// Local address that this connection is bound to
         local_addr: SocketAddr,
+        /// Data movement observed in this connection
+        activity: Arc<LurkTunnelActivity>,
+        /// Attributes of the session carried by this connection
+        session: Arc<LurkSessionInfo>,
+        /// Destination the client has connected to, if the connection has been redirected to the transparent listener
+        original_destination: Option<SocketAddr>,
+        /// Slot of the connection among the ones open by its client, or why the connection is over the limit
+        client_connection: Result<Option<LurkClientConnection>>,
     }
 
     impl LurkTcpConnection {
@@ -279,11 +1336,34 @@ pub mod connection {
             Ok(LurkTcpConnection {
                 peer_addr: stream.peer_addr()?,
                 local_addr: stream.local_addr()?,
-                stream,
+                activity: Arc::new(LurkTunnelActivity::new()),
+                session: Arc::new(LurkSessionInfo::default()),
+                stream: LurkConnectionStream::Tcp(stream),
                 label,
+                original_destination: None,
+                client_connection: Ok(None),
             })
         }
 
+        /// Connection redirected from the ```original_destination``` to the transparent listener.
+        #[cfg(test)]
+        pub fn with_original_destination(mut self, original_destination: SocketAddr) -> LurkTcpConnection {
+            self.original_destination = Some(original_destination);
+            self
+        }
+
+        /// Connection accepted from the load balancer on behalf of the client at ```peer_addr```.
+        pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> LurkTcpConnection {
+            self.peer_addr = peer_addr;
+            self
+        }
+
+        /// Connection accounted to its client by the ```client_connection``` slot taken when it's been accepted.
+        pub fn with_client_connection(mut self, client_connection: Result<Option<LurkClientConnection>>) -> LurkTcpConnection {
+            self.client_connection = client_connection;
+            self
+        }
+
         pub fn peer_addr(&self) -> SocketAddr {
             self.peer_addr
         }
@@ -296,21 +1376,52 @@ pub mod connection {
             self.label
         }
 
-        pub fn stream_mut(&mut self) -> &mut TcpStream {
+        pub fn original_destination(&self) -> Option<SocketAddr> {
+            self.original_destination
+        }
+
+        pub fn stream_mut(&mut self) -> &mut LurkConnectionStream {
             &mut self.stream
         }
+
+        /// Underlying TCP stream, unless the connection is in-memory or TLS one.
+        pub fn tcp_stream_mut(&mut self) -> Option<&mut TcpStream> {
+            match &mut self.stream {
+                LurkConnectionStream::Tcp(stream) => Some(stream),
+                #[cfg(feature = "https")]
+                LurkConnectionStream::Tls(_) => None,
+                #[cfg(test)]
+                LurkConnectionStream::Memory(_) => None,
+            }
+        }
+
+        pub fn activity(&self) -> Arc<LurkTunnelActivity> {
+            Arc::clone(&self.activity)
+        }
+
+        pub fn session(&self) -> Arc<LurkSessionInfo> {
+            Arc::clone(&self.session)
+        }
+
+        /// Take the slot of the connection among the ones open by its client, so it's held by the handler.
+        /// Fails if the client has exceeded its limit of connections.
+        pub fn take_client_connection(&mut self) -> Result<Option<LurkClientConnection>> {
+            std::mem::replace(&mut self.client_connection, Ok(None))
+        }
     }
 
     /// Converts TCP connection to tokio IO instance.
-    impl From<LurkTcpConnection> for TokioIo<TcpStream> {
+    impl From<LurkTcpConnection> for TokioIo<LurkConnectionStream> {
         fn from(conn: LurkTcpConnection) -> Self {
             TokioIo::new(conn.stream)
         }
     }
 
+    /// Handler of the connections carrying particular traffic label.
+    /// Single instance serves all connections, so it must not keep per-connection state.
     #[async_trait]
-    pub trait LurkTcpConnectionHandler: Send {
-        async fn handle(&mut self, mut conn: LurkTcpConnection) -> Result<()>;
+    pub trait LurkTcpConnectionHandler: Send + Sync {
+        async fn handle(&self, mut conn: LurkTcpConnection) -> Result<()>;
     }
 
     #[cfg(test)]
@@ -347,6 +1458,24 @@ pub mod connection {
                 .await
                 .unwrap();
 
+            {
+                // Write known label (SOCKS4)
+                TcpStream::connect(addr)
+                    .and_then(|mut s| async move { s.write_all(&[0x04]).await })
+                    .await
+                    .unwrap();
+            }
+
+            listener
+                .accept()
+                .and_then(|(s, _)| async move {
+                    let label = LurkTcpConnectionLabel::from_tcp_stream(&s).await.unwrap();
+                    assert_eq!(LurkTcpConnectionLabel::Socks4, label);
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
             {
                 // Write unknown label
                 TcpStream::connect(addr)
@@ -367,3 +1496,55 @@ pub mod connection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn establish_tcp_connection_from(addr: SocketAddr, local_ip: IpAddr) -> Result<TcpStream> {
+        let mut tcp_opts = default_tcp_options();
+        tcp_opts.set_local_ip(local_ip);
+        establish_tcp_connection_with_opts(addr, &tcp_opts).await
+    }
+
+    #[tokio::test]
+    async fn connect_from_local_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local_ip: IpAddr = "127.0.0.2".parse().unwrap();
+
+        let (tcp_stream, accepted) = tokio::join!(establish_tcp_connection_from(addr, local_ip), listener.accept());
+
+        assert_eq!(local_ip, tcp_stream.unwrap().local_addr().unwrap().ip());
+        assert_eq!(local_ip, accepted.unwrap().1.ip());
+    }
+
+    #[tokio::test]
+    async fn connect_from_other_family() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert!(establish_tcp_connection_from(addr, "::1".parse().unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_with_fast_open_and_mptcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tcp_opts = default_tcp_options();
+        tcp_opts.set_fast_open(true).set_mptcp(true);
+
+        // Connection is established whether the kernel supports the options or not.
+        let (tcp_stream, accepted) = tokio::join!(establish_tcp_connection_with_opts(addr, &tcp_opts), listener.accept());
+        let (mut tcp_stream, mut accepted) = (tcp_stream.unwrap(), accepted.unwrap().0);
+
+        tcp_stream.write_all(b"syn data").await.unwrap();
+        let mut buf = [0u8; 8];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"syn data", &buf);
+    }
+}
@@ -0,0 +1,483 @@
+use super::ocsp;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::{debug, warn};
+use rustls::{
+    crypto::{
+        ring::{self, Ticketer},
+        CryptoProvider,
+    },
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, NoServerSessionStorage, ResolvesServerCert, ResolvesServerCertUsingSni},
+    sign::CertifiedKey,
+    version::{TLS12, TLS13},
+    ServerConfig, SupportedProtocolVersion,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// Oldest TLS version the listeners negotiate with the clients.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkTlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+/// Shorthand for the TLS policy of the listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkTlsPreset {
+    /// TLS 1.3 only
+    Modern,
+    /// TLS 1.2 and 1.3
+    #[default]
+    Compatible,
+}
+
+/// How the clients resume their TLS sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkTlsResumption {
+    /// Sessions aren't resumed, every connection goes through the full handshake
+    Off,
+    /// Sessions are cached by the listener
+    #[default]
+    Cache,
+    /// Sessions are kept by the clients in the tickets encrypted by the listener
+    Tickets,
+}
+
+/// TLS settings shared by all TLS listeners.
+///
+/// **Fields**:
+/// * ```min_version``` - oldest TLS version negotiated with the clients
+/// * ```cipher_suites``` - names of the cipher suites (e.g. "TLS13_AES_256_GCM_SHA384") in the order of preference, all supported ones if empty
+/// * ```resumption``` - how the clients resume their sessions
+/// * ```ocsp_refresh_interval``` - period between two fetches of OCSP responses stapled to the certificates, nothing is stapled if unset
+/// * ```kernel_offload``` - whether the sessions of the HTTPS listener are handed over to the kernel (kTLS) after the handshake
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LurkTlsPolicy {
+    min_version: LurkTlsVersion,
+    cipher_suites: Vec<String>,
+    resumption: LurkTlsResumption,
+    ocsp_refresh_interval: Option<Duration>,
+    kernel_offload: bool,
+}
+
+impl LurkTlsPolicy {
+    pub fn new(preset: LurkTlsPreset) -> LurkTlsPolicy {
+        let min_version = match preset {
+            LurkTlsPreset::Modern => LurkTlsVersion::Tls13,
+            LurkTlsPreset::Compatible => LurkTlsVersion::Tls12,
+        };
+        LurkTlsPolicy {
+            min_version,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_min_version(&mut self, min_version: LurkTlsVersion) -> &mut LurkTlsPolicy {
+        self.min_version = min_version;
+        self
+    }
+
+    pub fn set_cipher_suites(&mut self, cipher_suites: Vec<String>) -> &mut LurkTlsPolicy {
+        self.cipher_suites = cipher_suites;
+        self
+    }
+
+    pub fn set_resumption(&mut self, resumption: LurkTlsResumption) -> &mut LurkTlsPolicy {
+        self.resumption = resumption;
+        self
+    }
+
+    pub fn set_ocsp_refresh_interval(&mut self, ocsp_refresh_interval: Option<Duration>) -> &mut LurkTlsPolicy {
+        self.ocsp_refresh_interval = ocsp_refresh_interval;
+        self
+    }
+
+    pub fn set_kernel_offload(&mut self, kernel_offload: bool) -> &mut LurkTlsPolicy {
+        self.kernel_offload = kernel_offload;
+        self
+    }
+
+    pub fn ocsp_refresh_interval(&self) -> Option<Duration> {
+        self.ocsp_refresh_interval
+    }
+
+    #[cfg_attr(not(feature = "https"), allow(dead_code))]
+    pub fn kernel_offload(&self) -> bool {
+        self.kernel_offload
+    }
+}
+
+/// Certificate presented to the clients asking for the ```server_name``` by SNI instead of the default one.
+/// It's written as "server_name:cert_path:key_path".
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkSniCert {
+    server_name: String,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl LurkSniCert {
+    pub fn new(server_name: impl Into<String>, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> LurkSniCert {
+        LurkSniCert {
+            server_name: server_name.into(),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+}
+
+impl FromStr for LurkSniCert {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LurkSniCert> {
+        let mut parts = s.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(server_name), Some(cert_path), Some(key_path))
+                if [server_name, cert_path, key_path].iter().all(|part| !part.is_empty()) =>
+            {
+                Ok(LurkSniCert::new(server_name, cert_path, key_path))
+            }
+            _ => bail!("'{}' should be written as 'server_name:cert_path:key_path'", s),
+        }
+    }
+}
+
+/// Application protocol the ACME CA negotiates to validate the TLS-ALPN-01 challenge.
+#[cfg(feature = "acme")]
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Certificates presented by the listener: the ones picked by the server name the client has asked for (SNI)
+/// and the default one presented otherwise. OCSP responses stapled to them are replaced while the listener is running.
+///
+/// The ACME CA validating the TLS-ALPN-01 challenge is presented the challenge certificate of the name instead.
+#[derive(Debug)]
+pub struct LurkCertResolver {
+    by_name: HashMap<String, RwLock<Arc<CertifiedKey>>>,
+    default: RwLock<Arc<CertifiedKey>>,
+    #[cfg(feature = "acme")]
+    cert_path: PathBuf,
+    #[cfg(feature = "acme")]
+    key_path: PathBuf,
+    #[cfg(feature = "acme")]
+    challenge_certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl LurkCertResolver {
+    /// Read the default certificate chain from ```cert_path``` and the ones of ```sni_certs``` along with their keys.
+    pub fn load(cert_path: &Path, key_path: &Path, sni_certs: &[LurkSniCert]) -> Result<LurkCertResolver> {
+        let provider = ring::default_provider();
+        let default = certified_key(cert_path, key_path, &provider)?;
+        let mut by_name = HashMap::with_capacity(sni_certs.len());
+        for sni_cert in sni_certs {
+            let certified_key = certified_key(&sni_cert.cert_path, &sni_cert.key_path, &provider)?;
+            // Certificate is checked to be valid for the name, so misconfiguration is caught on start.
+            ResolvesServerCertUsingSni::new()
+                .add(&sni_cert.server_name, certified_key.clone())
+                .with_context(|| {
+                    format!(
+                        "certificate {} can't be presented for '{}'",
+                        sni_cert.cert_path.display(),
+                        sni_cert.server_name
+                    )
+                })?;
+            by_name.insert(sni_cert.server_name.to_ascii_lowercase(), RwLock::new(Arc::new(certified_key)));
+        }
+
+        Ok(LurkCertResolver {
+            by_name,
+            default: RwLock::new(Arc::new(default)),
+            #[cfg(feature = "acme")]
+            cert_path: cert_path.to_owned(),
+            #[cfg(feature = "acme")]
+            key_path: key_path.to_owned(),
+            #[cfg(feature = "acme")]
+            challenge_certs: RwLock::default(),
+        })
+    }
+
+    /// Read the default certificate chain and its key again, once they are renewed. Clients, which
+    /// have completed the handshake, keep the old certificate.
+    #[cfg(feature = "acme")]
+    pub fn reload_default(&self) -> Result<()> {
+        let certified_key = certified_key(&self.cert_path, &self.key_path, &ring::default_provider())?;
+        *self.default.write().expect("Certificate lock is poisoned") = Arc::new(certified_key);
+        Ok(())
+    }
+
+    /// Present the certificate to the ACME CA validating the TLS-ALPN-01 challenge for the ```server_name```,
+    /// or stop presenting it once the challenge is over.
+    #[cfg(feature = "acme")]
+    pub fn set_challenge_cert(&self, server_name: &str, certified_key: Option<CertifiedKey>) {
+        let mut challenge_certs = self.challenge_certs.write().expect("Certificate lock is poisoned");
+        match certified_key {
+            Some(certified_key) => challenge_certs.insert(server_name.to_ascii_lowercase(), Arc::new(certified_key)),
+            None => challenge_certs.remove(&server_name.to_ascii_lowercase()),
+        };
+    }
+
+    /// Asynchronously refresh stapled OCSP responses until cancelled.
+    pub async fn run_ocsp_stapling(&self, refresh_interval: Duration, token: CancellationToken) {
+        let mut ticker = interval(refresh_interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.staple_ocsp().await,
+                _ = token.cancelled() => return,
+            }
+        }
+    }
+
+    /// Fetch OCSP responses for all certificates once and staple them. Certificate, which
+    /// has failed to get the fresh response, keeps the last stapled one. Response isn't stapled,
+    /// if the certificate has been reloaded (e.g. renewed) while it was fetched.
+    pub async fn staple_ocsp(&self) {
+        let certified_keys = std::iter::once(("default", &self.default)).chain(self.by_name.iter().map(|(name, key)| (name.as_str(), key)));
+        for (name, certified_key) in certified_keys {
+            let current = Arc::clone(&certified_key.read().expect("Certificate lock is poisoned"));
+            match ocsp::fetch_response(&current.cert).await {
+                Ok(response) => {
+                    let mut certified_key = certified_key.write().expect("Certificate lock is poisoned");
+                    if !Arc::ptr_eq(&current, &certified_key) {
+                        debug!("OCSP response isn't stapled, the {} certificate has been reloaded", name);
+                        continue;
+                    }
+                    let mut stapled = CertifiedKey::clone(&current);
+                    stapled.ocsp = Some(response);
+                    *certified_key = Arc::new(stapled);
+                    debug!("OCSP response is stapled to the {} certificate", name);
+                }
+                // Stale response is still valid until its next update.
+                Err(err) => warn!("Failed to refresh OCSP response of the {} certificate: {:#}", name, err),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for LurkCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        #[cfg(feature = "acme")]
+        if client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN))
+        {
+            let challenge_certs = self.challenge_certs.read().expect("Certificate lock is poisoned");
+            return challenge_certs.get(client_hello.server_name()?).cloned();
+        }
+
+        let certified_key = client_hello.server_name().and_then(|server_name| self.by_name.get(server_name));
+        let certified_key = certified_key.unwrap_or(&self.default).read().expect("Certificate lock is poisoned");
+        Some(Arc::clone(&certified_key))
+    }
+}
+
+/// TLS configuration of the listener presenting the ```certs``` and negotiating one of the
+/// ```alpn_protocols``` with the clients as the ```policy``` allows.
+pub fn server_config(certs: Arc<LurkCertResolver>, alpn_protocols: &[&[u8]], policy: &LurkTlsPolicy) -> Result<ServerConfig> {
+    let mut provider = ring::default_provider();
+    if !policy.cipher_suites.is_empty() {
+        let supported = provider.cipher_suites;
+        provider.cipher_suites = policy
+            .cipher_suites
+            .iter()
+            .map(|name| {
+                supported
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()) == *name)
+                    .copied()
+                    .with_context(|| format!("cipher suite '{}' isn't supported", name))
+            })
+            .collect::<Result<_>>()?;
+    }
+    let versions: &[&SupportedProtocolVersion] = match policy.min_version {
+        LurkTlsVersion::Tls12 => &[&TLS13, &TLS12],
+        LurkTlsVersion::Tls13 => &[&TLS13],
+    };
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .context("cipher suites don't support any of the allowed TLS versions")?
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+    config.alpn_protocols = alpn_protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    match policy.resumption {
+        LurkTlsResumption::Off => {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.send_tls13_tickets = 0;
+        }
+        LurkTlsResumption::Cache => {}
+        LurkTlsResumption::Tickets => config.ticketer = Ticketer::new()?,
+    }
+    // Secrets of the established sessions are handed over to the kernel.
+    config.enable_secret_extraction = policy.kernel_offload;
+
+    Ok(config)
+}
+
+/// Certificate chain and the private key read from PEM files.
+fn certified_key(cert_path: &Path, key_path: &Path, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let cert_chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).with_context(|| format!("failed to read private key from {}", key_path.display()))?;
+
+    CertifiedKey::from_der(cert_chain, key, provider)
+        .with_context(|| format!("private key {} doesn't fit the certificate", key_path.display()))
+}
+
+/// Write self-signed certificate for the ```server_name``` and its key to the directory.
+/// Returns paths of the PEM files along with the certificate clients should trust.
+#[cfg(test)]
+pub fn write_self_signed_cert(dir: &Path, server_name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+    let certified_key = rcgen::generate_simple_self_signed(vec![server_name.to_owned()]).unwrap();
+    std::fs::create_dir_all(dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, certified_key.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified_key.key_pair.serialize_pem()).unwrap();
+
+    (cert_path, key_path, certified_key.cert.der().clone())
+}
+
+/// Write certificate for the ```server_name``` issued by the new CA, which names its OCSP responder by ```ocsp_url```,
+/// and its key to the directory. Certificate file holds the chain of both certificates.
+/// Returns paths of the PEM files along with the CA certificate clients should trust.
+#[cfg(test)]
+pub fn write_ca_signed_cert(dir: &Path, server_name: &str, ocsp_url: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+    use rcgen::{BasicConstraints, CertificateParams, CustomExtension, DnType, IsCa, KeyPair};
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "Lurk Test CA");
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+    // Authority Information Access with the only OCSP responder.
+    let authority_info_access = yasna::construct_der(|writer| {
+        writer.write_sequence(|descriptions| {
+            descriptions.next().write_sequence(|description| {
+                description
+                    .next()
+                    .write_oid(&yasna::models::ObjectIdentifier::from_slice(&[1, 3, 6, 1, 5, 5, 7, 48, 1]));
+                description
+                    .next()
+                    .write_tagged_implicit(yasna::Tag::context(6), |writer| writer.write_ia5_string(ocsp_url));
+            })
+        })
+    });
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![server_name.to_owned()]).unwrap();
+    params.custom_extensions.push(CustomExtension::from_oid_content(
+        &[1, 3, 6, 1, 5, 5, 7, 1, 1],
+        authority_info_access,
+    ));
+    let cert = params.signed_by(&key, &ca_cert, &ca_key).unwrap();
+
+    std::fs::create_dir_all(dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, cert.pem() + &ca_cert.pem()).unwrap();
+    std::fs::write(&key_path, key.serialize_pem()).unwrap();
+
+    (cert_path, key_path, ca_cert.der().clone())
+}
+
+/// TLS configuration of the client trusting the only certificate.
+#[cfg(test)]
+pub fn client_config(cert: CertificateDer<'static>, alpn_protocols: &[&[u8]]) -> rustls::ClientConfig {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let provider = Arc::new(ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols.iter().map(|protocol| protocol.to_vec()).collect();
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{
+        matchers::request,
+        responders::{cycle, status_code},
+        Expectation, Server,
+    };
+
+    #[tokio::test]
+    async fn staple_ocsp_responses() {
+        let response = ocsp::successful_response(b"good");
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/ocsp"))
+                .times(2)
+                .respond_with(cycle![status_code(200).body(response.clone()), status_code(500)]),
+        );
+
+        let dir = std::env::temp_dir().join(format!("lurk-tls-ocsp-{}", std::process::id()));
+        let (cert_path, key_path, _) = write_ca_signed_cert(&dir.join("default"), "localhost", &server.url_str("/ocsp"));
+        // Self-signed certificate doesn't have the responder to ask.
+        let (sni_cert_path, sni_key_path, _) = write_self_signed_cert(&dir.join("sni"), "admin.localhost");
+        let certs = LurkCertResolver::load(
+            &cert_path,
+            &key_path,
+            &[LurkSniCert::new("admin.localhost", sni_cert_path, sni_key_path)],
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let stapled = |certified_key: &RwLock<Arc<CertifiedKey>>| certified_key.read().unwrap().ocsp.clone();
+        certs.staple_ocsp().await;
+        assert_eq!(Some(response.clone()), stapled(&certs.default));
+        assert_eq!(None, stapled(&certs.by_name["admin.localhost"]));
+
+        // Responder has failed, so the last response is kept.
+        certs.staple_ocsp().await;
+        assert_eq!(Some(response), stapled(&certs.default));
+    }
+
+    #[cfg(feature = "acme")]
+    #[tokio::test]
+    async fn keep_certificate_reloaded_while_stapling() {
+        use httptest::responders::delay_and_then;
+
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/ocsp")).respond_with(delay_and_then(
+                Duration::from_millis(500),
+                status_code(200).body(ocsp::successful_response(b"good")),
+            )),
+        );
+
+        let dir = std::env::temp_dir().join(format!("lurk-tls-reload-{}", std::process::id()));
+        let (cert_path, key_path, _) = write_ca_signed_cert(&dir, "localhost", &server.url_str("/ocsp"));
+        let certs = LurkCertResolver::load(&cert_path, &key_path, &[]).unwrap();
+
+        // Certificate is renewed while its OCSP response is being fetched.
+        let stapling = certs.staple_ocsp();
+        let renewal = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            write_ca_signed_cert(&dir, "localhost", &server.url_str("/ocsp"));
+            certs.reload_default().unwrap();
+        };
+        tokio::join!(stapling, renewal);
+
+        let renewed = certified_key(&cert_path, &key_path, &ring::default_provider()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let default = Arc::clone(&certs.default.read().unwrap());
+        assert_eq!(renewed.cert, default.cert);
+        assert_eq!(None, default.ocsp);
+    }
+}
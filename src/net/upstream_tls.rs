@@ -0,0 +1,348 @@
+use crate::common::error::LurkError;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::digest::{digest, SHA256};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    server::ParsedCertificate,
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// Bundles of the CAs trusted by the system, the first existing one is used.
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// How the certificate of the upstream proxy connected over TLS is verified.
+///
+/// **Fields**:
+/// * ```server_name``` - name sent in SNI and the certificate is verified against, IP address of the proxy if unset
+/// * ```ca_bundle``` - PEM file with the CAs trusted to issue the certificate, the system ones are trusted if unset (unless the key is pinned)
+/// * ```pins``` - SHA-256 digests of the public keys (SPKI) the certificate is allowed to have. Pinned key alone is trusted if no CA bundle is set
+/// * ```insecure_skip_verify``` - whether the certificate is accepted without any verification
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LurkUpstreamTlsOptions {
+    server_name: Option<String>,
+    ca_bundle: Option<PathBuf>,
+    pins: Vec<[u8; 32]>,
+    insecure_skip_verify: bool,
+}
+
+impl LurkUpstreamTlsOptions {
+    pub fn new() -> LurkUpstreamTlsOptions {
+        LurkUpstreamTlsOptions::default()
+    }
+
+    pub fn set_server_name(&mut self, server_name: impl Into<String>) -> &mut LurkUpstreamTlsOptions {
+        self.server_name = Some(server_name.into());
+        self
+    }
+
+    pub fn set_ca_bundle(&mut self, ca_bundle: impl Into<PathBuf>) -> &mut LurkUpstreamTlsOptions {
+        self.ca_bundle = Some(ca_bundle.into());
+        self
+    }
+
+    pub fn set_pins(&mut self, pins: impl IntoIterator<Item = [u8; 32]>) -> &mut LurkUpstreamTlsOptions {
+        self.pins = pins.into_iter().collect();
+        self
+    }
+
+    pub fn set_insecure_skip_verify(&mut self, insecure_skip_verify: bool) -> &mut LurkUpstreamTlsOptions {
+        self.insecure_skip_verify = insecure_skip_verify;
+        self
+    }
+}
+
+/// Parse SHA-256 digest of the public key encoded in base64, the way HPKP pins are (e.g. "pin-sha256" of curl).
+pub fn parse_pin_sha256(pin: &str) -> Result<[u8; 32]> {
+    let decoded = BASE64.decode(pin.trim()).with_context(|| format!("pin '{}' isn't base64", pin))?;
+    decoded.try_into().map_err(|_| anyhow!("pin '{}' isn't SHA-256 digest", pin))
+}
+
+/// TLS client of the upstream proxy.
+#[derive(Clone)]
+pub struct LurkUpstreamTls {
+    options: LurkUpstreamTlsOptions,
+    server_name: Option<ServerName<'static>>,
+    connector: TlsConnector,
+}
+
+impl LurkUpstreamTls {
+    pub fn new(options: LurkUpstreamTlsOptions) -> Result<LurkUpstreamTls> {
+        if options.insecure_skip_verify && (options.ca_bundle.is_some() || !options.pins.is_empty()) {
+            bail!("certificate of upstream proxy can't be verified with the CA bundle or pins, while verification is skipped");
+        }
+
+        let server_name = match &options.server_name {
+            Some(name) => Some(ServerName::try_from(name.clone()).with_context(|| format!("'{}' isn't valid server name", name))?),
+            None => None,
+        };
+
+        let provider = Arc::new(default_provider());
+        let webpki = match &options.ca_bundle {
+            _ if options.insecure_skip_verify => None,
+            Some(ca_bundle) => Some(webpki_verifier(ca_bundle, &provider)?),
+            None if !options.pins.is_empty() => None,
+            None => {
+                let system_bundle = SYSTEM_CA_BUNDLES
+                    .iter()
+                    .map(Path::new)
+                    .find(|path| path.exists())
+                    .ok_or_else(|| anyhow!("system CA bundle isn't found, CA bundle or pin should be set for upstream proxy"))?;
+                Some(webpki_verifier(system_bundle, &provider)?)
+            }
+        };
+        let verifier = LurkUpstreamCertVerifier {
+            webpki,
+            pins: options.pins.clone(),
+            provider: Arc::clone(&provider),
+        };
+
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+
+        Ok(LurkUpstreamTls {
+            options,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Run TLS handshake with the proxy at ```addr``` over established TCP connection. Certificate
+    /// not passing the verification fails it with [`LurkError::UpstreamTlsVerificationFailed`].
+    pub async fn connect(&self, addr: SocketAddr, tcp_stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::IpAddress(addr.ip().into()),
+        };
+
+        self.connector.connect(server_name, tcp_stream).await.map_err(|err| {
+            match err.get_ref().and_then(|err| err.downcast_ref::<rustls::Error>()) {
+                Some(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)) => {
+                    anyhow!(LurkError::UpstreamTlsVerificationFailed(addr, "public key isn't pinned".to_owned()))
+                }
+                Some(rustls::Error::InvalidCertificate(reason)) => {
+                    anyhow!(LurkError::UpstreamTlsVerificationFailed(addr, reason.to_string()))
+                }
+                _ => anyhow!(err),
+            }
+        })
+    }
+}
+
+impl fmt::Debug for LurkUpstreamTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LurkUpstreamTls").field("options", &self.options).finish()
+    }
+}
+
+impl PartialEq for LurkUpstreamTls {
+    fn eq(&self, other: &Self) -> bool {
+        self.options == other.options
+    }
+}
+
+fn webpki_verifier(ca_bundle: &Path, provider: &Arc<CryptoProvider>) -> Result<Arc<WebPkiServerVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_bundle).with_context(|| format!("failed to read CA bundle {}", ca_bundle.display()))? {
+        roots.add(cert.with_context(|| format!("failed to parse CA bundle {}", ca_bundle.display()))?)?;
+    }
+
+    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::clone(provider))
+        .build()
+        .with_context(|| format!("CA bundle {} has no usable certificates", ca_bundle.display()))
+}
+
+/// Verifier of the certificate chain by the trusted CAs, if there are any, and of the public key of the certificate by the pins.
+#[derive(Debug)]
+struct LurkUpstreamCertVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for LurkUpstreamCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let spki = ParsedCertificate::try_from(end_entity)?.subject_public_key_info();
+        let spki_digest = digest(&SHA256, spki.as_ref());
+        match self.pins.iter().any(|pin| pin == spki_digest.as_ref()) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(CertificateError::ApplicationVerificationFailure.into()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// TLS acceptor of the proxy with self-signed certificate for the ```server_name```.
+/// Returns it along with the certificate and the pin of its public key.
+#[cfg(test)]
+pub fn self_signed_acceptor(server_name: &str) -> (tokio_rustls::TlsAcceptor, CertificateDer<'static>, [u8; 32]) {
+    use rustls::pki_types::PrivateKeyDer;
+
+    let certified_key = rcgen::generate_simple_self_signed(vec![server_name.to_owned()]).unwrap();
+    let cert = certified_key.cert.der().clone();
+    let key = PrivateKeyDer::try_from(certified_key.key_pair.serialize_der()).unwrap();
+    let pin = digest(&SHA256, &certified_key.key_pair.public_key_der())
+        .as_ref()
+        .try_into()
+        .unwrap();
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+
+    (tokio_rustls::TlsAcceptor::from(Arc::new(config)), cert, pin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn parse_pins() {
+        let pin = [7u8; 32];
+        assert_eq!(pin, parse_pin_sha256(&BASE64.encode(pin)).unwrap());
+        assert!(parse_pin_sha256("not base64").is_err());
+        assert!(parse_pin_sha256(&BASE64.encode([7u8; 20])).is_err());
+
+        let mut options = LurkUpstreamTlsOptions::new();
+        options.set_pins([pin]).set_insecure_skip_verify(true);
+        assert!(LurkUpstreamTls::new(options).is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_upstream_certificate() {
+        let (acceptor, cert, pin) = self_signed_acceptor("proxy.lurk.test");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(tcp_stream).await {
+                        stream.write_all(b"ok").await.unwrap();
+                        stream.shutdown().await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let temp_dir = std::env::temp_dir().join(format!("lurk-upstream-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        // Bundle of the CA, which hasn't issued the certificate, and the one also trusting the certificate itself.
+        let mut ca_params = rcgen::CertificateParams::default();
+        ca_params.distinguished_name.push(rcgen::DnType::CommonName, "Lurk Test CA");
+        let ca_bundle = temp_dir.join("ca.pem");
+        std::fs::write(
+            &ca_bundle,
+            ca_params.self_signed(&rcgen::KeyPair::generate().unwrap()).unwrap().pem(),
+        )
+        .unwrap();
+        let ca_bundle_with_cert = temp_dir.join("ca-with-cert.pem");
+        let cert_pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            BASE64.encode(cert.as_ref())
+        );
+        std::fs::write(&ca_bundle_with_cert, std::fs::read_to_string(&ca_bundle).unwrap() + &cert_pem).unwrap();
+
+        let mut trusted = LurkUpstreamTlsOptions::new();
+        trusted.set_server_name("proxy.lurk.test").set_ca_bundle(&ca_bundle_with_cert);
+        let mut pinned = LurkUpstreamTlsOptions::new();
+        pinned.set_pins([pin]);
+        let mut pinned_and_trusted = trusted.clone();
+        pinned_and_trusted.set_pins([pin]);
+        let mut insecure = LurkUpstreamTlsOptions::new();
+        insecure.set_insecure_skip_verify(true);
+        for options in [trusted.clone(), pinned, pinned_and_trusted, insecure] {
+            let tls = LurkUpstreamTls::new(options.clone()).unwrap();
+            let mut stream = tls.connect(addr, TcpStream::connect(addr).await.unwrap()).await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(b"ok", reply.as_slice(), "{:?}", options);
+        }
+
+        let mut untrusted = LurkUpstreamTlsOptions::new();
+        untrusted.set_server_name("proxy.lurk.test").set_ca_bundle(&ca_bundle);
+        let mut misnamed = trusted.clone();
+        misnamed.set_server_name("other.lurk.test");
+        let mut mispinned = LurkUpstreamTlsOptions::new();
+        mispinned.set_pins([[0u8; 32]]);
+        let mut mispinned_and_trusted = trusted;
+        mispinned_and_trusted.set_pins([[0u8; 32]]);
+        for options in [untrusted, misnamed, mispinned, mispinned_and_trusted] {
+            let tls = LurkUpstreamTls::new(options.clone()).unwrap();
+            let err = tls.connect(addr, TcpStream::connect(addr).await.unwrap()).await.unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<LurkError>(), Some(LurkError::UpstreamTlsVerificationFailed(failed_addr, _)) if *failed_addr == addr),
+                "{:?}: {}",
+                options,
+                err
+            );
+        }
+
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use crate::proto::socks5::consts::{self, auth::*};
+use anyhow::{anyhow, ensure, Result};
+use bytes::Bytes;
+use clap::ValueEnum;
+use http_body_util::Empty;
+use hyper::{client::conn::http1, header, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Way the running instance is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LurkPingKind {
+    // SOCKS5 handshake with the proxy.
+    Socks5,
+    // GET request to the healthcheck endpoint of HTTP API.
+    Healthcheck,
+}
+
+/// Check that the instance at ```addr``` responds properly within the timeout.
+pub async fn ping(kind: LurkPingKind, addr: SocketAddr, timeout_after: Duration) -> Result<()> {
+    let ping = async {
+        match kind {
+            LurkPingKind::Socks5 => ping_socks5(addr).await,
+            LurkPingKind::Healthcheck => ping_healthcheck(addr).await,
+        }
+    };
+
+    timeout(timeout_after, ping)
+        .await
+        .map_err(|_| anyhow!("{} hasn't responded in {:?}", addr, timeout_after))?
+}
+
+/// Offer both supported authentication methods: proxy is healthy if it picks any of them.
+async fn ping_socks5(addr: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(&[consts::SOCKS5_VERSION, 2, SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD])
+        .await?;
+
+    let mut response = [0u8; 2];
+    stream.read_exact(&mut response).await?;
+    debug!("SOCKS5 handshake response from {}: {:?}", addr, response);
+
+    ensure!(response[0] == consts::SOCKS5_VERSION, "unexpected SOCKS version {}", response[0]);
+    ensure!(
+        response[1] != SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
+        "none of authentication methods is accepted"
+    );
+
+    Ok(())
+}
+
+async fn ping_healthcheck(addr: SocketAddr) -> Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let request = Request::get("/healthcheck")
+        .header(header::HOST, addr.to_string())
+        .body(Empty::<Bytes>::new())?;
+    let response = sender.send_request(request).await?;
+    debug!("Healthcheck response from {}: {}", addr, response.status());
+
+    ensure!(
+        response.status() == StatusCode::OK,
+        "healthcheck has responded with {}",
+        response.status()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+///
+/// HTTP capsules carrying UDP payloads of the CONNECT-UDP tunnel
+/// over upgraded HTTP/1.1 connection.
+///
+/// RFC 9297, RFC 9298
+/// https://datatracker.ietf.org/doc/html/rfc9298
+///
+use crate::io::{LurkRequest, LurkResponse};
+use anyhow::{bail, ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Type of the capsule carrying HTTP datagram.
+const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+
+/// Context ID of HTTP datagrams carrying whole UDP payloads.
+const CONTEXT_ID_UDP_PAYLOAD: u64 = 0x00;
+
+/// Capsules larger than the maximum UDP payload (plus context ID) are never legit.
+const MAX_CAPSULE_LENGTH: u64 = 65535 + 8;
+
+/// Maximum value encodable as QUIC variable-length integer.
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
+#[derive(Debug, PartialEq)]
+pub struct LurkCapsule {
+    capsule_type: u64,
+    payload: Bytes,
+}
+
+impl LurkCapsule {
+    /// Datagram capsule carrying the UDP payload.
+    pub fn udp_datagram(udp_payload: &[u8]) -> LurkCapsule {
+        let mut payload = BytesMut::with_capacity(1 + udp_payload.len());
+        put_varint(&mut payload, CONTEXT_ID_UDP_PAYLOAD);
+        payload.put_slice(udp_payload);
+
+        LurkCapsule {
+            capsule_type: CAPSULE_TYPE_DATAGRAM,
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Returns UDP payload if the capsule carries it. Capsules of unknown types
+    /// and datagrams with unknown context IDs have to be silently dropped.
+    pub fn udp_payload(&self) -> Option<&[u8]> {
+        if self.capsule_type != CAPSULE_TYPE_DATAGRAM {
+            return None;
+        }
+
+        let (context_id, len) = decode_varint(&self.payload)?;
+        (context_id == CONTEXT_ID_UDP_PAYLOAD).then(|| &self.payload[len..])
+    }
+
+    /// Append encoded capsule to the buffer.
+    pub fn encode(&self, bytes: &mut BytesMut) {
+        bytes.reserve(16 + self.payload.len());
+        put_varint(bytes, self.capsule_type);
+        put_varint(bytes, self.payload.len() as u64);
+        bytes.put_slice(&self.payload);
+    }
+
+    /// Take the capsule from the start of the buffer. Returns None if it hasn't been received completely yet.
+    pub fn decode(bytes: &mut BytesMut) -> Result<Option<LurkCapsule>> {
+        let Some((capsule_type, type_len)) = decode_varint(bytes) else {
+            return Ok(None);
+        };
+        let Some((length, length_len)) = decode_varint(&bytes[type_len..]) else {
+            return Ok(None);
+        };
+        ensure!(length <= MAX_CAPSULE_LENGTH, "capsule of {} bytes is too large", length);
+
+        let header_len = type_len + length_len;
+        if bytes.len() < header_len + length as usize {
+            return Ok(None);
+        }
+
+        let _ = bytes.split_to(header_len);
+        Ok(Some(LurkCapsule {
+            capsule_type,
+            payload: bytes.split_to(length as usize).freeze(),
+        }))
+    }
+}
+
+impl LurkRequest for LurkCapsule {
+    async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<LurkCapsule> {
+        let capsule_type = read_varint(stream).await?;
+        let length = read_varint(stream).await?;
+        ensure!(length <= MAX_CAPSULE_LENGTH, "capsule of {} bytes is too large", length);
+
+        let mut payload = vec![0u8; length as usize];
+        stream.read_exact(&mut payload).await?;
+
+        Ok(LurkCapsule {
+            capsule_type,
+            payload: Bytes::from(payload),
+        })
+    }
+}
+
+impl LurkResponse for LurkCapsule {
+    async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        let mut bytes = BytesMut::new();
+        self.encode(&mut bytes);
+
+        stream.write_all(&bytes).await?;
+        Ok(())
+    }
+}
+
+/// Write QUIC variable-length integer (RFC 9000, section 16) in the shortest form.
+fn put_varint(bytes: &mut BytesMut, value: u64) {
+    debug_assert!(value <= MAX_VARINT, "value is too large for varint");
+    match value {
+        0..=0x3f => bytes.put_u8(value as u8),
+        0x40..=0x3fff => bytes.put_u16(0x4000 | value as u16),
+        0x4000..=0x3fff_ffff => bytes.put_u32(0x8000_0000 | value as u32),
+        _ => bytes.put_u64(0xc000_0000_0000_0000 | value),
+    }
+}
+
+/// Decode variable-length integer from the start of the buffer. Returns its value and length.
+fn decode_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    let len = 1 << (first >> 6);
+    let encoded = bytes.get(..len)?;
+
+    let value = encoded[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, &b| (value << 8) | u64::from(b));
+    Some((value, len))
+}
+
+async fn read_varint<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<u64> {
+    let mut encoded = [0u8; 8];
+    stream.read_exact(&mut encoded[..1]).await?;
+    let len = 1 << (encoded[0] >> 6);
+    stream.read_exact(&mut encoded[1..len]).await?;
+
+    match decode_varint(&encoded[..len]) {
+        Some((value, _)) => Ok(value),
+        None => bail!("malformed varint"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_roundtrip() {
+        for (value, len) in [(0, 1), (37, 1), (15293, 2), (494878333, 4), (151288809941952652, 8)] {
+            let mut bytes = BytesMut::new();
+            put_varint(&mut bytes, value);
+            assert_eq!(len, bytes.len());
+            assert_eq!(Some((value, len)), decode_varint(&bytes));
+        }
+
+        // Example from RFC 9000, appendix A.1.
+        assert_eq!(Some((15293, 2)), decode_varint(&[0x7b, 0xbd]));
+        assert_eq!(None, decode_varint(&[0x7b]));
+    }
+
+    #[tokio::test]
+    async fn datagram_capsule_roundtrip() {
+        let capsule = LurkCapsule::udp_datagram(b"ping");
+        let mut bytes = Vec::new();
+        capsule.write_to(&mut bytes).await.unwrap();
+        assert_eq!(&[0x00, 0x05, 0x00, b'p', b'i', b'n', b'g'], bytes.as_slice());
+
+        let parsed = LurkCapsule::read_from(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(Some(b"ping".as_slice()), parsed.udp_payload());
+
+        // Unknown capsule type is parsed, but doesn't carry UDP payload.
+        let parsed = LurkCapsule::read_from(&mut [0x3f, 0x01, 0x00].as_slice()).await.unwrap();
+        assert_eq!(None, parsed.udp_payload());
+
+        // Oversized capsule is rejected before its payload is read.
+        assert!(LurkCapsule::read_from(&mut [0x00, 0x80, 0x10, 0x00, 0x00].as_slice())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn decode_capsules_from_buffer() {
+        let mut bytes = BytesMut::new();
+        LurkCapsule::udp_datagram(b"ping").encode(&mut bytes);
+        LurkCapsule::udp_datagram(b"pong").encode(&mut bytes);
+        bytes.put_slice(&[0x00, 0x05, 0x00]);
+
+        let parsed = LurkCapsule::decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(Some(b"ping".as_slice()), parsed.udp_payload());
+        let parsed = LurkCapsule::decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(Some(b"pong".as_slice()), parsed.udp_payload());
+
+        // Incomplete capsule is left in the buffer until the rest of it is received.
+        assert_eq!(None, LurkCapsule::decode(&mut bytes).unwrap());
+        assert_eq!(3, bytes.len());
+
+        // Oversized capsule is rejected before its payload is received.
+        let mut bytes = BytesMut::from(&[0x00, 0x80, 0x10, 0x00, 0x00][..]);
+        assert!(LurkCapsule::decode(&mut bytes).is_err());
+    }
+}
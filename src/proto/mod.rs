@@ -1 +1,7 @@
+#[cfg(feature = "http")]
+pub mod capsule;
+// Client side of the protocol (e.g. requests writing) is used by the tests only.
+#[cfg(feature = "socks4")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod socks4;
 pub mod socks5;
@@ -0,0 +1,96 @@
+///
+/// Socks4 and Socks4a protocol implementation details
+///
+/// https://www.openssh.com/txt/socks4.protocol
+/// https://www.openssh.com/txt/socks4a.protocol
+///
+use crate::common::error::{InvalidValue, LurkError};
+
+pub mod request;
+pub mod response;
+
+#[cfg(test)]
+mod test;
+
+#[rustfmt::skip]
+pub(crate) mod consts {
+    pub const SOCKS4_VERSION: u8 = 0x04;
+    /// Version of the reply, it's not the protocol version.
+    pub const SOCKS4_REPLY_VERSION: u8 = 0x00;
+
+    pub mod command {
+        pub const SOCKS4_CMD_CONNECT: u8 = 0x01;
+        pub const SOCKS4_CMD_BIND: u8 = 0x02;
+    }
+
+    pub mod reply {
+        pub const SOCKS4_REPLY_GRANTED: u8 = 0x5a;
+        pub const SOCKS4_REPLY_REJECTED: u8 = 0x5b;
+        pub const SOCKS4_REPLY_IDENTD_UNREACHABLE: u8 = 0x5c;
+        pub const SOCKS4_REPLY_IDENTD_MISMATCH: u8 = 0x5d;
+    }
+}
+
+#[rustfmt::skip]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Command {
+    Connect,
+    Bind
+}
+
+impl TryFrom<u8> for Command {
+    type Error = LurkError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use consts::command::*;
+        match value {
+            SOCKS4_CMD_CONNECT => Ok(Command::Connect),
+            SOCKS4_CMD_BIND => Ok(Command::Bind),
+            _ => Err(LurkError::DataError(InvalidValue::SocksCommand(value))),
+        }
+    }
+}
+
+impl Command {
+    pub fn as_u8(&self) -> u8 {
+        use consts::command::*;
+        match self {
+            Command::Connect => SOCKS4_CMD_CONNECT,
+            Command::Bind => SOCKS4_CMD_BIND,
+        }
+    }
+}
+
+#[rustfmt::skip]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReplyStatus {
+    Granted,
+    Rejected,
+    IdentdUnreachable,
+    IdentdMismatch,
+    OtherReply(u8)
+}
+
+impl ReplyStatus {
+    pub fn as_u8(&self) -> u8 {
+        use consts::reply::*;
+        match self {
+            ReplyStatus::Granted => SOCKS4_REPLY_GRANTED,
+            ReplyStatus::Rejected => SOCKS4_REPLY_REJECTED,
+            ReplyStatus::IdentdUnreachable => SOCKS4_REPLY_IDENTD_UNREACHABLE,
+            ReplyStatus::IdentdMismatch => SOCKS4_REPLY_IDENTD_MISMATCH,
+            ReplyStatus::OtherReply(other) => *other,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> ReplyStatus {
+        use consts::reply::*;
+        match value {
+            SOCKS4_REPLY_GRANTED => ReplyStatus::Granted,
+            SOCKS4_REPLY_REJECTED => ReplyStatus::Rejected,
+            SOCKS4_REPLY_IDENTD_UNREACHABLE => ReplyStatus::IdentdUnreachable,
+            SOCKS4_REPLY_IDENTD_MISMATCH => ReplyStatus::IdentdMismatch,
+            other => ReplyStatus::OtherReply(other),
+        }
+    }
+}
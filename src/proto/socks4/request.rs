@@ -0,0 +1,125 @@
+use super::{consts, Command};
+use crate::{common::error::InvalidValue, io::LurkRequest, net::Address};
+use anyhow::{ensure, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// The client connects to the SOCKS server and sends a request:
+// +----+----+---------+--------+------------+------+
+// | VN | CD | DSTPORT |  DSTIP |   USERID   | NULL |
+// +----+----+---------+--------+------------+------+
+// | 1  | 1  |    2    |    4   |  variable  |  1   |
+// +----+----+---------+--------+------------+------+
+//
+// SOCKS4a client, which can't resolve the destination, sets DSTIP to 0.0.0.x (x is non-zero)
+// and appends the domain name terminated by NULL after the user id.
+
+#[derive(Debug)]
+pub struct RelayRequest {
+    command: Command,
+    endpoint_address: Address,
+    user_id: String,
+}
+
+impl RelayRequest {
+    /// Maximum length of the user id and domain name, the longer ones are rejected.
+    const MAX_STRING_LEN: usize = u8::MAX as usize;
+
+    /// DSTIP of SOCKS4a request telling that domain name follows the user id.
+    const SOCKS4A_IP: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 1);
+
+    pub fn new(command: Command, endpoint_address: Address, user_id: impl Into<String>) -> RelayRequest {
+        RelayRequest {
+            command,
+            endpoint_address,
+            user_id: user_id.into(),
+        }
+    }
+
+    /// Write the request on behalf of the client. Domain names are sent in SOCKS4a form.
+    pub async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        let mut packet = vec![consts::SOCKS4_VERSION, self.command.as_u8()];
+        let name = match &self.endpoint_address {
+            Address::SocketAddress(SocketAddr::V4(addr)) => {
+                packet.extend_from_slice(&addr.port().to_be_bytes());
+                packet.extend_from_slice(&addr.ip().octets());
+                None
+            }
+            Address::SocketAddress(SocketAddr::V6(addr)) => anyhow::bail!("IPv6 address {} can't be sent over SOCKS4", addr),
+            Address::DomainName(name, port) => {
+                packet.extend_from_slice(&port.to_be_bytes());
+                packet.extend_from_slice(&RelayRequest::SOCKS4A_IP.octets());
+                Some(name)
+            }
+        };
+        packet.extend_from_slice(self.user_id.as_bytes());
+        packet.push(0x00);
+        if let Some(name) = name {
+            packet.extend_from_slice(name.as_bytes());
+            packet.push(0x00);
+        }
+        stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn endpoint_address(&self) -> &Address {
+        &self.endpoint_address
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+}
+
+impl LurkRequest for RelayRequest {
+    async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<RelayRequest> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+
+        let (version, cmd) = (header[0], header[1]);
+        ensure!(version == consts::SOCKS4_VERSION, InvalidValue::ProtocolVersion(version));
+        let command = Command::try_from(cmd)?;
+
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+        let user_id = read_null_terminated(stream).await?;
+
+        // 0.0.0.x is never a valid destination, so it marks SOCKS4a request.
+        let endpoint_address = match ip.octets() {
+            [0, 0, 0, x] if x != 0 => {
+                let name = read_null_terminated(stream).await?;
+                ensure!(!name.is_empty(), "SOCKS4a request carries empty domain name");
+                Address::DomainName(name, port)
+            }
+            _ => Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(ip, port))),
+        };
+
+        Ok(RelayRequest {
+            command,
+            endpoint_address,
+            user_id,
+        })
+    }
+}
+
+/// Read the string terminated by NULL. Stream isn't read beyond it: following bytes belong to the tunnel.
+async fn read_null_terminated<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<String> {
+    let mut bytes = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0x00 => break,
+            byte => bytes.push(byte),
+        }
+        ensure!(
+            bytes.len() <= RelayRequest::MAX_STRING_LEN,
+            "SOCKS4 request field is longer than {} bytes",
+            RelayRequest::MAX_STRING_LEN
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
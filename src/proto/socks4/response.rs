@@ -0,0 +1,75 @@
+use super::{consts, ReplyStatus};
+use crate::{common::error::InvalidValue, io::LurkResponse};
+use anyhow::{ensure, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// The SOCKS server sends a reply once the request is evaluated:
+// +----+----+---------+-------+
+// | VN | CD | DSTPORT | DSTIP |
+// +----+----+---------+-------+
+// | 1  | 1  |    2    |   4   |
+// +----+----+---------+-------+
+//
+// VN is the version of the reply and has to be 0.
+
+#[derive(Debug, PartialEq)]
+pub struct RelayResponse {
+    status: ReplyStatus,
+    bound_addr: SocketAddrV4,
+}
+
+impl RelayResponse {
+    pub fn granted(bound_addr: SocketAddr) -> RelayResponse {
+        RelayResponse::new(ReplyStatus::Granted, bound_addr)
+    }
+
+    pub fn rejected(bound_addr: SocketAddr) -> RelayResponse {
+        RelayResponse::new(ReplyStatus::Rejected, bound_addr)
+    }
+
+    /// IPv6 bound address can't be sent over SOCKS4, it's replaced with 0.0.0.0:0 then.
+    pub fn new(status: ReplyStatus, bound_addr: SocketAddr) -> RelayResponse {
+        let bound_addr = match bound_addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+
+        RelayResponse { status, bound_addr }
+    }
+
+    /// Read the response on behalf of the client.
+    pub async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<RelayResponse> {
+        let mut buff = [0u8; 8];
+        stream.read_exact(&mut buff).await?;
+
+        ensure!(buff[0] == consts::SOCKS4_REPLY_VERSION, InvalidValue::ProtocolVersion(buff[0]));
+        let port = u16::from_be_bytes([buff[2], buff[3]]);
+        let ip = Ipv4Addr::new(buff[4], buff[5], buff[6], buff[7]);
+
+        Ok(RelayResponse {
+            status: ReplyStatus::from_u8(buff[1]),
+            bound_addr: SocketAddrV4::new(ip, port),
+        })
+    }
+
+    pub fn status(&self) -> ReplyStatus {
+        self.status
+    }
+
+    pub fn bound_address(&self) -> SocketAddrV4 {
+        self.bound_addr
+    }
+}
+
+impl LurkResponse for RelayResponse {
+    async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        let mut buff = [0u8; 8];
+        buff[0] = consts::SOCKS4_REPLY_VERSION;
+        buff[1] = self.status.as_u8();
+        buff[2..4].copy_from_slice(&self.bound_addr.port().to_be_bytes());
+        buff[4..].copy_from_slice(&self.bound_addr.ip().octets());
+        stream.write_all(&buff).await?;
+        Ok(())
+    }
+}
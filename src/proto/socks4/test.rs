@@ -0,0 +1,133 @@
+use crate::{
+    common::{
+        assertions::{assert_lurk_err, bail_unless_lurk_err},
+        error::{InvalidValue, LurkError},
+    },
+    io::{LurkRequest, LurkResponse},
+    net::{ipv4_socket_address, Address},
+    proto::socks4::{consts::*, request::RelayRequest, response::RelayResponse, Command, ReplyStatus},
+};
+use futures::executor::block_on;
+use proptest::{collection::vec, prelude::*};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+#[tokio::test]
+#[rustfmt::skip]
+async fn rw_relay_messages() {
+    let mut read_stream = tokio_test::io::Builder::new()
+        .read(&[SOCKS4_VERSION, command::SOCKS4_CMD_CONNECT, 10, 10, 127, 0, 0, 1])
+        .read(b"user\0")
+        .read(&[SOCKS4_VERSION, 0xff, 0, 80, 127, 0, 0, 1]) // Incorrect SOCKS4 command
+        .build();
+
+    let request = RelayRequest::read_from(&mut read_stream)
+        .await
+        .expect("Relay request should be parsed");
+
+    assert_eq!(Command::Connect, request.command());
+    assert_eq!("user", request.user_id());
+    assert_eq!(
+        &ipv4_socket_address!(Ipv4Addr::new(127, 0, 0, 1), 2570),
+        request.endpoint_address(),
+        "Relay request parsed incorrectly"
+    );
+
+    bail_unless_lurk_err!(
+        LurkError::DataError(InvalidValue::SocksCommand(0xff)),
+        RelayRequest::read_from(&mut read_stream).await
+    );
+
+    let mut write_stream = tokio_test::io::Builder::new()
+        .write(&[SOCKS4_REPLY_VERSION, reply::SOCKS4_REPLY_GRANTED, 0, 11, 127, 0, 0, 1])
+        .build();
+
+    let response = RelayResponse::granted("127.0.0.1:11".parse().unwrap());
+    response.write_to(&mut write_stream).await.expect("Relay response should be written");
+}
+
+#[tokio::test]
+#[rustfmt::skip]
+async fn read_socks4a_request() {
+    let mut read_stream = tokio_test::io::Builder::new()
+        .read(&[SOCKS4_VERSION, command::SOCKS4_CMD_CONNECT, 0, 80, 0, 0, 0, 0xff])
+        .read(b"\0example.com\0")
+        .build();
+
+    let request = RelayRequest::read_from(&mut read_stream)
+        .await
+        .expect("SOCKS4a request should be parsed");
+
+    assert_eq!("", request.user_id());
+    assert_eq!(&Address::DomainName("example.com".to_owned(), 80), request.endpoint_address());
+}
+
+#[tokio::test]
+async fn reject_malformed_requests() {
+    // Not SOCKS4 version.
+    let bytes = [0x05, command::SOCKS4_CMD_CONNECT, 0, 80, 127, 0, 0, 1, 0];
+    let err = RelayRequest::read_from(&mut bytes.as_slice()).await.unwrap_err();
+    assert_eq!(InvalidValue::ProtocolVersion(0x05), err.downcast::<InvalidValue>().unwrap());
+
+    // User ID isn't terminated within the limit.
+    let bytes = [
+        &[SOCKS4_VERSION, command::SOCKS4_CMD_CONNECT, 0, 80, 127, 0, 0, 1],
+        [b'a'; 300].as_slice(),
+    ]
+    .concat();
+    assert!(RelayRequest::read_from(&mut bytes.as_slice()).await.is_err());
+
+    // SOCKS4a request without domain name.
+    let bytes = [SOCKS4_VERSION, command::SOCKS4_CMD_CONNECT, 0, 80, 0, 0, 0, 1, 0, 0];
+    assert!(RelayRequest::read_from(&mut bytes.as_slice()).await.is_err());
+}
+
+#[test]
+fn ipv6_bound_address_is_unspecified() {
+    let response = RelayResponse::rejected("[::1]:1080".parse().unwrap());
+    assert_eq!(ReplyStatus::Rejected, response.status());
+    assert_eq!(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), response.bound_address());
+}
+
+fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        any::<SocketAddrV4>()
+            .prop_filter(
+                "0.0.0.x marks SOCKS4a request",
+                |addr| !matches!(addr.ip().octets(), [0, 0, 0, x] if x != 0)
+            )
+            .prop_map(|addr| Address::SocketAddress(SocketAddr::V4(addr))),
+        ("[a-z0-9.-]{1,255}", any::<u16>()).prop_map(|(name, port)| Address::DomainName(name, port)),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![Just(Command::Connect), Just(Command::Bind)]
+}
+
+proptest! {
+    #[test]
+    fn relay_messages_round_trip(cmd in command(), addr in address(), user_id in "[[:print:]]{0,255}", bound_addr in any::<SocketAddrV4>(), status in any::<u8>()) {
+        let mut bytes = vec![];
+        block_on(RelayRequest::new(cmd, addr.clone(), user_id.as_str()).write_to(&mut bytes)).unwrap();
+        let request = block_on(RelayRequest::read_from(&mut bytes.as_slice())).unwrap();
+        prop_assert_eq!((cmd, &addr, user_id.as_str()), (request.command(), request.endpoint_address(), request.user_id()));
+
+        let response = RelayResponse::new(ReplyStatus::from_u8(status), SocketAddr::V4(bound_addr));
+        let mut bytes = vec![];
+        block_on(response.write_to(&mut bytes)).unwrap();
+        prop_assert_eq!(response, block_on(RelayResponse::read_from(&mut bytes.as_slice())).unwrap());
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in vec(any::<u8>(), 0..600)) {
+        let _ = block_on(RelayResponse::read_from(&mut bytes.as_slice()));
+        let _ = block_on(RelayRequest::read_from(&mut bytes.as_slice()));
+    }
+
+    #[test]
+    fn relay_request_with_valid_header_never_panics(cmd in any::<u8>(), ip in any::<[u8; 4]>(), tail in vec(any::<u8>(), 0..600)) {
+        // Arbitrary bytes rarely get past the header, so the strings are fuzzed behind the valid one.
+        let bytes = [&[SOCKS4_VERSION, cmd, 0, 80], ip.as_slice(), tail.as_slice()].concat();
+        let _ = block_on(RelayRequest::read_from(&mut bytes.as_slice()));
+    }
+}
@@ -7,11 +7,11 @@
 use crate::{
     auth::LurkAuthMethod,
     common::error::{InvalidValue, LurkError},
-    net::Address,
+    net::{ipv4_socket_address, ipv6_socket_address, Address},
 };
 use anyhow::{bail, Result};
 use bytes::BufMut;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use tokio::io::AsyncReadExt;
 
 pub mod request;
@@ -21,16 +21,24 @@ pub mod response;
 mod test;
 
 #[rustfmt::skip]
-mod consts {
+pub(crate) mod consts {
     pub const SOCKS5_VERSION: u8 = 0x05;
 
     pub mod auth {
         pub const SOCKS5_AUTH_METHOD_NONE: u8 = 0x00;
         pub const SOCKS5_AUTH_METHOD_GSSAPI: u8 = 0x01;
         pub const SOCKS5_AUTH_METHOD_PASSWORD: u8 = 0x02;
+        pub const SOCKS5_AUTH_METHOD_PRIVATE: std::ops::RangeInclusive<u8> = 0x80..=0xfe;
         pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE: u8 = 0xff;
     }
 
+    // RFC 1929
+    pub mod password {
+        pub const SOCKS5_PASSWORD_AUTH_VERSION: u8 = 0x01;
+        pub const SOCKS5_PASSWORD_AUTH_SUCCEEDED: u8 = 0x00;
+        pub const SOCKS5_PASSWORD_AUTH_FAILED: u8 = 0x01;
+    }
+
     pub mod command {
         pub const SOCKS5_CMD_CONNECT: u8 = 0x01;
         pub const SOCKS5_CMD_BIND: u8 = 0x02;
@@ -63,17 +71,18 @@ impl LurkAuthMethod {
             SOCKS5_AUTH_METHOD_NONE => Ok(LurkAuthMethod::None),
             SOCKS5_AUTH_METHOD_GSSAPI => Ok(LurkAuthMethod::GssAPI),
             SOCKS5_AUTH_METHOD_PASSWORD => Ok(LurkAuthMethod::Password),
+            _ if SOCKS5_AUTH_METHOD_PRIVATE.contains(&value) => Ok(LurkAuthMethod::Private(value)),
             _ => bail!(LurkError::DataError(InvalidValue::AuthMethod(value))),
         }
     }
 
-    #[cfg(test)]
     pub fn as_socks5_const(&self) -> u8 {
         use self::consts::auth::*;
         match self {
             LurkAuthMethod::None => SOCKS5_AUTH_METHOD_NONE,
             LurkAuthMethod::GssAPI => SOCKS5_AUTH_METHOD_GSSAPI,
             LurkAuthMethod::Password => SOCKS5_AUTH_METHOD_PASSWORD,
+            LurkAuthMethod::Private(code) => *code,
         }
     }
 }
@@ -102,41 +111,78 @@ impl TryFrom<u8> for Command {
 }
 
 impl Address {
+    /// Maximum length of the encoded address: type, domain name length, domain name and port.
+    pub const MAX_ENCODED_LEN: usize = 1 + 1 + u8::MAX as usize + 2;
+
     pub async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Address> {
-        use consts::address::*;
-        let address_type = stream.read_u8().await?;
+        // Address type and the first address byte (domain name length) tell
+        // how many bytes are left, so the rest is read at once.
+        let mut buff = [0u8; Address::MAX_ENCODED_LEN];
+        stream.read_exact(&mut buff[..2]).await?;
+
+        let len = Address::encoded_len(buff[0], buff[1])?;
+        stream.read_exact(&mut buff[2..len]).await?;
+
+        Address::decode(&buff[..len])
+    }
 
+    /// Returns length of the encoded address judging by its type and the first address byte.
+    pub fn encoded_len(address_type: u8, first_byte: u8) -> Result<usize> {
+        use consts::address::*;
         match address_type {
-            SOCKS5_ADDR_TYPE_IPV4 => Address::read_ipv4(stream).await,
-            SOCKS5_ADDR_TYPE_IPV6 => Address::read_ipv6(stream).await,
+            SOCKS5_ADDR_TYPE_IPV4 => Ok(1 + 4 + 2),
+            SOCKS5_ADDR_TYPE_IPV6 => Ok(1 + 16 + 2),
+            SOCKS5_ADDR_TYPE_DOMAIN_NAME => Ok(1 + 1 + first_byte as usize + 2),
+            _ => bail!(LurkError::DataError(InvalidValue::AddressType(address_type))),
+        }
+    }
+
+    /// Parse the address encoded in the ```bytes``` (including address type).
+    /// Length of ```bytes``` is expected to be equal to the ```encoded_len```.
+    pub fn decode(bytes: &[u8]) -> Result<Address> {
+        use consts::address::*;
+        debug_assert_eq!(Address::encoded_len(bytes[0], bytes[1]).ok(), Some(bytes.len()));
+
+        let (address, port) = bytes[1..].split_at(bytes.len() - 3);
+        let port = u16::from_be_bytes([port[0], port[1]]);
+
+        match bytes[0] {
+            SOCKS5_ADDR_TYPE_IPV4 => {
+                let ipv4: [u8; 4] = address.try_into()?;
+                Ok(ipv4_socket_address!(Ipv4Addr::from(ipv4), port))
+            }
+            SOCKS5_ADDR_TYPE_IPV6 => {
+                let ipv6: [u8; 16] = address.try_into()?;
+                Ok(ipv6_socket_address!(Ipv6Addr::from(ipv6), port))
+            }
             SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
-                let len = stream.read_u8().await?;
-                Address::read_domain_name(stream, len).await
+                let name = String::from_utf8(address[1..].to_vec()).map_err(LurkError::DomainNameDecodingFailed)?;
+                Ok(Address::DomainName(name, port))
             }
-            _ => bail!(LurkError::DataError(InvalidValue::AddressType(address_type))),
+            address_type => bail!(LurkError::DataError(InvalidValue::AddressType(address_type))),
         }
     }
 
-    pub fn write_to<T: BufMut>(&self, buf: &mut T) {
+    pub fn write_to<T: BufMut>(&self, buf: &mut T) -> Result<()> {
         match self {
             Address::SocketAddress(SocketAddr::V4(ipv4_addr)) => {
                 buf.put_u8(consts::address::SOCKS5_ADDR_TYPE_IPV4);
-                Address::write_ipv4(buf, ipv4_addr)
+                Address::write_ipv4(buf, ipv4_addr);
             }
             Address::SocketAddress(SocketAddr::V6(ipv6_addr)) => {
                 buf.put_u8(consts::address::SOCKS5_ADDR_TYPE_IPV6);
-                Address::write_ipv6(buf, ipv6_addr)
+                Address::write_ipv6(buf, ipv6_addr);
             }
             Address::DomainName(name, port) => {
                 buf.put_u8(consts::address::SOCKS5_ADDR_TYPE_DOMAIN_NAME);
-                Address::write_domain_name(buf, name, port)
+                Address::write_domain_name(buf, name, port)?;
             }
         }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-#[allow(dead_code)]
 pub enum ReplyStatus {
     Succeeded,
     GeneralFailure,
@@ -152,7 +198,7 @@ pub enum ReplyStatus {
 
 impl ReplyStatus {
     #[rustfmt::skip]
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         match self {
             ReplyStatus::Succeeded               => consts::reply::SOCKS5_REPLY_SUCCEEDED,
             ReplyStatus::GeneralFailure          => consts::reply::SOCKS5_REPLY_GENERAL_FAILURE,
@@ -166,6 +212,23 @@ impl ReplyStatus {
             ReplyStatus::OtherReply(other)       => other,
         }
     }
+
+    #[rustfmt::skip]
+    pub fn from_u8(value: u8) -> ReplyStatus {
+        use consts::reply::*;
+        match value {
+            SOCKS5_REPLY_SUCCEEDED                  => ReplyStatus::Succeeded,
+            SOCKS5_REPLY_GENERAL_FAILURE            => ReplyStatus::GeneralFailure,
+            SOCKS5_REPLY_CONNECTION_NOT_ALLOWED     => ReplyStatus::ConnectionNotAllowed,
+            SOCKS5_REPLY_NETWORK_UNREACHABLE        => ReplyStatus::NetworkUnreachable,
+            SOCKS5_REPLY_HOST_UNREACHABLE           => ReplyStatus::HostUnreachable,
+            SOCKS5_REPLY_CONNECTION_REFUSED         => ReplyStatus::ConnectionRefused,
+            SOCKS5_REPLY_TTL_EXPIRED                => ReplyStatus::TtlExpired,
+            SOCKS5_REPLY_COMMAND_NOT_SUPPORTED      => ReplyStatus::CommandNotSupported,
+            SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED => ReplyStatus::AddressTypeNotSupported,
+            other                                   => ReplyStatus::OtherReply(other),
+        }
+    }
 }
 
 impl From<LurkError> for ReplyStatus {
@@ -173,6 +236,11 @@ impl From<LurkError> for ReplyStatus {
         match err {
             LurkError::UnsupportedSocksCommand(_) => ReplyStatus::CommandNotSupported,
             LurkError::UnresolvedDomainName(_) => ReplyStatus::HostUnreachable,
+            LurkError::BindAcceptTimeout(..) => ReplyStatus::TtlExpired,
+            LurkError::DestinationBlocked(..) | LurkError::SessionLimitExceeded(..) | LurkError::ClientConnectionLimitExceeded(..) => {
+                ReplyStatus::ConnectionNotAllowed
+            }
+            LurkError::UpstreamProxyRefused(_, status) => status,
             _ => ReplyStatus::GeneralFailure,
         }
     }
@@ -1,11 +1,13 @@
 use super::{Address, Command};
-use crate::{auth::LurkAuthMethod, common::error::InvalidValue, io::LurkRequest, proto::socks5::consts};
+use crate::{
+    auth::LurkAuthMethod,
+    common::error::{InvalidValue, LurkError},
+    io::LurkRequest,
+    proto::socks5::consts,
+};
 use anyhow::{ensure, Result};
 use std::collections::HashSet;
-use tokio::io::AsyncReadExt;
-
-#[cfg(test)]
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 // The client connects to the server, and sends a
 // version identifier/method selection message:
@@ -21,16 +23,23 @@ pub struct HandshakeRequest {
 }
 
 impl HandshakeRequest {
-    #[cfg(test)]
+    /// Maximum length of the request: version, number of methods and methods.
+    const MAX_LEN: usize = 2 + u8::MAX as usize;
+
     pub fn new(auth_methods: HashSet<LurkAuthMethod>) -> HandshakeRequest {
         HandshakeRequest { auth_methods }
     }
 
-    #[cfg(test)]
-    pub async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) {
+    /// Write the request on behalf of the client.
+    pub async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        ensure!(
+            (1..=u8::MAX as usize).contains(&self.auth_methods.len()),
+            "handshake request should offer 1 to 255 methods"
+        );
         let mut packet = vec![consts::SOCKS5_VERSION, self.auth_methods.len() as u8];
         self.auth_methods.iter().for_each(|m| packet.push(m.as_socks5_const()));
-        stream.write_all(&packet).await.unwrap();
+        stream.write_all(&packet).await?;
+        Ok(())
     }
 
     pub fn auth_methods(&self) -> &HashSet<LurkAuthMethod> {
@@ -43,33 +52,96 @@ impl LurkRequest for HandshakeRequest {
     where
         Self: std::marker::Sized,
     {
-        let mut header: [u8; 2] = [0, 0];
-        stream.read_exact(&mut header).await?;
+        let mut buff = [0u8; HandshakeRequest::MAX_LEN];
+        stream.read_exact(&mut buff[..2]).await?;
 
-        let (version, nmethods) = (header[0], header[1]);
+        let (version, nmethods) = (buff[0], buff[1] as usize);
 
         // Bail out if version is not supported.
         ensure!(version == consts::SOCKS5_VERSION, InvalidValue::ProtocolVersion(version));
 
         // Parse requested auth methods.
-        let auth_methods = match nmethods {
-            0 => HashSet::new(),
-            n => {
-                let mut methods = vec![0; n.into()];
-                stream.read_exact(&mut methods).await?;
-
-                // Drop unknown auth methods.
-                methods
-                    .iter()
-                    .map(|&m| LurkAuthMethod::from_socks5_const(m))
-                    .collect::<Result<HashSet<LurkAuthMethod>>>()?
-            }
-        };
+        let methods = &mut buff[2..2 + nmethods];
+        stream.read_exact(methods).await?;
+
+        let auth_methods = methods
+            .iter()
+            .map(|&m| LurkAuthMethod::from_socks5_const(m))
+            .collect::<Result<HashSet<LurkAuthMethod>>>()?;
 
         Ok(HandshakeRequest { auth_methods })
     }
 }
 
+// Once the password method has been selected, the client sends
+// username/password request (RFC 1929):
+// +----+------+----------+------+----------+
+// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+// +----+------+----------+------+----------+
+// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+// +----+------+----------+------+----------+
+
+#[derive(Debug)]
+pub struct PasswordAuthRequest {
+    username: String,
+    password: String,
+}
+
+impl PasswordAuthRequest {
+    pub fn new(username: &str, password: &str) -> PasswordAuthRequest {
+        PasswordAuthRequest {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    /// Write the request on behalf of the client.
+    pub async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        ensure!(
+            self.username.len() <= u8::MAX as usize && self.password.len() <= u8::MAX as usize,
+            "username and password should be 255 bytes long at most"
+        );
+        let mut packet = vec![consts::password::SOCKS5_PASSWORD_AUTH_VERSION, self.username.len() as u8];
+        packet.extend_from_slice(self.username.as_bytes());
+        packet.push(self.password.len() as u8);
+        packet.extend_from_slice(self.password.as_bytes());
+        stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+impl LurkRequest for PasswordAuthRequest {
+    async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<PasswordAuthRequest> {
+        // Every read covers the length of the next field, so the whole
+        // request takes three reads: VER+ULEN, UNAME+PLEN and PASSWD.
+        let mut buff = [0u8; 1 + u8::MAX as usize];
+        stream.read_exact(&mut buff[..2]).await?;
+
+        let (version, username_len) = (buff[0], buff[1] as usize);
+        ensure!(
+            version == consts::password::SOCKS5_PASSWORD_AUTH_VERSION,
+            LurkError::DataError(InvalidValue::PasswordAuthVersion(version))
+        );
+
+        stream.read_exact(&mut buff[..username_len + 1]).await?;
+        let username = String::from_utf8_lossy(&buff[..username_len]).into_owned();
+
+        let password_len = buff[username_len] as usize;
+        stream.read_exact(&mut buff[..password_len]).await?;
+        let password = String::from_utf8_lossy(&buff[..password_len]).into_owned();
+
+        Ok(PasswordAuthRequest { username, password })
+    }
+}
+
 // The SOCKS request information is sent by the client as
 // soon as it has established a connection to the SOCKS
 // server, and completed the authentication negotiations.
@@ -86,6 +158,27 @@ pub struct RelayRequest {
 }
 
 impl RelayRequest {
+    /// Maximum length of the request: header and the longest address.
+    const MAX_LEN: usize = 3 + Address::MAX_ENCODED_LEN;
+
+    pub fn new(command: Command, endpoint_address: Address) -> RelayRequest {
+        RelayRequest { command, endpoint_address }
+    }
+
+    /// Write the request on behalf of the client.
+    pub async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        use consts::command::*;
+        let cmd = match self.command {
+            Command::TCPConnect => SOCKS5_CMD_CONNECT,
+            Command::TCPBind => SOCKS5_CMD_BIND,
+            Command::UDPAssociate => SOCKS5_CMD_UDP_ASSOCIATE,
+        };
+        let mut packet = vec![consts::SOCKS5_VERSION, cmd, 0x00];
+        self.endpoint_address.write_to(&mut packet)?;
+        stream.write_all(&packet).await?;
+        Ok(())
+    }
+
     pub fn command(&self) -> Command {
         self.command
     }
@@ -97,8 +190,11 @@ impl RelayRequest {
 
 impl LurkRequest for RelayRequest {
     async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<RelayRequest> {
-        let mut buff: [u8; 3] = [0, 0, 0];
-        stream.read_exact(&mut buff).await?;
+        // Header along with the address type and the first address byte tell
+        // the length of the whole request, so the rest is read at once.
+        // Stream isn't read beyond the request: following bytes belong to the tunnel.
+        let mut buff = [0u8; RelayRequest::MAX_LEN];
+        stream.read_exact(&mut buff[..5]).await?;
 
         let (version, cmd, reserved) = (buff[0], buff[1], buff[2]);
 
@@ -106,7 +202,10 @@ impl LurkRequest for RelayRequest {
         ensure!(reserved == 0x00, InvalidValue::ReservedValue(reserved));
 
         let command = Command::try_from(cmd)?;
-        let endpoint_address = Address::read_from(stream).await?;
+
+        let len = 3 + Address::encoded_len(buff[3], buff[4])?;
+        stream.read_exact(&mut buff[5..len]).await?;
+        let endpoint_address = Address::decode(&buff[3..len])?;
 
         Ok(RelayRequest { command, endpoint_address })
     }
@@ -1,13 +1,14 @@
 use super::{consts, Address, ReplyStatus};
-use crate::{auth::LurkAuthMethod, io::LurkResponse};
-use anyhow::{bail, Result};
-use bytes::{BufMut, BytesMut};
+use crate::{
+    auth::LurkAuthMethod,
+    common::error::{InvalidValue, LurkError},
+    io::LurkResponse,
+};
+use anyhow::{bail, ensure, Result};
+use bytes::BufMut;
 use log::error;
 use std::net::SocketAddr;
-use tokio::io::AsyncWriteExt;
-
-#[cfg(test)]
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 // The server selects from one of the methods given in METHODS, and
 // sends a METHOD selection message:
@@ -27,13 +28,18 @@ impl HandshakeResponse {
         HandshakeResponseBuilder { method: None }
     }
 
-    #[cfg(test)]
-    pub async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> HandshakeResponse {
+    /// Read the response on behalf of the client.
+    pub async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<HandshakeResponse> {
         let mut header: [u8; 2] = [0, 0];
-        stream.read_exact(&mut header).await.unwrap();
+        stream.read_exact(&mut header).await?;
+
+        ensure!(header[0] == consts::SOCKS5_VERSION, InvalidValue::ProtocolVersion(header[0]));
+        Ok(HandshakeResponse { method: header[1] })
+    }
 
-        assert!(header[0] == consts::SOCKS5_VERSION);
-        HandshakeResponse { method: header[1] }
+    /// Method selected by the server, none if the server found no acceptable method.
+    pub fn auth_method(&self) -> Option<LurkAuthMethod> {
+        LurkAuthMethod::from_socks5_const(self.method).ok()
     }
 }
 
@@ -61,7 +67,7 @@ pub struct HandshakeResponseBuilder {
 impl HandshakeResponseBuilder {
     pub fn with_auth_method(&mut self, selected_method: LurkAuthMethod) -> &mut HandshakeResponseBuilder {
         debug_assert!(self.method.is_none(), "should be unset");
-        self.method = Some(selected_method as u8);
+        self.method = Some(selected_method.as_socks5_const());
         self
     }
 
@@ -78,6 +84,73 @@ impl HandshakeResponseBuilder {
     }
 }
 
+// The server verifies the supplied username and password, and sends
+// the following response (RFC 1929):
+// +----+--------+
+// |VER | STATUS |
+// +----+--------+
+// | 1  |   1    |
+// +----+--------+
+
+#[derive(Debug, PartialEq)]
+pub struct PasswordAuthResponse {
+    status: u8,
+}
+
+impl PasswordAuthResponse {
+    pub fn builder() -> PasswordAuthResponseBuilder {
+        PasswordAuthResponseBuilder { status: None }
+    }
+
+    /// Read the response on behalf of the client.
+    pub async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<PasswordAuthResponse> {
+        let mut header: [u8; 2] = [0, 0];
+        stream.read_exact(&mut header).await?;
+
+        ensure!(
+            header[0] == consts::password::SOCKS5_PASSWORD_AUTH_VERSION,
+            LurkError::DataError(InvalidValue::PasswordAuthVersion(header[0]))
+        );
+        Ok(PasswordAuthResponse { status: header[1] })
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.status == consts::password::SOCKS5_PASSWORD_AUTH_SUCCEEDED
+    }
+}
+
+impl LurkResponse for PasswordAuthResponse {
+    async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        let response: [u8; 2] = [consts::password::SOCKS5_PASSWORD_AUTH_VERSION, self.status];
+        stream.write_all(&response).await?;
+        Ok(())
+    }
+}
+
+pub struct PasswordAuthResponseBuilder {
+    status: Option<u8>,
+}
+
+impl PasswordAuthResponseBuilder {
+    pub fn with_success(&mut self) -> &mut PasswordAuthResponseBuilder {
+        debug_assert!(self.status.is_none(), "should be unset");
+        self.status = Some(consts::password::SOCKS5_PASSWORD_AUTH_SUCCEEDED);
+        self
+    }
+
+    pub fn with_failure(&mut self) -> &mut PasswordAuthResponseBuilder {
+        debug_assert!(self.status.is_none(), "should be unset");
+        self.status = Some(consts::password::SOCKS5_PASSWORD_AUTH_FAILED);
+        self
+    }
+
+    pub fn build(&self) -> PasswordAuthResponse {
+        PasswordAuthResponse {
+            status: self.status.expect("Expected password authentication status"),
+        }
+    }
+}
+
 // The server evaluates the relay request, and returns a reply formed as follows:
 // +----+-----+-------+------+----------+----------+
 // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
@@ -85,7 +158,7 @@ impl HandshakeResponseBuilder {
 // | 1  |  1  | X'00' |  1   | Variable |    2     |
 // +----+-----+-------+------+----------+----------+
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RelayResponse {
     bound_addr: Address,
     status: ReplyStatus,
@@ -98,14 +171,39 @@ impl RelayResponse {
             status: None,
         }
     }
+
+    /// Read the response on behalf of the client.
+    pub async fn read_from<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<RelayResponse> {
+        let mut header = [0u8; 3];
+        stream.read_exact(&mut header).await?;
+
+        ensure!(header[0] == consts::SOCKS5_VERSION, InvalidValue::ProtocolVersion(header[0]));
+        Ok(RelayResponse {
+            bound_addr: Address::read_from(stream).await?,
+            status: ReplyStatus::from_u8(header[1]),
+        })
+    }
+
+    pub fn status(&self) -> ReplyStatus {
+        self.status
+    }
+
+    pub fn bound_address(&self) -> &Address {
+        &self.bound_addr
+    }
 }
 
 impl LurkResponse for RelayResponse {
     async fn write_to<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
-        let mut bytes = BytesMut::new();
-        bytes.put_slice(&[consts::SOCKS5_VERSION, self.status.as_u8(), 0x00]);
-        self.bound_addr.write_to(&mut bytes);
-        stream.write_all(&bytes).await?;
+        // Response is serialized on the stack and written by the single call.
+        let mut buff = [0u8; 3 + Address::MAX_ENCODED_LEN];
+        let unused_len = {
+            let mut unused = &mut buff[..];
+            unused.put_slice(&[consts::SOCKS5_VERSION, self.status.as_u8(), 0x00]);
+            self.bound_addr.write_to(&mut unused)?;
+            unused.len()
+        };
+        stream.write_all(&buff[..buff.len() - unused_len]).await?;
         Ok(())
     }
 }
@@ -128,12 +226,26 @@ impl RelayResponseBuilder {
         self
     }
 
+    pub fn with_status(&mut self, status: ReplyStatus) -> &mut RelayResponseBuilder {
+        debug_assert!(self.status.is_none(), "should be unset");
+        self.status = Some(status);
+        self
+    }
+
     pub fn with_bound_address(&mut self, bound_addr: SocketAddr) -> &mut RelayResponseBuilder {
         debug_assert!(self.bound_addr.is_none(), "should be unset");
         self.bound_addr = Some(Address::SocketAddress(bound_addr));
         self
     }
 
+    /// Bound address of the domain name type, e.g. when the reply names the host rather than its IP.
+    /// Response with the name longer than 255 bytes fails to be written.
+    pub fn with_bound_domain_name(&mut self, name: impl Into<String>, port: u16) -> &mut RelayResponseBuilder {
+        debug_assert!(self.bound_addr.is_none(), "should be unset");
+        self.bound_addr = Some(Address::DomainName(name.into(), port));
+        self
+    }
+
     pub fn build(&self) -> RelayResponse {
         RelayResponse {
             bound_addr: self.bound_addr.clone().expect("Bound address expected"),
@@ -5,19 +5,21 @@ use crate::{
         error::{InvalidValue, LurkError},
     },
     io::{LurkRequest, LurkResponse},
-    net::ipv4_socket_address,
+    net::{ipv4_socket_address, ipv6_socket_address},
     proto::socks5::{
         consts::*,
-        request::{HandshakeRequest, RelayRequest},
-        response::{HandshakeResponse, RelayResponse},
+        request::{HandshakeRequest, PasswordAuthRequest, RelayRequest},
+        response::{HandshakeResponse, PasswordAuthResponse, RelayResponse},
         Address, Command, ReplyStatus,
     },
 };
 use anyhow::anyhow;
+use futures::executor::block_on;
+use proptest::{collection::vec, prelude::*};
 use std::{
     collections::HashSet,
     io,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 
 #[tokio::test]
@@ -68,6 +70,34 @@ async fn rw_handshake_messages() {
         .expect("Handshake response with NoAcceptableMethod should be written");
 }
 
+#[tokio::test]
+#[rustfmt::skip]
+async fn rw_password_auth_messages() {
+    let mut read_stream = tokio_test::io::Builder::new()
+        .read(&[password::SOCKS5_PASSWORD_AUTH_VERSION, 5, b'a', b'l', b'i', b'c', b'e', 3, b'p', b'w', b'd'])
+        .read(&[0x05, 0x00])
+        .build();
+
+    let request = PasswordAuthRequest::read_from(&mut read_stream)
+        .await
+        .expect("Password authentication request should be parsed");
+
+    assert_eq!(("alice", "pwd"), (request.username(), request.password()));
+
+    bail_unless_lurk_err!(
+        LurkError::DataError(InvalidValue::PasswordAuthVersion(0x05)),
+        PasswordAuthRequest::read_from(&mut read_stream).await
+    );
+
+    let mut write_stream = tokio_test::io::Builder::new()
+        .write(&[password::SOCKS5_PASSWORD_AUTH_VERSION, password::SOCKS5_PASSWORD_AUTH_SUCCEEDED])
+        .write(&[password::SOCKS5_PASSWORD_AUTH_VERSION, password::SOCKS5_PASSWORD_AUTH_FAILED])
+        .build();
+
+    PasswordAuthResponse::builder().with_success().build().write_to(&mut write_stream).await.unwrap();
+    PasswordAuthResponse::builder().with_failure().build().write_to(&mut write_stream).await.unwrap();
+}
+
 #[tokio::test]
 #[rustfmt::skip]
 async fn rw_relay_messages() {
@@ -79,7 +109,7 @@ async fn rw_relay_messages() {
             address::SOCKS5_ADDR_TYPE_IPV4,
             127, 0, 0, 1, 10, 10,
         ])
-        .read(&[SOCKS5_VERSION, 0xff, 0x00]) // Incorrect SOCKS5 command
+        .read(&[SOCKS5_VERSION, 0xff, 0x00, address::SOCKS5_ADDR_TYPE_IPV4, 127]) // Incorrect SOCKS5 command
         .build();
 
     let request = RelayRequest::read_from(&mut read_stream)
@@ -121,7 +151,7 @@ async fn rw_relay_messages() {
 async fn rw_address() {
     let mut moked_stream = tokio_test::io::Builder::new()
         .read(&[address::SOCKS5_ADDR_TYPE_IPV4, 127, 0, 0, 1, 10, 10]) // correct IPv4
-        .read(&[0xff]) // invalid address type
+        .read(&[0xff, 0x00]) // invalid address type
         .build();
 
     let addr = Address::read_from(&mut moked_stream).await.expect("Parsed IPv4 address");
@@ -134,8 +164,99 @@ async fn rw_address() {
 
     let addr_to_write = ipv4_socket_address!(Ipv4Addr::new(127, 0, 0, 1), 2570);
     let mut written_address = vec![];
-    addr_to_write.write_to(&mut written_address);
+    addr_to_write.write_to(&mut written_address).unwrap();
     assert_eq!(vec![address::SOCKS5_ADDR_TYPE_IPV4, 127, 0, 0, 1, 10, 10], written_address);
+
+    let addr_to_write = ipv6_socket_address!(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 2570);
+    let mut written_address = vec![];
+    addr_to_write.write_to(&mut written_address).unwrap();
+    assert_eq!(
+        vec![address::SOCKS5_ADDR_TYPE_IPV6, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 10, 10],
+        written_address
+    );
+}
+
+#[tokio::test]
+#[rustfmt::skip]
+async fn rw_relay_messages_with_ipv6_address() {
+    let ipv6_bytes = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    let mut write_stream = tokio_test::io::Builder::new()
+        .write(&[&[SOCKS5_VERSION, command::SOCKS5_CMD_CONNECT, 0x00, address::SOCKS5_ADDR_TYPE_IPV6], ipv6_bytes.as_slice(), &[0, 80]].concat())
+        .write(&[&[SOCKS5_VERSION, reply::SOCKS5_REPLY_SUCCEEDED, 0x00, address::SOCKS5_ADDR_TYPE_IPV6], ipv6_bytes.as_slice(), &[4, 56]].concat())
+        .build();
+
+    let endpoint = ipv6_socket_address!(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80);
+    RelayRequest::new(Command::TCPConnect, endpoint)
+        .write_to(&mut write_stream)
+        .await
+        .expect("Relay request should be written");
+
+    RelayResponse::builder()
+        .with_success()
+        .with_bound_address("[2001:db8::1]:1080".parse().unwrap())
+        .build()
+        .write_to(&mut write_stream)
+        .await
+        .expect("Relay response with IPv6 bound address should be written");
+}
+
+#[tokio::test]
+#[rustfmt::skip]
+async fn rw_relay_response_with_domain_name() {
+    let mut write_stream = tokio_test::io::Builder::new()
+        .write(&[&[SOCKS5_VERSION, reply::SOCKS5_REPLY_SUCCEEDED, 0x00, address::SOCKS5_ADDR_TYPE_DOMAIN_NAME, 15], b"www.example.com".as_slice(), &[4, 56]].concat())
+        .build();
+
+    let response = RelayResponse::builder()
+        .with_success()
+        .with_bound_domain_name("www.example.com", 1080)
+        .build();
+    response.write_to(&mut write_stream).await.expect("Relay response with domain name should be written");
+
+    let mut read_stream = tokio_test::io::Builder::new()
+        .read(&[&[SOCKS5_VERSION, reply::SOCKS5_REPLY_SUCCEEDED, 0x00, address::SOCKS5_ADDR_TYPE_DOMAIN_NAME, 15], b"www.example.com".as_slice(), &[4, 56]].concat())
+        .build();
+
+    assert_eq!(response, RelayResponse::read_from(&mut read_stream).await.expect("Relay response should be parsed"));
+    assert_eq!(&Address::DomainName("www.example.com".to_owned(), 1080), response.bound_address());
+}
+
+#[tokio::test]
+async fn refuse_to_write_too_long_domain_name() {
+    let name = "a".repeat(u8::MAX as usize + 1);
+
+    // Nothing is written, as the length of the name doesn't fit its prefix.
+    let mut written = vec![];
+    let response = RelayResponse::builder()
+        .with_success()
+        .with_bound_domain_name(name.as_str(), 1080)
+        .build();
+    assert!(response.write_to(&mut written).await.is_err());
+    assert!(RelayRequest::new(Command::TCPConnect, Address::DomainName(name, 1080))
+        .write_to(&mut written)
+        .await
+        .is_err());
+    assert!(written.is_empty());
+}
+
+#[test]
+#[rustfmt::skip]
+fn decode_address() {
+    use address::*;
+
+    let ipv4 = [SOCKS5_ADDR_TYPE_IPV4, 127, 0, 0, 1, 10, 10];
+    let ipv6 = [SOCKS5_ADDR_TYPE_IPV6, 0, 0, 0, 0, 0, 0xff, 0xff, 0xc0, 0x0a, 0x02, 0xff, 0xca, 0x1, 0x0, 0x11, 0xff, 10, 10];
+    let domain = [&[SOCKS5_ADDR_TYPE_DOMAIN_NAME, 15], b"www.example.com".as_slice(), &[10, 10]].concat();
+
+    for (bytes, expected) in [
+        (ipv4.as_slice(), ipv4_socket_address!(Ipv4Addr::new(127, 0, 0, 1), 2570)),
+        (ipv6.as_slice(), ipv6_socket_address!(Ipv6Addr::new(0, 0, 0xff, 0xffc0, 0xa02, 0xffca, 0x100, 0x11ff), 2570)),
+        (domain.as_slice(), Address::DomainName("www.example.com".to_owned(), 2570)),
+    ] {
+        assert_eq!(bytes.len(), Address::encoded_len(bytes[0], bytes[1]).unwrap());
+        assert_eq!(expected, Address::decode(bytes).unwrap());
+    }
 }
 
 #[test]
@@ -151,3 +272,112 @@ fn error_to_relay_status_cast() {
     assert_eq!(ReplyStatus::HostUnreachable,         anyhow!(io::Error::from(io::ErrorKind::ConnectionAborted)).into());
     assert_eq!(ReplyStatus::GeneralFailure,          anyhow!(io::Error::from(io::ErrorKind::NotFound)).into());
 }
+
+fn auth_method() -> impl Strategy<Value = LurkAuthMethod> {
+    prop_oneof![
+        Just(LurkAuthMethod::None),
+        Just(LurkAuthMethod::GssAPI),
+        Just(LurkAuthMethod::Password),
+        auth::SOCKS5_AUTH_METHOD_PRIVATE.prop_map(LurkAuthMethod::Private),
+    ]
+}
+
+fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        (any::<Ipv4Addr>(), any::<u16>()).prop_map(|(ip, port)| ipv4_socket_address!(ip, port)),
+        (any::<Ipv6Addr>(), any::<u16>()).prop_map(|(ip, port)| ipv6_socket_address!(ip, port)),
+        // Up to 255 bytes of UTF-8.
+        ("\\PC{0,63}", any::<u16>()).prop_map(|(name, port)| Address::DomainName(name, port)),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![Just(Command::TCPConnect), Just(Command::TCPBind), Just(Command::UDPAssociate)]
+}
+
+proptest! {
+    #[test]
+    fn handshake_messages_round_trip(methods in vec(auth_method(), 1..=u8::MAX as usize), selected in auth_method()) {
+        let methods = HashSet::from_iter(methods);
+        let mut bytes = vec![];
+        block_on(HandshakeRequest::new(methods.clone()).write_to(&mut bytes)).unwrap();
+        let request = block_on(HandshakeRequest::read_from(&mut bytes.as_slice())).unwrap();
+        prop_assert_eq!(&methods, request.auth_methods());
+
+        let response = HandshakeResponse::builder().with_auth_method(selected).build();
+        let mut bytes = vec![];
+        block_on(response.write_to(&mut bytes)).unwrap();
+        prop_assert_eq!(response, block_on(HandshakeResponse::read_from(&mut bytes.as_slice())).unwrap());
+    }
+
+    #[test]
+    fn password_auth_request_round_trip(username in "[[:print:]]{0,255}", password in "[[:print:]]{0,255}") {
+        let mut bytes = vec![];
+        block_on(PasswordAuthRequest::new(&username, &password).write_to(&mut bytes)).unwrap();
+        let request = block_on(PasswordAuthRequest::read_from(&mut bytes.as_slice())).unwrap();
+        prop_assert_eq!((username.as_str(), password.as_str()), (request.username(), request.password()));
+    }
+
+    #[test]
+    fn relay_messages_round_trip(cmd in command(), addr in address(), bound_addr in any::<SocketAddr>(), status in any::<u8>()) {
+        let mut bytes = vec![];
+        block_on(RelayRequest::new(cmd, addr.clone()).write_to(&mut bytes)).unwrap();
+        let request = block_on(RelayRequest::read_from(&mut bytes.as_slice())).unwrap();
+        prop_assert_eq!((cmd, &addr), (request.command(), request.endpoint_address()));
+
+        // Flow info and scope ID aren't transferred.
+        let bound_addr = match bound_addr {
+            SocketAddr::V6(v6) => SocketAddr::V6(SocketAddrV6::new(*v6.ip(), v6.port(), 0, 0)),
+            v4 => v4,
+        };
+        let response = RelayResponse::builder()
+            .with_status(ReplyStatus::from_u8(status))
+            .with_bound_address(bound_addr)
+            .build();
+        let mut bytes = vec![];
+        block_on(response.write_to(&mut bytes)).unwrap();
+        prop_assert_eq!(response, block_on(RelayResponse::read_from(&mut bytes.as_slice())).unwrap());
+    }
+
+    #[test]
+    fn relay_response_with_domain_name_round_trip(name in "\\PC{0,63}", port in any::<u16>(), status in any::<u8>()) {
+        let response = RelayResponse::builder()
+            .with_status(ReplyStatus::from_u8(status))
+            .with_bound_domain_name(name.as_str(), port)
+            .build();
+        let mut bytes = vec![];
+        block_on(response.write_to(&mut bytes)).unwrap();
+        prop_assert_eq!(response, block_on(RelayResponse::read_from(&mut bytes.as_slice())).unwrap());
+    }
+
+    #[test]
+    fn address_round_trip(addr in address()) {
+        let mut bytes = vec![];
+        addr.write_to(&mut bytes).unwrap();
+        prop_assert_eq!(bytes.len(), Address::encoded_len(bytes[0], bytes[1]).unwrap());
+        prop_assert_eq!(&addr, &Address::decode(&bytes).unwrap());
+        prop_assert_eq!(addr, block_on(Address::read_from(&mut bytes.as_slice())).unwrap());
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in vec(any::<u8>(), 0..512)) {
+        let _ = block_on(HandshakeRequest::read_from(&mut bytes.as_slice()));
+        let _ = block_on(PasswordAuthRequest::read_from(&mut bytes.as_slice()));
+        let _ = block_on(Address::read_from(&mut bytes.as_slice()));
+        let _ = block_on(RelayResponse::read_from(&mut bytes.as_slice()));
+
+        // Parsed request is the exact prefix of the stream, the rest belongs to the tunnel.
+        if let Ok(request) = block_on(RelayRequest::read_from(&mut bytes.as_slice())) {
+            let mut written = vec![];
+            block_on(request.write_to(&mut written)).unwrap();
+            prop_assert_eq!(&bytes[..written.len()], written.as_slice());
+        }
+    }
+
+    #[test]
+    fn relay_request_with_valid_header_never_panics(cmd in any::<u8>(), atyp in 0u8..6, tail in vec(any::<u8>(), 0..300)) {
+        // Arbitrary bytes rarely get past the header, so the address is fuzzed behind the valid one.
+        let bytes = [&[SOCKS5_VERSION, cmd, 0x00, atyp], tail.as_slice()].concat();
+        let _ = block_on(RelayRequest::read_from(&mut bytes.as_slice()));
+    }
+}
@@ -0,0 +1,166 @@
+use crate::io::{mirror::LurkMirrorDirection, recording};
+use anyhow::{Context, Result};
+use log::debug;
+use std::{
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::{sleep_until, timeout, Instant},
+};
+
+/// Outcome of the replayed recording.
+#[derive(Debug, PartialEq)]
+pub struct LurkReplaySummary {
+    /// Endpoint the recording has been replayed against.
+    pub target: String,
+    /// Number of client chunks sent to the endpoint.
+    pub sent_chunks: usize,
+    /// Number of bytes sent to the endpoint.
+    pub sent_bytes: usize,
+    /// Data the endpoint has responded with.
+    pub received: Vec<u8>,
+    /// Data the recorded destination has responded with.
+    pub recorded: Vec<u8>,
+}
+
+impl LurkReplaySummary {
+    /// Position of the first byte the response differs from the recorded one at.
+    pub fn first_mismatch(&self) -> Option<usize> {
+        let common = self
+            .received
+            .iter()
+            .zip(&self.recorded)
+            .position(|(received, recorded)| received != recorded);
+        common.or_else(|| (self.received.len() != self.recorded.len()).then(|| self.received.len().min(self.recorded.len())))
+    }
+}
+
+impl fmt::Display for LurkReplaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sent {} chunk(s) ({} bytes) to {}, received {} bytes, {} bytes were recorded",
+            self.sent_chunks,
+            self.sent_bytes,
+            self.target,
+            self.received.len(),
+            self.recorded.len()
+        )?;
+        match self.first_mismatch() {
+            Some(position) => write!(f, "\nResponse differs from the recorded one at byte {}", position),
+            None => write!(f, "\nResponse matches the recorded one"),
+        }
+    }
+}
+
+/// Replay the client side of the recording against the ```target``` (the recorded destination by default):
+/// client chunks are sent with the recorded delays between them (back-to-back with ```no_delay```),
+/// while the response of the endpoint is collected until it closes the connection or ```wait``` is over.
+pub async fn replay(path: &Path, target: Option<&str>, no_delay: bool, wait: Duration) -> Result<LurkReplaySummary> {
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("unable to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let destination = recording::read_header(&mut reader)
+        .await
+        .with_context(|| format!("{} is not a valid recording", path.display()))?;
+
+    let target = target.unwrap_or(&destination).to_owned();
+    let stream = TcpStream::connect(target.as_str())
+        .await
+        .with_context(|| format!("unable to connect to {}", target))?;
+    let (mut read_half, mut write_half) = stream.into_split();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut receiver = tokio::spawn({
+        let received = Arc::clone(&received);
+        async move {
+            let mut buf = [0u8; 8192];
+            while let Ok(n @ 1..) = read_half.read(&mut buf).await {
+                received.lock().expect("Replay lock is poisoned").extend_from_slice(&buf[..n]);
+            }
+        }
+    });
+
+    let started = Instant::now();
+    let (mut sent_chunks, mut sent_bytes, mut recorded) = (0, 0, Vec::new());
+    while let Some(chunk) = recording::read_chunk(&mut reader).await? {
+        match chunk.direction {
+            LurkMirrorDirection::L2R => {
+                if !no_delay {
+                    sleep_until(started + chunk.offset).await;
+                }
+                debug!("Sending {} bytes recorded at {:?}", chunk.payload.len(), chunk.offset);
+                write_half.write_all(&chunk.payload).await?;
+                sent_chunks += 1;
+                sent_bytes += chunk.payload.len();
+            }
+            LurkMirrorDirection::R2L => recorded.extend_from_slice(&chunk.payload),
+        }
+    }
+    write_half.shutdown().await?;
+
+    if timeout(wait, &mut receiver).await.is_err() {
+        receiver.abort();
+    }
+    let received = std::mem::take(&mut *received.lock().expect("Replay lock is poisoned"));
+
+    Ok(LurkReplaySummary {
+        target,
+        sent_chunks,
+        sent_bytes,
+        received,
+        recorded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn replay_recording() {
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = endpoint.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            stream.write_all(&request.to_ascii_uppercase()).await.unwrap();
+        });
+
+        let mut content = BytesMut::new();
+        content.put(recording::encode_header("example.com:80"));
+        content.put(recording::encode_chunk(LurkMirrorDirection::L2R, Duration::ZERO, b"hello "));
+        content.put(recording::encode_chunk(
+            LurkMirrorDirection::L2R,
+            Duration::from_millis(20),
+            b"world",
+        ));
+        content.put(recording::encode_chunk(
+            LurkMirrorDirection::R2L,
+            Duration::from_millis(30),
+            b"HELLO WORLD",
+        ));
+        let path = std::env::temp_dir().join(format!("lurk-replay-{}.rec", std::process::id()));
+        std::fs::write(&path, &content).unwrap();
+
+        let summary = replay(&path, Some(&endpoint_addr.to_string()), false, Duration::from_secs(5))
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(2, summary.sent_chunks);
+        assert_eq!(11, summary.sent_bytes);
+        assert_eq!(b"HELLO WORLD".as_slice(), summary.received);
+        assert_eq!(None, summary.first_mismatch());
+    }
+}
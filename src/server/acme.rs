@@ -0,0 +1,988 @@
+use crate::net::tls::LurkCertResolver;
+use anyhow::{anyhow, bail, Context, Result};
+use async_listen::is_transient_error;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming, client::conn::http1, header, server::conn::http1 as server_http1, service::service_fn, Method, Request, Response,
+    StatusCode, Uri,
+};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{
+    crypto::ring as provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::ResolvesServerCertUsingSni,
+    sign::CertifiedKey,
+    ClientConfig, RootCertStore,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::{interval, sleep, timeout},
+};
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+use yasna::{
+    tags::{TAG_GENERALIZEDTIME, TAG_UTCTIME},
+    Tag,
+};
+
+/// Challenge the ACME CA validates control over the domains with.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkAcmeChallenge {
+    /// Token is served over HTTP on port 80 of the domain
+    #[default]
+    #[value(name = "http-01")]
+    Http01,
+    /// Certificate with the token is presented to "acme-tls/1" clients by the HTTPS listener on port 443 of the domain
+    #[value(name = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl LurkAcmeChallenge {
+    /// Type of the challenge in the authorizations of the CA.
+    fn kind(&self) -> &'static str {
+        match self {
+            LurkAcmeChallenge::Http01 => "http-01",
+            LurkAcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// Settings of the ACME client.
+///
+/// **Fields**:
+/// * ```directory_url``` - URL of the ACME directory of the CA
+/// * ```domains``` - names the certificate is issued for
+/// * ```contacts``` - emails the CA notifies about the account and its certificates
+/// * ```state_dir``` - directory the account key, the certificate and its key are kept in
+/// * ```challenge``` - how the CA validates control over the domains
+/// * ```http_challenge_addr``` - TCP address HTTP-01 tokens are served on
+/// * ```ca_bundle``` - PEM file with the roots trusted to serve https:// directory
+/// * ```renew_before``` - how long before the expiration the certificate is renewed
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkAcmeOptions {
+    directory_url: String,
+    domains: Vec<String>,
+    contacts: Vec<String>,
+    state_dir: PathBuf,
+    challenge: LurkAcmeChallenge,
+    http_challenge_addr: SocketAddr,
+    ca_bundle: Option<PathBuf>,
+    renew_before: Duration,
+}
+
+impl LurkAcmeOptions {
+    pub fn new(directory_url: impl Into<String>, domains: Vec<String>, state_dir: impl Into<PathBuf>) -> LurkAcmeOptions {
+        LurkAcmeOptions {
+            directory_url: directory_url.into(),
+            domains,
+            contacts: Vec::new(),
+            state_dir: state_dir.into(),
+            challenge: LurkAcmeChallenge::default(),
+            http_challenge_addr: "0.0.0.0:80".parse().unwrap(),
+            ca_bundle: None,
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+
+    pub fn set_contacts(&mut self, contacts: Vec<String>) -> &mut LurkAcmeOptions {
+        self.contacts = contacts;
+        self
+    }
+
+    pub fn set_challenge(&mut self, challenge: LurkAcmeChallenge) -> &mut LurkAcmeOptions {
+        self.challenge = challenge;
+        self
+    }
+
+    pub fn set_http_challenge_addr(&mut self, http_challenge_addr: SocketAddr) -> &mut LurkAcmeOptions {
+        self.http_challenge_addr = http_challenge_addr;
+        self
+    }
+
+    pub fn set_ca_bundle(&mut self, ca_bundle: Option<PathBuf>) -> &mut LurkAcmeOptions {
+        self.ca_bundle = ca_bundle;
+        self
+    }
+
+    pub fn set_renew_before(&mut self, renew_before: Duration) -> &mut LurkAcmeOptions {
+        self.renew_before = renew_before;
+        self
+    }
+
+    /// PEM file with the certificate chain presented by the TLS listeners.
+    pub fn cert_path(&self) -> PathBuf {
+        self.state_dir.join("cert.pem")
+    }
+
+    /// PEM file with the private key of the certificate.
+    pub fn key_path(&self) -> PathBuf {
+        self.state_dir.join("key.pem")
+    }
+
+    /// PEM file with the private key of the account.
+    fn account_key_path(&self) -> PathBuf {
+        self.state_dir.join("account.pem")
+    }
+}
+
+/// Certificate of the TLS listeners obtained from the ACME CA and renewed before it expires.
+///
+/// Until the CA issues the first certificate, listeners present the self-signed placeholder,
+/// so they could be started (and answer TLS-ALPN-01 challenge) without one.
+pub struct LurkAcme {
+    options: LurkAcmeOptions,
+    listener_certs: Vec<Arc<LurkCertResolver>>,
+    challenge_listener: Option<TcpListener>,
+    /// Key authorizations of the pending HTTP-01 challenges by their tokens.
+    http_challenges: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl LurkAcme {
+    /// Period between two checks whether the certificate should be renewed.
+    const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    /// Period between two polls of the authorization or order, which the CA is still processing.
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// Delay before the challenge listener accepts connections again after the failure.
+    const DELAY_AFTER_ACCEPT_ERROR: Duration = Duration::from_millis(500);
+
+    /// Number of polls before the authorization or order is given up.
+    const POLL_ATTEMPTS: usize = 30;
+
+    /// Prepare the state directory, write the placeholder certificate there unless it has the
+    /// certificate already, and bind the listener serving HTTP-01 challenge tokens.
+    pub async fn bind(options: LurkAcmeOptions) -> Result<LurkAcme> {
+        std::fs::create_dir_all(&options.state_dir)
+            .with_context(|| format!("failed to create ACME state directory {}", options.state_dir.display()))?;
+        if !options.cert_path().exists() || !options.key_path().exists() {
+            let placeholder = rcgen::generate_simple_self_signed(options.domains.clone())?;
+            write_files(&[
+                (&options.key_path(), placeholder.key_pair.serialize_pem().as_bytes()),
+                (&options.cert_path(), placeholder.cert.pem().as_bytes()),
+            ])?;
+            info!("Self-signed certificate is presented until the ACME CA issues one");
+        }
+
+        let challenge_listener = match options.challenge {
+            LurkAcmeChallenge::Http01 => Some(
+                TcpListener::bind(options.http_challenge_addr)
+                    .await
+                    .with_context(|| format!("failed to bind ACME challenge listener on {}", options.http_challenge_addr))?,
+            ),
+            LurkAcmeChallenge::TlsAlpn01 => None,
+        };
+
+        Ok(LurkAcme {
+            options,
+            listener_certs: Vec::new(),
+            challenge_listener,
+            http_challenges: Arc::default(),
+        })
+    }
+
+    /// Present the certificate by the listener once it's renewed, and the TLS-ALPN-01 challenge certificates while they are validated.
+    pub fn add_listener_certs(&mut self, certs: Arc<LurkCertResolver>) {
+        self.listener_certs.push(certs);
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn challenge_addr(&self) -> Option<SocketAddr> {
+        self.challenge_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Asynchronously renew the certificate and serve HTTP-01 challenge tokens until cancelled.
+    pub async fn run(self, token: CancellationToken) {
+        info!(
+            "Certificate for {} is obtained from {}",
+            self.options.domains.join(", "),
+            self.options.directory_url
+        );
+
+        let renewal = async {
+            let mut ticker = interval(Self::RENEWAL_CHECK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(err) = self.renew().await {
+                    // Current certificate is presented until the next attempt succeeds.
+                    warn!("Failed to renew ACME certificate: {:#}", err);
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = renewal => {}
+            _ = self.serve_http_challenges() => {}
+            _ = token.cancelled() => {}
+        }
+    }
+
+    /// Obtain the new certificate, if the current one is the placeholder, expires soon or doesn't cover
+    /// all domains. Returns whether the certificate is renewed.
+    pub async fn renew(&self) -> Result<bool> {
+        if !self.renewal_due()? {
+            debug!("ACME certificate isn't due for renewal");
+            return Ok(false);
+        }
+
+        self.issue().await?;
+        for certs in &self.listener_certs {
+            certs.reload_default()?;
+        }
+        info!("ACME certificate for {} is renewed", self.options.domains.join(", "));
+        Ok(true)
+    }
+
+    /// Whether the certificate of the state directory should be replaced.
+    fn renewal_due(&self) -> Result<bool> {
+        let cert_path = self.options.cert_path();
+        let cert_chain = CertificateDer::pem_file_iter(&cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read certificates from {}", cert_path.display()))?;
+        let Some(cert) = cert_chain.first() else {
+            return Ok(true);
+        };
+        let (self_issued, not_after) = validity(cert)?;
+        if self_issued || not_after - Utc::now() < chrono::Duration::from_std(self.options.renew_before)? {
+            return Ok(true);
+        }
+
+        let key = PrivateKeyDer::from_pem_file(self.options.key_path()).context("failed to read private key of ACME certificate")?;
+        let certified_key = CertifiedKey::from_der(cert_chain, key, &provider::default_provider())?;
+        let covers = |domain: &String| ResolvesServerCertUsingSni::new().add(domain, certified_key.clone()).is_ok();
+        Ok(!self.options.domains.iter().all(covers))
+    }
+
+    /// Go through the order of the new certificate with the CA and write it to the state directory.
+    async fn issue(&self) -> Result<()> {
+        let mut account = LurkAcmeAccount::open(&self.options, self.account_key()?).await?;
+
+        #[derive(Deserialize)]
+        struct Order {
+            status: String,
+            authorizations: Vec<String>,
+            finalize: String,
+            certificate: Option<String>,
+        }
+
+        let identifiers: Vec<_> = self
+            .options
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let response = account
+            .post(&account.directory.new_order.clone(), Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Order = serde_json::from_slice(response.body()).context("unexpected order")?;
+        for authorization_url in &order.authorizations {
+            self.authorize(&mut account, authorization_url).await?;
+        }
+
+        let key = KeyPair::generate()?;
+        let csr = CertificateParams::new(self.options.domains.clone())?.serialize_request(&key)?;
+        account
+            .post(&order.finalize, Some(json!({ "csr": BASE64_URL.encode(csr.der()) })))
+            .await?;
+
+        let mut certificate_url = None;
+        for _ in 0..Self::POLL_ATTEMPTS {
+            let order: Order = account.fetch(&order_url).await?;
+            match order.status.as_str() {
+                "valid" => {
+                    certificate_url = order.certificate;
+                    break;
+                }
+                "pending" | "ready" | "processing" => sleep(Self::POLL_INTERVAL).await,
+                status => bail!("order of the certificate is {}", status),
+            }
+        }
+        let Some(certificate_url) = certificate_url else {
+            bail!("certificate hasn't been issued in time");
+        };
+
+        let cert_chain = account.post(&certificate_url, None).await?.into_body();
+        write_files(&[
+            (&self.options.key_path(), key.serialize_pem().as_bytes()),
+            (&self.options.cert_path(), &cert_chain),
+        ])
+    }
+
+    /// Complete the challenge of the authorization, unless the CA has validated the domain already.
+    async fn authorize(&self, account: &mut LurkAcmeAccount, url: &str) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Identifier {
+            value: String,
+        }
+        #[derive(Deserialize)]
+        struct Challenge {
+            #[serde(rename = "type")]
+            kind: String,
+            url: String,
+            token: String,
+        }
+        #[derive(Deserialize)]
+        struct Authorization {
+            identifier: Identifier,
+            status: String,
+            challenges: Vec<Challenge>,
+        }
+
+        let authorization: Authorization = account.fetch(url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let kind = self.options.challenge.kind();
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == kind)
+            .with_context(|| format!("CA doesn't offer {} challenge for {}", kind, domain))?;
+
+        let key_authorization = account.key_authorization(&challenge.token);
+        self.set_challenge_response(&domain, &challenge.token, Some(&key_authorization))?;
+        let validation = async {
+            account.post(&challenge.url, Some(json!({}))).await?;
+            for _ in 0..Self::POLL_ATTEMPTS {
+                let authorization: Authorization = account.fetch(url).await?;
+                match authorization.status.as_str() {
+                    "valid" => return Ok(()),
+                    "pending" => sleep(Self::POLL_INTERVAL).await,
+                    status => bail!("authorization of {} is {}", domain, status),
+                }
+            }
+            bail!("authorization of {} hasn't completed in time", domain)
+        }
+        .await;
+        self.set_challenge_response(&domain, &challenge.token, None)?;
+
+        debug!("{} challenge for {} is completed", kind, domain);
+        validation
+    }
+
+    /// Answer the challenge with the key authorization while it's validated, or stop answering it.
+    fn set_challenge_response(&self, domain: &str, token: &str, key_authorization: Option<&str>) -> Result<()> {
+        match self.options.challenge {
+            LurkAcmeChallenge::Http01 => {
+                let mut http_challenges = self.http_challenges.write().expect("ACME challenges lock is poisoned");
+                match key_authorization {
+                    Some(key_authorization) => http_challenges.insert(token.to_owned(), key_authorization.to_owned()),
+                    None => http_challenges.remove(token),
+                };
+            }
+            LurkAcmeChallenge::TlsAlpn01 => {
+                let challenge_cert = match key_authorization {
+                    Some(key_authorization) => {
+                        let key = KeyPair::generate()?;
+                        let mut params = CertificateParams::new(vec![domain.to_owned()])?;
+                        let digest = digest(&SHA256, key_authorization.as_bytes());
+                        params.custom_extensions.push(CustomExtension::new_acme_identifier(digest.as_ref()));
+                        let cert = params.self_signed(&key)?;
+                        let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+                        Some(CertifiedKey::from_der(
+                            vec![cert.der().clone()],
+                            key,
+                            &provider::default_provider(),
+                        )?)
+                    }
+                    None => None,
+                };
+                for certs in &self.listener_certs {
+                    certs.set_challenge_cert(domain, challenge_cert.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Key of the account, which is created once and kept in the state directory.
+    fn account_key(&self) -> Result<EcdsaKeyPair> {
+        let path = self.options.account_key_path();
+        let key = match std::fs::read_to_string(&path) {
+            Ok(pem) => KeyPair::from_pem(&pem).with_context(|| format!("failed to read ACME account key from {}", path.display()))?,
+            Err(_) => {
+                let key = KeyPair::generate()?;
+                write_file(&path, key.serialize_pem().as_bytes())?;
+                key
+            }
+        };
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &SystemRandom::new())
+            .map_err(|err| anyhow!("ACME account key {} isn't ECDSA P-256 one: {}", path.display(), err))
+    }
+
+    /// Serve HTTP-01 challenge tokens, if the CA validates them. Acception errors (e.g. too many open files)
+    /// don't stop the listener, so the renewal running alongside isn't cancelled.
+    async fn serve_http_challenges(&self) {
+        let Some(listener) = &self.challenge_listener else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let (tcp_stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("ACME challenge listener has failed to accept connection: {}", err);
+                    if !is_transient_error(&err) {
+                        sleep(Self::DELAY_AFTER_ACCEPT_ERROR).await;
+                    }
+                    continue;
+                }
+            };
+
+            let http_challenges = Arc::clone(&self.http_challenges);
+            let service = service_fn(move |request: Request<Incoming>| {
+                let key_authorization = request.uri().path().strip_prefix("/.well-known/acme-challenge/").and_then(|token| {
+                    let http_challenges = http_challenges.read().expect("ACME challenges lock is poisoned");
+                    http_challenges.get(token).cloned()
+                });
+                let response = match key_authorization {
+                    Some(key_authorization) => Response::new(Full::new(Bytes::from(key_authorization))),
+                    None => {
+                        let mut response = Response::new(Full::default());
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        response
+                    }
+                };
+                async move { Ok::<_, Infallible>(response) }
+            });
+            tokio::spawn(async move {
+                if let Err(err) = server_http1::Builder::new()
+                    .serve_connection(TokioIo::new(tcp_stream), service)
+                    .await
+                {
+                    debug!("Error occurred while serving ACME challenge to {}: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+}
+
+/// URLs of the ACME directory.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LurkAcmeDirectory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// Account of the CA, which signs the requests with its key.
+struct LurkAcmeAccount {
+    client: LurkAcmeClient,
+    key: EcdsaKeyPair,
+    directory: LurkAcmeDirectory,
+    /// URL of the account, which identifies it once it's created.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl LurkAcmeAccount {
+    /// Find (or create) the account with the key.
+    async fn open(options: &LurkAcmeOptions, key: EcdsaKeyPair) -> Result<LurkAcmeAccount> {
+        let client = LurkAcmeClient::new(options.ca_bundle.as_deref())?;
+        let response = client.request(Method::GET, &options.directory_url, None).await?;
+        let directory = serde_json::from_slice(response.body()).context("unexpected ACME directory")?;
+        let mut account = LurkAcmeAccount {
+            client,
+            key,
+            directory,
+            kid: None,
+            nonce: None,
+        };
+
+        let contacts: Vec<_> = options.contacts.iter().map(|email| format!("mailto:{}", email)).collect();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contacts });
+        let response = account.post(&account.directory.new_account.clone(), Some(payload)).await?;
+        account.kid = Some(location(&response)?);
+
+        Ok(account)
+    }
+
+    /// Send the request signed by the account. Requests without payload are POST-as-GET ones.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<Response<Bytes>> {
+        #[derive(Deserialize, Default)]
+        struct Problem {
+            #[serde(rename = "type")]
+            kind: String,
+            detail: String,
+        }
+
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let response = self
+                .client
+                .request(Method::POST, url, Some(self.sign(url, &nonce, payload.as_ref())?))
+                .await?;
+            self.nonce = response
+                .headers()
+                .get("replay-nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_owned);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            // Nonce could expire, then the request is sent once again with the fresh one.
+            let problem: Problem = serde_json::from_slice(response.body()).unwrap_or_default();
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "ACME server has responded to {} with {}: {}",
+                url,
+                response.status(),
+                problem.detail
+            );
+        }
+    }
+
+    /// Fetch the object with POST-as-GET request.
+    async fn fetch<T: DeserializeOwned>(&mut self, url: &str) -> Result<T> {
+        let response = self.post(url, None).await?;
+        serde_json::from_slice(response.body()).with_context(|| format!("unexpected ACME object at {}", url))
+    }
+
+    async fn new_nonce(&self) -> Result<String> {
+        let response = self.client.request(Method::HEAD, &self.directory.new_nonce, None).await?;
+        let nonce = response.headers().get("replay-nonce").context("ACME server hasn't sent nonce")?;
+        Ok(nonce.to_str()?.to_owned())
+    }
+
+    /// Flattened JWS of the request. The account is identified by its URL, or by its key until it's created.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = BASE64_URL.encode(protected.to_string());
+        let payload = payload.map(|payload| BASE64_URL.encode(payload.to_string())).unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&SystemRandom::new(), format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("failed to sign ACME request"))?;
+
+        let jws = json!({ "protected": protected, "payload": payload, "signature": BASE64_URL.encode(signature) });
+        Ok(jws.to_string().into_bytes())
+    }
+
+    /// Public key of the account as JWK. Members are in the order its thumbprint is taken with.
+    fn jwk(&self) -> Value {
+        // Uncompressed point is 0x04 followed by both coordinates.
+        let point = self.key.public_key().as_ref();
+        json!({ "crv": "P-256", "kty": "EC", "x": BASE64_URL.encode(&point[1..33]), "y": BASE64_URL.encode(&point[33..]) })
+    }
+
+    /// Response to the challenge with the token, which proves it's answered by the account.
+    fn key_authorization(&self, token: &str) -> String {
+        let jwk = self.jwk();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            jwk["x"].as_str().unwrap_or_default(),
+            jwk["y"].as_str().unwrap_or_default()
+        );
+        format!("{}.{}", token, BASE64_URL.encode(digest(&SHA256, canonical.as_bytes())))
+    }
+}
+
+/// HTTP client of the ACME server. Connection is established for every request.
+struct LurkAcmeClient {
+    tls_connector: Option<TlsConnector>,
+}
+
+impl LurkAcmeClient {
+    /// Time given to the server to respond.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Client trusting the roots from the CA bundle, it could reach http:// servers only without the bundle.
+    fn new(ca_bundle: Option<&Path>) -> Result<LurkAcmeClient> {
+        let Some(ca_bundle) = ca_bundle else {
+            return Ok(LurkAcmeClient { tls_connector: None });
+        };
+
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca_bundle).with_context(|| format!("failed to read CA bundle {}", ca_bundle.display()))? {
+            roots.add(cert?)?;
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(LurkAcmeClient {
+            tls_connector: Some(TlsConnector::from(Arc::new(config))),
+        })
+    }
+
+    async fn request(&self, method: Method, url: &str, body: Option<Vec<u8>>) -> Result<Response<Bytes>> {
+        timeout(Self::REQUEST_TIMEOUT, self.send(method, url, body))
+            .await
+            .with_context(|| format!("ACME server hasn't responded to {} in time", url))?
+            .with_context(|| format!("ACME request to {} has failed", url))
+    }
+
+    async fn send(&self, method: Method, url: &str, body: Option<Vec<u8>>) -> Result<Response<Bytes>> {
+        let uri: Uri = url.parse().with_context(|| format!("'{}' is not a valid URL", url))?;
+        let Some(authority) = uri.authority() else {
+            bail!("URL has no host");
+        };
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(header::HOST, authority.as_str());
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/jose+json");
+        }
+        let request = request.body(Full::new(Bytes::from(body.unwrap_or_default())))?;
+
+        // IPv6 address comes in brackets.
+        let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+        match uri.scheme_str() {
+            Some("https") => {
+                let Some(tls_connector) = &self.tls_connector else {
+                    bail!("CA bundle is needed to reach https:// ACME server");
+                };
+                let tcp_stream = TcpStream::connect((host, authority.port_u16().unwrap_or(443))).await?;
+                let tls_stream = tls_connector.connect(ServerName::try_from(host.to_owned())?, tcp_stream).await?;
+                send_request(tls_stream, request).await
+            }
+            Some("http") => {
+                let tcp_stream = TcpStream::connect((host, authority.port_u16().unwrap_or(80))).await?;
+                send_request(tcp_stream, request).await
+            }
+            _ => bail!("only http:// and https:// URLs are supported"),
+        }
+    }
+}
+
+async fn send_request<S>(stream: S, request: Request<Full<Bytes>>) -> Result<Response<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let (parts, body) = sender.send_request(request).await?.into_parts();
+    Ok(Response::from_parts(parts, body.collect().await?.to_bytes()))
+}
+
+/// URL of the object the CA has created.
+fn location(response: &Response<Bytes>) -> Result<String> {
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .context("ACME server hasn't sent location")?;
+    Ok(location.to_str()?.to_owned())
+}
+
+/// Whether the certificate is issued by itself (i.e. it's the placeholder) and when it expires.
+fn validity(cert: &[u8]) -> Result<(bool, DateTime<Utc>)> {
+    let (self_issued, not_after) = yasna::parse_der(cert, |reader| {
+        reader.read_sequence(|cert| {
+            let validity = cert.next().read_sequence(|tbs| {
+                tbs.read_optional(|reader| reader.read_tagged(Tag::context(0), |reader| reader.read_i64()))?;
+                tbs.next().read_der()?; // serial number
+                tbs.next().read_der()?; // signature algorithm
+                let issuer = tbs.next().read_der()?;
+                let not_after = tbs.next().read_sequence(|validity| {
+                    validity.next().read_tagged_der()?;
+                    validity.next().read_tagged_der()
+                })?;
+                let subject = tbs.next().read_der()?;
+                while tbs.read_optional(|reader| reader.read_der())?.is_some() {}
+                Ok((issuer == subject, not_after))
+            })?;
+            cert.next().read_der()?; // signature algorithm
+            cert.next().read_der()?; // signature
+            Ok(validity)
+        })
+    })
+    .context("failed to parse certificate")?;
+
+    let format = if not_after.tag() == TAG_UTCTIME {
+        "%y%m%d%H%M%SZ"
+    } else if not_after.tag() == TAG_GENERALIZEDTIME {
+        "%Y%m%d%H%M%SZ"
+    } else {
+        bail!("certificate has unexpected expiration time");
+    };
+    let not_after = NaiveDateTime::parse_from_str(std::str::from_utf8(not_after.value())?, format)?;
+    Ok((self_issued, not_after.and_utc()))
+}
+
+/// Replace the file at once, so it's never read half-written.
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    write_files(&[(path, contents)])
+}
+
+/// Replace the files together: all of them are written aside first and renamed into place only
+/// once every one is complete, so a failure never leaves a new key next to an old certificate.
+/// Files are created readable by the owner only, since they hold keys.
+fn write_files(files: &[(&Path, &[u8])]) -> Result<()> {
+    let mut tmp_paths = Vec::with_capacity(files.len());
+    for (path, contents) in files {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        if let Err(err) = write_private_file(&tmp_path, contents) {
+            for tmp_path in &tmp_paths {
+                let _ = std::fs::remove_file(tmp_path);
+            }
+            return Err(err).with_context(|| format!("failed to write {}", tmp_path.display()));
+        }
+        tmp_paths.push(tmp_path);
+    }
+    for (tmp_path, (path, _)) in tmp_paths.iter().zip(files) {
+        std::fs::rename(tmp_path, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Create the file anew with owner-only permissions, so its contents are never exposed to others.
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    // Permissions are applied only when the file is created, so a leftover of a previous run is removed.
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, contents)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, DnType, IsCa, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256};
+    use std::sync::Mutex;
+
+    /// Public key of the CSR, which the mock CA issues the certificate for.
+    struct CsrPublicKey(Vec<u8>);
+
+    impl rcgen::PublicKeyData for CsrPublicKey {
+        fn der_bytes(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn algorithm(&self) -> &SignatureAlgorithm {
+            &PKCS_ECDSA_P256_SHA256
+        }
+    }
+
+    /// CA, which validates the HTTP-01 challenge of the only domain.
+    struct MockCa {
+        url: String,
+        domain: String,
+        challenge_addr: SocketAddr,
+        ca_key: KeyPair,
+        ca_cert: Certificate,
+        validated: bool,
+        cert_chain: Option<String>,
+    }
+
+    impl MockCa {
+        async fn respond(state: Arc<Mutex<MockCa>>, request: Request<Incoming>) -> Response<Full<Bytes>> {
+            let (method, path) = (request.method().clone(), request.uri().path().to_owned());
+            let jws: Value = serde_json::from_slice(&request.into_body().collect().await.unwrap().to_bytes()).unwrap_or_default();
+            let payload: Value = BASE64_URL
+                .decode(jws["payload"].as_str().unwrap_or_default())
+                .ok()
+                .and_then(|payload| serde_json::from_slice(&payload).ok())
+                .unwrap_or_default();
+
+            if path == "/challenge" {
+                let url = format!("http://{}/.well-known/acme-challenge/token", state.lock().unwrap().challenge_addr);
+                let response = LurkAcmeClient::new(None).unwrap().request(Method::GET, &url, None).await.unwrap();
+                state.lock().unwrap().validated = response.body().starts_with(b"token.");
+            }
+
+            let mut state = state.lock().unwrap();
+            let url = state.url.clone();
+            let order = |state: &MockCa| {
+                json!({
+                    "status": if state.cert_chain.is_some() { "valid" } else { "pending" },
+                    "authorizations": [format!("{}/authz", url)],
+                    "finalize": format!("{}/finalize", url),
+                    "certificate": format!("{}/cert", url),
+                })
+            };
+            let (body, location) = match (method, path.as_str()) {
+                (Method::GET, "/directory") => (
+                    json!({
+                        "newNonce": format!("{}/nonce", url),
+                        "newAccount": format!("{}/account", url),
+                        "newOrder": format!("{}/order", url),
+                    })
+                    .to_string(),
+                    None,
+                ),
+                (Method::HEAD, "/nonce") => (String::new(), None),
+                (Method::POST, "/account") => ("{}".to_owned(), Some(format!("{}/account/1", url))),
+                (Method::POST, "/order") => (order(&state).to_string(), Some(format!("{}/order/1", url))),
+                (Method::POST, "/order/1") => (order(&state).to_string(), None),
+                (Method::POST, "/authz") => (
+                    json!({
+                        "identifier": { "type": "dns", "value": state.domain },
+                        "status": if state.validated { "valid" } else { "pending" },
+                        "challenges": [{ "type": "http-01", "url": format!("{}/challenge", url), "token": "token" }],
+                    })
+                    .to_string(),
+                    None,
+                ),
+                (Method::POST, "/challenge") => ("{}".to_owned(), None),
+                (Method::POST, "/finalize") => {
+                    let csr = BASE64_URL.decode(payload["csr"].as_str().unwrap()).unwrap();
+                    let public_key = yasna::parse_der(&csr, |reader| {
+                        reader.read_sequence(|csr| {
+                            let public_key = csr.next().read_sequence(|info| {
+                                info.next().read_der()?; // version
+                                info.next().read_der()?; // subject
+                                let public_key = info.next().read_sequence(|spki| {
+                                    spki.next().read_der()?; // algorithm
+                                    Ok(spki.next().read_bitvec_bytes()?.0)
+                                })?;
+                                info.next().read_der()?; // attributes
+                                Ok(public_key)
+                            })?;
+                            csr.next().read_der()?; // signature algorithm
+                            csr.next().read_der()?; // signature
+                            Ok(public_key)
+                        })
+                    })
+                    .unwrap();
+                    let cert = CertificateParams::new(vec![state.domain.clone()])
+                        .unwrap()
+                        .signed_by(&CsrPublicKey(public_key), &state.ca_cert, &state.ca_key)
+                        .unwrap();
+                    state.cert_chain = Some(cert.pem() + &state.ca_cert.pem());
+                    (order(&state).to_string(), None)
+                }
+                (Method::POST, "/cert") => (state.cert_chain.clone().unwrap_or_default(), None),
+                _ => {
+                    let mut response = Response::new(Full::default());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    return response;
+                }
+            };
+
+            let mut response = Response::builder().header("replay-nonce", "nonce");
+            if let Some(location) = location {
+                response = response.header(header::LOCATION, location).status(StatusCode::CREATED);
+            }
+            response.body(Full::new(Bytes::from(body))).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn renew_certificate() {
+        let ca_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", ca_listener.local_addr().unwrap());
+        let state_dir = std::env::temp_dir().join(format!("lurk-acme-renew-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        let mut options = LurkAcmeOptions::new(format!("{}/directory", url), vec!["lurk.test".to_owned()], &state_dir);
+        options.set_http_challenge_addr("127.0.0.1:0".parse().unwrap()).set_ca_bundle(None);
+        let acme = LurkAcme::bind(options).await.unwrap();
+
+        // Placeholder is written until the certificate is issued.
+        let placeholder = std::fs::read(acme.options.cert_path()).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "Lurk Test CA");
+        let state = Arc::new(Mutex::new(MockCa {
+            url,
+            domain: "lurk.test".to_owned(),
+            challenge_addr: acme.challenge_addr().unwrap(),
+            ca_cert: ca_params.self_signed(&ca_key).unwrap(),
+            ca_key,
+            validated: false,
+            cert_chain: None,
+        }));
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = ca_listener.accept().await.unwrap();
+                let state = Arc::clone(&state);
+                let service = service_fn(move |request| {
+                    let state = Arc::clone(&state);
+                    async move { Ok::<_, Infallible>(MockCa::respond(state, request).await) }
+                });
+                tokio::spawn(server_http1::Builder::new().serve_connection(TokioIo::new(tcp_stream), service));
+            }
+        });
+
+        tokio::select! {
+            renewed = acme.renew() => assert!(renewed.unwrap()),
+            _ = acme.serve_http_challenges() => unreachable!(),
+        }
+        let cert_chain = std::fs::read(acme.options.cert_path()).unwrap();
+        assert_ne!(placeholder, cert_chain);
+        let cert = CertificateDer::from_pem_slice(&cert_chain).unwrap();
+        assert!(!validity(&cert).unwrap().0);
+
+        // Issued certificate is valid long enough.
+        assert!(!acme.renew().await.unwrap());
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_files_readable_by_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let state_dir = std::env::temp_dir().join(format!("lurk-acme-write-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        std::fs::create_dir_all(&state_dir).unwrap();
+        let (key_path, cert_path) = (state_dir.join("lurk.key"), state_dir.join("lurk.crt"));
+
+        // Leftover of an interrupted write is readable by everyone.
+        std::fs::write(state_dir.join("lurk.key.tmp"), b"stale").unwrap();
+        std::fs::set_permissions(state_dir.join("lurk.key.tmp"), std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_files(&[(&key_path, b"key"), (&cert_path, b"cert")]).unwrap();
+        assert_eq!(b"key".as_slice(), std::fs::read(&key_path).unwrap());
+        assert_eq!(b"cert".as_slice(), std::fs::read(&cert_path).unwrap());
+        for path in [&key_path, &cert_path] {
+            assert_eq!(0o600, std::fs::metadata(path).unwrap().permissions().mode() & 0o777);
+        }
+        assert!(!state_dir.join("lurk.key.tmp").exists());
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+}
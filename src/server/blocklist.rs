@@ -0,0 +1,343 @@
+use crate::common::error::{LurkDenyReason, LurkDenySource};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, LengthLimitError, Limited};
+use hyper::{client::conn::http1, header, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::Mutex,
+    time::{interval, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Settings of the destination blocklist.
+///
+/// **Fields**:
+/// * ```urls``` - HTTP URLs of the lists, either in hosts-file or plain domain-list format
+/// * ```refresh_interval``` - period between two fetches of every list
+/// * ```fetch_timeout``` - time given to every list to be downloaded, so the stalled server doesn't hold up the others
+/// * ```max_list_size``` - lists with longer content are refused
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkBlocklistOptions {
+    urls: Vec<String>,
+    refresh_interval: Duration,
+    fetch_timeout: Duration,
+    max_list_size: usize,
+}
+
+impl LurkBlocklistOptions {
+    pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub const DEFAULT_MAX_LIST_SIZE: usize = 64 * 1024 * 1024;
+
+    pub fn new(urls: impl IntoIterator<Item = impl Into<String>>, refresh_interval: Duration) -> LurkBlocklistOptions {
+        LurkBlocklistOptions {
+            urls: urls.into_iter().map(Into::into).collect(),
+            refresh_interval,
+            fetch_timeout: LurkBlocklistOptions::DEFAULT_FETCH_TIMEOUT,
+            max_list_size: LurkBlocklistOptions::DEFAULT_MAX_LIST_SIZE,
+        }
+    }
+
+    pub fn set_fetch_timeout(&mut self, fetch_timeout: Duration) -> &mut LurkBlocklistOptions {
+        self.fetch_timeout = fetch_timeout;
+        self
+    }
+
+    pub fn set_max_list_size(&mut self, max_list_size: usize) -> &mut LurkBlocklistOptions {
+        self.max_list_size = max_list_size;
+        self
+    }
+}
+
+/// Last fetched content of the list.
+#[derive(Default)]
+struct LurkBlocklistSource {
+    etag: Option<String>,
+    entries: HashSet<String>,
+}
+
+/// Destinations (domains and IPs) clients are not allowed to connect to.
+/// Lists are fetched from their URLs and refreshed periodically, the list
+/// that has failed to refresh keeps its last fetched content.
+pub struct LurkBlocklist {
+    options: LurkBlocklistOptions,
+    sources: Mutex<Vec<LurkBlocklistSource>>,
+    /// Entries of all lists along with index of the list they come from.
+    entries: RwLock<Arc<HashMap<String, usize>>>,
+}
+
+impl LurkBlocklist {
+    pub fn new(options: LurkBlocklistOptions) -> LurkBlocklist {
+        let sources = options.urls.iter().map(|_| LurkBlocklistSource::default()).collect();
+        LurkBlocklist {
+            options,
+            sources: Mutex::new(sources),
+            entries: RwLock::new(Arc::default()),
+        }
+    }
+
+    /// Why the destination host is blocked: it or any of its parent domains is listed.
+    pub fn deny_reason(&self, host: &str) -> Option<LurkDenyReason> {
+        let entries = Arc::clone(&self.entries.read().expect("Blocklist lock is poisoned"));
+        if entries.is_empty() {
+            return None;
+        }
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let is_ip = host.parse::<IpAddr>().is_ok();
+        let mut domain = host.as_str();
+        let (pattern, idx) = loop {
+            if let Some((pattern, idx)) = entries.get_key_value(domain) {
+                break (pattern, idx);
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) if !is_ip => domain = parent,
+                _ => return None,
+            }
+        };
+
+        Some(LurkDenyReason {
+            source: LurkDenySource::Blocklist,
+            rule: self.options.urls[*idx].clone(),
+            pattern: pattern.clone(),
+        })
+    }
+
+    /// Asynchronously refresh lists until cancelled.
+    pub async fn run(&self, token: CancellationToken) {
+        info!(
+            "Refreshing {} blocklist(s) every {:?}",
+            self.options.urls.len(),
+            self.options.refresh_interval
+        );
+
+        let mut ticker = interval(self.options.refresh_interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.refresh().await,
+                _ = token.cancelled() => return,
+            }
+        }
+    }
+
+    /// Fetch all lists once. Lists that haven't changed since the last fetch
+    /// (by ETag) aren't downloaded again.
+    pub async fn refresh(&self) {
+        let (fetch_timeout, max_list_size) = (self.options.fetch_timeout, self.options.max_list_size);
+        let mut sources = self.sources.lock().await;
+        for (url, source) in self.options.urls.iter().zip(sources.iter_mut()) {
+            let fetched = match timeout(fetch_timeout, fetch(url, source.etag.as_deref(), max_list_size)).await {
+                Ok(fetched) => fetched,
+                Err(_) => Err(anyhow!("list hasn't been downloaded within {:?}", fetch_timeout)),
+            };
+            match fetched {
+                Ok(Some((etag, content))) => {
+                    source.entries = parse_list(&content);
+                    source.etag = etag;
+                    info!("Blocklist {} is updated: {} entries", url, source.entries.len());
+                }
+                Ok(None) => debug!("Blocklist {} hasn't changed", url),
+                // Stale list is better than no list at all.
+                Err(err) => warn!("Failed to refresh blocklist {}: {:#}", url, err),
+            }
+        }
+
+        // Entry listed several times is attributed to the first list.
+        let mut entries = HashMap::new();
+        for (idx, source) in sources.iter().enumerate() {
+            for entry in &source.entries {
+                entries.entry(entry.clone()).or_insert(idx);
+            }
+        }
+        *self.entries.write().expect("Blocklist lock is poisoned") = Arc::new(entries);
+    }
+}
+
+/// Download the list, unless it still has passed ETag. Lists longer than ```max_size``` are refused.
+/// Returns new ETag (if any) and content of the list.
+async fn fetch(url: &str, etag: Option<&str>, max_size: usize) -> Result<Option<(Option<String>, String)>> {
+    let uri: Uri = url.parse().with_context(|| format!("'{}' is not a valid URL", url))?;
+    ensure!(uri.scheme_str() == Some("http"), "only http:// URLs are supported");
+    let Some(authority) = uri.authority() else {
+        bail!("URL has no host");
+    };
+
+    // IPv6 address comes in brackets.
+    let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((host, authority.port_u16().unwrap_or(80))).await?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let mut request = Request::get(uri.path_and_query().map_or("/", |path| path.as_str())).header(header::HOST, authority.as_str());
+    if let Some(etag) = etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+
+    let response = sender.send_request(request.body(Empty::<Bytes>::new())?).await?;
+    match response.status() {
+        StatusCode::NOT_MODIFIED => Ok(None),
+        StatusCode::OK => {
+            let etag = response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(str::to_owned);
+            let body = match Limited::new(response.into_body(), max_size).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(err) if err.is::<LengthLimitError>() => bail!("list is longer than {} bytes", max_size),
+                Err(err) => return Err(anyhow!(err)),
+            };
+            Ok(Some((etag, String::from_utf8_lossy(&body).into_owned())))
+        }
+        status => bail!("server has responded with {}", status),
+    }
+}
+
+/// Entries of the list in either format:
+/// * hosts file - "0.0.0.0 ads.example.com tracker.example.com", the address is ignored
+/// * domain list - "ads.example.com"
+///
+/// Comments ("#") and empty lines are skipped.
+fn parse_list(content: &str) -> HashSet<String> {
+    let mut entries = HashSet::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace().peekable();
+
+        // The first token of hosts file line is the address names are resolved to.
+        if tokens.peek().is_some_and(|token| token.parse::<IpAddr>().is_ok()) {
+            let address = tokens.next().unwrap_or_default();
+            if tokens.peek().is_none() {
+                entries.insert(address.to_ascii_lowercase());
+            }
+        }
+
+        entries.extend(
+            tokens
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+                .filter(|name| !matches!(name.as_str(), "localhost" | "localhost.localdomain" | "broadcasthost" | "local")),
+        );
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{
+        all_of,
+        matchers::{contains, key, not, request},
+        responders::status_code,
+        Expectation, Server,
+    };
+
+    #[test]
+    fn parse_lists() {
+        let hosts = "# Threat intel\n127.0.0.1 localhost\n0.0.0.0 Ads.Example.com tracker.example.net # inline\n\n";
+        let domains = "malware.example.org\n  phishing.example.com.\n203.0.113.7\n";
+
+        assert_eq!(
+            HashSet::from(["ads.example.com".to_owned(), "tracker.example.net".to_owned()]),
+            parse_list(hosts)
+        );
+        assert_eq!(
+            HashSet::from([
+                "malware.example.org".to_owned(),
+                "phishing.example.com".to_owned(),
+                "203.0.113.7".to_owned()
+            ]),
+            parse_list(domains)
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_with_etag() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/hosts"),
+                request::headers(not(contains(key("if-none-match")))),
+            ])
+            .respond_with(
+                status_code(200)
+                    .insert_header("etag", "\"v1\"")
+                    .body("0.0.0.0 ads.example.com\n203.0.113.7\n"),
+            ),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/hosts"),
+                request::headers(contains(("if-none-match", "\"v1\""))),
+            ])
+            .respond_with(status_code(304)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/broken"))
+                .times(2)
+                .respond_with(status_code(500)),
+        );
+
+        let blocklist = LurkBlocklist::new(LurkBlocklistOptions::new(
+            [server.url_str("/hosts"), server.url_str("/broken")],
+            Duration::from_secs(60),
+        ));
+        assert_eq!(None, blocklist.deny_reason("ads.example.com"));
+
+        for _ in 0..2 {
+            blocklist.refresh().await;
+
+            assert_eq!(
+                Some(LurkDenyReason {
+                    source: LurkDenySource::Blocklist,
+                    rule: server.url_str("/hosts"),
+                    pattern: "ads.example.com".to_owned()
+                }),
+                blocklist.deny_reason("cdn.ADS.example.com.")
+            );
+            assert!(blocklist.deny_reason("ads.example.com").is_some());
+            assert!(blocklist.deny_reason("203.0.113.7").is_some());
+            assert_eq!(None, blocklist.deny_reason("example.com"));
+            assert_eq!(None, blocklist.deny_reason("bads.example.com"));
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_stalled_and_oversized_lists() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/huge"))
+                .respond_with(status_code(200).body("0.0.0.0 huge.example.com\n".repeat(64))),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/hosts")).respond_with(status_code(200).body("0.0.0.0 ads.example.com\n")),
+        );
+
+        // Server accepts the connection, but never responds.
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_url = format!("http://{}/hosts", stalled.local_addr().unwrap());
+
+        let mut options = LurkBlocklistOptions::new(
+            [stalled_url, server.url_str("/huge"), server.url_str("/hosts")],
+            Duration::from_secs(60),
+        );
+        options.set_fetch_timeout(Duration::from_millis(200)).set_max_list_size(1024);
+        let blocklist = LurkBlocklist::new(options);
+
+        tokio::time::timeout(Duration::from_secs(5), blocklist.refresh())
+            .await
+            .expect("stalled list shouldn't hold up the refresh");
+        assert_eq!(None, blocklist.deny_reason("huge.example.com"));
+        assert!(blocklist.deny_reason("ads.example.com").is_some());
+        drop(stalled);
+    }
+}
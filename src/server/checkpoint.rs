@@ -0,0 +1,201 @@
+use super::stats::{LurkCumulativeCounters, LurkServerStats};
+use crate::auth::{quota::LurkQuotaUsage, users::LurkUserStore};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{fs, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+/// Settings of periodic stats checkpointing.
+///
+/// **Fields**:
+/// * ```path``` - file where checkpoints are stored
+/// * ```interval``` - period between two checkpoints
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkStatsCheckpointOptions {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl LurkStatsCheckpointOptions {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> LurkStatsCheckpointOptions {
+        LurkStatsCheckpointOptions {
+            path: path.into(),
+            interval,
+        }
+    }
+}
+
+/// Content of the checkpoint file.
+#[derive(Serialize, Deserialize, Debug)]
+struct LurkStatsCheckpoint {
+    saved_utc_ts: DateTime<Utc>,
+    counters: LurkCumulativeCounters,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    quota_usage: HashMap<String, LurkQuotaUsage>,
+}
+
+/// Keeps cumulative stats counters and quota usage of users on disk, so they survive server restarts.
+pub struct LurkStatsCheckpointer {
+    stats: Arc<LurkServerStats>,
+    users: Option<Arc<LurkUserStore>>,
+    options: LurkStatsCheckpointOptions,
+}
+
+impl LurkStatsCheckpointer {
+    pub fn new(stats: Arc<LurkServerStats>, options: LurkStatsCheckpointOptions) -> LurkStatsCheckpointer {
+        LurkStatsCheckpointer {
+            stats,
+            users: None,
+            options,
+        }
+    }
+
+    /// Save quota usage of the users along with the counters.
+    pub fn with_users(mut self, users: Arc<LurkUserStore>) -> LurkStatsCheckpointer {
+        self.users = Some(users);
+        self
+    }
+
+    /// Load counters from the checkpoint file (if it exists) and add them to the stats.
+    pub async fn restore(&self) -> Result<()> {
+        let path = &self.options.path;
+        let content = match fs::read(path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("Stats checkpoint {} doesn't exist yet, counters start from zero", path.display());
+                return Ok(());
+            }
+            Err(err) => return Err(err).with_context(|| format!("unable to read stats checkpoint {}", path.display())),
+        };
+
+        let checkpoint: LurkStatsCheckpoint =
+            serde_json::from_slice(&content).with_context(|| format!("stats checkpoint {} is corrupted", path.display()))?;
+
+        self.stats.restore_cumulative_counters(&checkpoint.counters);
+        if let Some(users) = &self.users {
+            users.restore_quota_usage(checkpoint.quota_usage);
+        }
+        info!(
+            "Restored stats counters saved at {} from {}",
+            checkpoint.saved_utc_ts,
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Write current counters to the checkpoint file.
+    ///
+    /// Checkpoint is written to the temporary file first and then renamed,
+    /// so the crash in the middle of writing doesn't corrupt the previous one.
+    pub async fn save(&self) -> Result<()> {
+        let checkpoint = LurkStatsCheckpoint {
+            saved_utc_ts: Utc::now(),
+            counters: self.stats.get_cumulative_counters(),
+            quota_usage: self.users.as_ref().map(|users| users.quota_usage()).unwrap_or_default(),
+        };
+
+        let path = &self.options.path;
+        let tmp_path = LurkStatsCheckpointer::tmp_path(path);
+
+        fs::write(&tmp_path, serde_json::to_vec(&checkpoint)?).await?;
+        fs::rename(&tmp_path, path).await?;
+
+        debug!("Stats checkpoint is saved to {}", path.display());
+        Ok(())
+    }
+
+    /// Periodically save checkpoints until the token is cancelled.
+    pub async fn run(self: Arc<Self>, token: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = sleep(self.options.interval) => {
+                    if let Err(err) = self.save().await {
+                        error!("Failed to save stats checkpoint to {}: {}", self.options.path.display(), err);
+                    }
+                },
+                _ = token.cancelled() => break
+            }
+        }
+    }
+
+    fn tmp_path(path: &Path) -> PathBuf {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        PathBuf::from(tmp_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{quota::LurkQuota, users::LurkUser},
+        net::tcp::connection::LurkTcpConnectionLabel,
+    };
+
+    #[tokio::test]
+    async fn save_and_restore_counters() {
+        let path = std::env::temp_dir().join(format!("lurk-stats-checkpoint-{}.json", std::process::id()));
+        let options = LurkStatsCheckpointOptions::new(&path, Duration::from_secs(60));
+
+        // Save counters of the "previous" server run.
+        let stats = Arc::new(LurkServerStats::new());
+        stats.on_connection_accepted(LurkTcpConnectionLabel::Socks5);
+        stats.on_accept_error();
+
+        let checkpointer = LurkStatsCheckpointer::new(Arc::clone(&stats), options.clone());
+        checkpointer.save().await.expect("Checkpoint should be saved");
+
+        // Restore them in the "next" one.
+        let restored_stats = Arc::new(LurkServerStats::new());
+        restored_stats.on_connection_accepted(LurkTcpConnectionLabel::Http);
+
+        let checkpointer = LurkStatsCheckpointer::new(Arc::clone(&restored_stats), options);
+        checkpointer.restore().await.expect("Checkpoint should be restored");
+
+        let counters = restored_stats.get_cumulative_counters();
+        assert_eq!(2, counters.accepted_connections);
+        assert_eq!(1, counters.accept_errors);
+        assert_eq!(1, counters.socks5_connections);
+        assert_eq!(1, counters.http_connections);
+
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_and_restore_quota_usage() {
+        let path = std::env::temp_dir().join(format!("lurk-quota-checkpoint-{}.json", std::process::id()));
+        let options = LurkStatsCheckpointOptions::new(&path, Duration::from_secs(60));
+        let quota = LurkQuota {
+            daily_bytes: Some(1000),
+            monthly_bytes: None,
+        };
+        let new_users = || Arc::new(LurkUserStore::new([LurkUser::new("alice", "secret", quota)], false));
+
+        let users = new_users();
+        users.charge("alice", 1000);
+        let checkpointer = LurkStatsCheckpointer::new(Arc::new(LurkServerStats::new()), options.clone()).with_users(users);
+        checkpointer.save().await.expect("Checkpoint should be saved");
+
+        // Quota used up before the restart is still used up after it.
+        let restored_users = new_users();
+        assert!(restored_users.check_quota("alice").is_ok());
+        let checkpointer = LurkStatsCheckpointer::new(Arc::new(LurkServerStats::new()), options).with_users(Arc::clone(&restored_users));
+        checkpointer.restore().await.expect("Checkpoint should be restored");
+        assert_eq!(1000, restored_users.get_usage("alice").unwrap().daily_bytes());
+        assert!(restored_users.check_quota("alice").is_err());
+
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,888 @@
+use crate::{
+    auth::users::LurkUserStore,
+    net::bans::{LurkBanChange, LurkClientBans},
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::{Buf, BytesMut};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{interval, timeout, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Settings of the state shared by the nodes of the cluster through Redis.
+///
+/// **Fields**:
+/// * ```redis_addr``` - "host:port" of the Redis server
+/// * ```redis_password``` - password sent with AUTH command, if it's required
+/// * ```node_id``` - name of the node, unique within the cluster
+/// * ```key_prefix``` - prefix of all keys written by the nodes, so several clusters could share one Redis
+/// * ```sync_interval``` - period between two exchanges of the state with the cluster
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkClusterOptions {
+    redis_addr: String,
+    redis_password: Option<String>,
+    node_id: String,
+    key_prefix: String,
+    sync_interval: Duration,
+}
+
+impl LurkClusterOptions {
+    pub const DEFAULT_KEY_PREFIX: &'static str = "lurk";
+    pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(redis_addr: impl Into<String>, node_id: impl Into<String>) -> LurkClusterOptions {
+        LurkClusterOptions {
+            redis_addr: redis_addr.into(),
+            redis_password: None,
+            node_id: node_id.into(),
+            key_prefix: LurkClusterOptions::DEFAULT_KEY_PREFIX.to_owned(),
+            sync_interval: LurkClusterOptions::DEFAULT_SYNC_INTERVAL,
+        }
+    }
+
+    pub fn set_redis_password(&mut self, password: impl Into<String>) -> &mut LurkClusterOptions {
+        self.redis_password = Some(password.into());
+        self
+    }
+
+    pub fn set_key_prefix(&mut self, key_prefix: impl Into<String>) -> &mut LurkClusterOptions {
+        self.key_prefix = key_prefix.into();
+        self
+    }
+
+    pub fn set_sync_interval(&mut self, sync_interval: Duration) -> &mut LurkClusterOptions {
+        self.sync_interval = sync_interval;
+        self
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Sessions of the node are forgotten by the cluster once it hasn't shared them for this long.
+    fn sessions_ttl_secs(&self) -> u64 {
+        (3 * self.sync_interval.as_secs()).max(5)
+    }
+
+    fn nodes_key(&self) -> String {
+        format!("{}:nodes", self.key_prefix)
+    }
+
+    fn sessions_key(&self, node_id: &str) -> String {
+        format!("{}:sessions:{}", self.key_prefix, node_id)
+    }
+
+    fn bans_key(&self) -> String {
+        format!("{}:bans", self.key_prefix)
+    }
+
+    fn ban_key(&self, ip: &str) -> String {
+        format!("{}:ban:{}", self.key_prefix, ip)
+    }
+
+    fn daily_usage_key(&self, user: &str, now: DateTime<Utc>) -> String {
+        format!("{}:usage:{}:{}", self.key_prefix, user, now.format("%Y-%m-%d"))
+    }
+
+    fn monthly_usage_key(&self, user: &str, now: DateTime<Utc>) -> String {
+        format!("{}:usage:{}:{}", self.key_prefix, user, now.format("%Y-%m"))
+    }
+}
+
+/// Periodic exchange of the client bans, users' transfer usage and active sessions with the other nodes,
+/// so bans, quotas and limits of simultaneous sessions are enforced across the whole cluster.
+///
+/// Every ban is a key expiring along with the ban, listed in the set of bans, so every node takes the
+/// bans of the others. Every node adds the bytes its users have transferred since the last exchange
+/// to the counters of the cluster and takes the totals back. Active sessions are published by every
+/// node under its own key, which expires unless the node refreshes it, so sessions of the crashed node
+/// are not counted forever. Node keeps serving with the last known state if Redis is unavailable.
+pub struct LurkClusterSync {
+    options: LurkClusterOptions,
+    bans: Arc<LurkClientBans>,
+    users: Option<Arc<LurkUserStore>>,
+    redis: Option<LurkRedisConnection>,
+}
+
+impl LurkClusterSync {
+    /// Number of seconds the daily and monthly usage counters are kept for.
+    const DAILY_USAGE_TTL_SECS: u64 = 2 * 24 * 3600;
+    const MONTHLY_USAGE_TTL_SECS: u64 = 32 * 24 * 3600;
+
+    pub fn new(options: LurkClusterOptions, bans: Arc<LurkClientBans>) -> LurkClusterSync {
+        LurkClusterSync {
+            options,
+            bans,
+            users: None,
+            redis: None,
+        }
+    }
+
+    /// Share usage and sessions of the users as well.
+    pub fn with_users(mut self, users: Arc<LurkUserStore>) -> LurkClusterSync {
+        self.users = Some(users);
+        self
+    }
+
+    /// Asynchronously exchange the state with the cluster until cancelled.
+    pub async fn run(mut self, token: CancellationToken) {
+        info!(
+            "Sharing client bans, users' usage and sessions through Redis {} as node '{}'",
+            self.options.redis_addr, self.options.node_id
+        );
+
+        let mut ticker = interval(self.options.sync_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut failing = false;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => match self.sync().await {
+                    Ok(()) if failing => {
+                        info!("State is shared with the cluster again");
+                        failing = false;
+                    }
+                    Ok(()) => {}
+                    Err(err) => {
+                        // Connection is likely broken, so it's established again next time.
+                        self.redis = None;
+                        if !failing {
+                            warn!("Unable to share state with the cluster, last known one is used: {:#}", err);
+                            failing = true;
+                        }
+                    }
+                },
+                _ = token.cancelled() => break,
+            }
+        }
+
+        // Sessions of the stopped node shouldn't be counted by the others.
+        let sessions_key = self.options.sessions_key(&self.options.node_id);
+        if let Ok(redis) = self.connection().await {
+            let _ = redis.execute(&[vec!["DEL".into(), sessions_key]]).await;
+        }
+    }
+
+    /// Share local state with the cluster and take the state of the cluster back.
+    async fn sync(&mut self) -> Result<()> {
+        let now = Utc::now();
+        self.push_bans(now).await?;
+        self.pull_bans().await?;
+
+        let Some(users) = self.users.clone() else {
+            return Ok(());
+        };
+        self.push_usage(&users, now).await?;
+        self.push_sessions(&users).await?;
+        self.pull_usage(&users, now).await?;
+        self.pull_sessions(&users).await
+    }
+
+    async fn push_bans(&mut self, now: DateTime<Utc>) -> Result<()> {
+        let changes = self.bans.take_unsynced_changes();
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let bans_key = self.options.bans_key();
+        let mut commands = Vec::with_capacity(2 * changes.len());
+        for change in &changes {
+            match change {
+                LurkBanChange::Ban(ip, expires_at) if *expires_at > now => {
+                    let (ip, ttl_secs) = (ip.to_string(), (*expires_at - now).num_seconds().max(1));
+                    let expires_at = expires_at.timestamp().to_string();
+                    commands.push(vec![
+                        "SET".into(),
+                        self.options.ban_key(&ip),
+                        expires_at,
+                        "EX".into(),
+                        ttl_secs.to_string(),
+                    ]);
+                    commands.push(vec!["SADD".into(), bans_key.clone(), ip]);
+                }
+                // Ban which has already expired is lifted the same way, so it's not left in the set.
+                LurkBanChange::Ban(ip, _) | LurkBanChange::Unban(ip) => {
+                    let ip = ip.to_string();
+                    commands.push(vec!["DEL".into(), self.options.ban_key(&ip)]);
+                    commands.push(vec!["SREM".into(), bans_key.clone(), ip]);
+                }
+            }
+        }
+
+        // Changes are idempotent, so they are safely shared once again, even if Redis has applied them.
+        let result = async { self.connection().await?.execute(&commands).await }.await;
+        if result.is_err() {
+            self.bans.restore_unsynced_changes(changes);
+        }
+        result.map(|_| ())
+    }
+
+    async fn pull_bans(&mut self) -> Result<()> {
+        let bans_key = self.options.bans_key();
+        let replies = self
+            .connection()
+            .await?
+            .execute(&[vec!["SMEMBERS".into(), bans_key.clone()]])
+            .await?;
+        let banned: Vec<String> = match replies.into_iter().next() {
+            Some(reply) => reply
+                .into_array()?
+                .into_iter()
+                .map(LurkRedisReply::into_string)
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        if banned.is_empty() {
+            self.bans.apply_cluster_bans(HashMap::new());
+            return Ok(());
+        }
+
+        let mut mget = vec!["MGET".into()];
+        mget.extend(banned.iter().map(|ip| self.options.ban_key(ip)));
+        let replies = self.connection().await?.execute(&[mget]).await?;
+        let values = replies
+            .into_iter()
+            .next()
+            .map(LurkRedisReply::into_array)
+            .transpose()?
+            .unwrap_or_default();
+        ensure!(
+            values.len() == banned.len(),
+            "MGET returned {} values instead of {}",
+            values.len(),
+            banned.len()
+        );
+
+        let mut cluster_bans = HashMap::new();
+        let mut expired = Vec::new();
+        for (ip, value) in banned.into_iter().zip(values) {
+            if value == LurkRedisReply::Bulk(None) {
+                // Key of the ban has expired along with it.
+                expired.push(vec!["SREM".into(), bans_key.clone(), ip]);
+                continue;
+            }
+            let client: IpAddr = ip.parse().with_context(|| format!("banned client '{}' is malformed", ip))?;
+            let expires_at = i64::try_from(value.as_u64()?)
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                .with_context(|| format!("expiration of the ban of {} is malformed", ip))?;
+            cluster_bans.insert(client, expires_at);
+        }
+        if !expired.is_empty() {
+            self.connection().await?.execute(&expired).await?;
+        }
+
+        self.bans.apply_cluster_bans(cluster_bans);
+        Ok(())
+    }
+
+    async fn push_usage(&mut self, users: &LurkUserStore, now: DateTime<Utc>) -> Result<()> {
+        let unsynced_bytes = users.take_unsynced_bytes();
+        let mut commands = Vec::with_capacity(4 * unsynced_bytes.len());
+        for (user, bytes) in unsynced_bytes.iter().filter(|(_, bytes)| **bytes > 0) {
+            for (key, ttl) in [
+                (self.options.daily_usage_key(user, now), LurkClusterSync::DAILY_USAGE_TTL_SECS),
+                (self.options.monthly_usage_key(user, now), LurkClusterSync::MONTHLY_USAGE_TTL_SECS),
+            ] {
+                commands.push(vec!["INCRBY".into(), key.clone(), bytes.to_string()]);
+                commands.push(vec!["EXPIRE".into(), key, ttl.to_string()]);
+            }
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let redis = match self.connection().await {
+            Ok(redis) => redis,
+            Err(err) => {
+                users.restore_unsynced_bytes(unsynced_bytes);
+                return Err(err);
+            }
+        };
+        // Once increments are sent, Redis may have applied them even if their replies are lost, so they
+        // aren't restored to be sent again: usage is rather undercounted once than counted twice.
+        redis.execute(&commands).await.map(|_| ())
+    }
+
+    async fn push_sessions(&mut self, users: &LurkUserStore) -> Result<()> {
+        let sessions_key = self.options.sessions_key(&self.options.node_id);
+        // Sessions are replaced in a transaction, so other nodes never see them half-written.
+        let mut commands = vec![vec!["MULTI".into()], vec!["DEL".into(), sessions_key.clone()]];
+
+        let sessions = users.local_sessions();
+        if !sessions.is_empty() {
+            let mut hset = vec!["HSET".into(), sessions_key.clone()];
+            for (user, active) in sessions {
+                hset.extend([user, active.to_string()]);
+            }
+            commands.push(hset);
+        }
+        commands.push(vec!["EXPIRE".into(), sessions_key, self.options.sessions_ttl_secs().to_string()]);
+        commands.push(vec!["SADD".into(), self.options.nodes_key(), self.options.node_id.clone()]);
+        commands.push(vec!["EXEC".into()]);
+
+        self.connection().await?.execute(&commands).await?;
+        Ok(())
+    }
+
+    async fn pull_usage(&mut self, users: &LurkUserStore, now: DateTime<Utc>) -> Result<()> {
+        let names = users.names_with_quota();
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let mut mget = vec!["MGET".into()];
+        for name in &names {
+            mget.push(self.options.daily_usage_key(name, now));
+            mget.push(self.options.monthly_usage_key(name, now));
+        }
+        let replies = self.connection().await?.execute(&[mget]).await?;
+        let values = replies
+            .into_iter()
+            .next()
+            .map(LurkRedisReply::into_array)
+            .transpose()?
+            .unwrap_or_default();
+        ensure!(
+            values.len() == 2 * names.len(),
+            "MGET returned {} values instead of {}",
+            values.len(),
+            2 * names.len()
+        );
+
+        for (name, usage) in names.iter().zip(values.chunks(2)) {
+            users.apply_cluster_usage(name, usage[0].as_u64()?, usage[1].as_u64()?);
+        }
+        Ok(())
+    }
+
+    async fn pull_sessions(&mut self, users: &LurkUserStore) -> Result<()> {
+        let nodes_key = self.options.nodes_key();
+        let replies = self
+            .connection()
+            .await?
+            .execute(&[vec!["SMEMBERS".into(), nodes_key.clone()]])
+            .await?;
+        let nodes: Vec<String> = match replies.into_iter().next() {
+            Some(reply) => reply
+                .into_array()?
+                .into_iter()
+                .map(LurkRedisReply::into_string)
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        let remote_nodes: Vec<String> = nodes.into_iter().filter(|node| *node != self.options.node_id).collect();
+
+        let commands: Vec<Vec<String>> = remote_nodes
+            .iter()
+            .map(|node| vec!["HGETALL".into(), self.options.sessions_key(node)])
+            .collect();
+        let replies = self.connection().await?.execute(&commands).await?;
+
+        let mut remote_sessions: HashMap<String, usize> = HashMap::new();
+        for (node, reply) in remote_nodes.iter().zip(replies) {
+            let fields = reply.into_array()?;
+            if fields.is_empty() {
+                // Node has stopped (or has no sessions and will add itself back).
+                debug!("Node '{}' has no sessions shared with the cluster", node);
+                let srem = vec!["SREM".into(), nodes_key.clone(), node.clone()];
+                self.connection().await?.execute(&[srem]).await?;
+                continue;
+            }
+            for pair in fields.chunks(2) {
+                let [user, active] = pair else {
+                    bail!("sessions of node '{}' are malformed", node)
+                };
+                *remote_sessions.entry(user.as_string()?).or_default() += active.as_u64()? as usize;
+            }
+        }
+
+        users.set_remote_sessions(remote_sessions);
+        Ok(())
+    }
+
+    /// Connection to Redis, established (and authenticated) once it's needed.
+    async fn connection(&mut self) -> Result<&mut LurkRedisConnection> {
+        if self.redis.is_none() {
+            let mut redis = LurkRedisConnection::connect(&self.options.redis_addr).await?;
+            if let Some(password) = &self.options.redis_password {
+                redis.execute(&[vec!["AUTH".into(), password.clone()]]).await?;
+            }
+            self.redis = Some(redis);
+        }
+        Ok(self.redis.as_mut().expect("connection should be established"))
+    }
+}
+
+/// Reply of Redis (RESP2). Error replies are turned into errors.
+#[derive(Debug, Clone, PartialEq)]
+enum LurkRedisReply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<LurkRedisReply>),
+}
+
+impl LurkRedisReply {
+    /// Maximum size of the bulk string, the same as the Redis default (proto-max-bulk-len).
+    const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+
+    /// Replies of the commands sent are nested two levels deep at most (e.g. EXEC of HGETALL),
+    /// deeper ones are never legit and would only exhaust the stack.
+    const MAX_NESTING_DEPTH: usize = 8;
+
+    fn into_array(self) -> Result<Vec<LurkRedisReply>> {
+        match self {
+            LurkRedisReply::Array(items) => Ok(items),
+            reply => bail!("expected array, got {:?}", reply),
+        }
+    }
+
+    fn into_string(self) -> Result<String> {
+        self.as_string()
+    }
+
+    fn as_string(&self) -> Result<String> {
+        match self {
+            LurkRedisReply::Status(value) => Ok(value.clone()),
+            LurkRedisReply::Bulk(Some(value)) => String::from_utf8(value.clone()).context("value is not UTF-8"),
+            reply => bail!("expected string, got {:?}", reply),
+        }
+    }
+
+    /// Numeric value, missing value is zero.
+    fn as_u64(&self) -> Result<u64> {
+        match self {
+            LurkRedisReply::Integer(value) => u64::try_from(*value).context("value is negative"),
+            LurkRedisReply::Bulk(None) => Ok(0),
+            reply => reply.as_string()?.parse().context("value is not a number"),
+        }
+    }
+
+    /// Parse reply from the beginning of the buffer. Returns the reply along
+    /// with its length, or nothing if the reply isn't received completely yet.
+    fn parse(buf: &[u8]) -> Result<Option<(LurkRedisReply, usize)>> {
+        LurkRedisReply::parse_nested(buf, 0)
+    }
+
+    fn parse_nested(buf: &[u8], depth: usize) -> Result<Option<(LurkRedisReply, usize)>> {
+        let Some(line_end) = buf.windows(2).position(|window| window == b"\r\n") else {
+            return Ok(None);
+        };
+        if line_end == 0 {
+            bail!("reply kind is missing")
+        }
+        let line = std::str::from_utf8(&buf[1..line_end]).context("reply is not UTF-8")?;
+        let mut len = line_end + 2;
+
+        let reply = match buf[0] {
+            b'+' => LurkRedisReply::Status(line.to_owned()),
+            b'-' => bail!("Redis error: {}", line),
+            b':' => LurkRedisReply::Integer(line.parse().context("malformed integer reply")?),
+            b'$' => match line.parse::<i64>().context("malformed bulk reply")? {
+                -1 => LurkRedisReply::Bulk(None),
+                size => {
+                    let size = usize::try_from(size).context("malformed bulk reply")?;
+                    if size > LurkRedisReply::MAX_BULK_SIZE {
+                        bail!("bulk reply of {} bytes is too large", size)
+                    }
+                    if buf.len() < len + size + 2 {
+                        return Ok(None);
+                    }
+                    let value = buf[len..len + size].to_vec();
+                    len += size + 2;
+                    LurkRedisReply::Bulk(Some(value))
+                }
+            },
+            b'*' => match line.parse::<i64>().context("malformed array reply")? {
+                -1 => LurkRedisReply::Array(Vec::new()),
+                count => {
+                    ensure!(depth < LurkRedisReply::MAX_NESTING_DEPTH, "array reply is nested too deep");
+                    let mut items = Vec::new();
+                    for _ in 0..count {
+                        let Some((item, item_len)) = LurkRedisReply::parse_nested(&buf[len..], depth + 1)? else {
+                            return Ok(None);
+                        };
+                        items.push(item);
+                        len += item_len;
+                    }
+                    LurkRedisReply::Array(items)
+                }
+            },
+            kind => bail!("unknown reply kind {:#04x}", kind),
+        };
+
+        Ok(Some((reply, len)))
+    }
+}
+
+/// Encode command as RESP array of bulk strings.
+fn encode_command(command: &[String], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+    for arg in command {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Minimal Redis client sending pipelined commands over one connection.
+struct LurkRedisConnection {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl LurkRedisConnection {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn connect(addr: &str) -> Result<LurkRedisConnection> {
+        let stream = timeout(LurkRedisConnection::TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| anyhow!("connection to Redis {} has timed out", addr))?
+            .with_context(|| format!("unable to connect to Redis {}", addr))?;
+
+        Ok(LurkRedisConnection {
+            stream,
+            buf: BytesMut::new(),
+        })
+    }
+
+    /// Send commands at once and wait for all their replies.
+    async fn execute(&mut self, commands: &[Vec<String>]) -> Result<Vec<LurkRedisReply>> {
+        timeout(LurkRedisConnection::TIMEOUT, self.execute_pipeline(commands))
+            .await
+            .map_err(|_| anyhow!("Redis hasn't replied in time"))?
+    }
+
+    async fn execute_pipeline(&mut self, commands: &[Vec<String>]) -> Result<Vec<LurkRedisReply>> {
+        let mut request = Vec::new();
+        for command in commands {
+            encode_command(command, &mut request);
+        }
+        self.stream.write_all(&request).await?;
+
+        let mut replies = Vec::with_capacity(commands.len());
+        // Error reply of any command fails the whole pipeline, after all replies are read.
+        let (mut received, mut error) = (0, None);
+        while received < commands.len() {
+            match LurkRedisReply::parse(&self.buf) {
+                Ok(Some((reply, len))) => {
+                    self.buf.advance(len);
+                    replies.push(reply);
+                    received += 1;
+                }
+                Ok(None) => {
+                    if self.stream.read_buf(&mut self.buf).await? == 0 {
+                        bail!("Redis has closed the connection");
+                    }
+                }
+                Err(err) if self.buf.first() == Some(&b'-') => {
+                    let line_end = self.buf.windows(2).position(|window| window == b"\r\n").unwrap_or_default();
+                    self.buf.advance(line_end + 2);
+                    error.get_or_insert(err);
+                    received += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(replies),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{quota::LurkQuota, users::LurkUser};
+    use std::{collections::HashSet, sync::Mutex};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_replies() {
+        assert_eq!(
+            Some((LurkRedisReply::Status("OK".to_owned()), 5)),
+            LurkRedisReply::parse(b"+OK\r\n").unwrap()
+        );
+        assert_eq!(Some((LurkRedisReply::Integer(42), 5)), LurkRedisReply::parse(b":42\r\n").unwrap());
+        assert_eq!(Some((LurkRedisReply::Bulk(None), 5)), LurkRedisReply::parse(b"$-1\r\n").unwrap());
+        assert_eq!(
+            Some((
+                LurkRedisReply::Array(vec![
+                    LurkRedisReply::Bulk(Some(b"alice".to_vec())),
+                    LurkRedisReply::Bulk(Some(b"2".to_vec()))
+                ]),
+                22
+            )),
+            LurkRedisReply::parse(b"*2\r\n$5\r\nalice\r\n$1\r\n2\r\n").unwrap()
+        );
+
+        // Incomplete replies are read further.
+        assert_eq!(None, LurkRedisReply::parse(b"$5\r\nali").unwrap());
+        assert_eq!(None, LurkRedisReply::parse(b"*2\r\n$5\r\nalice\r\n").unwrap());
+        assert_eq!(None, LurkRedisReply::parse(b":4").unwrap());
+
+        assert!(LurkRedisReply::parse(b"-ERR unknown command\r\n").is_err());
+        assert!(LurkRedisReply::parse(b"?\r\n").is_err());
+        assert!(LurkRedisReply::parse(b"\r\n").is_err());
+        assert!(LurkRedisReply::parse(b"*1\r\n\r\n").is_err());
+        assert!(LurkRedisReply::parse(b"$9223372036854775807\r\n").is_err());
+
+        // Arrays are nested a few levels deep at most.
+        let nested = |depth: usize| [b"*1\r\n".repeat(depth), b":1\r\n".to_vec()].concat();
+        assert!(LurkRedisReply::parse(&nested(LurkRedisReply::MAX_NESTING_DEPTH)).unwrap().is_some());
+        assert!(LurkRedisReply::parse(&nested(LurkRedisReply::MAX_NESTING_DEPTH + 1)).is_err());
+    }
+
+    #[test]
+    fn encode_commands() {
+        let mut buf = Vec::new();
+        encode_command(&["INCRBY".to_owned(), "key".to_owned(), "10".to_owned()], &mut buf);
+        assert_eq!(b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$2\r\n10\r\n".to_vec(), buf);
+    }
+
+    /// In-memory Redis serving the commands used by the cluster sync.
+    #[derive(Default)]
+    struct FakeRedis {
+        strings: HashMap<String, u64>,
+        hashes: HashMap<String, HashMap<String, String>>,
+        sets: HashMap<String, HashSet<String>>,
+    }
+
+    impl FakeRedis {
+        fn execute(&mut self, command: &[String]) -> String {
+            let bulk = |value: &str| format!("${}\r\n{}\r\n", value.len(), value);
+            match command[0].as_str() {
+                "INCRBY" => {
+                    let value = self.strings.entry(command[1].clone()).or_default();
+                    *value += command[2].parse::<u64>().unwrap();
+                    format!(":{}\r\n", value)
+                }
+                "MGET" => {
+                    let values: Vec<String> = command[1..]
+                        .iter()
+                        .map(|key| match self.strings.get(key) {
+                            Some(value) => bulk(&value.to_string()),
+                            None => "$-1\r\n".to_owned(),
+                        })
+                        .collect();
+                    format!("*{}\r\n{}", values.len(), values.concat())
+                }
+                // Commands of the transaction are executed right away.
+                "MULTI" => "+OK\r\n".to_owned(),
+                "EXEC" => "*0\r\n".to_owned(),
+                "EXPIRE" => ":1\r\n".to_owned(),
+                // Keys never expire, tests remove them instead.
+                "SET" => {
+                    self.strings.insert(command[1].clone(), command[2].parse().unwrap());
+                    "+OK\r\n".to_owned()
+                }
+                "DEL" => {
+                    self.hashes.remove(&command[1]);
+                    self.strings.remove(&command[1]);
+                    ":1\r\n".to_owned()
+                }
+                "HSET" => {
+                    let hash = self.hashes.entry(command[1].clone()).or_default();
+                    for pair in command[2..].chunks(2) {
+                        hash.insert(pair[0].clone(), pair[1].clone());
+                    }
+                    ":1\r\n".to_owned()
+                }
+                "HGETALL" => {
+                    let fields: Vec<String> = self
+                        .hashes
+                        .get(&command[1])
+                        .into_iter()
+                        .flatten()
+                        .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                        .collect();
+                    format!("*{}\r\n{}", fields.len(), fields.concat())
+                }
+                "SADD" => {
+                    self.sets.entry(command[1].clone()).or_default().insert(command[2].clone());
+                    ":1\r\n".to_owned()
+                }
+                "SREM" => {
+                    self.sets.entry(command[1].clone()).or_default().remove(&command[2]);
+                    ":1\r\n".to_owned()
+                }
+                "SMEMBERS" => {
+                    let members: Vec<String> = self.sets.get(&command[1]).into_iter().flatten().map(|m| bulk(m)).collect();
+                    format!("*{}\r\n{}", members.len(), members.concat())
+                }
+                _ => "-ERR unknown command\r\n".to_owned(),
+            }
+        }
+    }
+
+    async fn serve_fake_redis(listener: TcpListener, redis: Arc<Mutex<FakeRedis>>) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let redis = Arc::clone(&redis);
+            tokio::spawn(async move {
+                let mut buf = BytesMut::new();
+                loop {
+                    while let Ok(Some((LurkRedisReply::Array(args), len))) = LurkRedisReply::parse(&buf) {
+                        buf.advance(len);
+                        let command: Vec<String> = args.iter().map(|arg| arg.as_string().unwrap()).collect();
+                        let reply = redis.lock().unwrap().execute(&command);
+                        stream.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                    if stream.read_buf(&mut buf).await.unwrap_or_default() == 0 {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    fn cluster_sync(redis_addr: &str, node_id: &str, users: &Arc<LurkUserStore>) -> LurkClusterSync {
+        LurkClusterSync::new(LurkClusterOptions::new(redis_addr, node_id), Arc::new(LurkClientBans::new())).with_users(Arc::clone(users))
+    }
+
+    fn user_store() -> Arc<LurkUserStore> {
+        let quota = LurkQuota {
+            daily_bytes: Some(1000),
+            monthly_bytes: None,
+        };
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", quota)], false).with_max_sessions(2);
+        Arc::new(users)
+    }
+
+    #[tokio::test]
+    async fn share_usage_and_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis_addr = listener.local_addr().unwrap().to_string();
+        let redis = Arc::new(Mutex::new(FakeRedis::default()));
+        let fake_redis = tokio::spawn(serve_fake_redis(listener, Arc::clone(&redis)));
+
+        let (users_a, users_b) = (user_store(), user_store());
+        let mut node_a = cluster_sync(&redis_addr, "a", &users_a);
+        let mut node_b = cluster_sync(&redis_addr, "b", &users_b);
+
+        // Usage of both nodes is summed up.
+        users_a.charge("alice", 600);
+        users_b.charge("alice", 300);
+        node_a.sync().await.unwrap();
+        node_b.sync().await.unwrap();
+        node_a.sync().await.unwrap();
+        assert_eq!(900, users_a.get_usage("alice").unwrap().daily_bytes());
+        assert_eq!(900, users_b.get_usage("alice").unwrap().daily_bytes());
+
+        users_b.charge("alice", 100);
+        node_b.sync().await.unwrap();
+        node_a.sync().await.unwrap();
+        assert!(users_a.check_quota("alice").is_err(), "Quota is used up across the cluster");
+
+        // Sessions on the other node are counted towards the limit.
+        let _session = users_a.open_session("alice").unwrap();
+        node_a.sync().await.unwrap();
+        node_b.sync().await.unwrap();
+        let _session = users_b.open_session("alice").unwrap();
+        assert!(users_b.open_session("alice").is_err());
+
+        // Sessions of the node, which has stopped sharing them, aren't counted anymore.
+        redis.lock().unwrap().hashes.remove("lurk:sessions:a");
+        node_b.sync().await.unwrap();
+        assert!(users_b.open_session("alice").is_ok());
+        assert!(!redis.lock().unwrap().sets["lurk:nodes"].contains("a"));
+
+        fake_redis.abort();
+    }
+
+    #[tokio::test]
+    async fn keep_usage_while_redis_is_unavailable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis_addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let users = user_store();
+        let mut node = cluster_sync(&redis_addr, "a", &users);
+
+        users.charge("alice", 600);
+        assert!(node.sync().await.is_err());
+        // Bytes are shared once Redis is available again.
+        assert_eq!(HashMap::from([("alice".to_owned(), 600)]), users.take_unsynced_bytes());
+        assert_eq!(600, users.get_usage("alice").unwrap().daily_bytes());
+    }
+
+    #[tokio::test]
+    async fn drop_usage_sent_without_reply() {
+        // Redis reads the increments, but the connection is broken before it replies.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis_addr = listener.local_addr().unwrap().to_string();
+        let redis = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut redis = FakeRedis::default();
+            let mut buf = BytesMut::new();
+            loop {
+                while let Some((LurkRedisReply::Array(args), len)) = LurkRedisReply::parse(&buf).unwrap() {
+                    buf.advance(len);
+                    let command: Vec<String> = args.iter().map(|arg| arg.as_string().unwrap()).collect();
+                    if command[0] == "INCRBY" {
+                        return;
+                    }
+                    stream.write_all(redis.execute(&command).as_bytes()).await.unwrap();
+                }
+                stream.read_buf(&mut buf).await.unwrap();
+            }
+        });
+
+        let users = user_store();
+        let mut node = cluster_sync(&redis_addr, "a", &users);
+
+        // Increments may have been applied, so they aren't sent once again.
+        users.charge("alice", 600);
+        assert!(node.sync().await.is_err());
+        assert!(users.take_unsynced_bytes().is_empty());
+
+        redis.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn share_bans() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis_addr = listener.local_addr().unwrap().to_string();
+        let redis = Arc::new(Mutex::new(FakeRedis::default()));
+        let fake_redis = tokio::spawn(serve_fake_redis(listener, Arc::clone(&redis)));
+
+        let (bans_a, bans_b) = (Arc::new(LurkClientBans::new()), Arc::new(LurkClientBans::new()));
+        let mut node_a = LurkClusterSync::new(LurkClusterOptions::new(&redis_addr, "a"), Arc::clone(&bans_a));
+        let mut node_b = LurkClusterSync::new(LurkClusterOptions::new(&redis_addr, "b"), Arc::clone(&bans_b));
+        let (alice, bob): (IpAddr, IpAddr) = ("203.0.113.1".parse().unwrap(), "2001:db8::1".parse().unwrap());
+
+        // Client banned on one node is banned on the other one.
+        let expires_at = bans_a.ban(alice, Duration::from_secs(3600));
+        bans_b.ban(bob, Duration::from_secs(3600));
+        node_a.sync().await.unwrap();
+        node_b.sync().await.unwrap();
+        node_a.sync().await.unwrap();
+        assert!(bans_a.is_banned(bob));
+        assert!(bans_b.is_banned(alice));
+        assert_eq!(
+            Some(&(expires_at.timestamp() as u64)),
+            redis.lock().unwrap().strings.get("lurk:ban:203.0.113.1")
+        );
+
+        // Lifted ban is lifted everywhere.
+        bans_b.unban(alice);
+        node_b.sync().await.unwrap();
+        node_a.sync().await.unwrap();
+        assert!(!bans_a.is_banned(alice));
+
+        // Expired ban is forgotten.
+        redis.lock().unwrap().strings.remove("lurk:ban:2001:db8::1");
+        node_a.sync().await.unwrap();
+        assert!(!bans_a.is_banned(bob));
+        assert!(redis.lock().unwrap().sets["lurk:bans"].is_empty());
+
+        fake_redis.abort();
+    }
+}
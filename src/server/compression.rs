@@ -0,0 +1,342 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, StreamBody};
+use hyper::{
+    body::{Body, Frame},
+    header::{self, HeaderMap, HeaderValue},
+    Method, Response, StatusCode,
+};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Content coding the responses are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LurkContentCoding {
+    Gzip,
+    Brotli,
+}
+
+impl LurkContentCoding {
+    pub fn token(&self) -> &'static str {
+        match self {
+            LurkContentCoding::Gzip => "gzip",
+            LurkContentCoding::Brotli => "br",
+        }
+    }
+
+    /// Update headers of the response compressed with the coding: its length and ranges are
+    /// unknown from now on, and the entity tag can't be strong anymore.
+    pub fn encode_head(&self, headers: &mut HeaderMap) {
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(header::ACCEPT_RANGES);
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(self.token()));
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+        let weak_etag = headers
+            .get(header::ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .and_then(|etag| HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat()).ok());
+        if let Some(weak_etag) = weak_etag {
+            headers.insert(header::ETAG, weak_etag);
+        }
+    }
+
+    /// Compress the body as it's streamed through. Trailers of the body follow the compressed data.
+    pub fn encode_body<B>(&self, body: B) -> BoxBody<Bytes, io::Error>
+    where
+        B: Body<Data = Bytes> + Send + Sync + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        // Encoder reads data only, so trailers are put aside until it's finished.
+        let trailers = Arc::new(Mutex::new(None));
+        let data = BodyStream::new(body).filter_map({
+            let trailers = Arc::clone(&trailers);
+            move |frame| match frame.map(Frame::into_data) {
+                Ok(Ok(data)) => Some(Ok(data)),
+                Ok(Err(frame)) => {
+                    if let Ok(frame_trailers) = frame.into_trailers() {
+                        *trailers.lock().unwrap() = Some(frame_trailers);
+                    }
+                    None
+                }
+                Err(err) => Some(Err(io::Error::other(err))),
+            }
+        });
+        let reader = StreamReader::new(data);
+        match self {
+            LurkContentCoding::Gzip => encoded_body(GzipEncoder::new(reader), trailers),
+            LurkContentCoding::Brotli => encoded_body(BrotliEncoder::new(reader), trailers),
+        }
+    }
+}
+
+/// Body of the encoder output, followed by the trailers put aside once the output is over.
+fn encoded_body<E>(encoder: E, trailers: Arc<Mutex<Option<HeaderMap>>>) -> BoxBody<Bytes, io::Error>
+where
+    E: AsyncRead + Send + Sync + 'static,
+{
+    let trailers = std::iter::once_with(move || trailers.lock().unwrap().take())
+        .flatten()
+        .map(|trailers| Ok(Frame::trailers(trailers)));
+    StreamBody::new(
+        ReaderStream::new(encoder)
+            .map(|chunk| chunk.map(Frame::data))
+            .chain(tokio_stream::iter(trailers)),
+    )
+    .boxed()
+}
+
+/// Compression of the forwarded HTTP responses for the clients accepting compressed content.
+///
+/// **Fields**:
+/// * ```min_size``` - responses with shorter Content-Length are sent as they are, the ones without it are compressed
+/// * ```content_types``` - media types of the compressed responses, the ones ending with "/" match all their subtypes (e.g. "text/")
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkCompression {
+    min_size: u64,
+    content_types: Vec<String>,
+}
+
+impl LurkCompression {
+    pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+    pub const DEFAULT_CONTENT_TYPES: &'static [&'static str] = &[
+        "text/",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ];
+
+    pub fn new() -> LurkCompression {
+        LurkCompression {
+            min_size: LurkCompression::DEFAULT_MIN_SIZE,
+            content_types: LurkCompression::DEFAULT_CONTENT_TYPES.iter().map(|&t| t.to_owned()).collect(),
+        }
+    }
+
+    pub fn set_min_size(&mut self, min_size: u64) -> &mut LurkCompression {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn set_content_types(&mut self, content_types: impl IntoIterator<Item = String>) -> &mut LurkCompression {
+        self.content_types = content_types
+            .into_iter()
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Coding the response to the request is compressed with, which is the one the client prefers by Accept-Encoding
+    /// (brotli, if it accepts both equally). Responses to HEAD, without content (e.g. 204 or 304), partial, already encoded,
+    /// marked with "no-transform", of other media types or shorter than the minimum size are left as they are.
+    pub fn coding<B>(&self, method: &Method, accept_encoding: Option<&HeaderValue>, response: &Response<B>) -> Option<LurkContentCoding> {
+        let status = response.status();
+        if method == Method::HEAD
+            || status.is_informational()
+            || matches!(
+                status,
+                StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+            )
+        {
+            return None;
+        }
+
+        let headers = response.headers();
+        if headers.contains_key(header::CONTENT_ENCODING) || headers.contains_key(header::CONTENT_RANGE) {
+            return None;
+        }
+        let no_transform = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        if no_transform {
+            return None;
+        }
+
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let compressible = self.content_types.iter().any(|content_type| match content_type.ends_with('/') {
+            true => media_type.starts_with(content_type.as_str()),
+            false => media_type == *content_type,
+        });
+        if !compressible {
+            return None;
+        }
+
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        if content_length.is_some_and(|content_length| content_length < self.min_size) {
+            return None;
+        }
+
+        preferred_coding(accept_encoding?.to_str().ok()?)
+    }
+}
+
+impl Default for LurkCompression {
+    fn default() -> Self {
+        LurkCompression::new()
+    }
+}
+
+/// Coding with the highest quality in Accept-Encoding, codings not listed take the quality of "*".
+fn preferred_coding(accept_encoding: &str) -> Option<LurkContentCoding> {
+    let mut qualities = [None, None, None];
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "br" => qualities[0] = Some(quality),
+            "gzip" | "x-gzip" => qualities[1] = Some(quality),
+            "*" => qualities[2] = Some(quality),
+            _ => {}
+        }
+    }
+
+    let [brotli, gzip, any] = qualities;
+    let brotli = brotli.or(any).unwrap_or(0.0);
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    match (brotli, gzip) {
+        (brotli, gzip) if brotli > 0.0 && brotli >= gzip => Some(LurkContentCoding::Brotli),
+        (_, gzip) if gzip > 0.0 => Some(LurkContentCoding::Gzip),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder};
+    use http_body_util::Full;
+    use tokio::io::AsyncReadExt;
+
+    fn response(content_type: &str, content_length: usize) -> Response<()> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, content_length)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn pick_coding() {
+        let compression = LurkCompression::new();
+        let coding = |accept_encoding: &str, response: &Response<()>| {
+            compression.coding(&Method::GET, Some(&HeaderValue::from_str(accept_encoding).unwrap()), response)
+        };
+        let html = response("text/html; charset=utf-8", 4096);
+
+        assert_eq!(Some(LurkContentCoding::Brotli), coding("gzip, deflate, br", &html));
+        assert_eq!(Some(LurkContentCoding::Gzip), coding("gzip, br;q=0.5", &html));
+        assert_eq!(Some(LurkContentCoding::Gzip), coding("br;q=0, *", &html));
+        assert_eq!(None, coding("identity, deflate", &html));
+        assert_eq!(None, coding("*;q=0", &html));
+        assert_eq!(None, compression.coding(&Method::GET, None, &html));
+        assert_eq!(
+            None,
+            compression.coding(&Method::HEAD, Some(&HeaderValue::from_static("br")), &html)
+        );
+
+        assert_eq!(None, coding("br", &response("text/html", 100)));
+        assert_eq!(None, coding("br", &response("image/png", 4096)));
+        assert_eq!(Some(LurkContentCoding::Brotli), coding("br", &response("Application/JSON", 4096)));
+
+        let mut encoded = response("text/html", 4096);
+        encoded
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(None, coding("br", &encoded));
+        let mut no_transform = response("text/html", 4096);
+        no_transform
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("max-age=60, No-Transform"));
+        assert_eq!(None, coding("br", &no_transform));
+        let mut not_modified = response("text/html", 4096);
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+        assert_eq!(None, coding("br", &not_modified));
+
+        let mut compression = LurkCompression::new();
+        compression.set_min_size(0).set_content_types(["image/".to_owned()]);
+        assert_eq!(
+            Some(LurkContentCoding::Gzip),
+            compression.coding(&Method::GET, Some(&HeaderValue::from_static("gzip")), &response("image/png", 10))
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_response() {
+        let content = "lurk ".repeat(1024);
+        for coding in [LurkContentCoding::Gzip, LurkContentCoding::Brotli] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content.len()));
+            headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+            coding.encode_head(&mut headers);
+            assert_eq!(None, headers.get(header::CONTENT_LENGTH));
+            assert_eq!(coding.token(), headers[header::CONTENT_ENCODING]);
+            assert_eq!("accept-encoding", headers[header::VARY]);
+            assert_eq!("W/\"v1\"", headers[header::ETAG]);
+
+            let body = coding.encode_body(Full::new(Bytes::from(content.clone())));
+            let compressed = body.collect().await.unwrap().to_bytes();
+            assert!(compressed.len() < content.len());
+
+            let mut decompressed = String::new();
+            match coding {
+                LurkContentCoding::Gzip => GzipDecoder::new(compressed.as_ref()).read_to_string(&mut decompressed).await,
+                LurkContentCoding::Brotli => BrotliDecoder::new(compressed.as_ref()).read_to_string(&mut decompressed).await,
+            }
+            .unwrap();
+            assert_eq!(content, decompressed);
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_trailers_of_compressed_response() {
+        let content = "lurk ".repeat(1024);
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        for coding in [LurkContentCoding::Gzip, LurkContentCoding::Brotli] {
+            let frames = [
+                Ok::<_, io::Error>(Frame::data(Bytes::from(content.clone()))),
+                Ok(Frame::trailers(trailers.clone())),
+            ];
+            let collected = coding
+                .encode_body(StreamBody::new(tokio_stream::iter(frames)))
+                .collect()
+                .await
+                .unwrap();
+            assert_eq!(Some(&trailers), collected.trailers());
+
+            let mut decompressed = String::new();
+            match coding {
+                LurkContentCoding::Gzip => {
+                    GzipDecoder::new(collected.to_bytes().as_ref())
+                        .read_to_string(&mut decompressed)
+                        .await
+                }
+                LurkContentCoding::Brotli => {
+                    BrotliDecoder::new(collected.to_bytes().as_ref())
+                        .read_to_string(&mut decompressed)
+                        .await
+                }
+            }
+            .unwrap();
+            assert_eq!(content, decompressed);
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use anyhow::{ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use chrono::Utc;
+use clap::ValueEnum;
+use http_body_util::{BodyExt, Full};
+use hyper::{client::conn::http1, header, Method, Request};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{net::IpAddr, time::Duration};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Service registry the proxy is registered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LurkDiscoveryBackend {
+    // Consul agent HTTP API.
+    Consul,
+    // etcd v3 JSON gateway.
+    Etcd,
+}
+
+impl LurkDiscoveryBackend {
+    /// Address of the locally running agent (or cluster member).
+    pub fn default_endpoint(&self) -> &'static str {
+        match self {
+            LurkDiscoveryBackend::Consul => "127.0.0.1:8500",
+            LurkDiscoveryBackend::Etcd => "127.0.0.1:2379",
+        }
+    }
+}
+
+/// Settings of the proxy registration in the service registry.
+///
+/// **Fields**:
+/// * ```backend``` - service registry kind
+/// * ```endpoint``` - address ("host:port") of the registry HTTP API
+/// * ```service_name``` - name the proxy is discoverable by
+/// * ```advertise_ip``` - IP address advertised to the clients, registry decides if not set
+/// * ```health_url``` - URL of the healthcheck endpoint, if it's served
+/// * ```tags``` - tags attached to the registration
+/// * ```ttl``` - registration expires unless it's renewed within this period
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkDiscoveryOptions {
+    backend: LurkDiscoveryBackend,
+    endpoint: String,
+    service_name: String,
+    advertise_ip: Option<IpAddr>,
+    health_url: Option<String>,
+    tags: Vec<String>,
+    ttl: Duration,
+}
+
+impl LurkDiscoveryOptions {
+    /// Lower bound for the registration TTL.
+    const MIN_TTL: Duration = Duration::from_secs(3);
+
+    pub fn new(
+        backend: LurkDiscoveryBackend,
+        endpoint: impl Into<String>,
+        service_name: impl Into<String>,
+        ttl: Duration,
+    ) -> LurkDiscoveryOptions {
+        LurkDiscoveryOptions {
+            backend,
+            endpoint: endpoint.into(),
+            service_name: service_name.into(),
+            advertise_ip: None,
+            health_url: None,
+            tags: Vec::new(),
+            ttl: ttl.max(Self::MIN_TTL),
+        }
+    }
+
+    pub fn set_advertise_ip(&mut self, advertise_ip: IpAddr) -> &mut LurkDiscoveryOptions {
+        self.advertise_ip = Some(advertise_ip);
+        self
+    }
+
+    pub fn set_health_url(&mut self, health_url: impl Into<String>) -> &mut LurkDiscoveryOptions {
+        self.health_url = Some(health_url.into());
+        self
+    }
+
+    pub fn set_tags(&mut self, tags: impl IntoIterator<Item = impl Into<String>>) -> &mut LurkDiscoveryOptions {
+        self.tags = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Registration is renewed several times per TTL, so a single failed heartbeat doesn't expire it.
+    fn heartbeat_interval(&self) -> Duration {
+        self.ttl / 3
+    }
+}
+
+/// Handle of the registration needed to renew and remove it.
+#[derive(Debug, PartialEq)]
+enum LurkRegistration {
+    Consul { service_id: String },
+    Etcd { lease_id: String },
+}
+
+/// Registers the proxy in the service registry on start, keeps the registration
+/// alive by heartbeats and removes it on shutdown.
+pub struct LurkServiceRegistrar {
+    options: LurkDiscoveryOptions,
+    node_id: String,
+}
+
+impl LurkServiceRegistrar {
+    /// Prefix of the etcd keys the registrations are stored under.
+    const ETCD_KEY_PREFIX: &'static str = "/services";
+    /// Registry is expected to respond within this period, so a stalled one doesn't block the heartbeats and shutdown.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(options: LurkDiscoveryOptions) -> LurkServiceRegistrar {
+        LurkServiceRegistrar {
+            options,
+            node_id: format!("{}-{}", std::process::id(), Utc::now().timestamp_millis()),
+        }
+    }
+
+    /// Identity of the proxy instance within the registry. Several instances listening on the same port
+    /// (e.g. on different hosts) share one etcd cluster, so it's the advertised address if it's set.
+    fn instance_id(&self) -> String {
+        self.options
+            .advertise_ip
+            .map_or_else(|| self.node_id.clone(), |advertise_ip| advertise_ip.to_string())
+    }
+
+    /// Register the proxy listening on the ```port``` and keep it registered until the token is cancelled.
+    pub async fn run(self, port: u16, token: CancellationToken) {
+        let registration = tokio::select! {
+            registration = self.register_with_retries(port) => registration,
+            _ = token.cancelled() => return
+        };
+
+        loop {
+            tokio::select! {
+                _ = sleep(self.options.heartbeat_interval()) => {
+                    if let Err(err) = self.heartbeat(&registration).await {
+                        warn!("Failed to renew registration in {:?}: {}", self.options.backend, err);
+                    }
+                },
+                _ = token.cancelled() => break
+            }
+        }
+
+        match self.deregister(&registration).await {
+            Ok(()) => info!("Proxy is deregistered from {:?}", self.options.backend),
+            Err(err) => error!("Failed to deregister proxy from {:?}: {}", self.options.backend, err),
+        }
+    }
+
+    /// Registry may be not available yet (e.g. agent is starting along with the proxy).
+    async fn register_with_retries(&self, port: u16) -> LurkRegistration {
+        loop {
+            match self.register(port).await {
+                Ok(registration) => {
+                    info!(
+                        "Proxy is registered in {:?} at {} as '{}'",
+                        self.options.backend, self.options.endpoint, self.options.service_name
+                    );
+                    return registration;
+                }
+                Err(err) => {
+                    warn!("Failed to register proxy in {:?}: {}", self.options.backend, err);
+                    sleep(self.options.heartbeat_interval()).await;
+                }
+            }
+        }
+    }
+
+    async fn register(&self, port: u16) -> Result<LurkRegistration> {
+        let options = &self.options;
+        match options.backend {
+            LurkDiscoveryBackend::Consul => {
+                let service_id = format!("{}-{}", options.service_name, port);
+                let mut checks = vec![json!({
+                    "CheckID": format!("service:{}", service_id),
+                    "TTL": format!("{}s", options.ttl.as_secs()),
+                    "DeregisterCriticalServiceAfter": format!("{}s", options.ttl.as_secs() * 10),
+                })];
+                if let Some(health_url) = &options.health_url {
+                    checks.push(json!({ "HTTP": health_url, "Interval": format!("{}s", options.heartbeat_interval().as_secs()) }));
+                }
+                let service = json!({
+                    "ID": service_id,
+                    "Name": options.service_name,
+                    "Address": options.advertise_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                    "Port": port,
+                    "Tags": options.tags,
+                    "Checks": checks,
+                });
+
+                self.request(Method::PUT, "/v1/agent/service/register", Some(service)).await?;
+                // TTL check is critical until the first heartbeat.
+                let registration = LurkRegistration::Consul { service_id };
+                self.heartbeat(&registration).await?;
+                Ok(registration)
+            }
+            LurkDiscoveryBackend::Etcd => {
+                #[derive(Deserialize)]
+                struct LeaseGrantResponse {
+                    #[serde(rename = "ID")]
+                    id: String,
+                }
+
+                let lease = self
+                    .request(Method::POST, "/v3/lease/grant", Some(json!({ "TTL": options.ttl.as_secs() })))
+                    .await?;
+                let lease: LeaseGrantResponse = serde_json::from_value(lease).context("unexpected lease grant response")?;
+
+                let key = format!(
+                    "{}/{}/{}-{}-{}",
+                    Self::ETCD_KEY_PREFIX,
+                    options.service_name,
+                    options.service_name,
+                    self.instance_id(),
+                    port
+                );
+                let value = json!({
+                    "addr": options.advertise_ip,
+                    "port": port,
+                    "health": options.health_url,
+                    "tags": options.tags,
+                });
+                let put = json!({
+                    "key": BASE64.encode(key),
+                    "value": BASE64.encode(value.to_string()),
+                    "lease": lease.id,
+                });
+                self.request(Method::POST, "/v3/kv/put", Some(put)).await?;
+
+                Ok(LurkRegistration::Etcd { lease_id: lease.id })
+            }
+        }
+    }
+
+    async fn heartbeat(&self, registration: &LurkRegistration) -> Result<()> {
+        match registration {
+            LurkRegistration::Consul { service_id } => {
+                let path = format!("/v1/agent/check/pass/service:{}", service_id);
+                self.request(Method::PUT, &path, None).await?;
+            }
+            LurkRegistration::Etcd { lease_id } => {
+                self.request(Method::POST, "/v3/lease/keepalive", Some(json!({ "ID": lease_id })))
+                    .await?;
+            }
+        }
+        debug!("Registration in {:?} is renewed", self.options.backend);
+        Ok(())
+    }
+
+    async fn deregister(&self, registration: &LurkRegistration) -> Result<()> {
+        match registration {
+            LurkRegistration::Consul { service_id } => {
+                let path = format!("/v1/agent/service/deregister/{}", service_id);
+                self.request(Method::PUT, &path, None).await?;
+            }
+            LurkRegistration::Etcd { lease_id } => {
+                // Keys attached to the lease are removed along with it.
+                self.request(Method::POST, "/v3/lease/revoke", Some(json!({ "ID": lease_id })))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send request to the registry HTTP API. Returns JSON response body (null if it's empty).
+    async fn request(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        timeout(Self::REQUEST_TIMEOUT, self.send_request(method, path, body))
+            .await
+            .with_context(|| format!("{} {} has timed out", self.options.endpoint, path))?
+    }
+
+    async fn send_request(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let stream = TcpStream::connect(self.options.endpoint.as_str()).await?;
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+
+        let body = body.map_or_else(Bytes::new, |body| Bytes::from(body.to_string()));
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, self.options.endpoint.as_str())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(body))?;
+
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        ensure!(
+            status.is_success(),
+            "{} {} has responded with {}: {}",
+            self.options.endpoint,
+            path,
+            status,
+            String::from_utf8_lossy(&body)
+        );
+
+        match body.is_empty() {
+            true => Ok(Value::Null),
+            false => serde_json::from_slice(&body).with_context(|| format!("{} has responded with invalid JSON", path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{
+        all_of,
+        matchers::{json_decoded, request},
+        responders::{json_encoded, status_code},
+        Expectation, Server,
+    };
+
+    fn options(server: &Server, backend: LurkDiscoveryBackend) -> LurkDiscoveryOptions {
+        let mut options = LurkDiscoveryOptions::new(backend, server.addr().to_string(), "lurk", Duration::from_secs(30));
+        options
+            .set_advertise_ip("10.0.0.1".parse().unwrap())
+            .set_health_url("http://10.0.0.1:8080/healthcheck")
+            .set_tags(["edge"]);
+        options
+    }
+
+    #[tokio::test]
+    async fn consul_registration() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/v1/agent/service/register"),
+                request::body(json_decoded(|body: &Value| {
+                    body["ID"] == "lurk-1080" && body["Address"] == "10.0.0.1" && body["Port"] == 1080 && body["Tags"][0] == "edge"
+                })),
+            ])
+            .respond_with(status_code(200)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/v1/agent/check/pass/service:lurk-1080")).respond_with(status_code(200)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/v1/agent/service/deregister/lurk-1080")).respond_with(status_code(200)),
+        );
+
+        let registrar = LurkServiceRegistrar::new(options(&server, LurkDiscoveryBackend::Consul));
+        let registration = registrar.register(1080).await.expect("Proxy should be registered");
+        assert_eq!(
+            LurkRegistration::Consul {
+                service_id: "lurk-1080".to_owned()
+            },
+            registration
+        );
+        registrar.deregister(&registration).await.expect("Proxy should be deregistered");
+    }
+
+    #[tokio::test]
+    async fn etcd_registration() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/v3/lease/grant"),
+                request::body(json_decoded(|body: &Value| body["TTL"] == 30)),
+            ])
+            .respond_with(json_encoded(json!({ "ID": "7587", "TTL": "30" }))),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/v3/kv/put"),
+                request::body(json_decoded(|body: &Value| {
+                    body["key"] == BASE64.encode("/services/lurk/lurk-10.0.0.1-1080") && body["lease"] == "7587"
+                })),
+            ])
+            .respond_with(json_encoded(json!({}))),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/v3/lease/keepalive"),
+                request::body(json_decoded(|body: &Value| body["ID"] == "7587")),
+            ])
+            .respond_with(json_encoded(json!({}))),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/v3/lease/revoke"),
+                request::body(json_decoded(|body: &Value| body["ID"] == "7587")),
+            ])
+            .respond_with(json_encoded(json!({}))),
+        );
+
+        let registrar = LurkServiceRegistrar::new(options(&server, LurkDiscoveryBackend::Etcd));
+        let registration = registrar.register(1080).await.expect("Proxy should be registered");
+        registrar.heartbeat(&registration).await.expect("Registration should be renewed");
+        registrar.deregister(&registration).await.expect("Proxy should be deregistered");
+    }
+
+    #[test]
+    fn distinct_etcd_keys_without_advertise_ip() {
+        let options = LurkDiscoveryOptions::new(LurkDiscoveryBackend::Etcd, "127.0.0.1:2379", "lurk", Duration::from_secs(30));
+        let registrar = LurkServiceRegistrar::new(options.clone());
+        let mut other = LurkServiceRegistrar::new(options);
+        other.node_id.push_str("-other");
+        assert_ne!(registrar.instance_id(), other.instance_id());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_stalled_registry() {
+        // Registry accepts the connection, but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let options = LurkDiscoveryOptions::new(LurkDiscoveryBackend::Etcd, endpoint, "lurk", Duration::from_secs(30));
+        let registrar = LurkServiceRegistrar::new(options);
+
+        let err = registrar.register(1080).await.expect_err("Registration should time out");
+        assert!(err.to_string().contains("has timed out"), "{}", err);
+        drop(listener);
+    }
+}
@@ -0,0 +1,210 @@
+use clap::ValueEnum;
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio::{net::lookup_host, time::timeout};
+
+/// What is done with the destination listed in one of the zones.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkDnsblAction {
+    /// Refuse connection to the destination
+    #[default]
+    Block,
+    /// Connect, but log a warning about the destination
+    Flag,
+}
+
+/// Settings of DNS blocklist lookups.
+///
+/// **Fields**:
+/// * ```zones``` - DNSBL zones destination IPs are looked up in, e.g. "zen.spamhaus.org"
+/// * ```action``` - what is done with the listed destination
+/// * ```cache_ttl``` - period lookup results are reused for
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkDnsblOptions {
+    zones: Vec<String>,
+    action: LurkDnsblAction,
+    cache_ttl: Duration,
+}
+
+impl LurkDnsblOptions {
+    pub fn new(zones: impl IntoIterator<Item = impl Into<String>>, action: LurkDnsblAction, cache_ttl: Duration) -> LurkDnsblOptions {
+        LurkDnsblOptions {
+            zones: zones.into_iter().map(Into::into).collect(),
+            action,
+            cache_ttl,
+        }
+    }
+}
+
+struct LurkDnsblEntry {
+    listed_in: Option<String>,
+    expires: Instant,
+}
+
+/// Looks up destination IPs in DNS blocklists before connecting to them.
+/// Results are cached, so repeated destinations don't wait for DNS.
+pub struct LurkDnsbl {
+    options: LurkDnsblOptions,
+    cache: Mutex<HashMap<IpAddr, LurkDnsblEntry>>,
+}
+
+impl LurkDnsbl {
+    /// Lookup in the zone that hasn't answered within this period is considered negative.
+    const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Expired results are purged once there are this many cached ones.
+    const CACHE_CAPACITY: usize = 16384;
+
+    pub fn new(options: LurkDnsblOptions) -> LurkDnsbl {
+        LurkDnsbl {
+            options,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn action(&self) -> LurkDnsblAction {
+        self.options.action
+    }
+
+    /// Zone the destination IP is listed in, if any. Non-public addresses aren't looked up,
+    /// so internal destinations aren't disclosed to the zone operators.
+    pub async fn listed_in(&self, ip: IpAddr) -> Option<String> {
+        if !is_public(ip) {
+            return None;
+        }
+
+        if let Some(entry) = self.cache().get(&ip).filter(|entry| entry.expires > Instant::now()) {
+            return entry.listed_in.clone();
+        }
+
+        let mut listed_in = None;
+        for zone in &self.options.zones {
+            if self.lookup(ip, zone).await {
+                listed_in = Some(zone.clone());
+                break;
+            }
+        }
+
+        let mut cache = self.cache();
+        if cache.len() >= Self::CACHE_CAPACITY {
+            let now = Instant::now();
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() < Self::CACHE_CAPACITY {
+            let expires = Instant::now() + self.options.cache_ttl;
+            cache.insert(
+                ip,
+                LurkDnsblEntry {
+                    listed_in: listed_in.clone(),
+                    expires,
+                },
+            );
+        }
+
+        listed_in
+    }
+
+    async fn lookup(&self, ip: IpAddr, zone: &str) -> bool {
+        let name = query_name(ip, zone);
+        let replies = timeout(Self::LOOKUP_TIMEOUT, lookup_host((name.as_str(), 0))).await;
+        match replies {
+            Ok(Ok(mut replies)) => replies.any(|reply| is_listing_reply(reply.ip())),
+            // NXDOMAIN means the address isn't listed.
+            Ok(Err(err)) => {
+                debug!("{} is not listed: {}", name, err);
+                false
+            }
+            Err(_) => {
+                warn!("DNSBL lookup of {} has timed out", name);
+                false
+            }
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<IpAddr, LurkDnsblEntry>> {
+        self.cache.lock().expect("DNSBL cache lock is poisoned")
+    }
+}
+
+/// Name queried to look up the IP in the zone: octets (nibbles for IPv6)
+/// in reverse order prepended to the zone, e.g. "4.3.2.1.zen.spamhaus.org".
+fn query_name(ip: IpAddr, zone: &str) -> String {
+    let mut name = String::new();
+    match ip {
+        IpAddr::V4(ip) => ip.octets().iter().rev().for_each(|octet| {
+            let _ = write!(name, "{}.", octet);
+        }),
+        IpAddr::V6(ip) => ip.octets().iter().rev().for_each(|octet| {
+            let _ = write!(name, "{:x}.{:x}.", octet & 0x0f, octet >> 4);
+        }),
+    }
+    name.push_str(zone);
+    name
+}
+
+/// Listed addresses are answered with 127.0.0.0/8, except for 127.255.255.0/24
+/// which reports errors (e.g. queries through public resolvers are refused).
+/// Anything else is likely a resolver substituting NXDOMAIN answers.
+fn is_listing_reply(reply: IpAddr) -> bool {
+    match reply {
+        IpAddr::V4(reply) => reply.is_loopback() && reply.octets()[..3] != [127, 255, 255],
+        IpAddr::V6(_) => false,
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()),
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            // Unique local (fc00::/7) and link-local (fe80::/10) addresses are skipped too.
+            !(ip.is_loopback() || ip.is_unspecified() || (segment & 0xfe00) == 0xfc00 || (segment & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dnsbl_queries() {
+        assert_eq!(
+            "4.3.2.1.zen.spamhaus.org",
+            query_name("1.2.3.4".parse().unwrap(), "zen.spamhaus.org")
+        );
+        assert_eq!(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.dnsbl.example",
+            query_name("2001:db8::1".parse().unwrap(), "dnsbl.example")
+        );
+
+        assert!(is_listing_reply("127.0.0.2".parse().unwrap()));
+        assert!(is_listing_reply("127.0.0.10".parse().unwrap()));
+        assert!(!is_listing_reply("127.255.255.254".parse().unwrap()));
+        assert!(!is_listing_reply("198.51.100.1".parse().unwrap()));
+
+        assert!(is_public("198.51.100.1".parse().unwrap()));
+        assert!(!is_public("10.1.2.3".parse().unwrap()));
+        assert!(!is_public("127.0.0.1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+        assert!(is_public("2001:db8::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn skip_internal_destinations() {
+        let dnsbl = LurkDnsbl::new(LurkDnsblOptions::new(
+            ["dnsbl.invalid"],
+            LurkDnsblAction::Block,
+            Duration::from_secs(60),
+        ));
+        assert_eq!(None, dnsbl.listed_in("192.168.1.1".parse().unwrap()).await);
+        assert!(dnsbl.cache().is_empty());
+    }
+}
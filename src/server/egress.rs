@@ -0,0 +1,257 @@
+use chrono::Utc;
+use clap::ValueEnum;
+use log::warn;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// Consecutive connection failures of the egress IP after which it's considered unhealthy.
+const MAX_FAILURES: u32 = 3;
+
+/// Unhealthy egress IP is given another chance once this period is over.
+const UNHEALTHY_PERIOD: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct LurkEgressHealth {
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl LurkEgressHealth {
+    fn is_healthy(&self) -> bool {
+        self.failures < MAX_FAILURES || self.last_failure.is_some_and(|ts| ts.elapsed() >= UNHEALTHY_PERIOD)
+    }
+}
+
+/// How clients are rotated over the egress IPs.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkEgressRotation {
+    /// Client keeps exiting from the same IP while it's healthy
+    #[default]
+    Sticky,
+    /// Every connection exits from the next IP
+    RoundRobin,
+    /// Client sticks to the IP within the period and is likely to move to another one once it's over
+    Periodic,
+}
+
+/// Spreads clients over several egress IPs of the host. By default, a given client
+/// (user or client IP) keeps exiting from the same address while it's healthy.
+///
+/// IPs are ranked for every client with rendezvous hashing: once IP becomes unhealthy,
+/// only its clients move to their next IP, the rest of clients stay where they are.
+/// Periodic rotation ranks IPs for the client along with the current period, so clients
+/// are reshuffled at once when the period is over, e.g. on all nodes of the fleet.
+pub struct LurkEgressBalancer {
+    ips: Vec<IpAddr>,
+    health: Mutex<HashMap<IpAddr, LurkEgressHealth>>,
+    rotation: LurkEgressRotation,
+    period: Duration,
+    next: AtomicUsize,
+}
+
+impl LurkEgressBalancer {
+    pub const DEFAULT_ROTATION_PERIOD: Duration = Duration::from_secs(300);
+
+    pub fn new(ips: impl IntoIterator<Item = IpAddr>) -> LurkEgressBalancer {
+        let ips: Vec<IpAddr> = ips.into_iter().collect();
+        let health = ips.iter().map(|ip| (*ip, LurkEgressHealth::default())).collect();
+
+        LurkEgressBalancer {
+            ips,
+            health: Mutex::new(health),
+            rotation: LurkEgressRotation::Sticky,
+            period: LurkEgressBalancer::DEFAULT_ROTATION_PERIOD,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Rotate clients over the IPs with passed policy. Period is used by the periodic rotation only.
+    pub fn with_rotation(mut self, rotation: LurkEgressRotation, period: Duration) -> LurkEgressBalancer {
+        debug_assert!(!period.is_zero(), "rotation period should be positive");
+        self.rotation = rotation;
+        self.period = period.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Egress IP for the next connection of the client with passed key. Unhealthy IPs are
+    /// skipped unless all of them are unhealthy.
+    pub fn pick(&self, key: &str) -> Option<IpAddr> {
+        match self.rotation {
+            LurkEgressRotation::Sticky => self.pick_ranked(key),
+            LurkEgressRotation::RoundRobin => self.pick_next(),
+            LurkEgressRotation::Periodic => {
+                let period = Utc::now().timestamp().max(0) as u64 / self.period.as_secs();
+                self.pick_ranked(&format!("{}#{}", key, period))
+            }
+        }
+    }
+
+    /// The highest ranked healthy IP, or the highest ranked overall if all of them are unhealthy.
+    fn pick_ranked(&self, key: &str) -> Option<IpAddr> {
+        let health = self.health();
+        let is_healthy = |ip: &IpAddr| health[ip].is_healthy();
+
+        let ranked = || self.ips.iter().copied().map(|ip| (rank(key, ip), ip));
+        ranked()
+            .filter(|(_, ip)| is_healthy(ip))
+            .max()
+            .or_else(|| ranked().max())
+            .map(|(_, ip)| ip)
+    }
+
+    /// The next healthy IP after the previously picked one, or the next one if all of them are unhealthy.
+    fn pick_next(&self) -> Option<IpAddr> {
+        if self.ips.is_empty() {
+            return None;
+        }
+
+        let health = self.health();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let ip = (0..self.ips.len())
+            .map(|offset| self.ips[(start + offset) % self.ips.len()])
+            .find(|ip| health[ip].is_healthy())
+            .unwrap_or(self.ips[start % self.ips.len()]);
+
+        Some(ip)
+    }
+
+    /// Account result of the connection established from the egress IP.
+    pub fn on_connect(&self, ip: IpAddr, succeeded: bool) {
+        let mut health = self.health();
+        let Some(ip_health) = health.get_mut(&ip) else {
+            return;
+        };
+
+        if succeeded {
+            *ip_health = LurkEgressHealth::default();
+        } else {
+            ip_health.failures += 1;
+            ip_health.last_failure = Some(Instant::now());
+            if ip_health.failures == MAX_FAILURES {
+                warn!(
+                    "Egress IP {} has failed {} connections in a row, moving its clients away",
+                    ip, MAX_FAILURES
+                );
+            }
+        }
+    }
+
+    fn health(&self) -> MutexGuard<'_, HashMap<IpAddr, LurkEgressHealth>> {
+        self.health.lock().expect("Egress health lock is poisoned")
+    }
+}
+
+/// Weight of the egress IP for the client: FNV-1a of both, finalized to spread
+/// similar inputs (e.g. adjacent IPs) over the whole range.
+fn rank(key: &str, ip: IpAddr) -> u64 {
+    let ip_octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes().chain([0]).chain(ip_octets) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sticky_pick() {
+        let ips: Vec<IpAddr> = (1..=4).map(|i| format!("10.0.0.{i}").parse().unwrap()).collect();
+        let balancer = LurkEgressBalancer::new(ips.clone());
+        let clients: Vec<String> = (0..64).map(|i| format!("client{i}")).collect();
+
+        let picked: Vec<IpAddr> = clients.iter().map(|key| balancer.pick(key).unwrap()).collect();
+        assert_eq!(picked, clients.iter().map(|key| balancer.pick(key).unwrap()).collect::<Vec<_>>());
+        assert!(ips.iter().all(|ip| picked.contains(ip)), "clients should be spread over all IPs");
+
+        // Clients of the failed IP move away, the rest stay.
+        for _ in 0..MAX_FAILURES {
+            balancer.on_connect(ips[0], false);
+        }
+        for (key, before) in clients.iter().zip(&picked) {
+            let after = balancer.pick(key).unwrap();
+            assert_ne!(ips[0], after);
+            if *before != ips[0] {
+                assert_eq!(*before, after);
+            }
+        }
+
+        // Successful connection makes IP healthy again.
+        balancer.on_connect(ips[0], true);
+        assert_eq!(picked, clients.iter().map(|key| balancer.pick(key).unwrap()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn all_unhealthy() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let balancer = LurkEgressBalancer::new([ip]);
+        for _ in 0..MAX_FAILURES {
+            balancer.on_connect(ip, false);
+        }
+
+        assert_eq!(Some(ip), balancer.pick("alice"));
+        assert_eq!(None, LurkEgressBalancer::new([]).pick("alice"));
+    }
+
+    #[test]
+    fn round_robin_pick() {
+        let ips: Vec<IpAddr> = (1..=3).map(|i| format!("10.0.0.{i}").parse().unwrap()).collect();
+        let balancer = LurkEgressBalancer::new(ips.clone()).with_rotation(LurkEgressRotation::RoundRobin, Duration::from_secs(1));
+
+        // The same client exits from every IP in turn.
+        let picked: Vec<IpAddr> = (0..6).map(|_| balancer.pick("alice").unwrap()).collect();
+        assert_eq!([&ips[..], &ips[..]].concat(), picked);
+
+        // Unhealthy IP is skipped.
+        for _ in 0..MAX_FAILURES {
+            balancer.on_connect(ips[1], false);
+        }
+        let picked: Vec<IpAddr> = (0..3).map(|_| balancer.pick("alice").unwrap()).collect();
+        assert_eq!(vec![ips[0], ips[2], ips[2]], picked);
+    }
+
+    #[test]
+    fn periodic_pick() {
+        let ips: Vec<IpAddr> = (1..=8).map(|i| format!("10.0.0.{i}").parse().unwrap()).collect();
+        let period = Duration::from_secs(3600);
+        let balancer = LurkEgressBalancer::new(ips).with_rotation(LurkEgressRotation::Periodic, period);
+        let clients: Vec<String> = (0..64).map(|i| format!("client{i}")).collect();
+
+        // Clients are sticky within the period, ranked by the key along with the period.
+        let current = Utc::now().timestamp() as u64 / period.as_secs();
+        let picked: Vec<IpAddr> = clients.iter().map(|key| balancer.pick(key).unwrap()).collect();
+        let expected: Vec<IpAddr> = clients
+            .iter()
+            .map(|key| balancer.pick_ranked(&format!("{key}#{current}")).unwrap())
+            .collect();
+        if Utc::now().timestamp() as u64 / period.as_secs() == current {
+            assert_eq!(expected, picked);
+        }
+
+        // Most of the clients move once the period is over.
+        let moved = clients
+            .iter()
+            .zip(&picked)
+            .filter(|(key, ip)| balancer.pick_ranked(&format!("{}#{}", key, current + 1)).unwrap() != **ip)
+            .count();
+        assert!(moved > clients.len() / 2, "only {moved} clients have moved");
+    }
+}
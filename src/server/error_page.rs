@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+use hyper::StatusCode;
+use std::path::Path;
+
+/// HTML page served instead of the empty body when the proxy refuses the request.
+///
+/// Template may contain the following placeholders:
+/// * ```{{status}}``` - numeric status code, e.g. "502"
+/// * ```{{title}}``` - canonical reason of the status, e.g. "Bad Gateway"
+/// * ```{{reason}}``` - why the request was refused
+/// * ```{{contact}}``` - contact info of the proxy administrators
+///
+/// Substituted values are HTML-escaped, unknown placeholders are kept as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkErrorPage {
+    template: String,
+    contact: String,
+}
+
+impl LurkErrorPage {
+    pub const CONTENT_TYPE: &'static str = "text/html; charset=utf-8";
+
+    pub fn new(template: impl Into<String>, contact: impl Into<String>) -> LurkErrorPage {
+        LurkErrorPage {
+            template: template.into(),
+            contact: contact.into(),
+        }
+    }
+
+    pub fn from_file(path: &Path, contact: impl Into<String>) -> Result<LurkErrorPage> {
+        let template = std::fs::read_to_string(path).with_context(|| format!("unable to read error page template {}", path.display()))?;
+        Ok(LurkErrorPage::new(template, contact))
+    }
+
+    pub fn render(&self, status: StatusCode, reason: &str) -> String {
+        let mut page = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+
+        // Single pass, so placeholders coming with substituted values are not expanded.
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}").map(|end| start + end) else {
+                break;
+            };
+
+            page.push_str(&rest[..start]);
+            match rest[start + 2..end].trim() {
+                "status" => page.push_str(status.as_str()),
+                "title" => push_escaped(&mut page, status.canonical_reason().unwrap_or_default()),
+                "reason" => push_escaped(&mut page, reason),
+                "contact" => push_escaped(&mut page, &self.contact),
+                _ => page.push_str(&rest[start..end + 2]),
+            }
+            rest = &rest[end + 2..];
+        }
+
+        page.push_str(rest);
+        page
+    }
+}
+
+fn push_escaped(page: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => page.push_str("&amp;"),
+            '<' => page.push_str("&lt;"),
+            '>' => page.push_str("&gt;"),
+            '"' => page.push_str("&quot;"),
+            '\'' => page.push_str("&#39;"),
+            c => page.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let page = LurkErrorPage::new(
+            "<h1>{{status}} {{ title }}</h1><p>{{reason}}</p><p>{{contact}}</p>{{unknown}} {{",
+            "it@example.com",
+        );
+
+        assert_eq!(
+            "<h1>403 Forbidden</h1><p>&lt;script&gt; {{contact}}</p><p>it@example.com</p>{{unknown}} {{",
+            page.render(StatusCode::FORBIDDEN, "<script> {{contact}}")
+        );
+    }
+}
@@ -1,15 +1,784 @@
-use crate::net::tcp::connection::{LurkTcpConnectionHandler, LurkTcpConnectionLabel};
-use anyhow::{bail, Result};
-use http::LurkHttpHandler;
-use socks5::LurkSocks5Handler;
+#[cfg(feature = "compression")]
+use super::compression::LurkCompression;
+use super::{
+    blocklist::LurkBlocklist,
+    dnsbl::{LurkDnsbl, LurkDnsblAction},
+    egress::LurkEgressBalancer,
+    error_page::LurkErrorPage,
+    policy::{LurkPolicy, LurkPolicyAction},
+    pool::LurkWarmPool,
+    recordings::LurkRecordings,
+    routing::{LurkRoute, LurkRouting},
+    ssrf::LurkSsrfGuard,
+    stats::LurkServerStats,
+};
+use crate::auth::{private::LurkPrivateAuthMethod, users::LurkUserStore, LurkAuthMethod, LurkAuthenticator, LurkOfferedAuthMethods};
+use crate::common::error::{LurkDenyReason, LurkDenySource, LurkError};
+use crate::io::{
+    mirror::LurkTunnelMirror,
+    tunnel::{LurkTunnel, LurkTunnelActivity},
+};
+use crate::net::{
+    geoip::LurkCountryAccess,
+    socks5::LurkUpstreamProxy,
+    tcp::{
+        self,
+        connection::{LurkTcpConnectionHandler, LurkTcpConnectionLabel},
+        LurkOutboundStream, TcpConnectionOptions,
+    },
+    Address, LurkResolvePolicy,
+};
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use log::warn;
+use std::{
+    collections::HashSet,
+    future::{pending, Future},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{interval, sleep},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-mod http;
+#[cfg(feature = "http")]
+pub(crate) mod http;
+#[cfg(feature = "socks4")]
+mod socks4;
+#[cfg(feature = "socks5")]
 mod socks5;
+#[cfg(feature = "transparent")]
+mod transparent;
 
-pub fn create_tcp_connection_handler(label: &LurkTcpConnectionLabel) -> Result<Box<dyn LurkTcpConnectionHandler>> {
-    match label {
-        LurkTcpConnectionLabel::Http => Ok(Box::new(LurkHttpHandler {})),
-        LurkTcpConnectionLabel::Socks5 => Ok(Box::new(LurkSocks5Handler {})),
-        LurkTcpConnectionLabel::Unknown(_) => bail!("Unknown TCP connection"),
+/// Address reported to SOCKS clients in the successful reply to CONNECT (BND.ADDR and BND.PORT).
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum LurkReplyBoundAddress {
+    /// Local address of the connection with the destination
+    #[default]
+    Outbound,
+    /// Local address of the connection with the client, e.g. when the outbound one is a private address behind NAT
+    Inbound,
+}
+
+/// Server-wide settings and state shared with connection handlers.
+/// Parts of it are left unused once some of the handlers aren't compiled in.
+#[cfg_attr(not(all(feature = "http", feature = "socks5")), allow(dead_code))]
+pub struct LurkHandlerContext {
+    stats: Arc<LurkServerStats>,
+    response_write_timeout: Duration,
+    users: Option<Arc<LurkUserStore>>,
+    private_auth_methods: Vec<Arc<dyn LurkPrivateAuthMethod>>,
+    offered_auth_methods: Option<Arc<LurkOfferedAuthMethods>>,
+    warm_pool: Option<Arc<LurkWarmPool>>,
+    keep_hop_by_hop_headers: bool,
+    error_page: Option<Arc<LurkErrorPage>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Arc<LurkCompression>>,
+    egress_balancer: Option<Arc<LurkEgressBalancer>>,
+    blocklist: Option<Arc<LurkBlocklist>>,
+    policy: Option<Arc<LurkPolicy>>,
+    dnsbl: Option<Arc<LurkDnsbl>>,
+    recordings: Option<Arc<LurkRecordings>>,
+    outbound_tcp_options: TcpConnectionOptions,
+    min_read_rate: Option<(u64, Duration)>,
+    tunnel_rate_limit: Option<u64>,
+    max_tunnel_lifetime: Option<Duration>,
+    reply_bound_address: LurkReplyBoundAddress,
+    resolve_policy: LurkResolvePolicy,
+    upstream_proxy: Option<Arc<LurkUpstreamProxy>>,
+    routing: Option<Arc<LurkRouting>>,
+    ssrf_guard: Option<Arc<LurkSsrfGuard>>,
+    destination_countries: Option<Arc<LurkCountryAccess>>,
+    task_tracker: TaskTracker,
+    task_cancellation_token: CancellationToken,
+}
+
+#[cfg_attr(not(all(feature = "http", feature = "socks5")), allow(dead_code))]
+impl LurkHandlerContext {
+    /// How often bytes relayed by the authenticated user are charged to its quota.
+    pub const QUOTA_CHARGE_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(stats: Arc<LurkServerStats>, response_write_timeout: Duration) -> LurkHandlerContext {
+        LurkHandlerContext {
+            stats,
+            response_write_timeout,
+            users: None,
+            private_auth_methods: Vec::new(),
+            offered_auth_methods: None,
+            warm_pool: None,
+            keep_hop_by_hop_headers: false,
+            error_page: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            egress_balancer: None,
+            blocklist: None,
+            policy: None,
+            dnsbl: None,
+            recordings: None,
+            outbound_tcp_options: tcp::default_tcp_options(),
+            min_read_rate: None,
+            tunnel_rate_limit: None,
+            max_tunnel_lifetime: None,
+            reply_bound_address: LurkReplyBoundAddress::default(),
+            resolve_policy: LurkResolvePolicy::default(),
+            upstream_proxy: None,
+            routing: None,
+            ssrf_guard: None,
+            destination_countries: None,
+            task_tracker: TaskTracker::new(),
+            task_cancellation_token: CancellationToken::new(),
+        }
+    }
+
+    /// Require clients to authenticate as one of the users.
+    pub fn with_users(mut self, users: Arc<LurkUserStore>) -> LurkHandlerContext {
+        self.users = Some(users);
+        self
+    }
+
+    /// Offer private SOCKS5 authentication methods to the clients.
+    pub fn with_private_auth_methods(mut self, methods: Vec<Arc<dyn LurkPrivateAuthMethod>>) -> LurkHandlerContext {
+        self.private_auth_methods = methods;
+        self
+    }
+
+    /// Offer built-in authentication methods, which could be switched at runtime.
+    pub fn with_offered_auth_methods(mut self, methods: Arc<LurkOfferedAuthMethods>) -> LurkHandlerContext {
+        self.offered_auth_methods = Some(methods);
+        self
+    }
+
+    /// Take connections to the popular destinations from the pool.
+    pub fn with_warm_pool(mut self, warm_pool: Arc<LurkWarmPool>) -> LurkHandlerContext {
+        self.warm_pool = Some(warm_pool);
+        self
+    }
+
+    /// Relay hop-by-hop HTTP headers verbatim, as it was done before they were stripped.
+    pub fn with_hop_by_hop_headers_kept(mut self) -> LurkHandlerContext {
+        self.keep_hop_by_hop_headers = true;
+        self
+    }
+
+    /// Describe refused HTTP requests with the page rendered from the template.
+    pub fn with_error_page(mut self, error_page: Arc<LurkErrorPage>) -> LurkHandlerContext {
+        self.error_page = Some(error_page);
+        self
+    }
+
+    /// Compress forwarded HTTP responses for the clients accepting compressed content.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, compression: Arc<LurkCompression>) -> LurkHandlerContext {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Establish connections with the destinations with these options (e.g. TCP Fast Open, MPTCP).
+    pub fn with_outbound_tcp_options(mut self, tcp_options: TcpConnectionOptions) -> LurkHandlerContext {
+        self.outbound_tcp_options = tcp_options;
+        self
+    }
+
+    /// Close tunnels, which clients read slower than ```bytes_per_sec``` within the ```window```
+    /// while the destinations keep sending.
+    pub fn with_min_read_rate(mut self, bytes_per_sec: u64, window: Duration) -> LurkHandlerContext {
+        self.min_read_rate = Some((bytes_per_sec, window));
+        self
+    }
+
+    /// Relay at most ```bytes_per_sec``` in each direction of every tunnel, unless user or routing rule has its own limit.
+    pub fn with_tunnel_rate_limit(mut self, bytes_per_sec: u64) -> LurkHandlerContext {
+        self.tunnel_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Close tunnels, which have been open for longer than ```max_lifetime```.
+    pub fn with_max_tunnel_lifetime(mut self, max_lifetime: Duration) -> LurkHandlerContext {
+        self.max_tunnel_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Report this address to SOCKS clients once the destination is connected.
+    pub fn with_reply_bound_address(mut self, reply_bound_address: LurkReplyBoundAddress) -> LurkHandlerContext {
+        self.reply_bound_address = reply_bound_address;
+        self
+    }
+
+    /// Resolve domain names of the destinations with this policy.
+    pub fn with_resolve_policy(mut self, resolve_policy: LurkResolvePolicy) -> LurkHandlerContext {
+        self.resolve_policy = resolve_policy;
+        self
+    }
+
+    /// Connect to the destinations through the parent SOCKS5 proxy instead of connecting directly.
+    pub fn with_upstream_proxy(mut self, upstream_proxy: Arc<LurkUpstreamProxy>) -> LurkHandlerContext {
+        self.upstream_proxy = Some(upstream_proxy);
+        self
+    }
+
+    /// Route the destinations by the rules, falling back to the upstream proxy settings for unmatched ones.
+    pub fn with_routing(mut self, routing: Arc<LurkRouting>) -> LurkHandlerContext {
+        self.routing = Some(routing);
+        self
+    }
+
+    /// Refuse destinations resolved into special-purpose addresses.
+    pub fn with_ssrf_guard(mut self, ssrf_guard: Arc<LurkSsrfGuard>) -> LurkHandlerContext {
+        self.ssrf_guard = Some(ssrf_guard);
+        self
+    }
+
+    /// Allow or deny connections to the destinations by the countries they are located in.
+    pub fn with_destination_countries(mut self, countries: Arc<LurkCountryAccess>) -> LurkHandlerContext {
+        self.destination_countries = Some(countries);
+        self
+    }
+
+    /// Spread clients over several egress IPs.
+    pub fn with_egress_balancer(mut self, egress_balancer: Arc<LurkEgressBalancer>) -> LurkHandlerContext {
+        self.egress_balancer = Some(egress_balancer);
+        self
+    }
+
+    /// Refuse connections to the blocklisted destinations.
+    pub fn with_blocklist(mut self, blocklist: Arc<LurkBlocklist>) -> LurkHandlerContext {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Allow or deny connections to the destinations by the rules of the policy.
+    pub fn with_policy(mut self, policy: Arc<LurkPolicy>) -> LurkHandlerContext {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Look destination IPs up in DNS blocklists before connecting to them.
+    pub fn with_dnsbl(mut self, dnsbl: Arc<LurkDnsbl>) -> LurkHandlerContext {
+        self.dnsbl = Some(dnsbl);
+        self
+    }
+
+    /// Record tunnels selected by the policy or through the HTTP endpoint.
+    pub fn with_recordings(mut self, recordings: Arc<LurkRecordings>) -> LurkHandlerContext {
+        self.recordings = Some(recordings);
+        self
+    }
+
+    /// Run tunnels outliving their connection handlers (e.g. upgraded HTTP connections) on the server
+    /// tracker, so server waits for them on shutdown and cancels them along with the other tasks.
+    pub fn with_task_tracker(mut self, task_tracker: TaskTracker, task_cancellation_token: CancellationToken) -> LurkHandlerContext {
+        self.task_tracker = task_tracker;
+        self.task_cancellation_token = task_cancellation_token;
+        self
+    }
+
+    pub fn stats(&self) -> &LurkServerStats {
+        &self.stats
+    }
+
+    /// Maximum time given to the peer to accept protocol response.
+    pub fn response_write_timeout(&self) -> Duration {
+        self.response_write_timeout
+    }
+
+    /// Rate (bytes/sec) the tunnel of the user to the destination is limited to in each direction. Limits of the user
+    /// and of the routing rule matching the destination override the default one, the lower of them applies if both are set.
+    pub fn tunnel_rate_limit(&self, address: &Address, user: Option<&str>) -> Option<u64> {
+        let rule_limit = self.routing.as_ref().and_then(|routing| routing.rate_limit(address));
+        let user_limit = user.zip(self.users()).and_then(|(user, users)| users.rate_limit(user));
+        match (rule_limit, user_limit) {
+            (Some(rule_limit), Some(user_limit)) => Some(rule_limit.min(user_limit)),
+            (rule_limit, user_limit) => rule_limit.or(user_limit).or(self.tunnel_rate_limit),
+        }
+    }
+
+    /// Set the tunnel between ```peer_addr``` and the destination up the same way for every handler: mirror it, close it
+    /// once the client reads too slowly or it's open for too long, and limit its rate for the user and the destination.
+    pub fn configure_tunnel<'a, X, Y>(
+        &self,
+        mut tunnel: LurkTunnel<'a, X, Y>,
+        peer_addr: SocketAddr,
+        address: &Address,
+        user: Option<&str>,
+    ) -> LurkTunnel<'a, X, Y>
+    where
+        X: AsyncRead + AsyncWrite + Unpin,
+        Y: AsyncRead + AsyncWrite + Unpin,
+    {
+        for mirror in self.tunnel_mirrors(peer_addr, address) {
+            tunnel = tunnel.with_mirror(mirror);
+        }
+        if let Some((bytes_per_sec, window)) = self.min_read_rate {
+            tunnel = tunnel.with_min_read_rate(bytes_per_sec, window);
+        }
+        if let Some(max_lifetime) = self.max_tunnel_lifetime {
+            tunnel = tunnel.with_max_lifetime(max_lifetime);
+        }
+        if let Some(bytes_per_sec) = self.tunnel_rate_limit(address, user) {
+            tunnel = tunnel.with_rate_limit(bytes_per_sec);
+        }
+        tunnel
+    }
+
+    /// Account the tunnel failure, which is counted if the proxy has closed the tunnel by one of its limits.
+    pub fn on_tunnel_error(&self, err: &anyhow::Error) {
+        match err.downcast_ref::<LurkError>() {
+            Some(LurkError::TunnelSlowRead(_)) => self.stats.on_slow_read_closure(),
+            Some(LurkError::TunnelLifetimeExceeded(_)) => self.stats.on_lifetime_closure(),
+            _ => {}
+        }
+    }
+
+    /// Run the tunnel. Bytes relayed on behalf of the authenticated user are charged
+    /// to its quota on the fly, so the session can be closed once quota is exceeded.
+    /// Session is closed as well once it's open for longer than the class of the user allows.
+    pub async fn run_tunnel<X, Y>(
+        &self,
+        tunnel: &mut LurkTunnel<'_, X, Y>,
+        activity: &LurkTunnelActivity,
+        user: Option<&str>,
+    ) -> Result<(u64, u64)>
+    where
+        X: AsyncRead + AsyncWrite + Unpin,
+        Y: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (Some(users), Some(user)) = (self.users(), user) else {
+            return tunnel.run().await;
+        };
+
+        // Activity could be shared with the whole connection, only bytes relayed by the tunnel are charged.
+        let mut charged_bytes = activity.l2r_bytes() + activity.r2l_bytes();
+        let mut charge = || {
+            let relayed_bytes = activity.l2r_bytes() + activity.r2l_bytes();
+            users.charge(user, relayed_bytes - charged_bytes);
+            charged_bytes = relayed_bytes;
+        };
+
+        let max_duration = users.max_session_duration(user);
+        let expired = async {
+            match max_duration {
+                Some(max_duration) => sleep(max_duration).await,
+                None => pending().await,
+            }
+        };
+
+        let mut ticker = interval(LurkHandlerContext::QUOTA_CHARGE_INTERVAL);
+        let tunnel_run = tunnel.run();
+        tokio::pin!(tunnel_run, expired);
+
+        loop {
+            tokio::select! {
+                res = &mut tunnel_run => {
+                    charge();
+                    return res;
+                },
+                _ = &mut expired => {
+                    charge();
+                    bail!(LurkError::SessionDurationExceeded(user.to_owned(), max_duration.unwrap_or_default()))
+                },
+                _ = ticker.tick() => {
+                    charge();
+                    if users.close_active() {
+                        users.check_quota(user)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Spawn the tunnel on the server tracker. It's dropped once the server is shut down.
+    pub(crate) fn spawn_tunnel<F>(&self, tunnel: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let token = self.task_cancellation_token.clone();
+        self.task_tracker.spawn(async move {
+            tokio::select! {
+                _ = tunnel => {},
+                _ = token.cancelled() => {}
+            }
+        });
+    }
+
+    /// Address reported to SOCKS client, which has connected to ```inbound_addr```, once the destination
+    /// is connected with ```outbound_stream```. Inbound address is reported, if the outbound one is unknown.
+    pub fn reply_bound_address(&self, inbound_addr: SocketAddr, outbound_stream: &LurkOutboundStream) -> SocketAddr {
+        match self.reply_bound_address {
+            LurkReplyBoundAddress::Outbound => outbound_stream.local_addr().unwrap_or(inbound_addr),
+            LurkReplyBoundAddress::Inbound => inbound_addr,
+        }
+    }
+
+    pub fn resolve_policy(&self) -> LurkResolvePolicy {
+        self.resolve_policy
+    }
+
+    pub fn keep_hop_by_hop_headers(&self) -> bool {
+        self.keep_hop_by_hop_headers
+    }
+
+    pub fn error_page(&self) -> Option<&LurkErrorPage> {
+        self.error_page.as_deref()
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn compression(&self) -> Option<&LurkCompression> {
+        self.compression.as_deref()
+    }
+
+    /// Why clients are not allowed to connect to the destination host:
+    /// it's either denied by the policy or blocklisted. Denials are counted per rule.
+    pub fn deny_reason(&self, host: &str) -> Option<LurkDenyReason> {
+        let reason = self
+            .policy
+            .as_ref()
+            .and_then(|policy| policy.deny_reason(host))
+            .or_else(|| self.blocklist.as_ref()?.deny_reason(host))?;
+
+        self.stats.on_destination_denied(&reason);
+        Some(reason)
+    }
+
+    /// Mirrors of the tunnel between ```peer_addr``` and the destination: the one required
+    /// by the policy, and the recording if it's requested by the policy or enabled for the client.
+    pub fn tunnel_mirrors(&self, peer_addr: SocketAddr, address: &Address) -> Vec<LurkTunnelMirror> {
+        let host = address.host();
+        let mut mirrors = Vec::new();
+
+        if let Some(target) = self.policy.as_ref().and_then(|policy| policy.mirror_target(&host)) {
+            mirrors.push(LurkTunnelMirror::start(target, peer_addr, &address.to_string()));
+        }
+
+        let recorded_by_policy = self
+            .policy
+            .as_ref()
+            .is_some_and(|policy| policy.evaluate(&host) == LurkPolicyAction::Record);
+        match &self.recordings {
+            Some(recordings) if recorded_by_policy || recordings.is_enabled_for(peer_addr.ip()) => {
+                mirrors.push(recordings.record(peer_addr, &address.to_string()));
+            }
+            None if recorded_by_policy => warn!("Tunnel to {} is not recorded: recordings directory isn't set", address),
+            _ => {}
+        }
+
+        mirrors
+    }
+
+    pub fn users(&self) -> Option<&LurkUserStore> {
+        self.users.as_deref()
+    }
+
+    /// Routing rules the destinations are routed by, if there are any.
+    pub fn routing(&self) -> Option<&LurkRouting> {
+        self.routing.as_deref()
+    }
+
+    pub fn private_auth_methods(&self) -> &[Arc<dyn LurkPrivateAuthMethod>] {
+        &self.private_auth_methods
+    }
+
+    /// Built-in authentication methods offered right now, if they are switchable.
+    pub fn offered_auth_methods(&self) -> Option<Vec<LurkAuthMethod>> {
+        self.offered_auth_methods.as_ref().map(|methods| methods.get())
+    }
+
+    /// Client of the protocol without authentication (e.g. SOCKS4) is allowed,
+    /// if "no authentication" method would be selected for the SOCKS5 one.
+    pub fn is_unauthenticated_allowed(&self) -> bool {
+        let mut authenticator = LurkAuthenticator::new(self.users());
+        if let Some(methods) = self.offered_auth_methods() {
+            authenticator = authenticator.with_offered_methods(methods);
+        }
+
+        authenticator.select_auth_method(&HashSet::from([LurkAuthMethod::None])).is_some()
+    }
+
+    /// Establish TCP connection with the destination.
+    /// Connection pre-established by the warm pool is used if there is any,
+    /// unless the destination isn't connected to directly.
+    pub async fn connect(&self, address: &Address) -> Result<LurkOutboundStream> {
+        let pool = self.warm_pool.as_ref().filter(|_| matches!(self.route(address), LurkRoute::Direct));
+        if let Some(stream) = pool.and_then(|pool| pool.take(&address.to_string())) {
+            self.check_ip(address, stream.peer_addr()?.ip()).await?;
+            return Ok(stream.into());
+        }
+
+        self.reconnect(address).await
+    }
+
+    /// Establish TCP connection with the destination on behalf of the client.
+    /// Egress IP of the user is used if there is any, otherwise the balancer picks
+    /// the one for the client: by the username if it has authenticated, by IP if not.
+    pub async fn connect_for(&self, address: &Address, user: Option<&str>, peer_ip: IpAddr) -> Result<LurkOutboundStream> {
+        self.connect_on_behalf(address, user, peer_ip, false).await
+    }
+
+    /// Establish new TCP connection with the destination on behalf of the client bypassing the warm pool,
+    /// e.g. once the previous one has turned out to be dead. Egress IP is picked the same way as by ```connect_for```.
+    pub async fn reconnect_for(&self, address: &Address, user: Option<&str>, peer_ip: IpAddr) -> Result<LurkOutboundStream> {
+        self.connect_on_behalf(address, user, peer_ip, true).await
+    }
+
+    /// Establish TCP connection with the destination on behalf of the client, which could have picked the parent proxy
+    /// by the hint in its username. Destinations blocked by the routing rules are refused regardless of the hint.
+    pub async fn connect_hinted_for(
+        &self,
+        address: &Address,
+        user: Option<&str>,
+        route_hint: Option<&str>,
+        peer_ip: IpAddr,
+    ) -> Result<LurkOutboundStream> {
+        let hinted_route = route_hint.and_then(|hint| self.routing.as_ref()?.hinted_route(address, hint));
+        match hinted_route {
+            Some(LurkRoute::Upstream(upstream_proxy)) => {
+                let mut tcp_options = self.outbound_tcp_options.clone();
+                if let Some(egress_ip) = self.user_egress_ip(user) {
+                    tcp_options.set_local_ip(egress_ip);
+                }
+                self.connect_upstream(&upstream_proxy, address, &tcp_options).await
+            }
+            Some(LurkRoute::Block(reason)) => Err(self.blocked(address, reason)),
+            _ => self.connect_for(address, user, peer_ip).await,
+        }
+    }
+
+    async fn connect_on_behalf(
+        &self,
+        address: &Address,
+        user: Option<&str>,
+        peer_ip: IpAddr,
+        bypass_pool: bool,
+    ) -> Result<LurkOutboundStream> {
+        if let Some(egress_ip) = self.user_egress_ip(user) {
+            return self.connect_from(address, egress_ip).await;
+        }
+
+        let balanced = self.egress_balancer.as_ref().and_then(|balancer| {
+            let egress_ip = balancer.pick(&user.map_or_else(|| peer_ip.to_string(), str::to_owned))?;
+            Some((balancer, egress_ip))
+        });
+        let Some((balancer, egress_ip)) = balanced else {
+            return match bypass_pool {
+                true => self.reconnect(address).await,
+                false => self.connect(address).await,
+            };
+        };
+
+        let result = self.connect_from(address, egress_ip).await;
+        balancer.on_connect(egress_ip, result.is_ok());
+        result
+    }
+
+    /// Local IP datagrams of the client are sent from, picked the same way as the one its TCP connections are
+    /// established from. None stands for the default address of the host.
+    pub(crate) fn datagram_egress_ip(&self, user: Option<&str>, peer_ip: IpAddr) -> Option<IpAddr> {
+        self.user_egress_ip(user).or_else(|| {
+            self.egress_balancer
+                .as_ref()?
+                .pick(&user.map_or_else(|| peer_ip.to_string(), str::to_owned))
+        })
+    }
+
+    /// Egress IP assigned to the user, if there is any.
+    fn user_egress_ip(&self, user: Option<&str>) -> Option<IpAddr> {
+        self.users()?.get_user(user?)?.egress_ip()
+    }
+
+    /// Establish TCP connection with the destination from the given local address.
+    /// Pooled connections are established from the default one, so the pool is bypassed.
+    pub async fn connect_from(&self, address: &Address, local_ip: IpAddr) -> Result<LurkOutboundStream> {
+        let mut tcp_options = self.outbound_tcp_options.clone();
+        tcp_options.set_local_ip(local_ip);
+        self.dial(address, &tcp_options).await
+    }
+
+    /// Establish new TCP connection with the destination bypassing the warm pool,
+    /// e.g. once the pooled connection has turned out to be dead.
+    pub async fn reconnect(&self, address: &Address) -> Result<LurkOutboundStream> {
+        self.dial(address, &self.outbound_tcp_options).await
+    }
+
+    /// Establish FTP data connection to the ```port``` of the server the ```control``` connection to the ```target``` is
+    /// established with. It takes the route of the control connection, so it reaches the same server from the same egress IP:
+    /// directly to the IP the control connection is established with, or to the same host through the upstream proxy.
+    pub(crate) async fn connect_ftp_data(&self, target: &Address, control: &LurkOutboundStream, port: u16) -> Result<LurkOutboundStream> {
+        match self.route(target) {
+            LurkRoute::Direct => {
+                let mut tcp_options = self.outbound_tcp_options.clone();
+                tcp_options.set_local_ip(control.local_addr()?.ip());
+                let data_addr = SocketAddr::new(control.peer_addr()?.ip(), port);
+                Ok(tcp::establish_tcp_connection_with_opts(data_addr, &tcp_options).await?.into())
+            }
+            LurkRoute::Upstream(upstream_proxy) => {
+                let address = match target {
+                    Address::SocketAddress(addr) => Address::SocketAddress(SocketAddr::new(addr.ip(), port)),
+                    Address::DomainName(name, _) => Address::DomainName(name.clone(), port),
+                };
+                self.connect_upstream(&upstream_proxy, &address, &self.outbound_tcp_options).await
+            }
+            LurkRoute::Block(reason) => Err(self.blocked(target, reason)),
+        }
+    }
+
+    /// Resolve the destination datagrams are relayed to. Upstream proxies relay TCP connections only,
+    /// so datagrams to the destinations routed through them are refused, as well as to the blocked ones.
+    pub(crate) async fn resolve_datagram_target(&self, address: &Address) -> Result<SocketAddr> {
+        match self.route(address) {
+            LurkRoute::Direct => self.resolve(address).await,
+            LurkRoute::Upstream(_) => bail!(LurkError::DatagramsNotRoutable(address.to_string())),
+            LurkRoute::Block(reason) => Err(self.blocked(address, reason)),
+        }
+    }
+
+    /// Connect to the destination the way it's routed. Domain names of the destinations connected
+    /// through upstream proxies are resolved by them, so neither SSRF guard, countries nor DNS blocklists check them.
+    async fn dial(&self, address: &Address, tcp_options: &TcpConnectionOptions) -> Result<LurkOutboundStream> {
+        match self.route(address) {
+            LurkRoute::Direct => Ok(tcp::establish_tcp_connection_with_opts(self.resolve(address).await?, tcp_options)
+                .await?
+                .into()),
+            LurkRoute::Upstream(upstream_proxy) => self.connect_upstream(&upstream_proxy, address, tcp_options).await,
+            LurkRoute::Block(reason) => Err(self.blocked(address, reason)),
+        }
+    }
+
+    /// Connect to the destination through the upstream proxy. Proxies with TLS certificates
+    /// failing the verification are counted apart from the ones failing to connect.
+    async fn connect_upstream(
+        &self,
+        upstream_proxy: &LurkUpstreamProxy,
+        address: &Address,
+        tcp_options: &TcpConnectionOptions,
+    ) -> Result<LurkOutboundStream> {
+        let result = upstream_proxy.connect(address, tcp_options).await;
+        if let Err(err) = &result {
+            if let Some(LurkError::UpstreamTlsVerificationFailed(..)) = err.downcast_ref::<LurkError>() {
+                self.stats.on_upstream_tls_verification_failure();
+            }
+        }
+        result
+    }
+
+    /// Error refusing the destination blocked by the routing rule, which is counted as denied.
+    fn blocked(&self, address: &Address, reason: LurkDenyReason) -> anyhow::Error {
+        self.stats.on_destination_denied(&reason);
+        anyhow!(LurkError::DestinationBlocked(address.to_string(), reason))
+    }
+
+    /// Route of the first matching routing rule, otherwise the upstream proxy if it takes the destination.
+    fn route(&self, address: &Address) -> LurkRoute {
+        if let Some(route) = self.routing.as_ref().and_then(|routing| routing.route(address)) {
+            return route;
+        }
+        match &self.upstream_proxy {
+            Some(upstream_proxy) if upstream_proxy.routes(address) => LurkRoute::Upstream(Arc::clone(upstream_proxy)),
+            _ => LurkRoute::Direct,
+        }
+    }
+
+    /// Resolve destination address, which is checked by SSRF guard, its country and against DNS blocklists.
+    async fn resolve(&self, address: &Address) -> Result<SocketAddr> {
+        let socket_addr = address.to_socket_addr(self.resolve_policy).await?;
+        self.check_ip(address, socket_addr.ip()).await?;
+        Ok(socket_addr)
+    }
+
+    async fn check_ip(&self, address: &Address, ip: IpAddr) -> Result<()> {
+        if let Some(reason) = self.ssrf_guard.as_ref().and_then(|ssrf_guard| ssrf_guard.deny_reason(ip)) {
+            self.stats.on_destination_denied(&reason);
+            bail!(LurkError::DestinationBlocked(address.to_string(), reason))
+        }
+        if let Some(country) = self
+            .destination_countries
+            .as_ref()
+            .and_then(|countries| countries.denied_country(ip))
+        {
+            let reason = LurkDenyReason {
+                source: LurkDenySource::GeoIp,
+                rule: country,
+                pattern: ip.to_string(),
+            };
+            self.stats.on_destination_denied(&reason);
+            bail!(LurkError::DestinationBlocked(address.to_string(), reason))
+        }
+        self.check_dnsbl(address, ip).await
+    }
+
+    async fn check_dnsbl(&self, address: &Address, ip: IpAddr) -> Result<()> {
+        let Some(dnsbl) = &self.dnsbl else {
+            return Ok(());
+        };
+
+        match (dnsbl.listed_in(ip).await, dnsbl.action()) {
+            (Some(zone), LurkDnsblAction::Block) => {
+                let reason = LurkDenyReason {
+                    source: LurkDenySource::Dnsbl,
+                    rule: zone,
+                    pattern: ip.to_string(),
+                };
+                self.stats.on_destination_denied(&reason);
+                bail!(LurkError::DestinationBlocked(address.to_string(), reason))
+            }
+            (Some(zone), LurkDnsblAction::Flag) => {
+                warn!("Connecting to {} ({}), which is listed in {}", address, ip, zone);
+                Ok(())
+            }
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+/// Handlers of all supported traffic labels.
+///
+/// Handlers keep nothing but the shared context, so they are created once
+/// and every accepted connection is dispatched to one of them.
+///
+/// Handlers are compiled in by the cargo features of the same name ("socks5", "socks4", "http" and "transparent"),
+/// connections of the label without compiled handler are closed.
+#[derive(Clone)]
+pub struct LurkHandlers {
+    #[cfg(feature = "socks5")]
+    socks5: Arc<socks5::LurkSocks5Handler>,
+    #[cfg(feature = "socks4")]
+    socks4: Arc<socks4::LurkSocks4Handler>,
+    #[cfg(feature = "http")]
+    http: Arc<http::LurkHttpHandler>,
+    #[cfg(feature = "transparent")]
+    transparent: Arc<transparent::LurkTransparentHandler>,
+}
+
+impl LurkHandlers {
+    #[allow(unused_variables)]
+    pub fn new(context: Arc<LurkHandlerContext>) -> LurkHandlers {
+        LurkHandlers {
+            #[cfg(feature = "socks5")]
+            socks5: Arc::new(socks5::LurkSocks5Handler::new(Arc::clone(&context))),
+            #[cfg(feature = "socks4")]
+            socks4: Arc::new(socks4::LurkSocks4Handler::new(Arc::clone(&context))),
+            #[cfg(feature = "http")]
+            http: Arc::new(http::LurkHttpHandler::new(Arc::clone(&context))),
+            #[cfg(feature = "transparent")]
+            transparent: Arc::new(transparent::LurkTransparentHandler::new(Arc::clone(&context))),
+        }
+    }
+
+    /// Returns handler of the connections with passed label.
+    pub fn get(&self, label: &LurkTcpConnectionLabel) -> Result<Arc<dyn LurkTcpConnectionHandler>> {
+        match label {
+            #[cfg(feature = "http")]
+            LurkTcpConnectionLabel::Http => Ok(self.http.clone()),
+            #[cfg(feature = "socks5")]
+            LurkTcpConnectionLabel::Socks5 => Ok(self.socks5.clone()),
+            #[cfg(feature = "socks4")]
+            LurkTcpConnectionLabel::Socks4 => Ok(self.socks4.clone()),
+            #[cfg(feature = "transparent")]
+            LurkTcpConnectionLabel::Transparent => Ok(self.transparent.clone()),
+            LurkTcpConnectionLabel::Unknown(_) => bail!("Unknown TCP connection"),
+            #[allow(unreachable_patterns)]
+            label => bail!("Handler of {} connections is not compiled in", label),
+        }
     }
 }
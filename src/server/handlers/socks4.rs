@@ -0,0 +1,248 @@
+use super::LurkHandlerContext;
+use crate::{
+    common::{error::LurkError, logging},
+    io::{tunnel::LurkTunnel, LurkRequest, LurkResponse},
+    net::tcp::connection::{LurkTcpConnection, LurkTcpConnectionHandler, LurkTcpConnectionLabel},
+    proto::socks4::{request::RelayRequest, response::RelayResponse, Command},
+};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use human_bytes::human_bytes;
+use log::{debug, error, info};
+use std::{sync::Arc, time::Instant};
+use tokio::{io::AsyncWriteExt, time::timeout};
+
+/// Handler of legacy SOCKS4 and SOCKS4a clients.
+///
+/// The protocol has no authentication, so clients are served only while the server
+/// accepts unauthenticated SOCKS5 clients as well. Only CONNECT command is supported.
+pub struct LurkSocks4Handler {
+    context: Arc<LurkHandlerContext>,
+}
+
+impl LurkSocks4Handler {
+    pub fn new(context: Arc<LurkHandlerContext>) -> LurkSocks4Handler {
+        LurkSocks4Handler { context }
+    }
+
+    /// Handling SOCKS4 request and establishing the tunnel "client <-- lurk proxy --> target".
+    async fn process_relay_request(&self, conn: &mut LurkTcpConnection, handshake_started: Instant) -> Result<()> {
+        let conn_peer_addr = conn.peer_addr();
+        let conn_bound_addr = conn.local_addr();
+        let conn_activity = conn.activity();
+        let request = RelayRequest::read_from(conn.stream_mut()).await?;
+        let address = request.endpoint_address();
+        conn.session().set_destination(address);
+
+        if !self.context.is_unauthenticated_allowed() {
+            debug!("SOCKS4 client {} is refused: authentication is required", conn_peer_addr);
+            return self
+                .on_relay_request_handling_error(anyhow!(LurkError::NoAcceptableAuthenticationMethod), &request, conn)
+                .await;
+        }
+
+        if request.command() != Command::Connect {
+            let err = anyhow!("SOCKS4 {:?} command is not supported", request.command());
+            return self.on_relay_request_handling_error(err, &request, conn).await;
+        }
+
+        info!("SOCKS4 {:?} from peer {} to {}", request.command(), conn_peer_addr, address);
+
+        let stats = self.context.stats();
+        let destinations = stats.destinations();
+        let host = address.host();
+
+        if let Some(reason) = self.context.deny_reason(&host) {
+            return self
+                .on_relay_request_handling_error(anyhow!(LurkError::DestinationBlocked(host, reason)), &request, conn)
+                .await;
+        }
+
+        let connect_started = Instant::now();
+        let mut outbound_stream = match self.context.connect_for(address, None, conn_peer_addr.ip()).await {
+            Ok(outbound_stream) => {
+                stats.connect_latency().observe(connect_started.elapsed());
+                let reply_addr = self.context.reply_bound_address(conn_bound_addr, &outbound_stream);
+                self.write_response(&RelayResponse::granted(reply_addr), conn.stream_mut()).await?;
+                stats.handshake_duration().observe(handshake_started.elapsed());
+
+                outbound_stream
+            }
+            Err(err) => {
+                destinations.on_failure(&host);
+                return self.on_relay_request_handling_error(err, &request, conn).await;
+            }
+        };
+
+        let inbound_stream = conn.stream_mut();
+        let tunnel = LurkTunnel::new(inbound_stream, &mut outbound_stream).with_activity(Arc::clone(&conn_activity));
+        let mut tunnel = self.context.configure_tunnel(tunnel, conn_peer_addr, address, None);
+
+        logging::log_tunnel_created!(conn_peer_addr, conn_bound_addr, address);
+
+        let tunnel_started = Instant::now();
+        let tunnel_result = tunnel.run().await;
+        stats.tunnel_lifetime().observe(tunnel_started.elapsed());
+
+        match tunnel_result {
+            Ok((l2r, r2l)) => {
+                logging::log_tunnel_closed!(conn_peer_addr, conn_bound_addr, address, l2r, r2l);
+                destinations.on_session_finished(&host, l2r, r2l);
+            }
+            Err(err) => {
+                logging::log_tunnel_closed_with_error!(conn_peer_addr, conn_bound_addr, address, err);
+                destinations.on_session_finished(&host, conn_activity.l2r_bytes(), conn_activity.r2l_bytes());
+
+                if err.downcast_ref::<LurkError>().is_some() {
+                    self.context.on_tunnel_error(&err);
+                    let _ = outbound_stream.shutdown().await;
+                    let _ = inbound_stream.shutdown().await;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// SOCKS4 has the single failure reply, so the client is told the request is rejected whatever the reason.
+    async fn on_relay_request_handling_error(
+        &self,
+        err: anyhow::Error,
+        request: &RelayRequest,
+        conn: &mut LurkTcpConnection,
+    ) -> Result<()> {
+        let err_msg = err.to_string();
+        if let Some(LurkError::DestinationBlocked(_, reason)) = err.downcast_ref::<LurkError>() {
+            conn.session().set_deny_reason(reason);
+        }
+        let response = RelayResponse::rejected(conn.local_addr());
+
+        logging::log_request_handling_error!(conn, err_msg, request, response);
+        self.write_response(&response, conn.stream_mut()).await
+    }
+
+    /// Write response to the client. Peer that doesn't accept the response
+    /// within configured timeout is considered misbehaving.
+    async fn write_response<T>(&self, response: &RelayResponse, stream: &mut T) -> Result<()>
+    where
+        T: AsyncWriteExt + Unpin,
+    {
+        let write_timeout = self.context.response_write_timeout();
+        match timeout(write_timeout, response.write_to(stream)).await {
+            Ok(res) => res,
+            Err(_) => {
+                self.context.stats().on_response_write_timeout();
+                bail!(LurkError::ResponseWriteTimeout(write_timeout))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LurkTcpConnectionHandler for LurkSocks4Handler {
+    async fn handle(&self, mut conn: LurkTcpConnection) -> Result<()> {
+        debug_assert_eq!(LurkTcpConnectionLabel::Socks4, conn.label(), "expected SOCKS4 label");
+        let handshake_started = Instant::now();
+        let _client_connection = match conn.take_client_connection() {
+            Ok(client_connection) => client_connection,
+            Err(err) => {
+                let request = RelayRequest::read_from(conn.stream_mut()).await?;
+                return self.on_relay_request_handling_error(err, &request, &mut conn).await;
+            }
+        };
+        self.process_relay_request(&mut conn, handshake_started).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{
+            quota::LurkQuota,
+            users::{LurkUser, LurkUserStore},
+        },
+        net::{tcp::connection::LurkTcpConnectionFactory, Address},
+        proto::socks4::ReplyStatus,
+        server::stats::LurkServerStats,
+    };
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, DuplexStream},
+        net::TcpListener,
+    };
+
+    fn test_context() -> LurkHandlerContext {
+        LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+    }
+
+    fn in_memory_connection() -> (LurkTcpConnection, DuplexStream) {
+        LurkTcpConnectionFactory::create_in_memory_connection(
+            LurkTcpConnectionLabel::Socks4,
+            "127.0.0.1:50000".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn connect_and_relay() {
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = Address::SocketAddress(endpoint.local_addr().unwrap());
+        let handler = LurkSocks4Handler::new(Arc::new(test_context()));
+        let (conn, mut client) = in_memory_connection();
+
+        let server = tokio::spawn(async move { handler.handle(conn).await });
+        RelayRequest::new(Command::Connect, endpoint_addr, "user")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+
+        let (mut endpoint_stream, outbound_addr) = endpoint.accept().await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(RelayResponse::granted(outbound_addr), response);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buff = [0u8; 4];
+        endpoint_stream.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"ping", &buff);
+
+        drop(client);
+        drop(endpoint_stream);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_when_authentication_is_required() {
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let handler = LurkSocks4Handler::new(Arc::new(test_context().with_users(Arc::new(users))));
+        let (mut conn, mut client) = in_memory_connection();
+
+        let endpoint_addr = Address::DomainName("example.com".to_owned(), 80);
+        RelayRequest::new(Command::Connect, endpoint_addr, "user")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+
+        handler.process_relay_request(&mut conn, Instant::now()).await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Rejected, response.status());
+    }
+
+    #[tokio::test]
+    async fn reject_bind_command() {
+        let handler = LurkSocks4Handler::new(Arc::new(test_context()));
+        let (mut conn, mut client) = in_memory_connection();
+
+        let endpoint_addr = Address::SocketAddress("127.0.0.1:21".parse().unwrap());
+        RelayRequest::new(Command::Bind, endpoint_addr, "")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+
+        handler.process_relay_request(&mut conn, Instant::now()).await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Rejected, response.status());
+    }
+}
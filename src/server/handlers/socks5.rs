@@ -1,136 +1,358 @@
+use super::LurkHandlerContext;
 use crate::{
-    auth::LurkAuthenticator,
+    auth::{LurkAuthMethod, LurkAuthenticator},
     common::{error::LurkError, logging},
     io::{tunnel::LurkTunnel, LurkRequest, LurkResponse},
-    net::tcp::{
-        self,
-        connection::{LurkTcpConnection, LurkTcpConnectionHandler, LurkTcpConnectionLabel},
+    net::{
+        tcp::{
+            connection::{LurkTcpConnection, LurkTcpConnectionHandler, LurkTcpConnectionLabel},
+            LurkOutboundStream,
+        },
+        Address,
     },
     proto::socks5::{
-        request::{HandshakeRequest, RelayRequest},
-        response::{HandshakeResponse, RelayResponse},
+        request::{HandshakeRequest, PasswordAuthRequest, RelayRequest},
+        response::{HandshakeResponse, PasswordAuthResponse, RelayResponse},
         Command,
     },
+    server::stats::sink::LurkStatsEvent,
 };
 use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use human_bytes::human_bytes;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, net::TcpListener, time::timeout};
 
-pub struct LurkSocks5Handler {}
+pub struct LurkSocks5Handler {
+    context: Arc<LurkHandlerContext>,
+}
 
 impl LurkSocks5Handler {
+    /// Time given to the application server to connect to the address bound by BIND command.
+    const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new(context: Arc<LurkHandlerContext>) -> LurkSocks5Handler {
+        LurkSocks5Handler { context }
+    }
+
     /// Handshaking with SOCKS5 client.
-    /// Afterwards, authenticator should contain negotiated method.
-    async fn process_handshake(conn: &mut LurkTcpConnection) -> Result<()> {
+    /// Returns name of the authenticated user if password authentication took place.
+    async fn process_handshake(&self, conn: &mut LurkTcpConnection) -> Result<Option<String>> {
         let request = HandshakeRequest::read_from(conn.stream_mut()).await?;
 
         // Authenticator will select method among all stored in request
         // and authenticate the connection on success.
-        let mut authenticator = LurkAuthenticator::new();
+        let mut authenticator = LurkAuthenticator::new(self.context.users()).with_private_methods(self.context.private_auth_methods());
+        if let Some(methods) = self.context.offered_auth_methods() {
+            authenticator = authenticator.with_offered_methods(methods);
+        }
 
         match authenticator.select_auth_method(request.auth_methods()) {
             Some(method) => {
                 debug!("Selected authentication method {:?} for {}", method, conn.peer_addr());
                 // Respond to the client with selected method.
-                HandshakeResponse::builder()
-                    .with_auth_method(method)
-                    .build()
-                    .write_to(conn.stream_mut())
-                    .await?;
+                let response = HandshakeResponse::builder().with_auth_method(method).build();
+                self.write_response(&response, conn.stream_mut()).await?;
                 // Authenticate the client by using selected method.
-                // Note: Currently, only None method (disabled auth) is supported,
-                // so just a sanity check here.
-                authenticator.authenticate_connection(conn)
+                match method {
+                    LurkAuthMethod::Password => self.process_password_auth(&authenticator, conn).await.map(Some),
+                    LurkAuthMethod::Private(code) => self.process_private_auth(&authenticator, code, conn).await,
+                    _ => authenticator.authenticate_connection(conn).map(|_| None),
+                }
             }
             None => {
                 debug!("No acceptable methods identified for {}", conn.peer_addr());
-                HandshakeResponse::builder()
-                    .with_no_acceptable_method()
-                    .build()
-                    .write_to(conn.stream_mut())
-                    .await?;
+                let response = HandshakeResponse::builder().with_no_acceptable_method().build();
+                self.write_response(&response, conn.stream_mut()).await?;
                 bail!(LurkError::NoAcceptableAuthenticationMethod)
             }
         }
     }
 
+    /// Username/password sub-negotiation (RFC 1929).
+    /// Returns name of the authenticated user on success.
+    async fn process_password_auth(&self, authenticator: &LurkAuthenticator<'_>, conn: &mut LurkTcpConnection) -> Result<String> {
+        let request = PasswordAuthRequest::read_from(conn.stream_mut()).await?;
+
+        // Username could end with the hint picking the parent proxy, e.g. "alice+de".
+        let (username, route_hint) = match self
+            .context
+            .routing()
+            .and_then(|routing| routing.split_route_hint(request.username()))
+        {
+            Some((username, route_hint)) => (username, Some(route_hint)),
+            None => (request.username(), None),
+        };
+
+        let auth_result = authenticator.authenticate_user(username, request.password());
+        self.context.stats().emit(LurkStatsEvent::AuthResult {
+            peer_addr: conn.peer_addr(),
+            user: username,
+            succeeded: auth_result.is_ok(),
+        });
+
+        match auth_result {
+            Ok(()) => {
+                debug!("User '{}' has been authenticated from {}", username, conn.peer_addr());
+                conn.session().set_user(username);
+                if let Some(route_hint) = route_hint {
+                    conn.session().set_route_hint(route_hint);
+                }
+                let response = PasswordAuthResponse::builder().with_success().build();
+                self.write_response(&response, conn.stream_mut()).await?;
+                Ok(username.to_owned())
+            }
+            Err(err) => {
+                let response = PasswordAuthResponse::builder().with_failure().build();
+                self.write_response(&response, conn.stream_mut()).await?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Sub-negotiation of the private method, driven by its implementation.
+    /// Returns name of the authenticated user if it's one of the configured users,
+    /// so its transfer quota is enforced the same way as after password authentication.
+    async fn process_private_auth(
+        &self,
+        authenticator: &LurkAuthenticator<'_>,
+        code: u8,
+        conn: &mut LurkTcpConnection,
+    ) -> Result<Option<String>> {
+        let method = authenticator
+            .private_method(code)
+            .ok_or(LurkError::UnsupportedAuthMethod(LurkAuthMethod::Private(code)))?;
+
+        let peer_addr = conn.peer_addr();
+        let stream = conn
+            .tcp_stream_mut()
+            .ok_or_else(|| anyhow!("Private authentication methods are run over TCP connections only"))?;
+        let auth_result = method.authenticate(stream, peer_addr).await;
+        self.context.stats().emit(LurkStatsEvent::AuthResult {
+            peer_addr,
+            user: auth_result.as_ref().ok().and_then(Option::as_deref).unwrap_or_default(),
+            succeeded: auth_result.is_ok(),
+        });
+
+        let Some(user) = auth_result? else {
+            debug!("Private method {:#04x} has authenticated {}", code, peer_addr);
+            return Ok(None);
+        };
+
+        debug!("Private method {:#04x} has authenticated {} as '{}'", code, peer_addr, user);
+        conn.session().set_user(&user);
+        match self.context.users() {
+            Some(users) if users.get_user(&user).is_some() => users.check_quota(&user).map(|_| Some(user)),
+            _ => Ok(None),
+        }
+    }
+
     /// Handling SOCKS5 command which comes in relay request from client.
-    async fn process_relay_request(conn: &mut LurkTcpConnection) -> Result<()> {
+    /// Handshake duration is measured since ```handshake_started``` till the tunnel is ready.
+    async fn process_relay_request(&self, conn: &mut LurkTcpConnection, handshake_started: Instant, user: Option<&str>) -> Result<()> {
         let conn_peer_addr = conn.peer_addr();
         let conn_bound_addr = conn.local_addr();
+        let conn_activity = conn.activity();
+        let conn_session = conn.session();
         let inbound_stream = conn.stream_mut();
         let request = RelayRequest::read_from(inbound_stream).await?;
         let command = request.command();
         let address = request.endpoint_address();
+        conn_session.set_destination(address);
 
         // Bail out and notify client if command isn't supported
-        if command != Command::TCPConnect {
-            return LurkSocks5Handler::on_relay_request_handling_error(
-                anyhow!(LurkError::UnsupportedSocksCommand(command)),
-                &request,
-                conn,
-            )
-            .await;
+        if !matches!(command, Command::TCPConnect | Command::TCPBind) {
+            return self
+                .on_relay_request_handling_error(anyhow!(LurkError::UnsupportedSocksCommand(command)), &request, conn)
+                .await;
         }
 
-        info!("SOCKS5 CONNECT from peer {} to {}", conn_peer_addr, address);
+        info!("SOCKS5 {:?} from peer {} to {}", command, conn_peer_addr, address);
+
+        // Session of the user is accounted till the tunnel is closed.
+        let _user_session = match (self.context.users(), user) {
+            (Some(users), Some(user)) if users.get_user(user).is_some() => match users.open_session(user) {
+                Ok(session) => Some(session),
+                Err(err) => return self.on_relay_request_handling_error(err, &request, conn).await,
+            },
+            _ => None,
+        };
+
+        let stats = self.context.stats();
+        let destinations = stats.destinations();
+        let host = address.host();
 
-        // Create TCP stream with the endpoint
-        let mut outbound_stream = match tcp::establish_tcp_connection(address.to_socket_addr().await?).await {
-            Ok(outbound_stream) => {
-                // On success, respond to relay request with success
-                RelayResponse::builder()
-                    .with_success()
-                    .with_bound_address(conn_bound_addr)
-                    .build()
-                    .write_to(inbound_stream)
-                    .await?;
+        if let Some(reason) = self.context.deny_reason(&host) {
+            return self
+                .on_relay_request_handling_error(anyhow!(LurkError::DestinationBlocked(host, reason)), &request, conn)
+                .await;
+        }
+
+        // Create TCP stream with the endpoint: connect to it, or accept its connection for BIND command
+        let connect_started = Instant::now();
+        let outbound = match command {
+            Command::TCPBind => self.accept_bound(address, conn_bound_addr.ip(), inbound_stream).await,
+            _ => self
+                .context
+                .connect_hinted_for(address, user, conn_session.route_hint(), conn_peer_addr.ip())
+                .await
+                .inspect(|_| stats.connect_latency().observe(connect_started.elapsed()))
+                .map(|outbound_stream| {
+                    let reply_addr = self.context.reply_bound_address(conn_bound_addr, &outbound_stream);
+                    (outbound_stream, reply_addr)
+                }),
+        };
+        let mut outbound_stream = match outbound {
+            Ok((outbound_stream, reply_addr)) => {
+                // On success, respond to relay request with success. The second reply to BIND
+                // carries the address the endpoint has connected from.
+                let response = RelayResponse::builder().with_success().with_bound_address(reply_addr).build();
+                self.write_response(&response, inbound_stream).await?;
+                stats.handshake_duration().observe(handshake_started.elapsed());
 
                 outbound_stream
             }
-            Err(err) => return LurkSocks5Handler::on_relay_request_handling_error(err, &request, conn).await,
+            Err(err) => {
+                destinations.on_failure(&host);
+                return self.on_relay_request_handling_error(err, &request, conn).await;
+            }
         };
 
         // Create proxy tunnel which operates with the following TCP streams:
         // - L2R: client   <--> proxy
         // - R2L: endpoint <--> proxy
-        let mut tunnel = LurkTunnel::new(inbound_stream, &mut outbound_stream);
+        let tunnel = LurkTunnel::new(inbound_stream, &mut outbound_stream).with_activity(Arc::clone(&conn_activity));
+        let mut tunnel = self.context.configure_tunnel(tunnel, conn_peer_addr, address, user);
 
         logging::log_tunnel_created!(conn_peer_addr, conn_bound_addr, address);
 
         // Start data relaying
-        match tunnel.run().await {
+        let tunnel_started = Instant::now();
+        let tunnel_result = self.context.run_tunnel(&mut tunnel, &conn_activity, user).await;
+        stats.tunnel_lifetime().observe(tunnel_started.elapsed());
+
+        match tunnel_result {
             Ok((l2r, r2l)) => {
                 logging::log_tunnel_closed!(conn_peer_addr, conn_bound_addr, address, l2r, r2l);
+                destinations.on_session_finished(&host, l2r, r2l);
             }
             Err(err) => {
                 logging::log_tunnel_closed_with_error!(conn_peer_addr, conn_bound_addr, address, err);
+                // Account data relayed before the failure.
+                destinations.on_session_finished(&host, conn_activity.l2r_bytes(), conn_activity.r2l_bytes());
+
+                // Session closed by the proxy (e.g. quota or duration limit) is reported as the close reason,
+                // both sides are shut down gracefully.
+                if err.downcast_ref::<LurkError>().is_some() {
+                    self.context.on_tunnel_error(&err);
+                    let _ = outbound_stream.shutdown().await;
+                    let _ = inbound_stream.shutdown().await;
+                    return Err(err);
+                }
             }
         }
 
         Ok(())
     }
 
-    async fn on_relay_request_handling_error(err: anyhow::Error, request: &RelayRequest, conn: &mut LurkTcpConnection) -> Result<()> {
+    /// Handle BIND command: listen on the interface the client has connected to, send the first reply
+    /// with the listening address and accept the connection from the ```expected``` peer (e.g. FTP server).
+    /// Connections from other hosts are dropped. Any host could connect, if the expected IP is unspecified.
+    async fn accept_bound<T>(
+        &self,
+        expected: &Address,
+        local_ip: IpAddr,
+        inbound_stream: &mut T,
+    ) -> Result<(LurkOutboundStream, SocketAddr)>
+    where
+        T: AsyncWriteExt + Unpin,
+    {
+        let expected_ip = expected.to_socket_addr(self.context.resolve_policy()).await?.ip();
+        let listener = TcpListener::bind(SocketAddr::new(local_ip, 0)).await?;
+        let listener_addr = listener.local_addr()?;
+        debug!("Waiting for connection from {} on {}", expected, listener_addr);
+
+        // The first BIND reply: address the client passes to the application server.
+        let response = RelayResponse::builder().with_success().with_bound_address(listener_addr).build();
+        self.write_response(&response, inbound_stream).await?;
+
+        let accept = async {
+            loop {
+                let (stream, peer_addr) = listener.accept().await?;
+                if expected_ip.is_unspecified() || peer_addr.ip() == expected_ip {
+                    return anyhow::Ok((stream.into(), peer_addr));
+                }
+                warn!(
+                    "Connection from {} to {} is dropped: {} is expected",
+                    peer_addr, listener_addr, expected
+                );
+            }
+        };
+
+        timeout(LurkSocks5Handler::BIND_ACCEPT_TIMEOUT, accept)
+            .await
+            .map_err(|_| LurkError::BindAcceptTimeout(expected.to_string(), LurkSocks5Handler::BIND_ACCEPT_TIMEOUT))?
+    }
+
+    async fn on_relay_request_handling_error(
+        &self,
+        err: anyhow::Error,
+        request: &RelayRequest,
+        conn: &mut LurkTcpConnection,
+    ) -> Result<()> {
         let err_msg = err.to_string();
+        if let Some(LurkError::DestinationBlocked(_, reason)) = err.downcast_ref::<LurkError>() {
+            conn.session().set_deny_reason(reason);
+        }
         let response = RelayResponse::builder().with_err(err).with_bound_address(conn.local_addr()).build();
 
         logging::log_request_handling_error!(conn, err_msg, request, response);
-        response.write_to(conn.stream_mut()).await
+        self.write_response(&response, conn.stream_mut()).await
+    }
+
+    /// Write response to the client. Peer that doesn't accept the response
+    /// within configured timeout is considered misbehaving.
+    async fn write_response<R, T>(&self, response: &R, stream: &mut T) -> Result<()>
+    where
+        R: LurkResponse,
+        T: AsyncWriteExt + Unpin,
+    {
+        let write_timeout = self.context.response_write_timeout();
+        match timeout(write_timeout, response.write_to(stream)).await {
+            Ok(res) => res,
+            Err(_) => {
+                self.context.stats().on_response_write_timeout();
+                bail!(LurkError::ResponseWriteTimeout(write_timeout))
+            }
+        }
     }
 }
 
 #[async_trait]
 impl LurkTcpConnectionHandler for LurkSocks5Handler {
-    async fn handle(&mut self, mut conn: LurkTcpConnection) -> Result<()> {
+    async fn handle(&self, mut conn: LurkTcpConnection) -> Result<()> {
         debug_assert_eq!(LurkTcpConnectionLabel::Socks5, conn.label(), "expected SOCKS5 label");
+        let handshake_started = Instant::now();
+        // Connection over the limit of the client is refused once it has asked for the relay.
+        let client_connection = conn.take_client_connection();
         // Complete handshake process and authenticate the client on success.
-        LurkSocks5Handler::process_handshake(&mut conn).await?;
+        let user = self.process_handshake(&mut conn).await?;
+        let _client_connection = match client_connection {
+            Ok(client_connection) => client_connection,
+            Err(err) => {
+                let request = RelayRequest::read_from(conn.stream_mut()).await?;
+                return self.on_relay_request_handling_error(err, &request, &mut conn).await;
+            }
+        };
         // Proceed with SOCKS5 relay handling.
         // This will receive and process relay request, handle SOCKS5 command
         // and establish the tunnel "client <-- lurk proxy --> target".
-        LurkSocks5Handler::process_relay_request(&mut conn).await
+        self.process_relay_request(&mut conn, handshake_started, user.as_deref()).await
     }
 }
 
@@ -138,86 +360,624 @@ impl LurkTcpConnectionHandler for LurkSocks5Handler {
 mod tests {
 
     use super::*;
-    use crate::{auth::LurkAuthMethod, common::assertions::assert_lurk_err, net::tcp::listener::LurkTcpListener};
+    use crate::{
+        auth::{
+            private::LurkPrivateAuthMethod,
+            quota::LurkQuota,
+            users::{LurkUser, LurkUserClass, LurkUserStore},
+            LurkAuthMethod,
+        },
+        common::assertions::assert_lurk_err,
+        io::tunnel::LurkTunnelActivity,
+        net::{
+            client_limits::LurkClientConnections,
+            geoip::{self, LurkCountryAccess},
+            sim::{self, LurkSimLinkOptions},
+            socks5::LurkUpstreamProxy,
+            tcp::{connection::LurkTcpConnectionFactory, listener::LurkTcpListener},
+        },
+        proto::socks5::ReplyStatus,
+        server::{handlers::LurkReplyBoundAddress, routing::LurkRouting, ssrf::LurkSsrfGuard, stats::LurkServerStats},
+    };
+    use anyhow::ensure;
     use futures::TryFutureExt;
     use pretty_assertions::assert_eq;
-    use std::collections::HashSet;
-    use tokio::net::TcpStream;
+    use std::{collections::HashSet, net::SocketAddr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, DuplexStream},
+        net::{TcpSocket, TcpStream},
+        time::{sleep, Instant},
+    };
     use tokio_test::assert_ok;
 
     // :0 tells the OS to pick an open port.
     const TEST_BIND_IPV4: &str = "127.0.0.1:0";
 
+    fn test_handler() -> LurkSocks5Handler {
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1));
+        LurkSocks5Handler::new(Arc::new(context))
+    }
+
+    fn in_memory_connection() -> (LurkTcpConnection, DuplexStream) {
+        LurkTcpConnectionFactory::create_in_memory_connection(
+            LurkTcpConnectionLabel::Socks5,
+            "127.0.0.1:50000".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+        )
+    }
+
     #[tokio::test]
     async fn handshake_with_auth_method() {
-        let mut listener = LurkTcpListener::bind(TEST_BIND_IPV4).await.expect("Expect binded listener");
+        let (mut conn, mut client) = in_memory_connection();
+
+        // Send handshake request with auth methods.
+        HandshakeRequest::new(HashSet::from([
+            LurkAuthMethod::None,
+            LurkAuthMethod::GssAPI,
+            LurkAuthMethod::Password,
+        ]))
+        .write_to(&mut client)
+        .await
+        .unwrap();
+
+        assert_ok!(test_handler().process_handshake(&mut conn).await);
+
+        // Read and verify handshake response.
+        let actual = HandshakeResponse::read_from(&mut client).await.unwrap();
+        let reference = HandshakeResponse::builder().with_auth_method(LurkAuthMethod::None).build();
+        assert_eq!(reference, actual);
+    }
 
-        let listener_addr = listener.local_addr();
-        let client_handle = tokio::spawn(async move {
-            TcpStream::connect(listener_addr)
-                .and_then(|mut s| async move {
-                    // Send handshake request with auth methods.
-                    HandshakeRequest::new(HashSet::from([
-                        LurkAuthMethod::None,
-                        LurkAuthMethod::GssAPI,
-                        LurkAuthMethod::Password,
-                    ]))
-                    .write_to(&mut s)
-                    .await;
+    #[tokio::test]
+    async fn handshake_with_non_accepatable_method() {
+        let (mut conn, mut client) = in_memory_connection();
 
-                    // Read and verify handshake response.
-                    let actual = HandshakeResponse::read_from(&mut s).await;
-                    let reference = HandshakeResponse::builder().with_auth_method(LurkAuthMethod::None).build();
+        // Send handshake request with auth methods.
+        HandshakeRequest::new(HashSet::from([LurkAuthMethod::GssAPI, LurkAuthMethod::Password]))
+            .write_to(&mut client)
+            .await
+            .unwrap();
 
-                    assert_eq!(reference, actual);
-                    Ok(())
-                })
+        assert_lurk_err!(
+            LurkError::NoAcceptableAuthenticationMethod,
+            test_handler().process_handshake(&mut conn).await.expect_err("Expect error")
+        );
+
+        // Read and verify handshake response.
+        let actual = HandshakeResponse::read_from(&mut client).await.unwrap();
+        let reference = HandshakeResponse::builder().with_no_acceptable_method().build();
+        assert_eq!(reference, actual);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_password() {
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1)).with_users(Arc::new(users));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+
+        for (password, authenticated, reference) in [
+            ("wrong", false, PasswordAuthResponse::builder().with_failure().build()),
+            ("secret", true, PasswordAuthResponse::builder().with_success().build()),
+        ] {
+            let (mut conn, mut client) = in_memory_connection();
+            HandshakeRequest::new(HashSet::from([LurkAuthMethod::None, LurkAuthMethod::Password]))
+                .write_to(&mut client)
                 .await
-                .unwrap()
+                .unwrap();
+            PasswordAuthRequest::new("alice", password).write_to(&mut client).await.unwrap();
+
+            let result = handler.process_handshake(&mut conn).await;
+            if authenticated {
+                assert_eq!(Some("alice".to_owned()), result.unwrap());
+            } else {
+                assert_lurk_err!(LurkError::InvalidCredentials("alice".to_owned()), result.expect_err("Expect error"));
+            }
+
+            // Server insists on password authentication.
+            let actual = HandshakeResponse::read_from(&mut client).await.unwrap();
+            assert_eq!(
+                HandshakeResponse::builder().with_auth_method(LurkAuthMethod::Password).build(),
+                actual
+            );
+            assert_eq!(reference, PasswordAuthResponse::read_from(&mut client).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_accepts_expected_peer() {
+        let (mut conn, mut client) = in_memory_connection();
+        let handler = test_handler();
+        let relay = tokio::spawn(async move { handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await });
+
+        // Application server is expected to connect from 127.0.0.2.
+        let expected = Address::SocketAddress("127.0.0.2:0".parse().unwrap());
+        RelayRequest::new(Command::TCPBind, expected).write_to(&mut client).await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Succeeded, response.status());
+        let Address::SocketAddress(listener_addr) = response.bound_address().clone() else {
+            panic!("Expect bound socket address");
+        };
+        assert_eq!("127.0.0.1".parse::<IpAddr>().unwrap(), listener_addr.ip());
+
+        // Connection from another host is dropped.
+        let mut stranger = TcpStream::connect(listener_addr).await.unwrap();
+        assert_eq!(0, stranger.read(&mut [0u8; 1]).await.unwrap_or_default());
+
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut server = socket.connect(listener_addr).await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Succeeded, response.status());
+        assert_eq!(&Address::SocketAddress(server.local_addr().unwrap()), response.bound_address());
+
+        // Data is relayed between the client and the application server.
+        server.write_all(b"220 ready").await.unwrap();
+        let mut buf = [0u8; 9];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"220 ready", &buf);
+        client.write_all(b"QUIT").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"QUIT", &buf);
+
+        drop(client);
+        drop(server);
+        assert_ok!(relay.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connect_reply_carries_bound_address() {
+        for (reply_bound_address, inbound) in [(LurkReplyBoundAddress::Outbound, false), (LurkReplyBoundAddress::Inbound, true)] {
+            let endpoint = TcpListener::bind(TEST_BIND_IPV4).await.unwrap();
+            let endpoint_addr = Address::SocketAddress(endpoint.local_addr().unwrap());
+            let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+                .with_reply_bound_address(reply_bound_address);
+            let handler = LurkSocks5Handler::new(Arc::new(context));
+            let (mut conn, mut client) = in_memory_connection();
+            let relay = tokio::spawn(async move { handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await });
+
+            RelayRequest::new(Command::TCPConnect, endpoint_addr)
+                .write_to(&mut client)
+                .await
+                .unwrap();
+            let (endpoint_stream, outbound_addr) = endpoint.accept().await.unwrap();
+            let response = RelayResponse::read_from(&mut client).await.unwrap();
+            assert_eq!(ReplyStatus::Succeeded, response.status());
+
+            let expected: SocketAddr = if inbound {
+                "127.0.0.1:1080".parse().unwrap()
+            } else {
+                outbound_addr
+            };
+            assert_eq!(&Address::SocketAddress(expected), response.bound_address());
+
+            drop(client);
+            drop(endpoint_stream);
+            assert_ok!(relay.await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_through_upstream_proxy() {
+        let endpoint = TcpListener::bind(TEST_BIND_IPV4).await.unwrap();
+        let endpoint_addr = Address::SocketAddress(endpoint.local_addr().unwrap());
+        let mut upstream_listener = LurkTcpListener::bind(TEST_BIND_IPV4).await.unwrap();
+        let upstream_proxy = LurkUpstreamProxy::new(upstream_listener.local_addr());
+        let upstream = tokio::spawn(async move {
+            let conn = upstream_listener.accept().await.unwrap();
+            test_handler().handle(conn).await
         });
 
-        tokio::task::yield_now().await;
+        let context =
+            LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1)).with_upstream_proxy(Arc::new(upstream_proxy));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+        let (mut conn, mut client) = in_memory_connection();
+        let relay = tokio::spawn(async move { handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await });
+
+        RelayRequest::new(Command::TCPConnect, endpoint_addr)
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let (mut endpoint_stream, _) = endpoint.accept().await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Succeeded, response.status());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buff = [0u8; 4];
+        endpoint_stream.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"ping", &buff);
+
+        drop(client);
+        drop(endpoint_stream);
+        assert_ok!(relay.await.unwrap());
+        assert_ok!(upstream.await.unwrap());
+    }
 
-        let mut conn = listener.accept().await.expect("Expect created connection");
-        assert_eq!(LurkTcpConnectionLabel::Socks5, conn.label());
-        assert_ok!(LurkSocks5Handler::process_handshake(&mut conn).await);
+    #[tokio::test]
+    async fn connect_through_upstream_picked_by_username() {
+        let endpoint = TcpListener::bind(TEST_BIND_IPV4).await.unwrap();
+        let endpoint_addr = Address::SocketAddress(endpoint.local_addr().unwrap());
+        let mut upstream_listener = LurkTcpListener::bind(TEST_BIND_IPV4).await.unwrap();
+        let routing = format!(
+            r#"{{"upstreams": {{"second": {{"addr": "{}"}}}}, "default": "direct"}}"#,
+            upstream_listener.local_addr()
+        );
+        let upstream = tokio::spawn(async move {
+            let conn = upstream_listener.accept().await.unwrap();
+            test_handler().handle(conn).await
+        });
 
-        assert_ok!(client_handle.into_future().await);
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+            .with_users(Arc::new(users))
+            .with_routing(Arc::new(LurkRouting::parse(&routing).unwrap()));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+        let (conn, mut client) = in_memory_connection();
+        let session = conn.session();
+        let serving = tokio::spawn(async move { handler.handle(conn).await });
+
+        HandshakeRequest::new(HashSet::from([LurkAuthMethod::Password]))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        PasswordAuthRequest::new("alice+second", "secret")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        RelayRequest::new(Command::TCPConnect, endpoint_addr)
+            .write_to(&mut client)
+            .await
+            .unwrap();
+
+        HandshakeResponse::read_from(&mut client).await.unwrap();
+        let response = PasswordAuthResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(PasswordAuthResponse::builder().with_success().build(), response);
+        let (mut endpoint_stream, _) = endpoint.accept().await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Succeeded, response.status());
+        assert_eq!(Some("alice"), session.user());
+        assert_eq!(Some("second"), session.route_hint());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buff = [0u8; 4];
+        endpoint_stream.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"ping", &buff);
+
+        // Destination has been connected to by the upstream proxy.
+        drop(client);
+        drop(endpoint_stream);
+        assert_ok!(serving.await.unwrap());
+        assert_ok!(upstream.await.unwrap());
     }
 
     #[tokio::test]
-    async fn handshake_with_non_accepatable_method() {
+    async fn refuse_blocked_route() {
+        let routing = LurkRouting::parse(r#"{"rules": [{"id": "intranet", "action": "block", "cidr": "10.0.0.0/8"}]}"#).unwrap();
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1)).with_routing(Arc::new(routing));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+        let (mut conn, mut client) = in_memory_connection();
+
+        RelayRequest::new(Command::TCPConnect, Address::SocketAddress("10.1.2.3:443".parse().unwrap()))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        assert_ok!(handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await);
+
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::ConnectionNotAllowed, response.status());
+    }
+
+    #[tokio::test]
+    async fn refuse_special_purpose_destination() {
+        let ssrf_guard = LurkSsrfGuard::new(Vec::new());
+        let context =
+            LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1)).with_ssrf_guard(Arc::new(ssrf_guard));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+        let (mut conn, mut client) = in_memory_connection();
+
+        RelayRequest::new(Command::TCPConnect, Address::DomainName("localhost".to_owned(), 22))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        assert_ok!(handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await);
+
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::ConnectionNotAllowed, response.status());
+    }
+
+    #[tokio::test]
+    async fn refuse_destination_by_country() {
+        let countries = LurkCountryAccess::new(geoip::tests::test_geoip(), [], ["CN".to_owned()]);
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+            .with_destination_countries(Arc::new(countries));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+        let (mut conn, mut client) = in_memory_connection();
+
+        RelayRequest::new(Command::TCPConnect, Address::SocketAddress("175.16.199.1:443".parse().unwrap()))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        assert_ok!(handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await);
+
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::ConnectionNotAllowed, response.status());
+    }
+
+    #[tokio::test]
+    async fn refuse_client_over_connection_limit() {
+        let connections = Arc::new(LurkClientConnections::new(1));
+        let handler = test_handler();
+        let (conn, mut client) = in_memory_connection();
+        let peer_ip = conn.peer_addr().ip();
+        let open = connections.open(peer_ip).unwrap();
+        let conn = conn.with_client_connection(connections.open(peer_ip).map(Some));
+
+        let relay = tokio::spawn(async move { handler.handle(conn).await });
+        HandshakeRequest::new(HashSet::from([LurkAuthMethod::None]))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let response = HandshakeResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(Some(LurkAuthMethod::None), response.auth_method());
+
+        RelayRequest::new(Command::TCPConnect, Address::SocketAddress("192.0.2.1:443".parse().unwrap()))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::ConnectionNotAllowed, response.status());
+        assert_ok!(relay.await.unwrap());
+        drop(open);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bind_times_out() {
+        let (mut conn, mut client) = in_memory_connection();
+        let handler = test_handler();
+        let relay = tokio::spawn(async move { handler.process_relay_request(&mut conn, std::time::Instant::now(), None).await });
+
+        let expected = Address::SocketAddress("127.0.0.1:21".parse().unwrap());
+        RelayRequest::new(Command::TCPBind, expected).write_to(&mut client).await.unwrap();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::Succeeded, response.status());
+
+        // Nobody connects, so the second reply reports the failure.
+        let started = Instant::now();
+        let response = RelayResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(ReplyStatus::TtlExpired, response.status());
+        assert_eq!(LurkSocks5Handler::BIND_ACCEPT_TIMEOUT, started.elapsed());
+        assert_ok!(relay.await.unwrap());
+    }
+
+    /// Private method expecting the length-prefixed token.
+    struct LurkTokenAuth;
+
+    #[async_trait]
+    impl LurkPrivateAuthMethod for LurkTokenAuth {
+        fn code(&self) -> u8 {
+            0x80
+        }
+
+        async fn authenticate(&self, stream: &mut TcpStream, _peer_addr: SocketAddr) -> Result<Option<String>> {
+            let mut token = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut token).await?;
+
+            let valid = token == b"alice-token";
+            stream.write_u8(u8::from(!valid)).await?;
+            ensure!(valid, "invalid token");
+            Ok(Some("alice".to_owned()))
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_with_private_method() {
         let mut listener = LurkTcpListener::bind(TEST_BIND_IPV4).await.expect("Expect binded listener");
 
         let listener_addr = listener.local_addr();
         let client_handle = tokio::spawn(async move {
-            TcpStream::connect(listener_addr)
-                .and_then(|mut s| async move {
-                    // Send handshake request with auth methods.
-                    HandshakeRequest::new(HashSet::from([LurkAuthMethod::GssAPI, LurkAuthMethod::Password]))
-                        .write_to(&mut s)
-                        .await;
-
-                    // Read and verify handshake response.
-                    let actual = HandshakeResponse::read_from(&mut s).await;
-                    let reference = HandshakeResponse::builder().with_no_acceptable_method().build();
-
-                    assert_eq!(reference, actual);
-                    Ok(())
-                })
-                .await
-                .unwrap()
+            for (token, status) in [(b"wrong".as_slice(), 1), (b"alice-token".as_slice(), 0)] {
+                let mut s = TcpStream::connect(listener_addr).await.unwrap();
+                HandshakeRequest::new(HashSet::from([LurkAuthMethod::None, LurkAuthMethod::Private(0x80)]))
+                    .write_to(&mut s)
+                    .await
+                    .unwrap();
+
+                // Private method is preferred over the built-in one.
+                let actual = HandshakeResponse::read_from(&mut s).await.unwrap();
+                assert_eq!(
+                    HandshakeResponse::builder().with_auth_method(LurkAuthMethod::Private(0x80)).build(),
+                    actual
+                );
+
+                s.write_u8(token.len() as u8).await.unwrap();
+                s.write_all(token).await.unwrap();
+                assert_eq!(status, s.read_u8().await.unwrap());
+            }
         });
 
-        tokio::task::yield_now().await;
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+            .with_users(Arc::new(users))
+            .with_private_auth_methods(vec![Arc::new(LurkTokenAuth)]);
+        let handler = LurkSocks5Handler::new(Arc::new(context));
 
         let mut conn = listener.accept().await.expect("Expect created connection");
-        assert_eq!(LurkTcpConnectionLabel::Socks5, conn.label());
+        assert!(handler.process_handshake(&mut conn).await.is_err());
+
+        let mut conn = listener.accept().await.expect("Expect created connection");
+        assert_eq!(Some("alice".to_owned()), handler.process_handshake(&mut conn).await.unwrap());
+
+        assert_ok!(client_handle.into_future().await);
+    }
+
+    #[tokio::test]
+    async fn response_write_timeout() {
+        let write_timeout = Duration::from_millis(50);
+        let context = Arc::new(LurkHandlerContext::new(Arc::new(LurkServerStats::new()), write_timeout));
+        let handler = LurkSocks5Handler::new(Arc::clone(&context));
+
+        // Peer never reads, so response doesn't fit into 1-byte buffer.
+        let (mut stream, _peer) = tokio::io::duplex(1);
+        let response = HandshakeResponse::builder().with_no_acceptable_method().build();
+
         assert_lurk_err!(
-            LurkError::NoAcceptableAuthenticationMethod,
-            LurkSocks5Handler::process_handshake(&mut conn).await.expect_err("Expect error")
+            LurkError::ResponseWriteTimeout(write_timeout),
+            handler.write_response(&response, &mut stream).await.expect_err("Expect error")
         );
+        assert_eq!(1, context.stats().get_response_write_timeouts());
+    }
 
-        assert_ok!(client_handle.into_future().await);
+    #[tokio::test(start_paused = true)]
+    async fn response_write_timeout_on_partitioned_link() {
+        let write_timeout = Duration::from_secs(10);
+        let context = Arc::new(LurkHandlerContext::new(Arc::new(LurkServerStats::new()), write_timeout));
+        let handler = LurkSocks5Handler::new(Arc::clone(&context));
+
+        let options = LurkSimLinkOptions {
+            latency: Duration::from_millis(50),
+            window: 4,
+            ..LurkSimLinkOptions::default()
+        };
+        let (mut client, mut server, control) = sim::link(options);
+
+        let started = Instant::now();
+        HandshakeRequest::new(HashSet::from([LurkAuthMethod::None]))
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        HandshakeRequest::read_from(&mut server).await.unwrap();
+        assert_eq!(Duration::from_millis(50), started.elapsed());
+
+        // Client becomes unreachable, so the response doesn't fit into the window.
+        control.partition();
+        let response = RelayResponse::builder()
+            .with_success()
+            .with_bound_address("127.0.0.1:1080".parse().unwrap())
+            .build();
+        assert_lurk_err!(
+            LurkError::ResponseWriteTimeout(write_timeout),
+            handler.write_response(&response, &mut server).await.expect_err("Expect error")
+        );
+        assert_eq!(Duration::from_millis(50) + write_timeout, started.elapsed());
+        assert_eq!(1, context.stats().get_response_write_timeouts());
+    }
+
+    /// Links "client - proxy" and "proxy - destination" with the same faults.
+    /// Destination echoes everything back.
+    fn simulated_tunnel_links(options: LurkSimLinkOptions) -> (DuplexStream, DuplexStream, DuplexStream) {
+        let (client, inbound, _) = sim::link(options);
+        let (outbound, destination, _) = sim::link(LurkSimLinkOptions {
+            seed: options.seed + 100,
+            ..options
+        });
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(destination);
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        (client, inbound, outbound)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_duration_over_lossy_link() {
+        let users =
+            LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default()).with_class("trial")], false).with_classes([(
+                "trial".to_owned(),
+                LurkUserClass {
+                    max_session_secs: Some(1800),
+                },
+            )]);
+        let users = Arc::new(users);
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(10)).with_users(Arc::clone(&users));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+
+        let options = LurkSimLinkOptions {
+            latency: Duration::from_millis(40),
+            loss_rate: 0.1,
+            ..LurkSimLinkOptions::default()
+        };
+        let (mut client, mut inbound, mut outbound) = simulated_tunnel_links(options);
+
+        // Client chats once a minute for longer than the class allows.
+        let chat = tokio::spawn(async move {
+            loop {
+                client.write_all(b"ping").await?;
+                client.read_exact(&mut [0u8; 4]).await?;
+                sleep(Duration::from_secs(60)).await;
+            }
+            #[allow(unreachable_code)]
+            std::io::Result::Ok(())
+        });
+
+        let started = Instant::now();
+        let activity = Arc::new(LurkTunnelActivity::new());
+        let mut tunnel = LurkTunnel::new(&mut inbound, &mut outbound).with_activity(Arc::clone(&activity));
+        assert_lurk_err!(
+            LurkError::SessionDurationExceeded("alice".to_owned(), Duration::from_secs(1800)),
+            handler
+                .context
+                .run_tunnel(&mut tunnel, &activity, Some("alice"))
+                .await
+                .expect_err("Session should be closed")
+        );
+        assert_eq!(Duration::from_secs(1800), started.elapsed());
+
+        // Everything relayed before the session was closed is charged.
+        assert!(activity.l2r_bytes() > 0);
+        assert_eq!(
+            activity.l2r_bytes() + activity.r2l_bytes(),
+            users.get_usage("alice").unwrap().daily_bytes()
+        );
+        chat.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quota_closes_tunnel_over_lossy_link() {
+        let quota = LurkQuota {
+            daily_bytes: Some(16 * 1024),
+            monthly_bytes: None,
+        };
+        let users = Arc::new(LurkUserStore::new([LurkUser::new("alice", "secret", quota)], true));
+        let context = LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(10)).with_users(Arc::clone(&users));
+        let handler = LurkSocks5Handler::new(Arc::new(context));
+
+        let options = LurkSimLinkOptions {
+            latency: Duration::from_millis(80),
+            loss_rate: 0.2,
+            seed: 42,
+            ..LurkSimLinkOptions::default()
+        };
+        let (mut client, mut inbound, mut outbound) = simulated_tunnel_links(options);
+
+        // Client uploads 1 KiB every 100 ms, the quota is used up in less than a second.
+        let upload = tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(&mut client);
+            let drain = async { tokio::io::copy(&mut reader, &mut tokio::io::sink()).await };
+            let send = async {
+                loop {
+                    writer.write_all(&[0u8; 1024]).await?;
+                    sleep(Duration::from_millis(100)).await;
+                }
+                #[allow(unreachable_code)]
+                std::io::Result::Ok(0)
+            };
+            tokio::try_join!(drain, send)
+        });
+
+        let started = Instant::now();
+        let activity = Arc::new(LurkTunnelActivity::new());
+        let mut tunnel = LurkTunnel::new(&mut inbound, &mut outbound).with_activity(Arc::clone(&activity));
+        assert_lurk_err!(
+            LurkError::QuotaExceeded("alice".to_owned()),
+            handler
+                .context
+                .run_tunnel(&mut tunnel, &activity, Some("alice"))
+                .await
+                .expect_err("Session should be closed")
+        );
+
+        // Quota is checked on every charge, so the tunnel lives a couple of charge intervals at most.
+        assert!(started.elapsed() <= 2 * LurkHandlerContext::QUOTA_CHARGE_INTERVAL);
+        assert!(users.get_usage("alice").unwrap().daily_bytes() >= 16 * 1024);
+        upload.abort();
     }
 }
@@ -0,0 +1,194 @@
+use super::LurkHandlerContext;
+use crate::{
+    common::{error::LurkError, logging},
+    io::tunnel::LurkTunnel,
+    net::{
+        tcp::connection::{LurkTcpConnection, LurkTcpConnectionHandler, LurkTcpConnectionLabel},
+        Address,
+    },
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use human_bytes::human_bytes;
+use log::{debug, error, info};
+use std::{sync::Arc, time::Instant};
+use tokio::io::AsyncWriteExt;
+
+/// Handler of connections redirected to the transparent listener by the firewall
+/// (iptables REDIRECT or TPROXY).
+///
+/// Client doesn't know it's proxied, so there is no handshake: connection is tunneled to its
+/// original destination right away, or just closed if it can't be. Like SOCKS4, the clients
+/// have no way to authenticate, so they are served only while the server accepts
+/// unauthenticated SOCKS5 clients as well.
+pub struct LurkTransparentHandler {
+    context: Arc<LurkHandlerContext>,
+}
+
+impl LurkTransparentHandler {
+    pub fn new(context: Arc<LurkHandlerContext>) -> LurkTransparentHandler {
+        LurkTransparentHandler { context }
+    }
+
+    /// Establishing the tunnel "client <-- lurk proxy --> original destination".
+    async fn relay(&self, conn: &mut LurkTcpConnection) -> Result<()> {
+        let conn_peer_addr = conn.peer_addr();
+        let conn_bound_addr = conn.local_addr();
+        let conn_activity = conn.activity();
+        let Some(original_destination) = conn.original_destination() else {
+            bail!("Original destination of the transparent connection is unknown")
+        };
+        let address = Address::SocketAddress(original_destination);
+        conn.session().set_destination(&address);
+
+        if !self.context.is_unauthenticated_allowed() {
+            bail!(LurkError::NoAcceptableAuthenticationMethod)
+        }
+
+        info!("Transparent connection from peer {} to {}", conn_peer_addr, address);
+
+        let stats = self.context.stats();
+        let destinations = stats.destinations();
+        let host = address.host();
+
+        if let Some(reason) = self.context.deny_reason(&host) {
+            bail!(LurkError::DestinationBlocked(host, reason))
+        }
+
+        let connect_started = Instant::now();
+        let mut outbound_stream = match self.context.connect_for(&address, None, conn_peer_addr.ip()).await {
+            Ok(outbound_stream) => {
+                stats.connect_latency().observe(connect_started.elapsed());
+                outbound_stream
+            }
+            Err(err) => {
+                destinations.on_failure(&host);
+                return Err(err);
+            }
+        };
+
+        let inbound_stream = conn.stream_mut();
+        let tunnel = LurkTunnel::new(inbound_stream, &mut outbound_stream).with_activity(Arc::clone(&conn_activity));
+        let mut tunnel = self.context.configure_tunnel(tunnel, conn_peer_addr, &address, None);
+
+        logging::log_tunnel_created!(conn_peer_addr, conn_bound_addr, address);
+
+        let tunnel_started = Instant::now();
+        let tunnel_result = tunnel.run().await;
+        stats.tunnel_lifetime().observe(tunnel_started.elapsed());
+
+        match tunnel_result {
+            Ok((l2r, r2l)) => {
+                logging::log_tunnel_closed!(conn_peer_addr, conn_bound_addr, address, l2r, r2l);
+                destinations.on_session_finished(&host, l2r, r2l);
+            }
+            Err(err) => {
+                logging::log_tunnel_closed_with_error!(conn_peer_addr, conn_bound_addr, address, err);
+                destinations.on_session_finished(&host, conn_activity.l2r_bytes(), conn_activity.r2l_bytes());
+
+                if err.downcast_ref::<LurkError>().is_some() {
+                    self.context.on_tunnel_error(&err);
+                    let _ = outbound_stream.shutdown().await;
+                    let _ = inbound_stream.shutdown().await;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LurkTcpConnectionHandler for LurkTransparentHandler {
+    async fn handle(&self, mut conn: LurkTcpConnection) -> Result<()> {
+        debug_assert_eq!(LurkTcpConnectionLabel::Transparent, conn.label(), "expected transparent label");
+        let _client_connection = conn.take_client_connection()?;
+        let result = self.relay(&mut conn).await;
+
+        // Destination could be denied both by its address and by the IP it's connected to.
+        if let Some(LurkError::DestinationBlocked(_, reason)) = result.as_ref().err().and_then(|err| err.downcast_ref()) {
+            conn.session().set_deny_reason(reason);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{
+            quota::LurkQuota,
+            users::{LurkUser, LurkUserStore},
+        },
+        net::tcp::connection::LurkTcpConnectionFactory,
+        server::{ssrf::LurkSsrfGuard, stats::LurkServerStats},
+    };
+    use pretty_assertions::assert_eq;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, DuplexStream},
+        net::TcpListener,
+    };
+
+    fn test_context() -> LurkHandlerContext {
+        LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+    }
+
+    fn redirected_connection(original_destination: SocketAddr) -> (LurkTcpConnection, DuplexStream) {
+        let (conn, client) = LurkTcpConnectionFactory::create_in_memory_connection(
+            LurkTcpConnectionLabel::Transparent,
+            "127.0.0.1:50000".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+        );
+        (conn.with_original_destination(original_destination), client)
+    }
+
+    #[tokio::test]
+    async fn relay_to_original_destination() {
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handler = LurkTransparentHandler::new(Arc::new(test_context()));
+        let (conn, mut client) = redirected_connection(endpoint.local_addr().unwrap());
+
+        // Destination speaks first, while the client is waiting for it.
+        let server = tokio::spawn(async move { handler.handle(conn).await });
+        let (mut endpoint_stream, _) = endpoint.accept().await.unwrap();
+        endpoint_stream.write_all(b"220 ready").await.unwrap();
+        let mut buff = [0u8; 9];
+        client.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"220 ready", &buff);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buff = [0u8; 4];
+        endpoint_stream.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"ping", &buff);
+
+        drop(client);
+        drop(endpoint_stream);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuse_denied_destination() {
+        let context = test_context().with_ssrf_guard(Arc::new(LurkSsrfGuard::new(Vec::new())));
+        let handler = LurkTransparentHandler::new(Arc::new(context));
+        let (conn, _client) = redirected_connection("127.0.0.1:25".parse().unwrap());
+        let session = conn.session();
+
+        let err = handler.handle(conn).await.expect_err("destination should be denied");
+        assert!(matches!(err.downcast_ref::<LurkError>(), Some(LurkError::DestinationBlocked(..))));
+        assert_eq!(Some("127.0.0.1:25"), session.destination());
+        assert!(session.deny_reason().is_some());
+    }
+
+    #[tokio::test]
+    async fn refuse_when_authentication_is_required() {
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let handler = LurkTransparentHandler::new(Arc::new(test_context().with_users(Arc::new(users))));
+        let (conn, _client) = redirected_connection("192.0.2.1:443".parse().unwrap());
+
+        let err = handler.handle(conn).await.expect_err("client should be refused");
+        assert_eq!(Some(&LurkError::NoAcceptableAuthenticationMethod), err.downcast_ref::<LurkError>());
+    }
+}
@@ -0,0 +1,625 @@
+use super::{
+    handlers::{
+        http::{utils, LurkHttpHandler},
+        LurkHandlerContext,
+    },
+    stats::node::LurkListenerKind,
+};
+use crate::{
+    common::error::LurkError,
+    io::tunnel::{LurkTunnel, LurkTunnelActivity},
+    net::{
+        tcp::{connection::LurkSessionInfo, listener::LurkClientAccess},
+        tls::{self, LurkCertResolver, LurkSniCert, LurkTlsPolicy},
+    },
+};
+use anyhow::{bail, Context as _, Result};
+use bytes::{Buf, Bytes};
+use h3::{error::StreamError, server::RequestStream};
+use hyper::{Method, Request, Response, StatusCode};
+use log::{debug, error, info, warn};
+use quinn::crypto::rustls::QuicServerConfig;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Request stream of the HTTP/3 connection accepted by the listener.
+type LurkHttp3Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Sending half of the request stream.
+type LurkHttp3SendStream = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+/// Data being sent over the sending half, which is given back once it's done.
+type LurkHttp3Sending = Pin<Box<dyn Future<Output = (Box<LurkHttp3SendStream>, Result<(), StreamError>)> + Send>>;
+
+/// Settings of the experimental HTTP/3 (QUIC) listener.
+///
+/// **Fields**:
+/// * ```bind_addr``` - UDP address the listener is bound to
+/// * ```cert_path``` - PEM file with the certificate chain presented to the clients
+/// * ```key_path``` - PEM file with the private key of the certificate
+/// * ```sni_certs``` - certificates presented instead of the default one to the clients asking for their server names
+/// * ```tls_policy``` - TLS versions, cipher suites and session resumption allowed to the clients
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkHttp3Options {
+    bind_addr: SocketAddr,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    sni_certs: Vec<LurkSniCert>,
+    tls_policy: LurkTlsPolicy,
+}
+
+impl LurkHttp3Options {
+    pub fn new(bind_addr: SocketAddr, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> LurkHttp3Options {
+        LurkHttp3Options {
+            bind_addr,
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            sni_certs: Vec::new(),
+            tls_policy: LurkTlsPolicy::default(),
+        }
+    }
+
+    pub fn set_sni_certs(&mut self, sni_certs: Vec<LurkSniCert>) -> &mut LurkHttp3Options {
+        self.sni_certs = sni_certs;
+        self
+    }
+
+    pub fn set_tls_policy(&mut self, tls_policy: LurkTlsPolicy) -> &mut LurkHttp3Options {
+        self.tls_policy = tls_policy;
+        self
+    }
+}
+
+/// Listener serving CONNECT requests over HTTP/3 (e.g. MASQUE-style clients).
+///
+/// Every accepted CONNECT stream is tunneled to the destination the same way
+/// HTTP/1.1 CONNECT is: clients are authenticated with Basic credentials and
+/// destinations are checked against the policy and blocklists.
+pub(crate) struct LurkHttp3Listener {
+    endpoint: quinn::Endpoint,
+    context: Arc<LurkHandlerContext>,
+    certs: Arc<LurkCertResolver>,
+    ocsp_refresh_interval: Option<Duration>,
+    client_access: LurkClientAccess,
+}
+
+impl LurkHttp3Listener {
+    /// Application protocol negotiated with the clients during TLS handshake.
+    const ALPN: &'static [u8] = b"h3";
+
+    /// Streams aren't watched by the watchdog, so tunnels which have relayed nothing for this period are closed.
+    const TUNNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+    pub fn bind(
+        options: &LurkHttp3Options,
+        client_access: &LurkClientAccess,
+        context: Arc<LurkHandlerContext>,
+    ) -> Result<LurkHttp3Listener> {
+        let certs = Arc::new(LurkCertResolver::load(&options.cert_path, &options.key_path, &options.sni_certs)?);
+        let server_config = Self::server_config(Arc::clone(&certs), &options.tls_policy)?;
+        let endpoint = quinn::Endpoint::server(server_config, options.bind_addr)
+            .with_context(|| format!("failed to bind HTTP/3 listener on {}", options.bind_addr))?;
+
+        let bound_addr = endpoint.local_addr()?;
+        info!("HTTP/3 proxy is listening on {}", bound_addr);
+        context.stats().on_listener_bound(LurkListenerKind::Http3, bound_addr);
+
+        Ok(LurkHttp3Listener {
+            endpoint,
+            context,
+            certs,
+            ocsp_refresh_interval: options.tls_policy.ocsp_refresh_interval(),
+            client_access: client_access.clone(),
+        })
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Certificates presented to the clients.
+    #[cfg(feature = "acme")]
+    pub fn certs(&self) -> Arc<LurkCertResolver> {
+        Arc::clone(&self.certs)
+    }
+
+    /// Accept QUIC connections until the token is cancelled. Connections are served by the tracked tasks.
+    pub async fn run(self, task_tracker: TaskTracker, token: CancellationToken) {
+        if let Some(refresh_interval) = self.ocsp_refresh_interval {
+            let (certs, token) = (Arc::clone(&self.certs), token.clone());
+            task_tracker.spawn(async move { certs.run_ocsp_stapling(refresh_interval, token).await });
+        }
+
+        loop {
+            let incoming = tokio::select! {
+                incoming = self.endpoint.accept() => incoming,
+                _ = token.cancelled() => break,
+            };
+            let Some(incoming) = incoming else {
+                break;
+            };
+
+            // Clients, which aren't allowed or are over their limit of connections, are refused before QUIC handshake.
+            // Connection is accounted to its client until it's closed.
+            let peer_addr = incoming.remote_address();
+            let admitted = self
+                .client_access
+                .check(peer_addr.ip())
+                .and_then(|_| self.client_access.open_connection(peer_addr.ip()));
+            let client_connection = match admitted {
+                Ok(client_connection) => client_connection,
+                Err(err) => {
+                    debug!("HTTP/3 connection from {} is refused: {}", peer_addr, err);
+                    match err.downcast_ref::<LurkError>() {
+                        Some(LurkError::ClientThrottled(..)) => self.context.stats().on_connection_throttled(),
+                        Some(LurkError::ClientConnectionLimitExceeded(..)) => {}
+                        _ => self.context.stats().on_client_denied(),
+                    }
+                    incoming.refuse();
+                    continue;
+                }
+            };
+
+            let (context, tracker) = (Arc::clone(&self.context), task_tracker.clone());
+            task_tracker.spawn(async move {
+                let _client_connection = client_connection;
+                if let Err(err) = Self::serve_connection(incoming, context, tracker).await {
+                    error!("Error occurred while serving HTTP/3 connection from {}: {}", peer_addr, err);
+                }
+            });
+        }
+
+        self.endpoint.close(0u32.into(), b"");
+        self.endpoint.wait_idle().await;
+    }
+
+    fn server_config(certs: Arc<LurkCertResolver>, tls_policy: &LurkTlsPolicy) -> Result<quinn::ServerConfig> {
+        // QUIC requires TLS 1.3, so the oldest version allowed by the policy doesn't matter. Its initial
+        // packets are protected with TLS13_AES_128_GCM_SHA256, so the policy can't leave this suite out.
+        let tls_config = tls::server_config(certs, &[Self::ALPN], tls_policy)?;
+        let crypto = QuicServerConfig::try_from(tls_config)?;
+        Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+    }
+
+    async fn serve_connection(incoming: quinn::Incoming, context: Arc<LurkHandlerContext>, task_tracker: TaskTracker) -> Result<()> {
+        let conn = incoming.await?;
+        let peer_addr = conn.remote_address();
+        debug!("HTTP/3 connection from {} is established", peer_addr);
+
+        let mut h3_conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+        loop {
+            match h3_conn.accept().await {
+                Ok(Some(resolver)) => {
+                    let context = Arc::clone(&context);
+                    task_tracker.spawn(async move {
+                        let result = match resolver.resolve_request().await {
+                            Ok((request, stream)) => Self::serve_request(request, stream, peer_addr, context).await,
+                            Err(err) => Err(err.into()),
+                        };
+                        if let Err(err) = result {
+                            error!("Error occurred while serving HTTP/3 request of {}: {}", peer_addr, err);
+                        }
+                    });
+                }
+                Ok(None) => return Ok(()),
+                Err(err) if err.is_h3_no_error() => return Ok(()),
+                Err(err) => bail!(err),
+            }
+        }
+    }
+
+    async fn serve_request(
+        mut request: Request<()>,
+        mut stream: LurkHttp3Stream,
+        peer_addr: SocketAddr,
+        context: Arc<LurkHandlerContext>,
+    ) -> Result<()> {
+        let request_started = Instant::now();
+        info!("{:?} {} '{}'", request.version(), request.method(), request.uri());
+
+        // Extended CONNECT (e.g. CONNECT-UDP, WebTransport) isn't supported yet.
+        if request.method() != Method::CONNECT || request.extensions().get::<h3::ext::Protocol>().is_some() {
+            return Self::respond(&mut stream, StatusCode::NOT_IMPLEMENTED).await;
+        }
+
+        let session = LurkSessionInfo::default();
+        if let Some(response) = LurkHttpHandler::authenticate(&mut request, peer_addr, &session, &context) {
+            return Self::send(&mut stream, response.map(|_| ())).await;
+        }
+
+        // Session of the user is accounted till the tunnel is closed.
+        let _user_session = match LurkHttpHandler::open_user_session(&session, &context) {
+            Ok(user_session) => user_session,
+            Err(err) => {
+                warn!("Refusing request of {}: {}", peer_addr, err);
+                return Self::respond(&mut stream, StatusCode::TOO_MANY_REQUESTS).await;
+            }
+        };
+
+        let Some(remote_addr) = utils::get_host_addr(&mut request) else {
+            error!("Failed to get remote host address");
+            return Self::respond(&mut stream, StatusCode::BAD_REQUEST).await;
+        };
+        let remote_host = remote_addr.host();
+
+        if let Some(reason) = context.deny_reason(&remote_host) {
+            warn!("Refusing request to {}, it's denied by {}", remote_host, reason);
+            return Self::respond(&mut stream, StatusCode::FORBIDDEN).await;
+        }
+
+        let stats = context.stats();
+        let connect_started = Instant::now();
+        let mut outbound = match context.connect_for(&remote_addr, session.user(), peer_addr.ip()).await {
+            Ok(outbound) => {
+                stats.connect_latency().observe(connect_started.elapsed());
+                outbound
+            }
+            Err(err) => {
+                error!("Failed to establish outbound TCP connection: {}", err);
+                stats.destinations().on_failure(&remote_host);
+                let status = match err.downcast_ref::<LurkError>() {
+                    Some(LurkError::DestinationBlocked(..)) => StatusCode::FORBIDDEN,
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                return Self::respond(&mut stream, status).await;
+            }
+        };
+
+        Self::respond(&mut stream, StatusCode::OK).await?;
+
+        // Tunnel is run the same way as HTTP/1.1 CONNECT one, bytes relayed by the authenticated user are charged to its quota.
+        let activity = Arc::new(LurkTunnelActivity::new());
+        let mut inbound = LurkHttp3Io::new(stream);
+        let tunnel = LurkTunnel::new(&mut inbound, &mut outbound)
+            .with_activity(Arc::clone(&activity))
+            .with_idle_timeout(Self::TUNNEL_IDLE_TIMEOUT);
+        let mut tunnel = context.configure_tunnel(tunnel, peer_addr, &remote_addr, session.user());
+        stats.handshake_duration().observe(request_started.elapsed());
+        debug!("HTTP/3 tunnel to {} is created", remote_addr);
+
+        let tunnel_started = Instant::now();
+        let tunnel_result = context.run_tunnel(&mut tunnel, &activity, session.user()).await;
+        stats.tunnel_lifetime().observe(tunnel_started.elapsed());
+
+        if let Err(err) = tunnel_result {
+            context.on_tunnel_error(&err);
+            error!("Error occurred while HTTP/3 tunnel was running: {}", err);
+        }
+        // Data relayed before the failure is accounted as well.
+        stats
+            .destinations()
+            .on_session_finished(&remote_host, activity.l2r_bytes(), activity.r2l_bytes());
+
+        Ok(())
+    }
+
+    async fn respond(stream: &mut LurkHttp3Stream, status: StatusCode) -> Result<()> {
+        let response = Response::builder().status(status).body(()).expect("HTTP response was not built");
+        Self::send(stream, response).await
+    }
+
+    /// Send response head. Stream is finished unless the tunnel follows it.
+    async fn send(stream: &mut LurkHttp3Stream, response: Response<()>) -> Result<()> {
+        let status = response.status();
+        stream.send_response(response).await?;
+        if status != StatusCode::OK {
+            stream.finish().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Request stream of the CONNECT tunnel as the byte stream, so it's relayed by [`LurkTunnel`]. Written data is sent
+/// to the client in the background, it's flushed once the following write, flush or shutdown is complete.
+struct LurkHttp3Io {
+    recv_stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+    received: Bytes,
+    send_state: LurkHttp3SendState,
+}
+
+enum LurkHttp3SendState {
+    Idle(Box<LurkHttp3SendStream>),
+    Sending(LurkHttp3Sending),
+    Finishing(Pin<Box<dyn Future<Output = Result<(), StreamError>> + Send>>),
+    Finished,
+}
+
+impl LurkHttp3Io {
+    fn new(stream: LurkHttp3Stream) -> LurkHttp3Io {
+        let (send_stream, recv_stream) = stream.split();
+        LurkHttp3Io {
+            recv_stream,
+            received: Bytes::new(),
+            send_state: LurkHttp3SendState::Idle(Box::new(send_stream)),
+        }
+    }
+
+    /// Wait until data written before is sent.
+    fn poll_sent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let LurkHttp3SendState::Sending(sending) = &mut self.send_state {
+            let (send_stream, result) = ready!(sending.as_mut().poll(cx));
+            self.send_state = LurkHttp3SendState::Idle(send_stream);
+            result.map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for LurkHttp3Io {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.received.is_empty() {
+            match ready!(self.recv_stream.poll_recv_data(cx)) {
+                Ok(Some(mut chunk)) => self.received = chunk.copy_to_bytes(chunk.remaining()),
+                Ok(None) => return Poll::Ready(Ok(())),
+                Err(err) => return Poll::Ready(Err(io::Error::other(err))),
+            }
+        }
+
+        let n = buf.remaining().min(self.received.len());
+        buf.put_slice(&self.received.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for LurkHttp3Io {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_sent(cx))?;
+        let LurkHttp3SendState::Idle(mut send_stream) = std::mem::replace(&mut self.send_state, LurkHttp3SendState::Finished) else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+
+        let data = Bytes::copy_from_slice(buf);
+        self.send_state = LurkHttp3SendState::Sending(Box::pin(async move {
+            let result = send_stream.send_data(data).await;
+            (send_stream, result)
+        }));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_sent(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match std::mem::replace(&mut self.send_state, LurkHttp3SendState::Finished) {
+                sending @ LurkHttp3SendState::Sending(_) => {
+                    self.send_state = sending;
+                    ready!(self.poll_sent(cx))?;
+                }
+                LurkHttp3SendState::Idle(mut send_stream) => {
+                    self.send_state = LurkHttp3SendState::Finishing(Box::pin(async move { send_stream.finish().await }));
+                }
+                LurkHttp3SendState::Finishing(mut finishing) => {
+                    let Poll::Ready(result) = finishing.as_mut().poll(cx) else {
+                        self.send_state = LurkHttp3SendState::Finishing(finishing);
+                        return Poll::Pending;
+                    };
+                    return Poll::Ready(result.map_err(io::Error::other));
+                }
+                LurkHttp3SendState::Finished => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{
+            quota::LurkQuota,
+            users::{LurkUser, LurkUserStore},
+        },
+        server::stats::LurkServerStats,
+    };
+    use bytes::BytesMut;
+    use h3::client::SendRequest;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::pki_types::CertificateDer;
+    use std::future::poll_fn;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::{sleep, timeout},
+    };
+
+    type LurkHttp3Client = SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+    fn test_context() -> LurkHandlerContext {
+        LurkHandlerContext::new(Arc::new(LurkServerStats::new()), Duration::from_secs(1))
+    }
+
+    /// Start the listener with the self-signed certificate. Returns the certificate clients should trust.
+    fn start_listener(name: &str, context: LurkHandlerContext) -> (SocketAddr, CertificateDer<'static>, CancellationToken) {
+        start_listener_with_access(name, &LurkClientAccess::default(), context)
+    }
+
+    fn start_listener_with_access(
+        name: &str,
+        client_access: &LurkClientAccess,
+        context: LurkHandlerContext,
+    ) -> (SocketAddr, CertificateDer<'static>, CancellationToken) {
+        let dir = std::env::temp_dir().join(format!("lurk-http3-{}-{}", name, std::process::id()));
+        let (cert_path, key_path, cert) = tls::write_self_signed_cert(&dir, "localhost");
+
+        let options = LurkHttp3Options::new("127.0.0.1:0".parse().unwrap(), cert_path, key_path);
+        let listener = LurkHttp3Listener::bind(&options, client_access, Arc::new(context)).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let token = CancellationToken::new();
+        tokio::spawn(listener.run(TaskTracker::new(), token.clone()));
+
+        (listener_addr, cert, token)
+    }
+
+    fn client_endpoint(cert: CertificateDer<'static>) -> quinn::Endpoint {
+        let tls_config = tls::client_config(cert, &[LurkHttp3Listener::ALPN]);
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config).unwrap())));
+        endpoint
+    }
+
+    async fn connect_client(listener_addr: SocketAddr, cert: CertificateDer<'static>) -> (quinn::Endpoint, LurkHttp3Client) {
+        let endpoint = client_endpoint(cert);
+        let conn = endpoint.connect(listener_addr, "localhost").unwrap().await.unwrap();
+
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn)).await.unwrap();
+        tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+
+        (endpoint, send_request)
+    }
+
+    fn connect_request(authority: &str) -> Request<()> {
+        Request::builder().method(Method::CONNECT).uri(authority).body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connect_and_relay() {
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+        let (listener_addr, cert, token) = start_listener("relay", test_context());
+        let (_client_endpoint, mut client) = connect_client(listener_addr, cert).await;
+
+        let mut stream = client.send_request(connect_request(&endpoint_addr.to_string())).await.unwrap();
+        let (mut endpoint_stream, _) = endpoint.accept().await.unwrap();
+        assert_eq!(StatusCode::OK, stream.recv_response().await.unwrap().status());
+
+        stream.send_data(Bytes::from_static(b"ping")).await.unwrap();
+        let mut buff = [0u8; 4];
+        endpoint_stream.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"ping", &buff);
+
+        endpoint_stream.write_all(b"pong").await.unwrap();
+        drop(endpoint_stream);
+        let mut received = BytesMut::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            received.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(b"pong"[..], received[..]);
+
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn charge_relayed_bytes_to_user() {
+        let users = Arc::new(LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false));
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+        let (listener_addr, cert, token) = start_listener("charge", test_context().with_users(Arc::clone(&users)));
+        let (_client_endpoint, mut client) = connect_client(listener_addr, cert).await;
+
+        // "YWxpY2U6c2VjcmV0" is "alice:secret".
+        let mut request = connect_request(&endpoint_addr.to_string());
+        request
+            .headers_mut()
+            .insert(hyper::header::PROXY_AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        let mut stream = client.send_request(request).await.unwrap();
+        let (mut endpoint_stream, _) = endpoint.accept().await.unwrap();
+        assert_eq!(StatusCode::OK, stream.recv_response().await.unwrap().status());
+
+        stream.send_data(Bytes::from_static(b"ping")).await.unwrap();
+        stream.finish().await.unwrap();
+        let mut received = Vec::new();
+        endpoint_stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(b"ping", received.as_slice());
+        endpoint_stream.write_all(b"pong").await.unwrap();
+        drop(endpoint_stream);
+        while stream.recv_data().await.unwrap().is_some() {}
+
+        // Session is closed and charged once both directions are finished.
+        timeout(Duration::from_secs(5), async {
+            while users.active_sessions("alice") > 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session should be closed");
+        assert_eq!(8, users.get_usage("alice").unwrap().daily_bytes());
+
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn require_proxy_authentication() {
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let (listener_addr, cert, token) = start_listener("auth", test_context().with_users(Arc::new(users)));
+        let (_client_endpoint, mut client) = connect_client(listener_addr, cert).await;
+
+        let mut stream = client.send_request(connect_request("127.0.0.1:80")).await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(StatusCode::PROXY_AUTHENTICATION_REQUIRED, response.status());
+        assert_eq!(utils::BASIC_AUTH_CHALLENGE, response.headers()[hyper::header::PROXY_AUTHENTICATE]);
+
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn refuse_plain_requests() {
+        let (listener_addr, cert, token) = start_listener("plain", test_context());
+        let (_client_endpoint, mut client) = connect_client(listener_addr, cert).await;
+
+        let request = Request::builder().uri("https://example.com/").body(()).unwrap();
+        let mut stream = client.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        assert_eq!(StatusCode::NOT_IMPLEMENTED, stream.recv_response().await.unwrap().status());
+
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn refuse_denied_clients() {
+        let stats = Arc::new(LurkServerStats::new());
+        let context = LurkHandlerContext::new(Arc::clone(&stats), Duration::from_secs(1));
+        let client_access = LurkClientAccess::new(Vec::new(), vec!["127.0.0.0/8".parse().unwrap()]);
+        let (listener_addr, cert, token) = start_listener_with_access("denied", &client_access, context);
+
+        let endpoint = client_endpoint(cert);
+        let connecting = endpoint.connect(listener_addr, "localhost").unwrap();
+        assert!(timeout(Duration::from_secs(5), connecting).await.unwrap().is_err());
+        assert_eq!(1, stats.get_denied_clients());
+
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn limit_connections_per_client() {
+        let mut client_access = LurkClientAccess::default();
+        client_access.set_max_connections(1);
+        let (listener_addr, cert, token) = start_listener_with_access("limit", &client_access, test_context());
+
+        let (first_endpoint, _first_client) = connect_client(listener_addr, cert.clone()).await;
+        let endpoint = client_endpoint(cert.clone());
+        let connecting = endpoint.connect(listener_addr, "localhost").unwrap();
+        assert!(timeout(Duration::from_secs(5), connecting).await.unwrap().is_err());
+
+        // Slot of the client is released once its connection is closed.
+        first_endpoint.close(0u32.into(), b"");
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let endpoint = client_endpoint(cert.clone());
+                if endpoint.connect(listener_addr, "localhost").unwrap().await.is_ok() {
+                    break;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("client should be allowed to connect again");
+
+        token.cancel();
+    }
+}
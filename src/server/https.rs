@@ -0,0 +1,436 @@
+use super::{stats::node::LurkListenerKind, LurkAcceptor};
+use crate::net::{
+    ktls::{self, LurkKtlsStream},
+    tcp::{
+        connection::{LurkTcpConnection, LurkTcpConnectionFactory, LurkTcpConnectionLabel},
+        listener::{self, LurkClientAccess, LurkTcpListenerOptions},
+    },
+    tls::{self, LurkCertResolver, LurkSniCert, LurkTlsPolicy},
+};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{net::TcpListener, time::timeout};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+/// Settings of the HTTPS proxy listener.
+///
+/// **Fields**:
+/// * ```bind_addr``` - TCP address the listener is bound to
+/// * ```cert_path``` - PEM file with the certificate chain presented to the clients
+/// * ```key_path``` - PEM file with the private key of the certificate
+/// * ```sni_certs``` - certificates presented instead of the default one to the clients asking for their server names
+/// * ```tls_policy``` - TLS versions, cipher suites and session resumption allowed to the clients
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkHttpsOptions {
+    bind_addr: SocketAddr,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    sni_certs: Vec<LurkSniCert>,
+    tls_policy: LurkTlsPolicy,
+}
+
+impl LurkHttpsOptions {
+    pub fn new(bind_addr: SocketAddr, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> LurkHttpsOptions {
+        LurkHttpsOptions {
+            bind_addr,
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            sni_certs: Vec::new(),
+            tls_policy: LurkTlsPolicy::default(),
+        }
+    }
+
+    pub fn set_sni_certs(&mut self, sni_certs: Vec<LurkSniCert>) -> &mut LurkHttpsOptions {
+        self.sni_certs = sni_certs;
+        self
+    }
+
+    pub fn set_tls_policy(&mut self, tls_policy: LurkTlsPolicy) -> &mut LurkHttpsOptions {
+        self.tls_policy = tls_policy;
+        self
+    }
+}
+
+/// Listener of the HTTP proxy clients connecting over TLS (i.e. "https://" proxies).
+///
+/// TLS is terminated by the listener, so the connections are served by the HTTP
+/// handler and accounted as the plain HTTP ones accepted by the proxy listener.
+pub(crate) struct LurkHttpsListener {
+    tcp_listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    certs: Arc<LurkCertResolver>,
+    ocsp_refresh_interval: Option<Duration>,
+    kernel_offload: bool,
+    client_access: LurkClientAccess,
+}
+
+impl LurkHttpsListener {
+    /// Application protocol negotiated with the clients, which support ALPN.
+    const ALPN: &'static [u8] = b"http/1.1";
+
+    /// Protocols offered to the clients. ACME CA negotiates its own one to validate the TLS-ALPN-01 challenge.
+    #[cfg(feature = "acme")]
+    const ALPN_PROTOCOLS: &'static [&'static [u8]] = &[Self::ALPN, tls::ACME_TLS_ALPN];
+    #[cfg(not(feature = "acme"))]
+    const ALPN_PROTOCOLS: &'static [&'static [u8]] = &[Self::ALPN];
+
+    /// Time given to the client to complete TLS handshake.
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn bind(options: &LurkHttpsOptions, listener_options: &LurkTcpListenerOptions) -> Result<LurkHttpsListener> {
+        let certs = Arc::new(LurkCertResolver::load(&options.cert_path, &options.key_path, &options.sni_certs)?);
+        let tls_config = tls::server_config(Arc::clone(&certs), Self::ALPN_PROTOCOLS, &options.tls_policy)?;
+        let tcp_listener = listener::bind_tcp_listener(options.bind_addr, listener_options)
+            .with_context(|| format!("failed to bind HTTPS listener on {}", options.bind_addr))?;
+
+        Ok(LurkHttpsListener {
+            tcp_listener,
+            tls_acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+            certs,
+            ocsp_refresh_interval: options.tls_policy.ocsp_refresh_interval(),
+            kernel_offload: options.tls_policy.kernel_offload(),
+            client_access: listener_options.client_access().clone(),
+        })
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+
+    /// Certificates presented to the clients.
+    #[cfg(feature = "acme")]
+    pub fn certs(&self) -> Arc<LurkCertResolver> {
+        Arc::clone(&self.certs)
+    }
+
+    /// Accept connections until the server is shut down. TLS handshakes run in the separate tasks,
+    /// then connections are dispatched to the handler the same way plain ones are.
+    pub async fn run(self, acceptor: LurkAcceptor) {
+        let bound_addr = self.tcp_listener.local_addr().expect("listener doesn't have local address");
+        info!("HTTPS proxy is listening on {}", bound_addr);
+        acceptor.stats.on_listener_bound(LurkListenerKind::Https, bound_addr);
+
+        if let Some(refresh_interval) = self.ocsp_refresh_interval {
+            let (certs, token) = (Arc::clone(&self.certs), acceptor.task_cancellation_token.clone());
+            acceptor
+                .task_tracker
+                .spawn(async move { certs.run_ocsp_stapling(refresh_interval, token).await });
+        }
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.tcp_listener.accept() => accepted,
+                _ = acceptor.task_cancellation_token.cancelled() => break,
+            };
+
+            // Clients, which aren't allowed, are dropped before TLS handshake.
+            let accepted = match accepted {
+                Ok((tcp_stream, peer_addr)) => self.client_access.check(peer_addr.ip()).map(|_| (tcp_stream, peer_addr)),
+                Err(err) => Err(err.into()),
+            };
+            let (tcp_stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    if acceptor.on_tcp_acception_error(err).await {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            // Connection is accounted to its client before TLS handshake, the one over the limit is refused by the handler.
+            let client_connection = self.client_access.open_connection(peer_addr.ip());
+            let (tls_acceptor, acceptor_clone, kernel_offload) = (self.tls_acceptor.clone(), acceptor.clone(), self.kernel_offload);
+            acceptor.task_tracker.spawn(async move {
+                let tcp_stream = LurkKtlsStream::new(tcp_stream, kernel_offload);
+                let conn = match timeout(Self::HANDSHAKE_TIMEOUT, tls_acceptor.accept(tcp_stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        Self::create_connection(tls_stream, kernel_offload).map(|conn| conn.with_client_connection(client_connection))
+                    }
+                    Ok(Err(err)) => Err(err.into()),
+                    Err(_) => Err(anyhow!("TLS handshake has timed out")),
+                };
+                match conn {
+                    Ok(conn) => acceptor_clone.on_tcp_connection_established(conn).await,
+                    Err(err) => warn!("Failed to establish TLS session with {}: {}", peer_addr, err),
+                }
+            });
+        }
+    }
+
+    /// Connection served over the session established with the client. Session is handed over
+    /// to the kernel, if it's asked to and the kernel supports it, and stays in userspace otherwise.
+    fn create_connection(mut tls_stream: TlsStream<LurkKtlsStream>, kernel_offload: bool) -> Result<LurkTcpConnection> {
+        if kernel_offload {
+            match ktls::attach(&mut tls_stream) {
+                Ok(()) => {
+                    let tcp_stream = ktls::offload(tls_stream).context("failed to hand TLS session over to the kernel")?;
+                    return LurkTcpConnectionFactory::create_connection(tcp_stream, LurkTcpConnectionLabel::Http);
+                }
+                Err(err) => debug!("TLS session stays in userspace: {}", err),
+            }
+        }
+
+        tls_stream.get_mut().0.passthrough();
+        LurkTcpConnectionFactory::create_tls_connection(tls_stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::tls::LurkTlsPreset;
+    use crate::{net::tcp::connection::LurkTcpConnectionLabel, server::LurkServer};
+    use pretty_assertions::assert_eq;
+    use rustls::{pki_types::ServerName, CipherSuite};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn connect_over_tls() {
+        let dir = std::env::temp_dir().join(format!("lurk-https-{}", std::process::id()));
+        let (cert_path, key_path, cert) = tls::write_self_signed_cert(&dir, "localhost");
+        let options = LurkHttpsOptions::new("127.0.0.1:0".parse().unwrap(), cert_path, key_path);
+        let https_listener = LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).unwrap();
+        let listener_addr = https_listener.local_addr().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let serve = tokio::spawn(https_listener.run(acceptor));
+
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+
+        let connector = TlsConnector::from(Arc::new(tls::client_config(cert, &[LurkHttpsListener::ALPN])));
+        let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = connector.connect(server_name, tcp_stream).await.unwrap();
+        assert_eq!(Some(LurkHttpsListener::ALPN), client.get_ref().1.alpn_protocol());
+
+        let request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n\r\n", addr = endpoint_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (mut endpoint_stream, _) = endpoint.accept().await.unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(client.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+        assert_eq!(1, server.get_stats().get_connections_with_label(LurkTcpConnectionLabel::Http));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buff = [0u8; 4];
+        endpoint_stream.read_exact(&mut buff).await.unwrap();
+        assert_eq!(b"ping", &buff);
+
+        server.shutdown();
+        serve.await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn offload_to_kernel() {
+        if !ktls::is_supported() {
+            eprintln!("kTLS isn't supported by the kernel, \"tls\" module is needed");
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!("lurk-https-ktls-{}", std::process::id()));
+        let (cert_path, key_path, cert) = tls::write_self_signed_cert(&dir, "localhost");
+        let mut options = LurkHttpsOptions::new("127.0.0.1:0".parse().unwrap(), cert_path, key_path);
+        let mut policy = LurkTlsPolicy::default();
+        policy.set_kernel_offload(true);
+        options.set_tls_policy(policy);
+        let https_listener = LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).unwrap();
+        let listener_addr = https_listener.local_addr().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Both TLS 1.3 and TLS 1.2 sessions are handed over to the kernel.
+        for client_config in [
+            tls::client_config(cert.clone(), &[LurkHttpsListener::ALPN]),
+            rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS12])
+                .unwrap()
+                .with_root_certificates({
+                    let mut roots = rustls::RootCertStore::empty();
+                    roots.add(cert.clone()).unwrap();
+                    roots
+                })
+                .with_no_client_auth(),
+        ] {
+            // Request is sent right after the handshake, so it could arrive along with the client's Finished.
+            let request = b"CONNECT lurk.test:443 HTTP/1.1\r\nHost: lurk.test:443\r\n\r\n";
+            let connect = async {
+                let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+                let server_name = ServerName::try_from("localhost").unwrap();
+                let mut client = TlsConnector::from(Arc::new(client_config))
+                    .connect(server_name, tcp_stream)
+                    .await
+                    .unwrap();
+                client.write_all(request).await.unwrap();
+                client
+            };
+            let accept = async {
+                let (tcp_stream, _) = https_listener.tcp_listener.accept().await.unwrap();
+                https_listener
+                    .tls_acceptor
+                    .accept(LurkKtlsStream::new(tcp_stream, true))
+                    .await
+                    .unwrap()
+            };
+            let (mut client, tls_stream) = tokio::join!(connect, accept);
+
+            let mut conn = LurkHttpsListener::create_connection(tls_stream, true).unwrap();
+            let tcp_stream = conn.tcp_stream_mut().expect("TLS session has stayed in userspace");
+            assert_eq!("tls", ktls::upper_layer_protocol(tcp_stream).unwrap());
+
+            // Records are decrypted and encrypted by the kernel from now on.
+            let mut received = vec![0u8; request.len()];
+            tcp_stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(request.as_slice(), received);
+            tcp_stream.write_all(b"pong").await.unwrap();
+            let mut buff = [0u8; 4];
+            client.read_exact(&mut buff).await.unwrap();
+            assert_eq!(b"pong", &buff);
+        }
+    }
+
+    #[tokio::test]
+    async fn select_cert_by_sni() {
+        let dir = std::env::temp_dir().join(format!("lurk-https-sni-{}", std::process::id()));
+        let (cert_path, key_path, default_cert) = tls::write_self_signed_cert(&dir.join("default"), "localhost");
+        let (admin_cert_path, admin_key_path, admin_cert) = tls::write_self_signed_cert(&dir.join("admin"), "admin.localhost");
+        let mut options = LurkHttpsOptions::new("127.0.0.1:0".parse().unwrap(), &cert_path, &key_path);
+
+        // Certificate, which isn't valid for the name, is refused on start.
+        options.set_sni_certs(vec![LurkSniCert::new("proxy.localhost", &admin_cert_path, &admin_key_path)]);
+        assert!(LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).is_err());
+
+        options.set_sni_certs(vec![format!(
+            "admin.localhost:{}:{}",
+            admin_cert_path.display(),
+            admin_key_path.display()
+        )
+        .parse()
+        .unwrap()]);
+        let https_listener = LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).unwrap();
+        let listener_addr = https_listener.local_addr().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let serve = tokio::spawn(https_listener.run(server.acceptor()));
+
+        // Clients trust the only certificate, so the handshake succeeds once it's the one presented for the name.
+        for (server_name, cert) in [("admin.localhost", admin_cert), ("localhost", default_cert)] {
+            let connector = TlsConnector::from(Arc::new(tls::client_config(cert.clone(), &[LurkHttpsListener::ALPN])));
+            let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+            let client = connector
+                .connect(ServerName::try_from(server_name).unwrap(), tcp_stream)
+                .await
+                .unwrap();
+            assert_eq!(Some(&[cert][..]), client.get_ref().1.peer_certificates());
+        }
+
+        server.shutdown();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn apply_tls_policy() {
+        let dir = std::env::temp_dir().join(format!("lurk-https-policy-{}", std::process::id()));
+        let (cert_path, key_path, cert) = tls::write_self_signed_cert(&dir, "localhost");
+        let mut options = LurkHttpsOptions::new("127.0.0.1:0".parse().unwrap(), &cert_path, &key_path);
+
+        // Unknown cipher suites are refused on start.
+        let mut policy = LurkTlsPolicy::new(LurkTlsPreset::Modern);
+        policy.set_cipher_suites(vec!["TLS13_NO_SUCH_SUITE".to_owned()]);
+        options.set_tls_policy(policy.clone());
+        assert!(LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).is_err());
+
+        policy.set_cipher_suites(vec!["TLS13_CHACHA20_POLY1305_SHA256".to_owned()]);
+        options.set_tls_policy(policy);
+        let https_listener = LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).unwrap();
+        let listener_addr = https_listener.local_addr().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let serve = tokio::spawn(https_listener.run(server.acceptor()));
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        // Clients get the only allowed cipher suite.
+        let connector = TlsConnector::from(Arc::new(tls::client_config(cert.clone(), &[LurkHttpsListener::ALPN])));
+        let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+        let client = connector.connect(server_name.clone(), tcp_stream).await.unwrap();
+        assert_eq!(
+            Some(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+            client.get_ref().1.negotiated_cipher_suite().map(|suite| suite.suite())
+        );
+
+        // Clients, which don't support TLS 1.3, are refused by the modern policy.
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+        assert!(TlsConnector::from(Arc::new(client_config))
+            .connect(server_name, tcp_stream)
+            .await
+            .is_err());
+
+        server.shutdown();
+        serve.await.unwrap();
+    }
+
+    #[cfg(feature = "acme")]
+    #[tokio::test]
+    async fn present_acme_challenge_cert() {
+        use rustls::{
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            sign::CertifiedKey,
+        };
+
+        let dir = std::env::temp_dir().join(format!("lurk-https-acme-{}", std::process::id()));
+        let (cert_path, key_path, _) = tls::write_self_signed_cert(&dir.join("default"), "localhost");
+        let (challenge_cert_path, challenge_key_path, challenge_cert) = tls::write_self_signed_cert(&dir.join("challenge"), "localhost");
+        let options = LurkHttpsOptions::new("127.0.0.1:0".parse().unwrap(), &cert_path, &key_path);
+        let https_listener = LurkHttpsListener::bind(&options, &LurkTcpListenerOptions::default()).unwrap();
+        let listener_addr = https_listener.local_addr().unwrap();
+        let certs = https_listener.certs();
+        let challenge_key = CertifiedKey::from_der(
+            vec![CertificateDer::from_pem_file(&challenge_cert_path).unwrap()],
+            PrivateKeyDer::from_pem_file(&challenge_key_path).unwrap(),
+            &rustls::crypto::ring::default_provider(),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let serve = tokio::spawn(https_listener.run(server.acceptor()));
+        let connector = TlsConnector::from(Arc::new(tls::client_config(challenge_cert.clone(), &[tls::ACME_TLS_ALPN])));
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        // Challenge certificate is presented to "acme-tls/1" clients only while it's set.
+        certs.set_challenge_cert("localhost", Some(challenge_key));
+        let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+        let client = connector.connect(server_name.clone(), tcp_stream).await.unwrap();
+        assert_eq!(Some(tls::ACME_TLS_ALPN), client.get_ref().1.alpn_protocol());
+        assert_eq!(Some(&[challenge_cert][..]), client.get_ref().1.peer_certificates());
+
+        certs.set_challenge_cert("localhost", None);
+        let tcp_stream = TcpStream::connect(listener_addr).await.unwrap();
+        assert!(connector.connect(server_name, tcp_stream).await.is_err());
+
+        server.shutdown();
+        serve.await.unwrap();
+    }
+}
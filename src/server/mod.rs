@@ -1,23 +1,142 @@
 use crate::{
-    common::logging::{self},
-    net::tcp::{connection::LurkTcpConnection, listener::LurkTcpListener},
+    auth::{private::LurkPrivateAuthMethod, users::LurkUserStore, LurkOfferedAuthMethods},
+    common::{error::LurkError, logging},
+    net::{
+        bans::LurkClientBans,
+        geoip::LurkCountryAccess,
+        socks5::LurkUpstreamProxy,
+        tcp::{
+            self,
+            connection::LurkTcpConnection,
+            listener::{self, LurkTcpConnectionSetup, LurkTcpListener, LurkTcpListenerOptions, LurkTransparentMode},
+        },
+        LurkResolvePolicy,
+    },
 };
-use anyhow::Result;
+#[cfg(feature = "acme")]
+use acme::{LurkAcme, LurkAcmeOptions};
+use anyhow::{bail, ensure, Result};
 use async_listen::is_transient_error;
-use handlers::create_tcp_connection_handler;
+use blocklist::{LurkBlocklist, LurkBlocklistOptions};
+use checkpoint::{LurkStatsCheckpointOptions, LurkStatsCheckpointer};
+use chrono::Utc;
+use cluster::{LurkClusterOptions, LurkClusterSync};
+#[cfg(feature = "compression")]
+use compression::LurkCompression;
+use discovery::{LurkDiscoveryOptions, LurkServiceRegistrar};
+use dnsbl::{LurkDnsbl, LurkDnsblOptions};
+use egress::{LurkEgressBalancer, LurkEgressRotation};
+use error_page::LurkErrorPage;
+use handlers::{LurkHandlerContext, LurkHandlers};
+#[cfg(feature = "http3")]
+use http3::{LurkHttp3Listener, LurkHttp3Options};
+#[cfg(feature = "https")]
+use https::{LurkHttpsListener, LurkHttpsOptions};
 use log::{debug, error, info, warn};
-use stats::LurkServerStats;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{signal, time::sleep};
+use policy::LurkPolicy;
+use pool::{LurkWarmPool, LurkWarmPoolOptions};
+use profiles::LurkProfiles;
+use recordings::LurkRecordings;
+use registry::LurkConnectionRegistry;
+use restart::LurkRestartOptions;
+use routing::LurkRouting;
+use sessions::{LurkSessionRecord, LurkSessionRecordOptions, LurkSessionRecorder};
+use shards::{LurkShard, LurkShardingOptions};
+use ssrf::LurkSsrfGuard;
+use stats::{
+    destinations::LurkDestinationStats,
+    node::{LurkListenerKind, LurkNodeState},
+    rates::LurkRateTracker,
+    sink::{LurkStatsEvent, LurkStatsSink},
+    LurkServerStats,
+};
+use std::{
+    future::pending,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    signal,
+    time::{sleep, Instant},
+};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use watchdog::{LurkWatchdog, LurkWatchdogOptions};
 
-mod handlers;
+pub(crate) mod handlers;
 
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod blocklist;
+pub mod checkpoint;
+pub mod cluster;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod discovery;
+pub mod dnsbl;
+pub mod egress;
+pub mod error_page;
+#[cfg(feature = "http3")]
+pub mod http3;
+#[cfg(feature = "https")]
+pub mod https;
+pub mod policy;
+pub mod pool;
+pub mod profiles;
+pub mod recordings;
+pub mod registry;
+pub mod restart;
+pub mod routing;
+pub mod sessions;
+pub mod shards;
+pub mod ssrf;
 pub mod stats;
+pub mod tenants;
+pub mod watchdog;
+
+pub use handlers::LurkReplyBoundAddress;
 
 pub struct LurkServer {
     bind_addr: SocketAddr,
+    additional_bind_addrs: Vec<SocketAddr>,
+    transparent_listener: Option<(SocketAddr, LurkTcpListenerOptions)>,
+    listener_options: LurkTcpListenerOptions,
+    acceptors: usize,
+    accept_batch_size: usize,
     stats: Arc<LurkServerStats>,
+    registry: Arc<LurkConnectionRegistry>,
+    handlers: LurkHandlers,
+    users: Option<Arc<LurkUserStore>>,
+    offered_auth_methods: Arc<LurkOfferedAuthMethods>,
+    watchdog_options: Option<LurkWatchdogOptions>,
+    checkpointer: Option<Arc<LurkStatsCheckpointer>>,
+    recorder: Option<Arc<LurkSessionRecorder>>,
+    warm_pool: Option<Arc<LurkWarmPool>>,
+    blocklist: Option<Arc<LurkBlocklist>>,
+    policy: Option<Arc<LurkPolicy>>,
+    routing: Option<Arc<LurkRouting>>,
+    profiles: Option<Arc<LurkProfiles>>,
+    recordings: Option<Arc<LurkRecordings>>,
+    bans: Arc<LurkClientBans>,
+    sharding_options: Option<LurkShardingOptions>,
+    discovery_options: Option<LurkDiscoveryOptions>,
+    cluster_options: Option<LurkClusterOptions>,
+    restart_options: Option<LurkRestartOptions>,
+    #[cfg(feature = "http3")]
+    http3_options: Option<LurkHttp3Options>,
+    #[cfg(feature = "http3")]
+    handler_context: Arc<LurkHandlerContext>,
+    #[cfg(feature = "https")]
+    https_options: Option<LurkHttpsOptions>,
+    #[cfg(feature = "acme")]
+    acme_options: Option<LurkAcmeOptions>,
+    draining: AtomicBool,
+    restarting: AtomicBool,
     task_tracker: TaskTracker,
     task_cancellation_token: CancellationToken,
 }
@@ -27,58 +146,633 @@ impl LurkServer {
     /// handle resource exhaustion errors.
     const DELAY_AFTER_ERROR_MILLIS: u64 = 500;
 
+    /// Bounds of the delay between attempts to bind broken listener again.
+    const MIN_REBIND_DELAY: Duration = Duration::from_millis(100);
+    const MAX_REBIND_DELAY: Duration = Duration::from_secs(30);
+
+    /// Default number of pending connections accepted per listener wakeup.
+    pub const DEFAULT_ACCEPT_BATCH_SIZE: usize = 16;
+
+    /// Default time given to the peer to accept protocol response.
+    pub const DEFAULT_RESPONSE_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Period between checks whether connections of the draining node have finished.
+    const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Create server with default settings.
     pub fn new(bind_addr: SocketAddr) -> LurkServer {
-        LurkServer {
+        LurkServer::builder(bind_addr).build()
+    }
+
+    pub fn builder(bind_addr: SocketAddr) -> LurkServerBuilder {
+        LurkServerBuilder {
             bind_addr,
-            stats: Arc::new(LurkServerStats::new()),
-            task_tracker: TaskTracker::new(),
-            task_cancellation_token: CancellationToken::new(),
+            additional_bind_addrs: Vec::new(),
+            transparent_listener: None,
+            listener_options: LurkTcpListenerOptions::default(),
+            acceptors: 1,
+            accept_batch_size: LurkServer::DEFAULT_ACCEPT_BATCH_SIZE,
+            response_write_timeout: LurkServer::DEFAULT_RESPONSE_WRITE_TIMEOUT,
+            destinations_capacity: LurkDestinationStats::DEFAULT_CAPACITY,
+            keep_hop_by_hop_headers: false,
+            error_page: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            egress_ips: Vec::new(),
+            egress_rotation: LurkEgressRotation::default(),
+            egress_rotation_period: LurkEgressBalancer::DEFAULT_ROTATION_PERIOD,
+            outbound_fast_open: false,
+            outbound_mptcp: false,
+            min_read_rate: None,
+            tunnel_rate_limit: None,
+            max_tunnel_lifetime: None,
+            reply_bound_address: LurkReplyBoundAddress::default(),
+            resolve_policy: LurkResolvePolicy::default(),
+            upstream_proxy: None,
+            routing: None,
+            ssrf_guard: None,
+            destination_countries: None,
+            max_connections_per_client: None,
+            users: None,
+            private_auth_methods: Vec::new(),
+            watchdog_options: None,
+            checkpoint_options: None,
+            session_record_options: None,
+            stats_sinks: Vec::new(),
+            warm_pool_options: None,
+            blocklist_options: None,
+            policy: None,
+            profiles: None,
+            dnsbl_options: None,
+            recordings_dir: None,
+            sharding_options: None,
+            discovery_options: None,
+            cluster_options: None,
+            restart_options: None,
+            #[cfg(feature = "http3")]
+            http3_options: None,
+            #[cfg(feature = "https")]
+            https_options: None,
+            #[cfg(feature = "acme")]
+            acme_options: None,
         }
     }
 
     pub async fn run(&self) -> Result<()> {
-        let mut tcp_listener = LurkTcpListener::bind(self.bind_addr).await?;
-        info!("Proxy is listening on {}", self.bind_addr);
+        let acceptor = self.acceptor();
+
+        // Shards and accept loops would outlive the server if it's dropped (or fails to start) without graceful shutdown.
+        let _shutdown_guard = LurkShutdownGuard(self);
+
+        // Sharded server accepts connections on the dedicated threads, otherwise it's done by the current runtime.
+        let (tcp_listeners, shards, bound_addr) = match &self.sharding_options {
+            Some(sharding_options) => {
+                let (shards, bound_addr) = self.spawn_shards(sharding_options, &acceptor).await?;
+                (Vec::new(), shards, bound_addr)
+            }
+            None => {
+                let tcp_listeners = self.bind_acceptors(self.bind_addr).await?;
+                let bound_addr = tcp_listeners[0].local_addr();
+                (tcp_listeners, Vec::new(), bound_addr)
+            }
+        };
+
+        // Additional addresses are served by the current runtime even if the server is sharded.
+        let mut accept_loops: Vec<_> = tcp_listeners
+            .into_iter()
+            .map(|tcp_listener| (tcp_listener, self.listener_options.clone()))
+            .collect();
+        for &bind_addr in &self.additional_bind_addrs {
+            for tcp_listener in self.bind_acceptors(bind_addr).await? {
+                accept_loops.push((tcp_listener, self.listener_options.clone()));
+            }
+        }
+
+        if let Some((bind_addr, transparent_options)) = &self.transparent_listener {
+            let tcp_listener = LurkTcpListener::bind_with_opts(*bind_addr, transparent_options).await?;
+            info!("Transparent proxy is listening on {}", bind_addr);
+            self.stats
+                .on_listener_bound(LurkListenerKind::Transparent, tcp_listener.local_addr());
+            accept_loops.push((tcp_listener, transparent_options.clone()));
+        }
+
+        // Placeholder certificate is written before the listeners read it.
+        #[cfg(feature = "acme")]
+        let mut acme = match &self.acme_options {
+            Some(acme_options) => Some(LurkAcme::bind(acme_options.clone()).await?),
+            None => None,
+        };
+
+        #[cfg(feature = "https")]
+        if let Some(https_options) = &self.https_options {
+            let https_listener = LurkHttpsListener::bind(https_options, &self.listener_options)?;
+            #[cfg(feature = "acme")]
+            if let Some(acme) = &mut acme {
+                acme.add_listener_certs(https_listener.certs());
+            }
+            self.task_tracker.spawn(https_listener.run(acceptor.clone()));
+        }
+
+        #[cfg(feature = "http3")]
+        if let Some(http3_options) = &self.http3_options {
+            let http3_listener = LurkHttp3Listener::bind(
+                http3_options,
+                self.listener_options.client_access(),
+                Arc::clone(&self.handler_context),
+            )?;
+            #[cfg(feature = "acme")]
+            if let Some(acme) = &mut acme {
+                acme.add_listener_certs(http3_listener.certs());
+            }
+            self.task_tracker
+                .spawn(http3_listener.run(self.task_tracker.clone(), self.task_cancellation_token.clone()));
+        }
+
+        #[cfg(feature = "acme")]
+        if let Some(acme) = acme {
+            self.task_tracker.spawn(acme.run(self.task_cancellation_token.clone()));
+        }
+
+        if let Some(checkpointer) = &self.checkpointer {
+            checkpointer.restore().await?;
+            self.task_tracker
+                .spawn(Arc::clone(checkpointer).run(self.task_cancellation_token.clone()));
+        }
+
+        let recorder_handle = self.recorder.as_ref().map(|recorder| recorder.start());
 
         self.stats.on_server_started();
 
+        self.task_tracker.spawn(LurkServer::sample_rates(
+            Arc::clone(&self.stats),
+            Arc::clone(&self.registry),
+            self.task_cancellation_token.clone(),
+        ));
+
+        if let Some(warm_pool) = &self.warm_pool {
+            let (warm_pool, token) = (Arc::clone(warm_pool), self.task_cancellation_token.clone());
+            self.task_tracker.spawn(async move { warm_pool.run(token).await });
+        }
+
+        if let Some(blocklist) = &self.blocklist {
+            let (blocklist, token) = (Arc::clone(blocklist), self.task_cancellation_token.clone());
+            self.task_tracker.spawn(async move { blocklist.run(token).await });
+        }
+
+        if let Some(policy) = &self.policy {
+            let (policy, token) = (Arc::clone(policy), self.task_cancellation_token.clone());
+            self.task_tracker.spawn(async move { policy.run(token).await });
+        }
+
+        if let Some(discovery_options) = &self.discovery_options {
+            let registrar = LurkServiceRegistrar::new(discovery_options.clone());
+            self.task_tracker
+                .spawn(registrar.run(bound_addr.port(), self.task_cancellation_token.clone()));
+        }
+
+        if let Some(cluster_options) = &self.cluster_options {
+            let cluster_sync = LurkClusterSync::new(cluster_options.clone(), Arc::clone(&self.bans));
+            let cluster_sync = match &self.users {
+                Some(users) => cluster_sync.with_users(Arc::clone(users)),
+                None => cluster_sync,
+            };
+            self.task_tracker.spawn(cluster_sync.run(self.task_cancellation_token.clone()));
+        }
+
+        if let Some(watchdog_options) = self.watchdog_options {
+            let watchdog = LurkWatchdog::new(Arc::clone(&self.registry), watchdog_options);
+            self.task_tracker.spawn(watchdog.run(self.task_cancellation_token.clone()));
+        }
+
+        // Accept loops are separate tasks, so the runtime is free to run them on different workers.
+        for (tcp_listener, listener_options) in accept_loops {
+            let acceptor = acceptor.clone();
+            self.task_tracker
+                .spawn(async move { acceptor.serve(tcp_listener, &listener_options).await });
+        }
+
+        let restart = async {
+            match &self.restart_options {
+                Some(restart_options) => self.drain_for_restart(restart_options).await,
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = self.task_cancellation_token.cancelled() => {},
+            _ = restart => {
+                self.restarting.store(true, Ordering::Relaxed);
+                self.on_shutdown_requested();
+            },
+            _ = signal::ctrl_c() => {
+                info!("Received Ctrl+C. Gracefully tearing down ...");
+                self.on_shutdown_requested();
+            }
+        }
+
+        self.stats.on_server_finished();
+        self.task_tracker.wait().await;
+        for shard in shards {
+            shard.join().await;
+        }
+
+        // Write records of connections that were running till the very end.
+        if let (Some(recorder), Some(handle)) = (&self.recorder, recorder_handle) {
+            recorder.close();
+            handle.await?;
+        }
+
+        // Save counters updated by connections that were running till the very end.
+        if let Some(checkpointer) = &self.checkpointer {
+            checkpointer.save().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the next scheduled restart, then drain the node: active connections are given
+    /// the deadline to finish, the ones still running after it are closed along with the server.
+    async fn drain_for_restart(&self, options: &LurkRestartOptions) {
+        let Some(restart_ts) = options.schedule().next_after(Utc::now()) else {
+            warn!("Restart schedule '{}' never fires", options.schedule());
+            return pending().await;
+        };
+
+        info!("Next restart is scheduled at {}", restart_ts);
+        sleep((restart_ts - Utc::now()).to_std().unwrap_or_default()).await;
+
+        self.set_draining(true);
+        info!(
+            "Restarting: waiting up to {:?} for {} active connection(s) to finish",
+            options.drain_deadline(),
+            self.stats.get_active_connections()
+        );
+
+        let deadline = Instant::now() + options.drain_deadline();
+        while self.stats.get_active_connections() > 0 && Instant::now() < deadline {
+            sleep(LurkServer::DRAIN_CHECK_INTERVAL).await;
+        }
+
+        match self.stats.get_active_connections() {
+            0 => info!("All connections have finished, restarting"),
+            active => warn!("Drain deadline is over, {} active connection(s) are closed", active),
+        }
+    }
+
+    /// Periodically feed stats with counters used to compute rolling rates.
+    async fn sample_rates(stats: Arc<LurkServerStats>, registry: Arc<LurkConnectionRegistry>, token: CancellationToken) {
         loop {
+            let active_connections_bytes = registry
+                .snapshot()
+                .iter()
+                .map(|entry| entry.activity().l2r_bytes() + entry.activity().r2l_bytes())
+                .sum();
+            stats.sample_rates(active_connections_bytes);
+
             tokio::select! {
-                accepted = tcp_listener.accept() => match accepted {
-                    Ok(conn) => self.on_tcp_connection_established(conn).await,
-                    Err(err) => self.on_tcp_acception_error(err).await,
+                _ = sleep(LurkRateTracker::SAMPLE_INTERVAL) => continue,
+                _ = token.cancelled() => break
+            }
+        }
+    }
+
+    /// Spawn accepting shards, each with its own listener bound to the same address.
+    /// Returns the shards and the address they're bound to.
+    async fn spawn_shards(&self, options: &LurkShardingOptions, acceptor: &LurkAcceptor) -> Result<(Vec<LurkShard>, SocketAddr)> {
+        let mut listener_options = self.listener_options.clone();
+        listener_options.set_reuse_port(true);
+
+        // Shards after the first one bind the actual address in case the port has been picked by the OS.
+        let mut bind_addr = self.bind_addr;
+        let mut shards = Vec::with_capacity(options.shards());
+        for index in 0..options.shards() {
+            let (shard, bound_addr) = LurkShard::spawn(index, options, bind_addr, listener_options.clone(), acceptor.clone()).await?;
+            shards.push(shard);
+            bind_addr = bound_addr;
+        }
+
+        info!("Proxy is listening on {} with {} shard(s)", self.bind_addr, shards.len());
+        self.stats.on_listener_bound(LurkListenerKind::Proxy, bind_addr);
+
+        Ok((shards, bind_addr))
+    }
+
+    /// Bind listeners of all the acceptors to the address. Listeners are only bound here, so the server
+    /// fails to start as a whole if any of its addresses can't be listened on.
+    async fn bind_acceptors(&self, bind_addr: SocketAddr) -> Result<Vec<LurkTcpListener>> {
+        let tcp_listener = LurkTcpListener::bind_with_opts(bind_addr, &self.listener_options).await?;
+        let bound_addr = tcp_listener.local_addr();
+
+        // The rest of the listeners bind the actual address in case the port has been picked by the OS.
+        let mut tcp_listeners = vec![tcp_listener];
+        for _ in 1..self.acceptors {
+            tcp_listeners.push(LurkTcpListener::bind_with_opts(bound_addr, &self.listener_options).await?);
+        }
+
+        match self.acceptors {
+            1 => info!("Proxy is listening on {}", bind_addr),
+            acceptors => info!("Proxy is listening on {} with {} acceptor(s)", bind_addr, acceptors),
+        }
+        self.stats.on_listener_bound(LurkListenerKind::Proxy, bound_addr);
+
+        Ok(tcp_listeners)
+    }
+
+    fn acceptor(&self) -> LurkAcceptor {
+        LurkAcceptor {
+            accept_batch_size: self.accept_batch_size,
+            stats: Arc::clone(&self.stats),
+            registry: Arc::clone(&self.registry),
+            handlers: self.handlers.clone(),
+            recorder: self.recorder.clone(),
+            task_tracker: self.task_tracker.clone(),
+            task_cancellation_token: self.task_cancellation_token.clone(),
+        }
+    }
+
+    pub fn get_stats(&self) -> Arc<LurkServerStats> {
+        Arc::clone(&self.stats)
+    }
+
+    pub fn get_registry(&self) -> Arc<LurkConnectionRegistry> {
+        Arc::clone(&self.registry)
+    }
+
+    /// Users allowed to access the proxy, if authentication is required.
+    pub fn get_users(&self) -> Option<Arc<LurkUserStore>> {
+        self.users.clone()
+    }
+
+    /// Rules routing the destinations, if there are any.
+    pub fn get_routing(&self) -> Option<Arc<LurkRouting>> {
+        self.routing.clone()
+    }
+
+    /// Clients banned from connecting to the node, shared with the cluster if it's configured.
+    pub fn get_bans(&self) -> Arc<LurkClientBans> {
+        Arc::clone(&self.bans)
+    }
+
+    /// Built-in SOCKS5 authentication methods offered to the clients, switchable at runtime.
+    pub fn get_offered_auth_methods(&self) -> Arc<LurkOfferedAuthMethods> {
+        Arc::clone(&self.offered_auth_methods)
+    }
+
+    /// Named profiles of the settings, if they are configured.
+    pub fn get_profiles(&self) -> Option<Arc<LurkProfiles>> {
+        self.profiles.clone()
+    }
+
+    /// Apply all settings of the profile at once: offered authentication methods, destination
+    /// policy and limit of user sessions. Returns the name of the previously active profile.
+    ///
+    /// Nothing is changed if the profile can't be applied, e.g. its policy file is invalid.
+    /// Active connections are kept, new settings affect new ones only.
+    pub fn switch_profile(&self, name: &str) -> Result<String> {
+        let Some(profiles) = &self.profiles else {
+            bail!("profiles are not configured")
+        };
+        let Some(profile) = profiles.get(name) else {
+            bail!("profile '{}' is not defined", name)
+        };
+
+        let auth_methods = profile.auth_methods.unwrap_or_else(|| self.offered_auth_methods.initial());
+        self.offered_auth_methods.validate(&auth_methods)?;
+        ensure!(
+            self.users.is_some() || profile.max_sessions_per_user.is_none(),
+            "limit of user sessions requires users"
+        );
+        match (&self.policy, &profile.policy_file) {
+            (Some(policy), Some(policy_file)) => policy.switch_to(policy_file)?,
+            (None, Some(_)) => bail!("policy of the profile requires the policy of the node"),
+            _ => {}
+        }
+
+        self.offered_auth_methods.set(auth_methods)?;
+        if let Some(users) = &self.users {
+            users.set_max_sessions(profile.max_sessions_per_user);
+        }
+
+        let previous = profiles.set_active(name);
+        info!("Profile '{}' is active, previous one was '{}'", name, previous);
+        Ok(previous)
+    }
+
+    /// Recordings of the tunnels, if they are enabled.
+    pub fn get_recordings(&self) -> Option<Arc<LurkRecordings>> {
+        self.recordings.clone()
+    }
+
+    /// Code the process should exit with once the server has been stopped for the scheduled restart.
+    pub fn get_restart_exit_code(&self) -> Option<i32> {
+        let restart_options = self.restart_options.as_ref()?;
+        self.restarting.load(Ordering::Relaxed).then(|| restart_options.exit_code())
+    }
+
+    /// Ask load balancers to stop sending new clients to the node, while
+    /// it keeps serving them (or to resume sending them).
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+        info!("Node is {}", if draining { "draining" } else { "no longer draining" });
+    }
+
+    pub fn get_state(&self) -> LurkNodeState {
+        if !self.stats.is_server_started() || self.task_cancellation_token.is_cancelled() {
+            LurkNodeState::Down
+        } else if self.draining.load(Ordering::Relaxed) {
+            LurkNodeState::Drain
+        } else {
+            LurkNodeState::Up
+        }
+    }
+
+    /// Stop accepting connections and shut the running server down, as it's done on Ctrl+C
+    /// (e.g. on request of the application embedding the proxy).
+    pub fn shutdown(&self) {
+        info!("Shutdown is requested. Gracefully tearing down ...");
+        self.on_shutdown_requested();
+    }
+
+    fn on_shutdown_requested(&self) {
+        self.task_tracker.close();
+        self.task_cancellation_token.cancel();
+    }
+}
+
+/// Shuts the server down once dropped.
+struct LurkShutdownGuard<'a>(&'a LurkServer);
+
+impl Drop for LurkShutdownGuard<'_> {
+    fn drop(&mut self) {
+        self.0.on_shutdown_requested();
+    }
+}
+
+/// Accepts connections from the listener and dispatches them to the handlers.
+///
+/// Acceptor shares server's state, so it could be cloned to every thread
+/// serving its own listener.
+#[derive(Clone)]
+pub(crate) struct LurkAcceptor {
+    accept_batch_size: usize,
+    stats: Arc<LurkServerStats>,
+    registry: Arc<LurkConnectionRegistry>,
+    handlers: LurkHandlers,
+    recorder: Option<Arc<LurkSessionRecorder>>,
+    task_tracker: TaskTracker,
+    task_cancellation_token: CancellationToken,
+}
+
+impl LurkAcceptor {
+    /// Accept connections until the server is shut down.
+    ///
+    /// Listener is supervised: if it gets broken (e.g. closed by the OS), it's bound
+    /// to the same address with the same ```listener_options``` again instead of
+    /// leaving the server without the ability to accept connections.
+    async fn serve(&self, mut tcp_listener: LurkTcpListener, listener_options: &LurkTcpListenerOptions) {
+        let bind_addr = tcp_listener.local_addr();
+        loop {
+            let mut is_broken = false;
+            tokio::select! {
+                accepted = tcp_listener.accept_batch(self.accept_batch_size) => {
+                    let setup = tcp_listener.connection_setup();
+                    for res in accepted {
+                        match res {
+                            Ok((tcp_stream, peer_addr)) => self.on_tcp_connection_accepted(Arc::clone(&setup), tcp_stream, peer_addr),
+                            Err(err) => is_broken |= self.on_tcp_acception_error(err.into()).await,
+                        }
+                    }
                 },
-                _ = signal::ctrl_c() => {
-                    info!("Received Ctrl+C. Gracefully tearing down ...");
-                    self.on_shutdown_requested();
-                    break
+                _ = self.task_cancellation_token.cancelled() => break
+            }
+
+            if is_broken {
+                // Release the address before binding it again.
+                drop(tcp_listener);
+                match self.rebind(bind_addr, listener_options).await {
+                    Some(rebound_listener) => tcp_listener = rebound_listener,
+                    None => break,
                 }
             }
         }
+    }
 
-        self.stats.on_server_finished();
-        self.task_tracker.wait().await;
+    /// Bind listener with exponential backoff until it succeeds. Returns None if server is shut down meanwhile.
+    async fn rebind(&self, bind_addr: SocketAddr, listener_options: &LurkTcpListenerOptions) -> Option<LurkTcpListener> {
+        let mut delay = LurkServer::MIN_REBIND_DELAY;
+        loop {
+            match LurkTcpListener::bind_with_opts(bind_addr, listener_options).await {
+                Ok(tcp_listener) => {
+                    info!("Proxy listener on {} has been recovered", bind_addr);
+                    self.stats.emit(LurkStatsEvent::ListenerRecovered { addr: bind_addr });
+                    return Some(tcp_listener);
+                }
+                Err(err) => warn!(
+                    "Failed to bind proxy listener on {} again, retry in {:?}: {}",
+                    bind_addr, delay, err
+                ),
+            }
 
-        Ok(())
+            tokio::select! {
+                _ = sleep(delay) => delay = (delay * 2).min(LurkServer::MAX_REBIND_DELAY),
+                _ = self.task_cancellation_token.cancelled() => return None
+            }
+        }
     }
 
-    async fn on_tcp_acception_error(&self, err: anyhow::Error) {
+    /// Account acception error. Returns true if listener is broken and has to be bound again.
+    async fn on_tcp_acception_error(&self, err: anyhow::Error) -> bool {
+        if self.on_tcp_connection_refused(&err) {
+            return false;
+        }
+
         logging::log_tcp_acception_error!(err);
+        self.stats.on_accept_error();
 
         if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if listener::is_listener_broken(err) {
+                error!("Proxy listener is broken: {}", err);
+                return true;
+            }
             if !is_transient_error(err) {
                 // Perform sleep after non-transient errors
                 sleep(Duration::from_millis(LurkServer::DELAY_AFTER_ERROR_MILLIS)).await;
             }
         }
+
+        false
+    }
+
+    /// Account failure to set up accepted connection. It's the connection that has failed, not the listener.
+    fn on_tcp_connection_setup_error(&self, err: anyhow::Error) {
+        if !self.on_tcp_connection_refused(&err) {
+            logging::log_tcp_acception_error!(err);
+            self.stats.on_accept_error();
+        }
+    }
+
+    /// Account connection refused before it's dispatched to the handler. Returns false if error isn't the refusal.
+    fn on_tcp_connection_refused(&self, err: &anyhow::Error) -> bool {
+        match err.downcast_ref::<LurkError>() {
+            Some(LurkError::ClientNotAllowed(ip)) => {
+                debug!("Connection from {} is dropped, client isn't allowed", ip);
+                self.stats.on_client_denied();
+            }
+            Some(LurkError::ClientThrottled(ip)) => {
+                debug!("Connection from {} is dropped, client opens connections too fast", ip);
+                self.stats.on_connection_throttled();
+            }
+            Some(LurkError::ClientBanned(ip)) => {
+                debug!("Connection from {} is dropped, client is banned", ip);
+                self.stats.on_client_denied();
+            }
+            Some(LurkError::ClientNotRedirected(ip)) => {
+                warn!(
+                    "Connection from {} is dropped, it hasn't been redirected to the transparent listener",
+                    ip
+                );
+                self.stats.on_client_denied();
+            }
+            Some(LurkError::InvalidProxyHeader(ip, reason)) => {
+                warn!("Connection from {} is dropped, invalid PROXY protocol header: {}", ip, reason);
+                self.stats.on_accept_error();
+            }
+            Some(LurkError::ProtocolNotDetected(ip, timeout)) => {
+                debug!(
+                    "Connection from {} is dropped, client hasn't sent anything within {:?}",
+                    ip, timeout
+                );
+                self.stats.on_accept_error();
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Set accepted connection up (e.g. read PROXY protocol header and detect its protocol) and dispatch it to the handler
+    /// in a separate task, so the clients and load balancers, which are slow to send their first bytes, don't hold up the listener.
+    fn on_tcp_connection_accepted(&self, setup: Arc<LurkTcpConnectionSetup>, tcp_stream: TcpStream, peer_addr: SocketAddr) {
+        let acceptor = self.clone();
+        self.task_tracker.spawn(async move {
+            let res = tokio::select! {
+                res = setup.create_connection(tcp_stream, peer_addr) => res,
+                _ = acceptor.task_cancellation_token.cancelled() => return,
+            };
+            match res {
+                Ok(conn) => acceptor.on_tcp_connection_established(conn).await,
+                Err(err) => acceptor.on_tcp_connection_setup_error(err),
+            }
+        });
     }
 
     async fn on_tcp_connection_established(&self, conn: LurkTcpConnection) {
         let (conn_peer_addr, conn_label) = (conn.peer_addr(), conn.label());
         logging::log_tcp_established_conn!(conn_peer_addr, conn_label);
+        self.stats.on_connection_accepted(conn_label);
 
-        // Create connection handler and supply handling of particular traffic label in a separate thread.
-        let mut connection_handler = match create_tcp_connection_handler(&conn.label()) {
+        // Pick handler of particular traffic label and supply handling in a separate task.
+        let connection_handler = match self.handlers.get(&conn.label()) {
             Ok(handler) => handler,
             Err(err) => {
                 logging::log_tcp_closed_conn_with_error!(conn_peer_addr, conn_label, err);
@@ -86,35 +780,813 @@ impl LurkServer {
             }
         };
 
-        // Clone token in order to cancel running task from outside.
-        let token = self.task_cancellation_token.clone();
+        // Child token cancels running task either on server shutdown or on the
+        // direct request from outside (e.g. watchdog closing stuck connection).
+        let token = self.task_cancellation_token.child_token();
+
+        // Keep connection in the registry while it's being handled.
+        let registered_conn = self.registry.register(&conn, token.clone());
+        let (conn_activity, stats) = (conn.activity(), Arc::clone(&self.stats));
+        let recorder = self.recorder.clone();
+
+        stats.emit(LurkStatsEvent::ConnectionOpened {
+            peer_addr: conn_peer_addr,
+            label: conn_label,
+        });
 
         // Submit execution in a separate task.
         self.task_tracker.spawn(async move {
-            tokio::select! {
-                res = connection_handler.handle(conn) => {
-                    if let Err(err) = res {
+            let error = tokio::select! {
+                res = connection_handler.handle(conn) => match res {
+                    Err(err) => {
+                        let error = err.to_string();
                         logging::log_tcp_closed_conn_with_error!(conn_peer_addr, conn_label, err);
-                    } else {
+                        Some(error)
+                    }
+                    Ok(()) => {
                         logging::log_tcp_closed_conn!(conn_peer_addr, conn_label);
+                        None
                     }
                 },
                 _ = token.cancelled() => {
                     logging::log_tcp_canceled_conn!(conn_peer_addr, conn_label);
+                    Some("Connection has been cancelled".to_owned())
                 }
+            };
+            stats.emit(LurkStatsEvent::ConnectionClosed {
+                peer_addr: conn_peer_addr,
+                label: conn_label,
+                l2r_bytes: conn_activity.l2r_bytes(),
+                r2l_bytes: conn_activity.r2l_bytes(),
+            });
+            if let Some(recorder) = recorder {
+                recorder.record(LurkSessionRecord::new(registered_conn.entry(), error));
             }
         });
     }
+}
 
-    pub fn get_stats(&self) -> Arc<LurkServerStats> {
-        Arc::clone(&self.stats)
+pub struct LurkServerBuilder {
+    bind_addr: SocketAddr,
+    additional_bind_addrs: Vec<SocketAddr>,
+    transparent_listener: Option<(SocketAddr, LurkTransparentMode)>,
+    listener_options: LurkTcpListenerOptions,
+    acceptors: usize,
+    accept_batch_size: usize,
+    response_write_timeout: Duration,
+    destinations_capacity: usize,
+    keep_hop_by_hop_headers: bool,
+    error_page: Option<Arc<LurkErrorPage>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Arc<LurkCompression>>,
+    egress_ips: Vec<IpAddr>,
+    egress_rotation: LurkEgressRotation,
+    egress_rotation_period: Duration,
+    outbound_fast_open: bool,
+    outbound_mptcp: bool,
+    min_read_rate: Option<(u64, Duration)>,
+    tunnel_rate_limit: Option<u64>,
+    max_tunnel_lifetime: Option<Duration>,
+    reply_bound_address: LurkReplyBoundAddress,
+    resolve_policy: LurkResolvePolicy,
+    upstream_proxy: Option<Arc<LurkUpstreamProxy>>,
+    routing: Option<Arc<LurkRouting>>,
+    ssrf_guard: Option<Arc<LurkSsrfGuard>>,
+    destination_countries: Option<Arc<LurkCountryAccess>>,
+    max_connections_per_client: Option<usize>,
+    users: Option<Arc<LurkUserStore>>,
+    private_auth_methods: Vec<Arc<dyn LurkPrivateAuthMethod>>,
+    watchdog_options: Option<LurkWatchdogOptions>,
+    checkpoint_options: Option<LurkStatsCheckpointOptions>,
+    session_record_options: Option<LurkSessionRecordOptions>,
+    stats_sinks: Vec<Arc<dyn LurkStatsSink>>,
+    warm_pool_options: Option<LurkWarmPoolOptions>,
+    blocklist_options: Option<LurkBlocklistOptions>,
+    policy: Option<Arc<LurkPolicy>>,
+    profiles: Option<Arc<LurkProfiles>>,
+    dnsbl_options: Option<LurkDnsblOptions>,
+    recordings_dir: Option<PathBuf>,
+    sharding_options: Option<LurkShardingOptions>,
+    discovery_options: Option<LurkDiscoveryOptions>,
+    cluster_options: Option<LurkClusterOptions>,
+    restart_options: Option<LurkRestartOptions>,
+    #[cfg(feature = "http3")]
+    http3_options: Option<LurkHttp3Options>,
+    #[cfg(feature = "https")]
+    https_options: Option<LurkHttpsOptions>,
+    #[cfg(feature = "acme")]
+    acme_options: Option<LurkAcmeOptions>,
+}
+
+impl LurkServerBuilder {
+    /// Tune the socket the proxy is listening on (backlog, SO_REUSEADDR, TCP_DEFER_ACCEPT).
+    pub fn with_listener_options(&mut self, listener_options: LurkTcpListenerOptions) -> &mut LurkServerBuilder {
+        self.listener_options = listener_options;
+        self
     }
 
-    fn on_shutdown_requested(&self) {
-        self.task_tracker.close();
-        self.task_cancellation_token.cancel();
+    /// Limit time given to the peer to accept protocol response (e.g. SOCKS5 replies).
+    pub fn with_response_write_timeout(&mut self, response_write_timeout: Duration) -> &mut LurkServerBuilder {
+        self.response_write_timeout = response_write_timeout;
+        self
+    }
+
+    /// Close tunnels, which clients read slower than ```bytes_per_sec``` within the ```window```
+    /// while the destinations keep sending (slow-read resource exhaustion). Idle tunnels aren't closed.
+    pub fn with_min_read_rate(&mut self, bytes_per_sec: u64, window: Duration) -> &mut LurkServerBuilder {
+        debug_assert!(self.min_read_rate.is_none(), "should be unset");
+        self.min_read_rate = Some((bytes_per_sec, window));
+        self
+    }
+
+    /// Relay at most ```bytes_per_sec``` in each direction of every tunnel. Users and routing rules
+    /// could have their own limits, which override this one.
+    pub fn with_tunnel_rate_limit(&mut self, bytes_per_sec: u64) -> &mut LurkServerBuilder {
+        debug_assert!(self.tunnel_rate_limit.is_none(), "should be unset");
+        self.tunnel_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Close tunnels, which have been open for longer than ```max_lifetime```, whether they are relaying data or not.
+    pub fn with_max_tunnel_lifetime(&mut self, max_lifetime: Duration) -> &mut LurkServerBuilder {
+        debug_assert!(self.max_tunnel_lifetime.is_none(), "should be unset");
+        self.max_tunnel_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Report this address to SOCKS clients in the successful reply to CONNECT.
+    /// Local address of the connection with the destination is reported by default.
+    pub fn with_reply_bound_address(&mut self, reply_bound_address: LurkReplyBoundAddress) -> &mut LurkServerBuilder {
+        self.reply_bound_address = reply_bound_address;
+        self
+    }
+
+    /// Resolve domain names of the destinations with this policy instead of taking the first resolved address.
+    pub fn with_resolve_policy(&mut self, resolve_policy: LurkResolvePolicy) -> &mut LurkServerBuilder {
+        self.resolve_policy = resolve_policy;
+        self
+    }
+
+    /// Connect to the destinations routed to the parent SOCKS5 proxy through it instead of connecting directly.
+    pub fn with_upstream_proxy(&mut self, upstream_proxy: LurkUpstreamProxy) -> &mut LurkServerBuilder {
+        debug_assert!(self.upstream_proxy.is_none(), "should be unset");
+        self.upstream_proxy = Some(Arc::new(upstream_proxy));
+        self
+    }
+
+    /// Connect to the destinations directly, through the parent proxies or not at all as the rules decide.
+    /// Destinations matching none of the rules are connected as if there were no routing.
+    pub fn with_routing(&mut self, routing: LurkRouting) -> &mut LurkServerBuilder {
+        debug_assert!(self.routing.is_none(), "should be unset");
+        self.routing = Some(Arc::new(routing));
+        self
+    }
+
+    /// Refuse destinations resolved into loopback, private, link-local and other special-purpose addresses.
+    pub fn with_ssrf_guard(&mut self, ssrf_guard: LurkSsrfGuard) -> &mut LurkServerBuilder {
+        debug_assert!(self.ssrf_guard.is_none(), "should be unset");
+        self.ssrf_guard = Some(Arc::new(ssrf_guard));
+        self
+    }
+
+    /// Refuse destinations by the countries they are located in.
+    pub fn with_destination_countries(&mut self, countries: LurkCountryAccess) -> &mut LurkServerBuilder {
+        debug_assert!(self.destination_countries.is_none(), "should be unset");
+        self.destination_countries = Some(Arc::new(countries));
+        self
+    }
+
+    /// Refuse connections of the client IP which already has ```max_per_client``` open ones.
+    pub fn with_max_connections_per_client(&mut self, max_per_client: usize) -> &mut LurkServerBuilder {
+        debug_assert!(self.max_connections_per_client.is_none(), "should be unset");
+        self.max_connections_per_client = Some(max_per_client);
+        self
+    }
+
+    /// Limit number of pending connections accepted at once before handling them.
+    pub fn with_accept_batch_size(&mut self, accept_batch_size: usize) -> &mut LurkServerBuilder {
+        debug_assert!(accept_batch_size > 0, "batch should contain at least one connection");
+        self.accept_batch_size = accept_batch_size.max(1);
+        self
+    }
+
+    /// Limit number of destination hosts the per-destination stats are kept for.
+    pub fn with_destinations_capacity(&mut self, capacity: usize) -> &mut LurkServerBuilder {
+        self.destinations_capacity = capacity;
+        self
+    }
+
+    /// Relay hop-by-hop headers of forwarded HTTP requests and responses verbatim
+    /// instead of stripping them (legacy behavior).
+    pub fn with_hop_by_hop_headers_kept(&mut self, keep: bool) -> &mut LurkServerBuilder {
+        self.keep_hop_by_hop_headers = keep;
+        self
+    }
+
+    /// Serve the page rendered from the template along with HTTP error responses of the proxy.
+    pub fn with_error_page(&mut self, error_page: LurkErrorPage) -> &mut LurkServerBuilder {
+        debug_assert!(self.error_page.is_none(), "should be unset");
+        self.error_page = Some(Arc::new(error_page));
+        self
+    }
+
+    /// Compress forwarded HTTP responses on the fly for the clients accepting gzip or brotli.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(&mut self, compression: LurkCompression) -> &mut LurkServerBuilder {
+        debug_assert!(self.compression.is_none(), "should be unset");
+        self.compression = Some(Arc::new(compression));
+        self
+    }
+
+    /// Spread SOCKS5 clients over the egress IPs of the host. Every client (user or client IP)
+    /// sticks to the same IP while it's healthy. Users with their own egress IP aren't spread.
+    pub fn with_egress_ips(&mut self, egress_ips: Vec<IpAddr>) -> &mut LurkServerBuilder {
+        debug_assert!(self.egress_ips.is_empty(), "should be unset");
+        self.egress_ips = egress_ips;
+        self
+    }
+
+    /// Rotate clients over the egress IPs with passed policy instead of keeping them sticky.
+    /// Period is how long clients stick to their IPs with the periodic rotation.
+    pub fn with_egress_rotation(&mut self, rotation: LurkEgressRotation, period: Duration) -> &mut LurkServerBuilder {
+        self.egress_rotation = rotation;
+        self.egress_rotation_period = period;
+        self
+    }
+
+    /// Send the first data to the destinations along with SYN (TCP Fast Open), where the kernel supports it.
+    /// Saves a round trip on connecting to the destinations which have issued TFO cookie before.
+    pub fn with_outbound_fast_open(&mut self, fast_open: bool) -> &mut LurkServerBuilder {
+        self.outbound_fast_open = fast_open;
+        self
+    }
+
+    /// Connect to the destinations with Multipath TCP, where the kernel supports it.
+    /// Destinations which don't speak MPTCP are connected with plain TCP transparently.
+    pub fn with_outbound_mptcp(&mut self, mptcp: bool) -> &mut LurkServerBuilder {
+        self.outbound_mptcp = mptcp;
+        self
+    }
+
+    /// Require SOCKS5 clients to authenticate with username and password
+    /// of one of the users. Users' transfer quotas are enforced as well.
+    pub fn with_users(&mut self, users: Arc<LurkUserStore>) -> &mut LurkServerBuilder {
+        debug_assert!(self.users.is_none(), "should be unset");
+        self.users = Some(users);
+        self
+    }
+
+    /// Offer private SOCKS5 authentication method to the clients. Private methods
+    /// are preferred over the built-in ones, in the order they are registered.
+    pub fn with_private_auth_method(&mut self, method: Arc<dyn LurkPrivateAuthMethod>) -> &mut LurkServerBuilder {
+        debug_assert!((0x80..=0xfe).contains(&method.code()), "should be in the private range");
+        debug_assert!(
+            self.private_auth_methods
+                .iter()
+                .all(|registered| registered.code() != method.code()),
+            "should be registered once"
+        );
+        self.private_auth_methods.push(method);
+        self
+    }
+
+    /// Run watchdog looking for stuck connections along with the server.
+    pub fn with_watchdog(&mut self, options: LurkWatchdogOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.watchdog_options.is_none(), "should be unset");
+        self.watchdog_options = Some(options);
+        self
+    }
+
+    /// Periodically save cumulative stats counters and restore them on start.
+    pub fn with_stats_checkpoint(&mut self, options: LurkStatsCheckpointOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.checkpoint_options.is_none(), "should be unset");
+        self.checkpoint_options = Some(options);
+        self
+    }
+
+    /// Append a record about each finished connection to the rotating file.
+    pub fn with_session_records(&mut self, options: LurkSessionRecordOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.session_record_options.is_none(), "should be unset");
+        self.session_record_options = Some(options);
+        self
+    }
+
+    /// Pass stats events to the external sink along with the built-in stats.
+    pub fn with_stats_sink(&mut self, sink: Arc<dyn LurkStatsSink>) -> &mut LurkServerBuilder {
+        self.stats_sinks.push(sink);
+        self
+    }
+
+    /// Keep connections to the popular destinations established in advance.
+    pub fn with_warm_pool(&mut self, options: LurkWarmPoolOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.warm_pool_options.is_none(), "should be unset");
+        self.warm_pool_options = Some(options);
+        self
+    }
+
+    /// Refuse connections to the destinations listed in the blocklists.
+    pub fn with_blocklist(&mut self, options: LurkBlocklistOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.blocklist_options.is_none(), "should be unset");
+        self.blocklist_options = Some(options);
+        self
+    }
+
+    /// Allow or deny connections to the destinations by the rules of the policy.
+    /// Policy file is reloaded once it's modified.
+    pub fn with_policy(&mut self, policy: LurkPolicy) -> &mut LurkServerBuilder {
+        debug_assert!(self.policy.is_none(), "should be unset");
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Look destination IPs up in DNS blocklists before connecting to them.
+    pub fn with_dnsbl(&mut self, options: LurkDnsblOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.dnsbl_options.is_none(), "should be unset");
+        self.dnsbl_options = Some(options);
+        self
+    }
+
+    /// Settings, which could be switched at runtime all at once by the name of the profile.
+    pub fn with_profiles(&mut self, profiles: LurkProfiles) -> &mut LurkServerBuilder {
+        debug_assert!(self.profiles.is_none(), "should be unset");
+        self.profiles = Some(Arc::new(profiles));
+        self
+    }
+
+    /// Record tunnels selected by the "record" policy rules or through the HTTP endpoint to the directory.
+    pub fn with_recordings(&mut self, dir: PathBuf) -> &mut LurkServerBuilder {
+        debug_assert!(self.recordings_dir.is_none(), "should be unset");
+        self.recordings_dir = Some(dir);
+        self
+    }
+
+    /// Listen on one more address along with the main one. Connections accepted on all the
+    /// addresses share the stats and are shut down together.
+    pub fn with_additional_bind_addr(&mut self, bind_addr: SocketAddr) -> &mut LurkServerBuilder {
+        self.additional_bind_addrs.push(bind_addr);
+        self
+    }
+
+    /// Accept connections redirected by the firewall to the address and tunnel them to their
+    /// original destinations without any proxy protocol handshake.
+    pub fn with_transparent_listener(&mut self, bind_addr: SocketAddr, mode: LurkTransparentMode) -> &mut LurkServerBuilder {
+        debug_assert!(self.transparent_listener.is_none(), "should be unset");
+        self.transparent_listener = Some((bind_addr, mode));
+        self
+    }
+
+    /// Accept connections by several loops of the current runtime, each with its own
+    /// listener bound to the same address. Main address is served by shards instead, if sharding is enabled.
+    pub fn with_acceptors(&mut self, acceptors: usize) -> &mut LurkServerBuilder {
+        debug_assert!(acceptors > 0, "at least one acceptor is required");
+        self.acceptors = acceptors;
+        self
+    }
+
+    /// Accept and handle connections on several threads, each running its own
+    /// single-threaded runtime and listener.
+    pub fn with_sharding(&mut self, options: LurkShardingOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.sharding_options.is_none(), "should be unset");
+        self.sharding_options = Some(options);
+        self
+    }
+
+    /// Register the proxy in the service registry while it's running.
+    pub fn with_service_registration(&mut self, options: LurkDiscoveryOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.discovery_options.is_none(), "should be unset");
+        self.discovery_options = Some(options);
+        self
+    }
+
+    /// Share client bans, users' transfer usage and active sessions with the other nodes of the cluster,
+    /// so bans, quotas and session limits are enforced across all of them.
+    pub fn with_cluster_state(&mut self, options: LurkClusterOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.cluster_options.is_none(), "should be unset");
+        self.cluster_options = Some(options);
+        self
+    }
+
+    /// Drain and stop the server on schedule, so the supervisor restarts the process.
+    pub fn with_scheduled_restart(&mut self, options: LurkRestartOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.restart_options.is_none(), "should be unset");
+        self.restart_options = Some(options);
+        self
+    }
+
+    /// Serve CONNECT requests over HTTP/3 on the UDP address along with the TCP listener (experimental).
+    #[cfg(feature = "http3")]
+    pub fn with_http3(&mut self, options: LurkHttp3Options) -> &mut LurkServerBuilder {
+        debug_assert!(self.http3_options.is_none(), "should be unset");
+        self.http3_options = Some(options);
+        self
+    }
+
+    /// Accept HTTP proxy clients connecting over TLS on the separate port along with the plain listener.
+    #[cfg(feature = "https")]
+    pub fn with_https(&mut self, options: LurkHttpsOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.https_options.is_none(), "should be unset");
+        self.https_options = Some(options);
+        self
+    }
+
+    /// Obtain the certificate of the TLS listeners from the ACME CA and renew it. Listeners should
+    /// present the certificate from the state directory (see [`LurkAcmeOptions::cert_path`]).
+    #[cfg(feature = "acme")]
+    pub fn with_acme(&mut self, options: LurkAcmeOptions) -> &mut LurkServerBuilder {
+        debug_assert!(self.acme_options.is_none(), "should be unset");
+        self.acme_options = Some(options);
+        self
+    }
+
+    pub fn build(&self) -> LurkServer {
+        let stats = LurkServerStats::with_destinations_capacity(self.destinations_capacity).with_sinks(self.stats_sinks.clone());
+        let stats = Arc::new(stats);
+        let offered_auth_methods = Arc::new(LurkOfferedAuthMethods::new(self.users.is_some()));
+        let mut handler_context = LurkHandlerContext::new(Arc::clone(&stats), self.response_write_timeout)
+            .with_offered_auth_methods(Arc::clone(&offered_auth_methods))
+            .with_reply_bound_address(self.reply_bound_address)
+            .with_resolve_policy(self.resolve_policy);
+        if let Some(users) = &self.users {
+            handler_context = handler_context.with_users(Arc::clone(users));
+        }
+        if !self.private_auth_methods.is_empty() {
+            handler_context = handler_context.with_private_auth_methods(self.private_auth_methods.clone());
+        }
+        if self.keep_hop_by_hop_headers {
+            handler_context = handler_context.with_hop_by_hop_headers_kept();
+        }
+        if let Some(error_page) = &self.error_page {
+            handler_context = handler_context.with_error_page(Arc::clone(error_page));
+        }
+        #[cfg(feature = "compression")]
+        if let Some(compression) = &self.compression {
+            handler_context = handler_context.with_compression(Arc::clone(compression));
+        }
+        if let Some((bytes_per_sec, window)) = self.min_read_rate {
+            handler_context = handler_context.with_min_read_rate(bytes_per_sec, window);
+        }
+        if let Some(bytes_per_sec) = self.tunnel_rate_limit {
+            handler_context = handler_context.with_tunnel_rate_limit(bytes_per_sec);
+        }
+        if let Some(max_lifetime) = self.max_tunnel_lifetime {
+            handler_context = handler_context.with_max_tunnel_lifetime(max_lifetime);
+        }
+        if self.outbound_fast_open || self.outbound_mptcp {
+            let mut tcp_options = tcp::default_tcp_options();
+            tcp_options.set_fast_open(self.outbound_fast_open).set_mptcp(self.outbound_mptcp);
+            handler_context = handler_context.with_outbound_tcp_options(tcp_options);
+        }
+        if !self.egress_ips.is_empty() {
+            let balancer =
+                LurkEgressBalancer::new(self.egress_ips.iter().copied()).with_rotation(self.egress_rotation, self.egress_rotation_period);
+            handler_context = handler_context.with_egress_balancer(Arc::new(balancer));
+        }
+        let warm_pool = self.warm_pool_options.clone().map(|options| Arc::new(LurkWarmPool::new(options)));
+        if let Some(warm_pool) = &warm_pool {
+            handler_context = handler_context.with_warm_pool(Arc::clone(warm_pool));
+        }
+        let blocklist = self.blocklist_options.clone().map(|options| Arc::new(LurkBlocklist::new(options)));
+        if let Some(blocklist) = &blocklist {
+            handler_context = handler_context.with_blocklist(Arc::clone(blocklist));
+        }
+        if let Some(policy) = &self.policy {
+            handler_context = handler_context.with_policy(Arc::clone(policy));
+        }
+        if let Some(dnsbl_options) = &self.dnsbl_options {
+            handler_context = handler_context.with_dnsbl(Arc::new(LurkDnsbl::new(dnsbl_options.clone())));
+        }
+        let recordings = self.recordings_dir.clone().map(|dir| Arc::new(LurkRecordings::new(dir)));
+        if let Some(recordings) = &recordings {
+            handler_context = handler_context.with_recordings(Arc::clone(recordings));
+        }
+        if let Some(upstream_proxy) = &self.upstream_proxy {
+            handler_context = handler_context.with_upstream_proxy(Arc::clone(upstream_proxy));
+        }
+        if let Some(routing) = &self.routing {
+            handler_context = handler_context.with_routing(Arc::clone(routing));
+        }
+        if let Some(ssrf_guard) = &self.ssrf_guard {
+            handler_context = handler_context.with_ssrf_guard(Arc::clone(ssrf_guard));
+        }
+        if let Some(countries) = &self.destination_countries {
+            handler_context = handler_context.with_destination_countries(Arc::clone(countries));
+        }
+
+        let (task_tracker, task_cancellation_token) = (TaskTracker::new(), CancellationToken::new());
+        let handler_context = Arc::new(handler_context.with_task_tracker(task_tracker.clone(), task_cancellation_token.clone()));
+
+        // Every listener drops connections of the banned clients and shares the limit of connections per client.
+        let bans = Arc::new(LurkClientBans::new());
+        let mut client_access = self.listener_options.client_access().clone();
+        client_access.set_bans(Arc::clone(&bans));
+        if let Some(max_per_client) = self.max_connections_per_client {
+            client_access.set_max_connections(max_per_client);
+        }
+        let mut listener_options = self.listener_options.clone();
+        listener_options.set_client_access(client_access);
+
+        // Transparent listener is the only one on its address, but client access lists and other options apply to it as well.
+        // Connections are redirected to it straight from the clients, so they never start with PROXY protocol header.
+        let transparent_listener = self.transparent_listener.map(|(bind_addr, mode)| {
+            let mut transparent_options = listener_options.clone();
+            transparent_options.set_transparent(mode).set_proxy_protocol(Vec::new());
+            (bind_addr, transparent_options)
+        });
+
+        // Listeners of the acceptors share their addresses.
+        if self.acceptors > 1 {
+            listener_options.set_reuse_port(true);
+        }
+
+        LurkServer {
+            bind_addr: self.bind_addr,
+            additional_bind_addrs: self.additional_bind_addrs.clone(),
+            transparent_listener,
+            listener_options,
+            acceptors: self.acceptors,
+            accept_batch_size: self.accept_batch_size,
+            stats: Arc::clone(&stats),
+            handlers: LurkHandlers::new(Arc::clone(&handler_context)),
+            users: self.users.clone(),
+            offered_auth_methods,
+            registry: Arc::new(LurkConnectionRegistry::new()),
+            watchdog_options: self.watchdog_options,
+            checkpointer: self.checkpoint_options.clone().map(|options| {
+                let checkpointer = LurkStatsCheckpointer::new(Arc::clone(&stats), options);
+                Arc::new(match &self.users {
+                    Some(users) => checkpointer.with_users(Arc::clone(users)),
+                    None => checkpointer,
+                })
+            }),
+            recorder: self
+                .session_record_options
+                .clone()
+                .map(|options| Arc::new(LurkSessionRecorder::new(options))),
+            warm_pool,
+            blocklist,
+            policy: self.policy.clone(),
+            routing: self.routing.clone(),
+            profiles: self.profiles.clone(),
+            recordings,
+            bans,
+            sharding_options: self.sharding_options.clone(),
+            discovery_options: self.discovery_options.clone(),
+            cluster_options: self.cluster_options.clone(),
+            restart_options: self.restart_options.clone(),
+            #[cfg(feature = "http3")]
+            http3_options: self.http3_options.clone(),
+            #[cfg(feature = "http3")]
+            handler_context,
+            #[cfg(feature = "https")]
+            https_options: self.https_options.clone(),
+            #[cfg(feature = "acme")]
+            acme_options: self.acme_options.clone(),
+            draining: AtomicBool::new(false),
+            restarting: AtomicBool::new(false),
+            task_tracker,
+            task_cancellation_token,
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::net::tcp::listener::LurkClientAccess;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    #[tokio::test]
+    async fn rebind_broken_listener() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let listener_options = LurkTcpListenerOptions::default();
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        tcp_listener.shutdown();
+
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        timeout(Duration::from_secs(5), async {
+            while server.get_stats().get_listener_recoveries() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Listener should be recovered");
+        assert_eq!(1, server.get_stats().get_accept_errors());
+
+        // Listener accepts connections on the same address again.
+        TcpStream::connect(bound_addr)
+            .await
+            .expect("Recovered listener should accept connections");
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accept_clients_behind_silent_one() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let listener_options = LurkTcpListenerOptions::default();
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        // Protocol of the first client isn't known until it sends anything, which doesn't hold up the next one.
+        let _silent = TcpStream::connect(bound_addr).await.unwrap();
+        let mut client = TcpStream::connect(bound_addr).await.unwrap();
+        client.write_all(&[0x05]).await.unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            while server.get_stats().get_accepted_connections() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Client should be accepted");
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accept_clients_while_load_balancer_is_silent() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let mut listener_options = LurkTcpListenerOptions::default();
+        listener_options.set_proxy_protocol(vec!["127.0.0.0/8".parse().unwrap()]);
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        // Header of the first connection is awaited for seconds, the second one is accepted meanwhile.
+        let _silent = TcpStream::connect(bound_addr).await.unwrap();
+        let mut client = TcpStream::connect(bound_addr).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 56324 1080\r\n\x05")
+            .await
+            .unwrap();
+
+        timeout(Duration::from_secs(1), async {
+            while server.get_stats().get_accepted_connections() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Client should be accepted before the header of the silent one times out");
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_denied_clients() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let mut listener_options = LurkTcpListenerOptions::default();
+        listener_options.set_client_access(LurkClientAccess::new(Vec::new(), vec!["127.0.0.0/8".parse().unwrap()]));
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        // Client is dropped without waiting for it to send any data.
+        let mut client = TcpStream::connect(bound_addr).await.unwrap();
+        let mut buff = [0u8; 1];
+        let read = timeout(Duration::from_secs(5), client.read(&mut buff))
+            .await
+            .expect("Client should be dropped");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        timeout(Duration::from_secs(5), async {
+            while server.get_stats().get_denied_clients() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Denied client should be accounted");
+        assert_eq!(0, server.get_stats().get_accept_errors());
+        assert_eq!(0, server.get_stats().get_accepted_connections());
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_banned_clients() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let listener_options = server.listener_options.clone();
+        server.get_bans().ban("127.0.0.1".parse().unwrap(), Duration::from_secs(60));
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        let mut client = TcpStream::connect(bound_addr).await.unwrap();
+        let mut buff = [0u8; 1];
+        let read = timeout(Duration::from_secs(5), client.read(&mut buff))
+            .await
+            .expect("Client should be dropped");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        timeout(Duration::from_secs(5), async {
+            while server.get_stats().get_denied_clients() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Banned client should be accounted");
+        assert_eq!(0, server.get_stats().get_accepted_connections());
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttle_connection_bursts() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let mut client_access = LurkClientAccess::default();
+        client_access.set_connection_rate(1, 1);
+        let mut listener_options = LurkTcpListenerOptions::default();
+        listener_options.set_client_access(client_access);
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        // The first connection takes the only token, the second one comes too early and is dropped.
+        let mut accepted = TcpStream::connect(bound_addr).await.unwrap();
+        accepted.write_all(&[0x05]).await.unwrap();
+        let mut throttled = TcpStream::connect(bound_addr).await.unwrap();
+        let mut buff = [0u8; 1];
+        let read = timeout(Duration::from_secs(5), throttled.read(&mut buff))
+            .await
+            .expect("Client should be dropped");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        timeout(Duration::from_secs(5), async {
+            // Accepted connection is set up in its own task, so it may be accounted after the throttled one.
+            while server.get_stats().get_throttled_connections() == 0 || server.get_stats().get_accepted_connections() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Throttled and accepted connections should be accounted");
+        assert_eq!(1, server.get_stats().get_throttled_connections());
+        assert_eq!(1, server.get_stats().get_accepted_connections());
+        assert_eq!(0, server.get_stats().get_accept_errors());
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn account_silent_connections_to_their_clients() {
+        let server = LurkServer::new("127.0.0.1:0".parse().unwrap());
+        let acceptor = server.acceptor();
+        let mut client_access = LurkClientAccess::default();
+        client_access.set_max_connections(1);
+        let mut listener_options = LurkTcpListenerOptions::default();
+        listener_options.set_client_access(client_access);
+
+        let tcp_listener = LurkTcpListener::bind_with_opts("127.0.0.1:0", &listener_options).await.unwrap();
+        let bound_addr = tcp_listener.local_addr();
+        let serve_acceptor = acceptor.clone();
+        let serve = tokio::spawn(async move { serve_acceptor.serve(tcp_listener, &listener_options).await });
+
+        // Client holds its only slot with the connection it hasn't sent anything over yet.
+        let _silent = TcpStream::connect(bound_addr).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        let mut client = TcpStream::connect(bound_addr).await.unwrap();
+        client
+            .write_all(b"GET http://127.0.0.1:1/ HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .expect("Connection should be closed")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{response}");
+
+        server.on_shutdown_requested();
+        serve.await.unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{
+    common::error::{LurkDenyReason, LurkDenySource},
+    io::mirror::LurkMirrorTarget,
+};
+use anyhow::{bail, ensure, Context, Result};
+use log::{error, info};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// Period between two checks whether the policy file has been modified.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LurkPolicyAction {
+    Allow,
+    Deny,
+    /// Allow, and tee a copy of the relayed data to the rule's ```mirror_to``` target
+    Mirror,
+    /// Allow, and record the relayed data for replaying it later on
+    Record,
+}
+
+/// Rule as it's written in the policy file. Exactly one of the matchers is expected.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkPolicyRuleEntry {
+    id: String,
+    #[serde(default)]
+    priority: i32,
+    action: LurkPolicyAction,
+    host: Option<String>,
+    domain_suffix: Option<String>,
+    regex: Option<String>,
+    mirror_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkPolicyFile {
+    #[serde(default)]
+    rules: Vec<LurkPolicyRuleEntry>,
+    default: LurkPolicyAction,
+}
+
+enum LurkPolicyMatcher {
+    Host(String),
+    DomainSuffix(String),
+    Regex(Regex),
+}
+
+impl LurkPolicyMatcher {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            LurkPolicyMatcher::Host(expected) => host == expected,
+            LurkPolicyMatcher::DomainSuffix(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.')),
+            LurkPolicyMatcher::Regex(regex) => regex.is_match(host),
+        }
+    }
+
+    fn pattern(&self) -> &str {
+        match self {
+            LurkPolicyMatcher::Host(host) => host,
+            LurkPolicyMatcher::DomainSuffix(suffix) => suffix,
+            LurkPolicyMatcher::Regex(regex) => regex.as_str(),
+        }
+    }
+}
+
+struct LurkPolicyRule {
+    id: String,
+    priority: i32,
+    action: LurkPolicyAction,
+    matcher: LurkPolicyMatcher,
+    mirror_to: Option<LurkMirrorTarget>,
+}
+
+/// Validated rules ordered by priority (higher first, file order among equal ones),
+/// followed by the final default action.
+pub struct LurkPolicyRules {
+    rules: Vec<LurkPolicyRule>,
+    default: LurkPolicyAction,
+}
+
+impl LurkPolicyRules {
+    /// Rule id destinations denied by the final default are attributed to.
+    pub const DEFAULT_RULE_ID: &'static str = "default";
+
+    pub fn parse(content: &str) -> Result<LurkPolicyRules> {
+        let file: LurkPolicyFile = serde_json::from_str(content)?;
+
+        let mut ids = HashSet::new();
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for (idx, entry) in file.rules.into_iter().enumerate() {
+            let rule = LurkPolicyRule::from_entry(entry).with_context(|| format!("rule #{} is invalid", idx + 1))?;
+            ensure!(rule.id != Self::DEFAULT_RULE_ID, "rule id '{}' is reserved", rule.id);
+            ensure!(ids.insert(rule.id.clone()), "rule id '{}' is not unique", rule.id);
+            rules.push(rule);
+        }
+
+        // Stable sort keeps file order of the rules with equal priority.
+        rules.sort_by_key(|rule| Reverse(rule.priority));
+
+        Ok(LurkPolicyRules {
+            rules,
+            default: file.default,
+        })
+    }
+
+    /// Action of the first rule matching destination host, or the default one.
+    pub fn evaluate(&self, host: &str) -> LurkPolicyAction {
+        self.matching_rule(host).map_or(self.default, |rule| rule.action)
+    }
+
+    /// Why the destination host is denied, if it is.
+    pub fn deny_reason(&self, host: &str) -> Option<LurkDenyReason> {
+        let (rule, pattern) = match self.matching_rule(host) {
+            Some(rule) if rule.action == LurkPolicyAction::Deny => (rule.id.as_str(), rule.matcher.pattern()),
+            None if self.default == LurkPolicyAction::Deny => (Self::DEFAULT_RULE_ID, "*"),
+            _ => return None,
+        };
+
+        Some(LurkDenyReason {
+            source: LurkDenySource::Policy,
+            rule: rule.to_owned(),
+            pattern: pattern.to_owned(),
+        })
+    }
+
+    /// Where data relayed to the destination host is mirrored, if it is.
+    pub fn mirror_target(&self, host: &str) -> Option<LurkMirrorTarget> {
+        self.matching_rule(host).and_then(|rule| rule.mirror_to.clone())
+    }
+
+    fn matching_rule(&self, host: &str) -> Option<&LurkPolicyRule> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.rules.iter().find(|rule| rule.matcher.matches(&host))
+    }
+}
+
+impl LurkPolicyRule {
+    fn from_entry(entry: LurkPolicyRuleEntry) -> Result<LurkPolicyRule> {
+        ensure!(!entry.id.is_empty(), "rule id is empty");
+        let matcher = match (entry.host, entry.domain_suffix, entry.regex) {
+            (Some(host), None, None) => LurkPolicyMatcher::Host(host.to_ascii_lowercase()),
+            (None, Some(suffix), None) => LurkPolicyMatcher::DomainSuffix(suffix.trim_start_matches('.').to_ascii_lowercase()),
+            (None, None, Some(regex)) => {
+                LurkPolicyMatcher::Regex(Regex::new(&regex).with_context(|| format!("regex of rule '{}' is malformed", entry.id))?)
+            }
+            _ => bail!("rule '{}' should have exactly one of 'host', 'domain_suffix' and 'regex'", entry.id),
+        };
+        let mirror_to = match (entry.action, entry.mirror_to) {
+            (LurkPolicyAction::Mirror, Some(target)) => Some(LurkMirrorTarget::parse(&target)?),
+            (LurkPolicyAction::Mirror, None) => bail!("mirror rule '{}' should have 'mirror_to'", entry.id),
+            (_, Some(_)) => bail!("rule '{}' has 'mirror_to', but its action isn't 'mirror'", entry.id),
+            (_, None) => None,
+        };
+
+        Ok(LurkPolicyRule {
+            id: entry.id,
+            priority: entry.priority,
+            action: entry.action,
+            matcher,
+            mirror_to,
+        })
+    }
+}
+
+/// File the policy rules are loaded from, along with its modification time at the last load.
+struct LurkPolicySource {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+/// Destination policy loaded from the file. File is reloaded once it's modified:
+/// new rules replace the old ones at once, and only if all of them are valid.
+pub struct LurkPolicy {
+    source: Mutex<LurkPolicySource>,
+    rules: RwLock<Arc<LurkPolicyRules>>,
+}
+
+impl LurkPolicy {
+    /// Load policy from the file. Unlike reloads, invalid file is an error here.
+    pub fn from_file(path: &Path) -> Result<LurkPolicy> {
+        let (rules, modified) = load(path)?;
+        Ok(LurkPolicy {
+            source: Mutex::new(LurkPolicySource {
+                path: path.to_owned(),
+                modified,
+            }),
+            rules: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    /// Load rules from another file, which is watched for modifications from now on.
+    /// Invalid file is an error, and the current rules are kept.
+    pub fn switch_to(&self, path: &Path) -> Result<()> {
+        let mut source = self.source.lock().expect("Policy lock is poisoned");
+        let (rules, modified) = load(path)?;
+
+        info!("Policy is switched to {}: {} rules", path.display(), rules.rules.len());
+        *self.rules.write().expect("Policy lock is poisoned") = Arc::new(rules);
+        *source = LurkPolicySource {
+            path: path.to_owned(),
+            modified,
+        };
+        Ok(())
+    }
+
+    pub fn evaluate(&self, host: &str) -> LurkPolicyAction {
+        self.rules().evaluate(host)
+    }
+
+    pub fn deny_reason(&self, host: &str) -> Option<LurkDenyReason> {
+        self.rules().deny_reason(host)
+    }
+
+    pub fn mirror_target(&self, host: &str) -> Option<LurkMirrorTarget> {
+        self.rules().mirror_target(host)
+    }
+
+    fn rules(&self) -> Arc<LurkPolicyRules> {
+        Arc::clone(&self.rules.read().expect("Policy lock is poisoned"))
+    }
+
+    /// Asynchronously reload modified policy file until cancelled.
+    pub async fn run(&self, token: CancellationToken) {
+        let mut ticker = interval(RELOAD_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.reload_if_modified(),
+                _ = token.cancelled() => return,
+            }
+        }
+    }
+
+    /// Reload policy if the file has been modified since the last load.
+    /// Invalid file is reported and the current rules are kept.
+    pub fn reload_if_modified(&self) {
+        let mut source = self.source.lock().expect("Policy lock is poisoned");
+        let current = std::fs::metadata(&source.path).and_then(|metadata| metadata.modified()).ok();
+        if current == source.modified {
+            return;
+        }
+        // Broken file isn't loaded again until it's modified once more.
+        source.modified = current;
+
+        match load(&source.path) {
+            Ok((rules, _)) => {
+                info!("Policy {} is reloaded: {} rules", source.path.display(), rules.rules.len());
+                *self.rules.write().expect("Policy lock is poisoned") = Arc::new(rules);
+            }
+            Err(err) => error!(
+                "Policy {} is not reloaded, keeping previous rules: {:#}",
+                source.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<(LurkPolicyRules, Option<SystemTime>)> {
+    let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let content = std::fs::read_to_string(path).with_context(|| format!("unable to read policy file {}", path.display()))?;
+    let rules = LurkPolicyRules::parse(&content).with_context(|| format!("policy file {} is invalid", path.display()))?;
+    Ok((rules, modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluate_rules() {
+        let rules = LurkPolicyRules::parse(
+            r#"{
+                "rules": [
+                    {"id": "no-example", "action": "deny", "domain_suffix": "example.com"},
+                    {"id": "api", "priority": 10, "action": "allow", "host": "API.example.com"},
+                    {"id": "trackers", "action": "deny", "regex": "^(ads|track)[0-9]*\\."}
+                ],
+                "default": "allow"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(LurkPolicyAction::Allow, rules.evaluate("api.example.com."));
+        assert_eq!(LurkPolicyAction::Deny, rules.evaluate("www.example.com"));
+        assert_eq!(LurkPolicyAction::Deny, rules.evaluate("Example.com"));
+        assert_eq!(LurkPolicyAction::Allow, rules.evaluate("notexample.com"));
+        assert_eq!(LurkPolicyAction::Deny, rules.evaluate("ads2.example.net"));
+        assert_eq!(LurkPolicyAction::Allow, rules.evaluate("203.0.113.7"));
+
+        assert_eq!(
+            Some(LurkDenyReason {
+                source: LurkDenySource::Policy,
+                rule: "no-example".to_owned(),
+                pattern: "example.com".to_owned()
+            }),
+            rules.deny_reason("www.example.com")
+        );
+        assert_eq!(None, rules.deny_reason("api.example.com"));
+
+        let rules = LurkPolicyRules::parse(r#"{"default": "deny"}"#).unwrap();
+        assert_eq!(
+            Some(LurkDenyReason {
+                source: LurkDenySource::Policy,
+                rule: "default".to_owned(),
+                pattern: "*".to_owned()
+            }),
+            rules.deny_reason("example.com")
+        );
+    }
+
+    #[test]
+    fn mirror_rules() {
+        let rules = LurkPolicyRules::parse(
+            r#"{
+                "rules": [
+                    {"id": "ids", "action": "mirror", "domain_suffix": "example.com", "mirror_to": "tcp://10.0.0.5:9000"},
+                    {"id": "no-ads", "priority": 10, "action": "deny", "host": "ads.example.com"}
+                ],
+                "default": "allow"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(LurkPolicyAction::Mirror, rules.evaluate("www.example.com"));
+        assert_eq!(None, rules.deny_reason("www.example.com"));
+        assert_eq!(
+            Some(LurkMirrorTarget::Tcp("10.0.0.5:9000".to_owned())),
+            rules.mirror_target("www.example.com")
+        );
+        assert_eq!(None, rules.mirror_target("ads.example.com"));
+        assert_eq!(None, rules.mirror_target("example.net"));
+
+        let rules =
+            LurkPolicyRules::parse(r#"{"rules": [{"id": "debug", "action": "record", "host": "example.com"}], "default": "allow"}"#)
+                .unwrap();
+        assert_eq!(LurkPolicyAction::Record, rules.evaluate("example.com"));
+        assert_eq!(None, rules.deny_reason("example.com"));
+        assert_eq!(None, rules.mirror_target("example.com"));
+    }
+
+    #[test]
+    fn reject_invalid_rules() {
+        let invalid = [
+            r#"{"rules": []}"#,
+            r#"{"rules": [{"id": "a", "action": "deny", "regex": "("}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "deny"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "deny", "host": "a", "regex": "a"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "deny", "host": "a"}, {"id": "a", "action": "allow", "host": "b"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "block", "host": "a"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "default", "action": "deny", "host": "a"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "mirror", "host": "a"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "mirror", "host": "a", "mirror_to": "udp://10.0.0.5:9000"}], "default": "allow"}"#,
+            r#"{"rules": [{"id": "a", "action": "allow", "host": "a", "mirror_to": "tcp://10.0.0.5:9000"}], "default": "allow"}"#,
+        ];
+
+        for content in invalid {
+            assert!(LurkPolicyRules::parse(content).is_err(), "{} should be rejected", content);
+        }
+    }
+
+    #[test]
+    fn reload_modified_file() {
+        let path = std::env::temp_dir().join(format!("lurk-policy-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"rules": [{"id": "a", "action": "deny", "host": "a.com"}], "default": "allow"}"#,
+        )
+        .unwrap();
+        let policy = LurkPolicy::from_file(&path).unwrap();
+        assert_eq!(LurkPolicyAction::Deny, policy.evaluate("a.com"));
+
+        let set_modified = |secs| {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+
+        // Broken file doesn't replace valid rules.
+        std::fs::write(
+            &path,
+            r#"{"rules": [{"id": "a", "action": "deny", "regex": "("}], "default": "allow"}"#,
+        )
+        .unwrap();
+        set_modified(1);
+        policy.reload_if_modified();
+        assert_eq!(LurkPolicyAction::Deny, policy.evaluate("a.com"));
+
+        std::fs::write(&path, r#"{"default": "deny"}"#).unwrap();
+        set_modified(2);
+        policy.reload_if_modified();
+        assert_eq!(LurkPolicyAction::Deny, policy.evaluate("b.com"));
+        assert_eq!(LurkPolicyAction::Deny, policy.evaluate("a.com"));
+
+        std::fs::write(&path, r#"{"default": "allow"}"#).unwrap();
+        set_modified(3);
+        policy.reload_if_modified();
+        assert_eq!(LurkPolicyAction::Allow, policy.evaluate("a.com"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
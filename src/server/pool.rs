@@ -0,0 +1,181 @@
+use crate::net::tcp;
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::Notify, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+/// Settings of the pool with pre-established connections.
+///
+/// **Fields**:
+/// * ```destinations``` - allowlist of destinations ("host:port") connections are kept for
+/// * ```size``` - number of connections kept for every destination
+/// * ```idle_timeout``` - pooled connection unused for longer than this period is closed
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkWarmPoolOptions {
+    destinations: Vec<String>,
+    size: usize,
+    idle_timeout: Duration,
+}
+
+impl LurkWarmPoolOptions {
+    /// Lower bound for the period between pool refills.
+    const MIN_REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(destinations: impl IntoIterator<Item = impl Into<String>>, size: usize, idle_timeout: Duration) -> LurkWarmPoolOptions {
+        LurkWarmPoolOptions {
+            destinations: destinations.into_iter().map(Into::into).collect(),
+            size,
+            idle_timeout,
+        }
+    }
+
+    /// Expired connections are replaced several times per idle timeout period.
+    fn refill_interval(&self) -> Duration {
+        (self.idle_timeout / 4).max(Self::MIN_REFILL_INTERVAL)
+    }
+}
+
+struct LurkPooledConnection {
+    stream: TcpStream,
+    established: Instant,
+}
+
+impl LurkPooledConnection {
+    /// Connection is usable if it hasn't expired and the destination hasn't closed it
+    /// (or sent something unexpected) while it was idling.
+    fn is_usable(&self, idle_timeout: Duration) -> bool {
+        self.established.elapsed() < idle_timeout
+            && matches!(self.stream.try_read(&mut [0u8; 1]), Err(err) if err.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+/// Pool of TCP connections established in advance to the popular destinations,
+/// so clients don't wait for the TCP handshake with them.
+pub struct LurkWarmPool {
+    options: LurkWarmPoolOptions,
+    connections: Mutex<HashMap<String, VecDeque<LurkPooledConnection>>>,
+    refill_requested: Notify,
+}
+
+impl LurkWarmPool {
+    pub fn new(options: LurkWarmPoolOptions) -> LurkWarmPool {
+        let connections = options
+            .destinations
+            .iter()
+            .map(|destination| (destination.to_ascii_lowercase(), VecDeque::new()))
+            .collect();
+
+        LurkWarmPool {
+            options,
+            connections: Mutex::new(connections),
+            refill_requested: Notify::new(),
+        }
+    }
+
+    /// Take pooled connection to the destination ("host:port"), if there is any.
+    pub fn take(&self, destination: &str) -> Option<TcpStream> {
+        let mut connections = self.connections();
+        let pooled = connections.get_mut(&destination.to_ascii_lowercase())?;
+
+        // Taken (or expired) connection is replaced in the background.
+        self.refill_requested.notify_one();
+
+        while let Some(conn) = pooled.pop_front() {
+            if conn.is_usable(self.options.idle_timeout) {
+                debug!("Took pooled connection to {}", destination);
+                return Some(conn.stream);
+            }
+        }
+
+        None
+    }
+
+    /// Keep the pool filled until the token is cancelled.
+    pub async fn run(&self, token: CancellationToken) {
+        info!(
+            "Warm pool is started: {} connection(s) to each of {:?}",
+            self.options.size, self.options.destinations
+        );
+
+        loop {
+            self.refill().await;
+
+            tokio::select! {
+                _ = sleep(self.options.refill_interval()) => continue,
+                _ = self.refill_requested.notified() => continue,
+                _ = token.cancelled() => break
+            }
+        }
+    }
+
+    /// Drop unusable connections and establish new ones instead.
+    async fn refill(&self) {
+        for destination in &self.options.destinations {
+            let key = destination.to_ascii_lowercase();
+            let missing = {
+                let mut connections = self.connections();
+                let pooled = connections.entry(key.clone()).or_default();
+                pooled.retain(|conn| conn.is_usable(self.options.idle_timeout));
+                self.options.size.saturating_sub(pooled.len())
+            };
+
+            for _ in 0..missing {
+                match tcp::establish_tcp_connection(destination.as_str()).await {
+                    Ok(stream) => self.connections().entry(key.clone()).or_default().push_back(LurkPooledConnection {
+                        stream,
+                        established: Instant::now(),
+                    }),
+                    Err(err) => {
+                        warn!("Unable to establish pooled connection to {}: {}", destination, err);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns number of connections ready to be taken for the destination.
+    pub fn get_pooled_connections(&self, destination: &str) -> usize {
+        self.connections().get(&destination.to_ascii_lowercase()).map_or(0, VecDeque::len)
+    }
+
+    fn connections(&self) -> MutexGuard<'_, HashMap<String, VecDeque<LurkPooledConnection>>> {
+        self.connections.lock().expect("warm pool lock is poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn take_and_expire() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = listener.local_addr().unwrap().to_string();
+
+        let pool = LurkWarmPool::new(LurkWarmPoolOptions::new([destination.as_str()], 2, Duration::from_secs(60)));
+        pool.refill().await;
+        assert_eq!(2, pool.get_pooled_connections(&destination));
+
+        // Keep accepted sockets open, otherwise pooled connections are considered closed.
+        let (_accepted1, _) = listener.accept().await.unwrap();
+        let (_accepted2, _) = listener.accept().await.unwrap();
+
+        assert!(pool.take(&destination).is_some());
+        assert!(pool.take(&destination).is_some());
+        assert!(pool.take(&destination).is_none());
+        assert!(pool.take("127.0.0.1:1").is_none());
+
+        // Expired connections are dropped.
+        let pool = LurkWarmPool::new(LurkWarmPoolOptions::new([destination.as_str()], 1, Duration::ZERO));
+        pool.refill().await;
+        assert!(pool.take(&destination).is_none());
+    }
+}
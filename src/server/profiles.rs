@@ -0,0 +1,171 @@
+use crate::auth::LurkAuthMethod;
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Named set of the settings switched at runtime at once, e.g. "strict", "open" or "maintenance".
+/// Settings omitted by the profile are taken from the command line options.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LurkProfile {
+    /// Built-in SOCKS5 authentication methods offered to new clients, in the order of preference.
+    #[serde(default, deserialize_with = "deserialize_auth_methods")]
+    pub auth_methods: Option<Vec<LurkAuthMethod>>,
+    /// Destination policy (the same format as "--policy-file").
+    #[serde(default)]
+    pub policy_file: Option<PathBuf>,
+    /// Limit of simultaneous sessions of every user without their own one.
+    #[serde(default)]
+    pub max_sessions_per_user: Option<usize>,
+}
+
+impl LurkProfile {
+    /// Profile with the omitted settings taken from the defaults.
+    fn or(&self, defaults: &LurkProfile) -> LurkProfile {
+        LurkProfile {
+            auth_methods: self.auth_methods.clone().or_else(|| defaults.auth_methods.clone()),
+            policy_file: self.policy_file.clone().or_else(|| defaults.policy_file.clone()),
+            max_sessions_per_user: self.max_sessions_per_user.or(defaults.max_sessions_per_user),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkProfilesFile {
+    active: String,
+    profiles: BTreeMap<String, LurkProfile>,
+}
+
+/// Profiles loaded from the file along with the name of the active one.
+pub struct LurkProfiles {
+    defaults: LurkProfile,
+    profiles: BTreeMap<String, LurkProfile>,
+    active: Mutex<String>,
+}
+
+impl LurkProfiles {
+    /// Load profiles from the JSON file. Settings omitted by the profiles are taken from ```defaults```.
+    pub fn from_file(path: &Path, defaults: LurkProfile) -> Result<LurkProfiles> {
+        let content = std::fs::read(path).with_context(|| format!("unable to read profiles file {}", path.display()))?;
+        let file: LurkProfilesFile =
+            serde_json::from_slice(&content).with_context(|| format!("profiles file {} is malformed", path.display()))?;
+
+        LurkProfiles::new(file, defaults).with_context(|| format!("profiles file {} is invalid", path.display()))
+    }
+
+    fn new(file: LurkProfilesFile, defaults: LurkProfile) -> Result<LurkProfiles> {
+        ensure!(
+            file.profiles.contains_key(&file.active),
+            "active profile '{}' is not defined",
+            file.active
+        );
+        Ok(LurkProfiles {
+            defaults,
+            profiles: file.profiles,
+            active: Mutex::new(file.active),
+        })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.keys().map(String::as_str).collect()
+    }
+
+    /// Profile with the given name, with the omitted settings taken from the defaults.
+    pub fn get(&self, name: &str) -> Option<LurkProfile> {
+        self.profiles.get(name).map(|profile| profile.or(&self.defaults))
+    }
+
+    /// Profiles as they are written in the file, without the defaults.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &LurkProfile)> {
+        self.profiles.iter().map(|(name, profile)| (name.as_str(), profile))
+    }
+
+    pub fn active(&self) -> String {
+        self.active.lock().expect("Profiles lock is poisoned").clone()
+    }
+
+    /// Mark another profile as the active one. Returns the name of the previous one.
+    pub fn set_active(&self, name: &str) -> String {
+        std::mem::replace(&mut *self.active.lock().expect("Profiles lock is poisoned"), name.to_owned())
+    }
+}
+
+fn deserialize_auth_methods<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<LurkAuthMethod>>, D::Error> {
+    let Some(methods) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    methods
+        .iter()
+        .map(|method| method.parse::<LurkAuthMethod>())
+        .collect::<Result<_>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profiles_file() {
+        let file: LurkProfilesFile = serde_json::from_str(
+            r#"{
+                "active": "strict",
+                "profiles": {
+                    "strict": { "auth_methods": ["password"], "max_sessions_per_user": 1 },
+                    "open": { "auth_methods": ["password", "none"], "policy_file": "/etc/lurk/open-policy.json" }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let defaults = LurkProfile {
+            auth_methods: None,
+            policy_file: Some(PathBuf::from("/etc/lurk/policy.json")),
+            max_sessions_per_user: Some(4),
+        };
+        let profiles = LurkProfiles::new(file, defaults).unwrap();
+        assert_eq!(vec!["open", "strict"], profiles.names());
+        assert_eq!("strict", profiles.active());
+
+        assert_eq!(
+            Some(LurkProfile {
+                auth_methods: Some(vec![LurkAuthMethod::Password]),
+                policy_file: Some(PathBuf::from("/etc/lurk/policy.json")),
+                max_sessions_per_user: Some(1),
+            }),
+            profiles.get("strict")
+        );
+        assert_eq!(
+            Some(LurkProfile {
+                auth_methods: Some(vec![LurkAuthMethod::Password, LurkAuthMethod::None]),
+                policy_file: Some(PathBuf::from("/etc/lurk/open-policy.json")),
+                max_sessions_per_user: Some(4),
+            }),
+            profiles.get("open")
+        );
+        assert_eq!(None, profiles.get("maintenance"));
+
+        assert_eq!("strict", profiles.set_active("open"));
+        assert_eq!("open", profiles.active());
+    }
+
+    #[test]
+    fn invalid_profiles_files() {
+        let parse = |content: &str| -> Result<LurkProfiles> {
+            let file: LurkProfilesFile = serde_json::from_str(content)?;
+            LurkProfiles::new(file, LurkProfile::default())
+        };
+
+        assert!(parse(r#"{"active": "strict", "profiles": {"open": {}}}"#).is_err());
+        assert!(parse(r#"{"active": "open", "profiles": {"open": {"auth_methods": ["gssapi"]}}}"#).is_err());
+        assert!(parse(r#"{"active": "open", "profiles": {"open": {"acl": "none"}}}"#).is_err());
+        assert!(parse(r#"{"active": "open", "profiles": {"open": {}}}"#).is_ok());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::io::mirror::LurkTunnelMirror;
+use log::info;
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// Recordings of the selected tunnels, replayable with "lurk replay".
+///
+/// Tunnels are recorded either by the "record" rules of the policy,
+/// or for the clients recording has been enabled for through the HTTP endpoint.
+pub struct LurkRecordings {
+    dir: PathBuf,
+    clients: RwLock<BTreeSet<IpAddr>>,
+}
+
+impl LurkRecordings {
+    pub fn new(dir: impl Into<PathBuf>) -> LurkRecordings {
+        LurkRecordings {
+            dir: dir.into(),
+            clients: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    /// Directory recordings are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Record new tunnels of the client. Returns false if they are already recorded.
+    pub fn enable(&self, client: IpAddr) -> bool {
+        let enabled = self.client_set_mut().insert(client);
+        if enabled {
+            info!("Recording of tunnels from {} is enabled", client);
+        }
+        enabled
+    }
+
+    /// Stop recording new tunnels of the client. Returns false if they weren't recorded.
+    pub fn disable(&self, client: IpAddr) -> bool {
+        let disabled = self.client_set_mut().remove(&client);
+        if disabled {
+            info!("Recording of tunnels from {} is disabled", client);
+        }
+        disabled
+    }
+
+    /// Clients whose tunnels are recorded, in ascending order.
+    pub fn clients(&self) -> Vec<IpAddr> {
+        self.client_set().iter().copied().collect()
+    }
+
+    pub fn is_enabled_for(&self, client: IpAddr) -> bool {
+        self.client_set().contains(&client)
+    }
+
+    /// Start recording the tunnel between ```peer_addr``` and ```destination```.
+    pub fn record(&self, peer_addr: SocketAddr, destination: &str) -> LurkTunnelMirror {
+        LurkTunnelMirror::record(&self.dir, peer_addr, destination)
+    }
+
+    fn client_set(&self) -> RwLockReadGuard<'_, BTreeSet<IpAddr>> {
+        self.clients.read().expect("Recordings lock is poisoned")
+    }
+
+    fn client_set_mut(&self) -> RwLockWriteGuard<'_, BTreeSet<IpAddr>> {
+        self.clients.write().expect("Recordings lock is poisoned")
+    }
+}
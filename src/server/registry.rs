@@ -0,0 +1,142 @@
+use crate::{
+    io::tunnel::LurkTunnelActivity,
+    net::tcp::connection::{LurkSessionInfo, LurkTcpConnection, LurkTcpConnectionLabel},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio_util::sync::CancellationToken;
+
+/// Unique identifier of the connection served by the proxy.
+pub type LurkConnectionId = u64;
+
+/// Registry of connections that are currently handled by the server.
+pub struct LurkConnectionRegistry {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<LurkConnectionId, Arc<LurkConnectionEntry>>>,
+}
+
+impl LurkConnectionRegistry {
+    pub fn new() -> LurkConnectionRegistry {
+        LurkConnectionRegistry {
+            next_id: AtomicU64::new(1),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Put the connection into the registry. It stays registered until returned guard is dropped.
+    ///
+    /// Passed `token` is expected to cancel the task which is serving this connection.
+    pub fn register(self: &Arc<Self>, conn: &LurkTcpConnection, token: CancellationToken) -> LurkRegisteredConnection {
+        let entry = Arc::new(LurkConnectionEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            peer_addr: conn.peer_addr(),
+            label: conn.label(),
+            established_ts: Utc::now(),
+            activity: conn.activity(),
+            session: conn.session(),
+            token,
+        });
+
+        self.entries().insert(entry.id, Arc::clone(&entry));
+
+        LurkRegisteredConnection {
+            registry: Arc::clone(self),
+            entry,
+        }
+    }
+
+    /// Returns all currently registered connections.
+    pub fn snapshot(&self) -> Vec<Arc<LurkConnectionEntry>> {
+        self.entries().values().cloned().collect()
+    }
+
+    /// Returns the connection registered with the ```id```, if it's still served.
+    pub fn get(&self, id: LurkConnectionId) -> Option<Arc<LurkConnectionEntry>> {
+        self.entries().get(&id).cloned()
+    }
+
+    fn deregister(&self, id: LurkConnectionId) {
+        self.entries().remove(&id);
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<LurkConnectionId, Arc<LurkConnectionEntry>>> {
+        self.entries.lock().expect("connection registry lock is poisoned")
+    }
+}
+
+impl Default for LurkConnectionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Information about single connection stored in the registry.
+pub struct LurkConnectionEntry {
+    id: LurkConnectionId,
+    peer_addr: SocketAddr,
+    label: LurkTcpConnectionLabel,
+    established_ts: DateTime<Utc>,
+    activity: Arc<LurkTunnelActivity>,
+    session: Arc<LurkSessionInfo>,
+    token: CancellationToken,
+}
+
+impl LurkConnectionEntry {
+    pub fn id(&self) -> LurkConnectionId {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn label(&self) -> LurkTcpConnectionLabel {
+        self.label
+    }
+
+    pub fn established_ts(&self) -> DateTime<Utc> {
+        self.established_ts
+    }
+
+    pub fn activity(&self) -> &LurkTunnelActivity {
+        &self.activity
+    }
+
+    pub fn session(&self) -> &LurkSessionInfo {
+        &self.session
+    }
+
+    /// Cancel the task serving this connection.
+    pub fn close(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+/// Guard removing the connection from the registry once dropped.
+pub struct LurkRegisteredConnection {
+    registry: Arc<LurkConnectionRegistry>,
+    entry: Arc<LurkConnectionEntry>,
+}
+
+impl LurkRegisteredConnection {
+    pub fn entry(&self) -> &LurkConnectionEntry {
+        &self.entry
+    }
+}
+
+impl Drop for LurkRegisteredConnection {
+    fn drop(&mut self) {
+        self.registry.deregister(self.entry.id);
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Datelike, Days, DurationRound, NaiveTime, TimeDelta, Utc};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Cron-like schedule of the restarts: "minute hour day-of-month month day-of-week" in UTC.
+///
+/// Every field is either ```*``` or a comma-separated list of values (```5```), ranges (```1-5```)
+/// and steps (```*/15```, ```0-30/10```). Day of week is ```0```-```7```, both ```0``` and ```7``` are Sunday.
+/// As in cron, if both days of month and week are restricted, matching either of them is enough.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkRestartSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl LurkRestartSchedule {
+    /// Days to look the next restart up within, long enough for "29th of February" schedules.
+    const LOOKUP_DAYS: u64 = 8 * 366;
+
+    /// Closest time matching the schedule strictly after ```after```.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let after = after.duration_trunc(TimeDelta::minutes(1)).ok()?;
+        let mut day = after.date_naive();
+
+        for _ in 0..LurkRestartSchedule::LOOKUP_DAYS {
+            if self.matches_day(day.day(), day.month(), day.weekday().num_days_from_sunday()) {
+                for hour in (0..24).filter(|hour| bit_set(self.hours, *hour)) {
+                    for minute in (0..60).filter(|minute| bit_set(self.minutes, *minute)) {
+                        let time = day.and_time(NaiveTime::from_hms_opt(hour, minute, 0)?).and_utc();
+                        if time > after {
+                            return Some(time);
+                        }
+                    }
+                }
+            }
+            day = day.checked_add_days(Days::new(1))?;
+        }
+
+        None
+    }
+
+    fn matches_day(&self, day_of_month: u32, month: u32, day_of_week: u32) -> bool {
+        let day_of_month_matches = bit_set(self.days_of_month, day_of_month);
+        let day_of_week_matches = bit_set(self.days_of_week, day_of_week);
+        let day_matches = match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month_matches || day_of_week_matches,
+            _ => day_of_month_matches && day_of_week_matches,
+        };
+
+        day_matches && bit_set(self.months, month)
+    }
+}
+
+impl FromStr for LurkRestartSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<LurkRestartSchedule> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            bail!("schedule '{}' should have 5 fields, got {}", expression, fields.len());
+        };
+
+        let mut days_of_week_mask = parse_field(days_of_week, 0, 7).context("invalid day of week")?;
+        // Both 0 and 7 stand for Sunday.
+        if bit_set(days_of_week_mask, 7) {
+            days_of_week_mask |= 1;
+        }
+
+        Ok(LurkRestartSchedule {
+            expression: expression.to_owned(),
+            minutes: parse_field(minutes, 0, 59).context("invalid minute")?,
+            hours: parse_field(hours, 0, 23).context("invalid hour")?,
+            days_of_month: parse_field(days_of_month, 1, 31).context("invalid day of month")?,
+            months: parse_field(months, 1, 12).context("invalid month")?,
+            days_of_week: days_of_week_mask,
+            any_day_of_month: days_of_month == "*",
+            any_day_of_week: days_of_week == "*",
+        })
+    }
+}
+
+impl fmt::Display for LurkRestartSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+/// Parse field of the schedule into the mask of the matching values.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().with_context(|| format!("invalid step '{}'", step))?),
+            None => (item, 1),
+        };
+        ensure!(step > 0, "step should be positive");
+
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (parse_value(from, min, max)?, parse_value(to, min, max)?),
+                // Single value with a step runs till the end of the range.
+                None if item.contains('/') => (parse_value(range, min, max)?, max),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    (value, value)
+                }
+            },
+        };
+        ensure!(from <= to, "range {}-{} is reversed", from, to);
+
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32> {
+    let value = value.parse::<u32>().with_context(|| format!("'{}' is not a number", value))?;
+    ensure!((min..=max).contains(&value), "{} is out of range {}-{}", value, min, max);
+    Ok(value)
+}
+
+fn bit_set(mask: u64, bit: u32) -> bool {
+    mask & (1 << bit) != 0
+}
+
+/// Settings of the scheduled restarts.
+///
+/// **Fields**:
+/// * ```schedule``` - when the node starts draining before the restart
+/// * ```drain_deadline``` - maximum time given to the active connections to finish, the rest are closed
+/// * ```exit_code``` - code the process exits with, so the supervisor restarts it
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkRestartOptions {
+    schedule: LurkRestartSchedule,
+    drain_deadline: Duration,
+    exit_code: i32,
+}
+
+impl LurkRestartOptions {
+    /// Default code of the exit on scheduled restart (EX_TEMPFAIL).
+    pub const DEFAULT_EXIT_CODE: i32 = 75;
+
+    pub fn new(schedule: LurkRestartSchedule, drain_deadline: Duration, exit_code: i32) -> LurkRestartOptions {
+        LurkRestartOptions {
+            schedule,
+            drain_deadline,
+            exit_code,
+        }
+    }
+
+    pub fn schedule(&self) -> &LurkRestartSchedule {
+        &self.schedule
+    }
+
+    pub fn drain_deadline(&self) -> Duration {
+        self.drain_deadline
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn next_restart() {
+        let daily: LurkRestartSchedule = "30 4 * * *".parse().unwrap();
+        assert_eq!(Some(utc("2024-05-10T04:30:00Z")), daily.next_after(utc("2024-05-10T01:00:00Z")));
+        assert_eq!(Some(utc("2024-05-11T04:30:00Z")), daily.next_after(utc("2024-05-10T04:30:00Z")));
+
+        let quarterly: LurkRestartSchedule = "*/15 * * * *".parse().unwrap();
+        assert_eq!(Some(utc("2024-05-10T01:15:00Z")), quarterly.next_after(utc("2024-05-10T01:07:59Z")));
+
+        // 2024-05-10 is Friday, next Sunday is the 12th.
+        let sundays: LurkRestartSchedule = "0 3 * * 7".parse().unwrap();
+        assert_eq!(Some(utc("2024-05-12T03:00:00Z")), sundays.next_after(utc("2024-05-10T01:00:00Z")));
+
+        // Either the 1st of the month or Monday.
+        let either: LurkRestartSchedule = "0 0 1 * 1".parse().unwrap();
+        assert_eq!(Some(utc("2024-05-13T00:00:00Z")), either.next_after(utc("2024-05-10T01:00:00Z")));
+        assert_eq!(Some(utc("2024-06-01T00:00:00Z")), either.next_after(utc("2024-05-28T01:00:00Z")));
+
+        let leap_day: LurkRestartSchedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(Some(utc("2028-02-29T00:00:00Z")), leap_day.next_after(utc("2024-05-10T01:00:00Z")));
+
+        let never: LurkRestartSchedule = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(None, never.next_after(utc("2024-05-10T01:00:00Z")));
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        for expression in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(expression.parse::<LurkRestartSchedule>().is_err(), "'{}' is accepted", expression);
+        }
+    }
+}
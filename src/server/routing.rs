@@ -0,0 +1,463 @@
+#[cfg(feature = "upstream-tls")]
+use crate::net::upstream_tls::{self, LurkUpstreamTls, LurkUpstreamTlsOptions};
+use crate::{
+    common::error::{LurkDenyReason, LurkDenySource},
+    net::{socks5::LurkUpstreamProxy, Address},
+};
+use anyhow::{bail, ensure, Context, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LurkRouteAction {
+    Direct,
+    /// Connect through the parent SOCKS5 proxy named by the rule's ```upstream```
+    Upstream,
+    Block,
+}
+
+/// Where the destination is connected to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LurkRoute {
+    Direct,
+    Upstream(Arc<LurkUpstreamProxy>),
+    Block(LurkDenyReason),
+}
+
+/// Parent proxy as it's written in the routing file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkUpstreamEntry {
+    addr: SocketAddr,
+    user: Option<String>,
+    password: Option<String>,
+    #[cfg(feature = "upstream-tls")]
+    tls: Option<LurkUpstreamTlsEntry>,
+}
+
+/// TLS the parent proxy is connected over, as it's written in the routing file.
+#[cfg(feature = "upstream-tls")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkUpstreamTlsEntry {
+    server_name: Option<String>,
+    ca_bundle: Option<std::path::PathBuf>,
+    #[serde(default)]
+    pin_sha256: Vec<String>,
+    #[serde(default)]
+    insecure_skip_verify: bool,
+}
+
+#[cfg(feature = "upstream-tls")]
+impl LurkUpstreamTlsEntry {
+    fn into_tls(self) -> Result<LurkUpstreamTls> {
+        let mut options = LurkUpstreamTlsOptions::new();
+        if let Some(server_name) = self.server_name {
+            options.set_server_name(server_name);
+        }
+        if let Some(ca_bundle) = self.ca_bundle {
+            options.set_ca_bundle(ca_bundle);
+        }
+        let pins = self.pin_sha256.iter().map(|pin| upstream_tls::parse_pin_sha256(pin));
+        options
+            .set_pins(pins.collect::<Result<Vec<_>>>()?)
+            .set_insecure_skip_verify(self.insecure_skip_verify);
+
+        LurkUpstreamTls::new(options)
+    }
+}
+
+/// Rule as it's written in the routing file. Exactly one of the matchers is expected.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkRouteRuleEntry {
+    id: String,
+    action: LurkRouteAction,
+    upstream: Option<String>,
+    domain_suffix: Option<String>,
+    cidr: Option<IpNet>,
+    port: Option<u16>,
+    rate_limit: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkRoutingFile {
+    #[serde(default)]
+    upstreams: HashMap<String, LurkUpstreamEntry>,
+    #[serde(default)]
+    rules: Vec<LurkRouteRuleEntry>,
+    default: Option<LurkRouteAction>,
+    default_upstream: Option<String>,
+}
+
+enum LurkRouteMatcher {
+    DomainSuffix(String),
+    Cidr(IpNet),
+    Port(u16),
+}
+
+impl LurkRouteMatcher {
+    fn matches(&self, address: &Address) -> bool {
+        match (self, address) {
+            (LurkRouteMatcher::DomainSuffix(suffix), Address::DomainName(name, _)) => {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                name.strip_suffix(suffix.as_str())
+                    .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+            }
+            (LurkRouteMatcher::Cidr(net), Address::SocketAddress(addr)) => net.contains(&addr.ip()),
+            (LurkRouteMatcher::Port(port), Address::SocketAddress(addr)) => addr.port() == *port,
+            (LurkRouteMatcher::Port(port), Address::DomainName(_, name_port)) => name_port == port,
+            _ => false,
+        }
+    }
+
+    fn pattern(&self) -> String {
+        match self {
+            LurkRouteMatcher::DomainSuffix(suffix) => suffix.clone(),
+            LurkRouteMatcher::Cidr(net) => net.to_string(),
+            LurkRouteMatcher::Port(port) => format!(":{port}"),
+        }
+    }
+}
+
+struct LurkRouteRule {
+    id: String,
+    matcher: LurkRouteMatcher,
+    route: LurkRoute,
+    rate_limit: Option<u64>,
+}
+
+/// Routing rules deciding whether destinations are connected directly, through one of
+/// the parent proxies or not at all. Rules are evaluated in the file order, followed by
+/// the optional default. Domain names aren't resolved to match them against CIDR rules.
+/// Rules could also limit the rate (bytes/sec) the tunnels to their destinations relay at.
+/// Clients could pick one of the parent proxies by the hint in their username (e.g. "alice+de").
+pub struct LurkRouting {
+    upstreams: HashMap<String, Arc<LurkUpstreamProxy>>,
+    rules: Vec<LurkRouteRule>,
+    default: Option<LurkRoute>,
+}
+
+impl LurkRouting {
+    /// Rule id destinations blocked by the default are attributed to.
+    pub const DEFAULT_RULE_ID: &'static str = "default";
+
+    /// Separates the username from the routing hint following it.
+    pub const ROUTE_HINT_SEPARATOR: char = '+';
+
+    pub fn from_file(path: &Path) -> Result<LurkRouting> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("failed to read routing file {}", path.display()))?;
+        LurkRouting::parse(&content).with_context(|| format!("routing file {} is invalid", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<LurkRouting> {
+        let file: LurkRoutingFile = serde_json::from_str(content)?;
+
+        let mut upstreams = HashMap::with_capacity(file.upstreams.len());
+        for (name, entry) in file.upstreams {
+            let mut upstream = LurkUpstreamProxy::new(entry.addr);
+            match (entry.user, entry.password) {
+                (Some(user), Some(password)) => {
+                    upstream.set_credentials(user, password);
+                }
+                (None, None) => {}
+                _ => bail!("upstream '{}' should have both 'user' and 'password' or neither", name),
+            }
+            #[cfg(feature = "upstream-tls")]
+            if let Some(tls) = entry.tls {
+                upstream.set_tls(tls.into_tls().with_context(|| format!("TLS of upstream '{}' is invalid", name))?);
+            }
+            upstreams.insert(name, Arc::new(upstream));
+        }
+
+        let mut ids = HashSet::new();
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for (idx, entry) in file.rules.into_iter().enumerate() {
+            let rule = LurkRouteRule::from_entry(entry, &upstreams).with_context(|| format!("rule #{} is invalid", idx + 1))?;
+            ensure!(rule.id != Self::DEFAULT_RULE_ID, "rule id '{}' is reserved", rule.id);
+            ensure!(ids.insert(rule.id.clone()), "rule id '{}' is not unique", rule.id);
+            rules.push(rule);
+        }
+
+        let default = match file.default {
+            Some(action) => Some(route(action, file.default_upstream, &upstreams, || LurkDenyReason {
+                source: LurkDenySource::Routing,
+                rule: Self::DEFAULT_RULE_ID.to_owned(),
+                pattern: "*".to_owned(),
+            })?),
+            None => {
+                ensure!(file.default_upstream.is_none(), "'default_upstream' is set, but 'default' isn't");
+                None
+            }
+        };
+
+        Ok(LurkRouting { upstreams, rules, default })
+    }
+
+    /// Route of the first rule matching the destination, or the default one if there is any.
+    pub fn route(&self, address: &Address) -> Option<LurkRoute> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matcher.matches(address))
+            .map(|rule| rule.route.clone())
+            .or_else(|| self.default.clone())
+    }
+
+    /// Split the username into the name of the user and the routing hint, if it ends with the one naming the parent proxy.
+    /// Names of the users could contain the separator as well, so the username is left as is if there is no such proxy.
+    pub fn split_route_hint<'a>(&self, username: &'a str) -> Option<(&'a str, &'a str)> {
+        username
+            .rsplit_once(Self::ROUTE_HINT_SEPARATOR)
+            .filter(|(name, hint)| !name.is_empty() && self.upstreams.contains_key(*hint))
+    }
+
+    /// Route of the destination for the client, which has picked the parent proxy by the hint.
+    /// Destinations blocked by the rules stay blocked, the rest are connected through the picked proxy.
+    pub fn hinted_route(&self, address: &Address, hint: &str) -> Option<LurkRoute> {
+        match self.route(address) {
+            Some(LurkRoute::Block(reason)) => Some(LurkRoute::Block(reason)),
+            _ => self.upstreams.get(hint).map(|upstream| LurkRoute::Upstream(Arc::clone(upstream))),
+        }
+    }
+
+    /// Domain suffixes of the rules clients could evaluate by themselves (e.g. by proxy auto-config file) in the file order,
+    /// paired with whether they are connected directly. Clients can't tell whether the destination matches the rule by IP
+    /// or port before connecting, so the rules following the first of them, which isn't direct, are left out.
+    pub fn domain_routes(&self) -> Vec<(String, bool)> {
+        let mut routes = Vec::new();
+        for rule in &self.rules {
+            match (&rule.matcher, &rule.route) {
+                (LurkRouteMatcher::DomainSuffix(suffix), route) => routes.push((suffix.clone(), *route == LurkRoute::Direct)),
+                (_, LurkRoute::Direct) => continue,
+                _ => break,
+            }
+        }
+        routes
+    }
+
+    /// Rate limit of the first rule matching the destination, if it has one.
+    pub fn rate_limit(&self, address: &Address) -> Option<u64> {
+        self.rules.iter().find(|rule| rule.matcher.matches(address))?.rate_limit
+    }
+}
+
+impl LurkRouteRule {
+    fn from_entry(entry: LurkRouteRuleEntry, upstreams: &HashMap<String, Arc<LurkUpstreamProxy>>) -> Result<LurkRouteRule> {
+        ensure!(!entry.id.is_empty(), "rule id is empty");
+        let matcher = match (entry.domain_suffix, entry.cidr, entry.port) {
+            (Some(suffix), None, None) => LurkRouteMatcher::DomainSuffix(suffix.trim_start_matches('.').to_ascii_lowercase()),
+            (None, Some(net), None) => LurkRouteMatcher::Cidr(net.trunc()),
+            (None, None, Some(port)) => LurkRouteMatcher::Port(port),
+            _ => bail!("rule '{}' should have exactly one of 'domain_suffix', 'cidr' and 'port'", entry.id),
+        };
+        let route = route(entry.action, entry.upstream, upstreams, || LurkDenyReason {
+            source: LurkDenySource::Routing,
+            rule: entry.id.clone(),
+            pattern: matcher.pattern(),
+        })
+        .with_context(|| format!("route of rule '{}' is invalid", entry.id))?;
+        match (&route, entry.rate_limit) {
+            (_, Some(0)) => bail!("rate limit of rule '{}' should be positive", entry.id),
+            (LurkRoute::Block(_), Some(_)) => bail!("rule '{}' blocks destinations, but limits their rate", entry.id),
+            _ => {}
+        }
+
+        Ok(LurkRouteRule {
+            id: entry.id,
+            matcher,
+            route,
+            rate_limit: entry.rate_limit,
+        })
+    }
+}
+
+fn route(
+    action: LurkRouteAction,
+    upstream: Option<String>,
+    upstreams: &HashMap<String, Arc<LurkUpstreamProxy>>,
+    deny_reason: impl FnOnce() -> LurkDenyReason,
+) -> Result<LurkRoute> {
+    match (action, upstream) {
+        (LurkRouteAction::Upstream, Some(name)) => match upstreams.get(&name) {
+            Some(upstream) => Ok(LurkRoute::Upstream(Arc::clone(upstream))),
+            None => bail!("upstream '{}' isn't defined", name),
+        },
+        (LurkRouteAction::Upstream, None) => bail!("upstream route should name the upstream"),
+        (_, Some(_)) => bail!("upstream is named, but the action isn't 'upstream'"),
+        (LurkRouteAction::Direct, None) => Ok(LurkRoute::Direct),
+        (LurkRouteAction::Block, None) => Ok(LurkRoute::Block(deny_reason())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn domain(name: &str, port: u16) -> Address {
+        Address::DomainName(name.to_owned(), port)
+    }
+
+    fn ip(addr: &str) -> Address {
+        Address::SocketAddress(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn route_destinations() {
+        let routing = LurkRouting::parse(
+            r#"{
+                "upstreams": {
+                    "corp": {"addr": "10.0.0.1:1080", "user": "alice", "password": "secret"}
+                },
+                "rules": [
+                    {"id": "smtp", "action": "block", "port": 25},
+                    {"id": "intranet", "action": "direct", "cidr": "10.0.0.0/8", "rate_limit": 1048576},
+                    {"id": "corp", "action": "upstream", "upstream": "corp", "domain_suffix": ".Corp.example.com"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let mut corp = LurkUpstreamProxy::new("10.0.0.1:1080".parse().unwrap());
+        corp.set_credentials("alice", "secret");
+        assert_eq!(
+            Some(LurkRoute::Upstream(Arc::new(corp))),
+            routing.route(&domain("git.corp.example.com.", 443))
+        );
+        assert_eq!(Some(LurkRoute::Direct), routing.route(&ip("10.1.2.3:443")));
+        assert_eq!(
+            Some(LurkRoute::Block(LurkDenyReason {
+                source: LurkDenySource::Routing,
+                rule: "smtp".to_owned(),
+                pattern: ":25".to_owned()
+            })),
+            routing.route(&domain("corp.example.com", 25))
+        );
+        assert_eq!(None, routing.route(&domain("example.com", 443)));
+        assert_eq!(None, routing.route(&ip("192.0.2.1:443")));
+
+        assert_eq!(Some(1048576), routing.rate_limit(&ip("10.1.2.3:443")));
+        assert_eq!(None, routing.rate_limit(&domain("git.corp.example.com", 443)));
+        assert_eq!(None, routing.rate_limit(&ip("192.0.2.1:443")));
+
+        let routing = LurkRouting::parse(r#"{"default": "block"}"#).unwrap();
+        assert!(matches!(routing.route(&ip("192.0.2.1:443")), Some(LurkRoute::Block(reason)) if reason.rule == "default"));
+    }
+
+    #[test]
+    fn route_by_hint() {
+        let routing = LurkRouting::parse(
+            r#"{
+                "upstreams": {
+                    "de": {"addr": "10.0.0.1:1080"},
+                    "us": {"addr": "10.0.0.2:1080"}
+                },
+                "rules": [
+                    {"id": "smtp", "action": "block", "port": 25},
+                    {"id": "intranet", "action": "direct", "cidr": "10.0.0.0/8"}
+                ],
+                "default": "upstream",
+                "default_upstream": "us"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some(("alice", "de")), routing.split_route_hint("alice+de"));
+        assert_eq!(Some(("alice+bob", "us")), routing.split_route_hint("alice+bob+us"));
+        assert_eq!(None, routing.split_route_hint("alice"));
+        assert_eq!(None, routing.split_route_hint("alice+fr"));
+        assert_eq!(None, routing.split_route_hint("+de"));
+
+        let de = LurkRoute::Upstream(Arc::new(LurkUpstreamProxy::new("10.0.0.1:1080".parse().unwrap())));
+        assert_eq!(Some(de.clone()), routing.hinted_route(&domain("example.com", 443), "de"));
+        assert_eq!(Some(de), routing.hinted_route(&ip("10.1.2.3:443"), "de"));
+        assert!(matches!(
+            routing.hinted_route(&domain("example.com", 25), "de"),
+            Some(LurkRoute::Block(reason)) if reason.rule == "smtp"
+        ));
+        assert_eq!(None, routing.hinted_route(&domain("example.com", 443), "fr"));
+    }
+
+    #[test]
+    fn list_domain_routes() {
+        let routing = LurkRouting::parse(
+            r#"{
+                "upstreams": {"corp": {"addr": "10.0.0.1:1080"}},
+                "rules": [
+                    {"id": "ads", "action": "block", "domain_suffix": "ads.example.com"},
+                    {"id": "intranet", "action": "direct", "cidr": "10.0.0.0/8"},
+                    {"id": "example", "action": "direct", "domain_suffix": "example.com"},
+                    {"id": "corp", "action": "upstream", "upstream": "corp", "domain_suffix": "corp.lan"},
+                    {"id": "smtp", "action": "block", "port": 25},
+                    {"id": "internal", "action": "direct", "domain_suffix": "internal"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                ("ads.example.com".to_owned(), false),
+                ("example.com".to_owned(), true),
+                ("corp.lan".to_owned(), false)
+            ],
+            routing.domain_routes()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_routing() {
+        let invalid = [
+            r#"{"rules": [{"id": "a", "action": "direct"}]}"#,
+            r#"{"rules": [{"id": "a", "action": "direct", "port": 25, "cidr": "10.0.0.0/8"}]}"#,
+            r#"{"rules": [{"id": "a", "action": "direct", "cidr": "10.0.0.0/33"}]}"#,
+            r#"{"rules": [{"id": "a", "action": "upstream", "port": 25}]}"#,
+            r#"{"rules": [{"id": "a", "action": "upstream", "upstream": "corp", "port": 25}]}"#,
+            r#"{"rules": [{"id": "a", "action": "block", "upstream": "corp", "port": 25}], "upstreams": {"corp": {"addr": "10.0.0.1:1080"}}}"#,
+            r#"{"rules": [{"id": "a", "action": "block", "port": 25}, {"id": "a", "action": "direct", "port": 80}]}"#,
+            r#"{"rules": [{"id": "default", "action": "block", "port": 25}]}"#,
+            r#"{"upstreams": {"corp": {"addr": "10.0.0.1:1080", "user": "alice"}}}"#,
+            r#"{"default": "upstream"}"#,
+            r#"{"rules": [{"id": "a", "action": "block", "port": 25, "rate_limit": 1024}]}"#,
+            r#"{"rules": [{"id": "a", "action": "direct", "port": 25, "rate_limit": 0}]}"#,
+            r#"{"default_upstream": "corp", "upstreams": {"corp": {"addr": "10.0.0.1:1080"}}}"#,
+        ];
+
+        for content in invalid {
+            assert!(LurkRouting::parse(content).is_err(), "{} should be rejected", content);
+        }
+    }
+
+    #[cfg(feature = "upstream-tls")]
+    #[test]
+    fn configure_upstream_tls() {
+        let pin = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let valid = [
+            r#"{"upstreams": {"corp": {"addr": "10.0.0.1:1080", "tls": {"insecure_skip_verify": true}}}}"#.to_owned(),
+            format!(r#"{{"upstreams": {{"corp": {{"addr": "10.0.0.1:1080", "tls": {{"pin_sha256": ["{pin}"]}}}}}}}}"#),
+        ];
+        for content in valid {
+            assert!(LurkRouting::parse(&content).is_ok(), "{} should be accepted", content);
+        }
+
+        let invalid = [
+            format!(
+                r#"{{"upstreams": {{"corp": {{"addr": "10.0.0.1:1080", "tls": {{"pin_sha256": ["{pin}"], "insecure_skip_verify": true}}}}}}}}"#
+            ),
+            r#"{"upstreams": {"corp": {"addr": "10.0.0.1:1080", "tls": {"pin_sha256": ["AAAA"]}}}}"#.to_owned(),
+            r#"{"upstreams": {"corp": {"addr": "10.0.0.1:1080", "tls": {"ca_bundle": "/nonexistent/ca.pem"}}}}"#.to_owned(),
+            r#"{"upstreams": {"corp": {"addr": "10.0.0.1:1080", "tls": {"verify": false}}}}"#.to_owned(),
+        ];
+        for content in invalid {
+            assert!(LurkRouting::parse(&content).is_err(), "{} should be rejected", content);
+        }
+    }
+}
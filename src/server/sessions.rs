@@ -0,0 +1,357 @@
+use super::registry::{LurkConnectionEntry, LurkConnectionId};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use log::{debug, error};
+use serde::Serialize;
+use std::{
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+/// Format of the session records file.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq)]
+pub enum LurkSessionRecordFormat {
+    // One JSON object per line.
+    #[default]
+    Jsonl,
+    // Comma-separated values with the header line.
+    Csv,
+}
+
+/// Settings of the session records export.
+///
+/// **Fields**:
+/// * ```path``` - file where records are appended to
+/// * ```format``` - format of the records
+/// * ```max_file_size``` - size (in bytes) after which the file is rotated
+/// * ```max_files``` - number of rotated files to keep along with the current one
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkSessionRecordOptions {
+    path: PathBuf,
+    format: LurkSessionRecordFormat,
+    max_file_size: u64,
+    max_files: usize,
+}
+
+impl LurkSessionRecordOptions {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        format: LurkSessionRecordFormat,
+        max_file_size: u64,
+        max_files: usize,
+    ) -> LurkSessionRecordOptions {
+        LurkSessionRecordOptions {
+            path: path.into(),
+            format,
+            max_file_size,
+            max_files,
+        }
+    }
+}
+
+/// Summary of the single finished connection.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LurkSessionRecord {
+    pub id: LurkConnectionId,
+    pub started_utc_ts: DateTime<Utc>,
+    pub finished_utc_ts: DateTime<Utc>,
+    pub duration_ms: i64,
+    pub peer_addr: SocketAddr,
+    pub label: String,
+    pub user: Option<String>,
+    pub destination: Option<String>,
+    pub l2r_bytes: u64,
+    pub r2l_bytes: u64,
+    pub error: Option<String>,
+    pub denied_by: Option<String>,
+}
+
+impl LurkSessionRecord {
+    const CSV_HEADER: &'static str =
+        "id,started_utc_ts,finished_utc_ts,duration_ms,peer_addr,label,user,destination,l2r_bytes,r2l_bytes,error,denied_by";
+
+    /// Make the record of the connection which has just been closed.
+    pub fn new(entry: &LurkConnectionEntry, error: Option<String>) -> LurkSessionRecord {
+        let finished_utc_ts = Utc::now();
+        LurkSessionRecord {
+            id: entry.id(),
+            started_utc_ts: entry.established_ts(),
+            finished_utc_ts,
+            duration_ms: (finished_utc_ts - entry.established_ts()).num_milliseconds(),
+            peer_addr: entry.peer_addr(),
+            label: entry.label().to_string(),
+            user: entry.session().user().map(str::to_owned),
+            destination: entry.session().destination().map(str::to_owned),
+            l2r_bytes: entry.activity().l2r_bytes(),
+            r2l_bytes: entry.activity().r2l_bytes(),
+            error,
+            denied_by: entry.session().deny_reason().map(str::to_owned),
+        }
+    }
+
+    /// Render the record as a single line (including line feed).
+    fn to_line(&self, format: LurkSessionRecordFormat) -> Result<String> {
+        match format {
+            LurkSessionRecordFormat::Jsonl => {
+                let mut line = serde_json::to_string(self)?;
+                line.push('\n');
+                Ok(line)
+            }
+            LurkSessionRecordFormat::Csv => {
+                let mut line = String::new();
+                write!(
+                    line,
+                    "{},{},{},{},{},{},{},{},{},{},{},{}",
+                    self.id,
+                    self.started_utc_ts.to_rfc3339(),
+                    self.finished_utc_ts.to_rfc3339(),
+                    self.duration_ms,
+                    self.peer_addr,
+                    csv_field(&self.label),
+                    csv_field(self.user.as_deref().unwrap_or_default()),
+                    csv_field(self.destination.as_deref().unwrap_or_default()),
+                    self.l2r_bytes,
+                    self.r2l_bytes,
+                    csv_field(self.error.as_deref().unwrap_or_default()),
+                    csv_field(self.denied_by.as_deref().unwrap_or_default()),
+                )?;
+                line.push('\n');
+                Ok(line)
+            }
+        }
+    }
+}
+
+/// Quote the CSV field if it contains special characters.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Appends session records to the rotating file.
+///
+/// Records are passed to the background writer through the channel, so
+/// connection tasks never wait for the disk.
+pub struct LurkSessionRecorder {
+    options: LurkSessionRecordOptions,
+    sender: Mutex<Option<UnboundedSender<LurkSessionRecord>>>,
+}
+
+impl LurkSessionRecorder {
+    pub fn new(options: LurkSessionRecordOptions) -> LurkSessionRecorder {
+        LurkSessionRecorder {
+            options,
+            sender: Mutex::new(None),
+        }
+    }
+
+    /// Spawn background writer. Returned handle completes once the recorder
+    /// is closed and all submitted records are written.
+    pub fn start(&self) -> JoinHandle<()> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let previous = self.sender().replace(sender);
+        debug_assert!(previous.is_none(), "recorder has already been started");
+
+        let writer = LurkSessionRecordWriter::new(self.options.clone());
+        tokio::spawn(writer.run(receiver))
+    }
+
+    /// Submit the record. It's dropped if recorder isn't running.
+    pub fn record(&self, record: LurkSessionRecord) {
+        if let Some(sender) = self.sender().as_ref() {
+            let _ = sender.send(record);
+        }
+    }
+
+    /// Stop accepting new records.
+    pub fn close(&self) {
+        self.sender().take();
+    }
+
+    fn sender(&self) -> std::sync::MutexGuard<'_, Option<UnboundedSender<LurkSessionRecord>>> {
+        self.sender.lock().expect("session recorder lock is poisoned")
+    }
+}
+
+struct LurkSessionRecordWriter {
+    options: LurkSessionRecordOptions,
+    file: Option<File>,
+    file_size: u64,
+}
+
+impl LurkSessionRecordWriter {
+    fn new(options: LurkSessionRecordOptions) -> LurkSessionRecordWriter {
+        LurkSessionRecordWriter {
+            options,
+            file: None,
+            file_size: 0,
+        }
+    }
+
+    async fn run(mut self, mut receiver: UnboundedReceiver<LurkSessionRecord>) {
+        while let Some(record) = receiver.recv().await {
+            if let Err(err) = self.write(&record).await {
+                error!("Failed to write session record to {}: {}", self.options.path.display(), err);
+            }
+        }
+
+        if let Some(file) = self.file.as_mut() {
+            if let Err(err) = file.flush().await {
+                error!("Failed to flush session records to {}: {}", self.options.path.display(), err);
+            }
+        }
+    }
+
+    async fn write(&mut self, record: &LurkSessionRecord) -> Result<()> {
+        let line = record.to_line(self.options.format)?;
+
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => self.open().await?,
+        };
+
+        // Rotate the file unless it has no records yet.
+        if self.file_size > self.header_len() && self.file_size + line.len() as u64 > self.options.max_file_size {
+            file.flush().await?;
+            drop(file);
+            self.rotate().await?;
+            file = self.open().await?;
+        }
+
+        // File is reopened on the next record if writing fails.
+        file.write_all(line.as_bytes()).await?;
+        self.file = Some(file);
+        self.file_size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn header_len(&self) -> u64 {
+        match self.options.format {
+            LurkSessionRecordFormat::Csv => LurkSessionRecord::CSV_HEADER.len() as u64 + 1,
+            LurkSessionRecordFormat::Jsonl => 0,
+        }
+    }
+
+    async fn open(&mut self) -> Result<File> {
+        let path = &self.options.path;
+        let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+        self.file_size = file.metadata().await?.len();
+
+        // Every new CSV file starts with the header.
+        if self.file_size == 0 && self.options.format == LurkSessionRecordFormat::Csv {
+            let header = format!("{}\n", LurkSessionRecord::CSV_HEADER);
+            file.write_all(header.as_bytes()).await?;
+            self.file_size = header.len() as u64;
+        }
+
+        debug!("Session records are written to {}", path.display());
+        Ok(file)
+    }
+
+    /// Shift rotated files ("records.1" -> "records.2", ...) and move the current one to "records.1".
+    async fn rotate(&self) -> Result<()> {
+        let path = &self.options.path;
+        if self.options.max_files == 0 {
+            return remove_if_exists(path).await;
+        }
+
+        remove_if_exists(&rotated_path(path, self.options.max_files)).await?;
+        for idx in (1..self.options.max_files).rev() {
+            rename_if_exists(&rotated_path(path, idx), &rotated_path(path, idx + 1)).await?;
+        }
+        rename_if_exists(path, &rotated_path(path, 1)).await
+    }
+}
+
+fn rotated_path(path: &Path, idx: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{idx}"));
+    PathBuf::from(rotated)
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn test_record(id: LurkConnectionId) -> LurkSessionRecord {
+        let ts = Utc::now();
+        LurkSessionRecord {
+            id,
+            started_utc_ts: ts,
+            finished_utc_ts: ts,
+            duration_ms: 0,
+            peer_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000),
+            label: "SOCKS5".to_owned(),
+            user: Some("alice".to_owned()),
+            destination: Some("example.com:443".to_owned()),
+            l2r_bytes: 10,
+            r2l_bytes: 20,
+            error: Some("Connection reset, \"by peer\"".to_owned()),
+            denied_by: None,
+        }
+    }
+
+    #[test]
+    fn csv_line() {
+        let line = test_record(1).to_line(LurkSessionRecordFormat::Csv).unwrap();
+        assert!(line.starts_with("1,"));
+        assert!(line.ends_with(",127.0.0.1:5000,SOCKS5,alice,example.com:443,10,20,\"Connection reset, \"\"by peer\"\"\",\n"));
+    }
+
+    #[tokio::test]
+    async fn write_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("lurk-session-records-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("sessions.jsonl");
+
+        // Every file fits a single record only.
+        let record_len = test_record(1).to_line(LurkSessionRecordFormat::Jsonl).unwrap().len() as u64;
+        let options = LurkSessionRecordOptions::new(&path, LurkSessionRecordFormat::Jsonl, record_len, 2);
+
+        let recorder = LurkSessionRecorder::new(options);
+        let writer = recorder.start();
+        (1..=4).for_each(|id| recorder.record(test_record(id)));
+        recorder.close();
+        writer.await.unwrap();
+
+        // The oldest record is rotated away.
+        for (file, id) in [(path.clone(), 4), (rotated_path(&path, 1), 3), (rotated_path(&path, 2), 2)] {
+            let content = fs::read_to_string(&file).await.unwrap();
+            let value: serde_json::Value = serde_json::from_str(content.trim_end()).unwrap();
+            assert_eq!(id, value["id"]);
+        }
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use super::LurkAcceptor;
+use crate::net::tcp::listener::{LurkTcpListener, LurkTcpListenerOptions};
+use anyhow::{anyhow, Result};
+use core_affinity::CoreId;
+use log::{debug, error, warn};
+use std::{net::SocketAddr, thread};
+use tokio::{runtime, sync::oneshot, task};
+
+/// Settings of the sharded mode: every shard is a thread running single-threaded
+/// runtime with its own listener (SO_REUSEPORT), so connection is handled on the
+/// same thread it has been accepted by.
+///
+/// **Fields**:
+/// * ```shards``` - number of shards, zero means one shard per available CPU core
+/// * ```pin_threads``` - pin every shard thread to its own CPU core
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkShardingOptions {
+    shards: usize,
+    pin_threads: bool,
+}
+
+impl LurkShardingOptions {
+    pub fn new(shards: usize, pin_threads: bool) -> LurkShardingOptions {
+        LurkShardingOptions { shards, pin_threads }
+    }
+
+    pub(super) fn shards(&self) -> usize {
+        match self.shards {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    /// CPU core the shard is pinned to, if pinning is enabled.
+    fn core_for(&self, index: usize) -> Option<CoreId> {
+        if !self.pin_threads {
+            return None;
+        }
+
+        match core_affinity::get_core_ids() {
+            Some(core_ids) if !core_ids.is_empty() => Some(core_ids[index % core_ids.len()]),
+            _ => {
+                warn!("Unable to get CPU cores, shard {} isn't pinned", index);
+                None
+            }
+        }
+    }
+}
+
+/// Thread serving its own listener.
+pub(super) struct LurkShard {
+    index: usize,
+    handle: thread::JoinHandle<()>,
+}
+
+impl LurkShard {
+    /// Spawn shard thread and wait until its listener is bound.
+    /// Returns the shard and the address its listener is bound to.
+    pub(super) async fn spawn(
+        index: usize,
+        options: &LurkShardingOptions,
+        bind_addr: SocketAddr,
+        listener_options: LurkTcpListenerOptions,
+        acceptor: LurkAcceptor,
+    ) -> Result<(LurkShard, SocketAddr)> {
+        let core = options.core_for(index);
+        let (bound_tx, bound_rx) = oneshot::channel();
+
+        let handle = thread::Builder::new().name(format!("lurk-shard-{index}")).spawn(move || {
+            if let Some(core) = core {
+                match core_affinity::set_for_current(core) {
+                    true => debug!("Shard {} is pinned to CPU core {}", index, core.id),
+                    false => warn!("Unable to pin shard {} to CPU core {}", index, core.id),
+                }
+            }
+
+            let rt = match runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(rt) => rt,
+                Err(err) => {
+                    let _ = bound_tx.send(Err(err.into()));
+                    return;
+                }
+            };
+
+            rt.block_on(async move {
+                let tcp_listener = match LurkTcpListener::bind_with_opts(bind_addr, &listener_options).await {
+                    Ok(tcp_listener) => tcp_listener,
+                    Err(err) => {
+                        let _ = bound_tx.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = bound_tx.send(Ok(tcp_listener.local_addr()));
+
+                acceptor.serve(tcp_listener, &listener_options).await;
+
+                // Keep driving connections accepted by this shard until they're finished.
+                acceptor.task_tracker.wait().await;
+            });
+        })?;
+
+        let bound_addr = bound_rx
+            .await
+            .map_err(|_| anyhow!("shard {} has exited before binding the listener", index))??;
+
+        Ok((LurkShard { index, handle }, bound_addr))
+    }
+
+    /// Wait for the shard thread to finish.
+    pub(super) async fn join(self) {
+        let index = self.index;
+        match task::spawn_blocking(move || self.handle.join()).await {
+            Ok(Ok(())) => debug!("Shard {} is finished", index),
+            _ => error!("Shard {} has panicked", index),
+        }
+    }
+}
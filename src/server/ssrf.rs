@@ -0,0 +1,112 @@
+use crate::common::error::{LurkDenyReason, LurkDenySource};
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::LazyLock,
+};
+
+/// Loopback, private, link-local and other special-purpose ranges (IANA special-purpose registries),
+/// which aren't reachable on the public internet.
+const SPECIAL_PURPOSE_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.88.99.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001::/23",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+static RANGES: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    SPECIAL_PURPOSE_RANGES
+        .iter()
+        .map(|range| range.parse().expect("special-purpose range should be valid"))
+        .collect()
+});
+
+/// Refuses destinations resolved into special-purpose addresses, so the proxy
+/// running on the internal network can't be used to reach internal services.
+pub struct LurkSsrfGuard {
+    exceptions: Vec<IpNet>,
+}
+
+impl LurkSsrfGuard {
+    /// Guard refusing all special-purpose ranges, except for ```exceptions```.
+    pub fn new(exceptions: Vec<IpNet>) -> LurkSsrfGuard {
+        LurkSsrfGuard { exceptions }
+    }
+
+    /// Why the destination IP is refused, if it is. IPv4 addresses mapped into IPv6
+    /// ones and embedded into the well-known NAT64 prefix are checked as IPv4 ones.
+    pub fn deny_reason(&self, ip: IpAddr) -> Option<LurkDenyReason> {
+        let ip = match ip.to_canonical() {
+            IpAddr::V6(ipv6) if ipv6.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0] => {
+                IpAddr::V4(Ipv4Addr::from_bits(ipv6.to_bits() as u32))
+            }
+            ip => ip,
+        };
+        if self.exceptions.iter().any(|net| net.contains(&ip)) {
+            return None;
+        }
+
+        RANGES.iter().find(|range| range.contains(&ip)).map(|range| LurkDenyReason {
+            source: LurkDenySource::Ssrf,
+            rule: range.to_string(),
+            pattern: ip.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn refused_by(guard: &LurkSsrfGuard, ip: &str) -> Option<String> {
+        guard.deny_reason(ip.parse().unwrap()).map(|reason| reason.rule)
+    }
+
+    #[test]
+    fn refuse_special_purpose_destinations() {
+        let guard = LurkSsrfGuard::new(Vec::new());
+        assert_eq!(Some("127.0.0.0/8"), refused_by(&guard, "127.0.0.1").as_deref());
+        assert_eq!(Some("10.0.0.0/8"), refused_by(&guard, "10.1.2.3").as_deref());
+        assert_eq!(Some("172.16.0.0/12"), refused_by(&guard, "172.31.255.1").as_deref());
+        assert_eq!(Some("169.254.0.0/16"), refused_by(&guard, "169.254.169.254").as_deref());
+        assert_eq!(Some("100.64.0.0/10"), refused_by(&guard, "100.100.0.1").as_deref());
+        assert_eq!(Some("::1/128"), refused_by(&guard, "::1").as_deref());
+        assert_eq!(Some("fc00::/7"), refused_by(&guard, "fd12::1").as_deref());
+        assert_eq!(Some("fe80::/10"), refused_by(&guard, "fe80::1").as_deref());
+        assert_eq!(Some("192.168.0.0/16"), refused_by(&guard, "::ffff:192.168.1.1").as_deref());
+        assert_eq!(Some("10.0.0.0/8"), refused_by(&guard, "64:ff9b::a00:1").as_deref());
+
+        assert_eq!(None, refused_by(&guard, "93.184.216.34"));
+        assert_eq!(None, refused_by(&guard, "172.32.0.1"));
+        assert_eq!(None, refused_by(&guard, "2606:2800:220:1::1"));
+        assert_eq!(None, refused_by(&guard, "64:ff9b::5db8:d822"));
+    }
+
+    #[test]
+    fn allow_exceptions() {
+        let guard = LurkSsrfGuard::new(vec!["10.0.13.0/24".parse().unwrap()]);
+        assert_eq!(None, refused_by(&guard, "10.0.13.7"));
+        assert_eq!(Some("10.0.0.0/8"), refused_by(&guard, "10.0.14.7").as_deref());
+    }
+}
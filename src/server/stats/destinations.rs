@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Mutex};
+
+/// Aggregated statistics of traffic sent to the particular destination host.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LurkDestinationCounters {
+    /// Number of successfully established sessions.
+    pub sessions: u64,
+    /// Number of failed attempts to reach the destination.
+    pub failures: u64,
+    /// Bytes sent from clients to the destination.
+    pub l2r_bytes: u64,
+    /// Bytes sent from the destination to clients.
+    pub r2l_bytes: u64,
+    /// UTC timestamp of the last update.
+    pub last_seen_utc_ts: DateTime<Utc>,
+}
+
+impl LurkDestinationCounters {
+    fn new() -> LurkDestinationCounters {
+        LurkDestinationCounters {
+            sessions: 0,
+            failures: 0,
+            l2r_bytes: 0,
+            r2l_bytes: 0,
+            last_seen_utc_ts: Utc::now(),
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.l2r_bytes + self.r2l_bytes
+    }
+}
+
+/// Per-destination statistics stored in the bounded map.
+///
+/// Once the map is full, the destination which hasn't been seen for the
+/// longest time is evicted to make room for the new one.
+pub struct LurkDestinationStats {
+    capacity: usize,
+    entries: Mutex<HashMap<String, LurkDestinationCounters>>,
+}
+
+impl LurkDestinationStats {
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    pub fn new(capacity: usize) -> LurkDestinationStats {
+        LurkDestinationStats {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Called when session with the destination host is finished.
+    pub fn on_session_finished(&self, host: &str, l2r_bytes: u64, r2l_bytes: u64) {
+        self.update(host, |counters| {
+            counters.sessions += 1;
+            counters.l2r_bytes += l2r_bytes;
+            counters.r2l_bytes += r2l_bytes;
+        });
+    }
+
+    /// Called when destination host couldn't be reached.
+    pub fn on_failure(&self, host: &str) {
+        self.update(host, |counters| counters.failures += 1);
+    }
+
+    /// Returns aggregates of all tracked destinations ordered by amount of relayed data.
+    pub fn get_top_by_traffic(&self, limit: usize) -> Vec<(String, LurkDestinationCounters)> {
+        let mut entries: Vec<(String, LurkDestinationCounters)> = self
+            .entries()
+            .iter()
+            .map(|(host, counters)| (host.clone(), counters.clone()))
+            .collect();
+
+        entries.sort_by(|(_, a), (_, b)| b.total_bytes().cmp(&a.total_bytes()).then(b.sessions.cmp(&a.sessions)));
+        entries.truncate(limit);
+        entries
+    }
+
+    fn update(&self, host: &str, f: impl FnOnce(&mut LurkDestinationCounters)) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries();
+
+        if !entries.contains_key(host) && entries.len() >= self.capacity {
+            // Evict the least recently seen destination.
+            if let Some(evicted) = entries
+                .iter()
+                .min_by_key(|(_, counters)| counters.last_seen_utc_ts)
+                .map(|(host, _)| host.clone())
+            {
+                entries.remove(&evicted);
+            }
+        }
+
+        let counters = entries.entry(host.to_owned()).or_insert_with(LurkDestinationCounters::new);
+        f(counters);
+        counters.last_seen_utc_ts = Utc::now();
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, LurkDestinationCounters>> {
+        self.entries.lock().expect("destination stats lock is poisoned")
+    }
+}
+
+impl Default for LurkDestinationStats {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_and_evict() {
+        let stats = LurkDestinationStats::new(2);
+
+        stats.on_session_finished("a.com", 10, 100);
+        stats.on_session_finished("a.com", 10, 100);
+        stats.on_failure("b.com");
+
+        let top = stats.get_top_by_traffic(10);
+        assert_eq!(2, top.len());
+        assert_eq!("a.com", top[0].0);
+        assert_eq!(2, top[0].1.sessions);
+        assert_eq!(220, top[0].1.total_bytes());
+        assert_eq!(1, top[1].1.failures);
+
+        // Touch "a.com", so "b.com" becomes the least recently seen one.
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        stats.on_failure("a.com");
+        stats.on_session_finished("c.com", 1, 1);
+
+        let hosts: Vec<String> = stats.get_top_by_traffic(10).into_iter().map(|(host, _)| host).collect();
+        assert_eq!(vec!["a.com".to_owned(), "c.com".to_owned()], hosts);
+    }
+}
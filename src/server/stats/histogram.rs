@@ -0,0 +1,96 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Histogram of durations with fixed bucket boundaries.
+///
+/// Observations are recorded lock-free, so it's cheap to update the histogram
+/// from connection handlers.
+pub struct LurkHistogram {
+    bounds: &'static [f64],
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+/// Point-in-time copy of the histogram.
+///
+/// **Fields**:
+/// * ```buckets``` - pairs of upper bound (in seconds) and cumulative number of observations
+/// * ```count``` - total number of observations
+/// * ```sum_secs``` - sum of all observed values in seconds
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkHistogramSnapshot {
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum_secs: f64,
+}
+
+impl LurkHistogram {
+    /// Buckets suitable for network latencies (in seconds).
+    pub const LATENCY_BUCKETS: &'static [f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+    /// Buckets suitable for lifetime of long-living sessions (in seconds).
+    pub const LIFETIME_BUCKETS: &'static [f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 14400.0, 86400.0];
+
+    pub fn new(bounds: &'static [f64]) -> LurkHistogram {
+        debug_assert!(bounds.windows(2).all(|w| w[0] < w[1]), "bounds should be sorted");
+        LurkHistogram {
+            bounds,
+            // Extra bucket is for values above the largest bound.
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let idx = self.bounds.partition_point(|&bound| bound < secs);
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LurkHistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(&bound, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect();
+
+        LurkHistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum_secs: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observe_into_buckets() {
+        let histogram = LurkHistogram::new(&[0.1, 1.0, 10.0]);
+
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(100));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(60));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(vec![(0.1, 2), (1.0, 3), (10.0, 3)], snapshot.buckets);
+        assert_eq!(4, snapshot.count);
+        assert_eq!(60.65, snapshot.sum_secs);
+    }
+}
@@ -0,0 +1,566 @@
+use crate::{
+    common::error::{LurkDenyReason, LurkDenySource},
+    net::tcp::connection::LurkTcpConnectionLabel,
+};
+use chrono::{DateTime, Duration, Utc};
+use destinations::LurkDestinationStats;
+use histogram::LurkHistogram;
+use node::{LurkBoundListener, LurkBuildInfo, LurkListenerKind};
+use rates::{LurkRateTracker, LurkRates};
+use serde::{Deserialize, Serialize};
+use sink::{LurkStatsEvent, LurkStatsSink};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+pub mod destinations;
+pub mod histogram;
+pub mod node;
+pub mod rates;
+pub mod sink;
+
+pub struct LurkServerStats {
+    is_started: AtomicBool,
+    started_ts_millis: AtomicI64,
+    response_write_timeouts: AtomicU64,
+    slow_read_closures: AtomicU64,
+    lifetime_closures: AtomicU64,
+    upstream_tls_verification_failures: AtomicU64,
+    denied_clients: AtomicU64,
+    throttled_connections: AtomicU64,
+    accepted_connections: AtomicU64,
+    active_connections: AtomicU64,
+    accept_errors: AtomicU64,
+    listener_recoveries: AtomicU64,
+    socks5_connections: AtomicU64,
+    socks4_connections: AtomicU64,
+    http_connections: AtomicU64,
+    transparent_connections: AtomicU64,
+    unknown_connections: AtomicU64,
+    l2r_bytes: AtomicU64,
+    r2l_bytes: AtomicU64,
+    auth_successes: AtomicU64,
+    auth_failures: AtomicU64,
+    destinations: LurkDestinationStats,
+    rates: LurkRateTracker,
+    connect_latency: LurkHistogram,
+    handshake_duration: LurkHistogram,
+    tunnel_lifetime: LurkHistogram,
+    sinks: Vec<Arc<dyn LurkStatsSink>>,
+    build_info: LurkBuildInfo,
+    listeners: Mutex<Vec<LurkBoundListener>>,
+    destination_denials: Mutex<BTreeMap<(LurkDenySource, String), u64>>,
+}
+
+impl LurkServerStats {
+    pub fn new() -> LurkServerStats {
+        LurkServerStats::with_destinations_capacity(LurkDestinationStats::DEFAULT_CAPACITY)
+    }
+
+    /// Create stats which keep aggregates for up to `capacity` destination hosts.
+    pub fn with_destinations_capacity(capacity: usize) -> LurkServerStats {
+        LurkServerStats {
+            started_ts_millis: AtomicI64::new(0),
+            is_started: AtomicBool::new(false),
+            response_write_timeouts: AtomicU64::new(0),
+            slow_read_closures: AtomicU64::new(0),
+            lifetime_closures: AtomicU64::new(0),
+            upstream_tls_verification_failures: AtomicU64::new(0),
+            denied_clients: AtomicU64::new(0),
+            throttled_connections: AtomicU64::new(0),
+            accepted_connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            accept_errors: AtomicU64::new(0),
+            listener_recoveries: AtomicU64::new(0),
+            socks5_connections: AtomicU64::new(0),
+            socks4_connections: AtomicU64::new(0),
+            http_connections: AtomicU64::new(0),
+            transparent_connections: AtomicU64::new(0),
+            unknown_connections: AtomicU64::new(0),
+            l2r_bytes: AtomicU64::new(0),
+            r2l_bytes: AtomicU64::new(0),
+            auth_successes: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            destinations: LurkDestinationStats::new(capacity),
+            rates: LurkRateTracker::new(),
+            connect_latency: LurkHistogram::new(LurkHistogram::LATENCY_BUCKETS),
+            handshake_duration: LurkHistogram::new(LurkHistogram::LATENCY_BUCKETS),
+            tunnel_lifetime: LurkHistogram::new(LurkHistogram::LIFETIME_BUCKETS),
+            sinks: Vec::new(),
+            build_info: LurkBuildInfo::current(),
+            listeners: Mutex::new(Vec::new()),
+            destination_denials: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Forward every emitted event to the external sinks as well.
+    pub fn with_sinks(mut self, sinks: Vec<Arc<dyn LurkStatsSink>>) -> LurkServerStats {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Account the event and pass it to the external sinks.
+    pub fn emit(&self, event: LurkStatsEvent<'_>) {
+        self.on_event(&event);
+        self.sinks.iter().for_each(|sink| sink.on_event(&event));
+    }
+
+    /// Called when node is started to accept connections.
+    pub fn on_server_started(&self) {
+        assert!(!self.is_started.load(Ordering::Relaxed), "server shoudn't be started yet");
+        let current_time = Utc::now();
+
+        self.is_started.store(true, Ordering::Relaxed);
+        self.started_ts_millis.store(current_time.timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn on_server_finished(&self) {
+        /* Not implemented */
+    }
+
+    /// Called when node has bound the listener to the address.
+    pub fn on_listener_bound(&self, kind: LurkListenerKind, addr: SocketAddr) {
+        self.listeners
+            .lock()
+            .expect("listeners lock is poisoned")
+            .push(LurkBoundListener { kind, addr });
+    }
+
+    /// Called when listener has accepted new connection with identified label.
+    pub fn on_connection_accepted(&self, label: LurkTcpConnectionLabel) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+        self.label_counter(label).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when listener failed to accept new connection.
+    pub fn on_accept_error(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when broken listener has been bound again.
+    pub fn on_listener_recovered(&self) {
+        self.listener_recoveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when accepted connection is passed to the handler.
+    pub fn on_connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when handler has finished serving the connection.
+    /// Data relayed through the connection is added to the total amount.
+    pub fn on_connection_closed(&self, l2r_bytes: u64, r2l_bytes: u64) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        self.l2r_bytes.fetch_add(l2r_bytes, Ordering::Relaxed);
+        self.r2l_bytes.fetch_add(r2l_bytes, Ordering::Relaxed);
+    }
+
+    /// Called when client has tried to authenticate as one of the users.
+    pub fn on_auth_result(&self, succeeded: bool) {
+        let counter = if succeeded { &self.auth_successes } else { &self.auth_failures };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when client has been refused to connect to the destination.
+    pub fn on_destination_denied(&self, reason: &LurkDenyReason) {
+        let mut denials = self.destination_denials.lock().expect("denials lock is poisoned");
+        *denials.entry((reason.source, reason.rule.clone())).or_default() += 1;
+    }
+
+    /// Called when peer hasn't accepted protocol response in time.
+    pub fn on_response_write_timeout(&self) {
+        self.response_write_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of protocol responses which weren't written due to expired timeout.
+    pub fn get_response_write_timeouts(&self) -> u64 {
+        self.response_write_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Called when tunnel has been closed, as the client was reading slower than allowed.
+    pub fn on_slow_read_closure(&self) {
+        self.slow_read_closures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of tunnels closed due to the clients reading slower than allowed.
+    pub fn get_slow_read_closures(&self) -> u64 {
+        self.slow_read_closures.load(Ordering::Relaxed)
+    }
+
+    /// Called when tunnel has been closed, as it has reached its maximum lifetime.
+    pub fn on_lifetime_closure(&self) {
+        self.lifetime_closures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of tunnels closed due to reaching their maximum lifetime.
+    pub fn get_lifetime_closures(&self) -> u64 {
+        self.lifetime_closures.load(Ordering::Relaxed)
+    }
+
+    /// Called when connection with the upstream proxy has been dropped, as its TLS certificate hasn't been verified.
+    pub fn on_upstream_tls_verification_failure(&self) {
+        self.upstream_tls_verification_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of connections with the upstream proxies dropped due to their TLS certificates not being verified.
+    pub fn get_upstream_tls_verification_failures(&self) -> u64 {
+        self.upstream_tls_verification_failures.load(Ordering::Relaxed)
+    }
+
+    /// Called when connection has been dropped, as the client isn't allowed to use the proxy.
+    pub fn on_client_denied(&self) {
+        self.denied_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of connections dropped due to the clients not allowed to use the proxy.
+    pub fn get_denied_clients(&self) -> u64 {
+        self.denied_clients.load(Ordering::Relaxed)
+    }
+
+    /// Called when connection has been dropped, as its client opens connections too fast.
+    pub fn on_connection_throttled(&self) {
+        self.throttled_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of connections dropped due to the connection rate of their clients.
+    pub fn get_throttled_connections(&self) -> u64 {
+        self.throttled_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns total number of accepted connections.
+    pub fn get_accepted_connections(&self) -> u64 {
+        self.accepted_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of connections which are being served right now.
+    pub fn get_active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of failures happened while accepting connections.
+    pub fn get_accept_errors(&self) -> u64 {
+        self.accept_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of times broken listener has been bound again.
+    pub fn get_listener_recoveries(&self) -> u64 {
+        self.listener_recoveries.load(Ordering::Relaxed)
+    }
+
+    /// Returns total number of accepted connections carrying traffic with passed label.
+    /// All unknown labels are accounted together.
+    pub fn get_connections_with_label(&self, label: LurkTcpConnectionLabel) -> u64 {
+        self.label_counter(label).load(Ordering::Relaxed)
+    }
+
+    /// Returns total amount of bytes relayed in both directions by closed connections:
+    /// from clients to endpoints (L2R) and backwards (R2L).
+    pub fn get_relayed_bytes(&self) -> (u64, u64) {
+        (self.l2r_bytes.load(Ordering::Relaxed), self.r2l_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Returns number of successful and failed authentication attempts.
+    pub fn get_auth_results(&self) -> (u64, u64) {
+        (
+            self.auth_successes.load(Ordering::Relaxed),
+            self.auth_failures.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Returns number of refused destinations per rule (policy rule, blocklist or DNSBL zone).
+    pub fn get_destination_denials(&self) -> Vec<(LurkDenySource, String, u64)> {
+        let denials = self.destination_denials.lock().expect("denials lock is poisoned");
+        denials
+            .iter()
+            .map(|((source, rule), count)| (*source, rule.clone(), *count))
+            .collect()
+    }
+
+    /// Record current values of counters the rolling rates are computed from.
+    /// Bytes relayed by still active connections should be supplied by the caller.
+    pub fn sample_rates(&self, active_connections_bytes: u64) {
+        let (l2r_bytes, r2l_bytes) = self.get_relayed_bytes();
+        self.rates.record_sample(
+            Utc::now().timestamp_millis(),
+            self.get_accepted_connections(),
+            l2r_bytes + r2l_bytes + active_connections_bytes,
+        );
+    }
+
+    /// Returns rates computed over rolling windows.
+    pub fn get_rates(&self) -> LurkRates {
+        self.rates.get_rates()
+    }
+
+    /// Time taken to establish TCP connection with the destination.
+    pub fn connect_latency(&self) -> &LurkHistogram {
+        &self.connect_latency
+    }
+
+    /// Time from the first client byte till the tunnel is ready to relay data.
+    pub fn handshake_duration(&self) -> &LurkHistogram {
+        &self.handshake_duration
+    }
+
+    /// Time the tunnel has been relaying data.
+    pub fn tunnel_lifetime(&self) -> &LurkHistogram {
+        &self.tunnel_lifetime
+    }
+
+    /// Returns statistics aggregated per destination host.
+    pub fn destinations(&self) -> &LurkDestinationStats {
+        &self.destinations
+    }
+
+    /// Returns details of the running build.
+    pub fn get_build_info(&self) -> &LurkBuildInfo {
+        &self.build_info
+    }
+
+    /// Returns addresses the node is listening on.
+    pub fn get_bound_listeners(&self) -> Vec<LurkBoundListener> {
+        self.listeners.lock().expect("listeners lock is poisoned").clone()
+    }
+
+    /// Returns values of counters accumulated during the whole server lifetime.
+    pub fn get_cumulative_counters(&self) -> LurkCumulativeCounters {
+        let (l2r_bytes, r2l_bytes) = self.get_relayed_bytes();
+        let (auth_successes, auth_failures) = self.get_auth_results();
+        LurkCumulativeCounters {
+            accepted_connections: self.get_accepted_connections(),
+            accept_errors: self.get_accept_errors(),
+            socks5_connections: self.socks5_connections.load(Ordering::Relaxed),
+            socks4_connections: self.socks4_connections.load(Ordering::Relaxed),
+            http_connections: self.http_connections.load(Ordering::Relaxed),
+            transparent_connections: self.transparent_connections.load(Ordering::Relaxed),
+            unknown_connections: self.unknown_connections.load(Ordering::Relaxed),
+            response_write_timeouts: self.get_response_write_timeouts(),
+            slow_read_closures: self.get_slow_read_closures(),
+            lifetime_closures: self.get_lifetime_closures(),
+            upstream_tls_verification_failures: self.get_upstream_tls_verification_failures(),
+            denied_clients: self.get_denied_clients(),
+            throttled_connections: self.get_throttled_connections(),
+            l2r_bytes,
+            r2l_bytes,
+            auth_successes,
+            auth_failures,
+        }
+    }
+
+    /// Returns values of cumulative counters and resets them to zero.
+    ///
+    /// Each counter is swapped atomically, so no update happened in parallel is lost:
+    /// it is either returned by this call or accounted in the next one.
+    pub fn take_cumulative_counters(&self) -> LurkCumulativeCounters {
+        LurkCumulativeCounters {
+            accepted_connections: self.accepted_connections.swap(0, Ordering::Relaxed),
+            accept_errors: self.accept_errors.swap(0, Ordering::Relaxed),
+            socks5_connections: self.socks5_connections.swap(0, Ordering::Relaxed),
+            socks4_connections: self.socks4_connections.swap(0, Ordering::Relaxed),
+            http_connections: self.http_connections.swap(0, Ordering::Relaxed),
+            transparent_connections: self.transparent_connections.swap(0, Ordering::Relaxed),
+            unknown_connections: self.unknown_connections.swap(0, Ordering::Relaxed),
+            response_write_timeouts: self.response_write_timeouts.swap(0, Ordering::Relaxed),
+            slow_read_closures: self.slow_read_closures.swap(0, Ordering::Relaxed),
+            lifetime_closures: self.lifetime_closures.swap(0, Ordering::Relaxed),
+            upstream_tls_verification_failures: self.upstream_tls_verification_failures.swap(0, Ordering::Relaxed),
+            denied_clients: self.denied_clients.swap(0, Ordering::Relaxed),
+            throttled_connections: self.throttled_connections.swap(0, Ordering::Relaxed),
+            l2r_bytes: self.l2r_bytes.swap(0, Ordering::Relaxed),
+            r2l_bytes: self.r2l_bytes.swap(0, Ordering::Relaxed),
+            auth_successes: self.auth_successes.swap(0, Ordering::Relaxed),
+            auth_failures: self.auth_failures.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Add previously accumulated values (e.g. loaded from checkpoint) to the counters.
+    pub fn restore_cumulative_counters(&self, counters: &LurkCumulativeCounters) {
+        self.accepted_connections
+            .fetch_add(counters.accepted_connections, Ordering::Relaxed);
+        self.accept_errors.fetch_add(counters.accept_errors, Ordering::Relaxed);
+        self.socks5_connections.fetch_add(counters.socks5_connections, Ordering::Relaxed);
+        self.socks4_connections.fetch_add(counters.socks4_connections, Ordering::Relaxed);
+        self.http_connections.fetch_add(counters.http_connections, Ordering::Relaxed);
+        self.transparent_connections
+            .fetch_add(counters.transparent_connections, Ordering::Relaxed);
+        self.unknown_connections.fetch_add(counters.unknown_connections, Ordering::Relaxed);
+        self.response_write_timeouts
+            .fetch_add(counters.response_write_timeouts, Ordering::Relaxed);
+        self.slow_read_closures.fetch_add(counters.slow_read_closures, Ordering::Relaxed);
+        self.lifetime_closures.fetch_add(counters.lifetime_closures, Ordering::Relaxed);
+        self.upstream_tls_verification_failures
+            .fetch_add(counters.upstream_tls_verification_failures, Ordering::Relaxed);
+        self.denied_clients.fetch_add(counters.denied_clients, Ordering::Relaxed);
+        self.throttled_connections
+            .fetch_add(counters.throttled_connections, Ordering::Relaxed);
+        self.l2r_bytes.fetch_add(counters.l2r_bytes, Ordering::Relaxed);
+        self.r2l_bytes.fetch_add(counters.r2l_bytes, Ordering::Relaxed);
+        self.auth_successes.fetch_add(counters.auth_successes, Ordering::Relaxed);
+        self.auth_failures.fetch_add(counters.auth_failures, Ordering::Relaxed);
+    }
+
+    fn label_counter(&self, label: LurkTcpConnectionLabel) -> &AtomicU64 {
+        match label {
+            LurkTcpConnectionLabel::Socks5 => &self.socks5_connections,
+            LurkTcpConnectionLabel::Socks4 => &self.socks4_connections,
+            LurkTcpConnectionLabel::Http => &self.http_connections,
+            LurkTcpConnectionLabel::Transparent => &self.transparent_connections,
+            LurkTcpConnectionLabel::Unknown(_) => &self.unknown_connections,
+        }
+    }
+
+    /// Returns true if server is started.
+    /// There's no guarantee it hasn't finished yet.
+    pub fn is_server_started(&self) -> bool {
+        self.is_started.load(Ordering::Relaxed)
+    }
+
+    /// Returns time past since server is started.
+    pub fn get_uptime(&self) -> Duration {
+        assert!(self.is_started.load(Ordering::Relaxed), "server should be already started");
+        let current_ts = Utc::now();
+        let started_ts = self.get_started_utc_timestamp();
+
+        assert!(current_ts >= started_ts);
+        current_ts - started_ts
+    }
+
+    /// Returns UTC timestamp describing server start time.
+    pub fn get_started_utc_timestamp(&self) -> DateTime<Utc> {
+        assert!(self.is_started.load(Ordering::Relaxed), "server should be already started");
+        DateTime::from_timestamp_millis(self.started_ts_millis.load(Ordering::Relaxed)).expect("valid datetime")
+    }
+}
+
+/// Counters which keep growing during the server lifetime.
+/// Unlike gauges (e.g. number of active connections), they make sense across server restarts.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct LurkCumulativeCounters {
+    pub accepted_connections: u64,
+    pub accept_errors: u64,
+    pub socks5_connections: u64,
+    pub socks4_connections: u64,
+    pub http_connections: u64,
+    pub transparent_connections: u64,
+    pub unknown_connections: u64,
+    pub response_write_timeouts: u64,
+    pub slow_read_closures: u64,
+    pub lifetime_closures: u64,
+    pub upstream_tls_verification_failures: u64,
+    pub denied_clients: u64,
+    pub throttled_connections: u64,
+    pub l2r_bytes: u64,
+    pub r2l_bytes: u64,
+    pub auth_successes: u64,
+    pub auth_failures: u64,
+}
+
+/// Built-in sink aggregating events into counters exported by the HTTP endpoint
+/// (both JSON stats and Prometheus metrics).
+impl LurkStatsSink for LurkServerStats {
+    fn on_event(&self, event: &LurkStatsEvent<'_>) {
+        match *event {
+            LurkStatsEvent::ConnectionOpened { .. } => self.on_connection_opened(),
+            LurkStatsEvent::ConnectionClosed { l2r_bytes, r2l_bytes, .. } => self.on_connection_closed(l2r_bytes, r2l_bytes),
+            LurkStatsEvent::ListenerRecovered { .. } => self.on_listener_recovered(),
+            LurkStatsEvent::AuthResult { succeeded, .. } => self.on_auth_result(succeeded),
+            LurkStatsEvent::ConfigChanged { .. } => {}
+        }
+    }
+}
+
+impl Default for LurkServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_counters() {
+        let stats = LurkServerStats::new();
+
+        stats.on_connection_accepted(LurkTcpConnectionLabel::Socks5);
+        stats.on_connection_accepted(LurkTcpConnectionLabel::Socks4);
+        stats.on_connection_accepted(LurkTcpConnectionLabel::Http);
+        stats.on_connection_accepted(LurkTcpConnectionLabel::Unknown(0xff));
+        stats.on_accept_error();
+
+        stats.on_connection_opened();
+        stats.on_connection_opened();
+        assert_eq!(2, stats.get_active_connections());
+
+        stats.on_connection_closed(0, 0);
+        assert_eq!(1, stats.get_active_connections());
+
+        assert_eq!(4, stats.get_accepted_connections());
+        assert_eq!(1, stats.get_accept_errors());
+        assert_eq!(1, stats.get_connections_with_label(LurkTcpConnectionLabel::Socks5));
+        assert_eq!(1, stats.get_connections_with_label(LurkTcpConnectionLabel::Socks4));
+        assert_eq!(1, stats.get_connections_with_label(LurkTcpConnectionLabel::Http));
+        assert_eq!(1, stats.get_connections_with_label(LurkTcpConnectionLabel::Unknown(0x01)));
+        assert_eq!((0, 0), stats.get_relayed_bytes());
+    }
+
+    #[test]
+    fn take_cumulative_counters() {
+        let stats = LurkServerStats::new();
+        stats.on_connection_accepted(LurkTcpConnectionLabel::Socks5);
+        stats.on_connection_opened();
+
+        let taken = stats.take_cumulative_counters();
+        assert_eq!(1, taken.accepted_connections);
+        assert_eq!(1, taken.socks5_connections);
+
+        // Counters are reset, but gauges stay untouched.
+        assert_eq!(LurkCumulativeCounters::default(), stats.get_cumulative_counters());
+        assert_eq!(1, stats.get_active_connections());
+    }
+
+    #[test]
+    fn emit_to_sinks() {
+        use std::{net::SocketAddr, sync::Mutex};
+
+        #[derive(Default)]
+        struct CollectingSink(Mutex<Vec<String>>);
+
+        impl LurkStatsSink for CollectingSink {
+            fn on_event(&self, event: &LurkStatsEvent<'_>) {
+                self.0.lock().unwrap().push(format!("{event:?}"));
+            }
+        }
+
+        let sink = Arc::new(CollectingSink::default());
+        let stats = LurkServerStats::new().with_sinks(vec![sink.clone()]);
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let label = LurkTcpConnectionLabel::Socks5;
+
+        stats.emit(LurkStatsEvent::ConnectionOpened { peer_addr, label });
+        stats.emit(LurkStatsEvent::AuthResult {
+            peer_addr,
+            user: "alice",
+            succeeded: false,
+        });
+        stats.emit(LurkStatsEvent::ConnectionClosed {
+            peer_addr,
+            label,
+            l2r_bytes: 10,
+            r2l_bytes: 20,
+        });
+
+        // Events are accounted by stats itself and passed to the sink.
+        assert_eq!(0, stats.get_active_connections());
+        assert_eq!((10, 20), stats.get_relayed_bytes());
+        assert_eq!((0, 1), stats.get_auth_results());
+        assert_eq!(3, sink.0.lock().unwrap().len());
+    }
+}
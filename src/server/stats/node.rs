@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Details of the build the node is running.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LurkBuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub features: Vec<String>,
+}
+
+impl LurkBuildInfo {
+    /// Returns details of the running binary (collected by the build script).
+    pub fn current() -> LurkBuildInfo {
+        LurkBuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_hash: env!("LURK_GIT_HASH").to_owned(),
+            features: env!("LURK_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+}
+
+/// Kind of the listener bound by the node.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LurkListenerKind {
+    Proxy,
+    HttpEndpoint,
+    TcpCheck,
+    Http3,
+    Https,
+    Transparent,
+}
+
+/// Readiness of the node to take new clients, reported to load balancers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LurkNodeState {
+    /// Node accepts and serves new clients.
+    Up,
+    /// Node serves clients, but asks to send new ones to the other nodes.
+    Drain,
+    /// Node isn't started yet or is shutting down.
+    Down,
+}
+
+/// Address the node accepts connections on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LurkBoundListener {
+    pub kind: LurkListenerKind,
+    pub addr: SocketAddr,
+}
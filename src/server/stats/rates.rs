@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+/// Rates averaged over 1, 5 and 15 minutes windows.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct LurkRateWindows {
+    #[serde(rename = "1m")]
+    pub m1: f64,
+    #[serde(rename = "5m")]
+    pub m5: f64,
+    #[serde(rename = "15m")]
+    pub m15: f64,
+}
+
+/// Rolling rates of the node.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct LurkRates {
+    /// Accepted connections per second.
+    pub connections_per_sec: LurkRateWindows,
+    /// Relayed bytes (in both directions) per second.
+    pub bytes_per_sec: LurkRateWindows,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LurkRateSample {
+    ts_millis: i64,
+    connections: u64,
+    bytes: u64,
+}
+
+/// Keeps periodic samples of the monotonic counters and computes rates
+/// as a difference between the latest sample and the oldest one in the window.
+pub struct LurkRateTracker {
+    samples: Mutex<VecDeque<LurkRateSample>>,
+}
+
+impl LurkRateTracker {
+    /// Period the counters are expected to be sampled with.
+    pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+    const WINDOWS: [Duration; 3] = [Duration::from_secs(60), Duration::from_secs(5 * 60), Duration::from_secs(15 * 60)];
+
+    pub fn new() -> LurkRateTracker {
+        LurkRateTracker {
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Store values of the counters observed at the given moment.
+    pub fn record_sample(&self, ts_millis: i64, connections: u64, bytes: u64) {
+        let mut samples = self.samples();
+        samples.push_back(LurkRateSample {
+            ts_millis,
+            connections,
+            bytes,
+        });
+
+        // Drop samples which are out of the largest window.
+        let oldest_needed_ts = ts_millis - LurkRateTracker::WINDOWS[2].as_millis() as i64;
+        while samples.front().is_some_and(|sample| sample.ts_millis < oldest_needed_ts) {
+            samples.pop_front();
+        }
+    }
+
+    pub fn get_rates(&self) -> LurkRates {
+        let samples = self.samples();
+        let rates = LurkRateTracker::WINDOWS.map(|window| LurkRateTracker::rates_over(&samples, window));
+
+        LurkRates {
+            connections_per_sec: LurkRateWindows {
+                m1: rates[0].0,
+                m5: rates[1].0,
+                m15: rates[2].0,
+            },
+            bytes_per_sec: LurkRateWindows {
+                m1: rates[0].1,
+                m5: rates[1].1,
+                m15: rates[2].1,
+            },
+        }
+    }
+
+    /// Returns (connections/sec, bytes/sec) computed over the window ending at the latest sample.
+    /// While history is shorter than the window, rates are averaged over the available span.
+    fn rates_over(samples: &VecDeque<LurkRateSample>, window: Duration) -> (f64, f64) {
+        let Some(latest) = samples.back() else {
+            return (0.0, 0.0);
+        };
+
+        let window_start_ts = latest.ts_millis - window.as_millis() as i64;
+        let base = samples
+            .iter()
+            .rev()
+            .take_while(|sample| sample.ts_millis >= window_start_ts)
+            .last()
+            .unwrap_or(latest);
+
+        let elapsed_secs = (latest.ts_millis - base.ts_millis) as f64 / 1000.0;
+        if elapsed_secs <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        // Counters may go down after stats reset, don't report negative rates then.
+        let connections = latest.connections.saturating_sub(base.connections) as f64;
+        let bytes = latest.bytes.saturating_sub(base.bytes) as f64;
+
+        (connections / elapsed_secs, bytes / elapsed_secs)
+    }
+
+    fn samples(&self) -> std::sync::MutexGuard<'_, VecDeque<LurkRateSample>> {
+        self.samples.lock().expect("rate samples lock is poisoned")
+    }
+}
+
+impl Default for LurkRateTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_rates() {
+        let tracker = LurkRateTracker::new();
+        assert_eq!(LurkRates::default(), tracker.get_rates());
+
+        // 1 connection and 1000 bytes each 5 seconds during 20 minutes.
+        for i in 0..=240 {
+            tracker.record_sample(i * 5000, i as u64, i as u64 * 1000);
+        }
+
+        let rates = tracker.get_rates();
+        assert_eq!(0.2, rates.connections_per_sec.m1);
+        assert_eq!(0.2, rates.connections_per_sec.m15);
+        assert_eq!(200.0, rates.bytes_per_sec.m5);
+
+        // Burst of 600 connections during the last minute affects short window mostly.
+        tracker.record_sample(241 * 5000 + 55000, 840, 240_000);
+
+        let rates = tracker.get_rates();
+        assert_eq!(10.0, rates.connections_per_sec.m1);
+        assert!(rates.connections_per_sec.m15 < 1.0);
+
+        // Old samples are dropped.
+        assert!(tracker.samples().len() <= 15 * 60 / 5 + 1);
+    }
+}
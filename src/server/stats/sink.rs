@@ -0,0 +1,90 @@
+use crate::net::tcp::connection::LurkTcpConnectionLabel;
+use log::info;
+use std::net::SocketAddr;
+
+/// Event reported by the server to the stats sinks.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum LurkStatsEvent<'a> {
+    /// Accepted connection has been passed to the handler.
+    ConnectionOpened {
+        peer_addr: SocketAddr,
+        label: LurkTcpConnectionLabel,
+    },
+    /// Handler has finished serving the connection.
+    ConnectionClosed {
+        peer_addr: SocketAddr,
+        label: LurkTcpConnectionLabel,
+        l2r_bytes: u64,
+        r2l_bytes: u64,
+    },
+    /// Broken proxy listener has been bound again.
+    ListenerRecovered { addr: SocketAddr },
+    /// Client has tried to authenticate as the user.
+    AuthResult {
+        peer_addr: SocketAddr,
+        user: &'a str,
+        succeeded: bool,
+    },
+    /// Setting of the running node has been changed through the HTTP endpoint.
+    ConfigChanged {
+        changed_by: SocketAddr,
+        setting: &'a str,
+        value: &'a str,
+    },
+}
+
+/// Receiver of stats events.
+///
+/// Implement it to export stats to external systems (e.g. statsd or custom
+/// databases) and register the sink with
+/// [`LurkServerBuilder::with_stats_sink`](crate::server::LurkServerBuilder::with_stats_sink).
+///
+/// Events are delivered synchronously from connection tasks, so the
+/// implementation should be cheap and never block.
+pub trait LurkStatsSink: Send + Sync {
+    fn on_event(&self, event: &LurkStatsEvent<'_>);
+}
+
+/// Sink writing every event to the log.
+pub struct LurkLogStatsSink;
+
+impl LurkLogStatsSink {
+    const LOG_TARGET: &'static str = "lurk::stats";
+}
+
+impl LurkStatsSink for LurkLogStatsSink {
+    fn on_event(&self, event: &LurkStatsEvent<'_>) {
+        match event {
+            LurkStatsEvent::ConnectionOpened { peer_addr, label } => {
+                info!(target: Self::LOG_TARGET, "connection_opened peer={} label={}", peer_addr, label)
+            }
+            LurkStatsEvent::ConnectionClosed {
+                peer_addr,
+                label,
+                l2r_bytes,
+                r2l_bytes,
+            } => info!(
+                target: Self::LOG_TARGET,
+                "connection_closed peer={} label={} l2r_bytes={} r2l_bytes={}", peer_addr, label, l2r_bytes, r2l_bytes
+            ),
+            LurkStatsEvent::ListenerRecovered { addr } => info!(target: Self::LOG_TARGET, "listener_recovered addr={}", addr),
+            LurkStatsEvent::AuthResult {
+                peer_addr,
+                user,
+                succeeded,
+            } => info!(
+                target: Self::LOG_TARGET,
+                "auth_result peer={} user={} succeeded={}", peer_addr, user, succeeded
+            ),
+            LurkStatsEvent::ConfigChanged {
+                changed_by,
+                setting,
+                value,
+            } => info!(
+                target: Self::LOG_TARGET,
+                "config_changed by={} setting={} value={}", changed_by, setting, value
+            ),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Customer served on its own listener.
+///
+/// Every tenant is served by the separate server instance running in the same process,
+/// so its users, destination policy and stats are isolated from the other tenants.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LurkTenant {
+    /// Name the tenant is referred to by the HTTP endpoint.
+    pub name: String,
+    /// TCP port the tenant's listener is bound to.
+    pub port: u16,
+    /// Users of the tenant (the same format as "--users-file"), no authentication if not set.
+    #[serde(default)]
+    pub users_file: Option<PathBuf>,
+    /// Destination policy of the tenant (the same format as "--policy-file").
+    #[serde(default)]
+    pub policy_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LurkTenantsFile {
+    tenants: Vec<LurkTenant>,
+}
+
+impl LurkTenant {
+    /// Load tenants from the JSON file. Names and ports should be unique.
+    pub fn from_file(path: &Path) -> Result<Vec<LurkTenant>> {
+        let content = std::fs::read(path).with_context(|| format!("unable to read tenants file {}", path.display()))?;
+        let file: LurkTenantsFile =
+            serde_json::from_slice(&content).with_context(|| format!("tenants file {} is malformed", path.display()))?;
+
+        LurkTenant::validate(&file.tenants).with_context(|| format!("tenants file {} is invalid", path.display()))?;
+        Ok(file.tenants)
+    }
+
+    fn validate(tenants: &[LurkTenant]) -> Result<()> {
+        let (mut names, mut ports) = (HashSet::new(), HashSet::new());
+        for tenant in tenants {
+            ensure!(
+                !tenant.name.is_empty() && tenant.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "tenant name '{}' should consist of letters, digits, '-' and '_'",
+                tenant.name
+            );
+            ensure!(names.insert(&tenant.name), "tenant '{}' is defined twice", tenant.name);
+            ensure!(
+                ports.insert(tenant.port),
+                "port {} of tenant '{}' is taken by another one",
+                tenant.port,
+                tenant.name
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tenants_file() {
+        let file: LurkTenantsFile = serde_json::from_str(
+            r#"{
+                "tenants": [
+                    { "name": "acme", "port": 1081, "users_file": "/etc/lurk/acme-users.json" },
+                    { "name": "globex", "port": 1082, "policy_file": "/etc/lurk/globex-policy.json" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            LurkTenant {
+                name: "acme".to_owned(),
+                port: 1081,
+                users_file: Some(PathBuf::from("/etc/lurk/acme-users.json")),
+                policy_file: None,
+            },
+            file.tenants[0]
+        );
+        assert!(LurkTenant::validate(&file.tenants).is_ok());
+
+        let tenant = |name: &str, port| LurkTenant {
+            name: name.to_owned(),
+            port,
+            users_file: None,
+            policy_file: None,
+        };
+        assert!(LurkTenant::validate(&[tenant("acme", 1081), tenant("acme", 1082)]).is_err());
+        assert!(LurkTenant::validate(&[tenant("acme", 1081), tenant("globex", 1081)]).is_err());
+        assert!(LurkTenant::validate(&[tenant("acme/1", 1081)]).is_err());
+        assert!(LurkTenant::validate(&[tenant("", 1081)]).is_err());
+    }
+}
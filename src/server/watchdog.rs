@@ -0,0 +1,173 @@
+use super::registry::{LurkConnectionEntry, LurkConnectionRegistry};
+use crate::common::logging;
+use chrono::{DateTime, TimeDelta, Utc};
+use human_bytes::human_bytes;
+use log::{info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+/// Settings of the stuck connections watchdog.
+///
+/// **Fields**:
+/// * ```idle_threshold``` - connection without any data movement for longer than this period is considered stuck
+/// * ```force_close``` - close stuck connections instead of just reporting them
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LurkWatchdogOptions {
+    idle_threshold: Duration,
+    force_close: bool,
+}
+
+impl LurkWatchdogOptions {
+    /// Lower bound for the period between registry scans.
+    const MIN_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(idle_threshold: Duration, force_close: bool) -> LurkWatchdogOptions {
+        LurkWatchdogOptions {
+            idle_threshold,
+            force_close,
+        }
+    }
+
+    /// Registry is scanned several times per threshold period to report
+    /// stuck connections close to the moment they've crossed the threshold.
+    fn scan_interval(&self) -> Duration {
+        (self.idle_threshold / 4).max(Self::MIN_SCAN_INTERVAL)
+    }
+}
+
+/// Background task looking for connections with no data movement.
+pub struct LurkWatchdog {
+    registry: Arc<LurkConnectionRegistry>,
+    options: LurkWatchdogOptions,
+}
+
+impl LurkWatchdog {
+    pub fn new(registry: Arc<LurkConnectionRegistry>, options: LurkWatchdogOptions) -> LurkWatchdog {
+        LurkWatchdog { registry, options }
+    }
+
+    /// Periodically scan the registry until the token is cancelled.
+    pub async fn run(self, token: CancellationToken) {
+        info!(
+            "Watchdog is started: idle threshold {:?}, force close {}",
+            self.options.idle_threshold, self.options.force_close
+        );
+
+        loop {
+            tokio::select! {
+                _ = sleep(self.options.scan_interval()) => {
+                    self.scan(Utc::now());
+                },
+                _ = token.cancelled() => break
+            }
+        }
+    }
+
+    /// Report (and close, if configured) connections idling longer than the threshold.
+    /// Returns number of detected stuck connections.
+    fn scan(&self, now: DateTime<Utc>) -> usize {
+        let stuck: Vec<Arc<LurkConnectionEntry>> = self
+            .registry
+            .snapshot()
+            .into_iter()
+            .filter(|entry| !entry.is_closed() && self.idle_time(entry, now) > self.options.idle_threshold)
+            .collect();
+
+        for entry in &stuck {
+            let idle_time = self.idle_time(entry, now);
+            logging::log_stuck_conn!(entry, now - entry.established_ts(), idle_time, self.options.force_close);
+
+            if self.options.force_close {
+                entry.close();
+            }
+        }
+
+        stuck.len()
+    }
+
+    fn idle_time(&self, entry: &LurkConnectionEntry, now: DateTime<Utc>) -> Duration {
+        let last_activity_ts = entry.activity().last_activity_ts_millis();
+        let idle_millis = now.timestamp_millis() - last_activity_ts;
+
+        TimeDelta::milliseconds(idle_millis).to_std().unwrap_or(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::tcp::listener::LurkTcpListener;
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    #[tokio::test]
+    async fn detect_and_close_stuck_connections() {
+        let mut listener = LurkTcpListener::bind("127.0.0.1:0").await.expect("Expect binded listener");
+        let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+        client.write_all(&[0x05]).await.unwrap();
+
+        let conn = listener.accept().await.expect("Expect accepted connection");
+        let registry = Arc::new(LurkConnectionRegistry::new());
+        let registered = registry.register(&conn, CancellationToken::new());
+
+        let idle_threshold = Duration::from_secs(60);
+        let now = registered.entry().established_ts();
+
+        // Only reporting, connection stays alive.
+        let watchdog = LurkWatchdog::new(Arc::clone(&registry), LurkWatchdogOptions::new(idle_threshold, false));
+        assert_eq!(0, watchdog.scan(now));
+        assert_eq!(1, watchdog.scan(now + idle_threshold * 2));
+        assert!(!registered.entry().is_closed());
+
+        // Stuck connection gets closed.
+        let watchdog = LurkWatchdog::new(Arc::clone(&registry), LurkWatchdogOptions::new(idle_threshold, true));
+        assert_eq!(1, watchdog.scan(now + idle_threshold * 2));
+        assert!(registered.entry().is_closed());
+
+        // Closed connections are not reported again.
+        assert_eq!(0, watchdog.scan(now + idle_threshold * 2));
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn keep_busy_forwarding_connections_alive() {
+        use crate::{
+            net::tcp::connection::LurkTcpConnectionHandler,
+            server::handlers::http::{
+                tests::{context, in_memory_connection, spawn_chunked_origin},
+                LurkHttpHandler,
+            },
+        };
+        use tokio::io::AsyncReadExt;
+
+        // Origin answering every request forwarded by the proxy.
+        let origin_addr = spawn_chunked_origin(&["hello"]).await;
+
+        let (conn, mut client) = in_memory_connection();
+        let registry = Arc::new(LurkConnectionRegistry::new());
+        let registered = registry.register(&conn, CancellationToken::new());
+        let handler = LurkHttpHandler::new(Arc::new(context()));
+        tokio::spawn(async move { handler.handle(conn).await });
+
+        // Connection keeps forwarding requests for much longer than the threshold, but it's never idle for that long.
+        let idle_threshold = Duration::from_millis(200);
+        let watchdog = LurkWatchdog::new(Arc::clone(&registry), LurkWatchdogOptions::new(idle_threshold, true));
+        let request = format!("GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n");
+        for _ in 0..8 {
+            sleep(idle_threshold / 2).await;
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\nhello") {
+                response.push(client.read_u8().await.unwrap());
+            }
+            assert_eq!(0, watchdog.scan(Utc::now()));
+        }
+        assert!(!registered.entry().is_closed());
+        assert_eq!(40, registered.entry().activity().r2l_bytes());
+
+        // Once the client goes silent, connection is stuck as any other.
+        assert_eq!(1, watchdog.scan(Utc::now() + idle_threshold * 2));
+        assert!(registered.entry().is_closed());
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::{env, fmt::Write, path::PathBuf};
+
+/// Service manager the service file is generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LurkServiceKind {
+    // Unit file for Linux hosts.
+    Systemd,
+    // Property list for macOS hosts.
+    Launchd,
+}
+
+/// Everything needed to run lurk as a service.
+///
+/// **Fields**:
+/// * ```binary``` - absolute path to the lurk binary
+/// * ```args``` - command line options lurk is started with
+/// * ```working_dir``` - directory lurk is started in (log4rs config is looked up there)
+/// * ```user``` - user the service is run by, service manager's default if not set
+/// * ```limit_nofile``` - limit of open file descriptors, i.e. of simultaneous connections
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct LurkServiceSpec {
+    binary: PathBuf,
+    args: Vec<String>,
+    working_dir: PathBuf,
+    user: Option<String>,
+    limit_nofile: u64,
+}
+
+impl LurkServiceSpec {
+    /// Label of the service used by launchd.
+    const LAUNCHD_LABEL: &'static str = "com.github.boris-sinyapkin.lurk";
+
+    pub fn new(binary: PathBuf, args: Vec<String>, working_dir: PathBuf, user: Option<String>, limit_nofile: u64) -> LurkServiceSpec {
+        LurkServiceSpec {
+            binary,
+            args,
+            working_dir,
+            user,
+            limit_nofile,
+        }
+    }
+
+    /// Spec running the current binary in the current directory with the options
+    /// the current process has been started with, except the ```subcommand``` ones.
+    pub fn from_current_process(subcommand: &str, user: Option<String>, limit_nofile: u64) -> Result<LurkServiceSpec> {
+        let args = env::args().skip(1).take_while(|arg| arg != subcommand).collect();
+        Ok(LurkServiceSpec::new(
+            env::current_exe()?,
+            args,
+            env::current_dir()?,
+            user,
+            limit_nofile,
+        ))
+    }
+
+    pub fn render(&self, kind: LurkServiceKind) -> String {
+        match kind {
+            LurkServiceKind::Systemd => self.render_systemd_unit(),
+            LurkServiceKind::Launchd => self.render_launchd_plist(),
+        }
+    }
+
+    fn render_systemd_unit(&self) -> String {
+        let exec_start = std::iter::once(self.binary.display().to_string())
+            .chain(self.args.iter().cloned())
+            .map(|arg| systemd_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut unit = String::new();
+        writeln!(unit, "[Unit]").unwrap();
+        writeln!(unit, "Description=Lurk SOCKS5 proxy").unwrap();
+        writeln!(unit, "After=network-online.target").unwrap();
+        writeln!(unit, "Wants=network-online.target").unwrap();
+        writeln!(unit).unwrap();
+        writeln!(unit, "[Service]").unwrap();
+        writeln!(unit, "Type=simple").unwrap();
+        writeln!(unit, "ExecStart={}", exec_start).unwrap();
+        writeln!(unit, "WorkingDirectory={}", self.working_dir.display()).unwrap();
+        if let Some(user) = &self.user {
+            writeln!(unit, "User={}", user).unwrap();
+        }
+        // Ctrl+C is handled as a request for graceful shutdown.
+        writeln!(unit, "KillSignal=SIGINT").unwrap();
+        writeln!(unit, "Restart=on-failure").unwrap();
+        writeln!(unit, "LimitNOFILE={}", self.limit_nofile).unwrap();
+        writeln!(unit).unwrap();
+        writeln!(unit, "[Install]").unwrap();
+        writeln!(unit, "WantedBy=multi-user.target").unwrap();
+        unit
+    }
+
+    fn render_launchd_plist(&self) -> String {
+        let mut plist = String::new();
+        writeln!(plist, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(
+            plist,
+            r#"<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">"#
+        )
+        .unwrap();
+        writeln!(plist, r#"<plist version="1.0">"#).unwrap();
+        writeln!(plist, "<dict>").unwrap();
+        writeln!(plist, "  <key>Label</key>\n  <string>{}</string>", Self::LAUNCHD_LABEL).unwrap();
+        writeln!(plist, "  <key>ProgramArguments</key>\n  <array>").unwrap();
+        for arg in std::iter::once(self.binary.display().to_string()).chain(self.args.iter().cloned()) {
+            writeln!(plist, "    <string>{}</string>", xml_escape(&arg)).unwrap();
+        }
+        writeln!(plist, "  </array>").unwrap();
+        writeln!(
+            plist,
+            "  <key>WorkingDirectory</key>\n  <string>{}</string>",
+            xml_escape(&self.working_dir.display().to_string())
+        )
+        .unwrap();
+        if let Some(user) = &self.user {
+            writeln!(plist, "  <key>UserName</key>\n  <string>{}</string>", xml_escape(user)).unwrap();
+        }
+        writeln!(plist, "  <key>RunAtLoad</key>\n  <true/>").unwrap();
+        writeln!(
+            plist,
+            "  <key>KeepAlive</key>\n  <dict>\n    <key>SuccessfulExit</key>\n    <false/>\n  </dict>"
+        )
+        .unwrap();
+        for limits in ["SoftResourceLimits", "HardResourceLimits"] {
+            writeln!(
+                plist,
+                "  <key>{}</key>\n  <dict>\n    <key>NumberOfFiles</key>\n    <integer>{}</integer>\n  </dict>",
+                limits, self.limit_nofile
+            )
+            .unwrap();
+        }
+        writeln!(plist, "</dict>").unwrap();
+        writeln!(plist, "</plist>").unwrap();
+        plist
+    }
+}
+
+/// Quote the argument of systemd command line, if it's needed.
+fn systemd_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '$' | '%' | ';')) {
+        return arg.to_owned();
+    }
+
+    let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$").replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> LurkServiceSpec {
+        LurkServiceSpec::new(
+            PathBuf::from("/usr/local/bin/lurk"),
+            vec![
+                "-p".to_owned(),
+                "1081".to_owned(),
+                "--users-file".to_owned(),
+                "/etc/lurk/my users.json".to_owned(),
+            ],
+            PathBuf::from("/etc/lurk"),
+            Some("lurk".to_owned()),
+            65536,
+        )
+    }
+
+    #[test]
+    fn render_systemd_unit() {
+        let unit = spec().render(LurkServiceKind::Systemd);
+
+        assert!(unit.contains(r#"ExecStart=/usr/local/bin/lurk -p 1081 --users-file "/etc/lurk/my users.json""#));
+        assert!(unit.contains("WorkingDirectory=/etc/lurk\n"));
+        assert!(unit.contains("User=lurk\n"));
+        assert!(unit.contains("LimitNOFILE=65536\n"));
+
+        // User is omitted if it's not set.
+        let mut spec = spec();
+        spec.user = None;
+        assert!(!spec.render(LurkServiceKind::Systemd).contains("User="));
+    }
+
+    #[test]
+    fn render_launchd_plist() {
+        let plist = spec().render(LurkServiceKind::Launchd);
+
+        assert!(plist.contains("<string>/usr/local/bin/lurk</string>\n    <string>-p</string>\n    <string>1081</string>"));
+        assert!(plist.contains("<string>/etc/lurk/my users.json</string>"));
+        assert!(plist.contains("<key>UserName</key>\n  <string>lurk</string>"));
+        assert!(plist.contains("<key>NumberOfFiles</key>\n    <integer>65536</integer>"));
+    }
+
+    #[test]
+    fn quote_systemd_args() {
+        assert_eq!("--proxy-port", systemd_quote("--proxy-port"));
+        assert_eq!(r#""""#, systemd_quote(""));
+        assert_eq!(r#""a \"b\" 100%% $$HOME""#, systemd_quote(r#"a "b" 100% $HOME"#));
+    }
+}
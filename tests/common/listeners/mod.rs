@@ -87,6 +87,28 @@ impl LurkHttpEndpointListener {
 
         LurkHttpEndpointListener { endpoint }
     }
+
+    /// Serve the endpoint of the node, which is run by another listener.
+    #[allow(dead_code)]
+    pub fn with_node(addr: SocketAddr, node: Arc<LurkServer>) -> LurkHttpEndpointListener {
+        LurkHttpEndpointListener {
+            endpoint: LurkHttpEndpoint::new(addr, node),
+        }
+    }
+
+    /// Serve the tenants along with the node.
+    #[allow(dead_code)]
+    pub fn with_tenants(mut self, tenants: impl IntoIterator<Item = (String, Arc<LurkServer>)>) -> LurkHttpEndpointListener {
+        self.endpoint = self.endpoint.with_tenants(tenants);
+        self
+    }
+
+    /// Require the token on the endpoint routes.
+    #[allow(dead_code)]
+    pub fn with_token(mut self, token: &str) -> LurkHttpEndpointListener {
+        self.endpoint = self.endpoint.with_token(token);
+        self
+    }
 }
 
 impl AsyncListener for LurkHttpEndpointListener {
@@ -104,15 +126,27 @@ impl AsyncListener for LurkHttpEndpointListener {
  */
 
 pub struct LurkServerListener {
-    server: LurkServer,
+    server: Arc<LurkServer>,
 }
 
 impl LurkServerListener {
     pub fn new(addr: SocketAddr) -> LurkServerListener {
         LurkServerListener {
-            server: LurkServer::new(addr),
+            server: Arc::new(LurkServer::new(addr)),
         }
     }
+
+    /// Listen with the server built with custom settings.
+    #[allow(dead_code)]
+    pub fn with_server(server: LurkServer) -> LurkServerListener {
+        LurkServerListener::with_shared_server(Arc::new(server))
+    }
+
+    /// Listen with the server, which is shared with e.g. HTTP endpoint.
+    #[allow(dead_code)]
+    pub fn with_shared_server(server: Arc<LurkServer>) -> LurkServerListener {
+        LurkServerListener { server }
+    }
 }
 
 impl AsyncListener for LurkServerListener {
@@ -6,9 +6,12 @@ use hyper_util::rt::TokioIo;
 use log::{debug, LevelFilter};
 use log4rs_test_utils::test_logging::init_logging_once_for;
 use reqwest::Proxy;
-use std::{net::SocketAddr, sync::atomic::{AtomicUsize, Ordering}};
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
     task::{yield_now, JoinHandle},
 };
@@ -85,6 +88,40 @@ pub async fn spawn_http_echo_server(bind_addr: SocketAddr) -> (JoinHandle<()>, C
     (task_handle, cancellation_token)
 }
 
+/// Read the request head from the <code>stream</code> accepted by the origin and answer it with the raw
+/// <code>response</code>. Returns the head of the request in lowercase.
+pub async fn answer_http_request(stream: TcpStream, response: &str) -> String {
+    let mut stream = BufReader::new(stream);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        stream.read_line(&mut head).await.unwrap();
+    }
+    stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+    head.to_ascii_lowercase()
+}
+
+/// Spawn origin answering the request on the only connection with the raw <code>response</code>.
+/// The task returns the head of the request in lowercase.
+pub async fn spawn_http_origin(response: impl Into<String>) -> (SocketAddr, JoinHandle<String>) {
+    let response = response.into();
+    let origin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin_listener.local_addr().unwrap();
+    let origin = tokio::spawn(async move {
+        let (stream, _) = origin_listener.accept().await.unwrap();
+        answer_http_request(stream, &response).await
+    });
+    (origin_addr, origin)
+}
+
+/// Send the raw <code>request</code> to the HTTP proxy and read the response until the connection is closed.
+pub async fn send_http_request(proxy_addr: SocketAddr, request: String) -> String {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
 pub fn socks5_proxy(addr: SocketAddr) -> Proxy {
     Proxy::http(format!("socks5://{}", addr)).unwrap()
 }
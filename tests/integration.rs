@@ -1,5 +1,8 @@
+// Some helpers and imports serve the tests of the handlers left out of reduced builds.
+#[cfg_attr(not(all(feature = "http", feature = "socks5")), allow(dead_code))]
 mod common;
 
+#[cfg(feature = "socks5")]
 mod socks5_proxy {
 
     use crate::common::{
@@ -7,9 +10,31 @@ mod socks5_proxy {
         listeners::{self, cancel_listener, AsyncListener},
         next_available_address, utils,
     };
+    use async_socks5::{Auth, UnsuccessfulReply};
     use futures::{stream::FuturesUnordered, StreamExt};
     use httptest::{matchers::request::method_path, responders::status_code, Expectation, ServerBuilder};
     use log::info;
+    use lurk::{
+        auth::{
+            quota::LurkQuota,
+            users::{LurkUser, LurkUserClass, LurkUserStore},
+        },
+        client::{self, LurkClientProtocol, LurkProxyReply},
+        ping::{self, LurkPingKind},
+        prelude::ReplyStatus,
+        server::{
+            blocklist::LurkBlocklistOptions,
+            sessions::{LurkSessionRecordFormat, LurkSessionRecordOptions},
+            shards::LurkShardingOptions,
+            LurkServer,
+        },
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::sleep,
+    };
 
     #[tokio::test]
     async fn single_client() {
@@ -74,11 +99,415 @@ mod socks5_proxy {
         cancel_listener!(lurk);
         cancel_listener!(echo);
     }
+
+    #[tokio::test]
+    async fn ping() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let timeout = Duration::from_secs(1);
+
+        // Nobody is listening yet.
+        assert!(ping::ping(LurkPingKind::Socks5, lurk_server_addr, timeout).await.is_err());
+
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr);
+        let lurk = lurk.run().await;
+
+        ping::ping(LurkPingKind::Socks5, lurk_server_addr, timeout)
+            .await
+            .expect("Proxy should respond to SOCKS5 handshake");
+
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn sharded_reactors() {
+        common::init_logging();
+
+        let num_clients = 20;
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        // Run Lurk proxy with two pinned shards.
+        let server = LurkServer::builder(lurk_server_addr)
+            .with_sharding(LurkShardingOptions::new(2, true))
+            .build();
+        let stats = server.get_stats();
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        // Wait until all shards are bound.
+        for _ in 0..50 {
+            if !stats.get_bound_listeners().is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let client_tasks: FuturesUnordered<_> = (0..num_clients)
+            .map(|_| common::ping_pong_data_through_socks5(echo_server_addr, lurk_server_addr))
+            .collect();
+        client_tasks.collect::<()>().await;
+
+        assert_eq!(num_clients, stats.get_accepted_connections());
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reuse_port_acceptors() {
+        common::init_logging();
+
+        let num_clients = 20;
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        // Run Lurk proxy with three accept loops sharing the address.
+        let server = LurkServer::builder(lurk_server_addr).with_acceptors(3).build();
+        let stats = server.get_stats();
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        // Wait until all acceptors are bound.
+        for _ in 0..50 {
+            if !stats.get_bound_listeners().is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let client_tasks: FuturesUnordered<_> = (0..num_clients)
+            .map(|_| common::ping_pong_data_through_socks5(echo_server_addr, lurk_server_addr))
+            .collect();
+        client_tasks.collect::<()>().await;
+
+        assert_eq!(num_clients, stats.get_accepted_connections());
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn multiple_listen_addresses() {
+        common::init_logging();
+
+        let lurk_server_addrs = [next_available_address(), next_available_address()];
+        let echo_server_addr = next_available_address();
+
+        let server = LurkServer::builder(lurk_server_addrs[0])
+            .with_additional_bind_addr(lurk_server_addrs[1])
+            .build();
+        let stats = server.get_stats();
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        // Clients of both addresses are served by the same server.
+        for lurk_server_addr in lurk_server_addrs {
+            common::ping_pong_data_through_socks5(echo_server_addr, lurk_server_addr).await;
+        }
+
+        let bound_addrs: Vec<_> = stats.get_bound_listeners().iter().map(|listener| listener.addr).collect();
+        assert_eq!(lurk_server_addrs.to_vec(), bound_addrs);
+        assert_eq!(2, stats.get_accepted_connections());
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn password_auth_with_quota() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        // Single user who is allowed to relay 2 KiB per day.
+        let quota = LurkQuota {
+            daily_bytes: Some(2048),
+            monthly_bytes: None,
+        };
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", quota)], false);
+        let server = LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build();
+
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        let connect = |password: &'static str| async move {
+            let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+            async_socks5::connect(&mut stream, echo_server_addr, Some(Auth::new("alice", password)))
+                .await
+                .map(|_| stream)
+        };
+
+        // Wrong password is rejected.
+        assert!(connect("wrong").await.is_err());
+
+        // Relay 1 KiB in both directions, so the whole quota is used.
+        let mut stream = connect("secret").await.expect("Expect successfully established SOCKS5 connection");
+        let write_buff = utils::generate_data(1024);
+        stream.write_all(&write_buff).await.unwrap();
+        let mut read_buff = vec![0u8; 1024];
+        stream.read_exact(&mut read_buff).await.unwrap();
+        stream.shutdown().await.unwrap();
+        drop(stream);
+
+        // Quota is charged once the tunnel is closed. Afterwards, new sessions are rejected.
+        let mut rejected = false;
+        for _ in 0..50 {
+            if connect("secret").await.is_err() {
+                rejected = true;
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(rejected, "Session should be rejected once quota is exceeded");
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn client_connect() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let server = LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build();
+
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        let connect = |target: String, password: &'static str| async move {
+            let proxy_addr = lurk_server_addr.to_string();
+            client::connect(
+                LurkClientProtocol::Socks5,
+                &proxy_addr,
+                &target,
+                Some(("alice", password)),
+                Duration::from_secs(1),
+            )
+            .await
+        };
+
+        let report = connect(echo_server_addr.to_string(), "secret").await.unwrap();
+        assert_eq!(LurkProxyReply::Socks5(ReplyStatus::Succeeded), report.reply);
+        assert_eq!(lurk_server_addr, report.proxy_addr);
+        assert!(report.handshake.is_some());
+
+        // Refusal of the proxy is reported, rejected credentials are an error.
+        let report = connect(next_available_address().to_string(), "secret").await.unwrap();
+        assert_eq!(LurkProxyReply::Socks5(ReplyStatus::ConnectionRefused), report.reply);
+        let err = connect(echo_server_addr.to_string(), "wrong").await.unwrap_err();
+        assert_eq!("proxy has rejected credentials of 'alice'", err.to_string());
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn max_sessions_per_user() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false).with_max_sessions(1);
+        let server = LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build();
+
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        let connect = || async move {
+            let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+            async_socks5::connect(&mut stream, echo_server_addr, Some(Auth::new("alice", "secret")))
+                .await
+                .map(|_| stream)
+        };
+
+        let stream = connect().await.expect("Expect successfully established SOCKS5 connection");
+        assert!(matches!(
+            connect().await,
+            Err(async_socks5::Error::Response(UnsuccessfulReply::ConnectionNotAllowedByRules))
+        ));
+
+        // Session is released once the tunnel is closed.
+        drop(stream);
+        let mut accepted = false;
+        for _ in 0..50 {
+            if connect().await.is_ok() {
+                accepted = true;
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(accepted, "Session should be accepted once the previous one is closed");
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn max_session_duration() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let trial = LurkUserClass { max_session_secs: Some(1) };
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default()).with_class("trial")], false)
+            .with_classes([("trial".to_owned(), trial)]);
+        let server = LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build();
+
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        async_socks5::connect(&mut stream, echo_server_addr, Some(Auth::new("alice", "secret")))
+            .await
+            .expect("Expect successfully established SOCKS5 connection");
+
+        // Tunnel works until the session is closed by the proxy.
+        let write_buff = utils::generate_data(1024);
+        stream.write_all(&write_buff).await.unwrap();
+        let mut read_buff = vec![0u8; 1024];
+        stream.read_exact(&mut read_buff).await.unwrap();
+
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await;
+        assert!(
+            matches!(closed, Ok(Ok(0))),
+            "Session should be closed once it reaches maximum duration"
+        );
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn blocklisted_destination() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let blocklist_server = httptest::Server::run();
+        blocklist_server.expect(
+            Expectation::matching(method_path("GET", "/domains"))
+                .times(1..)
+                .respond_with(status_code(200).body("# Test list\nblocked.example\n")),
+        );
+        let options = LurkBlocklistOptions::new([blocklist_server.url_str("/domains")], Duration::from_secs(60));
+        let server = LurkServer::builder(lurk_server_addr).with_blocklist(options).build();
+
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        // Blocklist is fetched in the background once the proxy is started.
+        let mut refused = false;
+        for _ in 0..50 {
+            let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+            let result = async_socks5::connect(&mut stream, ("www.blocked.example".to_owned(), 80), None).await;
+            if matches!(
+                result,
+                Err(async_socks5::Error::Response(UnsuccessfulReply::ConnectionNotAllowedByRules))
+            ) {
+                refused = true;
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(refused, "Connection to blocklisted destination should be refused");
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        async_socks5::connect(&mut stream, echo_server_addr, None)
+            .await
+            .expect("Connection to destination which isn't blocklisted should be established");
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    async fn session_records() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let records_path = std::env::temp_dir().join(format!("lurk-integration-sessions-{}.jsonl", std::process::id()));
+        let options = LurkSessionRecordOptions::new(&records_path, LurkSessionRecordFormat::Jsonl, 1024 * 1024, 1);
+        let server = LurkServer::builder(lurk_server_addr).with_session_records(options).build();
+
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        // Relay some data and close the session.
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        async_socks5::connect(&mut stream, echo_server_addr, None).await.unwrap();
+        stream.write_all(&utils::generate_data(1024)).await.unwrap();
+        let mut read_buff = vec![0u8; 1024];
+        stream.read_exact(&mut read_buff).await.unwrap();
+        stream.shutdown().await.unwrap();
+        drop(stream);
+
+        // Record is written in the background once connection is closed.
+        let mut record = None;
+        for _ in 0..50 {
+            if let Ok(content) = tokio::fs::read_to_string(&records_path).await {
+                if let Some(line) = content.lines().next() {
+                    record = Some(serde_json::from_str::<serde_json::Value>(line).unwrap());
+                    break;
+                }
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let record = record.expect("Session record should be written");
+        assert_eq!("SOCKS5", record["label"]);
+        assert_eq!(echo_server_addr.to_string(), record["destination"]);
+        assert_eq!(1024, record["l2r_bytes"]);
+        assert_eq!(1024, record["r2l_bytes"]);
+
+        cancel_listener!(lurk);
+        cancel_listener!(echo);
+        let _ = std::fs::remove_file(&records_path);
+    }
 }
 
+#[cfg(feature = "http")]
 mod http_proxy {
 
-    use crate::common::{self, next_available_address, utils::http::create_http_client};
+    use crate::common::{
+        self,
+        listeners::{self, cancel_listener, AsyncListener},
+        next_available_address,
+        utils::http::create_http_client,
+    };
+    use hyper::StatusCode;
+    use lurk::{
+        client::{self, LurkClientProtocol, LurkProxyReply},
+        server::{error_page::LurkErrorPage, LurkServer},
+    };
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
 
     #[tokio::test]
     async fn single_client_connect() {
@@ -101,40 +530,1016 @@ mod http_proxy {
         token.cancel();
         handle.await.unwrap();
     }
-}
 
-mod api_endpoint {
+    #[tokio::test]
+    async fn client_connect() {
+        common::init_logging();
 
-    use crate::api_endpoint::listeners::cancel_listener;
-    use crate::common::{
-        self,
-        listeners::{self, AsyncListener},
-    };
-    use crate::common::{next_available_address, utils};
-    use hyper::StatusCode;
-    use serde_json::{json, Value};
+        let lurk_server_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+        let (handle, token) = common::spawn_http_echo_server(echo_server_addr).await;
+
+        let report = client::connect(
+            LurkClientProtocol::Http,
+            &lurk_server_addr.to_string(),
+            &echo_server_addr.to_string(),
+            None,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(LurkProxyReply::Http(StatusCode::OK), report.reply);
+        assert_eq!(None, report.handshake);
+
+        cancel_listener!(lurk);
+        token.cancel();
+        handle.await.unwrap();
+    }
 
     #[tokio::test]
-    async fn healthcheck() {
+    async fn connect_udp() {
         common::init_logging();
 
-        let http_endpoint_addr = next_available_address();
-        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
-        let http_endpoint = http_endpoint.run().await;
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
 
-        let response = utils::http::create_http_client()
-            .get(format!("http://{}/healthcheck", http_endpoint_addr))
-            .send()
-            .await
-            .expect("Unable to send healthcheck GET request");
+        // Spawn UDP echo server
+        let echo_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo_socket.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let (n, peer_addr) = echo_socket.recv_from(&mut buffer).await.unwrap();
+            echo_socket.send_to(&buffer[..n], peer_addr).await.unwrap();
+        });
 
-        assert_eq!(StatusCode::OK, response.status());
+        // Upgrade HTTP/1.1 connection to UDP proxying
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        let request = format!(
+            "GET /.well-known/masque/udp/{}/{}/ HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: connect-udp\r\nCapsule-Protocol: ?1\r\n\r\n",
+            echo_addr.ip(),
+            echo_addr.port(),
+            lurk_server_addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
 
-        let body_bytes = response.bytes().await.unwrap();
-        let body_value: Value = serde_json::from_slice(&body_bytes).unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "unexpected response: {response}");
+        assert!(response.to_ascii_lowercase().contains("capsule-protocol: ?1"));
 
-        assert_eq!(*body_value.get("uptime_secs").unwrap(), json!(null));
-        assert_eq!(*body_value.get("started_utc_ts").unwrap(), json!(null));
+        // Datagram capsule: type 0x00, length, context ID 0x00 and UDP payload
+        stream.write_all(&[0x00, 0x05, 0x00, b'p', b'i', b'n', b'g']).await.unwrap();
+
+        let mut echoed = [0u8; 7];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!([0x00, 0x05, 0x00, b'p', b'i', b'n', b'g'], echoed);
+
+        echo.await.unwrap();
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn ftp_retrieve() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+
+        // Spawn FTP server serving the single file
+        let ftp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ftp_addr = ftp_listener.local_addr().unwrap();
+        let ftp = tokio::spawn(async move {
+            let (control, _) = ftp_listener.accept().await.unwrap();
+            let mut control = BufReader::new(control);
+            let mut data_listener = None;
+
+            control.get_mut().write_all(b"220 Ready\r\n").await.unwrap();
+            let mut line = String::new();
+            while control.read_line(&mut line).await.unwrap() > 0 {
+                let reply = match line.split_whitespace().next().unwrap() {
+                    "USER" => "331 Password required\r\n".to_owned(),
+                    "PASS" => "230 Logged in\r\n".to_owned(),
+                    "TYPE" => "200 Type set to I\r\n".to_owned(),
+                    "SIZE" => "213 11\r\n".to_owned(),
+                    "EPSV" => {
+                        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                        let port = listener.local_addr().unwrap().port();
+                        data_listener = Some(listener);
+                        format!("229 Entering Extended Passive Mode (|||{}|)\r\n", port)
+                    }
+                    "RETR" => {
+                        assert_eq!("RETR pub/file.txt\r\n", line);
+                        let (mut data, _) = data_listener.take().unwrap().accept().await.unwrap();
+                        control.get_mut().write_all(b"150 Opening data connection\r\n").await.unwrap();
+                        data.write_all(b"hello, ftp!").await.unwrap();
+                        "226 Transfer complete\r\n".to_owned()
+                    }
+                    _ => "502 Not implemented\r\n".to_owned(),
+                };
+                control.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                line.clear();
+            }
+        });
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        let request = format!(
+            "GET ftp://{}/pub/file.txt HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            ftp_addr, ftp_addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {response}");
+        assert!(response.to_ascii_lowercase().contains("content-length: 11"));
+        assert!(response.ends_with("\r\n\r\nhello, ftp!"), "unexpected response: {response}");
+
+        ftp.await.unwrap();
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn request_smuggling_vectors() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+
+        // Spawn origin recording headers of the single forwarded request
+        let (origin_addr, origin) = common::spawn_http_origin("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+
+        let request_line = format!(
+            "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            origin_addr, origin_addr
+        );
+        let rejected = [
+            // Conflicting lengths (CL.CL)
+            "Content-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+            // Chunked is not the final coding (TE.TE obfuscation)
+            "Transfer-Encoding: chunked, identity\r\n\r\n0\r\n\r\n",
+            "Transfer-Encoding: xchunked\r\nContent-Length: 5\r\n\r\nhello",
+            // Obsolete line folding
+            "X-Folded: a\r\n b\r\nContent-Length: 0\r\n\r\n",
+            // Bare CR in the header value
+            "X-Bare: a\rb\r\nContent-Length: 0\r\n\r\n",
+        ];
+        for vector in rejected {
+            let response = common::send_http_request(lurk_server_addr, format!("{}{}", request_line, vector)).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{vector:?} is not rejected: {response}");
+        }
+
+        // Transfer-Encoding wins, so Content-Length (CL.TE) is not forwarded to the origin.
+        let vector = "Content-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let response = common::send_http_request(lurk_server_addr, format!("{}{}", request_line, vector)).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {response}");
+
+        let forwarded_head = origin.await.unwrap();
+        assert!(forwarded_head.contains("transfer-encoding: chunked"), "{forwarded_head}");
+        assert!(!forwarded_head.contains("content-length"), "{forwarded_head}");
+
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn chunked_trailers() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+
+        // Spawn origin emitting trailers if client has asked for them
+        let (origin_addr, origin) = common::spawn_http_origin(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status\r\n\r\n2\r\nok\r\n0\r\ngrpc-status: 0\r\n\r\n",
+        )
+        .await;
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nTE: trailers\r\nConnection: close, TE\r\n\r\n",
+            origin_addr, origin_addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {response}");
+        assert!(
+            response.to_ascii_lowercase().contains("trailer: grpc-status"),
+            "unexpected response: {response}"
+        );
+        assert!(
+            response
+                .to_ascii_lowercase()
+                .ends_with("\r\n2\r\nok\r\n0\r\ngrpc-status: 0\r\n\r\n"),
+            "trailers are not relayed: {response:?}"
+        );
+
+        // Proxy relays trailers, so it asks the origin for them on behalf of the client.
+        let forwarded_head = origin.await.unwrap();
+        assert!(forwarded_head.contains("te: trailers"), "{forwarded_head}");
+
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn expect_continue() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+
+        // Spawn origin refusing the first upload and accepting the second one
+        let origin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin_listener.local_addr().unwrap();
+        let origin = tokio::spawn(async move {
+            for accept in [false, true] {
+                let (stream, _) = origin_listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    stream.read_line(&mut head).await.unwrap();
+                }
+                assert!(head.to_ascii_lowercase().contains("expect: 100-continue"), "{head}");
+
+                if !accept {
+                    let response = "HTTP/1.1 413 Content Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                    stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+                    continue;
+                }
+
+                stream.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+                let mut body = [0u8; 5];
+                stream.read_exact(&mut body).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n{}",
+                    std::str::from_utf8(&body).unwrap()
+                );
+                stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        async fn read_head(stream: &mut TcpStream) -> String {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            String::from_utf8(head).unwrap()
+        }
+
+        let request = format!(
+            "PUT http://{}/upload HTTP/1.1\r\nHost: {}\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+            origin_addr, origin_addr
+        );
+
+        // Final response of the origin comes without interim one, so the body is never sent.
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 413"), "unexpected response: {head}");
+
+        // Interim response of the origin is relayed before the body is sent.
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 100"), "unexpected response: {head}");
+
+        stream.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "unexpected response: {head}");
+        let mut body = [0u8; 5];
+        stream.read_exact(&mut body).await.unwrap();
+        assert_eq!(b"hello", &body);
+
+        origin.await.unwrap();
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn retry_idempotent_request() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+
+        // Spawn origin closing every other connection without response
+        let origin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin_listener.local_addr().unwrap();
+        let origin = tokio::spawn(async move {
+            for n in 0..3 {
+                let (stream, _) = origin_listener.accept().await.unwrap();
+                if n % 2 == 0 {
+                    continue;
+                }
+                common::answer_http_request(stream, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+            }
+        });
+
+        // GET is sent once again over the fresh connection.
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            origin_addr, origin_addr
+        );
+        let response = common::send_http_request(lurk_server_addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {response}");
+        assert!(response.ends_with("ok"), "unexpected response: {response}");
+
+        // POST is not, so the failure is reported.
+        let request = format!(
+            "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: 2\r\n\r\nhi",
+            origin_addr, origin_addr
+        );
+        let response = common::send_http_request(lurk_server_addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "unexpected response: {response}");
+
+        origin.await.unwrap();
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn error_page() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let server = LurkServer::builder(lurk_server_addr)
+            .with_error_page(LurkErrorPage::new(
+                "<p>{{status}} {{title}}: {{reason}}. Contact {{contact}}</p>",
+                "it@example.com",
+            ))
+            .build();
+        let lurk = listeners::LurkServerListener::with_server(server).run().await;
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        let request = "POST ftp://127.0.0.1/file.txt HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"), "unexpected response: {response}");
+        assert!(response.to_ascii_lowercase().contains("content-type: text/html; charset=utf-8"));
+        assert!(
+            response.ends_with("<p>405 Method Not Allowed: only GET is supported for FTP URLs. Contact it@example.com</p>"),
+            "unexpected response: {response}"
+        );
+
+        cancel_listener!(lurk);
+    }
+
+    #[tokio::test]
+    async fn strip_hop_by_hop_headers() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let lurk = listeners::LurkServerListener::new(lurk_server_addr).run().await;
+
+        // Spawn origin recording headers of the request and responding with hop-by-hop headers
+        let (origin_addr, origin) = common::spawn_http_origin(
+            "HTTP/1.1 200 OK\r\nConnection: X-Origin\r\nX-Origin: 1\r\nKeep-Alive: timeout=5\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .await;
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nConnection: X-Secret\r\nX-Secret: token\r\nProxy-Connection: keep-alive\r\nKeep-Alive: timeout=5\r\nTE: gzip\r\nAccept: */*\r\n\r\n",
+            origin_addr, origin_addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\nok") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap().to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 200"), "unexpected response: {response}");
+        for name in ["x-origin", "keep-alive"] {
+            assert!(!response.contains(name), "{name} is relayed: {response}");
+        }
+
+        let forwarded_head = origin.await.unwrap();
+        assert!(forwarded_head.contains("accept: */*"), "{forwarded_head}");
+        for name in ["connection", "x-secret", "keep-alive", "te:"] {
+            assert!(!forwarded_head.contains(name), "{name} is forwarded: {forwarded_head}");
+        }
+
+        cancel_listener!(lurk);
+    }
+}
+
+#[cfg_attr(not(feature = "socks5"), allow(unused_imports))]
+mod api_endpoint {
+
+    use crate::api_endpoint::listeners::cancel_listener;
+    use crate::common::{
+        self,
+        listeners::{self, AsyncListener},
+    };
+    use crate::common::{next_available_address, utils};
+    use async_socks5::Auth;
+    use hyper::StatusCode;
+    use lurk::{
+        auth::{
+            quota::LurkQuota,
+            users::{LurkUser, LurkUserStore},
+        },
+        ctl::{self, LurkCtlAction},
+        ping::{self, LurkPingKind},
+        server::{
+            policy::LurkPolicy,
+            profiles::{LurkProfile, LurkProfiles},
+            LurkServer,
+        },
+    };
+    use serde_json::{json, Value};
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn healthcheck() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
+        let http_endpoint = http_endpoint.run().await;
+
+        let response = utils::http::create_http_client()
+            .get(format!("http://{}/healthcheck", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send healthcheck GET request");
+
+        assert_eq!(StatusCode::OK, response.status());
+
+        let body_bytes = response.bytes().await.unwrap();
+        let body_value: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        assert_eq!(body_value["state"], json!("down"));
+        assert_eq!(*body_value.get("uptime_secs").unwrap(), json!(null));
+        assert_eq!(*body_value.get("started_utc_ts").unwrap(), json!(null));
+        assert_eq!(body_value["connections"]["accepted"], json!(0));
+        assert_eq!(body_value["connections"]["active"], json!(0));
+        assert_eq!(body_value["traffic"]["l2r_bytes"], json!(0));
+        assert_eq!(body_value["rates"]["connections_per_sec"]["1m"], json!(0.0));
+        assert_eq!(body_value["rates"]["bytes_per_sec"]["15m"], json!(0.0));
+        assert_eq!(body_value["build"]["version"], json!(env!("CARGO_PKG_VERSION")));
+        assert!(body_value["build"]["git_hash"].is_string());
+        assert_eq!(body_value["listeners"][0]["kind"], json!("http_endpoint"));
+        assert_eq!(body_value["listeners"][0]["addr"], json!(http_endpoint_addr.to_string()));
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    async fn ping_healthcheck() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
+        let http_endpoint = http_endpoint.run().await;
+
+        ping::ping(LurkPingKind::Healthcheck, http_endpoint_addr, Duration::from_secs(1))
+            .await
+            .expect("HTTP endpoint should respond to healthcheck");
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    async fn problem_details_on_error() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
+        let http_endpoint = http_endpoint.run().await;
+
+        let client = utils::http::create_http_client();
+
+        // Unknown route
+        let response = client
+            .get(format!("http://{}/unknown", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send GET request");
+
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!("application/problem+json", response.headers()["content-type"]);
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(*body_value.get("type").unwrap(), json!("urn:lurk:problem:route-not-found"));
+        assert_eq!(*body_value.get("status").unwrap(), json!(404));
+        assert_eq!(*body_value.get("instance").unwrap(), json!("/unknown"));
+
+        // Not allowed method
+        let response = client
+            .post(format!("http://{}/healthcheck", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send POST request");
+
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+        assert_eq!("GET", response.headers()["allow"]);
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(*body_value.get("type").unwrap(), json!("urn:lurk:problem:method-not-allowed"));
+
+        // Node without recordings directory
+        let response = client
+            .put(format!("http://{}/recordings/127.0.0.1", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send PUT request");
+
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(*body_value.get("type").unwrap(), json!("urn:lurk:problem:recordings-disabled"));
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    async fn token_authorization() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr).with_token("s3cr3t");
+        let http_endpoint = http_endpoint.run().await;
+
+        let client = utils::http::create_http_client();
+        let stats_url = format!("http://{}/stats", http_endpoint_addr);
+
+        // No token
+        let response = client.get(&stats_url).send().await.expect("Unable to send stats GET request");
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!("Bearer", response.headers()["www-authenticate"]);
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(*body_value.get("type").unwrap(), json!("urn:lurk:problem:unauthorized"));
+
+        // Wrong token
+        let response = client
+            .get(&stats_url)
+            .bearer_auth("wrong")
+            .send()
+            .await
+            .expect("Unable to send stats GET request");
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        // Valid token
+        let response = client
+            .get(&stats_url)
+            .bearer_auth("s3cr3t")
+            .send()
+            .await
+            .expect("Unable to send stats GET request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        // Healthcheck doesn't require token
+        let response = client
+            .get(format!("http://{}/healthcheck", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send healthcheck GET request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    async fn ctl_connections() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr).with_token("s3cr3t");
+        let http_endpoint = http_endpoint.run().await;
+
+        let connections = ctl::execute(&LurkCtlAction::Connections, http_endpoint_addr, Some("s3cr3t"))
+            .await
+            .expect("Connections should be listed");
+        assert_eq!(serde_json::from_str::<Value>(&connections).unwrap(), json!([]));
+
+        let err = ctl::execute(&LurkCtlAction::Kill { id: 42 }, http_endpoint_addr, Some("s3cr3t"))
+            .await
+            .expect_err("Unknown connection shouldn't be closed");
+        assert!(err.to_string().contains("Connection 42 is not served by the node"));
+
+        ctl::execute(&LurkCtlAction::Status, http_endpoint_addr, None)
+            .await
+            .expect("Status shouldn't require token");
+
+        let err = ctl::execute(&LurkCtlAction::Drain, http_endpoint_addr, None)
+            .await
+            .expect_err("Drain requires token");
+        assert!(err.to_string().contains("401"));
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks5")]
+    async fn kick_user() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let http_endpoint_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let users = LurkUserStore::new(
+            [
+                LurkUser::new("alice", "secret", LurkQuota::default()),
+                LurkUser::new("bob", "pass", LurkQuota::default()),
+            ],
+            false,
+        );
+        let server = Arc::new(LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build());
+
+        let lurk = listeners::LurkServerListener::with_shared_server(Arc::clone(&server)).run().await;
+        let http_endpoint = listeners::LurkHttpEndpointListener::with_node(http_endpoint_addr, server)
+            .run()
+            .await;
+
+        // Echo server, which serves all sessions at once.
+        let echo = TcpListener::bind(echo_server_addr).await.unwrap();
+        let echo = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let connect = |user: &'static str, password: &'static str| async move {
+            let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+            async_socks5::connect(&mut stream, echo_server_addr, Some(Auth::new(user, password)))
+                .await
+                .expect("Expect successfully established SOCKS5 connection");
+            // Make sure the tunnel is up before kicking the user.
+            stream.write_all(&utils::generate_data(1024)).await.unwrap();
+            stream.read_exact(&mut vec![0u8; 1024]).await.unwrap();
+            stream
+        };
+        let mut alice_streams = vec![connect("alice", "secret").await, connect("alice", "secret").await];
+        let mut bob_stream = connect("bob", "pass").await;
+
+        let kicked = ctl::execute(&LurkCtlAction::Kick { name: "alice".to_owned() }, http_endpoint_addr, None)
+            .await
+            .expect("User should be kicked");
+        let kicked: Value = serde_json::from_str(&kicked).unwrap();
+        assert_eq!(2, kicked.as_array().unwrap().len());
+        assert!(kicked
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|connection| connection["user"] == json!("alice")));
+
+        for stream in &mut alice_streams {
+            let closed = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut Vec::new())).await;
+            assert!(matches!(closed, Ok(Ok(0))), "Connections of the kicked user should be closed");
+        }
+
+        // Sessions of other users are left intact.
+        bob_stream.write_all(&utils::generate_data(1024)).await.unwrap();
+        bob_stream.read_exact(&mut vec![0u8; 1024]).await.unwrap();
+
+        let err = ctl::execute(&LurkCtlAction::Kick { name: "carol".to_owned() }, http_endpoint_addr, None)
+            .await
+            .expect_err("Unknown user shouldn't be kicked");
+        assert!(err.to_string().contains("User 'carol' is not known to the node"));
+
+        cancel_listener!(lurk);
+        cancel_listener!(http_endpoint);
+        echo.abort();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks5")]
+    async fn tenants() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let tenant_server_addr = next_available_address();
+        let http_endpoint_addr = next_available_address();
+        let echo_server_addr = next_available_address();
+
+        let users = LurkUserStore::new([LurkUser::new("bob", "pass", LurkQuota::default())], false);
+        let server = Arc::new(LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build());
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let tenant = Arc::new(LurkServer::builder(tenant_server_addr).with_users(Arc::new(users)).build());
+
+        let lurk = listeners::LurkServerListener::with_shared_server(Arc::clone(&server)).run().await;
+        let acme = listeners::LurkServerListener::with_shared_server(Arc::clone(&tenant)).run().await;
+        let http_endpoint = listeners::LurkHttpEndpointListener::with_node(http_endpoint_addr, server)
+            .with_tenants([("acme".to_owned(), tenant)])
+            .run()
+            .await;
+        let echo = listeners::tcp_echo_server::TcpEchoServer::bind(echo_server_addr).await;
+        let echo = echo.run().await;
+
+        // Users of the tenant are known to its listener only.
+        let mut stream = TcpStream::connect(tenant_server_addr).await.unwrap();
+        async_socks5::connect(&mut stream, echo_server_addr, Some(Auth::new("alice", "secret")))
+            .await
+            .expect("Expect successfully established SOCKS5 connection");
+        stream.write_all(&utils::generate_data(1024)).await.unwrap();
+        stream.read_exact(&mut vec![0u8; 1024]).await.unwrap();
+        drop(stream);
+
+        let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+        async_socks5::connect(&mut stream, echo_server_addr, Some(Auth::new("alice", "secret")))
+            .await
+            .expect_err("Node shouldn't authenticate users of the tenant");
+
+        let tenants = ctl::execute(&LurkCtlAction::Tenants, http_endpoint_addr, None).await.unwrap();
+        let tenants: Value = serde_json::from_str(&tenants).unwrap();
+        assert_eq!(json!("acme"), tenants[0]["name"]);
+        assert_eq!(1, tenants.as_array().unwrap().len());
+
+        let response = utils::http::create_http_client()
+            .get(format!("http://{}/tenants/acme/stats", http_endpoint_addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let stats: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(json!(1), stats["counters"]["auth_successes"]);
+        assert_eq!(json!(1024), stats["counters"]["l2r_bytes"]);
+
+        // Accounting of the node isn't affected by the tenant.
+        let stats = ctl::execute(&LurkCtlAction::Stats, http_endpoint_addr, None).await.unwrap();
+        let stats: Value = serde_json::from_str(&stats).unwrap();
+        assert_eq!(json!(0), stats["counters"]["auth_successes"]);
+        assert_eq!(json!(1), stats["counters"]["auth_failures"]);
+        assert_eq!(json!(0), stats["counters"]["l2r_bytes"]);
+
+        let response = utils::http::create_http_client()
+            .get(format!("http://{}/tenants/globex/stats", http_endpoint_addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        cancel_listener!(lurk);
+        cancel_listener!(acme);
+        cancel_listener!(http_endpoint);
+        cancel_listener!(echo);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks5")]
+    async fn switch_auth_methods() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let http_endpoint_addr = next_available_address();
+        let destination_addr = next_available_address();
+
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let server = Arc::new(LurkServer::builder(lurk_server_addr).with_users(Arc::new(users)).build());
+
+        let lurk = listeners::LurkServerListener::with_shared_server(Arc::clone(&server)).run().await;
+        let http_endpoint = listeners::LurkHttpEndpointListener::with_node(http_endpoint_addr, server)
+            .run()
+            .await;
+        // Destination connections are accepted by the kernel backlog.
+        let _destination = TcpListener::bind(destination_addr).await.unwrap();
+
+        let connect = |auth: Option<Auth>| async move {
+            let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+            async_socks5::connect(&mut stream, destination_addr, auth).await.is_ok()
+        };
+        let set_auth_methods = |methods: &str| {
+            let action = LurkCtlAction::AuthMethods {
+                methods: methods.to_owned(),
+            };
+            async move { ctl::execute(&action, http_endpoint_addr, None).await }
+        };
+
+        let config = ctl::execute(&LurkCtlAction::Config, http_endpoint_addr, None).await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&config).unwrap(),
+            json!({"auth_methods": ["password"]})
+        );
+        assert!(!connect(None).await, "Anonymous client should be rejected");
+
+        // Open access is enabled for new handshakes only.
+        let config = set_auth_methods("password,none").await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&config).unwrap(),
+            json!({"auth_methods": ["password", "none"]})
+        );
+        assert!(connect(None).await, "Anonymous client should be accepted");
+        assert!(connect(Some(Auth::new("alice", "secret"))).await);
+
+        set_auth_methods("password").await.unwrap();
+        assert!(!connect(None).await, "Anonymous client should be rejected again");
+
+        let err = set_auth_methods("gssapi").await.expect_err("GSSAPI can't be offered");
+        assert!(err.to_string().contains("unknown authentication method 'gssapi'"));
+
+        cancel_listener!(lurk);
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks5")]
+    async fn switch_profiles() {
+        common::init_logging();
+
+        let lurk_server_addr = next_available_address();
+        let http_endpoint_addr = next_available_address();
+        let destination_addr = next_available_address();
+
+        let temp_file = |name: &str, content: String| {
+            let path = std::env::temp_dir().join(format!("lurk-integration-{}-{}.json", name, std::process::id()));
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        let policy_path = temp_file("profiles-policy", r#"{"default": "allow"}"#.to_owned());
+        let strict_policy_path = temp_file(
+            "profiles-strict-policy",
+            format!(
+                r#"{{"rules": [{{"id": "lan", "action": "deny", "host": "{}"}}], "default": "allow"}}"#,
+                destination_addr.ip()
+            ),
+        );
+        let profiles_path = temp_file(
+            "profiles",
+            format!(
+                r#"{{
+                    "active": "strict",
+                    "profiles": {{
+                        "strict": {{ "auth_methods": ["password"], "policy_file": {:?} }},
+                        "open": {{ "auth_methods": ["password", "none"] }}
+                    }}
+                }}"#,
+                strict_policy_path
+            ),
+        );
+
+        let users = LurkUserStore::new([LurkUser::new("alice", "secret", LurkQuota::default())], false);
+        let profiles = LurkProfiles::from_file(
+            &profiles_path,
+            LurkProfile {
+                policy_file: Some(policy_path.clone()),
+                ..LurkProfile::default()
+            },
+        )
+        .unwrap();
+        let server = Arc::new(
+            LurkServer::builder(lurk_server_addr)
+                .with_users(Arc::new(users))
+                .with_policy(LurkPolicy::from_file(&policy_path).unwrap())
+                .with_profiles(profiles)
+                .build(),
+        );
+        server.switch_profile("strict").unwrap();
+
+        let lurk = listeners::LurkServerListener::with_shared_server(Arc::clone(&server)).run().await;
+        let http_endpoint = listeners::LurkHttpEndpointListener::with_node(http_endpoint_addr, server)
+            .run()
+            .await;
+        // Destination connections are accepted by the kernel backlog.
+        let _destination = TcpListener::bind(destination_addr).await.unwrap();
+
+        let connect = |auth: Option<Auth>| async move {
+            let mut stream = TcpStream::connect(lurk_server_addr).await.unwrap();
+            async_socks5::connect(&mut stream, destination_addr, auth).await.is_ok()
+        };
+        let switch_profile = |name: &str| {
+            let action = LurkCtlAction::Profile { name: name.to_owned() };
+            async move { ctl::execute(&action, http_endpoint_addr, None).await }
+        };
+
+        assert!(!connect(None).await, "Anonymous client should be rejected");
+        assert!(
+            !connect(Some(Auth::new("alice", "secret"))).await,
+            "Destination should be denied by the strict policy"
+        );
+
+        // Authentication methods and policy are switched at once.
+        let config = switch_profile("open").await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&config).unwrap(),
+            json!({"auth_methods": ["password", "none"], "profile": "open", "profiles": ["open", "strict"]})
+        );
+        assert!(connect(None).await, "Anonymous client should be accepted");
+        assert!(connect(Some(Auth::new("alice", "secret"))).await);
+
+        switch_profile("strict").await.unwrap();
+        assert!(!connect(None).await, "Anonymous client should be rejected again");
+
+        let err = switch_profile("maintenance")
+            .await
+            .expect_err("Unknown profile can't be switched to");
+        assert!(err.to_string().contains("Profile 'maintenance' is not defined on the node"));
+
+        cancel_listener!(lurk);
+        cancel_listener!(http_endpoint);
+        for path in [policy_path, strict_policy_path, profiles_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_snapshot_and_reset() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
+        let http_endpoint = http_endpoint.run().await;
+
+        let client = utils::http::create_http_client();
+
+        let response = client
+            .get(format!("http://{}/stats", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send stats GET request");
+
+        assert_eq!(StatusCode::OK, response.status());
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body_value["active_connections"], json!(0));
+        assert_eq!(body_value["counters"]["accepted_connections"], json!(0));
+        assert_eq!(body_value["counters"]["l2r_bytes"], json!(0));
+        assert_eq!(body_value["rates"]["connections_per_sec"]["5m"], json!(0.0));
+
+        let response = client
+            .post(format!("http://{}/stats/reset", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send stats reset POST request");
+
+        assert_eq!(StatusCode::OK, response.status());
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body_value["counters"]["accepted_connections"], json!(0));
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    async fn prometheus_metrics() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
+        let http_endpoint = http_endpoint.run().await;
+
+        let response = utils::http::create_http_client()
+            .get(format!("http://{}/metrics", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send metrics GET request");
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+
+        let body = response.text().await.unwrap();
+        assert!(body.contains("# TYPE lurk_tunnel_lifetime_seconds histogram"));
+        assert!(body.contains("lurk_connect_latency_seconds_count 0"));
+        assert!(body.contains("lurk_connections_active 0"));
+
+        cancel_listener!(http_endpoint);
+    }
+
+    #[tokio::test]
+    async fn stats_destinations() {
+        common::init_logging();
+
+        let http_endpoint_addr = next_available_address();
+        let http_endpoint = listeners::LurkHttpEndpointListener::new(http_endpoint_addr);
+        let http_endpoint = http_endpoint.run().await;
+
+        let client = utils::http::create_http_client();
+
+        let response = client
+            .get(format!("http://{}/stats/destinations?limit=10", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send destinations GET request");
+
+        assert_eq!(StatusCode::OK, response.status());
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body_value, json!([]));
+
+        let response = client
+            .get(format!("http://{}/stats/destinations?limit=many", http_endpoint_addr))
+            .send()
+            .await
+            .expect("Unable to send destinations GET request");
+
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let body_value: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(*body_value.get("type").unwrap(), json!("urn:lurk:problem:bad-request"));
 
         cancel_listener!(http_endpoint);
     }